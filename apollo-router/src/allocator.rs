@@ -0,0 +1,206 @@
+//! Attribution of memory allocations to the stages of the request pipeline.
+//!
+//! With the `allocation_metrics` feature, the router installs a global allocator that counts the
+//! allocations made while the current thread is working on a pipeline stage. Stages are marked
+//! with [`in_stage`] for synchronous code and [`with_stage`] for futures. Without the feature,
+//! marking a stage does nothing.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use pin_project_lite::pin_project;
+
+/// A stage of the request pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stage {
+    #[cfg_attr(not(feature = "allocation_metrics"), allow(dead_code))]
+    Other,
+    Parse,
+    Plan,
+    Execute,
+    Serialize,
+}
+
+impl Stage {
+    #[cfg_attr(not(feature = "allocation_metrics"), allow(dead_code))]
+    pub(crate) const ALL: [Stage; 5] = [
+        Stage::Other,
+        Stage::Parse,
+        Stage::Plan,
+        Stage::Execute,
+        Stage::Serialize,
+    ];
+
+    #[cfg_attr(not(feature = "allocation_metrics"), allow(dead_code))]
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Other => "other",
+            Stage::Parse => "parse",
+            Stage::Plan => "plan",
+            Stage::Execute => "execute",
+            Stage::Serialize => "serialize",
+        }
+    }
+}
+
+/// Runs `f`, attributing its allocations to `stage`.
+pub(crate) fn in_stage<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    let _guard = tracking::enter(stage);
+    f()
+}
+
+pin_project! {
+    /// A future whose allocations are attributed to a stage, see [`with_stage`].
+    pub(crate) struct WithStage<F> {
+        stage: Stage,
+        #[pin]
+        inner: F,
+    }
+}
+
+impl<F: Future> Future for WithStage<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _guard = tracking::enter(*this.stage);
+        this.inner.poll(cx)
+    }
+}
+
+/// Attributes the allocations made while polling `future` to `stage`.
+pub(crate) fn with_stage<F: Future>(stage: Stage, future: F) -> WithStage<F> {
+    WithStage {
+        stage,
+        inner: future,
+    }
+}
+
+#[cfg(feature = "allocation_metrics")]
+pub(crate) use tracking::allocations;
+
+#[cfg(feature = "allocation_metrics")]
+mod tracking {
+    use std::alloc::GlobalAlloc;
+    use std::alloc::Layout;
+    use std::alloc::System;
+    use std::cell::Cell;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::atomic::Ordering;
+
+    use super::Stage;
+
+    #[global_allocator]
+    static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+    thread_local! {
+        static CURRENT_STAGE: Cell<Stage> = const { Cell::new(Stage::Other) };
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    static ALLOCATED_BYTES: [AtomicU64; Stage::ALL.len()] = [ZERO; Stage::ALL.len()];
+    static ALLOCATIONS: [AtomicU64; Stage::ALL.len()] = [ZERO; Stage::ALL.len()];
+
+    /// Allocations made in each stage since the router started: stage, bytes, count.
+    pub(crate) fn allocations() -> impl Iterator<Item = (Stage, u64, u64)> {
+        Stage::ALL.into_iter().map(|stage| {
+            (
+                stage,
+                ALLOCATED_BYTES[stage as usize].load(Ordering::Relaxed),
+                ALLOCATIONS[stage as usize].load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// Restores the previous stage when dropped.
+    pub(super) struct StageGuard(Stage);
+
+    impl Drop for StageGuard {
+        fn drop(&mut self) {
+            let _ = CURRENT_STAGE.try_with(|current| current.set(self.0));
+        }
+    }
+
+    pub(super) fn enter(stage: Stage) -> StageGuard {
+        StageGuard(
+            CURRENT_STAGE
+                .try_with(|current| current.replace(stage))
+                .unwrap_or(Stage::Other),
+        )
+    }
+
+    fn record(size: usize) {
+        // The thread local may already be destroyed when a thread exits
+        let stage = CURRENT_STAGE
+            .try_with(|current| current.get())
+            .unwrap_or(Stage::Other);
+        ALLOCATED_BYTES[stage as usize].fetch_add(size as u64, Ordering::Relaxed);
+        ALLOCATIONS[stage as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts allocations, then delegates them to the system allocator.
+    struct TrackingAllocator;
+
+    unsafe impl GlobalAlloc for TrackingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            record(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            record(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            // Only growing a buffer allocates more memory
+            if new_size > layout.size() {
+                record(new_size - layout.size());
+            }
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+}
+
+#[cfg(not(feature = "allocation_metrics"))]
+mod tracking {
+    use super::Stage;
+
+    pub(super) struct StageGuard;
+
+    pub(super) fn enter(_stage: Stage) -> StageGuard {
+        StageGuard
+    }
+}
+
+#[cfg(all(test, feature = "allocation_metrics"))]
+mod tests {
+    use super::*;
+
+    fn allocated_bytes(stage: Stage) -> u64 {
+        allocations()
+            .find(|(s, _, _)| *s == stage)
+            .map(|(_, bytes, _)| bytes)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn allocations_are_attributed_to_stages() {
+        let before = allocated_bytes(Stage::Parse);
+        let buffer = in_stage(Stage::Parse, || vec![0u8; 4096]);
+        assert!(allocated_bytes(Stage::Parse) - before >= 4096);
+        drop(buffer);
+
+        let before = allocated_bytes(Stage::Serialize);
+        let buffer = with_stage(Stage::Serialize, async { vec![0u8; 4096] }).await;
+        assert!(allocated_bytes(Stage::Serialize) - before >= 4096);
+        drop(buffer);
+    }
+}
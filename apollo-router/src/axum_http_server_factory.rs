@@ -6,6 +6,8 @@ use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
+use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
 use async_compression::tokio::write::BrotliDecoder;
 use async_compression::tokio::write::GzipDecoder;
 use async_compression::tokio::write::ZlibDecoder;
@@ -21,18 +23,23 @@ use axum::response::*;
 use axum::routing::get;
 use axum::Router;
 use bytes::Bytes;
+use bytes::BytesMut;
 use futures::channel::oneshot;
 use futures::future::ready;
+use futures::future::BoxFuture;
 use futures::prelude::*;
 use futures::stream::once;
 use futures::stream::BoxStream;
 use futures::StreamExt;
+use http::header::HeaderName;
 use http::header::CONTENT_ENCODING;
+use http::header::CONTENT_LENGTH;
 use http::header::CONTENT_TYPE;
 use http::header::VARY;
 use http::HeaderValue;
 use http::Request;
 use http::Uri;
+use hyper::body::HttpBody;
 use hyper::server::conn::Http;
 use hyper::Body;
 use mediatype::names::HTML;
@@ -43,11 +50,16 @@ use opentelemetry::global;
 use opentelemetry::trace::SpanKind;
 use opentelemetry::trace::TraceContextExt;
 use serde_json::json;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 #[cfg(unix)]
 use tokio::net::UnixListener;
 use tokio::sync::Notify;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::IntervalStream;
 use tower::util::BoxService;
 use tower::BoxError;
 use tower::ServiceExt;
@@ -58,37 +70,89 @@ use tower_service::Service;
 use tracing::Level;
 use tracing::Span;
 
+use crate::allocator;
+use crate::allocator::Stage;
+use crate::compression_dictionary;
+use crate::compression_dictionary::Dictionaries;
+use crate::configuration::Batching;
 use crate::configuration::Configuration;
+use crate::configuration::FileUploads;
+use crate::configuration::GetParameters;
+use crate::configuration::JsonStrings;
 use crate::configuration::ListenAddr;
+use crate::configuration::RequestHeaders;
+use crate::error::ApolloRouterError;
 use crate::graphql;
+use crate::health;
+use crate::health::Health;
+use crate::health::Readiness;
 use crate::http_ext;
 use crate::http_server_factory::HttpServerFactory;
 use crate::http_server_factory::HttpServerHandle;
 use crate::http_server_factory::Listener;
 use crate::http_server_factory::NetworkStream;
+use crate::json_ext;
+use crate::landing_page::LandingPageContent;
 use crate::plugin::Handler;
 use crate::plugins::traffic_shaping::Elapsed;
 use crate::plugins::traffic_shaping::RateLimited;
-use crate::router::ApolloRouterError;
+use crate::response_scanning;
+use crate::response_scanning::ResponseScanning;
+use crate::router_factory::IntrospectionListenerFactory;
 use crate::router_factory::SupergraphServiceFactory;
+use crate::services::router;
 use crate::services::MULTIPART_DEFER_CONTENT_TYPE;
+use crate::Context;
+
+/// Path of the gRPC health checking service, see <https://github.com/grpc/grpc/blob/master/doc/health-checking.md>
+const GRPC_HEALTH_PATH: &str = "/grpc.health.v1.Health/*rest";
+const ACCESS_CONTROL_REQUEST_PRIVATE_NETWORK: &str = "access-control-request-private-network";
+const ACCESS_CONTROL_ALLOW_PRIVATE_NETWORK: &str = "access-control-allow-private-network";
 
 /// A basic http server using Axum.
 /// Uses streaming as primary method of response.
 /// Redirects to studio for GET requests.
 #[derive(Debug)]
-pub(crate) struct AxumHttpServerFactory;
+pub(crate) struct AxumHttpServerFactory {
+    health: Arc<Health>,
+}
 
 impl AxumHttpServerFactory {
     pub(crate) fn new() -> Self {
-        Self
+        Self::with_health(Default::default())
+    }
+
+    /// The readiness endpoint of the servers reports this state.
+    pub(crate) fn with_health(health: Arc<Health>) -> Self {
+        Self { health }
     }
 }
 
+fn readiness<RF>(
+    health: Arc<Health>,
+    service_factory: &RF,
+    configuration: &Configuration,
+) -> Result<Readiness, ApolloRouterError>
+where
+    RF: SupergraphServiceFactory,
+{
+    Readiness::new(
+        health,
+        service_factory.schema_loaded(),
+        &configuration.server.experimental_health_endpoints,
+    )
+    .map_err(|e| {
+        ApolloRouterError::ServiceCreationError(
+            format!("health endpoints configuration error: {e}").into(),
+        )
+    })
+}
+
 pub(crate) fn make_axum_router<RF>(
     service_factory: RF,
     configuration: &Configuration,
     plugin_handlers: HashMap<String, Handler>,
+    health: Arc<Health>,
 ) -> Result<Router, ApolloRouterError>
 where
     RF: SupergraphServiceFactory,
@@ -102,37 +166,91 @@ where
     } else {
         configuration.server.graphql_path.clone()
     };
+    let readiness = Arc::new(readiness(health, &service_factory, configuration)?);
+    let schema_loaded = service_factory.schema_loaded();
+    let header_policy = HeaderPolicy::new(&configuration.server.experimental_request_headers)
+        .map_err(|e| {
+            ApolloRouterError::ServiceCreationError(
+                format!("request headers configuration error: {e}").into(),
+            )
+        })?;
+    let landing_page = LandingPageContent::new(&configuration.server)
+        .map_err(|e| {
+            ApolloRouterError::ServiceCreationError(
+                format!("landing page configuration error: {e}").into(),
+            )
+        })?
+        .map(Arc::new);
+    let scanning = Arc::new(ResponseScanning::new(service_factory.response_scanners()));
     let mut router = Router::<hyper::Body>::new()
         .route(
             &graphql_path,
             get({
-                let display_landing_page = configuration.server.landing_page;
+                // the landing page is displayed on the GraphQL endpoint unless it has its own path
+                let landing_page = landing_page.clone().filter(|_| {
+                    configuration
+                        .server
+                        .experimental_landing_page
+                        .path
+                        .is_none()
+                });
+                let parameters = Arc::new(configuration.server.experimental_get_parameters.clone());
+                let strings = configuration.experimental_json_strings;
+                let heartbeat_interval = configuration
+                    .server
+                    .experimental_defer_multipart
+                    .heartbeat_interval;
+                let scanning = scanning.clone();
                 move |host: Host, Extension(service): Extension<RF>, http_request: Request<Body>| {
                     handle_get(
                         host,
                         service.new_service().boxed(),
                         http_request,
-                        display_landing_page,
+                        landing_page.clone(),
+                        parameters.clone(),
+                        strings,
+                        heartbeat_interval,
+                        scanning.clone(),
                     )
                 }
             })
             .post({
+                let max_body_size = configuration.server.experimental_max_request_body_size;
+                let batching = configuration.server.experimental_batching.clone();
+                let file_uploads = configuration.server.experimental_file_uploads.clone();
+                let parameters = Arc::new(configuration.server.experimental_get_parameters.clone());
+                let strings = configuration.experimental_json_strings;
+                let heartbeat_interval = configuration
+                    .server
+                    .experimental_defer_multipart
+                    .heartbeat_interval;
                 move |host: Host,
                       uri: OriginalUri,
-                      request: Json<graphql::Request>,
-                      Extension(service): Extension<RF>,
-                      header_map: HeaderMap| {
+                      Extension(service_factory): Extension<RF>,
+                      http_request: Request<Body>| {
                     handle_post(
                         host,
                         uri,
-                        request,
-                        service.new_service().boxed(),
-                        header_map,
+                        http_request,
+                        service_factory,
+                        max_body_size,
+                        batching.clone(),
+                        file_uploads.clone(),
+                        parameters.clone(),
+                        strings,
+                        heartbeat_interval,
+                        scanning.clone(),
                     )
                 }
-            }),
+            })
+            .layer(middleware::from_fn(router_stage::<RF>)),
         )
-        .layer(middleware::from_fn(decompress_request_body))
+        .layer(middleware::from_fn({
+            let max_body_size = configuration.server.experimental_max_request_body_size;
+            move |req: Request<Body>, next: Next<Body>| {
+                decompress_request_body(req, next, max_body_size)
+            }
+        }))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(PropagatingMakeSpan::new())
@@ -150,11 +268,84 @@ where
                     }
                 }),
         )
-        .route(&configuration.server.health_check_path, get(health_check))
+        .route(
+            &configuration.server.health_check_path,
+            get(move || health_check(schema_loaded)),
+        )
+        .route(
+            &configuration
+                .server
+                .experimental_health_endpoints
+                .ready_path,
+            get(move || health::ready(readiness.clone())),
+        );
+    let live_path = &configuration.server.experimental_health_endpoints.live_path;
+    if *live_path != configuration.server.health_check_path {
+        router = router.route(live_path, get(move || health_check(schema_loaded)));
+    }
+    if let (Some(landing_page), Some(landing_page_path)) = (
+        landing_page,
+        &configuration.server.experimental_landing_page.path,
+    ) {
+        router = router.route(
+            landing_page_path,
+            get(move || {
+                let landing_page = landing_page.clone();
+                async move { landing_page.response() }
+            }),
+        );
+    }
+    if let Some(configuration_path) = &configuration.server.experimental_configuration_path {
+        let effective = json!(configuration.redacted_effective());
+        router = router.route(
+            configuration_path,
+            get(move || {
+                let effective = effective.clone();
+                async move { Json(effective) }
+            }),
+        );
+    }
+    router = router
+        .layer(middleware::from_fn(
+            move |req: Request<Body>, next: Next<Body>| {
+                apply_header_policy(header_policy.clone(), req, next)
+            },
+        ))
         .layer(Extension(service_factory))
         .layer(cors)
+        .layer(middleware::from_fn({
+            let allow = configuration.cors.allow_private_network;
+            move |req: Request<Body>, next: Next<Body>| private_network_access(allow, req, next)
+        }))
         .layer(CompressionLayer::new()); // To compress response body
 
+    let dictionaries = Dictionaries::load(
+        &configuration.server.experimental_compression_dictionaries,
+        &configuration.server.graphql_path,
+    )
+    .map_err(ApolloRouterError::ServiceCreationError)?;
+    if !dictionaries.is_empty() {
+        let dictionaries = Arc::new(dictionaries);
+        router = router.layer(middleware::from_fn({
+            let dictionaries = dictionaries.clone();
+            move |req: Request<Body>, next: Next<Body>| {
+                compression_dictionary::compress(dictionaries.clone(), req, next)
+            }
+        }));
+        for dictionary in &dictionaries.dictionaries {
+            let dictionary = dictionary.clone();
+            router = router.route(
+                &dictionary.url.clone(),
+                get(move || compression_dictionary::serve(dictionary.clone())),
+            );
+        }
+    }
+
+    Ok(route_plugin_handlers(router, plugin_handlers))
+}
+
+/// Serves the endpoints of plugins under `/plugins/<plugin name>`.
+fn route_plugin_handlers(mut router: Router, plugin_handlers: HashMap<String, Handler>) -> Router {
     for (plugin_name, handler) in plugin_handlers {
         router = router.route(
             &format!("/plugins/{}/*path", plugin_name),
@@ -172,7 +363,88 @@ where
             }),
         );
     }
-    Ok(router)
+    router
+}
+
+/// Router of the admin listener, with the task reporting the readiness of the router to the gRPC
+/// health checking service.
+fn make_admin_router(
+    configuration: &Configuration,
+    readiness: Readiness,
+    admin_handlers: HashMap<String, Handler>,
+) -> (Router, Option<BoxFuture<'static, ()>>) {
+    let mut router = Router::new();
+    let mut report = None;
+    if configuration.server.experimental_admin.grpc_health_check {
+        let (reporter, health_service) = tonic_health::server::health_reporter();
+        report = Some(health::report(Arc::new(readiness), reporter).boxed());
+        router = router.route(
+            GRPC_HEALTH_PATH,
+            tower::ServiceBuilder::new()
+                .map_response(|response: http::Response<tonic::body::BoxBody>| {
+                    response.map(axum::body::boxed)
+                })
+                .service(health_service),
+        );
+    }
+    (route_plugin_handlers(router, admin_handlers), report)
+}
+
+/// Serves HTTP on a connection until it is closed. When the server shuts down, the connection
+/// is closed after the current request.
+async fn serve_connection<S>(stream: S, app: Router, connection_shutdown: Arc<Notify>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let connection = Http::new()
+        .http1_keep_alive(true)
+        .serve_connection(stream, app);
+
+    tokio::pin!(connection);
+    tokio::select! {
+        // the connection finished first
+        _res = &mut connection => {
+        }
+        // the shutdown receiver was triggered first,
+        // so we tell the connection to do a graceful shutdown
+        // on the next request, then we wait for it to finish
+        _ = connection_shutdown.notified() => {
+            let c = connection.as_mut();
+            c.graceful_shutdown();
+
+            let _= connection.await;
+        }
+    }
+}
+
+/// Accepts plaintext HTTP connections on an additional listener, until the returned sender is
+/// dropped.
+fn spawn_listener(
+    name: &'static str,
+    listener: TcpListener,
+    app: Router,
+    connection_shutdown: Arc<Notify>,
+) -> (oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+    let (stop_sender, stop_receiver) = oneshot::channel::<()>();
+    let task = tokio::spawn(async move {
+        tokio::pin!(stop_receiver);
+        loop {
+            tokio::select! {
+                _ = &mut stop_receiver => break,
+                res = listener.accept() => match res {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_connection(
+                            stream,
+                            app.clone(),
+                            connection_shutdown.clone(),
+                        ));
+                    }
+                    Err(e) => tracing::error!("{} listener error: {}", name, e),
+                }
+            }
+        }
+    });
+    (stop_sender, task)
 }
 
 impl HttpServerFactory for AxumHttpServerFactory {
@@ -188,11 +460,76 @@ impl HttpServerFactory for AxumHttpServerFactory {
     where
         RF: SupergraphServiceFactory,
     {
+        let health = self.health.clone();
         Box::pin(async move {
             let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
             let listen_address = configuration.server.listen.clone();
 
-            let router = make_axum_router(service_factory, &configuration, plugin_handlers)?;
+            // the dedicated introspection listener serves the same operations, and allows them
+            // to introspect the schema
+            let introspection_listener =
+                match configuration.server.experimental_introspection.listen {
+                    Some(addr) => {
+                        let router = make_axum_router(
+                            IntrospectionListenerFactory(service_factory.clone()),
+                            &configuration,
+                            HashMap::new(),
+                            health.clone(),
+                        )?;
+                        let listener = TcpListener::bind(addr)
+                            .await
+                            .map_err(ApolloRouterError::ServerCreationError)?;
+                        tracing::info!(
+                            "GraphQL endpoint allowing introspection exposed at http://{}{}",
+                            addr,
+                            configuration.server.graphql_path
+                        );
+                        Some((listener, router))
+                    }
+                    None => None,
+                };
+
+            // the admin listener serves the endpoints which shouldn't be reachable by clients
+            let admin_listener = match configuration.server.experimental_admin.listen {
+                Some(addr) => {
+                    let readiness = readiness(health.clone(), &service_factory, &configuration)?;
+                    let (router, report) = make_admin_router(
+                        &configuration,
+                        readiness,
+                        service_factory.admin_endpoints(),
+                    );
+                    let listener = TcpListener::bind(addr)
+                        .await
+                        .map_err(ApolloRouterError::ServerCreationError)?;
+                    tracing::info!("administration endpoints exposed at http://{}", addr);
+                    Some((listener, router, report))
+                }
+                None if configuration.server.experimental_admin.grpc_health_check => {
+                    return Err(ApolloRouterError::ServiceCreationError(
+                        "the gRPC health check is served on the admin listener, set `server.experimental_admin.listen`"
+                            .into(),
+                    ));
+                }
+                None => None,
+            };
+
+            let router =
+                make_axum_router(service_factory, &configuration, plugin_handlers, health)?;
+
+            let server_tls = configuration.server.tls.clone();
+            let mut tls_config = match &server_tls {
+                Some(server_tls) => Some(crate::tls::server_config(server_tls).map_err(|e| {
+                    ApolloRouterError::ServerCreationError(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid TLS configuration: {}", e),
+                    ))
+                })?),
+                None => None,
+            };
+            let mut tls_changes = match &server_tls {
+                Some(server_tls) => crate::tls::watch(server_tls).boxed(),
+                None => stream::pending().boxed(),
+            };
 
             // if we received a TCP listener, reuse it, otherwise create a new one
             #[cfg_attr(not(unix), allow(unused_mut))]
@@ -215,9 +552,13 @@ impl HttpServerFactory for AxumHttpServerFactory {
                 .local_addr()
                 .map_err(ApolloRouterError::ServerCreationError)?;
 
+            let endpoint_address = match (&server_tls, &actual_listen_address) {
+                (Some(_), ListenAddr::SocketAddr(addr)) => format!("https://{}", addr),
+                _ => actual_listen_address.to_string(),
+            };
             tracing::info!(
                 "GraphQL endpoint exposed at {}{} 🚀",
-                actual_listen_address,
+                endpoint_address,
                 configuration.server.graphql_path
             );
             // this server reproduces most of hyper::server::Server's behaviour
@@ -231,14 +572,48 @@ impl HttpServerFactory for AxumHttpServerFactory {
                 let connection_shutdown = Arc::new(Notify::new());
                 let mut max_open_file_warning = None;
 
+                // the introspection listener is stopped with the server, and dropped before the
+                // next server binds its address
+                let introspection_server =
+                    introspection_listener.map(|(introspection_listener, app)| {
+                        spawn_listener(
+                            "introspection",
+                            introspection_listener,
+                            app,
+                            connection_shutdown.clone(),
+                        )
+                    });
+                // the admin listener is stopped the same way, with the gRPC health reports
+                let admin_server = admin_listener.map(|(admin_listener, app, report)| {
+                    (
+                        spawn_listener("admin", admin_listener, app, connection_shutdown.clone()),
+                        report.map(tokio::spawn),
+                    )
+                });
+
                 loop {
                     tokio::select! {
                         _ = &mut shutdown_receiver => {
                             break;
                         }
+                        Some(()) = tls_changes.next() => {
+                            if let Some(server_tls) = &server_tls {
+                                match crate::tls::server_config(server_tls) {
+                                    Ok(config) => {
+                                        tracing::info!("reloaded the TLS certificate");
+                                        tls_config = Some(config);
+                                    }
+                                    Err(e) => tracing::error!(
+                                        "could not reload the TLS certificate, keeping the previous one: {}",
+                                        e
+                                    ),
+                                }
+                            }
+                        }
                         res = listener.accept() => {
                             let app = router.clone();
                             let connection_shutdown = connection_shutdown.clone();
+                            let tls_config = tls_config.clone();
 
                             match res {
                                 Ok(res) => {
@@ -255,47 +630,21 @@ impl HttpServerFactory for AxumHttpServerFactory {
                                                     .expect(
                                                         "this should not fail unless the socket is invalid",
                                                     );
-                                                    let connection = Http::new()
-                                                    .http1_keep_alive(true)
-                                                    .serve_connection(stream, app);
-
-                                                tokio::pin!(connection);
-                                                tokio::select! {
-                                                    // the connection finished first
-                                                    _res = &mut connection => {
-                                                    }
-                                                    // the shutdown receiver was triggered first,
-                                                    // so we tell the connection to do a graceful shutdown
-                                                    // on the next request, then we wait for it to finish
-                                                    _ = connection_shutdown.notified() => {
-                                                        let c = connection.as_mut();
-                                                        c.graceful_shutdown();
-
-                                                        let _= connection.await;
+                                                match tls_config {
+                                                    #[cfg(feature = "tls")]
+                                                    Some(tls_config) => {
+                                                        match TlsAcceptor::from(tls_config).accept(stream).await {
+                                                            Ok(stream) => serve_connection(stream, app, connection_shutdown).await,
+                                                            Err(e) => tracing::debug!("TLS handshake failed: {}", e),
+                                                        }
                                                     }
+                                                    // Without the `tls` feature, TLS configurations are rejected on startup
+                                                    _ => serve_connection(stream, app, connection_shutdown).await,
                                                 }
                                             }
                                             #[cfg(unix)]
                                             NetworkStream::Unix(stream) => {
-                                                let connection = Http::new()
-                                                .http1_keep_alive(true)
-                                                .serve_connection(stream, app);
-
-                                                tokio::pin!(connection);
-                                                tokio::select! {
-                                                    // the connection finished first
-                                                    _res = &mut connection => {
-                                                    }
-                                                    // the shutdown receiver was triggered first,
-                                                    // so we tell the connection to do a graceful shutdown
-                                                    // on the next request, then we wait for it to finish
-                                                    _ = connection_shutdown.notified() => {
-                                                        let c = connection.as_mut();
-                                                        c.graceful_shutdown();
-
-                                                        let _= connection.await;
-                                                    }
-                                                }
+                                                serve_connection(stream, app, connection_shutdown).await
                                             }
                                         }
                                     });
@@ -381,6 +730,17 @@ impl HttpServerFactory for AxumHttpServerFactory {
                 // the shutdown receiver was triggered so we break out of
                 // the server loop, tell the currently active connections to stop
                 // then return the TCP listen socket
+                if let Some((stop_sender, task)) = introspection_server {
+                    drop(stop_sender);
+                    let _ = task.await;
+                }
+                if let Some(((stop_sender, task), report)) = admin_server {
+                    if let Some(report) = report {
+                        report.abort();
+                    }
+                    drop(stop_sender);
+                    let _ = task.await;
+                }
                 connection_shutdown.notify_waiters();
                 listener
             };
@@ -421,6 +781,7 @@ async fn custom_plugin_handler(
         .map_err(|err| err.to_string())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_get(
     Host(host): Host,
     service: BoxService<
@@ -429,21 +790,36 @@ async fn handle_get(
         BoxError,
     >,
     http_request: Request<Body>,
-    display_landing_page: bool,
+    landing_page: Option<Arc<LandingPageContent>>,
+    parameters: Arc<GetParameters>,
+    strings: JsonStrings,
+    heartbeat_interval: Option<Duration>,
+    scanning: Arc<ResponseScanning>,
 ) -> impl IntoResponse {
-    if prefers_html(http_request.headers()) && display_landing_page {
-        return display_home_page().into_response();
+    if let Some(max_length) = parameters.max_url_length {
+        let length = http_request
+            .uri()
+            .path_and_query()
+            .map(|path_and_query| path_and_query.as_str().len())
+            .unwrap_or_default();
+        if length > max_length {
+            return uri_too_long(max_length);
+        }
+    }
+
+    if let Some(landing_page) = landing_page.filter(|_| prefers_html(http_request.headers())) {
+        return landing_page.response();
     }
 
     if let Some(request) = http_request
         .uri()
         .query()
-        .and_then(|q| graphql::Request::from_urlencoded_query(q.to_string()).ok())
+        .and_then(|q| graphql::Request::from_urlencoded_query_with(q.to_string(), &parameters).ok())
     {
         let mut http_request = http_request.map(|_| request);
         *http_request.uri_mut() = Uri::from_str(&format!("http://{}{}", host, http_request.uri()))
             .expect("the URL is already valid because it comes from axum; qed");
-        return run_graphql_request(service, http_request)
+        return run_graphql_request(service, http_request, strings, heartbeat_interval, scanning)
             .await
             .into_response();
     }
@@ -451,37 +827,517 @@ async fn handle_get(
     (StatusCode::BAD_REQUEST, "Invalid Graphql request").into_response()
 }
 
-async fn handle_post(
+#[allow(clippy::too_many_arguments)]
+async fn handle_post<RF>(
     Host(host): Host,
     OriginalUri(uri): OriginalUri,
-    Json(request): Json<graphql::Request>,
-    service: BoxService<
-        http::Request<graphql::Request>,
-        http::Response<BoxStream<'static, graphql::Response>>,
-        BoxError,
-    >,
-    header_map: HeaderMap,
-) -> impl IntoResponse {
-    let mut http_request = Request::post(
-        Uri::from_str(&format!("http://{}{}", host, uri))
-            .expect("the URL is already valid because it comes from axum; qed"),
+    http_request: Request<Body>,
+    service_factory: RF,
+    max_body_size: Option<usize>,
+    batching: Batching,
+    file_uploads: FileUploads,
+    parameters: Arc<GetParameters>,
+    strings: JsonStrings,
+    heartbeat_interval: Option<Duration>,
+    scanning: Arc<ResponseScanning>,
+) -> impl IntoResponse
+where
+    RF: SupergraphServiceFactory,
+{
+    let (mut parts, body) = http_request.into_parts();
+    let uri = Uri::from_str(&format!("http://{}{}", host, uri))
+        .expect("the URL is already valid because it comes from axum; qed");
+    let context = parts.extensions.remove::<Context>();
+    if file_uploads.enabled {
+        if let Some(boundary) = file_uploads::multipart_boundary(&parts.headers) {
+            let (request, uploads) =
+                match file_uploads::read_operations(body, boundary, &file_uploads, max_body_size)
+                    .await
+                {
+                    Ok(operations) => operations,
+                    Err(error) => return error.into_response(),
+                };
+            let mut http_request = Request::post(uri)
+                .body(request)
+                .expect("body has already been parsed; qed");
+            *http_request.headers_mut() = parts.headers;
+            http_request.extensions_mut().insert(uploads);
+            if let Some(context) = context {
+                http_request.extensions_mut().insert(context);
+            }
+            return run_graphql_request(
+                service_factory.new_service().boxed(),
+                http_request,
+                strings,
+                heartbeat_interval,
+                scanning,
+            )
+            .await
+            .into_response();
+        }
+    }
+
+    let graphql_document = has_graphql_content_type(&parts.headers);
+    if !graphql_document && !has_json_content_type(&parts.headers) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected request with `Content-Type: application/json` or `Content-Type: application/graphql`",
+        )
+            .into_response();
+    }
+
+    let body = match read_body(body, &parts.headers, max_body_size).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    if batching.enabled && !graphql_document && is_json_array(&body) {
+        return handle_batch(
+            service_factory,
+            parts.headers,
+            uri,
+            context,
+            &body,
+            &batching,
+            strings,
+            &scanning,
+        )
+        .await;
+    }
+
+    let request = if graphql_document {
+        match document_request(&body, uri.query(), &parameters) {
+            Ok(request) => request,
+            Err(err) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid GraphQL request: {err}"),
+                )
+                    .into_response()
+            }
+        }
+    } else {
+        match serde_json::from_slice::<graphql::Request>(&body) {
+            Ok(request) => request,
+            Err(err) => {
+                let status = if err.classify() == serde_json::error::Category::Data {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                return (status, format!("Invalid GraphQL request: {err}")).into_response();
+            }
+        }
+    };
+
+    let mut http_request = Request::post(uri)
+        .body(request)
+        .expect("body has already been parsed; qed");
+    *http_request.headers_mut() = parts.headers;
+    if let Some(context) = context {
+        http_request.extensions_mut().insert(context);
+    }
+
+    run_graphql_request(
+        service_factory.new_service().boxed(),
+        http_request,
+        strings,
+        heartbeat_interval,
+        scanning,
     )
-    .body(request)
-    .expect("body has already been parsed; qed");
-    *http_request.headers_mut() = header_map;
+    .await
+    .into_response()
+}
 
-    run_graphql_request(service, http_request)
-        .await
+fn is_json_array(body: &[u8]) -> bool {
+    body.iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .map_or(false, |byte| *byte == b'[')
+}
+
+/// Executes the GraphQL requests of a batch concurrently, each one with the headers of the HTTP
+/// request and a snapshot of the context of the router stage, and answers with the array of their
+/// responses, in the same order.
+async fn handle_batch<RF>(
+    service_factory: RF,
+    headers: HeaderMap,
+    uri: Uri,
+    context: Option<Context>,
+    body: &[u8],
+    batching: &Batching,
+    strings: JsonStrings,
+    scanning: &ResponseScanning,
+) -> Response
+where
+    RF: SupergraphServiceFactory,
+{
+    let requests = match serde_json::from_slice::<Vec<graphql::Request>>(body) {
+        Ok(requests) => requests,
+        Err(err) => {
+            let status = if err.classify() == serde_json::error::Category::Data {
+                StatusCode::UNPROCESSABLE_ENTITY
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            return (status, format!("Invalid GraphQL request batch: {err}")).into_response();
+        }
+    };
+    if requests.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Invalid GraphQL request batch: empty batch",
+        )
+            .into_response();
+    }
+    if requests.len() > batching.max_size {
+        return batch_too_large(batching.max_size);
+    }
+
+    let operations: Vec<BatchedOperation> = requests.iter().map(BatchedOperation::new).collect();
+    // mutations are executed in the order of the batch, like the fields of a mutation
+    let sequential = operations.iter().any(|operation| operation.mutation);
+    let run = |(request, operation): (graphql::Request, BatchedOperation)| {
+        let mut http_request = Request::post(uri.clone())
+            .body(request)
+            .expect("body has already been parsed; qed");
+        *http_request.headers_mut() = headers.clone();
+        if let Some(context) = &context {
+            http_request.extensions_mut().insert(context.snapshot());
+        }
+        let service = service_factory.new_service().boxed();
+        async move {
+            if operation.deferred {
+                return batch_error(
+                    "deferred responses are not supported in batches",
+                    "DEFER_IN_BATCH",
+                );
+            }
+            run_batched_request(service, http_request).await
+        }
+    };
+    let requests = requests.into_iter().zip(operations);
+    let responses = if sequential {
+        let mut responses = Vec::new();
+        for request in requests {
+            responses.push(run(request).await);
+        }
+        responses
+    } else {
+        future::join_all(requests.map(run)).await
+    };
+
+    let mut headers = HeaderMap::new();
+    process_vary_header(&mut headers);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let mut body = Vec::new();
+    allocator::in_stage(Stage::Serialize, || {
+        json_ext::to_writer(&mut body, &responses, &strings)
+            .expect("body should be serializable; qed")
+    });
+    // the batch is scanned as a whole, and blocked as a whole
+    let body = scanning.scan(body).unwrap_or_else(|| {
+        let blocked = response_scanning::blocked_json(None, &strings);
+        let mut body = vec![b'['];
+        for index in 0..responses.len() {
+            if index > 0 {
+                body.push(b',');
+            }
+            body.extend_from_slice(&blocked);
+        }
+        body.push(b']');
+        body
+    });
+    (StatusCode::OK, headers, Bytes::from(body)).into_response()
+}
+
+/// What must be known about a request of a batch before executing it.
+struct BatchedOperation {
+    mutation: bool,
+    deferred: bool,
+}
+
+impl BatchedOperation {
+    /// Requests that can't be parsed are executed like queries, which reports their errors.
+    fn new(request: &graphql::Request) -> Self {
+        let query = request.query.as_deref().unwrap_or_default();
+        let tree = apollo_parser::Parser::new(query).parse();
+        if tree.errors().next().is_some() {
+            return BatchedOperation {
+                mutation: false,
+                deferred: false,
+            };
+        }
+        let document = tree.document();
+
+        let mut operations = document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::OperationDefinition(operation) => Some(operation),
+                _ => None,
+            });
+        let operation = match request.operation_name.as_deref() {
+            Some(operation_name) => operations.find(|operation| {
+                operation
+                    .name()
+                    .map(|name| name.text() == operation_name)
+                    .unwrap_or_default()
+            }),
+            None => operations.next(),
+        };
+        let mutation = operation
+            .and_then(|operation| operation.operation_type())
+            .map(|operation_type| operation_type.mutation_token().is_some())
+            .unwrap_or_default();
+        let deferred = document
+            .syntax()
+            .descendants()
+            .filter_map(ast::Directive::cast)
+            .any(|directive| {
+                directive
+                    .name()
+                    .map(|name| name.text() == "defer")
+                    .unwrap_or_default()
+            });
+        BatchedOperation { mutation, deferred }
+    }
+}
+
+fn batch_error(message: &str, code: &str) -> graphql::Response {
+    graphql::Response::builder()
+        .error(
+            graphql::Error::builder()
+                .message(message)
+                .extension("code", code)
+                .build(),
+        )
+        .build()
+}
+
+/// Runs one request of a batch. Its failures become GraphQL errors, to not fail the whole batch.
+async fn run_batched_request<RS>(
+    service: RS,
+    http_request: Request<graphql::Request>,
+) -> graphql::Response
+where
+    RS: Service<
+            http::Request<graphql::Request>,
+            Response = http::Response<BoxStream<'static, graphql::Response>>,
+            Error = BoxError,
+        > + Send,
+{
+    let response = match service.oneshot(http_request).await {
+        Ok(response) => response,
+        Err(e) => {
+            if let Some(source_err) = e.source() {
+                if let Some(rate_limited) = source_err.downcast_ref::<RateLimited>() {
+                    return batch_error(&rate_limited.to_string(), "REQUEST_RATE_LIMITED");
+                }
+                if source_err.is::<Elapsed>() {
+                    return batch_error(&Elapsed::new().to_string(), "REQUEST_TIMEOUT");
+                }
+            }
+            tracing::error!("router service call failed: {}", e);
+            return batch_error("router service call failed", "INTERNAL_SERVER_ERROR");
+        }
+    };
+    match response.into_body().next().await {
+        None => batch_error(
+            "router service is not available to process request",
+            "SERVICE_UNAVAILABLE",
+        ),
+        Some(response) if response.has_next.unwrap_or(false) => batch_error(
+            "deferred responses are not supported in batches",
+            "DEFER_IN_BATCH",
+        ),
+        Some(response) => response,
+    }
+}
+
+fn batch_too_large(max_size: usize) -> Response {
+    let error = graphql::Error::builder()
+        .message(format!("batch has more than {max_size} requests"))
+        .extension("code", "BATCH_LIMIT_EXCEEDED")
+        .extension("maxSize", max_size)
+        .build();
+    http_ext::Response::from(
+        http::Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(graphql::Response::builder().error(error).build())
+            .expect("the response is valid; qed"),
+    )
+    .into_response()
+}
+
+fn has_json_content_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .map(|mime| {
+            mime.type_() == "application"
+                && (mime.subtype() == "json" || mime.suffix().map_or(false, |name| name == "json"))
+        })
+        .unwrap_or_default()
+}
+
+fn has_graphql_content_type(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .map(|mime| {
+            mime.type_() == "application" && mime.subtype() == "graphql" && mime.suffix().is_none()
+        })
+        .unwrap_or_default()
+}
+
+/// A request whose body is a raw GraphQL document, the operation name, variables and extensions
+/// being in the query string.
+fn document_request(
+    body: &[u8],
+    query_string: Option<&str>,
+    parameters: &GetParameters,
+) -> Result<graphql::Request, BoxError> {
+    let document = std::str::from_utf8(body)?;
+    let mut request = match query_string {
+        Some(query_string) => {
+            graphql::Request::from_urlencoded_query_with(query_string.to_string(), parameters)?
+        }
+        None => graphql::Request::default(),
+    };
+    request.query = Some(document.to_string());
+    Ok(request)
+}
+
+fn uri_too_long(max_length: usize) -> Response {
+    let error = graphql::Error::builder()
+        .message(format!("request URL is longer than {max_length} bytes"))
+        .extension("code", "URI_TOO_LONG")
+        .extension("maxLength", max_length)
+        .build();
+    http_ext::Response::from(
+        http::Response::builder()
+            .status(StatusCode::URI_TOO_LONG)
+            .body(graphql::Response::builder().error(error).build())
+            .expect("the response is valid; qed"),
+    )
+    .into_response()
+}
+
+fn payload_too_large(max_size: usize) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("request body is larger than {max_size} bytes"),
+    )
         .into_response()
 }
 
-fn display_home_page() -> Html<Bytes> {
-    let html = Bytes::from_static(include_bytes!("../resources/index.html"));
-    Html(html)
+/// Reads a request body as it is received, failing as soon as it is larger than `max_size`.
+async fn read_body(
+    mut body: Body,
+    headers: &HeaderMap,
+    max_size: Option<usize>,
+) -> Result<Bytes, Response> {
+    let cannot_read = |err: hyper::Error| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("cannot read request body: {err}"),
+        )
+            .into_response()
+    };
+    let max_size = match max_size {
+        Some(max_size) => max_size,
+        None => return hyper::body::to_bytes(body).await.map_err(cannot_read),
+    };
+
+    // Don't wait for the body if the client announced it is too large
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.map_or(false, |length| length > max_size) {
+        return Err(payload_too_large(max_size));
+    }
+
+    let mut buffer = BytesMut::with_capacity(content_length.unwrap_or_default());
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(cannot_read)?;
+        if buffer.len() + chunk.len() > max_size {
+            return Err(payload_too_large(max_size));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer.freeze())
+}
+
+/// Heartbeat of the `multipart/mixed` responses: an empty JSON object, ignored by clients.
+const MULTIPART_HEARTBEAT: &[u8] = b"content-type: application/json\r\n\r\n{}\r\n--graphql\r\n";
+
+/// Interleaves heartbeats with the parts of a `multipart/mixed` response, every `interval` until
+/// the last part.
+fn with_heartbeats(
+    parts: impl Stream<Item = Bytes> + Send + 'static,
+    interval: Duration,
+) -> impl Stream<Item = Bytes> + Send + 'static {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let heartbeats =
+        IntervalStream::new(ticks).map(|_| Some(Bytes::from_static(MULTIPART_HEARTBEAT)));
+    futures::stream::select(parts.map(Some).chain(once(ready(None))), heartbeats)
+        .take_while(|part| ready(part.is_some()))
+        .filter_map(ready)
+}
+
+/// Serializes and scans the deferred responses, each one followed by the next delimiter. A
+/// blocked response is replaced with an error ending the stream.
+fn scanned_deferred(
+    responses: BoxStream<'static, graphql::Response>,
+    strings: JsonStrings,
+    scanning: Arc<ResponseScanning>,
+) -> impl Stream<Item = Bytes> + Send + 'static {
+    futures::stream::unfold(Some(responses), move |responses| {
+        let scanning = scanning.clone();
+        async move {
+            let mut responses = responses?;
+            let response = responses.next().await?;
+            let mut json = Vec::new();
+            allocator::in_stage(Stage::Serialize, || {
+                json_ext::to_writer(&mut json, &response, &strings).unwrap()
+            });
+            let (json, has_next) = match scanning.scan(json) {
+                Some(json) => (json, response.has_next.unwrap_or(false)),
+                None => (
+                    response_scanning::blocked_json(Some(false), &strings),
+                    false,
+                ),
+            };
+
+            let mut buf = Vec::from(&b"content-type: application/json\r\n\r\n"[..]);
+            buf.extend_from_slice(&json);
+            // the last chunk has a different end delimiter
+            if has_next {
+                buf.extend_from_slice(b"\r\n--graphql\r\n");
+            } else {
+                buf.extend_from_slice(b"\r\n--graphql--\r\n");
+            }
+
+            Some((
+                Bytes::from(buf),
+                if has_next { Some(responses) } else { None },
+            ))
+        }
+    })
 }
 
-async fn health_check() -> impl IntoResponse {
-    Json(json!({ "status": "pass" }))
+/// The router is alive in safe mode, but degraded until a schema is loaded.
+async fn health_check(schema_loaded: bool) -> impl IntoResponse {
+    if schema_loaded {
+        Json(json!({ "status": "pass" }))
+    } else {
+        Json(json!({
+            "status": "warn",
+            "output": "degraded: in safe mode until a schema is loaded"
+        }))
+    }
 }
 
 // Process the headers to make sure that `VARY` is set correctly
@@ -494,6 +1350,9 @@ fn process_vary_header(headers: &mut HeaderMap<HeaderValue>) {
 async fn run_graphql_request<RS>(
     service: RS,
     http_request: Request<graphql::Request>,
+    strings: JsonStrings,
+    heartbeat_interval: Option<Duration>,
+    scanning: Arc<ResponseScanning>,
 ) -> impl IntoResponse
 where
     RS: Service<
@@ -509,8 +1368,8 @@ where
             match service.call(Request::from_parts(head, body)).await {
                 Err(e) => {
                     if let Some(source_err) = e.source() {
-                        if source_err.is::<RateLimited>() {
-                            return RateLimited::new().into_response();
+                        if let Some(rate_limited) = source_err.downcast_ref::<RateLimited>() {
+                            return rate_limited.clone().into_response();
                         }
                         if source_err.is::<Elapsed>() {
                             return Elapsed::new().into_response();
@@ -538,7 +1397,21 @@ where
                                 .into_response()
                         }
                         Some(response) => {
-                            if response.has_next.unwrap_or(false) {
+                            let json = tracing::trace_span!("serialize_response").in_scope(|| {
+                                allocator::in_stage(Stage::Serialize, || {
+                                    let mut json = Vec::new();
+                                    json_ext::to_writer(&mut json, &response, &strings)
+                                        .expect("body should be serializable; qed");
+                                    json
+                                })
+                            });
+                            // a blocked primary response is sent alone, without its deferred
+                            // responses
+                            let (json, has_next) = match scanning.scan(json) {
+                                Some(json) => (json, response.has_next.unwrap_or(false)),
+                                None => (response_scanning::blocked_json(None, &strings), false),
+                            };
+                            if has_next {
                                 parts.headers.insert(
                                     CONTENT_TYPE,
                                     HeaderValue::from_static(MULTIPART_DEFER_CONTENT_TYPE),
@@ -549,26 +1422,17 @@ where
                                 let mut first_buf = Vec::from(
                                     &b"\r\n--graphql\r\ncontent-type: application/json\r\n\r\n"[..],
                                 );
-                                serde_json::to_writer(&mut first_buf, &response).unwrap();
+                                first_buf.extend_from_slice(&json);
                                 first_buf.extend_from_slice(b"\r\n--graphql\r\n");
 
-                                let body = once(ready(Ok(Bytes::from(first_buf)))).chain(
-                                    stream.map(|res| {
-                                        let mut buf = Vec::from(
-                                            &b"content-type: application/json\r\n\r\n"[..],
-                                        );
-                                        serde_json::to_writer(&mut buf, &res).unwrap();
-
-                                        // the last chunk has a different end delimiter
-                                        if res.has_next.unwrap_or(false) {
-                                            buf.extend_from_slice(b"\r\n--graphql\r\n");
-                                        } else {
-                                            buf.extend_from_slice(b"\r\n--graphql--\r\n");
-                                        }
-
-                                        Ok::<_, BoxError>(buf.into())
-                                    }),
-                                );
+                                let deferred = scanned_deferred(stream, strings, scanning);
+                                let deferred = match heartbeat_interval {
+                                    Some(interval) => with_heartbeats(deferred, interval).boxed(),
+                                    None => deferred.boxed(),
+                                };
+                                let body = once(ready(Bytes::from(first_buf)))
+                                    .chain(deferred)
+                                    .map(Ok::<_, BoxError>);
 
                                 (parts, StreamBody::new(body)).into_response()
                             } else {
@@ -576,12 +1440,11 @@ where
                                     CONTENT_TYPE,
                                     HeaderValue::from_static("application/json"),
                                 );
-                                tracing::trace_span!("serialize_response").in_scope(|| {
-                                    http_ext::Response::from(http::Response::from_parts(
-                                        parts, response,
-                                    ))
-                                    .into_response()
-                                })
+                                http_ext::Response::from(http::Response::from_parts(
+                                    parts,
+                                    Bytes::from(json),
+                                ))
+                                .into_response()
                             }
                         }
                     }
@@ -591,8 +1454,8 @@ where
         Err(e) => {
             tracing::error!("router service is not available to process request: {}", e);
             if let Some(source_err) = e.source() {
-                if source_err.is::<RateLimited>() {
-                    return RateLimited::new().into_response();
+                if let Some(rate_limited) = source_err.downcast_ref::<RateLimited>() {
+                    return rate_limited.clone().into_response();
                 }
                 if source_err.is::<Elapsed>() {
                     return Elapsed::new().into_response();
@@ -623,31 +1486,191 @@ fn prefers_html(headers: &HeaderMap) -> bool {
     })
 }
 
-async fn decompress_request_body(
-    req: Request<Body>,
+/// Request header policies, with header names validated.
+#[derive(Clone, Debug)]
+struct HeaderPolicy {
+    max_total_size: Option<usize>,
+    max_header_size: Option<usize>,
+    remove: Arc<Vec<HeaderName>>,
+    lowercase_values: Arc<Vec<HeaderName>>,
+}
+
+impl HeaderPolicy {
+    fn new(config: &RequestHeaders) -> Result<Self, http::header::InvalidHeaderName> {
+        let parse = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| HeaderName::try_from(name.as_str()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(HeaderPolicy {
+            max_total_size: config.max_total_size,
+            max_header_size: config.max_header_size,
+            remove: Arc::new(parse(&config.remove)?),
+            lowercase_values: Arc::new(parse(&config.lowercase_values)?),
+        })
+    }
+}
+
+async fn apply_header_policy(
+    policy: HeaderPolicy,
+    mut req: Request<Body>,
     next: Next<Body>,
 ) -> Result<Response, Response> {
-    let (parts, body) = req.into_parts();
-    let content_encoding = parts.headers.get(&CONTENT_ENCODING);
+    let headers = req.headers_mut();
+    for name in policy.remove.iter() {
+        headers.remove(name);
+    }
+
+    let mut total_size = 0;
+    for (name, value) in headers.iter() {
+        let size = name.as_str().len() + value.len();
+        if matches!(policy.max_header_size, Some(max) if size > max) {
+            return Err((
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!("request header '{name}' is too large"),
+            )
+                .into_response());
+        }
+        total_size += size;
+    }
+    if matches!(policy.max_total_size, Some(max) if total_size > max) {
+        return Err((
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "request headers are too large",
+        )
+            .into_response());
+    }
+
+    for name in policy.lowercase_values.iter() {
+        if let http::header::Entry::Occupied(mut entry) = headers.entry(name) {
+            for value in entry.iter_mut() {
+                if let Ok(lowercase) =
+                    HeaderValue::from_bytes(&value.as_bytes().to_ascii_lowercase())
+                {
+                    *value = lowercase;
+                }
+            }
+        }
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Allows the preflight requests asking for private network access, if their origin is allowed.
+async fn private_network_access(allow: bool, req: Request<Body>, next: Next<Body>) -> Response {
+    let requested = allow
+        && req.method() == http::Method::OPTIONS
+        && req
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_PRIVATE_NETWORK)
+            .map(|value| value == "true")
+            .unwrap_or_default();
+    let mut response = next.run(req).await;
+    if requested
+        && response
+            .headers()
+            .contains_key(http::header::ACCESS_CONTROL_ALLOW_ORIGIN)
+    {
+        response.headers_mut().insert(
+            HeaderName::from_static(ACCESS_CONTROL_ALLOW_PRIVATE_NETWORK),
+            HeaderValue::from_static("true"),
+        );
+    }
+    response
+}
+
+/// Runs the router stage of the plugins around the handler of the GraphQL endpoint.
+///
+/// The context of the stage goes to the supergraph request in the extensions of the HTTP
+/// request, along with its URI, which plugins can rewrite.
+async fn router_stage<RF>(request: Request<Body>, next: Next<Body>) -> Response
+where
+    RF: SupergraphServiceFactory,
+{
+    let service_factory = match request.extensions().get::<RF>() {
+        Some(service_factory) => service_factory.clone(),
+        None => return next.run(request).await,
+    };
+    let mut next = Some(next);
+    let handler = tower::service_fn(move |request: router::Request| {
+        let next = next.take();
+        async move {
+            let next = next.ok_or("the router service was called twice for the same request")?;
+            let (mut parts, body) = request.router_request.into_parts();
+            parts.extensions.insert(OriginalUri(parts.uri.clone()));
+            parts.extensions.insert(request.context.clone());
+            let (parts, body) = next
+                .run(Request::from_parts(parts, body))
+                .await
+                .into_parts();
+            // bodies already in memory keep their length
+            let body = if body.size_hint().exact().is_some() {
+                Body::from(hyper::body::to_bytes(body).await?)
+            } else {
+                Body::wrap_stream(stream::unfold(body, |mut body| async move {
+                    body.data().await.map(|chunk| (chunk, body))
+                }))
+            };
+            Ok::<_, BoxError>(router::Response {
+                response: http::Response::from_parts(parts, body),
+                context: request.context,
+            })
+        }
+    });
+    let request = router::Request {
+        router_request: request,
+        context: Context::new(),
+    };
+    match service_factory
+        .router_service(handler.boxed())
+        .oneshot(request)
+        .await
+    {
+        Ok(response) => response.response.into_response(),
+        Err(e) => {
+            tracing::error!("router service call failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "router service call failed",
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn decompress_request_body(
+    req: Request<Body>,
+    next: Next<Body>,
+    max_body_size: Option<usize>,
+) -> Result<Response, Response> {
+    let (parts, mut body) = req.into_parts();
+    let content_encoding = parts.headers.get(&CONTENT_ENCODING);
     macro_rules! decode_body {
         ($decoder: ident, $error_message: expr) => {{
-            let body_bytes = hyper::body::to_bytes(body)
-                .map_err(|err| {
+            // The body is decompressed as it is received, to stop as soon as it is too large
+            let mut decoder = $decoder::new(Vec::new());
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|err| {
                     (
                         StatusCode::BAD_REQUEST,
                         format!("cannot read request body: {err}"),
                     )
                         .into_response()
-                })
-                .await?;
-            let mut decoder = $decoder::new(Vec::new());
-            decoder.write_all(&body_bytes).await.map_err(|err| {
-                (
-                    StatusCode::BAD_REQUEST,
-                    format!("{}: {err}", $error_message),
-                )
-                    .into_response()
-            })?;
+                })?;
+                decoder.write_all(&chunk).await.map_err(|err| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("{}: {err}", $error_message),
+                    )
+                        .into_response()
+                })?;
+                if let Some(max_body_size) = max_body_size {
+                    if decoder.get_ref().len() > max_body_size {
+                        return Err(payload_too_large(max_body_size));
+                    }
+                }
+            }
             decoder.shutdown().await.map_err(|err| {
                 (
                     StatusCode::BAD_REQUEST,
@@ -655,6 +1678,11 @@ async fn decompress_request_body(
                 )
                     .into_response()
             })?;
+            if let Some(max_body_size) = max_body_size {
+                if decoder.get_ref().len() > max_body_size {
+                    return Err(payload_too_large(max_body_size));
+                }
+            }
 
             Ok(next
                 .run(Request::from_parts(parts, Body::from(decoder.into_inner())))
@@ -738,6 +1766,8 @@ impl<B> MakeSpan<B> for PropagatingMakeSpan {
 mod tests {
     use std::net::SocketAddr;
     use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
 
     use async_compression::tokio::write::GzipEncoder;
     use http::header::ACCEPT_ENCODING;
@@ -990,7 +2020,13 @@ mod tests {
             "{}",
             response.text().await.unwrap()
         );
-        assert_eq!(response.bytes().await.unwrap(), display_home_page().0);
+        assert!(response
+            .headers()
+            .contains_key(http::header::CONTENT_SECURITY_POLICY));
+        assert_eq!(
+            response.bytes().await.unwrap(),
+            &include_bytes!("../resources/index.html")[..]
+        );
         // }
         // insta::assert_json_snapshot!(test_span::get_spans_for_root(
         //     &root_span.id().unwrap(),
@@ -1237,90 +2273,675 @@ mod tests {
         assert!(err.is_status());
         assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
 
-        // Get query
-        let err = client
-            .get(url.as_str())
-            .query(&json!({ "query": "query" }))
+        // Get query
+        let err = client
+            .get(url.as_str())
+            .query(&json!({ "query": "query" }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .expect_err("should be not found");
+
+        assert!(err.is_status());
+        assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn it_serves_https() -> Result<(), ApolloRouterError> {
+        let expected_response = graphql::Response::builder()
+            .data(json!({"response": "yay"}))
+            .build();
+        let example_response = expected_response.clone();
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(1)
+            .returning(move |_| {
+                let example_response = example_response.clone();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(example_response)
+                        .unwrap(),
+                ))
+            });
+        let tls = crate::configuration::ServerTls {
+            certificate_chain: concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/tls/server.crt")
+                .into(),
+            key: concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/tls/server.key").into(),
+            client_certificate_authorities: None,
+        };
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .tls(tls)
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let port = match server.listen_address() {
+            ListenAddr::SocketAddr(addr) => addr.port(),
+            #[cfg(unix)]
+            ListenAddr::UnixSocket(_) => unreachable!("the server listens on a TCP socket"),
+        };
+
+        // Plaintext requests fail the TLS handshake
+        assert!(client
+            .post(format!("http://localhost:{}/", port))
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .is_err());
+
+        let https_client = reqwest::Client::builder()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(include_bytes!("testdata/tls/ca.crt")).unwrap(),
+            )
+            .build()
+            .unwrap();
+        let response = https_client
+            .post(format!("https://localhost:{}/", port))
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        assert_eq!(
+            response.json::<graphql::Response>().await.unwrap(),
+            expected_response,
+        );
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn response_with_custom_endpoint() -> Result<(), ApolloRouterError> {
+        let expected_response = graphql::Response::builder()
+            .data(json!({"response": "yay"}))
+            .build();
+        let example_response = expected_response.clone();
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(2)
+            .returning(move |_| {
+                let example_response = example_response.clone();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(example_response)
+                        .unwrap(),
+                ))
+            });
+        let conf = Configuration::builder()
+            .cors(
+                Cors::builder()
+                    .origins(vec!["http://studio".to_string()])
+                    .build(),
+            )
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .graphql_path(String::from("/graphql"))
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/graphql", server.listen_address());
+
+        // Post query
+        let response = client
+            .post(url.as_str())
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+
+        assert_eq!(
+            response.json::<graphql::Response>().await.unwrap(),
+            expected_response,
+        );
+
+        // Get query
+        let response = client
+            .get(url.as_str())
+            .query(&json!({ "query": "query" }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+
+        assert_eq!(
+            response.json::<graphql::Response>().await.unwrap(),
+            expected_response,
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_header_policies() -> Result<(), ApolloRouterError> {
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(1)
+            .returning(move |req| {
+                assert!(req.headers().get("x-internal").is_none());
+                assert_eq!(
+                    req.headers().get("x-client-name"),
+                    Some(&HeaderValue::from_static("mobile-app"))
+                );
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(graphql::Response::builder().data(json!({})).build())
+                        .unwrap(),
+                ))
+            });
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .request_headers(
+                        serde_json::from_value(json!({
+                            "max_total_size": 1024,
+                            "max_header_size": 128,
+                            "remove": ["x-internal"],
+                            "lowercase_values": ["x-client-name"]
+                        }))
+                        .unwrap(),
+                    )
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/", server.listen_address());
+
+        let response = client
+            .post(url.as_str())
+            .header("x-internal", "secret")
+            .header("x-client-name", "Mobile-App")
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = client
+            .post(url.as_str())
+            .header("x-large", "a".repeat(200))
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+
+        let response = client
+            .post(url.as_str())
+            .header("x-first", "a".repeat(100))
+            .header("x-second", "a".repeat(100))
+            .header("x-third", "a".repeat(100))
+            .header("x-fourth", "a".repeat(100))
+            .header("x-fifth", "a".repeat(100))
+            .header("x-sixth", "a".repeat(100))
+            .header("x-seventh", "a".repeat(100))
+            .header("x-eighth", "a".repeat(100))
+            .header("x-ninth", "a".repeat(100))
+            .header("x-tenth", "a".repeat(100))
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_parameters() -> Result<(), ApolloRouterError> {
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(1)
+            .returning(move |req| {
+                assert_eq!(req.body().query.as_deref(), Some("{ me { id } }"));
+                assert_eq!(req.body().operation_name.as_deref(), Some("Me"));
+                assert_eq!(
+                    req.body().extensions.get("persistedQuery"),
+                    Some(&serde_json_bytes::json!({ "version": 1, "sha256Hash": "abc" }))
+                );
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(graphql::Response::builder().data(json!({})).build())
+                        .unwrap(),
+                ))
+            });
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .get_parameters(crate::configuration::GetParameters {
+                        query: "q".to_string(),
+                        operation_name: "op".to_string(),
+                        persisted_query_hash: Some("hash".to_string()),
+                        max_url_length: Some(100),
+                        ..Default::default()
+                    })
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/", server.listen_address());
+
+        let response = client
+            .get(url.as_str())
+            .query(&[("q", "{ me { id } }"), ("op", "Me"), ("hash", "abc")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = client
+            .get(url.as_str())
+            .query(&[("q", "{ me { id } }".repeat(10))])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+        let response = response.json::<graphql::Response>().await.unwrap();
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&"URI_TOO_LONG".into())
+        );
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn heartbeats_are_sent_until_the_last_part() {
+        let last_part = futures::stream::once(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Bytes::from_static(b"last")
+        });
+        let parts: Vec<Bytes> = with_heartbeats(last_part, Duration::from_millis(10))
+            .collect()
+            .await;
+        assert!(parts.len() > 1);
+        assert_eq!(parts.last(), Some(&Bytes::from_static(b"last")));
+        assert!(parts[..parts.len() - 1]
+            .iter()
+            .all(|part| part == MULTIPART_HEARTBEAT));
+    }
+
+    #[tokio::test]
+    async fn request_batching() -> Result<(), ApolloRouterError> {
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(2)
+            .returning(move |req| {
+                let query = req.body().query.clone().unwrap_or_default();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(
+                            graphql::Response::builder()
+                                .data(json!({ "query": query }))
+                                .build(),
+                        )
+                        .unwrap(),
+                ))
+            });
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .batching(crate::configuration::Batching {
+                        enabled: true,
+                        max_size: 2,
+                    })
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/", server.listen_address());
+
+        let response = client
+            .post(url.as_str())
+            .body(json!([{ "query": "{ a }" }, { "query": "{ b }" }]).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.json::<Vec<graphql::Response>>().await.unwrap(),
+            vec![
+                graphql::Response::builder()
+                    .data(json!({ "query": "{ a }" }))
+                    .build(),
+                graphql::Response::builder()
+                    .data(json!({ "query": "{ b }" }))
+                    .build(),
+            ]
+        );
+
+        let response = client
+            .post(url.as_str())
+            .body(
+                json!([{ "query": "{ a }" }, { "query": "{ b }" }, { "query": "{ c }" }])
+                    .to_string(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = response.json::<graphql::Response>().await.unwrap();
+        assert_eq!(
+            response.errors[0].extensions.get("code"),
+            Some(&"BATCH_LIMIT_EXCEEDED".into())
+        );
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn request_batching_with_mutations_and_defer() -> Result<(), ApolloRouterError> {
+        let executed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = executed.clone();
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(2)
+            .returning(move |req| {
+                let query = req.body().query.clone().unwrap_or_default();
+                recorded.lock().unwrap().push(query.clone());
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(
+                            graphql::Response::builder()
+                                .data(json!({ "query": query }))
+                                .build(),
+                        )
+                        .unwrap(),
+                ))
+            });
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .batching(crate::configuration::Batching {
+                        enabled: true,
+                        max_size: 3,
+                    })
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/", server.listen_address());
+
+        let response = client
+            .post(url.as_str())
+            .body(
+                json!([
+                    { "query": "mutation { a }" },
+                    { "query": "{ b ... @defer { c } }" },
+                    { "query": "mutation { d }" }
+                ])
+                .to_string(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let responses = response.json::<Vec<graphql::Response>>().await.unwrap();
+        // the deferred query is rejected without being executed
+        assert_eq!(
+            responses[1].errors[0].extensions.get("code"),
+            Some(&"DEFER_IN_BATCH".into())
+        );
+        assert_eq!(
+            *executed.lock().unwrap(),
+            vec!["mutation { a }".to_string(), "mutation { d }".to_string()]
+        );
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn json_string_escaping() -> Result<(), ApolloRouterError> {
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(1)
+            .returning(move |_| {
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(
+                            graphql::Response::builder()
+                                .data(json!({ "name": "caf\u{e9}\u{2028}" }))
+                                .build(),
+                        )
+                        .unwrap(),
+                ))
+            });
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .build(),
+            )
+            .experimental_json_strings(crate::configuration::JsonStrings {
+                ascii_only: true,
+                ..Default::default()
+            })
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+        let url = format!("{}/", server.listen_address());
+
+        let response = client
+            .post(url.as_str())
+            .body(json!({ "query": "query" }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"data":{"name":"caf\u00e9\u2028"}}"#
+        );
+
+        server.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn compression_dictionaries() -> Result<(), ApolloRouterError> {
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(2)
+            .returning(move |_| {
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(
+                            graphql::Response::builder()
+                                .data(json!({ "me": { "name": "Ada" } }))
+                                .build(),
+                        )
+                        .unwrap(),
+                ))
+            });
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("graphql.dict");
+        std::fs::write(&path, r#"{"data":{"me":{"name":""}}}"#).unwrap();
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .compression_dictionary(crate::configuration::CompressionDictionary {
+                        path,
+                        url: "/dictionaries/graphql.dict".to_string(),
+                        match_path: None,
+                    })
+                    .build(),
+            )
+            .build();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+
+        let response = client
+            .get(format!(
+                "{}/dictionaries/graphql.dict",
+                server.listen_address()
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get("use-as-dictionary").unwrap(),
+            r#"match="/""#
+        );
+        let dictionary = response.bytes().await.unwrap();
+
+        let url = format!("{}/", server.listen_address());
+        let response = client
+            .post(url.as_str())
+            .body(json!({ "query": "{ me { name } }" }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get(header::LINK).unwrap(),
+            r#"</dictionaries/graphql.dict>; rel="compression-dictionary""#
+        );
+
+        let available = format!(
+            ":{}:",
+            base64::encode(<sha2::Sha256 as sha2::Digest>::digest(&dictionary))
+        );
+        let response = client
+            .post(url.as_str())
+            .header(ACCEPT_ENCODING, "gzip, dcz")
+            .header("available-dictionary", available)
+            .body(json!({ "query": "{ me { name } }" }).to_string())
             .send()
             .await
+            .unwrap();
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "dcz");
+        let body = response.bytes().await.unwrap();
+        let decompressed = zstd::bulk::Decompressor::with_dictionary(&dictionary)
             .unwrap()
-            .error_for_status()
-            .expect_err("should be not found");
-
-        assert!(err.is_status());
-        assert_eq!(err.status(), Some(StatusCode::NOT_FOUND));
+            .decompress(&body[40..], 1024)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&decompressed).unwrap(),
+            json!({ "data": { "me": { "name": "Ada" } } })
+        );
 
-        server.shutdown().await?;
-        Ok(())
+        server.shutdown().await
     }
 
     #[tokio::test]
-    async fn response_with_custom_endpoint() -> Result<(), ApolloRouterError> {
-        let expected_response = graphql::Response::builder()
-            .data(json!({"response": "yay"}))
-            .build();
-        let example_response = expected_response.clone();
+    async fn request_body_size_limit() -> Result<(), ApolloRouterError> {
         let mut expectations = MockSupergraphService::new();
         expectations
             .expect_service_call()
-            .times(2)
-            .returning(move |_| {
-                let example_response = example_response.clone();
+            .times(1)
+            .returning(move |req| {
+                assert_eq!(req.body().query.as_deref(), Some("{ me { id } }"));
                 Ok(http_ext::from_response_to_stream(
                     http::Response::builder()
                         .status(200)
-                        .body(example_response)
+                        .body(graphql::Response::builder().data(json!({})).build())
                         .unwrap(),
                 ))
             });
         let conf = Configuration::builder()
-            .cors(
-                Cors::builder()
-                    .origins(vec!["http://studio".to_string()])
-                    .build(),
-            )
             .server(
                 crate::configuration::Server::builder()
                     .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
-                    .graphql_path(String::from("/graphql"))
+                    .max_request_body_size(100)
                     .build(),
             )
             .build();
         let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
-        let url = format!("{}/graphql", server.listen_address());
+        let url = format!("{}/", server.listen_address());
 
-        // Post query
+        // A chunked body, without content length, is read as it is received
+        let chunks: Vec<Result<_, std::io::Error>> = vec![
+            Ok(r#"{"query":"#.to_string()),
+            Ok(r#""{ me { id } }"}"#.to_string()),
+        ];
         let response = client
             .post(url.as_str())
-            .body(json!({ "query": "query" }).to_string())
+            .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
             .send()
             .await
-            .unwrap()
-            .error_for_status()
             .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        assert_eq!(
-            response.json::<graphql::Response>().await.unwrap(),
-            expected_response,
-        );
+        let large_query = format!("{{ me {{ {} }} }}", "id ".repeat(50));
+        let response = client
+            .post(url.as_str())
+            .body(json!({ "query": large_query }).to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
 
-        // Get query
+        let chunks: Vec<Result<_, std::io::Error>> = vec![
+            Ok(r#"{"query":"#.to_string()),
+            Ok(json!(large_query).to_string()),
+            Ok("}".to_string()),
+        ];
         let response = client
-            .get(url.as_str())
-            .query(&json!({ "query": "query" }))
+            .post(url.as_str())
+            .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
             .send()
             .await
-            .unwrap()
-            .error_for_status()
             .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
 
-        assert_eq!(
-            response.json::<graphql::Response>().await.unwrap(),
-            expected_response,
-        );
+        // The limit applies to the decompressed body
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder
+            .write_all(json!({ "query": large_query }).to_string().as_bytes())
+            .await
+            .unwrap();
+        encoder.shutdown().await.unwrap();
+        let compressed_body = encoder.into_inner();
+        assert!(compressed_body.len() < 100);
+        let response = client
+            .post(url.as_str())
+            .header(CONTENT_ENCODING, HeaderValue::from_static("gzip"))
+            .body(compressed_body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
 
-        server.shutdown().await?;
-        Ok(())
+        server.shutdown().await
     }
 
     #[tokio::test]
@@ -1583,6 +3204,70 @@ mod tests {
         server.shutdown().await
     }
 
+    #[tokio::test]
+    async fn it_extracts_graphql_documents_on_post_requests() -> Result<(), ApolloRouterError> {
+        let query = "query Q($id: Int) { me(id: $id) }";
+
+        let expected_response = graphql::Response::builder()
+            .data(json!({"response": "yay"}))
+            .build();
+        let example_response = expected_response.clone();
+
+        let mut expectations = MockSupergraphService::new();
+        expectations
+            .expect_service_call()
+            .times(1)
+            .withf(move |req| {
+                assert_eq!(req.body().query.as_deref().unwrap(), query);
+                assert_eq!(req.body().operation_name.as_deref().unwrap(), "Q");
+                assert_eq!(
+                    req.body().variables.get("id"),
+                    Some(&serde_json_bytes::json!(1))
+                );
+                true
+            })
+            .returning(move |_| {
+                let example_response = example_response.clone();
+                Ok(http_ext::from_response_to_stream(
+                    http::Response::builder()
+                        .status(200)
+                        .body(example_response)
+                        .unwrap(),
+                ))
+            });
+        let (server, client) = init(expectations).await;
+        let url = format!(
+            "{}/?operationName=Q&variables=%7B%22id%22%3A1%7D",
+            server.listen_address()
+        );
+
+        let response = client
+            .post(url.as_str())
+            .header(CONTENT_TYPE, "application/graphql")
+            .body(query)
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+
+        assert_eq!(
+            response.json::<graphql::Response>().await.unwrap(),
+            expected_response,
+        );
+
+        let response = client
+            .post(format!("{}/", server.listen_address()).as_str())
+            .header(CONTENT_TYPE, "application/graphql")
+            .body(vec![0xff, 0xfe])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        server.shutdown().await
+    }
+
     #[tokio::test]
     async fn response_failure() -> Result<(), ApolloRouterError> {
         let mut expectations = MockSupergraphService::new();
@@ -1593,6 +3278,7 @@ mod tests {
                 let example_response = crate::error::FetchError::SubrequestHttpError {
                     service: "Mock service".to_string(),
                     reason: "Mock error".to_string(),
+                    status: None,
                 }
                 .to_response();
                 Ok(http_ext::from_response_to_stream(
@@ -1625,6 +3311,7 @@ mod tests {
             crate::error::FetchError::SubrequestHttpError {
                 service: "Mock service".to_string(),
                 reason: "Mock error".to_string(),
+                status: None,
             }
             .to_response()
         );
@@ -1836,6 +3523,196 @@ Content-Type: application/json\r
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let up = listener.local_addr().unwrap();
+        let probes = Arc::new(AtomicUsize::new(0));
+        let probed = probes.clone();
+        tokio::spawn(
+            axum::Server::from_tcp(listener).unwrap().serve(
+                Router::new()
+                    .route(
+                        "/health",
+                        get(move || {
+                            probed.fetch_add(1, Ordering::SeqCst);
+                            async { "ok" }
+                        }),
+                    )
+                    .into_make_service(),
+            ),
+        );
+        let down = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .health_endpoints(crate::configuration::HealthEndpoints {
+                        subgraph_probes: vec![
+                            crate::configuration::SubgraphProbe {
+                                name: "accounts".to_string(),
+                                url: format!("http://{}/health", up).parse().unwrap(),
+                            },
+                            crate::configuration::SubgraphProbe {
+                                name: "products".to_string(),
+                                url: format!("http://{}/health", down).parse().unwrap(),
+                            },
+                        ],
+                        ..Default::default()
+                    })
+                    .build(),
+            )
+            .build();
+        let expectations = MockSupergraphService::new();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+
+        let url = format!("{}/health/live", server.listen_address());
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.json::<serde_json::Value>().await.unwrap(),
+            json!({ "status": "pass" })
+        );
+
+        let url = format!("{}/health/ready", server.listen_address());
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["status"], "fail");
+        assert_eq!(body["checks"]["schema"], json!({ "status": "pass" }));
+        assert_eq!(
+            body["checks"]["subgraph:accounts"],
+            json!({ "status": "pass" })
+        );
+        assert_eq!(body["checks"]["subgraph:products"]["status"], "fail");
+        assert!(body["checks"].get("uplink").is_none());
+
+        // The results of the probes are reused
+        let url = format!("{}/health/ready", server.listen_address());
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(probes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_configuration_endpoint() {
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .configuration_path("/configuration".to_string())
+                    .build(),
+            )
+            .build();
+        let expectations = MockSupergraphService::new();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+
+        let url = format!("{}/configuration", server.listen_address());
+        let response = client.get(url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(
+            body["configuration"]["server"]["experimental_configuration_path"],
+            json!("/configuration")
+        );
+        assert_eq!(
+            body["origins"]["server.experimental_configuration_path"],
+            json!("code")
+        );
+        assert_eq!(body["origins"]["server.introspection"], json!("default"));
+    }
+
+    #[tokio::test]
+    async fn test_readiness_without_schema() {
+        let health = Arc::new(Health::default());
+        let router = make_axum_router(
+            crate::router_factory::SafeModeSupergraphServiceFactory,
+            &Configuration::default(),
+            HashMap::new(),
+            health.clone(),
+        )
+        .unwrap();
+        let ready = || {
+            http::Request::get("http://127.0.0.1/health/ready")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        health.uplink_failed("Uplink is unreachable".to_string());
+        let response = router.clone().oneshot(ready()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!({
+                "status": "fail",
+                "checks": {
+                    "schema": { "status": "fail", "output": "no schema is loaded" },
+                    "uplink": { "status": "fail", "output": "Uplink is unreachable" }
+                }
+            })
+        );
+
+        health.uplink_succeeded();
+        let response = router.oneshot(ready()).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["checks"]["uplink"],
+            json!({ "status": "pass" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_grpc_health_check() {
+        use tonic_health::proto::health_check_response::ServingStatus;
+        use tonic_health::proto::health_client::HealthClient;
+        use tonic_health::proto::HealthCheckRequest;
+
+        // the address of the admin listener isn't reported, so it can't use a random port
+        let admin_address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let conf = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .admin(crate::configuration::Admin {
+                        listen: Some(admin_address),
+                        grpc_health_check: true,
+                    })
+                    .build(),
+            )
+            .build();
+        let expectations = MockSupergraphService::new();
+        let (server, client) = init_with_config(expectations, conf, HashMap::new()).await;
+
+        // the health checking service is not served to clients
+        let response = client
+            .post(format!(
+                "{}/grpc.health.v1.Health/Check",
+                server.listen_address()
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_ne!(response.status(), StatusCode::OK);
+
+        let mut health_client = HealthClient::connect(format!("http://{}", admin_address))
+            .await
+            .unwrap();
+        let response = health_client
+            .check(HealthCheckRequest {
+                service: String::new(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.into_inner().status(), ServingStatus::Serving);
+    }
+
     #[test(tokio::test)]
     async fn it_send_bad_content_type() -> Result<(), ApolloRouterError> {
         let query = "query";
@@ -2144,6 +4021,67 @@ Content-Type: application/json\r
         Ok(())
     }
 
+    #[tokio::test]
+    async fn cors_path_policies() -> Result<(), ApolloRouterError> {
+        let origin = "https://any.example.com";
+
+        let conf = Configuration::builder()
+            .cors(
+                Cors::builder()
+                    .max_age(Duration::from_secs(600))
+                    .allow_private_network(true)
+                    .policy(crate::configuration::CorsPolicy {
+                        paths: vec!["/public/*".to_string()],
+                        allow_any_origin: false,
+                        mirror_any_origin: true,
+                        allow_credentials: false,
+                        origins: Vec::new(),
+                        match_origins: None,
+                    })
+                    .build(),
+            )
+            .server(
+                crate::configuration::Server::builder()
+                    .listen(SocketAddr::from_str("127.0.0.1:0").unwrap())
+                    .build(),
+            )
+            .build();
+        let (server, client) =
+            init_with_config(MockSupergraphService::new(), conf, HashMap::new()).await;
+
+        let url = format!("{}/", server.listen_address());
+        let response = request_cors_with_origin(&client, url.as_str(), origin).await;
+        assert_not_cors_origin(response, origin);
+
+        let url = format!("{}/public/graphql", server.listen_address());
+        let response = client
+            .request(Method::OPTIONS, url.as_str())
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "POST")
+            .header("Access-Control-Request-Headers", "content-type")
+            .header("Access-Control-Request-Private-Network", "true")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get("access-control-allow-private-network")
+                .and_then(|value| value.to_str().ok()),
+            Some("true")
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get("access-control-max-age")
+                .and_then(|value| value.to_str().ok()),
+            Some("600")
+        );
+        assert_cors_origin(response, origin);
+
+        Ok(())
+    }
+
     async fn request_cors_with_origin(
         client: &Client,
         url: &str,
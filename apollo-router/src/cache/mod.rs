@@ -25,6 +25,7 @@ where
     K: Clone + Send + Eq + Hash + 'static,
     V: Clone + Send + 'static,
 {
+    #[cfg(test)]
     pub(crate) async fn new() -> Self {
         Self::with_capacity(DEFAULT_CACHE_CAPACITY).await
     }
@@ -0,0 +1,306 @@
+//! Compression of responses with shared dictionaries, following the Compression Dictionary
+//! Transport draft.
+//!
+//! The router serves each configured dictionary with a `Use-As-Dictionary` header, and
+//! advertises it on GraphQL responses with a `Link` header. Clients that stored a dictionary
+//! announce its hash in the `Available-Dictionary` header of their requests: if they accept the
+//! `dcz` encoding, the response is then compressed with Zstandard and this dictionary.
+
+use std::sync::Arc;
+
+use axum::body::boxed;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use bytes::Bytes;
+use http::header::HeaderName;
+use http::header::ACCEPT_ENCODING;
+use http::header::CACHE_CONTROL;
+use http::header::CONTENT_ENCODING;
+use http::header::CONTENT_LENGTH;
+use http::header::CONTENT_TYPE;
+use http::header::LINK;
+use http::header::VARY;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::Request;
+use http::StatusCode;
+use hyper::Body;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+
+use crate::configuration::CompressionDictionary;
+
+const AVAILABLE_DICTIONARY: &str = "available-dictionary";
+const USE_AS_DICTIONARY: &str = "use-as-dictionary";
+const DICTIONARY_ENCODING: &str = "dcz";
+/// Header of `dcz` responses, followed by the SHA-256 hash of the dictionary.
+const DCZ_MAGIC: [u8; 8] = [0x5e, 0x2a, 0x4d, 0x18, 0x20, 0x00, 0x00, 0x00];
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// A dictionary loaded from its file.
+#[derive(Debug)]
+pub(crate) struct Dictionary {
+    /// Path on which the dictionary is served
+    pub(crate) url: String,
+    /// Pattern of the request paths the dictionary applies to
+    match_path: String,
+    content: Bytes,
+    hash: Vec<u8>,
+    /// The hash as sent by clients, as a structured field byte sequence
+    available: String,
+}
+
+impl Dictionary {
+    fn load(config: &CompressionDictionary, graphql_path: &str) -> Result<Self, BoxError> {
+        let content = std::fs::read(&config.path).map_err(|e| {
+            format!(
+                "could not read compression dictionary {}: {}",
+                config.path.display(),
+                e
+            )
+        })?;
+        let hash = Sha256::digest(&content).to_vec();
+        Ok(Dictionary {
+            url: config.url.clone(),
+            match_path: config
+                .match_path
+                .clone()
+                .unwrap_or_else(|| graphql_path.to_string()),
+            available: format!(":{}:", base64::encode(&hash)),
+            content: content.into(),
+            hash,
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self.match_path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.match_path,
+        }
+    }
+
+    /// Compresses a response body in the `dcz` format.
+    fn compress(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut compressor =
+            zstd::bulk::Compressor::with_dictionary(COMPRESSION_LEVEL, &self.content)?;
+        let mut compressed = Vec::with_capacity(DCZ_MAGIC.len() + self.hash.len() + body.len());
+        compressed.extend_from_slice(&DCZ_MAGIC);
+        compressed.extend_from_slice(&self.hash);
+        compressed.extend_from_slice(&compressor.compress(body)?);
+        Ok(compressed)
+    }
+}
+
+/// The configured dictionaries, the most recent last.
+#[derive(Debug, Default)]
+pub(crate) struct Dictionaries {
+    pub(crate) dictionaries: Vec<Arc<Dictionary>>,
+}
+
+impl Dictionaries {
+    pub(crate) fn load(
+        config: &[CompressionDictionary],
+        graphql_path: &str,
+    ) -> Result<Self, BoxError> {
+        Ok(Dictionaries {
+            dictionaries: config
+                .iter()
+                .map(|config| Dictionary::load(config, graphql_path).map(Arc::new))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.dictionaries.is_empty()
+    }
+
+    /// The dictionary announced by a request, if the client accepts the dictionary encoding.
+    fn available(&self, path: &str, headers: &HeaderMap) -> Option<Arc<Dictionary>> {
+        let available = headers.get(AVAILABLE_DICTIONARY)?.to_str().ok()?.trim();
+        if !accepts_dictionary_encoding(headers) {
+            return None;
+        }
+        self.dictionaries
+            .iter()
+            .find(|dictionary| dictionary.available == available && dictionary.matches(path))
+            .cloned()
+    }
+
+    /// The most recent dictionary applying to a path.
+    fn latest(&self, path: &str) -> Option<&Arc<Dictionary>> {
+        self.dictionaries
+            .iter()
+            .rev()
+            .find(|dictionary| dictionary.matches(path))
+    }
+}
+
+fn accepts_dictionary_encoding(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            parts.next() == Some(DICTIONARY_ENCODING)
+                && !parts.any(|part| part.replace(' ', "") == "q=0")
+        })
+}
+
+/// Serves a dictionary, so that clients store it for the requests it applies to.
+pub(crate) async fn serve(dictionary: Arc<Dictionary>) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (
+                HeaderName::from_static(USE_AS_DICTIONARY),
+                format!("match=\"{}\"", dictionary.match_path),
+            ),
+            (CACHE_CONTROL, "max-age=86400".to_string()),
+            (CONTENT_TYPE, "application/octet-stream".to_string()),
+        ],
+        dictionary.content.clone(),
+    )
+        .into_response()
+}
+
+/// Compresses responses with the dictionary announced by the request, or advertises the
+/// dictionary applying to the request.
+pub(crate) async fn compress(
+    dictionaries: Arc<Dictionaries>,
+    mut req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = req.uri().path().to_string();
+    let dictionary = dictionaries.available(&path, req.headers());
+    if dictionary.is_some() {
+        // The response is compressed here rather than by the compression layer
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+    }
+
+    let mut response = next.run(req).await;
+    let latest = match dictionaries.latest(&path) {
+        Some(latest) => latest,
+        None => return response,
+    };
+    for vary in ["accept-encoding", AVAILABLE_DICTIONARY] {
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static(vary));
+    }
+
+    let dictionary = match dictionary {
+        Some(dictionary) if is_compressible(response.headers()) => dictionary,
+        Some(_) => return response,
+        None => {
+            let link = format!("<{}>; rel=\"compression-dictionary\"", latest.url);
+            if let Ok(link) = HeaderValue::try_from(link) {
+                response.headers_mut().append(LINK, link);
+            }
+            return response;
+        }
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("could not read the response body: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let compressed = match tokio::task::spawn_blocking(move || dictionary.compress(&body)).await {
+        Ok(Ok(compressed)) => compressed,
+        Ok(Err(e)) => return compression_failed(e),
+        Err(e) => return compression_failed(e),
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(DICTIONARY_ENCODING),
+    );
+    Response::from_parts(parts, boxed(http_body::Full::new(Bytes::from(compressed))))
+}
+
+fn compression_failed(error: impl std::fmt::Display) -> Response {
+    tracing::error!(
+        "could not compress the response with a dictionary: {}",
+        error
+    );
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+/// Streamed and already encoded responses are not compressed with dictionaries.
+fn is_compressible(headers: &HeaderMap) -> bool {
+    !headers.contains_key(CONTENT_ENCODING)
+        && !headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("multipart/"))
+            .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionaries(content: &[u8]) -> (tempfile::TempDir, Dictionaries) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("graphql.dict");
+        std::fs::write(&path, content).unwrap();
+        let dictionaries = Dictionaries::load(
+            &[CompressionDictionary {
+                path,
+                url: "/dictionaries/graphql.dict".to_string(),
+                match_path: None,
+            }],
+            "/graphql",
+        )
+        .unwrap();
+        (dir, dictionaries)
+    }
+
+    #[test]
+    fn available_dictionaries_are_matched() {
+        let (_dir, dictionaries) = dictionaries(br#"{"data":{"topProducts":[{"name":""}]}}"#);
+        let available = dictionaries.dictionaries[0].available.clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AVAILABLE_DICTIONARY,
+            HeaderValue::try_from(available).unwrap(),
+        );
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, dcz"));
+        assert!(dictionaries.available("/graphql", &headers).is_some());
+        assert!(dictionaries.available("/other", &headers).is_none());
+
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, dcz;q=0"));
+        assert!(dictionaries.available("/graphql", &headers).is_none());
+
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("dcz"));
+        headers.insert(AVAILABLE_DICTIONARY, HeaderValue::from_static(":AAAA:"));
+        assert!(dictionaries.available("/graphql", &headers).is_none());
+    }
+
+    #[test]
+    fn responses_are_compressed_with_the_dictionary() {
+        let content = br#"{"data":{"topProducts":[{"upc":"1","name":"Table"}]}}"#;
+        let (_dir, dictionaries) = dictionaries(content);
+        let dictionary = &dictionaries.dictionaries[0];
+
+        let compressed = dictionary.compress(content).unwrap();
+        assert_eq!(&compressed[..8], &DCZ_MAGIC);
+        assert_eq!(&compressed[8..40], dictionary.hash.as_slice());
+
+        let mut decompressor =
+            zstd::bulk::Decompressor::with_dictionary(&dictionary.content).unwrap();
+        let decompressed = decompressor
+            .decompress(&compressed[40..], content.len())
+            .unwrap();
+        assert_eq!(decompressed, content);
+    }
+}
@@ -0,0 +1,93 @@
+//! Differences between two configurations, logged when the configuration is reloaded.
+
+use serde_json::Value;
+
+use super::Configuration;
+
+/// Path reported when the configurations can't be compared option by option.
+const WHOLE_CONFIGURATION: &str = "(whole configuration)";
+
+impl Configuration {
+    /// Paths of the options that differ in the new configuration, like `server.listen` or
+    /// `headers.all`, sorted.
+    ///
+    /// Only the paths are listed, not the values: they can hold secrets.
+    pub(crate) fn changes(&self, new: &Configuration) -> Vec<String> {
+        let (old, new) = match (serde_json::to_value(self), serde_json::to_value(new)) {
+            (Ok(old), Ok(new)) => (old, new),
+            // Without a way to compare them, the configurations are considered different
+            _ => return vec![WHOLE_CONFIGURATION.to_string()],
+        };
+        let mut changes = Vec::new();
+        diff("", &old, &new, &mut changes);
+        changes.sort();
+        changes
+    }
+}
+
+fn diff(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old_object), Value::Object(new_object)) => {
+            let added = new_object
+                .keys()
+                .filter(|key| !old_object.contains_key(*key));
+            for key in old_object.keys().chain(added) {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                match (old_object.get(key), new_object.get(key)) {
+                    (Some(old), Some(new)) => diff(&path, old, new, changes),
+                    _ => changes.push(path),
+                }
+            }
+        }
+        // Lists are compared as a whole
+        _ if old != new => changes.push(if path.is_empty() {
+            WHOLE_CONFIGURATION.to_string()
+        } else {
+            path.to_string()
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_options_are_listed() {
+        let old: Configuration = serde_yaml::from_str(
+            r#"
+server:
+  listen: 127.0.0.1:4000
+cors:
+  origins: [https://studio.apollographql.com]
+headers:
+  all:
+    - propagate:
+        named: authorization
+"#,
+        )
+        .unwrap();
+        let new: Configuration = serde_yaml::from_str(
+            r#"
+server:
+  listen: 127.0.0.1:4000
+cors:
+  origins: [https://studio.apollographql.com, https://example.com]
+include_subgraph_errors:
+  all: true
+"#,
+        )
+        .unwrap();
+
+        assert!(old.changes(&old).is_empty());
+        assert_eq!(
+            old.changes(&new),
+            vec!["cors.origins", "headers", "include_subgraph_errors"]
+        );
+    }
+}
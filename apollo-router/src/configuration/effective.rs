@@ -0,0 +1,247 @@
+//! Effective configuration of the router, with the origin of each option.
+//!
+//! The options set in the configuration file are recorded when it is validated, before its
+//! expressions are expanded and the defaults are applied, so that the effective configuration
+//! can tell which options come from the file, from the environment, or from their default.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use super::Configuration;
+
+/// Value of the options redacted from the configuration endpoint.
+const REDACTED: &str = "<redacted>";
+
+/// Where the value of a configuration option comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigurationOrigin {
+    /// The default value of the option
+    Default,
+    /// The configuration file
+    File,
+    /// An environment variable, expanded in the configuration file
+    Env,
+    /// A file read by a `${file.path}` expression of the configuration file
+    ReferencedFile,
+    /// The code embedding the router, for a configuration that was not read from YAML
+    Code,
+}
+
+/// The configuration the router runs with, after the expansion of expressions and with the
+/// defaults applied, and the origin of each of its options.
+#[derive(Clone, Debug, Serialize)]
+pub struct EffectiveConfiguration {
+    /// The configuration, as JSON
+    pub configuration: Value,
+    /// Origin of each option, by path like `server.listen`. Lists are options as a whole
+    pub origins: BTreeMap<String, ConfigurationOrigin>,
+}
+
+/// Paths of the options of a configuration file.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Origins {
+    /// Options set in the file
+    file: BTreeSet<String>,
+    /// Options whose value has expanded expressions, and where the expanded value comes from
+    expanded: BTreeMap<String, ConfigurationOrigin>,
+}
+
+impl Origins {
+    /// Records the options of a configuration file, before and after its expressions are
+    /// expanded.
+    pub(crate) fn new(raw: &Value, expanded: &Value) -> Self {
+        let mut origins = Origins::default();
+        origins.visit("", raw, expanded);
+        origins
+    }
+
+    fn visit(&mut self, path: &str, raw: &Value, expanded: &Value) {
+        match (raw, expanded) {
+            (Value::Object(raw), Value::Object(expanded)) => {
+                for (key, raw) in raw {
+                    if let Some(expanded) = expanded.get(key) {
+                        self.visit(&join(path, key), raw, expanded);
+                    }
+                }
+            }
+            _ => {
+                if raw != expanded {
+                    let origin = if raw.to_string().contains("${file.") {
+                        ConfigurationOrigin::ReferencedFile
+                    } else {
+                        ConfigurationOrigin::Env
+                    };
+                    self.expanded.insert(path.to_string(), origin);
+                }
+                self.file.insert(path.to_string());
+            }
+        }
+    }
+
+    /// Origin of an option of the effective configuration. The file and the effective
+    /// configuration don't always have the same shape, so an option set in the file is also the
+    /// origin of the options above or below it.
+    fn origin(&self, path: &str) -> ConfigurationOrigin {
+        if let Some((_, origin)) = self
+            .expanded
+            .iter()
+            .find(|(option, _)| related(option, path))
+        {
+            *origin
+        } else if self.file.iter().any(|option| related(option, path)) {
+            ConfigurationOrigin::File
+        } else {
+            ConfigurationOrigin::Default
+        }
+    }
+}
+
+impl Configuration {
+    /// The configuration the router runs with, and the origin of each of its options.
+    pub fn effective(&self) -> EffectiveConfiguration {
+        self.effective_configuration(false)
+    }
+
+    /// The effective configuration, without the values expanded from the environment or from
+    /// files, which can hold secrets.
+    pub(crate) fn redacted_effective(&self) -> EffectiveConfiguration {
+        self.effective_configuration(true)
+    }
+
+    fn effective_configuration(&self, redact: bool) -> EffectiveConfiguration {
+        let mut configuration = serde_json::to_value(self).unwrap_or_default();
+        let default = serde_json::to_value(Configuration::default()).unwrap_or_default();
+        let mut origins = BTreeMap::new();
+        visit(
+            "",
+            &mut configuration,
+            Some(&default),
+            &mut |path, value, default| {
+                let origin = match &self.origins {
+                    Some(file) => file.origin(path),
+                    None if Some(&*value) == default => ConfigurationOrigin::Default,
+                    None => ConfigurationOrigin::Code,
+                };
+                if redact
+                    && matches!(
+                        origin,
+                        ConfigurationOrigin::Env | ConfigurationOrigin::ReferencedFile
+                    )
+                {
+                    *value = Value::String(REDACTED.to_string());
+                }
+                origins.insert(path.to_string(), origin);
+            },
+        );
+        EffectiveConfiguration {
+            configuration,
+            origins,
+        }
+    }
+}
+
+/// Calls `f` on each option of a configuration, with its default value if there is one. Lists
+/// are options as a whole.
+fn visit(
+    path: &str,
+    value: &mut Value,
+    default: Option<&Value>,
+    f: &mut dyn FnMut(&str, &mut Value, Option<&Value>),
+) {
+    match value {
+        Value::Object(object) if !object.is_empty() => {
+            for (key, value) in object.iter_mut() {
+                let default = default.and_then(|default| default.get(key));
+                visit(&join(path, key), value, default, f);
+            }
+        }
+        _ => f(path, value, default),
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// Whether an option is the other one, or one of the options above or below it.
+fn related(option: &str, other: &str) -> bool {
+    let (shorter, longer) = if option.len() <= other.len() {
+        (option, other)
+    } else {
+        (other, option)
+    };
+    longer == shorter || (longer.starts_with(shorter) && longer[shorter.len()..].starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::configuration::validate_configuration;
+
+    #[test]
+    fn origins_are_reported() {
+        std::env::set_var("TEST_CONFIG_EFFECTIVE_PATH_UNIQUE", "/graphql");
+        let configuration = validate_configuration(
+            r#"
+server:
+  listen: 127.0.0.1:4001
+  graphql_path: ${env.TEST_CONFIG_EFFECTIVE_PATH_UNIQUE}
+"#,
+        )
+        .unwrap();
+
+        let effective = configuration.effective();
+        assert_eq!(
+            effective.configuration["server"]["graphql_path"],
+            json!("/graphql")
+        );
+        assert_eq!(
+            effective.origins["server.listen"],
+            ConfigurationOrigin::File
+        );
+        assert_eq!(
+            effective.origins["server.graphql_path"],
+            ConfigurationOrigin::Env
+        );
+        assert_eq!(
+            effective.origins["server.introspection"],
+            ConfigurationOrigin::Default
+        );
+
+        let redacted = configuration.redacted_effective();
+        assert_eq!(
+            redacted.configuration["server"]["graphql_path"],
+            json!(REDACTED)
+        );
+        assert_eq!(
+            redacted.configuration["server"]["listen"],
+            effective.configuration["server"]["listen"]
+        );
+    }
+
+    #[test]
+    fn options_set_in_code_are_reported() {
+        let configuration = Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .introspection(false)
+                    .build(),
+            )
+            .build();
+        let origins = configuration.effective().origins;
+        assert_eq!(origins["server.introspection"], ConfigurationOrigin::Code);
+        assert_eq!(origins["server.listen"], ConfigurationOrigin::Default);
+        assert!(related("plugins.experimental.dry_run", "plugins"));
+        assert!(!related("server.listen", "server.listener"));
+    }
+}
@@ -1,12 +1,20 @@
 //! Logic for loading configuration in to an object model
 // This entire file is license key functionality
+mod diff;
+mod effective;
+mod schedule;
 mod yaml;
 
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
 
 use derivative::Derivative;
 use displaydoc::Display;
@@ -30,9 +38,19 @@ use serde::Serialize;
 use serde_json::Map;
 use serde_json::Value;
 use thiserror::Error;
+use tower::steer::Picker;
+use tower::steer::Steer;
+use tower::Layer;
 use tower_http::cors::CorsLayer;
 use tower_http::cors::{self};
-
+use url::Url;
+
+pub use self::effective::ConfigurationOrigin;
+pub use self::effective::EffectiveConfiguration;
+use self::effective::Origins;
+pub(crate) use self::schedule::apply_schedules;
+pub(crate) use self::schedule::Schedule;
+pub(crate) use self::schedule::ACTIVE_SCHEDULES_CONTEXT_KEY;
 use crate::plugin::plugins;
 
 /// Configuration error.
@@ -44,6 +62,8 @@ pub(crate) enum ConfigurationError {
     CannotReadSecretFromFile(std::io::Error),
     /// could not read secret from environment variable: {0}
     CannotReadSecretFromEnv(std::env::VarError),
+    /// could not expand {expression}: {error}
+    CannotExpand { expression: String, error: String },
     /// unknown plugin {0}
     PluginUnknown(String),
     /// plugin {plugin} could not be configured: {error}
@@ -72,6 +92,63 @@ pub struct Configuration {
     #[serde(default)]
     pub(crate) cors: Cors,
 
+    /// TLS related configuration options.
+    #[serde(default)]
+    pub(crate) tls: Tls,
+
+    /// HTTP client options for the connections to subgraphs.
+    #[serde(default)]
+    pub(crate) http_client: SubgraphHttpClient,
+
+    /// Subgraphs backed by REST endpoints instead of a GraphQL server.
+    #[serde(default)]
+    pub(crate) experimental_rest_connectors: RestConnectors,
+
+    /// Subgraphs whose fields are resolved by the router itself.
+    #[serde(default)]
+    pub(crate) experimental_computed_fields: ComputedFields,
+
+    /// Serving the API schema as a federation subgraph, to compose it in another supergraph.
+    #[serde(default)]
+    pub(crate) experimental_subgraph_mode: SubgraphMode,
+
+    /// Delegation of the operations the supergraph can't resolve to an upstream GraphQL server.
+    #[serde(default)]
+    pub(crate) experimental_upstream: Upstream,
+
+    /// Parsing and serialization of the numbers of subgraph responses.
+    #[serde(default)]
+    pub(crate) experimental_json_numbers: JsonNumbers,
+
+    /// Escaping of the strings of responses, and handling of invalid strings in subgraph
+    /// responses.
+    #[serde(default)]
+    pub(crate) experimental_json_strings: JsonStrings,
+
+    /// Noncompliant behaviors of subgraphs, normalized instead of failing the subgraph request.
+    #[serde(default)]
+    pub(crate) experimental_subgraph_tolerances: SubgraphTolerances,
+
+    /// Bounds on the time spent planning operations.
+    #[serde(default)]
+    pub(crate) experimental_query_planning: QueryPlanning,
+
+    /// Timeouts and circuit breakers isolating the pipeline from misbehaving plugins.
+    #[serde(default)]
+    pub(crate) experimental_plugin_isolation: PluginIsolation,
+
+    /// Types and fields the router may request from each subgraph.
+    #[serde(default)]
+    pub(crate) experimental_subgraph_acl: SubgraphAcl,
+
+    /// Options applied during windows of time, over the rest of the configuration.
+    #[serde(default)]
+    pub(crate) experimental_schedules: Vec<Schedule>,
+
+    /// Where the supergraph schema comes from.
+    #[serde(default)]
+    pub(crate) supergraph: Supergraph,
+
     /// Plugin configuration
     #[serde(default)]
     plugins: UserPlugins,
@@ -80,10 +157,25 @@ pub struct Configuration {
     #[serde(default)]
     #[serde(flatten)]
     apollo_plugins: ApolloPlugins,
+
+    /// Options set in the configuration file, if the configuration was read from one
+    #[serde(skip)]
+    #[derivative(Debug = "ignore")]
+    origins: Option<Arc<Origins>>,
+
+    /// The configuration without the options of its schedules, as JSON
+    #[serde(skip)]
+    #[derivative(Debug = "ignore")]
+    unscheduled: Option<Arc<Value>>,
+
+    /// Names of the schedules whose options are applied
+    #[serde(skip)]
+    pub(crate) active_schedules: Vec<String>,
 }
 
 const APOLLO_PLUGIN_PREFIX: &str = "apollo.";
 const TELEMETRY_KEY: &str = "telemetry";
+const SCHEDULES_KEY: &str = "experimental_schedules";
 
 fn default_listen() -> ListenAddr {
     SocketAddr::from_str("127.0.0.1:4000").unwrap().into()
@@ -92,21 +184,53 @@ fn default_listen() -> ListenAddr {
 #[buildstructor::buildstructor]
 impl Configuration {
     #[builder]
+    #[allow(clippy::too_many_arguments)] // Used through a builder, not directly
     pub(crate) fn new(
         server: Option<Server>,
         cors: Option<Cors>,
+        tls: Option<Tls>,
+        http_client: Option<SubgraphHttpClient>,
+        experimental_rest_connectors: Option<RestConnectors>,
+        experimental_computed_fields: Option<ComputedFields>,
+        experimental_subgraph_mode: Option<SubgraphMode>,
+        experimental_upstream: Option<Upstream>,
+        experimental_json_numbers: Option<JsonNumbers>,
+        experimental_json_strings: Option<JsonStrings>,
+        experimental_subgraph_tolerances: Option<SubgraphTolerances>,
+        experimental_query_planning: Option<QueryPlanning>,
+        experimental_plugin_isolation: Option<PluginIsolation>,
+        experimental_subgraph_acl: Option<SubgraphAcl>,
+        experimental_schedules: Vec<Schedule>,
+        supergraph: Option<Supergraph>,
         plugins: Map<String, Value>,
         apollo_plugins: Map<String, Value>,
     ) -> Self {
         Self {
             server: server.unwrap_or_default(),
             cors: cors.unwrap_or_default(),
+            tls: tls.unwrap_or_default(),
+            http_client: http_client.unwrap_or_default(),
+            experimental_rest_connectors: experimental_rest_connectors.unwrap_or_default(),
+            experimental_computed_fields: experimental_computed_fields.unwrap_or_default(),
+            experimental_subgraph_mode: experimental_subgraph_mode.unwrap_or_default(),
+            experimental_upstream: experimental_upstream.unwrap_or_default(),
+            experimental_json_numbers: experimental_json_numbers.unwrap_or_default(),
+            experimental_json_strings: experimental_json_strings.unwrap_or_default(),
+            experimental_subgraph_tolerances: experimental_subgraph_tolerances.unwrap_or_default(),
+            experimental_query_planning: experimental_query_planning.unwrap_or_default(),
+            experimental_plugin_isolation: experimental_plugin_isolation.unwrap_or_default(),
+            experimental_subgraph_acl: experimental_subgraph_acl.unwrap_or_default(),
+            experimental_schedules,
+            supergraph: supergraph.unwrap_or_default(),
             plugins: UserPlugins {
                 plugins: Some(plugins),
             },
             apollo_plugins: ApolloPlugins {
                 plugins: apollo_plugins,
             },
+            origins: None,
+            unscheduled: None,
+            active_schedules: Vec::new(),
         }
     }
 
@@ -145,6 +269,46 @@ impl Configuration {
             .map(|(_, value)| value.clone())
     }
 
+    /// The configuration with the options of the schedules active at a time.
+    pub(crate) fn scheduled(&self, time: SystemTime) -> Result<Configuration, ConfigurationError> {
+        let unscheduled = match &self.unscheduled {
+            Some(unscheduled) => unscheduled.clone(),
+            None => Arc::new(
+                serde_json::to_value(self).map_err(ConfigurationError::DeserializeConfigError)?,
+            ),
+        };
+        let active: Vec<&Schedule> = self
+            .experimental_schedules
+            .iter()
+            .filter(|schedule| schedule.is_active(time))
+            .collect();
+        if active.is_empty() && self.active_schedules.is_empty() {
+            return Ok(self.clone());
+        }
+        let mut value = (*unscheduled).clone();
+        for schedule in &active {
+            schedule::merge(&mut value, &schedule.configuration);
+        }
+        let mut configuration: Configuration =
+            serde_json::from_value(value).map_err(ConfigurationError::DeserializeConfigError)?;
+        configuration.origins = self.origins.clone();
+        configuration.unscheduled = Some(unscheduled);
+        configuration.active_schedules = active
+            .into_iter()
+            .map(|schedule| schedule.name.clone())
+            .collect();
+        Ok(configuration)
+    }
+
+    /// The first start or end of a schedule after a time.
+    pub(crate) fn next_schedule_change(&self, time: SystemTime) -> Option<SystemTime> {
+        self.experimental_schedules
+            .iter()
+            .flat_map(|schedule| schedule.start.into_iter().chain(schedule.end))
+            .filter(|change| *change > time)
+            .min()
+    }
+
     // checks that we can reload configuration from the current one to the new one
     pub(crate) fn is_compatible(&self, new: &Configuration) -> Result<(), &'static str> {
         if self.apollo_plugins.plugins.get(TELEMETRY_KEY)
@@ -252,16 +416,32 @@ pub(crate) struct Server {
     #[serde(default = "default_listen")]
     pub(crate) listen: ListenAddr,
 
+    /// Serve HTTPS on the listen address, with a certificate and key read from PEM files. The
+    /// files are read again when they change
+    /// default: plaintext HTTP
+    #[serde(default)]
+    pub(crate) tls: Option<ServerTls>,
+
     /// introspection queries
     /// enabled by default
     #[serde(default = "default_introspection")]
     pub(crate) introspection: bool,
 
+    /// Experimental conditions on the requests allowed to introspect the schema, and caching of
+    /// introspection responses
+    #[serde(default)]
+    pub(crate) experimental_introspection: IntrospectionControl,
+
     /// display landing page
     /// enabled by default
     #[serde(default = "default_landing_page")]
     pub(crate) landing_page: bool,
 
+    /// Experimental choice of the landing page displayed to browsers, when `landing_page` is
+    /// enabled
+    #[serde(default)]
+    pub(crate) experimental_landing_page: LandingPage,
+
     /// The HTTP path on which GraphQL requests will be served.
     /// default: "/"
     #[serde(default = "default_graphql_path")]
@@ -272,15 +452,76 @@ pub(crate) struct Server {
     #[serde(default = "default_health_check_path")]
     pub(crate) health_check_path: String,
 
+    /// Experimental listener of the administration endpoints
+    #[serde(default)]
+    pub(crate) experimental_admin: Admin,
+
+    /// Experimental liveness and readiness endpoints
+    #[serde(default)]
+    pub(crate) experimental_health_endpoints: HealthEndpoints,
+
+    /// Experimental endpoint returning the effective configuration and the origin of each
+    /// option, with the values expanded from environment variables or files redacted
+    /// default: disabled
+    #[serde(default)]
+    pub(crate) experimental_configuration_path: Option<String>,
+
+    /// Experimental policies applied to the headers of client requests
+    #[serde(default)]
+    pub(crate) experimental_request_headers: RequestHeaders,
+
+    /// Experimental maximum size of request bodies in bytes, checked while the body is received
+    /// and while it is decompressed. Larger requests are rejected with a 413 status code
+    /// default: no limit
+    #[serde(default)]
+    pub(crate) experimental_max_request_body_size: Option<usize>,
+
+    /// Experimental names and limits of the query string parameters of GET requests
+    #[serde(default)]
+    pub(crate) experimental_get_parameters: GetParameters,
+
+    /// Experimental shared dictionaries used to compress responses for the clients that
+    /// stored them, following the Compression Dictionary Transport draft
+    #[serde(default)]
+    pub(crate) experimental_compression_dictionaries: Vec<CompressionDictionary>,
+
+    /// Experimental safe mode: if no schema can be loaded at startup, serve the health check
+    /// and answer GraphQL requests with a 503 status code until a schema is available
+    /// default: false
+    #[serde(default = "default_safe_mode")]
+    pub(crate) experimental_safe_mode: bool,
+
     /// Experimental @defer directive support
     /// default: false
     #[serde(default = "default_defer_support")]
     pub(crate) experimental_defer_support: bool,
 
+    /// Experimental options of the `multipart/mixed` responses delivering deferred results
+    #[serde(default)]
+    pub(crate) experimental_defer_multipart: DeferMultipart,
+
     /// Experimental limitation of query depth
     /// default: 4096
     #[serde(default = "default_parser_recursion_limit")]
     pub(crate) experimental_parser_recursion_limit: usize,
+
+    /// Experimental `apollo-schema-hash` and `apollo-operation-hash` response headers, with the
+    /// hash of the API schema and the hash of the operation
+    /// default: false
+    #[serde(default = "default_hash_headers")]
+    pub(crate) experimental_hash_headers: bool,
+
+    /// Experimental format of the GraphQL errors sent to clients
+    #[serde(default)]
+    pub(crate) experimental_error_format: ErrorFormat,
+
+    /// Experimental batching of GraphQL requests, sent as a JSON array in a single HTTP request
+    #[serde(default)]
+    pub(crate) experimental_batching: Batching,
+
+    /// Experimental file uploads, following the GraphQL multipart request specification
+    #[serde(default)]
+    pub(crate) experimental_file_uploads: FileUploads,
 }
 
 #[buildstructor::buildstructor]
@@ -289,26 +530,637 @@ impl Server {
     #[allow(clippy::too_many_arguments)] // Used through a builder, not directly
     pub(crate) fn new(
         listen: Option<ListenAddr>,
+        tls: Option<ServerTls>,
         introspection: Option<bool>,
+        introspection_control: Option<IntrospectionControl>,
         landing_page: Option<bool>,
+        landing_page_options: Option<LandingPage>,
         graphql_path: Option<String>,
         health_check_path: Option<String>,
+        admin: Option<Admin>,
+        health_endpoints: Option<HealthEndpoints>,
+        configuration_path: Option<String>,
+        request_headers: Option<RequestHeaders>,
+        max_request_body_size: Option<usize>,
+        get_parameters: Option<GetParameters>,
+        compression_dictionaries: Vec<CompressionDictionary>,
+        safe_mode: Option<bool>,
         defer_support: Option<bool>,
+        defer_multipart: Option<DeferMultipart>,
         parser_recursion_limit: Option<usize>,
+        hash_headers: Option<bool>,
+        error_format: Option<ErrorFormat>,
+        batching: Option<Batching>,
+        file_uploads: Option<FileUploads>,
     ) -> Self {
         Self {
             listen: listen.unwrap_or_else(default_listen),
+            tls,
             introspection: introspection.unwrap_or_else(default_introspection),
+            experimental_introspection: introspection_control.unwrap_or_default(),
             landing_page: landing_page.unwrap_or_else(default_landing_page),
+            experimental_landing_page: landing_page_options.unwrap_or_default(),
             graphql_path: graphql_path.unwrap_or_else(default_graphql_path),
             health_check_path: health_check_path.unwrap_or_else(default_health_check_path),
+            experimental_admin: admin.unwrap_or_default(),
+            experimental_health_endpoints: health_endpoints.unwrap_or_default(),
+            experimental_configuration_path: configuration_path,
+            experimental_request_headers: request_headers.unwrap_or_default(),
+            experimental_max_request_body_size: max_request_body_size,
+            experimental_get_parameters: get_parameters.unwrap_or_default(),
+            experimental_compression_dictionaries: compression_dictionaries,
+            experimental_safe_mode: safe_mode.unwrap_or_else(default_safe_mode),
             experimental_defer_support: defer_support.unwrap_or_else(default_defer_support),
+            experimental_defer_multipart: defer_multipart.unwrap_or_default(),
             experimental_parser_recursion_limit: parser_recursion_limit
                 .unwrap_or_else(default_parser_recursion_limit),
+            experimental_hash_headers: hash_headers.unwrap_or_else(default_hash_headers),
+            experimental_error_format: error_format.unwrap_or_default(),
+            experimental_batching: batching.unwrap_or_default(),
+            experimental_file_uploads: file_uploads.unwrap_or_default(),
         }
     }
 }
 
+/// Landing page displayed to browsers, on the GET requests preferring HTML.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LandingPage {
+    /// Page displayed (default: sandbox)
+    #[serde(default)]
+    pub(crate) page: LandingPageKind,
+
+    /// HTML file displayed by the `file` page, read when the router starts or reloads
+    #[serde(default)]
+    pub(crate) file: Option<PathBuf>,
+
+    /// `Content-Security-Policy` header of the landing page
+    /// default: the resources of the Apollo Sandbox or GraphiQL pages, none for the `file` page
+    #[serde(default)]
+    pub(crate) content_security_policy: Option<String>,
+
+    /// Path of the landing page, which is then displayed whatever the `Accept` header of the
+    /// request
+    /// default: the GraphQL endpoint path
+    #[serde(default)]
+    pub(crate) path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LandingPageKind {
+    /// Apollo Sandbox, to explore the schema and run operations
+    Sandbox,
+    /// GraphiQL, served from the unpkg CDN
+    Graphiql,
+    /// A static HTML file
+    File,
+    /// No landing page
+    Disabled,
+}
+
+impl Default for LandingPageKind {
+    fn default() -> Self {
+        LandingPageKind::Sandbox
+    }
+}
+
+/// TLS options for the router's listener
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ServerTls {
+    /// PEM file of the certificate chain, leaf certificate first
+    pub(crate) certificate_chain: PathBuf,
+
+    /// PEM file of the private key, in PKCS#8 or RSA format
+    pub(crate) key: PathBuf,
+
+    /// PEM file of the certificate authorities used to verify client certificates. When set,
+    /// clients must present a certificate signed by one of them
+    #[serde(default)]
+    pub(crate) client_certificate_authorities: Option<PathBuf>,
+}
+
+/// Policies applied to the headers of client requests.
+///
+/// Requests exceeding a size limit are rejected with a 431 status code.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RequestHeaders {
+    /// Maximum size in bytes of all the headers of a request, names included
+    #[serde(default)]
+    pub(crate) max_total_size: Option<usize>,
+
+    /// Maximum size in bytes of a single header, name included
+    #[serde(default)]
+    pub(crate) max_header_size: Option<usize>,
+
+    /// Headers removed from requests before they are processed
+    #[serde(default)]
+    pub(crate) remove: Vec<String>,
+
+    /// Headers whose values are converted to lowercase before they are processed
+    #[serde(default)]
+    pub(crate) lowercase_values: Vec<String>,
+}
+
+/// Conditions on the requests allowed to introspect the schema, when introspection is enabled.
+///
+/// A request is allowed if it meets any of the conditions. Without conditions, all requests are
+/// allowed.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IntrospectionControl {
+    /// Allow the requests with this context entry, set by an authentication plugin, to
+    /// introspect the schema
+    #[serde(default)]
+    pub(crate) authenticated_context: Option<String>,
+
+    /// Allow the requests of these clients, named by the `apollographql-client-name` header, to
+    /// introspect the schema
+    #[serde(default)]
+    pub(crate) client_names: Vec<String>,
+
+    /// Allow the requests received on this additional plaintext HTTP listener to introspect the
+    /// schema
+    #[serde(default)]
+    pub(crate) listen: Option<SocketAddr>,
+
+    /// Number of introspection responses cached for the schema. The cache is kept across
+    /// configuration reloads as long as the schema hash doesn't change
+    /// default: 5
+    #[serde(default)]
+    pub(crate) cache_capacity: Option<usize>,
+}
+
+/// Additional plaintext HTTP listener of the administration endpoints.
+///
+/// It serves the gRPC health checking protocol and the administration endpoints of plugins,
+/// which are not exposed on the main listener. It should only be reachable by operators.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Admin {
+    /// Socket address of the admin listener
+    /// default: disabled
+    #[serde(default)]
+    pub(crate) listen: Option<SocketAddr>,
+
+    /// Serve the gRPC health checking protocol on the admin listener
+    /// default: false
+    #[serde(default)]
+    pub(crate) grpc_health_check: bool,
+}
+
+/// Liveness and readiness endpoints.
+///
+/// The router is ready when a schema is loaded, its last Uplink poll succeeded, and every
+/// subgraph probe answers with a successful status code.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HealthEndpoints {
+    /// Path of the liveness endpoint
+    #[serde(default = "default_live_path")]
+    pub(crate) live_path: String,
+
+    /// Path of the readiness endpoint
+    #[serde(default = "default_ready_path")]
+    pub(crate) ready_path: String,
+
+    /// Subgraph endpoints checked by the readiness endpoint
+    #[serde(default)]
+    pub(crate) subgraph_probes: Vec<SubgraphProbe>,
+
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Timeout of each subgraph probe (default: 1s)
+    pub(crate) probe_timeout: Option<Duration>,
+}
+
+impl Default for HealthEndpoints {
+    fn default() -> Self {
+        Self {
+            live_path: default_live_path(),
+            ready_path: default_ready_path(),
+            subgraph_probes: Vec::new(),
+            probe_timeout: None,
+        }
+    }
+}
+
+fn default_live_path() -> String {
+    "/health/live".to_string()
+}
+
+fn default_ready_path() -> String {
+    "/health/ready".to_string()
+}
+
+/// A subgraph endpoint checked by the readiness endpoint.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphProbe {
+    /// Name of the subgraph, reported in the readiness checks
+    pub(crate) name: String,
+
+    /// URL requested with a GET request, which must answer with a successful status code
+    #[schemars(with = "String")]
+    pub(crate) url: Url,
+}
+
+/// Format of the GraphQL errors sent to clients.
+///
+/// The Apollo Server 3 format eases the migration of clients parsing errors the way Apollo
+/// Server 3 wrote them.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ErrorFormat {
+    /// Format of the errors, unless the client asks for another one (default: modern)
+    #[serde(default)]
+    pub(crate) default: ErrorStyle,
+
+    /// Let clients choose the format of the errors with an `errorFormat` parameter of the
+    /// media types of their `Accept` header, like `application/json;errorFormat=apollo_server3`
+    #[serde(default)]
+    pub(crate) negotiate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorStyle {
+    /// Errors as the router writes them
+    Modern,
+    /// Errors with an `extensions.code` and an `extensions.exception` object, holding the
+    /// stack trace if there is one
+    ApolloServer3,
+}
+
+impl Default for ErrorStyle {
+    fn default() -> Self {
+        ErrorStyle::Modern
+    }
+}
+
+/// Query planning timeout.
+///
+/// The planner can't be interrupted: when an operation times out, its planning goes on in the
+/// background and the plan is cached for the next requests.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct QueryPlanning {
+    /// Maximum time spent waiting for the plan of an operation, for example `500ms`
+    /// default: no limit
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) timeout: Option<Duration>,
+
+    /// What to do with the operations whose planning times out (default: reject)
+    #[serde(default)]
+    pub(crate) on_timeout: PlanningTimeoutPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PlanningTimeoutPolicy {
+    /// Reject the operation with a `PLANNING_TIMEOUT` error
+    Reject,
+    /// Execute the last plan computed for an operation with the same hash, even if it was
+    /// computed for a previous schema, and reject the operation if there is none
+    StalePlan,
+}
+
+impl Default for PlanningTimeoutPolicy {
+    fn default() -> Self {
+        PlanningTimeoutPolicy::Reject
+    }
+}
+
+/// Outbound ACLs of subgraphs.
+///
+/// The operations the query plans send to a subgraph with rules may only select the fields the
+/// rules allow: an operation whose plan requests another field fails planning. `__typename` and
+/// the `_entities` field of entity fetches are always allowed, the fields selected in the
+/// entities are checked.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphAcl {
+    /// Rules per subgraph, by subgraph name. Any field may be requested from the other subgraphs
+    #[serde(default)]
+    pub(crate) subgraphs: HashMap<String, SubgraphAclRules>,
+}
+
+/// Fields the router may request from a subgraph, as `Type.field`, `Type.*` or `Type` for all
+/// the fields of a type.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphAclRules {
+    /// Fields that may be requested
+    /// default: all fields
+    #[serde(default)]
+    pub(crate) allow: Option<Vec<String>>,
+
+    /// Fields that may not be requested, even if they are allowed
+    #[serde(default)]
+    pub(crate) deny: Vec<String>,
+}
+
+impl SubgraphAclRules {
+    /// Whether a field of a type may be requested.
+    pub(crate) fn allows(&self, type_name: &str, field_name: &str) -> bool {
+        let matches = |rule: &String| match rule.split_once('.') {
+            Some((rule_type, rule_field)) => {
+                rule_type == type_name && (rule_field == "*" || rule_field == field_name)
+            }
+            None => rule == type_name,
+        };
+        self.allow
+            .as_ref()
+            .map(|allow| allow.iter().any(matches))
+            .unwrap_or(true)
+            && !self.deny.iter().any(matches)
+    }
+}
+
+/// Plugins isolated from the rest of the pipeline.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PluginIsolation {
+    /// Per plugin isolation, by plugin name like `example.auth`
+    #[serde(default)]
+    pub(crate) plugins: HashMap<String, IsolatedPlugin>,
+}
+
+/// Timeout of the services of a plugin, and circuit breaker taking the plugin out of the
+/// pipeline after repeated failures.
+///
+/// The timeout applies to the time spent in the plugin itself, without the services it wraps.
+/// A failure is a timeout or an error returned by the plugin rather than by the services it
+/// wraps. Once the circuit is open, the next request after `open_duration` goes through the
+/// plugin again, and closes the circuit if it succeeds.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct IsolatedPlugin {
+    /// Maximum time spent in the plugin at each stage of a request, for example `100ms`
+    /// default: no limit
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) timeout: Option<Duration>,
+
+    /// Number of consecutive failures of the plugin opening its circuit
+    /// default: 5
+    #[serde(default)]
+    pub(crate) failure_threshold: Option<u32>,
+
+    /// Time the circuit stays open, for example `1m`
+    /// default: 30s
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) open_duration: Option<Duration>,
+
+    /// What to do with the requests while the circuit is open (default: bypass)
+    #[serde(default)]
+    pub(crate) on_open: CircuitPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CircuitPolicy {
+    /// Send the requests to the services the plugin wraps, as if the plugin was not configured
+    Bypass,
+    /// Fail the requests, like when the plugin fails
+    FailClosed,
+}
+
+impl Default for CircuitPolicy {
+    fn default() -> Self {
+        CircuitPolicy::Bypass
+    }
+}
+
+/// Where the supergraph schema comes from.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Supergraph {
+    /// A URL the supergraph schema is polled from, used when neither `--supergraph` nor
+    /// `APOLLO_KEY` is set. It is read at startup only: changing it needs a restart.
+    #[serde(default)]
+    pub(crate) source: Option<SupergraphSource>,
+
+    /// A second supergraph schema, executing a share of the requests to canary its rollout.
+    #[serde(default)]
+    pub(crate) experimental_canary: Option<Canary>,
+}
+
+/// A canary supergraph schema, loaded along the main one.
+///
+/// The canary schema has its own query planner and subgraph connections, but shares the plugins
+/// of the main schema. It is read when the router starts or reloads. The router metrics have a
+/// `schema` attribute, set to `canary` or `stable` depending on the schema a request was executed
+/// against.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Canary {
+    /// Path of the canary supergraph schema
+    pub(crate) path: PathBuf,
+
+    /// Percentage of the requests executed against the canary schema, from 0 to 100
+    /// default: 0
+    #[serde(default)]
+    pub(crate) percentage: f64,
+
+    /// Request header selecting the schema with a `canary` or `stable` value, whatever the
+    /// percentage
+    #[serde(default)]
+    pub(crate) header: Option<String>,
+}
+
+/// A supergraph schema served over HTTP(S), by a self-hosted registry for example.
+///
+/// The schema is fetched with a GET request at startup, then polled. The router reloads it when
+/// the response changes, the `ETag` of the last response being sent in an `If-None-Match` header.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SupergraphSource {
+    /// URL of the supergraph schema
+    #[schemars(with = "String")]
+    pub(crate) url: Url,
+
+    /// Headers sent with each request, like an `authorization` header
+    #[serde(default)]
+    pub(crate) headers: HashMap<String, String>,
+
+    /// Time between two polls, for example `30s`
+    /// default: 10s
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) poll_interval: Option<Duration>,
+}
+
+/// Batches of GraphQL requests, as sent by `apollo-link-batch-http`.
+///
+/// The requests of a batch are executed concurrently and their responses are sent back as a
+/// JSON array, in the order of the requests. Larger batches are rejected with a 400 status code.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Batching {
+    /// Accept POST requests with a JSON array of GraphQL requests
+    /// default: false
+    #[serde(default)]
+    pub(crate) enabled: bool,
+
+    /// Maximum number of requests in a batch
+    /// default: 32
+    #[serde(default = "default_max_batch_size")]
+    pub(crate) max_size: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_size: default_max_batch_size(),
+        }
+    }
+}
+
+/// Content negotiation and keepalive of the `multipart/mixed` responses delivering the results of
+/// queries using `@defer`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DeferMultipart {
+    /// Accept `multipart/mixed` in the `Accept` header without the `deferSpec` parameter, for
+    /// clients predating it
+    /// default: false
+    #[serde(default)]
+    pub(crate) accept_without_defer_spec: bool,
+
+    /// Interval of the heartbeats sent while waiting for the next deferred result, keeping idle
+    /// connections open through proxies
+    /// default: no heartbeats
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) heartbeat_interval: Option<Duration>,
+}
+
+fn default_max_batch_size() -> usize {
+    32
+}
+
+/// File uploads sent as `multipart/form-data` requests, following the GraphQL multipart request
+/// specification.
+///
+/// The files are not buffered: they are streamed to the subgraph receiving the variables they are
+/// for, in a `multipart/form-data` request too.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FileUploads {
+    /// Accept `multipart/form-data` requests with files
+    /// default: false
+    #[serde(default)]
+    pub(crate) enabled: bool,
+
+    /// Maximum number of files in a request
+    /// default: 4
+    #[serde(default = "default_max_files")]
+    pub(crate) max_files: usize,
+
+    /// Maximum size of a file, in bytes
+    /// default: no limit
+    #[serde(default)]
+    pub(crate) max_file_size: Option<u64>,
+}
+
+impl Default for FileUploads {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_files: default_max_files(),
+            max_file_size: None,
+        }
+    }
+}
+
+fn default_max_files() -> usize {
+    4
+}
+
+/// Names and limits of the query string parameters of GET requests.
+///
+/// Requests with a URL longer than the maximum length are rejected with a 414 status code.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GetParameters {
+    /// Parameter holding the query
+    #[serde(default = "default_query_parameter")]
+    pub(crate) query: String,
+
+    /// Parameter holding the operation name
+    #[serde(default = "default_operation_name_parameter")]
+    pub(crate) operation_name: String,
+
+    /// Parameter holding the JSON encoded variables
+    #[serde(default = "default_variables_parameter")]
+    pub(crate) variables: String,
+
+    /// Parameter holding the JSON encoded extensions
+    #[serde(default = "default_extensions_parameter")]
+    pub(crate) extensions: String,
+
+    /// Parameter holding the SHA-256 hash of an automatic persisted query as a plain string,
+    /// instead of the JSON encoded `persistedQuery` extension
+    #[serde(default)]
+    pub(crate) persisted_query_hash: Option<String>,
+
+    /// Maximum length in bytes of the path and query string of a request
+    #[serde(default)]
+    pub(crate) max_url_length: Option<usize>,
+}
+
+impl Default for GetParameters {
+    fn default() -> Self {
+        Self {
+            query: default_query_parameter(),
+            operation_name: default_operation_name_parameter(),
+            variables: default_variables_parameter(),
+            extensions: default_extensions_parameter(),
+            persisted_query_hash: None,
+            max_url_length: None,
+        }
+    }
+}
+
+fn default_query_parameter() -> String {
+    "query".to_string()
+}
+
+fn default_operation_name_parameter() -> String {
+    "operationName".to_string()
+}
+
+fn default_variables_parameter() -> String {
+    "variables".to_string()
+}
+
+fn default_extensions_parameter() -> String {
+    "extensions".to_string()
+}
+
+/// A shared compression dictionary.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CompressionDictionary {
+    /// File holding the dictionary, read when the router starts
+    pub(crate) path: PathBuf,
+
+    /// Path on which the router serves the dictionary to clients
+    pub(crate) url: String,
+
+    /// Request paths the dictionary applies to. A path ending with `*` matches any path starting
+    /// with it
+    /// default: the GraphQL path
+    #[serde(default)]
+    pub(crate) match_path: Option<String>,
+}
+
 /// Listening address.
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(untagged)]
@@ -361,7 +1213,7 @@ pub(crate) struct Cors {
     /// Set to true to allow any origin.
     ///
     /// Defaults to false
-    /// Having this set to true is the only way to allow Origin: null.
+    /// Having this or `mirror_any_origin` set to true is the only way to allow Origin: null.
     #[serde(default)]
     pub(crate) allow_any_origin: bool,
 
@@ -401,6 +1253,58 @@ pub(crate) struct Cors {
     /// Allowed request methods. Defaults to GET, POST, OPTIONS.
     #[serde(default = "default_cors_methods")]
     pub(crate) methods: Vec<String>,
+
+    /// Set to true to allow any origin by reflecting the `Origin` header of the request.
+    ///
+    /// Unlike `allow_any_origin`, responses name the origin instead of `*`. It cannot be
+    /// combined with `allow_credentials`.
+    #[serde(default)]
+    pub(crate) mirror_any_origin: bool,
+
+    /// How long browsers may cache the response of a preflight request, for example `10m`.
+    /// Defaults to the browser's own limit.
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) max_age: Option<Duration>,
+
+    /// Set to true to add the `Access-Control-Allow-Private-Network` header to preflight
+    /// responses when the browser asks for it, allowing websites on public networks to send
+    /// requests to a router on a private network.
+    #[serde(default)]
+    pub(crate) allow_private_network: bool,
+
+    /// Policies replacing the allowed origins and credentials for specific paths.
+    /// The first policy matching the path of a request applies.
+    #[serde(default)]
+    pub(crate) policies: Vec<CorsPolicy>,
+}
+
+/// Allowed origins and credentials for specific paths.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CorsPolicy {
+    /// The paths of this policy. A path ending with `*` matches any path starting with it.
+    pub(crate) paths: Vec<String>,
+
+    /// Set to true to allow any origin.
+    #[serde(default)]
+    pub(crate) allow_any_origin: bool,
+
+    /// Set to true to allow any origin by reflecting the `Origin` header of the request.
+    #[serde(default)]
+    pub(crate) mirror_any_origin: bool,
+
+    /// Set to true to add the `Access-Control-Allow-Credentials` header.
+    #[serde(default)]
+    pub(crate) allow_credentials: bool,
+
+    /// The origin(s) to allow requests from.
+    #[serde(default)]
+    pub(crate) origins: Vec<String>,
+
+    /// `Regex`es you want to match the origins against to determine if they're allowed.
+    #[serde(default)]
+    pub(crate) match_origins: Option<Vec<String>>,
 }
 
 impl Default for Cors {
@@ -413,6 +1317,10 @@ impl Default for Cors {
             allow_headers: Default::default(),
             expose_headers: Default::default(),
             match_origins: Default::default(),
+            mirror_any_origin: Default::default(),
+            max_age: Default::default(),
+            allow_private_network: Default::default(),
+            policies: Default::default(),
         }
     }
 }
@@ -441,10 +1349,18 @@ fn default_health_check_path() -> String {
     String::from("/.well-known/apollo/server-health")
 }
 
+fn default_safe_mode() -> bool {
+    false
+}
+
 fn default_defer_support() -> bool {
     false
 }
 
+fn default_hash_headers() -> bool {
+    false
+}
+
 fn default_parser_recursion_limit() -> usize {
     // This is `apollo-parser`’s default, which protects against stack overflow
     // but is still very high for "reasonable" queries.
@@ -471,6 +1387,10 @@ impl Cors {
         origins: Option<Vec<String>>,
         match_origins: Option<Vec<String>>,
         methods: Option<Vec<String>>,
+        mirror_any_origin: Option<bool>,
+        max_age: Option<Duration>,
+        allow_private_network: Option<bool>,
+        policies: Vec<CorsPolicy>,
     ) -> Self {
         Self {
             expose_headers,
@@ -480,12 +1400,52 @@ impl Cors {
             allow_any_origin: allow_any_origin.unwrap_or_default(),
             allow_credentials: allow_credentials.unwrap_or_default(),
             allow_headers: allow_headers.unwrap_or_default(),
+            mirror_any_origin: mirror_any_origin.unwrap_or_default(),
+            max_age,
+            allow_private_network: allow_private_network.unwrap_or_default(),
+            policies,
         }
     }
 }
 
 impl Cors {
-    pub(crate) fn into_layer(self) -> Result<CorsLayer, String> {
+    pub(crate) fn into_layer(self) -> Result<CorsPolicyLayer, String> {
+        let policies = self
+            .policies
+            .iter()
+            .map(|policy| self.with_policy(policy))
+            .collect::<Vec<_>>();
+        let mut layers = vec![self.policy_layer()?];
+        for policy in &policies {
+            layers.push(policy.policy_layer()?);
+        }
+        Ok(CorsPolicyLayer {
+            layers,
+            picker: PathPicker {
+                paths: Arc::new(
+                    self.policies
+                        .into_iter()
+                        .map(|policy| policy.paths)
+                        .collect(),
+                ),
+            },
+        })
+    }
+
+    /// The configuration of a path policy, with the headers and methods of this configuration.
+    fn with_policy(&self, policy: &CorsPolicy) -> Cors {
+        Cors {
+            allow_any_origin: policy.allow_any_origin,
+            mirror_any_origin: policy.mirror_any_origin,
+            allow_credentials: policy.allow_credentials,
+            origins: policy.origins.clone(),
+            match_origins: policy.match_origins.clone(),
+            policies: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    fn policy_layer(&self) -> Result<CorsLayer, String> {
         // Ensure configuration is valid before creating CorsLayer
 
         self.ensure_usable_cors_rules()?;
@@ -523,12 +1483,21 @@ impl Cors {
                         .ok()
                 },
             )));
+        let cors = match self.max_age {
+            Some(max_age) => cors.max_age(max_age),
+            None => cors,
+        };
 
         if self.allow_any_origin {
             Ok(cors.allow_origin(cors::Any))
-        } else if let Some(match_origins) = self.match_origins {
+        } else if self.mirror_any_origin {
+            Ok(cors.allow_origin(cors::AllowOrigin::predicate(
+                |_: &HeaderValue, _: &Parts| true,
+            )))
+        } else if let Some(match_origins) = &self.match_origins {
+            let origins = self.origins.clone();
             let regexes = match_origins
-                .into_iter()
+                .iter()
                 .filter_map(|regex| {
                     Regex::from_str(regex.as_str())
                         .map_err(|_| tracing::error!("origin regex '{regex}' is not valid"))
@@ -541,21 +1510,23 @@ impl Cors {
                     origin
                         .to_str()
                         .map(|o| {
-                            self.origins.iter().any(|origin| origin.as_str() == o)
+                            origins.iter().any(|origin| origin.as_str() == o)
                                 || regexes.iter().any(|regex| regex.is_match(o))
                         })
-                        .unwrap_or_default()
-                },
-            )))
-        } else {
-            Ok(cors.allow_origin(cors::AllowOrigin::list(
-                self.origins.into_iter().filter_map(|origin| {
-                    origin
-                        .parse()
-                        .map_err(|_| tracing::error!("origin '{origin}' is not valid"))
-                        .ok()
-                }),
+                        .unwrap_or_default()
+                },
             )))
+        } else {
+            Ok(
+                cors.allow_origin(cors::AllowOrigin::list(self.origins.iter().filter_map(
+                    |origin| {
+                        origin
+                            .parse()
+                            .map_err(|_| tracing::error!("origin '{origin}' is not valid"))
+                            .ok()
+                    },
+                ))),
+            )
         }
     }
 
@@ -585,6 +1556,11 @@ impl Cors {
                     with `Access-Control-Allow-Origin: *`");
             }
 
+            if self.mirror_any_origin {
+                return Err("Invalid CORS configuration: Cannot combine `Access-Control-Allow-Credentials: true` \
+                    with `mirror_any_origin: true`");
+            }
+
             if let Some(headers) = &self.expose_headers {
                 if headers.iter().any(|x| x == "*") {
                     return Err("Invalid CORS configuration: Cannot combine `Access-Control-Allow-Credentials: true` \
@@ -596,6 +1572,467 @@ impl Cors {
     }
 }
 
+/// CORS layer applying the policy of the request path, or the default policy.
+#[derive(Clone, Debug)]
+pub(crate) struct CorsPolicyLayer {
+    /// The default layer, followed by the layers of the path policies.
+    layers: Vec<CorsLayer>,
+    picker: PathPicker,
+}
+
+impl<S: Clone> Layer<S> for CorsPolicyLayer {
+    type Service = Steer<cors::Cors<S>, PathPicker, http::Request<hyper::Body>>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Steer::new(
+            self.layers
+                .iter()
+                .map(|layer| layer.layer(inner.clone()))
+                .collect::<Vec<_>>(),
+            self.picker.clone(),
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PathPicker {
+    paths: Arc<Vec<Vec<String>>>,
+}
+
+impl<S, B> Picker<S, http::Request<B>> for PathPicker {
+    fn pick(&mut self, request: &http::Request<B>, _services: &[S]) -> usize {
+        let path = request.uri().path();
+        self.paths
+            .iter()
+            .position(|paths| {
+                paths.iter().any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => path.starts_with(prefix),
+                    None => path == pattern,
+                })
+            })
+            .map(|index| index + 1)
+            .unwrap_or_default()
+    }
+}
+
+/// TLS related configuration options.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Tls {
+    /// TLS options for the connections to subgraphs
+    #[serde(default)]
+    pub(crate) subgraph: SubgraphTls,
+}
+
+/// TLS options for the connections to subgraphs
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphTls {
+    /// Use the X.509 SVID fetched from the SPIFFE Workload API as client certificate,
+    /// and its trust bundle to verify subgraph certificates
+    #[serde(default)]
+    pub(crate) spiffe: Option<Spiffe>,
+
+    /// Options for all subgraphs. Subgraphs with options don't use the SPIFFE identity
+    #[serde(default)]
+    pub(crate) all: Option<TlsClient>,
+
+    /// Per subgraph options, overriding the options for all subgraphs
+    #[serde(default)]
+    pub(crate) subgraphs: HashMap<String, TlsClient>,
+}
+
+impl SubgraphTls {
+    /// The options for a subgraph, if it has any.
+    pub(crate) fn for_subgraph(&self, name: &str) -> Option<TlsClient> {
+        match (&self.all, self.subgraphs.get(name)) {
+            (None, None) => None,
+            (Some(all), None) => Some(all.clone()),
+            (None, Some(subgraph)) => Some(subgraph.clone()),
+            (Some(all), Some(subgraph)) => Some(TlsClient {
+                certificate_authorities: subgraph
+                    .certificate_authorities
+                    .clone()
+                    .or_else(|| all.certificate_authorities.clone()),
+                client_authentication: subgraph
+                    .client_authentication
+                    .clone()
+                    .or_else(|| all.client_authentication.clone()),
+            }),
+        }
+    }
+}
+
+/// TLS options for the connections to a subgraph
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsClient {
+    /// PEM file of the certificate authorities used to verify the subgraph certificate
+    /// default: the root certificates of the operating system
+    #[serde(default)]
+    pub(crate) certificate_authorities: Option<PathBuf>,
+
+    /// Client certificate presented to the subgraph, for mutual TLS
+    #[serde(default)]
+    pub(crate) client_authentication: Option<TlsClientAuth>,
+}
+
+/// Client certificate and private key
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsClientAuth {
+    /// PEM file of the certificate chain, leaf certificate first
+    pub(crate) certificate_chain: PathBuf,
+
+    /// PEM file of the private key, in PKCS#8 or RSA format
+    pub(crate) key: PathBuf,
+}
+
+/// HTTP client options for the connections to subgraphs
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphHttpClient {
+    /// Options for all subgraphs
+    #[serde(default)]
+    pub(crate) all: Option<HttpClientOptions>,
+
+    /// Per subgraph options, overriding the options for all subgraphs
+    #[serde(default)]
+    pub(crate) subgraphs: HashMap<String, HttpClientOptions>,
+}
+
+impl SubgraphHttpClient {
+    /// The options for a subgraph, the unset options falling back to the options for all subgraphs.
+    pub(crate) fn for_subgraph(&self, name: &str) -> HttpClientOptions {
+        let all = self.all.clone().unwrap_or_default();
+        match self.subgraphs.get(name) {
+            None => all,
+            Some(subgraph) => HttpClientOptions {
+                http2: subgraph.http2.or(all.http2),
+                pool_max_idle_per_host: subgraph
+                    .pool_max_idle_per_host
+                    .or(all.pool_max_idle_per_host),
+                pool_idle_timeout: subgraph.pool_idle_timeout.or(all.pool_idle_timeout),
+                tcp_keepalive: subgraph.tcp_keepalive.or(all.tcp_keepalive),
+                experimental_capability_discovery: subgraph
+                    .experimental_capability_discovery
+                    .or(all.experimental_capability_discovery),
+                capabilities_ttl: subgraph.capabilities_ttl.or(all.capabilities_ttl),
+                experimental_batching: subgraph.experimental_batching.or(all.experimental_batching),
+            },
+        }
+    }
+}
+
+/// HTTP client options for the connections to a subgraph
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HttpClientOptions {
+    /// Use of HTTP/2 with the subgraph
+    /// default: enable
+    #[serde(default)]
+    pub(crate) http2: Option<Http2>,
+
+    /// Maximum number of idle connections kept open to each host of the subgraph
+    /// default: no limit
+    #[serde(default)]
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+
+    /// Delay after which an idle connection is closed
+    /// default: 90s
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) pool_idle_timeout: Option<Duration>,
+
+    /// Interval of the TCP keepalive probes sent on idle connections
+    /// default: no keepalive
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) tcp_keepalive: Option<Duration>,
+
+    /// Learn from the responses of the subgraph the encodings it accepts for compressed requests,
+    /// whether it supports automatic persisted queries, and whether it answers deferring
+    /// operations incrementally
+    /// default: disabled
+    #[serde(default)]
+    pub(crate) experimental_capability_discovery: Option<bool>,
+
+    /// Delay after which the capabilities learned from the subgraph responses are learned again
+    /// default: 5m
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) capabilities_ttl: Option<Duration>,
+
+    /// Send the requests of the fetches of an operation starting together, like the branches of
+    /// a parallel step, as a single request holding the array of the GraphQL requests. The
+    /// subgraph must accept batches of requests
+    /// default: disabled
+    #[serde(default)]
+    pub(crate) experimental_batching: Option<bool>,
+}
+
+/// Use of HTTP/2 with a subgraph
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Http2 {
+    /// HTTP/2 if the subgraph accepts it during the TLS handshake, HTTP/1.1 otherwise
+    Enable,
+    /// HTTP/1.1 only
+    Disable,
+    /// HTTP/2 only, including over plain text connections, where the subgraph is known to
+    /// support it
+    Http2Only,
+}
+
+/// Noncompliant behaviors tolerated from subgraphs
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphTolerances {
+    /// Tolerances for all subgraphs
+    #[serde(default)]
+    pub(crate) all: Option<Tolerances>,
+
+    /// Per subgraph tolerances, overriding the tolerances for all subgraphs
+    #[serde(default)]
+    pub(crate) subgraphs: HashMap<String, Tolerances>,
+}
+
+impl SubgraphTolerances {
+    /// The tolerances for a subgraph, the unset ones falling back to the tolerances for all
+    /// subgraphs.
+    pub(crate) fn for_subgraph(&self, name: &str) -> Tolerances {
+        let all = self.all.clone().unwrap_or_default();
+        match self.subgraphs.get(name) {
+            None => all,
+            Some(subgraph) => Tolerances {
+                non_json_content_type: subgraph.non_json_content_type.or(all.non_json_content_type),
+                errors_without_message: subgraph
+                    .errors_without_message
+                    .or(all.errors_without_message),
+                response_shape: subgraph.response_shape.or(all.response_shape),
+            },
+        }
+    }
+}
+
+/// Noncompliant behaviors tolerated from a subgraph. Responses with these behaviors are
+/// normalized into spec-compliant responses, and a warning is logged.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Tolerances {
+    /// Accept JSON bodies with a content type other than `application/json` or
+    /// `application/graphql+json`, like `text/plain`
+    /// default: false
+    #[serde(default)]
+    pub(crate) non_json_content_type: Option<bool>,
+
+    /// Accept errors without a string `message`, and errors that are strings instead of objects
+    /// default: false
+    #[serde(default)]
+    pub(crate) errors_without_message: Option<bool>,
+
+    /// Accept a single error instead of a list of errors, `data` that is not an object, and
+    /// invalid `locations`, `path` or `extensions` fields, which are dropped
+    /// default: false
+    #[serde(default)]
+    pub(crate) response_shape: Option<bool>,
+}
+
+/// Subgraphs backed by REST endpoints
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RestConnectors {
+    /// Per subgraph REST connector, replacing the GraphQL server of the subgraph
+    #[serde(default)]
+    pub(crate) subgraphs: HashMap<String, RestConnector>,
+}
+
+/// REST endpoints resolving the entities of a subgraph
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RestConnector {
+    /// Endpoint of each entity type, by type name
+    pub(crate) entities: HashMap<String, RestEntity>,
+}
+
+/// REST endpoint returning an entity
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RestEntity {
+    /// URL template of the endpoint, relative to the subgraph URL, with key fields of the entity
+    /// between braces, like `/products/{upc}` or `/users/{account.id}`
+    pub(crate) url: String,
+
+    /// JSON path of entity fields in the response of the endpoint, like `$.pricing.amount`.
+    /// The other fields are read from the top level fields of the response
+    #[serde(default)]
+    pub(crate) fields: HashMap<String, String>,
+}
+
+/// Subgraphs resolved by the router
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ComputedFields {
+    /// Per subgraph computed fields, replacing the GraphQL server of the subgraph
+    #[serde(default)]
+    pub(crate) subgraphs: HashMap<String, ComputedSubgraph>,
+}
+
+/// Fields of a subgraph resolved by the router
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ComputedSubgraph {
+    /// Value of each field, by coordinate like `Query.serverTime` or `User.fullName`
+    pub(crate) fields: HashMap<String, ComputedField>,
+}
+
+/// Value of a field resolved by the router
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum ComputedField {
+    /// Current time of the router, as an RFC 3339 string
+    ServerTime,
+    /// Value of this header of the client request
+    Header(String),
+    /// Value of this context entry
+    Context(String),
+    /// Constant value
+    Value(serde_json::Value),
+    /// Fields of the entity joined with a separator. The fields are required with `@requires`
+    Concat {
+        /// Names of the fields of the entity
+        fields: Vec<String>,
+        /// Separator between the fields (default: none)
+        #[serde(default)]
+        separator: String,
+    },
+}
+
+/// The router as a federation subgraph
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphMode {
+    /// Answer the `_service` and `_entities` fields of the federation subgraph specification
+    /// default: false
+    #[serde(default)]
+    pub(crate) enabled: bool,
+}
+
+/// Upstream GraphQL server, like a monolith being migrated to federation.
+///
+/// Operations with root fields the supergraph doesn't have are sent to the upstream server, and
+/// the responses to introspection queries merge the schemas of both.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Upstream {
+    /// URL of the upstream GraphQL server
+    /// default: no delegation
+    #[serde(default)]
+    pub(crate) url: Option<Url>,
+
+    /// Name of the upstream server in the subgraph options, like the HTTP client options, TLS and
+    /// header propagation. It can't be the name of a subgraph
+    /// default: upstream
+    #[serde(default = "default_upstream_name")]
+    pub(crate) name: String,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            url: None,
+            name: default_upstream_name(),
+        }
+    }
+}
+
+fn default_upstream_name() -> String {
+    "upstream".to_string()
+}
+
+/// Parsing and serialization of the numbers of subgraph responses.
+///
+/// Integers are kept as 64-bit signed or unsigned integers, and other numbers are parsed as
+/// 64-bit floats.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonNumbers {
+    /// Keep the values of `Int` fields that don't fit in 32 bits instead of replacing them with
+    /// null, for subgraphs returning 64-bit integers
+    #[serde(default)]
+    pub(crate) preserve_integer_width: bool,
+
+    /// Reject subgraph responses holding numbers that can't be parsed without losing precision,
+    /// like integers beyond the 64-bit range, instead of rounding them to the nearest float
+    #[serde(default)]
+    pub(crate) forbid_lossy_coercion: bool,
+
+    /// Serialize the integers of responses beyond 2^53 in absolute value as strings, so that
+    /// clients parsing numbers as 64-bit floats keep all their digits
+    #[serde(default)]
+    pub(crate) large_integers_as_strings: bool,
+}
+
+/// Escaping of the strings of responses, and handling of invalid strings in subgraph
+/// responses.
+///
+/// By default, responses are serialized as UTF-8, only escaping the characters that JSON
+/// requires.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonStrings {
+    /// Escape all the non-ASCII characters of responses, as `\uXXXX` sequences
+    #[serde(default)]
+    pub(crate) ascii_only: bool,
+
+    /// Escape the U+2028 and U+2029 line separators of responses, which some JavaScript parsers
+    /// and script embeddings treat as line terminators
+    #[serde(default)]
+    pub(crate) escape_line_separators: bool,
+
+    /// Handling of the lone surrogates of subgraph responses, UTF-16 escapes like `\ud800`
+    /// that don't encode a character
+    #[serde(default)]
+    pub(crate) lone_surrogates: LoneSurrogates,
+}
+
+/// Handling of the lone surrogates of subgraph responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LoneSurrogates {
+    /// Reject the subgraph response
+    Error,
+    /// Replace them with the U+FFFD replacement character
+    Replace,
+}
+
+impl Default for LoneSurrogates {
+    fn default() -> Self {
+        LoneSurrogates::Error
+    }
+}
+
+/// SPIFFE Workload API options
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Spiffe {
+    /// Address of the Workload API socket, like `unix:///tmp/spire-agent/public/api.sock`
+    /// default: the `SPIFFE_ENDPOINT_SOCKET` environment variable
+    #[serde(default)]
+    pub(crate) endpoint_socket: Option<String>,
+
+    /// SPIFFE ID of the SVID to use, when the workload is entitled to several
+    /// default: the first SVID returned by the Workload API
+    #[serde(default)]
+    pub(crate) spiffe_id: Option<String>,
+
+    /// SPIFFE IDs accepted for subgraphs whose certificate is issued by the trust bundle,
+    /// matched against the URI subject alternative name of the certificate
+    /// default: any SPIFFE ID of the router's trust domain
+    #[serde(default)]
+    pub(crate) allowed_ids: Vec<String>,
+}
+
 /// Generate a JSON schema for the configuration.
 pub(crate) fn generate_config_schema() -> RootSchema {
     let settings = SchemaSettings::draft07().with(|s| {
@@ -641,7 +2078,7 @@ pub(crate) fn validate_configuration(raw_yaml: &str) -> Result<Configuration, Co
             error: e.to_string(),
         }
     })?;
-    let expanded_yaml = expand_env_variables(yaml);
+    let expanded_yaml = expand_env_variables(yaml)?;
     let schema = serde_json::to_value(generate_config_schema()).map_err(|e| {
         ConfigurationError::InvalidConfiguration {
             message: "failed to parse schema",
@@ -683,9 +2120,10 @@ pub(crate) fn validate_configuration(raw_yaml: &str) -> Result<Configuration, Co
                                     e.instance = Cow::Owned(coerce(value));
 
                                     Some(format!(
-                                        "{}. {}\n\n{}\n{}^----- {}",
+                                        "{}. {} (line {})\n\n{}\n{}^----- {}",
                                         idx + 1,
                                         e.instance_path,
+                                        marker.line(),
                                         lines,
                                         " ".repeat(0.max(marker.col())),
                                         e
@@ -713,9 +2151,10 @@ pub(crate) fn validate_configuration(raw_yaml: &str) -> Result<Configuration, Co
                                         .join("\n");
 
                                     Some(format!(
-                                        "{}. {}\n\n{}\n└-----> {}",
+                                        "{}. {} (line {})\n\n{}\n└-----> {}",
                                         idx + 1,
                                         e.instance_path,
+                                        start_marker.line(),
                                         lines,
                                         e
                                     ))
@@ -745,9 +2184,10 @@ pub(crate) fn validate_configuration(raw_yaml: &str) -> Result<Configuration, Co
                                         .join("\n");
 
                                     Some(format!(
-                                        "{}. {}\n\n{}\n└-----> {}",
+                                        "{}. {} (line {})\n\n{}\n└-----> {}",
                                         idx + 1,
                                         e.instance_path,
+                                        start_marker.line(),
                                         lines,
                                         e
                                     ))
@@ -776,9 +2216,25 @@ pub(crate) fn validate_configuration(raw_yaml: &str) -> Result<Configuration, Co
         }
     }
 
-    let config: Configuration = serde_json::from_value(expanded_yaml)
+    let origins = Origins::new(yaml, &expanded_yaml);
+    let mut config: Configuration = serde_json::from_value(expanded_yaml.clone())
         .map_err(ConfigurationError::DeserializeConfigError)?;
+    config.origins = Some(Arc::new(origins));
+    validate_options(&config)?;
+
+    // ------------- Check the options of each schedule ----------------
+    if !config.experimental_schedules.is_empty() {
+        for schedule in &config.experimental_schedules {
+            validate_schedule(schedule, &expanded_yaml, &schema)?;
+        }
+        config.unscheduled = Some(Arc::new(expanded_yaml));
+    }
+
+    Ok(config)
+}
 
+/// Checks the options which the JSON schema can't check.
+fn validate_options(config: &Configuration) -> Result<(), ConfigurationError> {
     // ------------- Check for unknown fields at runtime ----------------
     // We can't do it with the `deny_unknown_fields` property on serde because we are using `flatten`
     let registered_plugins = plugins();
@@ -838,39 +2294,136 @@ pub(crate) fn validate_configuration(raw_yaml: &str) -> Result<Configuration, Co
             );
     }
 
-    Ok(config)
+    Ok(())
+}
+
+/// Checks the configuration with the options of a schedule merged over it.
+fn validate_schedule(
+    schedule: &Schedule,
+    unscheduled: &Value,
+    schema: &JSONSchema,
+) -> Result<(), ConfigurationError> {
+    let invalid = |error: String| ConfigurationError::InvalidConfiguration {
+        message: "invalid schedule",
+        error: format!("schedule '{}': {}", schedule.name, error),
+    };
+    for key in [SCHEDULES_KEY, TELEMETRY_KEY] {
+        if schedule.configuration.contains_key(key) {
+            return Err(invalid(format!("'{}' cannot be scheduled", key)));
+        }
+    }
+    if let (Some(start), Some(end)) = (schedule.start, schedule.end) {
+        if end <= start {
+            return Err(invalid("it must end after its start".to_string()));
+        }
+    }
+
+    let mut value = unscheduled.clone();
+    schedule::merge(&mut value, &schedule.configuration);
+    if let Err(errors) = schema.validate(&value) {
+        return Err(invalid(
+            errors
+                .map(|error| format!("{}: {}", error.instance_path, error))
+                .join(", "),
+        ));
+    }
+    let config: Configuration =
+        serde_json::from_value(value).map_err(|error| invalid(error.to_string()))?;
+    validate_options(&config).map_err(|error| invalid(error.to_string()))
 }
 
-fn expand_env_variables(configuration: &serde_json::Value) -> serde_json::Value {
+fn expand_env_variables(
+    configuration: &serde_json::Value,
+) -> Result<serde_json::Value, ConfigurationError> {
     let mut configuration = configuration.clone();
-    visit(&mut configuration);
-    configuration
+    visit(&mut configuration)?;
+    Ok(configuration)
 }
 
-fn visit(value: &mut Value) {
+fn visit(value: &mut Value) -> Result<(), ConfigurationError> {
     let mut expanded: Option<String> = None;
     match value {
         Value::String(value) => {
-            let new_value = envmnt::expand(
-                value,
-                Some(
-                    ExpandOptions::new()
-                        .clone_with_expansion_type(ExpansionType::UnixBracketsWithDefaults),
-                ),
-            );
+            let new_value = expand(value)?;
 
             if &new_value != value {
                 expanded = Some(new_value);
             }
         }
-        Value::Array(a) => a.iter_mut().for_each(visit),
-        Value::Object(o) => o.iter_mut().for_each(|(_, v)| visit(v)),
+        Value::Array(a) => a.iter_mut().try_for_each(visit)?,
+        Value::Object(o) => o.iter_mut().try_for_each(|(_, v)| visit(v))?,
         _ => {}
     }
     // The expansion may have resulted in a primitive, reparse and replace
     if let Some(expanded) = expanded {
         *value = coerce(&expanded)
     }
+    Ok(())
+}
+
+/// Expands the `${env.NAME}`, `${env.NAME:default}` and `${file.path}` expressions of a string,
+/// then the `${NAME}` and `${NAME:default}` expressions around them. The values read from the
+/// environment or from files are not expanded again.
+fn expand(value: &str) -> Result<String, ConfigurationError> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut literal_start = 0;
+    let mut position = 0;
+    while let Some(start) = value[position..].find("${").map(|start| position + start) {
+        let end = match value[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let resolved = match value[start + 2..end].split_once('.') {
+            Some(("env", variable)) => Some(expand_env(variable)?),
+            Some(("file", path)) => Some(expand_file(path)?),
+            _ => None,
+        };
+        if let Some(resolved) = resolved {
+            expanded.push_str(&expand_unix(&value[literal_start..start]));
+            expanded.push_str(&resolved);
+            literal_start = end + 1;
+        }
+        position = end + 1;
+    }
+    expanded.push_str(&expand_unix(&value[literal_start..]));
+    Ok(expanded)
+}
+
+fn expand_unix(value: &str) -> String {
+    envmnt::expand(
+        value,
+        Some(
+            ExpandOptions::new().clone_with_expansion_type(ExpansionType::UnixBracketsWithDefaults),
+        ),
+    )
+}
+
+/// Value of an environment variable, or its default. Unlike `${NAME}`, a missing variable without
+/// default is an error.
+fn expand_env(variable: &str) -> Result<String, ConfigurationError> {
+    let (name, default) = match variable.split_once(':') {
+        Some((name, default)) => (name, Some(default)),
+        None => (variable, None),
+    };
+    match (std::env::var(name), default) {
+        (Ok(value), _) => Ok(value),
+        (Err(std::env::VarError::NotPresent), Some(default)) => Ok(default.to_string()),
+        (Err(error), _) => Err(ConfigurationError::CannotExpand {
+            expression: format!("${{env.{}}}", name),
+            error: error.to_string(),
+        }),
+    }
+}
+
+/// Content of a file, without its trailing line breaks.
+fn expand_file(path: &str) -> Result<String, ConfigurationError> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(content.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        Err(error) => Err(ConfigurationError::CannotExpand {
+            expression: format!("${{file.{}}}", path),
+            error: error.to_string(),
+        }),
+    }
 }
 
 fn coerce(expanded: &str) -> Value {
@@ -1223,6 +2776,25 @@ cors:
         assert_eq!(error, "Invalid CORS configuration: Cannot combine `Access-Control-Allow-Credentials: true` with `Access-Control-Allow-Origin: *`");
     }
 
+    #[test]
+    fn it_does_not_allow_mirrored_origins_with_credentials() {
+        let cfg = validate_configuration(
+            r#"
+cors:
+  policies:
+    - paths: [ "/public/*" ]
+      allow_credentials: true
+      mirror_any_origin: true
+        "#,
+        )
+        .expect("should not have resulted in an error");
+        let error = cfg
+            .cors
+            .into_layer()
+            .expect_err("should have resulted in an error");
+        assert_eq!(error, "Invalid CORS configuration: Cannot combine `Access-Control-Allow-Credentials: true` with `mirror_any_origin: true`");
+    }
+
     #[test]
     fn validate_project_config_files() {
         #[cfg(not(unix))]
@@ -1293,6 +2865,55 @@ server:
         insta::assert_snapshot!(error.to_string());
     }
 
+    #[test]
+    fn env_and_file_expressions_are_expanded() {
+        std::env::set_var("TEST_CONFIG_ENV_EXPRESSION_UNIQUE", "/graphql");
+        std::env::set_var("TEST_CONFIG_NUMERIC_ENV_UNIQUE", "5");
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        fs::write(&secret, "s3cr3t ${TEST_CONFIG_NUMERIC_ENV_UNIQUE}\n").unwrap();
+
+        let configuration = validate_configuration(&format!(
+            r#"
+server:
+  graphql_path: ${{env.TEST_CONFIG_ENV_EXPRESSION_UNIQUE}}
+  introspection: ${{env.TEST_CONFIG_MISSING_ENV_UNIQUE:false}}
+headers:
+  all:
+    - insert:
+        name: authorization
+        value: Bearer ${{file.{}}} ${{TEST_CONFIG_NUMERIC_ENV_UNIQUE}}
+"#,
+            secret.display()
+        ))
+        .unwrap();
+        assert_eq!(configuration.server.graphql_path, "/graphql");
+        assert!(!configuration.server.introspection);
+        assert_eq!(
+            configuration.apollo_plugins.plugins["headers"]["all"][0]["insert"]["value"],
+            "Bearer s3cr3t ${TEST_CONFIG_NUMERIC_ENV_UNIQUE} 5"
+        );
+
+        let error = validate_configuration(
+            r#"
+server:
+  graphql_path: ${env.TEST_CONFIG_MISSING_ENV_UNIQUE}
+"#,
+        )
+        .expect_err("Must have an error because the variable is missing");
+        assert_eq!(
+            error.to_string(),
+            "could not expand ${env.TEST_CONFIG_MISSING_ENV_UNIQUE}: environment variable not found"
+        );
+        assert!(validate_configuration(
+            r#"
+server:
+  graphql_path: ${file./nonexistent/router/secret}
+"#,
+        )
+        .is_err());
+    }
+
     #[test]
     fn line_precise_config_errors_with_inline_sequence_env_expansion() {
         std::env::set_var("TEST_CONFIG_NUMERIC_ENV_UNIQUE", "5");
@@ -1345,4 +2966,73 @@ server:
         .expect_err("should have resulted in an error");
         insta::assert_snapshot!(error.to_string());
     }
+
+    #[test]
+    fn subgraph_tls_options_are_merged() {
+        let config: Configuration = serde_yaml::from_str(
+            r#"
+tls:
+  subgraph:
+    all:
+      certificate_authorities: ca.pem
+    subgraphs:
+      products:
+        client_authentication:
+          certificate_chain: products.crt
+          key: products.key
+      accounts:
+        certificate_authorities: accounts-ca.pem
+        "#,
+        )
+        .unwrap();
+        let tls = &config.tls.subgraph;
+
+        let products = tls.for_subgraph("products").unwrap();
+        assert_eq!(
+            products.certificate_authorities,
+            Some(PathBuf::from("ca.pem"))
+        );
+        assert_eq!(
+            products.client_authentication.unwrap().key,
+            PathBuf::from("products.key")
+        );
+        let accounts = tls.for_subgraph("accounts").unwrap();
+        assert_eq!(
+            accounts.certificate_authorities,
+            Some(PathBuf::from("accounts-ca.pem"))
+        );
+        assert!(accounts.client_authentication.is_none());
+        assert!(tls.for_subgraph("reviews").is_some());
+        assert!(Configuration::default()
+            .tls
+            .subgraph
+            .for_subgraph("reviews")
+            .is_none());
+    }
+
+    #[test]
+    fn http_client_options_are_merged() {
+        let config: Configuration = serde_yaml::from_str(
+            r#"
+http_client:
+  all:
+    pool_idle_timeout: 30s
+    tcp_keepalive: 60s
+  subgraphs:
+    products:
+      http2: http2_only
+      pool_idle_timeout: 5s
+        "#,
+        )
+        .unwrap();
+        let http_client = &config.http_client;
+
+        let products = http_client.for_subgraph("products");
+        assert_eq!(products.http2, Some(Http2::Http2Only));
+        assert_eq!(products.pool_idle_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(products.tcp_keepalive, Some(Duration::from_secs(60)));
+        let reviews = http_client.for_subgraph("reviews");
+        assert_eq!(reviews.http2, None);
+        assert_eq!(reviews.pool_idle_timeout, Some(Duration::from_secs(30)));
+    }
 }
@@ -0,0 +1,197 @@
+//! Scheduled changes of the configuration.
+//!
+//! A schedule holds options merged over the configuration file during a window of time, like
+//! higher rate limits during a sale or another landing page during a maintenance. The stream of
+//! configurations of the router is evaluated against the clock, and the configuration is applied
+//! again each time a window opens or closes, without a deploy.
+
+use std::time::SystemTime;
+
+use futures::prelude::*;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+
+use super::Configuration;
+
+/// Context key holding the names of the schedules active when a request is received.
+pub(crate) const ACTIVE_SCHEDULES_CONTEXT_KEY: &str = "apollo_router::configuration::schedules";
+
+/// Options of the configuration applied during a window of time.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Schedule {
+    /// Name of the schedule, in logs and telemetry
+    pub(crate) name: String,
+    /// When the schedule starts, as an RFC 3339 date (default: always started)
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "Option<String>", default)]
+    pub(crate) start: Option<SystemTime>,
+    /// When the schedule ends, as an RFC 3339 date (default: never ends)
+    #[serde(with = "humantime_serde", default)]
+    #[schemars(with = "Option<String>", default)]
+    pub(crate) end: Option<SystemTime>,
+    /// Options merged over the configuration while the schedule is active. Objects are merged,
+    /// other values are replaced
+    pub(crate) configuration: Map<String, Value>,
+}
+
+impl Schedule {
+    /// Whether the schedule is active at a time, from its start included to its end excluded.
+    pub(crate) fn is_active(&self, time: SystemTime) -> bool {
+        self.start.map(|start| start <= time).unwrap_or(true)
+            && self.end.map(|end| time < end).unwrap_or(true)
+    }
+}
+
+/// Merges options over a configuration, as JSON.
+pub(crate) fn merge(target: &mut Value, options: &Map<String, Value>) {
+    match target {
+        Value::Object(target) => {
+            for (key, value) in options {
+                match (target.get_mut(key), value) {
+                    (Some(target @ Value::Object(_)), Value::Object(value)) => merge(target, value),
+                    _ => {
+                        target.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        _ => *target = Value::Object(options.clone()),
+    }
+}
+
+/// Applies the schedules of a stream of configurations.
+///
+/// Each configuration of the stream is emitted with the options of the schedules active at the
+/// time, then again each time the active schedules change, until the next configuration of the
+/// stream replaces it.
+pub(crate) fn apply_schedules(
+    configurations: impl Stream<Item = Configuration> + Send + 'static,
+) -> impl Stream<Item = Configuration> + Send {
+    let state = State {
+        configurations: configurations.boxed(),
+        ended: false,
+        current: None,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            let next_change = state
+                .current
+                .as_ref()
+                .and_then(|current| current.next_schedule_change(SystemTime::now()));
+            // The configuration to apply, when, and whether it is applied again at a change of
+            // its schedules
+            let (configuration, time, rescheduled) = match next_change {
+                None if state.ended => return None,
+                None => (state.configurations.next().await?, SystemTime::now(), false),
+                Some(time) => {
+                    let delay = time.duration_since(SystemTime::now()).unwrap_or_default();
+                    let sleep = tokio::time::sleep(delay);
+                    let current = state
+                        .current
+                        .clone()
+                        .expect("the next change is the one of the current configuration; qed");
+                    if state.ended {
+                        sleep.await;
+                        (current, SystemTime::now().max(time), true)
+                    } else {
+                        tokio::select! {
+                            next = state.configurations.next() => match next {
+                                Some(configuration) => (configuration, SystemTime::now(), false),
+                                None => {
+                                    state.ended = true;
+                                    continue;
+                                }
+                            },
+                            _ = sleep => (current, SystemTime::now().max(time), true),
+                        }
+                    }
+                }
+            };
+
+            let scheduled = match configuration.scheduled(time) {
+                Ok(scheduled) => scheduled,
+                Err(err) => {
+                    tracing::error!("could not apply the configuration schedules: {}", err);
+                    configuration
+                }
+            };
+            let previous = state.current.replace(scheduled.clone());
+            let previous_schedules = previous
+                .map(|previous| previous.active_schedules)
+                .unwrap_or_default();
+            if previous_schedules != scheduled.active_schedules {
+                tracing::info!(
+                    "active configuration schedules: [{}]",
+                    scheduled.active_schedules.join(", ")
+                );
+            } else if rescheduled {
+                // The same schedules are active, there is nothing to apply
+                continue;
+            }
+            return Some((scheduled, state));
+        }
+    })
+}
+
+struct State {
+    configurations: stream::BoxStream<'static, Configuration>,
+    /// Whether the stream of configurations ended, the schedules of the last one still apply
+    ended: bool,
+    /// The last configuration emitted
+    current: Option<Configuration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn options_of_active_schedules_are_merged() {
+        let now = SystemTime::now();
+        let configuration: Configuration = serde_json::from_value(json!({
+            "server": { "experimental_landing_page": { "path": "/welcome" } },
+            "experimental_schedules": [{
+                "name": "maintenance",
+                "start": humantime::format_rfc3339(now + Duration::from_secs(60)).to_string(),
+                "end": humantime::format_rfc3339(now + Duration::from_secs(120)).to_string(),
+                "configuration": { "server": { "experimental_landing_page": { "path": "/maintenance" } } }
+            }]
+        }))
+        .unwrap();
+
+        let before = configuration.scheduled(now).unwrap();
+        assert!(before.active_schedules.is_empty());
+        assert_eq!(
+            configuration.next_schedule_change(now),
+            Some(configuration.experimental_schedules[0].start.unwrap())
+        );
+
+        let during = configuration
+            .scheduled(now + Duration::from_secs(90))
+            .unwrap();
+        assert_eq!(during.active_schedules, vec!["maintenance".to_string()]);
+        assert_eq!(
+            serde_json::to_value(&during).unwrap()["server"]["experimental_landing_page"]["path"],
+            json!("/maintenance")
+        );
+        // Schedules are applied over the configuration without them
+        let after = during.scheduled(now + Duration::from_secs(150)).unwrap();
+        assert!(after.active_schedules.is_empty());
+        assert_eq!(
+            serde_json::to_value(&after).unwrap()["server"]["experimental_landing_page"]["path"],
+            json!("/welcome")
+        );
+        assert_eq!(
+            configuration.next_schedule_change(now + Duration::from_secs(150)),
+            None
+        );
+    }
+}
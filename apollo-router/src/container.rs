@@ -0,0 +1,146 @@
+//! Resource limits of the container the router runs in.
+//!
+//! In a container, the CPUs and memory available to the router are bounded by its cgroup, while
+//! the cores of the whole host are visible. The router reads these limits at startup to size its
+//! worker threads, its blocking threads and its in-memory caches, instead of sizing them for the
+//! host.
+
+use std::path::Path;
+
+use once_cell::sync::Lazy;
+
+/// Default capacity of the query plan cache.
+pub(crate) const DEFAULT_PLAN_CACHE_CAPACITY: usize = 100;
+
+/// Memory from which caches get their default capacity. Below it, their capacity is reduced in
+/// proportion.
+const REFERENCE_MEMORY: u64 = 1024 * 1024 * 1024;
+
+/// Memory budgeted for each blocking thread.
+const MEMORY_PER_BLOCKING_THREAD: u64 = 4 * 1024 * 1024;
+
+/// Default maximum number of blocking threads of the Tokio runtime.
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+
+const MIN_MAX_BLOCKING_THREADS: usize = 16;
+
+/// Memory limits above this one mean no limit: cgroup v1 reports it as `i64::MAX` rounded to pages.
+const NO_MEMORY_LIMIT: u64 = 1 << 60;
+
+/// Limits of the container, read once.
+pub(crate) static CONTAINER_LIMITS: Lazy<ContainerLimits> = Lazy::new(ContainerLimits::detect);
+
+/// CPU and memory limits of the cgroup of the router.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ContainerLimits {
+    /// CPU quota, in cores
+    pub(crate) cpus: Option<f64>,
+    /// Memory limit, in bytes
+    pub(crate) memory: Option<u64>,
+}
+
+impl ContainerLimits {
+    fn detect() -> Self {
+        if cfg!(target_os = "linux") {
+            Self::from_cgroup(Path::new("/sys/fs/cgroup"))
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Reads the limits of cgroup v2, or of cgroup v1, mounted at `root`.
+    fn from_cgroup(root: &Path) -> Self {
+        let read = |path: &str| std::fs::read_to_string(root.join(path)).ok();
+
+        let cpus = read("cpu.max")
+            .and_then(|max| {
+                let mut parts = max.split_whitespace();
+                cpu_quota(parts.next()?, parts.next()?)
+            })
+            .or_else(|| {
+                cpu_quota(
+                    read("cpu/cpu.cfs_quota_us")?.trim(),
+                    read("cpu/cpu.cfs_period_us")?.trim(),
+                )
+            });
+        let memory = read("memory.max")
+            .or_else(|| read("memory/memory.limit_in_bytes"))
+            .and_then(|limit| limit.trim().parse::<u64>().ok())
+            .filter(|limit| *limit < NO_MEMORY_LIMIT);
+
+        ContainerLimits { cpus, memory }
+    }
+
+    /// Number of worker threads: the CPU quota rounded up, if there are more cores.
+    pub(crate) fn worker_threads(&self) -> Option<usize> {
+        let threads = (self.cpus?.ceil() as usize).max(1);
+        let cores = std::thread::available_parallelism()
+            .map(|cores| cores.get())
+            .unwrap_or(threads);
+        (threads < cores).then(|| threads)
+    }
+
+    /// Maximum number of blocking threads, which parse the operations.
+    pub(crate) fn max_blocking_threads(&self) -> usize {
+        match self.memory {
+            Some(memory) => ((memory / MEMORY_PER_BLOCKING_THREAD) as usize)
+                .clamp(MIN_MAX_BLOCKING_THREADS, DEFAULT_MAX_BLOCKING_THREADS),
+            None => DEFAULT_MAX_BLOCKING_THREADS,
+        }
+    }
+
+    /// Capacity of a cache, reduced from its default capacity if the memory is limited.
+    pub(crate) fn cache_capacity(&self, default: usize) -> usize {
+        match self.memory {
+            Some(memory) if memory < REFERENCE_MEMORY => {
+                ((default as u64 * memory / REFERENCE_MEMORY) as usize).max(1)
+            }
+            _ => default,
+        }
+    }
+}
+
+/// Number of cores of a CFS quota, `None` if unlimited (`max` in v2, `-1` in v1).
+fn cpu_quota(quota: &str, period: &str) -> Option<f64> {
+    let quota = quota.parse::<f64>().ok().filter(|quota| *quota > 0.0)?;
+    let period = period.parse::<f64>().ok().filter(|period| *period > 0.0)?;
+    Some(quota / period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_are_read_from_cgroups() {
+        let v2 = tempfile::tempdir().unwrap();
+        std::fs::write(v2.path().join("cpu.max"), "150000 100000\n").unwrap();
+        std::fs::write(v2.path().join("memory.max"), "268435456\n").unwrap();
+        let limits = ContainerLimits::from_cgroup(v2.path());
+        assert_eq!(
+            limits,
+            ContainerLimits {
+                cpus: Some(1.5),
+                memory: Some(256 * 1024 * 1024),
+            }
+        );
+        assert_eq!(limits.max_blocking_threads(), 64);
+        assert_eq!(limits.cache_capacity(DEFAULT_PLAN_CACHE_CAPACITY), 25);
+
+        let v1 = tempfile::tempdir().unwrap();
+        std::fs::create_dir(v1.path().join("cpu")).unwrap();
+        std::fs::create_dir(v1.path().join("memory")).unwrap();
+        std::fs::write(v1.path().join("cpu/cpu.cfs_quota_us"), "-1\n").unwrap();
+        std::fs::write(v1.path().join("cpu/cpu.cfs_period_us"), "100000\n").unwrap();
+        std::fs::write(
+            v1.path().join("memory/memory.limit_in_bytes"),
+            "9223372036854771712\n",
+        )
+        .unwrap();
+        let limits = ContainerLimits::from_cgroup(v1.path());
+        assert_eq!(limits, ContainerLimits::default());
+        assert_eq!(limits.worker_threads(), None);
+        assert_eq!(limits.max_blocking_threads(), DEFAULT_MAX_BLOCKING_THREADS);
+        assert_eq!(limits.cache_capacity(DEFAULT_PLAN_CACHE_CAPACITY), 100);
+    }
+}
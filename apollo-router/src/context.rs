@@ -26,10 +26,19 @@ pub(crate) type Entries = Arc<DashMap<String, Value>>;
 /// provide [`crate::SubgraphRequest`] or [`crate::SubgraphResponse`] processing. At such times,
 /// plugins should restrict themselves to the [`Context::get`] and [`Context::upsert`]
 /// functions to minimise the possibility of mis-sequenced updates.
+///
+/// The responses of a deferred query are processed one after the other, after the primary
+/// response. Rhai callbacks process each of them with a [snapshot](Context::snapshot) of the
+/// context taken when the primary response was processed, so they are not affected by the updates
+/// made while processing the other ones. Native plugins mapping the stream of responses get a
+/// single context for the whole stream, and can take snapshots the same way. State that must be
+/// visible across all responses goes in the [shared](Context::shared) context.
 #[derive(Clone, Debug)]
 pub struct Context {
     // Allows adding custom entries to the context.
     entries: Entries,
+    // Entries kept across snapshots.
+    shared: Entries,
 }
 
 impl Context {
@@ -37,8 +46,41 @@ impl Context {
     pub fn new() -> Self {
         Context {
             entries: Default::default(),
+            shared: Default::default(),
+        }
+    }
+
+    /// Copy the entries of the context.
+    ///
+    /// Updates made to the snapshot are not visible in this context, and the other way around.
+    /// The [shared](Context::shared) context is not copied.
+    pub fn snapshot(&self) -> Self {
+        Context {
+            entries: Arc::new(
+                self.entries
+                    .iter()
+                    .map(|entry| (entry.key().clone(), entry.value().clone()))
+                    .collect(),
+            ),
+            shared: self.shared.clone(),
         }
     }
+
+    /// The state shared by this context and all its snapshots.
+    ///
+    /// Updates made to the returned context are visible from every snapshot, for example to
+    /// aggregate data over all the responses of a deferred query.
+    pub fn shared(&self) -> Self {
+        Context {
+            entries: self.shared.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Whether both contexts belong to the same request, one being a snapshot of the other.
+    pub(crate) fn same_as(&self, other: &Context) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
 }
 
 impl Context {
@@ -146,6 +188,11 @@ impl Context {
     pub fn iter_mut(&self) -> impl Iterator<Item = RefMutMulti<'_, String, Value>> + '_ {
         self.entries.iter_mut()
     }
+
+    /// Keep only the entries for which the predicate returns `true`.
+    pub(crate) fn retain(&self, f: impl FnMut(&String, &mut Value) -> bool) {
+        self.entries.retain(f)
+    }
 }
 
 impl Default for Context {
@@ -219,4 +266,27 @@ mod test {
         assert_eq!(c.get("one").unwrap(), Some(2));
         assert_eq!(c.get("two").unwrap(), Some(3));
     }
+
+    #[test]
+    fn snapshots_are_isolated() {
+        let c = Context::new();
+        assert!(c.insert("key", 1).is_ok());
+        let snapshot = c.snapshot();
+        assert!(c.insert("key", 2).is_ok());
+        assert!(snapshot.insert("other", 3).is_ok());
+        assert_eq!(snapshot.get("key").unwrap(), Some(1));
+        assert_eq!(c.get("key").unwrap(), Some(2));
+        assert_eq!(c.get::<_, usize>("other").unwrap(), None);
+    }
+
+    #[test]
+    fn shared_entries_are_visible_from_snapshots() {
+        let c = Context::new();
+        let first = c.snapshot();
+        let second = c.snapshot();
+        assert!(first.shared().upsert("count", |v: usize| v + 1).is_ok());
+        assert!(second.shared().upsert("count", |v: usize| v + 1).is_ok());
+        assert_eq!(c.shared().get("count").unwrap(), Some(2));
+        assert_eq!(c.get::<_, usize>("count").unwrap(), None);
+    }
 }
@@ -0,0 +1,143 @@
+//! The router as a federated execution engine, without the HTTP server.
+
+use std::sync::Arc;
+
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::configuration::Configuration;
+use crate::error::ApolloRouterError;
+use crate::plugin::DynPlugin;
+use crate::plugin::Plugin;
+use crate::router_factory::SupergraphServiceConfigurator;
+use crate::router_factory::YamlSupergraphServiceFactory;
+use crate::services::supergraph;
+use crate::test_harness::extra_plugin_name;
+use crate::Schema;
+
+/// Builder for the router as a federated GraphQL execution engine: a [`tower::Service`] executing
+/// [`supergraph::Request`]s against a supergraph schema and streaming their responses.
+///
+/// The engine plans the operations, runs the plugins of its configuration and fetches the data
+/// from the subgraphs like the router does, but it does not listen for HTTP requests. The
+/// listeners, CORS, CSRF protection, the health check, the custom endpoints of plugins and HTTP
+/// compression belong to the HTTP server, which is compiled with the `http_server` feature of the
+/// crate, enabled by default. Without this feature the crate only holds the execution engine.
+///
+/// Unlike the [`TestHarness`](crate::TestHarness), the engine sends real requests to the
+/// subgraphs.
+///
+/// ```no_run
+/// use apollo_router::services::supergraph;
+/// use apollo_router::Engine;
+/// use http::Method;
+/// use http::Uri;
+/// use tower::util::ServiceExt;
+///
+/// # #[tokio::main] async fn main() -> Result<(), tower::BoxError> {
+/// let schema = std::fs::read_to_string("supergraph.graphql")?;
+/// let engine = Engine::builder()
+///     .schema(&schema)
+///     .configuration_json(serde_json::json!({ "include_subgraph_errors": { "all": true } }))?
+///     .build()
+///     .await?;
+/// let request = supergraph::Request::builder()
+///     .query("{ me { name } }")
+///     // Requests go through the CSRF protection if the `http_server` feature is enabled
+///     .header("content-type", "application/json")
+///     .method(Method::POST)
+///     .uri(Uri::from_static("/"))
+///     .build()?;
+/// let mut response = engine.oneshot(request).await?;
+/// while let Some(response) = response.next_response().await {
+///     println!("{}", serde_json::to_string(&response)?);
+/// }
+/// # Ok(()) }
+/// ```
+pub struct Engine<'a> {
+    schema: Option<&'a str>,
+    configuration: Option<Arc<Configuration>>,
+    extra_plugins: Vec<(String, Box<dyn DynPlugin>)>,
+}
+
+impl<'a> Engine<'a> {
+    /// Returns a builder for an execution engine.
+    pub fn builder() -> Self {
+        Self {
+            schema: None,
+            configuration: None,
+            extra_plugins: Vec::new(),
+        }
+    }
+
+    /// Specifies the supergraph schema definition. Required.
+    ///
+    /// Panics if called more than once.
+    pub fn schema(mut self, schema: &'a str) -> Self {
+        assert!(self.schema.is_none(), "schema was specified twice");
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Specifies the router configuration.
+    ///
+    /// If this isn’t called, the default configuration is used, as with an empty YAML file.
+    pub fn configuration(mut self, configuration: Arc<Configuration>) -> Self {
+        assert!(
+            self.configuration.is_none(),
+            "configuration was specified twice"
+        );
+        self.configuration = Some(configuration);
+        self
+    }
+
+    /// Specifies the router configuration as a JSON value,
+    /// such as from the `serde_json::json!` macro.
+    pub fn configuration_json(
+        self,
+        configuration: serde_json::Value,
+    ) -> Result<Self, serde_json::Error> {
+        Ok(self.configuration(serde_json::from_value(configuration)?))
+    }
+
+    /// Adds an extra, already instanciated plugin.
+    ///
+    /// May be called multiple times.
+    /// These extra plugins are added after plugins specified in configuration.
+    pub fn extra_plugin<P: Plugin>(mut self, plugin: P) -> Self {
+        let name = extra_plugin_name::<P>(self.extra_plugins.len());
+        self.extra_plugins.push((name, Box::new(plugin)));
+        self
+    }
+
+    /// Builds the engine.
+    ///
+    /// The clones of the returned service share the query planner and its caches.
+    pub async fn build(self) -> Result<supergraph::BoxCloneService, BoxError> {
+        let schema = self.schema.ok_or(ApolloRouterError::NoSchema)?;
+        supergraph_service(
+            schema,
+            self.configuration.unwrap_or_default(),
+            self.extra_plugins,
+        )
+        .await
+    }
+}
+
+/// Creates the supergraph service of a schema and a configuration, a new pipeline being made for
+/// each request.
+pub(crate) async fn supergraph_service(
+    schema: &str,
+    configuration: Arc<Configuration>,
+    extra_plugins: Vec<(String, Box<dyn DynPlugin>)>,
+) -> Result<supergraph::BoxCloneService, BoxError> {
+    let schema = Arc::new(Schema::parse(schema, &configuration)?);
+    let router_creator = YamlSupergraphServiceFactory
+        .create(configuration, schema, None, Some(extra_plugins))
+        .await?;
+    Ok(tower::service_fn(move |request| {
+        let service = router_creator.make();
+        async move { service.oneshot(request).await }
+    })
+    .boxed_clone())
+}
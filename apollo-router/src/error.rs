@@ -1,5 +1,6 @@
 //! Router errors.
 use std::sync::Arc;
+use std::time::Duration;
 
 use displaydoc::Display;
 use miette::Diagnostic;
@@ -88,6 +89,34 @@ pub(crate) enum FetchError {
 
         /// The reason the fetch failed.
         reason: String,
+
+        /// The HTTP status of the response, if the subgraph answered.
+        #[serde(skip)]
+        status: Option<u16>,
+    },
+
+    /// request to '{service}' timed out
+    SubrequestTimeout {
+        /// The service that did not respond in time.
+        service: String,
+    },
+
+    /// request to '{service}' was rate limited, retry after {retry_after} seconds
+    SubrequestRateLimited {
+        /// The service that was rate limited.
+        service: String,
+
+        /// Number of seconds before the service accepts requests again.
+        retry_after: u64,
+    },
+
+    /// service '{service}' is unavailable, retry after {retry_after} seconds
+    SubrequestUnavailable {
+        /// The service that is unavailable.
+        service: String,
+
+        /// Number of seconds before the service accepts requests again.
+        retry_after: u64,
     },
 
     /// subquery requires field '{field}' but it was not found in the current response
@@ -122,6 +151,23 @@ impl FetchError {
         }
     }
 
+    /// Number of seconds the service asked to wait before sending it requests again.
+    pub(crate) fn retry_after(&self) -> Option<u64> {
+        match self {
+            FetchError::SubrequestRateLimited { retry_after, .. }
+            | FetchError::SubrequestUnavailable { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// Finds a fetch error carrying a retry delay, which may be wrapped by a buffer layer.
+    pub(crate) fn find_retry_after(error: &BoxError) -> Option<&FetchError> {
+        error
+            .downcast_ref::<FetchError>()
+            .or_else(|| error.source().and_then(|source| source.downcast_ref()))
+            .filter(|error| error.retry_after().is_some())
+    }
+
     /// Convert the error to an appropriate response.
     pub(crate) fn to_response(&self) -> Response {
         Response {
@@ -150,6 +196,31 @@ impl From<QueryPlannerError> for FetchError {
     }
 }
 
+/// Error types for FederatedServer.
+#[derive(Error, Debug, Display)]
+pub enum ApolloRouterError {
+    /// failed to start server
+    StartupError,
+
+    /// failed to stop HTTP Server
+    HttpServerLifecycleError,
+
+    /// no valid configuration was supplied
+    NoConfiguration,
+
+    /// no valid schema was supplied
+    NoSchema,
+
+    /// could not create the HTTP pipeline: {0}
+    ServiceCreationError(BoxError),
+
+    /// could not create the HTTP server: {0}
+    ServerCreationError(std::io::Error),
+
+    /// a tracing subscriber was already installed
+    TracingSubscriberAlreadySet,
+}
+
 /// Error types for CacheResolver
 #[derive(Error, Debug, Display, Clone)]
 pub(crate) enum CacheResolverError {
@@ -199,6 +270,12 @@ pub(crate) enum QueryPlannerError {
 
     /// introspection error: {0}
     Introspection(IntrospectionError),
+
+    /// query planning timed out after {0:?}
+    Timeout(Duration),
+
+    /// the query plan requests {1} from subgraph '{0}', which its ACL does not allow
+    SubgraphAcl(String, String),
 }
 
 #[derive(Clone, Debug, Error)]
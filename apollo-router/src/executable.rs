@@ -13,8 +13,9 @@ use anyhow::Result;
 use clap::AppSettings;
 use clap::CommandFactory;
 use clap::Parser;
+use clap::Subcommand;
 use directories::ProjectDirs;
-use once_cell::sync::OnceCell;
+use http::HeaderMap;
 use tracing::dispatcher::with_default;
 use tracing::dispatcher::Dispatch;
 use tracing::instrument::WithSubscriber;
@@ -22,15 +23,22 @@ use tracing_subscriber::EnvFilter;
 use url::ParseError;
 use url::Url;
 
+use crate::cache::DEFAULT_CACHE_CAPACITY;
 use crate::configuration::generate_config_schema;
+use crate::configuration::validate_configuration;
 use crate::configuration::Configuration;
 use crate::configuration::ConfigurationError;
+use crate::container::CONTAINER_LIMITS;
+use crate::container::DEFAULT_PLAN_CACHE_CAPACITY;
+use crate::plugins::telemetry::logging::log_writer;
+use crate::plugins::telemetry::GLOBAL_ENV_FILTER;
 use crate::router::ConfigurationSource;
 use crate::router::RouterHttpServer;
 use crate::router::SchemaSource;
 use crate::router::ShutdownSource;
 
-pub(crate) static GLOBAL_ENV_FILTER: OnceCell<String> = OnceCell::new();
+/// Time between two polls of the schema URL of the configuration, if it does not set it.
+const DEFAULT_SUPERGRAPH_POLL_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Options for the router
 #[derive(Parser, Debug)]
@@ -53,6 +61,13 @@ pub(crate) struct Opt {
     #[clap(alias = "hr", long = "hot-reload", env = "APOLLO_ROUTER_HOT_RELOAD")]
     hot_reload: bool,
 
+    /// Do not reload the supergraph schema file when it changes, unless `--hot-reload` is set.
+    #[clap(
+        long = "no-supergraph-watch",
+        env = "APOLLO_ROUTER_NO_SUPERGRAPH_WATCH"
+    )]
+    no_supergraph_watch: bool,
+
     /// Configuration location relative to the project directory.
     #[clap(
         short,
@@ -75,6 +90,9 @@ pub(crate) struct Opt {
     #[clap(long)]
     schema: bool,
 
+    #[clap(subcommand)]
+    command: Option<Commands>,
+
     /// Your Apollo key.
     #[clap(skip = std::env::var("APOLLO_KEY").ok())]
     apollo_key: Option<String>,
@@ -95,6 +113,32 @@ pub(crate) struct Opt {
     /// Display version and exit.
     #[clap(parse(from_flag), long, short = 'V')]
     pub(crate) version: bool,
+
+    /// Run as a Windows service. Only use it when the router is started by the service control manager.
+    #[cfg(windows)]
+    #[clap(long = "windows-service")]
+    windows_service: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Configuration subcommands.
+    #[clap(subcommand)]
+    Config(ConfigSubcommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigSubcommand {
+    /// Prints the JSON schema of the configuration, including the registered plugins.
+    Schema,
+
+    /// Validates a configuration file against the JSON schema of the configuration, printing
+    /// its errors with their line numbers. Exits with a non-zero status if it is invalid.
+    Validate {
+        /// Path of the configuration file.
+        #[clap(parse(from_os_str))]
+        config_path: PathBuf,
+    },
 }
 
 /// Wrapper so that structop can display the default config path in the help message.
@@ -138,16 +182,31 @@ impl fmt::Display for ProjectDir {
 ///
 /// Refer to the examples if you would like to see how to run your own router with plugins.
 pub fn main() -> Result<()> {
+    #[cfg(windows)]
+    if Opt::parse().windows_service {
+        return crate::windows::run();
+    }
+
+    runtime()?.block_on(Executable::builder().start())
+}
+
+pub(crate) fn runtime() -> Result<tokio::runtime::Runtime> {
     let mut builder = tokio::runtime::Builder::new_multi_thread();
     builder.enable_all();
-    if let Some(nb) = std::env::var("APOLLO_ROUTER_NUM_CORES")
-        .ok()
-        .and_then(|value| value.parse::<usize>().ok())
-    {
+    if let Some(nb) = worker_threads() {
         builder.worker_threads(nb);
     }
-    let runtime = builder.build()?;
-    runtime.block_on(Executable::builder().start())
+    builder.max_blocking_threads(CONTAINER_LIMITS.max_blocking_threads());
+    Ok(builder.build()?)
+}
+
+/// Number of worker threads, from `APOLLO_ROUTER_NUM_CORES` or the CPU limit of the container.
+/// `None` for one per core.
+fn worker_threads() -> Option<usize> {
+    std::env::var("APOLLO_ROUTER_NUM_CORES")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .or_else(|| CONTAINER_LIMITS.worker_threads())
 }
 
 /// Entry point into creating a router executable.
@@ -191,11 +250,31 @@ impl Executable {
             return Ok(());
         }
 
+        match &opt.command {
+            Some(Commands::Config(ConfigSubcommand::Schema)) => {
+                let schema = generate_config_schema();
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+                return Ok(());
+            }
+            Some(Commands::Config(ConfigSubcommand::Validate { config_path })) => {
+                let configuration = std::fs::read_to_string(config_path)
+                    .with_context(|| format!("could not read {}", config_path.to_string_lossy()))?;
+                validate_configuration(&configuration).map_err(|err| {
+                    anyhow!("{} is invalid: {}", config_path.to_string_lossy(), err)
+                })?;
+                println!("{} is valid", config_path.to_string_lossy());
+                return Ok(());
+            }
+            None => {}
+        }
+
         let builder = tracing_subscriber::fmt::fmt().with_env_filter(
             EnvFilter::try_new(&opt.log_level).context("could not parse log configuration")?,
         );
 
-        let dispatcher = if atty::is(atty::Stream::Stdout) {
+        let dispatcher = if let Some(writer) = log_writer() {
+            Dispatch::new(builder.with_ansi(false).with_writer(writer).finish())
+        } else if atty::is(atty::Stream::Stdout) {
             Dispatch::new(builder.finish())
         } else {
             Dispatch::new(builder.json().finish())
@@ -218,6 +297,19 @@ impl Executable {
         opt: Opt,
         dispatcher: Dispatch,
     ) -> Result<()> {
+        let limits = &*CONTAINER_LIMITS;
+        if limits.cpus.is_some() || limits.memory.is_some() {
+            tracing::info!(
+                cpus = ?limits.cpus,
+                memory_bytes = ?limits.memory,
+                worker_threads = ?worker_threads(),
+                max_blocking_threads = limits.max_blocking_threads(),
+                plan_cache_capacity = limits.cache_capacity(DEFAULT_PLAN_CACHE_CAPACITY),
+                apq_cache_capacity = limits.cache_capacity(DEFAULT_CACHE_CAPACITY),
+                "sizing the router for the limits of its container"
+            );
+        }
+
         let current_directory = std::env::current_dir()?;
 
         let configuration = opt
@@ -238,9 +330,17 @@ impl Executable {
             })
             .unwrap_or_else(|| Configuration::builder().build().into());
 
+        // The schema URL of the configuration file is read once, it is not hot reloaded
+        let supergraph_source = match &configuration {
+            ConfigurationSource::File { path, .. } => ConfigurationSource::read_config(path)
+                .ok()
+                .and_then(|configuration| configuration.supergraph.source),
+            _ => None,
+        };
+
         let apollo_router_msg = format!("Apollo Router v{} // (c) Apollo Graph, Inc. // Licensed as ELv2 (https://go.apollo.dev/elv2)", std::env!("CARGO_PKG_VERSION"));
-        let schema = match (opt.supergraph_path, opt.apollo_key) {
-            (Some(supergraph_path), _) => {
+        let schema = match (opt.supergraph_path, supergraph_source, opt.apollo_key) {
+            (Some(supergraph_path), _, _) => {
                 tracing::info!("{apollo_router_msg}");
                 setup_panic_handler(dispatcher.clone());
 
@@ -249,13 +349,29 @@ impl Executable {
                 } else {
                     supergraph_path
                 };
+                // The schema file is reloaded when it changes, so that a new composition does not
+                // need a restart
                 SchemaSource::File {
                     path: supergraph_path,
-                    watch: opt.hot_reload,
+                    watch: opt.hot_reload || !opt.no_supergraph_watch,
                     delay: None,
                 }
             }
-            (None, Some(apollo_key)) => {
+            (None, Some(supergraph_source), _) => {
+                tracing::info!("{apollo_router_msg}");
+                setup_panic_handler(dispatcher.clone());
+
+                let headers = HeaderMap::try_from(&supergraph_source.headers)
+                    .context("invalid header in supergraph.source.headers")?;
+                SchemaSource::Url {
+                    url: supergraph_source.url,
+                    headers,
+                    poll_interval: supergraph_source
+                        .poll_interval
+                        .unwrap_or(DEFAULT_SUPERGRAPH_POLL_INTERVAL),
+                }
+            }
+            (None, None, Some(apollo_key)) => {
                 tracing::info!("{apollo_router_msg}");
 
                 let apollo_graph_ref = opt.apollo_graph_ref.ok_or_else(||anyhow!("cannot fetch the supergraph from Apollo Studio without setting the APOLLO_GRAPH_REF environment variable"))?;
@@ -294,6 +410,13 @@ impl Executable {
 
       $ ./router --supergraph <file_path>
 
+  * Poll a schema from a URL with the 'supergraph.source' section
+    of the configuration file:
+
+      supergraph:
+        source:
+          url: https://registry.example.com/supergraph.graphql
+
   * Fetch a registered schema from Apollo Studio by setting
     these environment variables:
 
@@ -322,6 +445,10 @@ impl Executable {
             .schema(schema)
             .shutdown(shutdown.unwrap_or(ShutdownSource::CtrlC))
             .start();
+        #[cfg(windows)]
+        if router.listen_address().await.is_ok() {
+            crate::windows::report_running();
+        }
         if let Err(err) = router.await {
             tracing::error!("{}", err);
             return Err(err.into());
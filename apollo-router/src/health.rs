@@ -0,0 +1,173 @@
+//! Readiness of the router to serve GraphQL requests.
+//!
+//! The readiness endpoint checks that a schema is loaded, that the last Uplink poll succeeded
+//! when the schema comes from Uplink, and that the configured subgraph probes answer. The results
+//! of the probes are reused for a few seconds, so frequent checks don't load the subgraphs. It
+//! responds with a 503 status code if one of these checks fails, listing the checks in the
+//! format of the liveness endpoint. The same checks drive the status reported to the gRPC health
+//! checking service of the admin listener.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::Json;
+use futures::future::join_all;
+use http::StatusCode;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tower::BoxError;
+
+use crate::configuration::HealthEndpoints;
+use crate::configuration::SubgraphProbe;
+
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the results of the subgraph probes are reused
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// Interval between the updates of the status reported to the gRPC health checking service
+const GRPC_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State of the schema source, updated while the router runs.
+#[derive(Debug, Default)]
+pub(crate) struct Health {
+    /// Result of the last Uplink poll, if the schema comes from Uplink
+    uplink: Mutex<Option<Result<(), String>>>,
+}
+
+impl Health {
+    pub(crate) fn uplink_succeeded(&self) {
+        *self.uplink.lock().expect("lock poisoned") = Some(Ok(()));
+    }
+
+    pub(crate) fn uplink_failed(&self, error: String) {
+        *self.uplink.lock().expect("lock poisoned") = Some(Err(error));
+    }
+
+    fn uplink(&self) -> Option<Result<(), String>> {
+        self.uplink.lock().expect("lock poisoned").clone()
+    }
+}
+
+/// Checks of the readiness endpoint, for a given server.
+pub(crate) struct Readiness {
+    health: Arc<Health>,
+    schema_loaded: bool,
+    probes: Vec<SubgraphProbe>,
+    client: reqwest::Client,
+    /// Results of the last subgraph probes, and when they ran
+    probe_results: tokio::sync::Mutex<Option<(Instant, Vec<Result<(), String>>)>>,
+}
+
+impl Readiness {
+    pub(crate) fn new(
+        health: Arc<Health>,
+        schema_loaded: bool,
+        configuration: &HealthEndpoints,
+    ) -> Result<Self, BoxError> {
+        let client = reqwest::Client::builder()
+            .timeout(configuration.probe_timeout.unwrap_or(DEFAULT_PROBE_TIMEOUT))
+            .build()?;
+        Ok(Readiness {
+            health,
+            schema_loaded,
+            probes: configuration.subgraph_probes.clone(),
+            client,
+            probe_results: Default::default(),
+        })
+    }
+
+    async fn probe(&self, probe: &SubgraphProbe) -> Result<(), String> {
+        let response = self
+            .client
+            .get(probe.url.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "the probe answered with status {}",
+                response.status()
+            ))
+        }
+    }
+
+    /// Results of the subgraph probes, run again once they are older than the probe interval.
+    async fn probe_results(&self) -> Vec<Result<(), String>> {
+        // The checks arriving while the probes run wait for their results
+        let mut cached = self.probe_results.lock().await;
+        match &*cached {
+            Some((probed_at, results)) if probed_at.elapsed() < PROBE_INTERVAL => results.clone(),
+            _ => {
+                let results = join_all(self.probes.iter().map(|probe| self.probe(probe))).await;
+                *cached = Some((Instant::now(), results.clone()));
+                results
+            }
+        }
+    }
+
+    /// Results of the checks, by name.
+    async fn checks(&self) -> Map<String, Value> {
+        let mut checks = Map::new();
+        let schema = if self.schema_loaded {
+            Ok(())
+        } else {
+            Err("no schema is loaded".to_string())
+        };
+        checks.insert("schema".to_string(), check(schema));
+        if let Some(uplink) = self.health.uplink() {
+            checks.insert("uplink".to_string(), check(uplink));
+        }
+        for (probe, result) in self.probes.iter().zip(self.probe_results().await) {
+            checks.insert(format!("subgraph:{}", probe.name), check(result));
+        }
+        checks
+    }
+}
+
+fn passed(checks: &Map<String, Value>) -> bool {
+    checks.values().all(|check| check["status"] == "pass")
+}
+
+pub(crate) async fn ready(readiness: Arc<Readiness>) -> Response {
+    let checks = readiness.checks().await;
+    let (status, status_code) = if passed(&checks) {
+        ("pass", StatusCode::OK)
+    } else {
+        ("fail", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    (
+        status_code,
+        Json(json!({ "status": status, "checks": checks })),
+    )
+        .into_response()
+}
+
+/// Reports the readiness of the router under the empty service name of the gRPC health checking
+/// service, in particular `NOT_SERVING` while it is in safe mode, until the task is aborted.
+pub(crate) async fn report(readiness: Arc<Readiness>, mut reporter: HealthReporter) {
+    let mut interval = tokio::time::interval(GRPC_REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let status = if passed(&readiness.checks().await) {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        reporter.set_service_status("", status).await;
+    }
+}
+
+fn check(result: Result<(), String>) -> Value {
+    match result {
+        Ok(()) => json!({ "status": "pass" }),
+        Err(output) => json!({ "status": "fail", "output": output }),
+    }
+}
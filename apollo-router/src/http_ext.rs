@@ -342,10 +342,14 @@ impl<T: Hash> Hash for Request<T> {
         self.inner.method().hash(state);
         self.inner.version().hash(state);
         self.inner.uri().hash(state);
-        // this assumes headers are in the same order
-        for (name, value) in self.inner.headers() {
+        // headers are hashed by name, so that their order does not matter
+        let mut names: Vec<&http::header::HeaderName> = self.inner.headers().keys().collect();
+        names.sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+        for name in names {
             name.hash(state);
-            value.hash(state);
+            for value in self.inner.headers().get_all(name) {
+                value.hash(state);
+            }
         }
         self.inner.body().hash(state);
     }
@@ -353,26 +357,27 @@ impl<T: Hash> Hash for Request<T> {
 
 impl<T: PartialEq> PartialEq for Request<T> {
     fn eq(&self, other: &Self) -> bool {
-        let mut res = self.inner.method().eq(other.inner.method())
+        let res = self.inner.method().eq(other.inner.method())
             && self.inner.version().eq(&other.inner.version())
             && self.inner.uri().eq(other.inner.uri());
 
         if !res {
             return false;
         }
-        if self.inner.headers().len() != other.inner.headers().len() {
+        if self.inner.headers().len() != other.inner.headers().len()
+            || self.inner.headers().keys_len() != other.inner.headers().keys_len()
+        {
             return false;
         }
 
-        // this assumes headers are in the same order
-        for ((name, value), (other_name, other_value)) in self
-            .inner
-            .headers()
-            .iter()
-            .zip(other.inner.headers().iter())
-        {
-            res = name.eq(other_name) && value.eq(other_value);
-            if !res {
+        // headers are compared by name, only the order of the values of a header matters
+        for name in self.inner.headers().keys() {
+            if !self.inner.headers().get_all(name).iter().eq(other
+                .inner
+                .headers()
+                .get_all(name)
+                .iter())
+            {
                 return false;
             }
         }
@@ -468,6 +473,10 @@ impl IntoResponse for Response<Bytes> {
 
 #[cfg(test)]
 mod test {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hash;
+    use std::hash::Hasher;
+
     use http::HeaderValue;
     use http::Method;
     use http::Uri;
@@ -496,4 +505,32 @@ mod test {
         assert_eq!(request.method(), Method::POST);
         assert_eq!(request.body(), &"test");
     }
+
+    #[test]
+    fn header_order_does_not_matter_for_equality() {
+        fn request(headers: &[(&'static str, &'static str)]) -> Request<&'static str> {
+            let mut builder = http::Request::builder().uri("http://example.com");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body("test").unwrap().into()
+        }
+        fn hash(request: &Request<&'static str>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            request.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a = request(&[("a", "1"), ("b", "2"), ("a", "3")]);
+        let b = request(&[("b", "2"), ("a", "1"), ("a", "3")]);
+        assert!(a == b);
+        assert_eq!(hash(&a), hash(&b));
+
+        // the order of the values of a header is significant
+        let c = request(&[("a", "3"), ("b", "2"), ("a", "1")]);
+        assert!(a != c);
+
+        let d = request(&[("a", "1"), ("b", "2")]);
+        assert!(a != d);
+    }
 }
@@ -6,9 +6,9 @@ use derivative::Derivative;
 use futures::channel::oneshot;
 use futures::prelude::*;
 
-use super::router::ApolloRouterError;
 use crate::configuration::Configuration;
 use crate::configuration::ListenAddr;
+use crate::error::ApolloRouterError;
 use crate::plugin::Handler;
 use crate::router_factory::SupergraphServiceFactory;
 
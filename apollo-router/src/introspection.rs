@@ -7,27 +7,75 @@ use router_bridge::planner::IncrementalDeliverySupport;
 use router_bridge::planner::QueryPlannerConfig;
 
 use crate::cache::storage::CacheStorage;
+use crate::configuration::IntrospectionControl;
 use crate::graphql::Response;
 use crate::Configuration;
+use crate::Schema;
+use crate::SupergraphRequest;
 
 const DEFAULT_INTROSPECTION_CACHE_CAPACITY: usize = 5;
 
+/// Context entry set on the requests that are not allowed to introspect the schema.
+pub(crate) const INTROSPECTION_DENIED_CONTEXT_KEY: &str = "apollo_introspection::denied";
+
+/// Header naming the client of a request.
+const CLIENT_NAME_HEADER: &str = "apollographql-client-name";
+
+/// Extension of the requests received on the dedicated introspection listener.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct IntrospectionListener;
+
 /// A cache containing our well known introspection queries.
 pub(crate) struct Introspection {
     cache: CacheStorage<String, Response>,
+    capacity: usize,
     defer_support: bool,
+    /// Hash of the schema the cached responses were computed for
+    schema_id: Option<String>,
 }
 
 impl Introspection {
     pub(crate) async fn with_capacity(configuration: &Configuration, capacity: usize) -> Self {
         Self {
             cache: CacheStorage::new(capacity).await,
+            capacity,
             defer_support: configuration.server.experimental_defer_support,
+            schema_id: None,
         }
     }
 
     pub(crate) async fn new(configuration: &Configuration) -> Self {
-        Self::with_capacity(configuration, DEFAULT_INTROSPECTION_CACHE_CAPACITY).await
+        Self::with_capacity(
+            configuration,
+            configuration
+                .server
+                .experimental_introspection
+                .cache_capacity
+                .unwrap_or(DEFAULT_INTROSPECTION_CACHE_CAPACITY),
+        )
+        .await
+    }
+
+    /// The cache of introspection responses for a schema.
+    pub(crate) async fn for_schema(configuration: &Configuration, schema: &Schema) -> Self {
+        Self {
+            schema_id: schema.schema_id.clone(),
+            ..Self::new(configuration).await
+        }
+    }
+
+    /// Whether the cached responses are still valid for a new configuration and schema, so that
+    /// the cache can be kept when the router is reloaded.
+    pub(crate) fn is_reusable(&self, configuration: &Configuration, schema: &Schema) -> bool {
+        self.schema_id.is_some()
+            && self.schema_id == schema.schema_id
+            && self.defer_support == configuration.server.experimental_defer_support
+            && self.capacity
+                == configuration
+                    .server
+                    .experimental_introspection
+                    .cache_capacity
+                    .unwrap_or(DEFAULT_INTROSPECTION_CACHE_CAPACITY)
     }
 
     #[cfg(test)]
@@ -91,6 +139,30 @@ impl Introspection {
     }
 }
 
+/// Whether a request is allowed to introspect the schema.
+pub(crate) fn is_allowed(control: &IntrospectionControl, request: &SupergraphRequest) -> bool {
+    let restricted = control.authenticated_context.is_some()
+        || !control.client_names.is_empty()
+        || control.listen.is_some();
+    let headers = request.originating_request.headers();
+    !restricted
+        || control
+            .authenticated_context
+            .as_ref()
+            .map_or(false, |key| request.context.get_json_value(key).is_some())
+        || headers
+            .get(CLIENT_NAME_HEADER)
+            .and_then(|name| name.to_str().ok())
+            .map_or(false, |name| {
+                control.client_names.iter().any(|allowed| allowed == name)
+            })
+        || request
+            .originating_request
+            .extensions()
+            .get::<IntrospectionListener>()
+            .is_some()
+}
+
 #[cfg(test)]
 mod introspection_tests {
     use super::*;
@@ -115,4 +187,43 @@ mod introspection_tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn introspection_is_allowed_by_conditions() {
+        let request = |name: &str, value: &str| {
+            SupergraphRequest::fake_builder()
+                .header(name.to_string(), value.to_string())
+                .build()
+                .unwrap()
+        };
+        let control: IntrospectionControl = serde_json::from_value(serde_json::json!({
+            "authenticated_context": "auth::user",
+            "client_names": ["studio"]
+        }))
+        .unwrap();
+
+        assert!(is_allowed(
+            &IntrospectionControl::default(),
+            &request("x-test", "")
+        ));
+        assert!(!is_allowed(&control, &request("x-test", "")));
+        // The header of an unverified token is not enough
+        assert!(!is_allowed(
+            &control,
+            &request("authorization", "Bearer token")
+        ));
+        assert!(is_allowed(&control, &request(CLIENT_NAME_HEADER, "studio")));
+        assert!(!is_allowed(&control, &request(CLIENT_NAME_HEADER, "web")));
+
+        let authenticated = request("x-test", "");
+        authenticated.context.insert("auth::user", "alice").unwrap();
+        assert!(is_allowed(&control, &authenticated));
+
+        let mut on_listener = request("x-test", "");
+        on_listener
+            .originating_request
+            .extensions_mut()
+            .insert(IntrospectionListener);
+        assert!(is_allowed(&control, &on_listener));
+    }
 }
@@ -4,6 +4,8 @@
 
 use std::cmp::min;
 use std::fmt;
+use std::io;
+use std::io::Write;
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -12,6 +14,7 @@ use serde_json_bytes::Entry;
 use serde_json_bytes::Map;
 pub(crate) use serde_json_bytes::Value;
 
+use crate::configuration::JsonStrings;
 use crate::error::FetchError;
 
 /// A JSON object.
@@ -575,6 +578,203 @@ impl fmt::Display for Path {
     }
 }
 
+/// Largest integer up to which all integers are exactly represented by 64-bit floats.
+const MAX_SAFE_INTEGER: u64 = 1 << 53;
+
+/// Returns the first number of a JSON document that can't be parsed without losing precision:
+/// an integer beyond the 64-bit range, or a float whose closest 64-bit float has other digits.
+pub(crate) fn lossy_number(json: &[u8]) -> Option<&str> {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut i = 0;
+    while i < json.len() {
+        let byte = json[i];
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else if byte == b'"' {
+            in_string = true;
+        } else if byte == b'-' || byte.is_ascii_digit() {
+            let start = i;
+            while i < json.len()
+                && matches!(json[i], b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')
+            {
+                i += 1;
+            }
+            // numbers are ASCII
+            let number = std::str::from_utf8(&json[start..i]).ok()?;
+            if is_lossy(number) {
+                return Some(number);
+            }
+            continue;
+        }
+        i += 1;
+    }
+    None
+}
+
+fn is_lossy(number: &str) -> bool {
+    if !number.contains(&['.', 'e', 'E'][..]) {
+        return number.parse::<i64>().is_err() && number.parse::<u64>().is_err();
+    }
+    match number.parse::<f64>() {
+        Ok(float) if float.is_finite() => {
+            decimal_digits(number) != decimal_digits(&format!("{:e}", float))
+        }
+        _ => true,
+    }
+}
+
+/// Significant digits of a decimal number, and the position of the decimal point relative to
+/// them.
+fn decimal_digits(number: &str) -> (String, i64) {
+    let (mantissa, exponent) = match number.find(&['e', 'E'][..]) {
+        Some(index) => (
+            &number[..index],
+            number[index + 1..].parse::<i64>().unwrap_or_default(),
+        ),
+        None => (number, 0),
+    };
+    let mantissa = mantissa.trim_start_matches('-');
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{}{}", integer, fraction);
+    let point = integer.len() as i64 + exponent
+        - (digits.len() - digits.trim_start_matches('0').len()) as i64;
+    let digits = digits.trim_matches('0').to_string();
+    if digits.is_empty() {
+        (digits, 0)
+    } else {
+        (digits, point)
+    }
+}
+
+/// Replaces the integers beyond 2^53 in absolute value with strings.
+pub(crate) fn stringify_large_integers(value: &mut Value) {
+    match value {
+        Value::Number(number) => {
+            let large = match (number.as_u64(), number.as_i64()) {
+                (Some(unsigned), _) => unsigned > MAX_SAFE_INTEGER,
+                (None, Some(signed)) => signed.unsigned_abs() > MAX_SAFE_INTEGER,
+                (None, None) => false,
+            };
+            if large {
+                *value = Value::String(number.to_string().into());
+            }
+        }
+        Value::Array(array) => array.iter_mut().for_each(stringify_large_integers),
+        Value::Object(object) => object.values_mut().for_each(stringify_large_integers),
+        _ => {}
+    }
+}
+
+/// Serializes a value as JSON, escaping the characters of strings as configured.
+pub(crate) fn to_writer<W, T>(writer: W, value: &T, strings: &JsonStrings) -> serde_json::Result<()>
+where
+    W: io::Write,
+    T: ?Sized + Serialize,
+{
+    if !strings.ascii_only && !strings.escape_line_separators {
+        return serde_json::to_writer(writer, value);
+    }
+    let mut serializer = serde_json::Serializer::with_formatter(
+        writer,
+        StringEscaping {
+            ascii_only: strings.ascii_only,
+            escape_line_separators: strings.escape_line_separators,
+        },
+    );
+    value.serialize(&mut serializer)
+}
+
+/// Compact formatter escaping the characters that serde_json writes as is.
+struct StringEscaping {
+    ascii_only: bool,
+    escape_line_separators: bool,
+}
+
+impl serde_json::ser::Formatter for StringEscaping {
+    fn write_string_fragment<W>(&mut self, writer: &mut W, fragment: &str) -> io::Result<()>
+    where
+        W: ?Sized + io::Write,
+    {
+        let mut start = 0;
+        for (index, c) in fragment.char_indices() {
+            let escape = (self.ascii_only && !c.is_ascii())
+                || (self.escape_line_separators && matches!(c, '\u{2028}' | '\u{2029}'));
+            if escape {
+                writer.write_all(fragment[start..index].as_bytes())?;
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    write!(writer, "\\u{:04x}", unit)?;
+                }
+                start = index + c.len_utf8();
+            }
+        }
+        writer.write_all(fragment[start..].as_bytes())
+    }
+}
+
+/// Replaces the escaped lone surrogates of the strings of a JSON document, that serde_json
+/// refuses to parse, with escaped U+FFFD replacement characters. Returns `None` if the document
+/// has none.
+pub(crate) fn replace_lone_surrogates(json: &[u8]) -> Option<Vec<u8>> {
+    let mut replaced: Option<Vec<u8>> = None;
+    let mut copied = 0;
+    let mut in_string = false;
+    let mut i = 0;
+    while i < json.len() {
+        let byte = json[i];
+        if !in_string {
+            in_string = byte == b'"';
+            i += 1;
+            continue;
+        }
+        match byte {
+            b'"' => in_string = false,
+            b'\\' if json.get(i + 1) == Some(&b'u') => {
+                let length = match hex_escape(json, i) {
+                    Some(0xD800..=0xDBFF) => match hex_escape(json, i + 6) {
+                        Some(0xDC00..=0xDFFF) => 12,
+                        _ => 0,
+                    },
+                    Some(0xDC00..=0xDFFF) => 0,
+                    _ => 6,
+                };
+                if length == 0 {
+                    let output = replaced.get_or_insert_with(|| Vec::with_capacity(json.len()));
+                    output.extend_from_slice(&json[copied..i]);
+                    output.extend_from_slice(b"\\ufffd");
+                    i += 6;
+                    copied = i;
+                } else {
+                    i += length;
+                }
+                continue;
+            }
+            b'\\' => i += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    replaced.map(|mut output| {
+        output.extend_from_slice(&json[copied..]);
+        output
+    })
+}
+
+/// UTF-16 code unit of the `\uXXXX` escape starting at an index.
+fn hex_escape(json: &[u8], index: usize) -> Option<u16> {
+    let escape = json.get(index..index + 6)?;
+    if &escape[..2] != b"\\u" {
+        return None;
+    }
+    u16::from_str_radix(std::str::from_utf8(&escape[2..]).ok()?, 16).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json_bytes::json;
@@ -734,4 +934,97 @@ mod tests {
         let result = Value::from_path(&path, json);
         assert_eq!(result, json!({"obj":{"arr":null}}));
     }
+
+    #[test]
+    fn lossy_numbers_are_found() {
+        assert_eq!(
+            lossy_number(br#"{"id":9007199254740993,"price":1.25}"#),
+            None
+        );
+        assert_eq!(lossy_number(br#"{"id":-9223372036854775808}"#), None);
+        assert_eq!(lossy_number(br#"{"id":18446744073709551615}"#), None);
+        assert_eq!(
+            lossy_number(br#"{"f":0.1,"g":1e-7,"h":2.50000000000000000000}"#),
+            None
+        );
+        assert_eq!(
+            lossy_number(br#"{"id":"123456789012345678901234","n":-0.0}"#),
+            None
+        );
+        assert_eq!(
+            lossy_number(br#"{"s":"a\"1","id":123456789012345678901234}"#),
+            Some("123456789012345678901234")
+        );
+        assert_eq!(
+            lossy_number(br#"[0.1000000000000000055511151231257827]"#),
+            Some("0.1000000000000000055511151231257827")
+        );
+        assert_eq!(lossy_number(br#"[1e400]"#), Some("1e400"));
+    }
+
+    #[test]
+    fn large_integers_are_stringified() {
+        let mut value = json!({
+            "id": 9007199254740993u64,
+            "negative": -9007199254740993i64,
+            "safe": 9007199254740992u64,
+            "list": [1, 18446744073709551615u64],
+            "float": 1e20
+        });
+        stringify_large_integers(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "id": "9007199254740993",
+                "negative": "-9007199254740993",
+                "safe": 9007199254740992u64,
+                "list": [1, "18446744073709551615"],
+                "float": 1e20
+            })
+        );
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let value = json!({ "name": "caf\u{e9} \u{1f600}\u{2028}\"" });
+        let serialize = |strings: JsonStrings| {
+            let mut buffer = Vec::new();
+            to_writer(&mut buffer, &value, &strings).unwrap();
+            String::from_utf8(buffer).unwrap()
+        };
+        assert_eq!(
+            serialize(JsonStrings::default()),
+            "{\"name\":\"caf\u{e9} \u{1f600}\u{2028}\\\"\"}"
+        );
+        assert_eq!(
+            serialize(JsonStrings {
+                escape_line_separators: true,
+                ..Default::default()
+            }),
+            "{\"name\":\"caf\u{e9} \u{1f600}\\u2028\\\"\"}"
+        );
+        assert_eq!(
+            serialize(JsonStrings {
+                ascii_only: true,
+                ..Default::default()
+            }),
+            r#"{"name":"caf\u00e9 \ud83d\ude00\u2028\""}"#
+        );
+    }
+
+    #[test]
+    fn lone_surrogates_are_replaced() {
+        assert_eq!(
+            replace_lone_surrogates(br#"{"a":"\ud83d\ude00","b":"\\ud800","\u00e9":1}"#),
+            None
+        );
+        let replaced =
+            replace_lone_surrogates(br#"{"a":"x\ud800y","b":"\udc00","c":"\ud800\u0041"}"#)
+                .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&replaced).unwrap(),
+            r#"{"a":"x\ufffdy","b":"\ufffd","c":"\ufffd\u0041"}"#
+        );
+        assert!(serde_json::from_slice::<serde_json::Value>(&replaced).is_ok());
+    }
 }
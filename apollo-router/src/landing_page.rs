@@ -0,0 +1,152 @@
+//! Landing page displayed to browsers.
+//!
+//! The page is Apollo Sandbox, GraphiQL or a static HTML file, sent with a
+//! `Content-Security-Policy` header. The built-in pages send their operations to the GraphQL
+//! endpoint, even when the landing page has its own path.
+
+use axum::response::Html;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use bytes::Bytes;
+use http::header::CONTENT_SECURITY_POLICY;
+use http::HeaderValue;
+use tower::BoxError;
+
+use crate::configuration::LandingPageKind;
+use crate::configuration::Server;
+
+const SANDBOX_PAGE: &str = include_str!("../resources/index.html");
+const GRAPHIQL_PAGE: &str = include_str!("../resources/graphiql.html");
+
+/// Expression of the built-in pages giving the URL of the GraphQL endpoint.
+const ENDPOINT_EXPRESSION: &str = "window.location.href";
+
+const SANDBOX_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline' https://embeddable-sandbox.cdn.apollographql.com; \
+    style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; \
+    font-src https://fonts.gstatic.com; \
+    img-src 'self' https://apollo-server-landing-page.cdn.apollographql.com; \
+    manifest-src https://apollo-server-landing-page.cdn.apollographql.com; \
+    frame-src https://sandbox.embed.apollographql.com";
+
+const GRAPHIQL_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline' https://unpkg.com; \
+    style-src 'self' 'unsafe-inline' https://unpkg.com; \
+    img-src 'self' data:";
+
+/// The landing page, ready to be sent.
+#[derive(Clone, Debug)]
+pub(crate) struct LandingPageContent {
+    html: Bytes,
+    content_security_policy: Option<HeaderValue>,
+}
+
+impl LandingPageContent {
+    /// The landing page of the server configuration, if it displays one.
+    pub(crate) fn new(server: &Server) -> Result<Option<LandingPageContent>, BoxError> {
+        if !server.landing_page {
+            return Ok(None);
+        }
+        let config = &server.experimental_landing_page;
+        let (html, default_policy) = match config.page {
+            LandingPageKind::Disabled => return Ok(None),
+            LandingPageKind::Sandbox => (
+                Self::with_endpoint(SANDBOX_PAGE, server),
+                Some(SANDBOX_CONTENT_SECURITY_POLICY),
+            ),
+            LandingPageKind::Graphiql => (
+                Self::with_endpoint(GRAPHIQL_PAGE, server),
+                Some(GRAPHIQL_CONTENT_SECURITY_POLICY),
+            ),
+            LandingPageKind::File => {
+                let path = config
+                    .file
+                    .as_ref()
+                    .ok_or("the file page needs a `file` option")?;
+                let html = std::fs::read(path).map_err(|e| {
+                    format!("could not read the landing page {}: {}", path.display(), e)
+                })?;
+                (Bytes::from(html), None)
+            }
+        };
+        let content_security_policy = config
+            .content_security_policy
+            .as_deref()
+            .or(default_policy)
+            .map(HeaderValue::from_str)
+            .transpose()
+            .map_err(|e| format!("invalid Content-Security-Policy: {}", e))?;
+        Ok(Some(LandingPageContent {
+            html,
+            content_security_policy,
+        }))
+    }
+
+    /// Points a built-in page to the GraphQL endpoint, if the landing page has its own path.
+    fn with_endpoint(page: &'static str, server: &Server) -> Bytes {
+        if server.experimental_landing_page.path.is_none() {
+            return Bytes::from_static(page.as_bytes());
+        }
+        let graphql_path = server.graphql_path.trim_end_matches('*');
+        let endpoint = format!(
+            "new URL({}, {}).href",
+            serde_json::Value::from(graphql_path),
+            ENDPOINT_EXPRESSION
+        );
+        Bytes::from(page.replacen(ENDPOINT_EXPRESSION, &endpoint, 1))
+    }
+
+    pub(crate) fn response(&self) -> Response {
+        let mut response = Html(self.html.clone()).into_response();
+        if let Some(policy) = &self.content_security_policy {
+            response
+                .headers_mut()
+                .insert(CONTENT_SECURITY_POLICY, policy.clone());
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::LandingPage;
+
+    #[test]
+    fn built_in_pages_use_the_graphql_endpoint() {
+        let server = |page: LandingPageKind, path: Option<&str>| {
+            Server::builder()
+                .graphql_path("/graphql".to_string())
+                .landing_page_options(LandingPage {
+                    page,
+                    file: None,
+                    content_security_policy: None,
+                    path: path.map(String::from),
+                })
+                .build()
+        };
+
+        let sandbox = LandingPageContent::new(&server(LandingPageKind::Sandbox, None))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sandbox.html, SANDBOX_PAGE.as_bytes());
+        assert_eq!(
+            sandbox.content_security_policy.unwrap(),
+            SANDBOX_CONTENT_SECURITY_POLICY
+        );
+
+        let graphiql =
+            LandingPageContent::new(&server(LandingPageKind::Graphiql, Some("/explore")))
+                .unwrap()
+                .unwrap();
+        let html = String::from_utf8(graphiql.html.to_vec()).unwrap();
+        assert!(html.contains(r#"new URL("/graphql", window.location.href).href"#));
+
+        assert!(
+            LandingPageContent::new(&server(LandingPageKind::Disabled, None))
+                .unwrap()
+                .is_none()
+        );
+        assert!(LandingPageContent::new(&server(LandingPageKind::File, None)).is_err());
+    }
+}
@@ -5,6 +5,8 @@
 //! If your interests are confined to developing plugins, then the following modules
 //! are likely to be of most interest to you:
 //!
+//! * [`sdk`] - the stable API for plugins, following semantic versioning: start here
+//!
 //! * [`self`] - this module (apollo_router) contains high level building blocks for a federated GraphQL router
 //!
 //! * [`graphql`] - graphql specific functionality for requests, responses, errors
@@ -47,38 +49,71 @@ mod json_ext;
 #[macro_use]
 pub mod plugin;
 
+mod allocator;
+#[cfg(feature = "http_server")]
 mod axum_http_server_factory;
 mod cache;
+#[cfg(feature = "http_server")]
+mod compression_dictionary;
 mod configuration;
+mod container;
 mod context;
+mod engine;
 mod error;
+#[cfg(feature = "http_server")]
 mod executable;
+#[cfg(feature = "http_server")]
 mod files;
 pub mod graphql;
+#[cfg(feature = "http_server")]
+mod health;
 mod http_ext;
+#[cfg(feature = "http_server")]
 mod http_server_factory;
 mod introspection;
+#[cfg(feature = "http_server")]
+mod landing_page;
 pub mod layers;
 mod plugins;
 mod query_planner;
+#[cfg(feature = "http_server")]
+mod remote_schema;
 mod request;
 mod response;
+#[cfg(feature = "http_server")]
+mod response_scanning;
+#[cfg(feature = "http_server")]
 mod router;
 mod router_factory;
+pub mod sdk;
 pub mod services;
 mod spec;
+#[cfg(feature = "http_server")]
 mod state_machine;
 mod test_harness;
+mod tls;
+#[cfg(all(windows, feature = "http_server"))]
+mod windows;
 
 pub use crate::configuration::Configuration;
+pub use crate::configuration::ConfigurationOrigin;
+pub use crate::configuration::EffectiveConfiguration;
 pub use crate::configuration::ListenAddr;
 pub use crate::context::Context;
+pub use crate::engine::Engine;
+pub use crate::error::ApolloRouterError;
+#[cfg(feature = "http_server")]
 pub use crate::executable::main;
+#[cfg(feature = "http_server")]
 pub use crate::executable::Executable;
-pub use crate::router::ApolloRouterError;
+pub use crate::plugins::telemetry::set_tracing_subscriber;
+#[cfg(feature = "http_server")]
 pub use crate::router::ConfigurationSource;
+#[cfg(feature = "http_server")]
 pub use crate::router::RouterHttpServer;
+#[cfg(feature = "http_server")]
 pub use crate::router::SchemaSource;
+#[cfg(feature = "http_server")]
 pub use crate::router::ShutdownSource;
 pub use crate::test_harness::TestHarness;
 
@@ -14,6 +14,7 @@
 //! processing. At each stage a [`Service`] is provided which provides an appropriate
 //! mechanism for interacting with the request and response.
 
+mod scanning;
 pub mod serde;
 #[macro_use]
 pub mod test;
@@ -38,8 +39,11 @@ use tower::BoxError;
 use tower::Service;
 use tower::ServiceBuilder;
 
+pub use self::scanning::ResponseScanner;
+pub use self::scanning::ScanVerdict;
 use crate::layers::ServiceBuilderExt;
 use crate::services::execution;
+use crate::services::router;
 use crate::services::subgraph;
 use crate::services::supergraph;
 use crate::transport;
@@ -168,6 +172,13 @@ pub trait Plugin: Send + Sync + 'static {
     where
         Self: Sized;
 
+    /// This service handles the HTTP requests to the GraphQL endpoint, before their body is parsed, and the HTTP responses sent back.
+    /// Define `router_service` if your customization needs the raw request (for example, to rewrite its URI or reject it based on its headers).
+    /// It is experimental: its signature may change in future releases.
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        service
+    }
+
     /// This service runs at the very beginning and very end of the request lifecycle.
     /// Define supergraph_service if your customization needs to interact at the earliest or latest point possible.
     /// For example, this is a good opportunity to perform JWT verification before allowing a request to proceed further.
@@ -198,6 +209,19 @@ pub trait Plugin: Send + Sync + 'static {
         None
     }
 
+    /// The `admin_endpoint` method lets you declare an endpoint for operators, only exposed on the admin listener.
+    /// Like `custom_endpoint`, it's only accessible for `apollo.` and `experimental.` plugins, via `/plugins/group.plugin_name` on the admin listener.
+    /// It is experimental: its signature may change in future releases.
+    fn admin_endpoint(&self) -> Option<transport::BoxService> {
+        None
+    }
+
+    /// The `response_scanner` method lets you scan the serialized responses right before they are sent to clients, to redact or block them.
+    /// It is experimental: its signature may change in future releases.
+    fn response_scanner(&self) -> Option<Arc<dyn ResponseScanner>> {
+        None
+    }
+
     /// Return the name of the plugin.
     fn name(&self) -> &'static str
     where
@@ -218,6 +242,9 @@ fn get_type_of<T>(_: &T) -> &'static str {
 /// For more information about the plugin lifecycle please check this documentation <https://www.apollographql.com/docs/router/customizations/native/#plugin-lifecycle>
 #[async_trait]
 pub(crate) trait DynPlugin: Send + Sync + 'static {
+    /// This service handles the HTTP requests to the GraphQL endpoint, before their body is parsed, and the HTTP responses sent back.
+    fn router_service(&self, service: router::BoxService) -> router::BoxService;
+
     /// This service runs at the very beginning and very end of the request lifecycle.
     /// It's the entrypoint of every requests and also the last hook before sending the response.
     /// Define supergraph_service if your customization needs to interact at the earliest or latest point possible.
@@ -241,6 +268,12 @@ pub(crate) trait DynPlugin: Send + Sync + 'static {
     /// For now it's only accessible for official `apollo.` plugins and for `experimental.`. This endpoint will be accessible via `/plugins/group.plugin_name`
     fn custom_endpoint(&self) -> Option<transport::BoxService>;
 
+    /// The `admin_endpoint` method lets you declare an endpoint for operators, only exposed on the admin listener.
+    fn admin_endpoint(&self) -> Option<transport::BoxService>;
+
+    /// The `response_scanner` method lets you scan the serialized responses right before they are sent to clients, to redact or block them.
+    fn response_scanner(&self) -> Option<Arc<dyn ResponseScanner>>;
+
     /// Return the name of the plugin.
     fn name(&self) -> &'static str;
 }
@@ -251,6 +284,10 @@ where
     T: Plugin,
     for<'de> <T as Plugin>::Config: Deserialize<'de>,
 {
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        self.router_service(service)
+    }
+
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
         self.supergraph_service(service)
     }
@@ -267,6 +304,14 @@ where
         self.custom_endpoint()
     }
 
+    fn admin_endpoint(&self) -> Option<transport::BoxService> {
+        self.admin_endpoint()
+    }
+
+    fn response_scanner(&self) -> Option<Arc<dyn ResponseScanner>> {
+        self.response_scanner()
+    }
+
     fn name(&self) -> &'static str {
         self.name()
     }
@@ -0,0 +1,31 @@
+//! Scanning of the responses sent to clients.
+
+use std::ops::Range;
+
+/// Scanner of the serialized responses, looking for data that must not reach clients, like
+/// personal or secret data.
+///
+/// A scanner is returned by [`Plugin::response_scanner`](super::Plugin::response_scanner). It
+/// sees the JSON of each response right before it is sent: the primary response, then each
+/// deferred response, as they stream. Scanners run in the order of their plugins, each one
+/// seeing the response redacted by the previous ones.
+pub trait ResponseScanner: Send + Sync + 'static {
+    /// Scans the serialized JSON of a response.
+    fn scan(&self, json: &[u8]) -> ScanVerdict;
+}
+
+/// What happens to a scanned response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ScanVerdict {
+    /// The response is sent unchanged.
+    Pass,
+
+    /// The characters of these byte spans are replaced with `*`, in the strings of the
+    /// response only: the JSON stays valid.
+    Redact(Vec<Range<usize>>),
+
+    /// The response is replaced with a `RESPONSE_BLOCKED` error, which ends the response stream.
+    /// The reason is logged, not sent to the client.
+    Block(String),
+}
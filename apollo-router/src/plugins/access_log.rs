@@ -0,0 +1,565 @@
+//! Access log of the requests, as JSON lines.
+//!
+//! The access log is separate from the logs of the tracing subscriber, so that its format stays
+//! stable for log ingestion pipelines: each request produces one line with the configured fields,
+//! always present and `null` if unknown. Lines are written by a dedicated thread, to the standard
+//! output or to a file that can be rotated hourly or daily.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::telemetry::CLIENT_NAME;
+use crate::plugins::telemetry::CLIENT_VERSION;
+use crate::register_plugin;
+use crate::services::execution;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+use crate::ExecutionRequest;
+use crate::SubgraphRequest;
+use crate::SupergraphRequest;
+
+const ACCESS_LOG_ID_CONTEXT_KEY: &str = "access_log::id";
+
+/// Configuration for the access log
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// File the lines are appended to (default: the standard output)
+    path: Option<PathBuf>,
+    /// Rotation of the file (default: never)
+    #[serde(default)]
+    rotation: Rotation,
+    /// Fields of the lines, in this order (default: all fields)
+    fields: Option<Vec<Field>>,
+}
+
+/// Rotation of the access log file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Rotation {
+    /// A single file
+    Never,
+    /// A file per hour, suffixed with the date and hour
+    Hourly,
+    /// A file per day, suffixed with the date
+    Daily,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation::Never
+    }
+}
+
+/// Field of the access log lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Field {
+    /// When the request started, in RFC 3339 format
+    Timestamp,
+    /// Operation name sent by the client
+    OperationName,
+    /// Client name, from the client name header of the `apollo` configuration
+    ClientName,
+    /// Client version, from the client version header of the `apollo` configuration
+    ClientVersion,
+    /// HTTP status of the response
+    Status,
+    /// Time until the response was ready, in milliseconds
+    DurationMs,
+    /// Time before the execution started, in milliseconds
+    PlanningMs,
+    /// Time spent executing the query plan, in milliseconds
+    ExecutionMs,
+    /// Summary of the subgraph fetches
+    Subgraphs,
+}
+
+const ALL_FIELDS: [Field; 9] = [
+    Field::Timestamp,
+    Field::OperationName,
+    Field::ClientName,
+    Field::ClientVersion,
+    Field::Status,
+    Field::DurationMs,
+    Field::PlanningMs,
+    Field::ExecutionMs,
+    Field::Subgraphs,
+];
+
+impl Field {
+    fn name(self) -> &'static str {
+        match self {
+            Field::Timestamp => "timestamp",
+            Field::OperationName => "operation_name",
+            Field::ClientName => "client_name",
+            Field::ClientVersion => "client_version",
+            Field::Status => "status",
+            Field::DurationMs => "duration_ms",
+            Field::PlanningMs => "planning_ms",
+            Field::ExecutionMs => "execution_ms",
+            Field::Subgraphs => "subgraphs",
+        }
+    }
+}
+
+/// Data of a request in flight.
+#[derive(Debug)]
+struct Record {
+    started_at: SystemTime,
+    start: Instant,
+    operation_name: Option<String>,
+    client_name: Option<String>,
+    client_version: Option<String>,
+    planning_ms: Option<u64>,
+    execution_ms: Option<u64>,
+    fetches: Vec<Fetch>,
+}
+
+/// Summary of a subgraph fetch.
+#[derive(Debug, Serialize)]
+struct Fetch {
+    subgraph: String,
+    /// HTTP status of the subgraph response, `None` if the fetch failed
+    status: Option<u16>,
+    duration_ms: u64,
+}
+
+#[derive(Debug)]
+struct AccessLog {
+    fields: Vec<Field>,
+    next_id: AtomicU64,
+    records: Mutex<HashMap<u64, Record>>,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl AccessLog {
+    fn start(&self, req: &SupergraphRequest) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let context_value = |key: &str| {
+            req.context
+                .get::<_, String>(key)
+                .ok()
+                .flatten()
+                .filter(|value| !value.is_empty())
+        };
+        let record = Record {
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            operation_name: req.originating_request.body().operation_name.clone(),
+            client_name: context_value(CLIENT_NAME),
+            client_version: context_value(CLIENT_VERSION),
+            planning_ms: None,
+            execution_ms: None,
+            fetches: Vec::new(),
+        };
+        self.records
+            .lock()
+            .expect("lock poisoned")
+            .insert(id, record);
+        id
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Record)) {
+        if let Some(record) = self.records.lock().expect("lock poisoned").get_mut(&id) {
+            f(record);
+        }
+    }
+
+    /// Removes the record of a request and sends its line to the writer.
+    fn finish(&self, id: u64, status: Option<u16>) {
+        let record = self.records.lock().expect("lock poisoned").remove(&id);
+        if let Some(record) = record {
+            let line = Value::Object(self.line(&record, status)).to_string();
+            // The writer only stops when the plugin is dropped
+            let _ = self.sender.send(line);
+        }
+    }
+
+    fn line(&self, record: &Record, status: Option<u16>) -> Map<String, Value> {
+        self.fields
+            .iter()
+            .map(|field| {
+                let value = match field {
+                    Field::Timestamp => {
+                        json!(humantime::format_rfc3339_millis(record.started_at).to_string())
+                    }
+                    Field::OperationName => json!(record.operation_name),
+                    Field::ClientName => json!(record.client_name),
+                    Field::ClientVersion => json!(record.client_version),
+                    Field::Status => json!(status),
+                    Field::DurationMs => json!(record.start.elapsed().as_millis() as u64),
+                    Field::PlanningMs => json!(record.planning_ms),
+                    Field::ExecutionMs => json!(record.execution_ms),
+                    Field::Subgraphs => json!(record.fetches),
+                };
+                (field.name().to_string(), value)
+            })
+            .collect()
+    }
+}
+
+fn access_log_id(context: &Context) -> Option<u64> {
+    context.get(ACCESS_LOG_ID_CONTEXT_KEY).ok().flatten()
+}
+
+/// Destination of the lines.
+struct Writer {
+    path: Option<PathBuf>,
+    rotation: Rotation,
+    /// The current file and its path
+    file: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl Writer {
+    /// Creates the writer, opening the file right away so that errors are reported when the
+    /// router starts.
+    fn new(path: Option<PathBuf>, rotation: Rotation) -> std::io::Result<Self> {
+        let mut writer = Writer {
+            path,
+            rotation,
+            file: None,
+        };
+        writer.current_file()?;
+        Ok(writer)
+    }
+
+    /// Path of the file for the current time, with the rotation suffix.
+    fn file_path(path: &Path, rotation: Rotation, now: SystemTime) -> PathBuf {
+        let timestamp = humantime::format_rfc3339_seconds(now).to_string();
+        let suffix = match rotation {
+            Rotation::Never => return path.to_path_buf(),
+            Rotation::Hourly => &timestamp[..13],
+            Rotation::Daily => &timestamp[..10],
+        };
+        let mut name = OsString::from(path.as_os_str());
+        name.push(".");
+        name.push(suffix);
+        PathBuf::from(name)
+    }
+
+    /// The file the lines are currently appended to, if the lines go to a file.
+    fn current_file(&mut self) -> std::io::Result<Option<&mut BufWriter<File>>> {
+        let path = match &self.path {
+            Some(path) => Self::file_path(path, self.rotation, SystemTime::now()),
+            None => return Ok(None),
+        };
+        if !matches!(&self.file, Some((current, _)) if *current == path) {
+            if let Some((_, mut previous)) = self.file.take() {
+                previous.flush()?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            self.file = Some((path, BufWriter::new(file)));
+        }
+        Ok(self.file.as_mut().map(|(_, file)| file))
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        match self.current_file()? {
+            Some(file) => writeln!(file, "{}", line),
+            None => writeln!(std::io::stdout().lock(), "{}", line),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.file {
+            Some((_, file)) => file.flush(),
+            None => std::io::stdout().flush(),
+        }
+    }
+}
+
+/// Writes the lines sent to the returned channel on a dedicated thread, which stops when the
+/// sender is dropped.
+fn spawn_writer(mut writer: Writer) -> std::io::Result<mpsc::UnboundedSender<String>> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    std::thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || {
+            while let Some(line) = receiver.blocking_recv() {
+                let mut result = writer.write_line(&line);
+                // Flush once the lines sent in the meantime are written
+                while let Ok(line) = receiver.try_recv() {
+                    result = result.and_then(|_| writer.write_line(&line));
+                }
+                if let Err(e) = result.and_then(|_| writer.flush()) {
+                    tracing::error!("could not write the access log: {}", e);
+                }
+            }
+        })?;
+    Ok(sender)
+}
+
+#[derive(Debug)]
+struct AccessLogPlugin {
+    access_log: Arc<AccessLog>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for AccessLogPlugin {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let config = init.config;
+        let writer = Writer::new(config.path.clone(), config.rotation).map_err(|e| {
+            format!(
+                "could not open the access log {}: {}",
+                config.path.unwrap_or_default().display(),
+                e
+            )
+        })?;
+        Ok(AccessLogPlugin {
+            access_log: Arc::new(AccessLog {
+                fields: config.fields.unwrap_or_else(|| ALL_FIELDS.to_vec()),
+                next_id: AtomicU64::new(0),
+                records: Default::default(),
+                sender: spawn_writer(writer)?,
+            }),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let start_log = self.access_log.clone();
+        let finish_log = self.access_log.clone();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SupergraphRequest| {
+                    let id = start_log.start(req);
+                    let _ = req.context.insert(ACCESS_LOG_ID_CONTEXT_KEY, id);
+                    id
+                },
+                move |id: u64, f| {
+                    let access_log = finish_log.clone();
+                    async move {
+                        let result: supergraph::ServiceResult = f.await;
+                        let status = result
+                            .as_ref()
+                            .ok()
+                            .map(|response| response.response.status().as_u16());
+                        access_log.finish(id, status);
+                        result
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        let access_log = self.access_log.clone();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &ExecutionRequest| {
+                    let id = access_log_id(&req.context);
+                    if let Some(id) = id {
+                        access_log.update(id, |record| {
+                            record.planning_ms = Some(record.start.elapsed().as_millis() as u64)
+                        });
+                    }
+                    (id, Instant::now(), access_log.clone())
+                },
+                |(id, start, access_log): (Option<u64>, Instant, Arc<AccessLog>), f| async move {
+                    let result: execution::ServiceResult = f.await;
+                    if let Some(id) = id {
+                        access_log.update(id, |record| {
+                            record.execution_ms = Some(start.elapsed().as_millis() as u64)
+                        });
+                    }
+                    result
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let access_log = self.access_log.clone();
+        let name = name.to_string();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SubgraphRequest| {
+                    (
+                        access_log_id(&req.context),
+                        Instant::now(),
+                        access_log.clone(),
+                        name.clone(),
+                    )
+                },
+                |(id, start, access_log, subgraph): (
+                    Option<u64>,
+                    Instant,
+                    Arc<AccessLog>,
+                    String,
+                ),
+                 f| async move {
+                    let result: subgraph::ServiceResult = f.await;
+                    if let Some(id) = id {
+                        let fetch = Fetch {
+                            subgraph,
+                            status: result
+                                .as_ref()
+                                .ok()
+                                .map(|response| response.response.status().as_u16()),
+                            duration_ms: start.elapsed().as_millis() as u64,
+                        };
+                        access_log.update(id, |record| record.fetches.push(fetch));
+                    }
+                    result
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "access_log", AccessLogPlugin);
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json_bytes::json as bjson;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::SupergraphResponse;
+
+    fn access_log(fields: Vec<Field>) -> (Arc<AccessLog>, mpsc::UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let access_log = AccessLog {
+            fields,
+            next_id: AtomicU64::new(0),
+            records: Default::default(),
+            sender,
+        };
+        (Arc::new(access_log), receiver)
+    }
+
+    #[tokio::test]
+    async fn requests_are_logged() {
+        let (access_log, mut receiver) = access_log(ALL_FIELDS.to_vec());
+        let plugin = AccessLogPlugin {
+            access_log: access_log.clone(),
+        };
+
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(move |req: SupergraphRequest| {
+                let id = access_log_id(&req.context).unwrap();
+                access_log.update(id, |record| {
+                    record.fetches.push(Fetch {
+                        subgraph: "accounts".to_string(),
+                        status: Some(200),
+                        duration_ms: 3,
+                    })
+                });
+                Ok(SupergraphResponse::fake_builder()
+                    .data(bjson!({ "me": { "id": "1" } }))
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let context = Context::new();
+        context.insert(CLIENT_NAME, "web".to_string()).unwrap();
+        plugin
+            .supergraph_service(mock_service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("{ me { id } }")
+                    .operation_name("Me")
+                    .context(context)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let line: Value = serde_json::from_str(&receiver.recv().await.unwrap()).unwrap();
+        let keys: Vec<&str> = line
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            keys,
+            ALL_FIELDS
+                .iter()
+                .map(|field| field.name())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(line["operation_name"], "Me");
+        assert_eq!(line["client_name"], "web");
+        assert_eq!(line["client_version"], Value::Null);
+        assert_eq!(line["status"], 200);
+        assert_eq!(
+            line["subgraphs"],
+            json!([{ "subgraph": "accounts", "status": 200, "duration_ms": 3 }])
+        );
+    }
+
+    #[test]
+    fn lines_have_the_configured_fields() {
+        let (access_log, _receiver) = access_log(vec![Field::Status, Field::OperationName]);
+        let id = access_log.start(&SupergraphRequest::fake_builder().build().unwrap());
+        let record = access_log.records.lock().unwrap().remove(&id).unwrap();
+        assert_eq!(
+            Value::Object(access_log.line(&record, None)).to_string(),
+            r#"{"status":null,"operation_name":null}"#
+        );
+    }
+
+    #[test]
+    fn files_are_rotated() {
+        let path = PathBuf::from("/var/log/router/access.log");
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_660_000_000);
+        assert_eq!(Writer::file_path(&path, Rotation::Never, now), path);
+        assert_eq!(
+            Writer::file_path(&path, Rotation::Hourly, now),
+            PathBuf::from("/var/log/router/access.log.2022-08-08T23")
+        );
+        assert_eq!(
+            Writer::file_path(&path, Rotation::Daily, now),
+            PathBuf::from("/var/log/router/access.log.2022-08-08")
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = Writer::new(Some(dir.path().join("access.log")), Rotation::Never).unwrap();
+        writer.write_line("{}").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("access.log")).unwrap(),
+            "{}\n"
+        );
+    }
+}
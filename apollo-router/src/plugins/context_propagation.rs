@@ -0,0 +1,218 @@
+//! Exchange of context entries with subgraphs.
+//!
+//! Selected entries of the context are sent to subgraphs in the `context` extension of their
+//! requests, and optionally in a header. Subgraphs answer with a `context` extension of their
+//! own, whose selected entries are written back into the context, where plugins of the later
+//! stages can read them.
+
+use http::header::HeaderName;
+use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::json_ext::Object;
+use crate::plugin::serde::deserialize_option_header_name;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::Context;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+/// Extension holding the context entries, in subgraph requests and responses.
+const CONTEXT_EXTENSION: &str = "context";
+
+/// Configuration for the exchange of context entries with subgraphs
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Keys of the context entries sent to subgraphs. A key ending with `*` selects the entries
+    /// starting with it
+    #[serde(default)]
+    send: Vec<String>,
+    /// Keys of the context entries read from the responses of subgraphs, which overwrite the
+    /// entries of the context. A key ending with `*` selects the entries starting with it
+    #[serde(default)]
+    receive: Vec<String>,
+    /// Also send the entries to subgraphs in this header, as a JSON object
+    #[schemars(with = "Option<String>", default)]
+    #[serde(deserialize_with = "deserialize_option_header_name", default)]
+    header: Option<HeaderName>,
+    /// Subgraphs exchanging context entries (default: all)
+    #[serde(default)]
+    subgraphs: Option<Vec<String>>,
+}
+
+/// Whether a key is selected by one of the patterns.
+fn selected(patterns: &[String], key: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == pattern,
+        })
+}
+
+/// The context entries to send to subgraphs.
+fn sent_entries(patterns: &[String], context: &Context) -> Object {
+    context
+        .iter()
+        .filter(|entry| selected(patterns, entry.key()))
+        .map(|entry| (entry.key().as_str().into(), entry.value().clone()))
+        .collect()
+}
+
+#[derive(Debug)]
+struct ContextPropagation {
+    config: Config,
+}
+
+#[async_trait::async_trait]
+impl Plugin for ContextPropagation {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(ContextPropagation {
+            config: init.config,
+        })
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let propagated = self
+            .config
+            .subgraphs
+            .as_ref()
+            .map(|subgraphs| subgraphs.iter().any(|subgraph| subgraph == name))
+            .unwrap_or(true);
+        if !propagated || (self.config.send.is_empty() && self.config.receive.is_empty()) {
+            return service;
+        }
+        let send = self.config.send.clone();
+        let header = self.config.header.clone();
+        let receive = self.config.receive.clone();
+        service
+            .map_request(move |mut req: SubgraphRequest| {
+                if send.is_empty() {
+                    return req;
+                }
+                let entries = sent_entries(&send, &req.context);
+                if entries.is_empty() {
+                    return req;
+                }
+                if let Some(header) = &header {
+                    match serde_json::to_string(&entries)
+                        .ok()
+                        .and_then(|json| HeaderValue::from_str(&json).ok())
+                    {
+                        Some(value) => {
+                            req.subgraph_request.headers_mut().insert(header, value);
+                        }
+                        None => {
+                            tracing::debug!("context entries are not valid in a header")
+                        }
+                    }
+                }
+                req.subgraph_request
+                    .body_mut()
+                    .extensions
+                    .insert(CONTEXT_EXTENSION.into(), Value::Object(entries));
+                req
+            })
+            .map_response(move |mut res: SubgraphResponse| {
+                // The extension is meant for the router, it is never forwarded
+                if let Some(Value::Object(entries)) =
+                    res.response.body_mut().extensions.remove(CONTEXT_EXTENSION)
+                {
+                    for (key, value) in entries {
+                        if selected(&receive, key.as_str()) {
+                            let _ = res.context.insert_json_value(key.as_str(), value);
+                        }
+                    }
+                }
+                res
+            })
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "context_propagation", ContextPropagation);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::DynPlugin;
+
+    #[tokio::test]
+    async fn context_entries_are_exchanged_with_subgraphs() {
+        let plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .get("experimental.context_propagation")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({
+                "send": ["tenant", "my_plugin::*"],
+                "receive": ["flags"],
+                "header": "x-router-context"
+            }))
+            .await
+            .unwrap();
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SubgraphRequest| {
+                assert_eq!(
+                    req.subgraph_request
+                        .body()
+                        .extensions
+                        .get(CONTEXT_EXTENSION),
+                    Some(&serde_json_bytes::json!({
+                        "tenant": "acme",
+                        "my_plugin::region": "eu"
+                    }))
+                );
+                assert!(req
+                    .subgraph_request
+                    .headers()
+                    .contains_key("x-router-context"));
+                Ok(SubgraphResponse::fake_builder()
+                    .extension(
+                        CONTEXT_EXTENSION,
+                        serde_json_bytes::json!({ "flags": ["beta"], "tenant": "other" }),
+                    )
+                    .context(req.context)
+                    .build())
+            });
+
+        let context = Context::new();
+        context.insert("tenant", "acme").unwrap();
+        context.insert("my_plugin::region", "eu").unwrap();
+        context.insert("secret", "hidden").unwrap();
+        let response = plugin
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(SubgraphRequest::fake_builder().context(context).build())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.context.get::<_, Vec<String>>("flags").unwrap(),
+            Some(vec!["beta".to_string()])
+        );
+        // Only the selected entries are received
+        assert_eq!(
+            response.context.get::<_, String>("tenant").unwrap(),
+            Some("acme".to_string())
+        );
+        assert!(response
+            .response
+            .body()
+            .extensions
+            .get(CONTEXT_EXTENSION)
+            .is_none());
+    }
+}
@@ -242,6 +242,12 @@ mod csrf_tests {
             .unwrap();
         assert_accepted(config.clone(), with_preflight_content_type).await;
 
+        let with_graphql_document = SupergraphRequest::fake_builder()
+            .header("content-type", "application/graphql")
+            .build()
+            .unwrap();
+        assert_accepted(config.clone(), with_graphql_document).await;
+
         let with_preflight_header = SupergraphRequest::fake_builder()
             .header("apollo-require-preflight", "this-is-a-test")
             .build()
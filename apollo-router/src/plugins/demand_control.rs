@@ -0,0 +1,930 @@
+//! Demand control.
+//!
+//! Estimates the cost of operations before they are executed, and rejects the operations that
+//! would cost too much. Once an operation is executed, its actual cost is measured on the
+//! response, so the estimates can be compared to reality.
+//!
+//! The cost of an operation is the sum of the cost of its fields:
+//! - a field returning an object, an interface or a union costs 1,
+//! - a field returning a scalar or an enum costs 0,
+//! - a root mutation field costs 10,
+//! - the cost of a list field is multiplied by the size of the list.
+//!
+//! Field costs can be changed with the `@cost(weight:)` directive in the schema, and list sizes
+//! with the `@listSize(assumedSize:, slicingArguments:)` directive: the size of a list is the
+//! value of its slicing argument in the operation, like `first`, or its assumed size if the
+//! argument is missing. The configuration overrides both directives. Lists without a size are
+//! expected to have `list_size` items.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use apollo_parser::ast;
+use futures::future::ready;
+use futures::stream::once;
+use futures::StreamExt;
+use http::HeaderValue;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json_bytes::json;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::error::Error;
+use crate::json_ext::Object;
+use crate::json_ext::Value;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::query_planner::OperationKind;
+use crate::register_plugin;
+use crate::services::supergraph;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+/// Context key holding the estimated cost of the operation.
+pub(crate) const ESTIMATED_COST_CONTEXT_KEY: &str = "apollo_demand_control::estimated_cost";
+/// Context key holding the actual cost of the operation, measured on its response.
+pub(crate) const ACTUAL_COST_CONTEXT_KEY: &str = "apollo_demand_control::actual_cost";
+
+const MUTATION_FIELD_WEIGHT: f64 = 10.0;
+
+const COST_HEADER: &str = "x-graphql-cost";
+
+/// Configuration for demand control
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Maximum estimated cost of an operation
+    max: f64,
+    /// What to do with operations over the maximum cost (default: enforce)
+    #[serde(default)]
+    mode: Mode,
+    /// Size assumed for lists without a configured size or a `@listSize` directive (default: 10)
+    #[serde(default = "default_list_size")]
+    list_size: u32,
+    /// Cost of fields, by coordinate (`Type.field`). Overrides the `@cost` directive
+    #[serde(default)]
+    costs: HashMap<String, f64>,
+    /// Assumed size of list fields, by coordinate (`Type.field`). Overrides the `@listSize`
+    /// directive
+    #[serde(default)]
+    list_sizes: HashMap<String, u32>,
+    /// Send the estimated cost of operations to clients, in the `x-graphql-cost` response header
+    /// (default: false)
+    #[serde(default)]
+    cost_header: bool,
+}
+
+fn default_list_size() -> u32 {
+    10
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    /// Reject operations over the maximum cost
+    Enforce,
+    /// Execute operations over the maximum cost, and log them
+    Measure,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Enforce
+    }
+}
+
+/// How a field of the schema is priced.
+#[derive(Clone, Debug, PartialEq)]
+struct FieldCost {
+    /// Name of the type returned by the field, without list and non null wrappers
+    ty: String,
+    weight: f64,
+    /// Assumed size of the list, if the field returns a list
+    list_size: Option<f64>,
+    /// Arguments of the field setting the size of the list, from `@listSize(slicingArguments:)`
+    slicing_arguments: Vec<String>,
+}
+
+/// The cost of the fields of a schema.
+#[derive(Debug, Default)]
+struct CostModel {
+    root_types: HashMap<OperationKind, String>,
+    object_types: HashSet<String>,
+    /// Fields of the object types and interfaces
+    types: HashMap<String, HashMap<String, FieldCost>>,
+}
+
+impl CostModel {
+    fn new(schema: &str, config: &Config) -> Result<Self, BoxError> {
+        let tree = apollo_parser::Parser::new(schema).parse();
+        if let Some(error) = tree.errors().next() {
+            return Err(format!("could not parse the schema: {}", error.message()).into());
+        }
+        let document = tree.document();
+
+        let mut root_types = HashMap::new();
+        let mut object_types = HashSet::new();
+        let mut composite_types = HashSet::new();
+        for definition in document.definitions() {
+            let name = match &definition {
+                ast::Definition::SchemaDefinition(schema) => {
+                    for operation in schema.root_operation_type_definitions() {
+                        if let (Some(kind), Some(name)) = (
+                            operation.operation_type(),
+                            operation.named_type().and_then(|n| n.name()),
+                        ) {
+                            root_types.insert(kind.into(), name.text().to_string());
+                        }
+                    }
+                    None
+                }
+                ast::Definition::ObjectTypeDefinition(d) => {
+                    if let Some(name) = d.name() {
+                        object_types.insert(name.text().to_string());
+                    }
+                    d.name()
+                }
+                ast::Definition::InterfaceTypeDefinition(d) => d.name(),
+                ast::Definition::UnionTypeDefinition(d) => d.name(),
+                _ => None,
+            };
+            if let Some(name) = name {
+                composite_types.insert(name.text().to_string());
+            }
+        }
+        if root_types.is_empty() {
+            root_types.insert(OperationKind::Query, "Query".to_string());
+            root_types.insert(OperationKind::Mutation, "Mutation".to_string());
+            root_types.insert(OperationKind::Subscription, "Subscription".to_string());
+        }
+        let mutation_type = root_types.get(&OperationKind::Mutation).cloned();
+
+        let mut types: HashMap<String, HashMap<String, FieldCost>> = HashMap::new();
+        for definition in document.definitions() {
+            let (type_name, fields) = match definition {
+                ast::Definition::ObjectTypeDefinition(d) => (d.name(), d.fields_definition()),
+                ast::Definition::ObjectTypeExtension(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeDefinition(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeExtension(d) => (d.name(), d.fields_definition()),
+                _ => continue,
+            };
+            let type_name = match type_name {
+                Some(name) => name.text().to_string(),
+                None => continue,
+            };
+            let default_weight = |ty: &str| {
+                if mutation_type.as_deref() == Some(type_name.as_str()) {
+                    MUTATION_FIELD_WEIGHT
+                } else if composite_types.contains(ty) {
+                    1.0
+                } else {
+                    0.0
+                }
+            };
+
+            let mut type_fields = HashMap::new();
+            for field in fields.iter().flat_map(|fields| fields.field_definitions()) {
+                let (name, (ty, is_list)) = match (field.name(), field.ty().and_then(named_type)) {
+                    (Some(name), Some(ty)) => (name.text().to_string(), ty),
+                    _ => continue,
+                };
+                let coordinate = format!("{}.{}", type_name, name);
+                let weight = config
+                    .costs
+                    .get(&coordinate)
+                    .copied()
+                    .or_else(|| directive_argument(field.directives(), "cost", "weight"))
+                    .unwrap_or_else(|| default_weight(&ty));
+                let configured_size = config
+                    .list_sizes
+                    .get(&coordinate)
+                    .map(|size| f64::from(*size));
+                let slicing_arguments = if is_list && configured_size.is_none() {
+                    directive_strings(field.directives(), "listSize", "slicingArguments")
+                } else {
+                    Vec::new()
+                };
+                let list_size = is_list.then(|| {
+                    configured_size
+                        .or_else(|| {
+                            directive_argument(field.directives(), "listSize", "assumedSize")
+                        })
+                        .unwrap_or_else(|| f64::from(config.list_size))
+                });
+                type_fields.insert(
+                    name,
+                    FieldCost {
+                        ty,
+                        weight,
+                        list_size,
+                        slicing_arguments,
+                    },
+                );
+            }
+            types.entry(type_name).or_default().extend(type_fields);
+        }
+
+        Ok(CostModel {
+            root_types,
+            object_types,
+            types,
+        })
+    }
+
+    fn field(&self, parent_type: &str, field: &ast::Field) -> Option<&FieldCost> {
+        let name = field.name()?;
+        self.types.get(parent_type)?.get(&name.text().to_string())
+    }
+}
+
+/// Returns the named type of a field type, and whether it is a list.
+fn named_type(ty: ast::Type) -> Option<(String, bool)> {
+    match ty {
+        ast::Type::NamedType(named) => Some((named.name()?.text().to_string(), false)),
+        ast::Type::ListType(list) => Some((named_type(list.ty()?)?.0, true)),
+        ast::Type::NonNullType(non_null) => match (non_null.named_type(), non_null.list_type()) {
+            (Some(named), _) => named_type(ast::Type::NamedType(named)),
+            (_, Some(list)) => named_type(ast::Type::ListType(list)),
+            _ => None,
+        },
+    }
+}
+
+/// Returns the value of a directive argument.
+fn directive_value(
+    directives: Option<ast::Directives>,
+    directive: &str,
+    argument: &str,
+) -> Option<ast::Value> {
+    directives
+        .iter()
+        .flat_map(|directives| directives.directives())
+        .filter(|d| d.name().map(|n| n.text() == directive).unwrap_or_default())
+        .flat_map(|d| d.arguments().into_iter().flat_map(|a| a.arguments()))
+        .find(|a| a.name().map(|n| n.text() == argument).unwrap_or_default())
+        .and_then(|a| a.value())
+}
+
+/// Returns the numeric value of a directive argument.
+fn directive_argument(
+    directives: Option<ast::Directives>,
+    directive: &str,
+    argument: &str,
+) -> Option<f64> {
+    match directive_value(directives, directive, argument)? {
+        ast::Value::IntValue(i) => i.to_string().parse().ok(),
+        ast::Value::FloatValue(f) => f.to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns the strings of a directive argument, a single string being a list of one item.
+fn directive_strings(
+    directives: Option<ast::Directives>,
+    directive: &str,
+    argument: &str,
+) -> Vec<String> {
+    match directive_value(directives, directive, argument) {
+        Some(ast::Value::ListValue(list)) => list
+            .values()
+            .filter_map(|value| match value {
+                ast::Value::StringValue(value) => Some(String::from(value)),
+                _ => None,
+            })
+            .collect(),
+        Some(ast::Value::StringValue(value)) => vec![String::from(value)],
+        _ => Vec::new(),
+    }
+}
+
+/// The operation to execute, and the fragments it may use.
+struct Operation {
+    kind: OperationKind,
+    selection_set: Option<ast::SelectionSet>,
+    fragments: HashMap<String, ast::FragmentDefinition>,
+}
+
+impl Operation {
+    /// Returns `None` if the query can't be parsed or the operation is not found: the query
+    /// planner reports those errors.
+    fn parse(query: &str, operation_name: Option<&str>) -> Option<Self> {
+        let tree = apollo_parser::Parser::new(query).parse();
+        if tree.errors().next().is_some() {
+            return None;
+        }
+        let document = tree.document();
+
+        let mut operations = document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::OperationDefinition(operation) => Some(operation),
+                _ => None,
+            });
+        let operation = match operation_name {
+            Some(operation_name) => operations.find(|operation| {
+                operation
+                    .name()
+                    .map(|name| name.text() == operation_name)
+                    .unwrap_or_default()
+            })?,
+            None => operations.next()?,
+        };
+        let fragments = document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::FragmentDefinition(fragment) => {
+                    let name = fragment.fragment_name()?.name()?.text().to_string();
+                    Some((name, fragment))
+                }
+                _ => None,
+            })
+            .collect();
+
+        Some(Operation {
+            kind: operation
+                .operation_type()
+                .map(Into::into)
+                .unwrap_or_default(),
+            selection_set: operation.selection_set(),
+            fragments,
+        })
+    }
+
+    fn fragment_type(&self, name: &str) -> Option<String> {
+        let fragment = self.fragments.get(name)?;
+        let type_condition = fragment.type_condition()?.named_type()?.name()?;
+        Some(type_condition.text().to_string())
+    }
+}
+
+/// Estimates the cost of operations, before they are executed.
+struct Estimator<'a> {
+    model: &'a CostModel,
+    operation: &'a Operation,
+    /// Variables of the request, for the slicing arguments
+    variables: &'a Object,
+    /// Memoized cost of the fragments
+    fragments: HashMap<String, f64>,
+    /// Fragments being estimated, to ignore cycles. Those operations fail validation later
+    in_progress: Vec<String>,
+}
+
+impl<'a> Estimator<'a> {
+    fn estimate(model: &'a CostModel, operation: &'a Operation, variables: &'a Object) -> f64 {
+        let root_type = match model.root_types.get(&operation.kind) {
+            Some(root_type) => root_type,
+            None => return 0.0,
+        };
+        Estimator {
+            model,
+            operation,
+            variables,
+            fragments: HashMap::new(),
+            in_progress: Vec::new(),
+        }
+        .selection_set(root_type, operation.selection_set.clone())
+    }
+
+    fn selection_set(
+        &mut self,
+        parent_type: &str,
+        selection_set: Option<ast::SelectionSet>,
+    ) -> f64 {
+        let model = self.model;
+        let mut cost = 0.0;
+        for selection in selection_set.iter().flat_map(|set| set.selections()) {
+            cost += match selection {
+                ast::Selection::Field(field) => match model.field(parent_type, &field) {
+                    Some(field_cost) => {
+                        let nested = self.selection_set(&field_cost.ty, field.selection_set());
+                        self.list_size(field_cost, &field).unwrap_or(1.0)
+                            * (field_cost.weight + nested)
+                    }
+                    None => 0.0,
+                },
+                ast::Selection::InlineFragment(fragment) => {
+                    let type_condition = fragment
+                        .type_condition()
+                        .and_then(|t| t.named_type())
+                        .and_then(|t| t.name())
+                        .map(|name| name.text().to_string())
+                        .unwrap_or_else(|| parent_type.to_string());
+                    self.selection_set(&type_condition, fragment.selection_set())
+                }
+                ast::Selection::FragmentSpread(spread) => {
+                    match spread.fragment_name().and_then(|name| name.name()) {
+                        Some(name) => self.fragment(name.text().to_string()),
+                        None => 0.0,
+                    }
+                }
+            };
+        }
+        cost
+    }
+
+    /// The size of a list field: the value of its largest slicing argument, or its assumed size.
+    fn list_size(&self, field_cost: &FieldCost, field: &ast::Field) -> Option<f64> {
+        let sliced = field
+            .arguments()
+            .iter()
+            .flat_map(|arguments| arguments.arguments())
+            .filter(|argument| {
+                argument
+                    .name()
+                    .map(|name| {
+                        field_cost
+                            .slicing_arguments
+                            .iter()
+                            .any(|slicing| name.text() == slicing.as_str())
+                    })
+                    .unwrap_or_default()
+            })
+            .filter_map(|argument| match argument.value()? {
+                ast::Value::IntValue(i) => i.to_string().parse::<f64>().ok(),
+                ast::Value::Variable(variable) => self
+                    .variables
+                    .get(variable.name()?.text().as_str())?
+                    .as_f64(),
+                _ => None,
+            })
+            .reduce(f64::max);
+        field_cost
+            .list_size
+            .map(|assumed_size| sliced.unwrap_or(assumed_size))
+    }
+
+    fn fragment(&mut self, name: String) -> f64 {
+        if let Some(cost) = self.fragments.get(&name) {
+            return *cost;
+        }
+        if self.in_progress.contains(&name) {
+            return 0.0;
+        }
+        let operation = self.operation;
+        let (type_condition, selection_set) = match (
+            operation.fragment_type(&name),
+            operation.fragments.get(&name),
+        ) {
+            (Some(type_condition), Some(fragment)) => (type_condition, fragment.selection_set()),
+            _ => return 0.0,
+        };
+
+        self.in_progress.push(name);
+        let cost = self.selection_set(&type_condition, selection_set);
+        let name = self.in_progress.pop().expect("pushed above; qed");
+        self.fragments.insert(name, cost);
+        cost
+    }
+}
+
+/// Measures the actual cost of an operation on its response: lists are priced with their actual
+/// size, and null fields cost nothing.
+struct Measurer<'a> {
+    model: &'a CostModel,
+    operation: &'a Operation,
+}
+
+impl<'a> Measurer<'a> {
+    fn measure(model: &'a CostModel, operation: &'a Operation, data: &Value) -> f64 {
+        match (model.root_types.get(&operation.kind), data.as_object()) {
+            (Some(root_type), Some(data)) => {
+                let mut fields = HashMap::new();
+                let measurer = Measurer { model, operation };
+                measurer.collect_fields(
+                    root_type,
+                    operation.selection_set.clone(),
+                    data,
+                    &mut fields,
+                );
+                measurer.fields(fields, data)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Groups the fields of a selection set by response key, so that a field selected several
+    /// times is only counted once.
+    fn collect_fields(
+        &self,
+        parent_type: &str,
+        selection_set: Option<ast::SelectionSet>,
+        object: &Object,
+        fields: &mut HashMap<String, (FieldCost, Vec<ast::SelectionSet>)>,
+    ) {
+        // Without __typename, we can't tell which fragments apply: all of them are collected
+        let typename = object.get("__typename").and_then(|t| t.as_str());
+        let applies = |type_condition: &str| match typename {
+            Some(typename) => {
+                typename == type_condition || !self.model.object_types.contains(type_condition)
+            }
+            None => true,
+        };
+
+        for selection in selection_set.iter().flat_map(|set| set.selections()) {
+            match selection {
+                ast::Selection::Field(field) => {
+                    let field_cost = match self.model.field(parent_type, &field) {
+                        Some(field_cost) => field_cost,
+                        None => continue,
+                    };
+                    let key = match field
+                        .alias()
+                        .and_then(|a| a.name())
+                        .or_else(|| field.name())
+                    {
+                        Some(key) => key.text().to_string(),
+                        None => continue,
+                    };
+                    let entry = fields
+                        .entry(key)
+                        .or_insert_with(|| (field_cost.clone(), Vec::new()));
+                    entry.1.extend(field.selection_set());
+                }
+                ast::Selection::InlineFragment(fragment) => {
+                    let type_condition = fragment
+                        .type_condition()
+                        .and_then(|t| t.named_type())
+                        .and_then(|t| t.name())
+                        .map(|name| name.text().to_string())
+                        .unwrap_or_else(|| parent_type.to_string());
+                    if applies(&type_condition) {
+                        self.collect_fields(
+                            &type_condition,
+                            fragment.selection_set(),
+                            object,
+                            fields,
+                        );
+                    }
+                }
+                ast::Selection::FragmentSpread(spread) => {
+                    let name = match spread.fragment_name().and_then(|name| name.name()) {
+                        Some(name) => name.text().to_string(),
+                        None => continue,
+                    };
+                    if let (Some(type_condition), Some(fragment)) = (
+                        self.operation.fragment_type(&name),
+                        self.operation.fragments.get(&name),
+                    ) {
+                        if applies(&type_condition) {
+                            self.collect_fields(
+                                &type_condition,
+                                fragment.selection_set(),
+                                object,
+                                fields,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn fields(
+        &self,
+        fields: HashMap<String, (FieldCost, Vec<ast::SelectionSet>)>,
+        object: &Object,
+    ) -> f64 {
+        fields
+            .into_iter()
+            .map(
+                |(key, (field_cost, selection_sets))| match object.get(key.as_str()) {
+                    Some(value) => self.value(&field_cost, &selection_sets, value),
+                    None => 0.0,
+                },
+            )
+            .sum()
+    }
+
+    fn value(
+        &self,
+        field_cost: &FieldCost,
+        selection_sets: &[ast::SelectionSet],
+        value: &Value,
+    ) -> f64 {
+        match value {
+            Value::Null => 0.0,
+            Value::Array(items) => items
+                .iter()
+                .map(|item| self.value(field_cost, selection_sets, item))
+                .sum(),
+            Value::Object(object) => {
+                let mut fields = HashMap::new();
+                for selection_set in selection_sets {
+                    self.collect_fields(
+                        &field_cost.ty,
+                        Some(selection_set.clone()),
+                        object,
+                        &mut fields,
+                    );
+                }
+                field_cost.weight + self.fields(fields, object)
+            }
+            _ => field_cost.weight,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DemandControl {
+    max: f64,
+    mode: Mode,
+    cost_header: bool,
+    model: Arc<CostModel>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for DemandControl {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let model = CostModel::new(&init.supergraph_sdl, &init.config)?;
+        Ok(DemandControl {
+            max: init.config.max,
+            mode: init.config.mode,
+            cost_header: init.config.cost_header,
+            model: Arc::new(model),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let max = self.max;
+        let mode = self.mode;
+        let estimate_model = self.model.clone();
+        let measure_model = self.model.clone();
+        let cost_header = self.cost_header;
+        ServiceBuilder::new()
+            // Rejected operations get the header too, so clients know by how much they went over
+            .map_response(move |mut res: SupergraphResponse| {
+                if cost_header {
+                    if let Ok(Some(estimated)) =
+                        res.context.get::<_, f64>(ESTIMATED_COST_CONTEXT_KEY)
+                    {
+                        if let Ok(value) = HeaderValue::from_str(&estimated.to_string()) {
+                            res.response.headers_mut().insert(COST_HEADER, value);
+                        }
+                    }
+                }
+                res
+            })
+            .checkpoint(move |req: SupergraphRequest| {
+                let body = req.originating_request.body();
+                let estimated = match body
+                    .query
+                    .as_deref()
+                    .and_then(|query| Operation::parse(query, body.operation_name.as_deref()))
+                {
+                    Some(operation) => {
+                        Estimator::estimate(&estimate_model, &operation, &body.variables)
+                    }
+                    None => return Ok(ControlFlow::Continue(req)),
+                };
+                let _ = req.context.insert(ESTIMATED_COST_CONTEXT_KEY, estimated);
+                if estimated <= max {
+                    return Ok(ControlFlow::Continue(req));
+                }
+
+                if mode == Mode::Measure {
+                    tracing::warn!(
+                        operation_name = ?body.operation_name,
+                        estimated_cost = estimated,
+                        max_cost = max,
+                        "executing an operation over the maximum cost"
+                    );
+                    return Ok(ControlFlow::Continue(req));
+                }
+
+                let error = Error::builder()
+                    .message(format!(
+                        "the estimated cost of the operation ({}) is over the maximum cost ({})",
+                        estimated, max
+                    ))
+                    .extension("code", "COST_ESTIMATED_TOO_EXPENSIVE")
+                    .extension("estimated_cost", json!(estimated))
+                    .extension("max_cost", json!(max))
+                    .build();
+                let res = SupergraphResponse::builder()
+                    .error(error)
+                    .status_code(StatusCode::BAD_REQUEST)
+                    .context(req.context)
+                    .build()?;
+                Ok(ControlFlow::Break(res))
+            })
+            .map_future_with_request_data(
+                |req: &SupergraphRequest| {
+                    let body = req.originating_request.body();
+                    (body.query.clone(), body.operation_name.clone())
+                },
+                move |(query, operation_name): (Option<String>, Option<String>), f| {
+                    let model = measure_model.clone();
+                    async move {
+                        let res: supergraph::ServiceResult = f.await;
+                        let mut res = match res {
+                            Ok(res) => res,
+                            Err(err) => return Err(err),
+                        };
+                        // Deferred responses are not measured
+                        let (parts, stream) = res.response.into_parts();
+                        let (first, rest) = stream.into_future().await;
+                        // The parsed operation can't be held across await points
+                        let actual = first
+                            .as_ref()
+                            .and_then(|first| first.data.as_ref())
+                            .zip(query.as_deref().and_then(|query| {
+                                Operation::parse(query, operation_name.as_deref())
+                            }))
+                            .map(|(data, operation)| Measurer::measure(&model, &operation, data));
+                        if let Some(actual) = actual {
+                            let _ = res.context.insert(ACTUAL_COST_CONTEXT_KEY, actual);
+                        }
+                        res.response = http::Response::from_parts(
+                            parts,
+                            once(ready(first.unwrap_or_default())).chain(rest).boxed(),
+                        );
+                        Ok(res)
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "demand_control", DemandControl);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+
+    const SCHEMA: &str = r#"
+        schema { query: Query mutation: Mutation }
+        type Query {
+            me: User
+            topProducts(first: Int, last: Int): [Product]
+                @listSize(assumedSize: 5, slicingArguments: ["first", "last"])
+            search(text: String): [SearchResult]
+        }
+        type Mutation { createReview(body: String): Review }
+        type User { id: ID! name: String reviews: [Review] }
+        type Product { upc: String! name: String reviews: [Review] @cost(weight: 2) }
+        type Review { id: ID! body: String author: User }
+        union SearchResult = User | Product
+    "#;
+
+    const TOP_PRODUCTS: &str = "{ topProducts { upc reviews { id author { id } } } }";
+
+    fn model(config: serde_json::Value) -> CostModel {
+        CostModel::new(SCHEMA, &serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn estimate(model: &CostModel, query: &str) -> f64 {
+        Estimator::estimate(
+            model,
+            &Operation::parse(query, None).unwrap(),
+            &Object::new(),
+        )
+    }
+
+    fn measure(model: &CostModel, query: &str, data: serde_json_bytes::Value) -> f64 {
+        Measurer::measure(model, &Operation::parse(query, None).unwrap(), &data)
+    }
+
+    #[test]
+    fn operations_are_estimated() {
+        let model = model(serde_json::json!({ "max": 100 }));
+        assert_eq!(estimate(&model, "{ me { id name } }"), 1.0);
+        // 5 products, each with 10 reviews costing 2, with an author
+        assert_eq!(estimate(&model, TOP_PRODUCTS), 155.0);
+        assert_eq!(
+            estimate(&model, "mutation { createReview(body: \"great\") { id } }"),
+            10.0
+        );
+        assert_eq!(
+            estimate(
+                &model,
+                "{ search { ... on Product { upc } ...U } } fragment U on User { name }"
+            ),
+            10.0
+        );
+    }
+
+    #[test]
+    fn slicing_arguments_set_list_sizes() {
+        let model = model(serde_json::json!({ "max": 100 }));
+        let query = "query($n: Int) { topProducts(first: 2, last: $n) { upc } }";
+        let operation = Operation::parse(query, None).unwrap();
+        let variables = json!({ "n": 3 }).as_object().unwrap().clone();
+        assert_eq!(Estimator::estimate(&model, &operation, &variables), 3.0);
+        // Without a value for the variable, the other slicing argument applies
+        assert_eq!(Estimator::estimate(&model, &operation, &Object::new()), 2.0);
+        assert_eq!(estimate(&model, "{ topProducts(first: 1) { upc } }"), 1.0);
+    }
+
+    #[test]
+    fn configuration_overrides_directives() {
+        let model = model(serde_json::json!({
+            "max": 100,
+            "costs": { "Product.reviews": 1 },
+            "list_sizes": { "Product.reviews": 2 }
+        }));
+        assert_eq!(estimate(&model, TOP_PRODUCTS), 25.0);
+    }
+
+    #[test]
+    fn responses_are_measured() {
+        let model = model(serde_json::json!({ "max": 100 }));
+        let data = json!({
+            "topProducts": [
+                { "upc": "1", "reviews": [{ "id": "1", "author": { "id": "1" } }, { "id": "2", "author": null }] },
+                { "upc": "2", "reviews": [] }
+            ]
+        });
+        assert_eq!(measure(&model, TOP_PRODUCTS, data), 7.0);
+
+        let query = "{ search { __typename ... on Product { reviews { id } } ... on User { reviews { id } } } }";
+        let data = json!({
+            "search": [
+                { "__typename": "Product", "reviews": [{ "id": "1" }] },
+                { "__typename": "User", "reviews": [{ "id": "2" }, { "id": "3" }] }
+            ]
+        });
+        assert_eq!(measure(&model, query, data), 6.0);
+    }
+
+    async fn plugin() -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.demand_control")
+            .expect("Plugin not found")
+            .create_instance(
+                &serde_json::json!({ "max": 100, "cost_header": true }),
+                Arc::new(SCHEMA.to_string()),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn expensive_operations_are_rejected() {
+        let mut response = plugin()
+            .await
+            .supergraph_service(MockSupergraphService::new().boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query(TOP_PRODUCTS)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.response.headers().get(COST_HEADER).unwrap(), "155");
+        let body = response.next_response().await.unwrap();
+        assert_eq!(
+            body.errors[0].extensions.get("code"),
+            Some(&"COST_ESTIMATED_TOO_EXPENSIVE".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn costs_are_stored_in_the_context() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                Ok(SupergraphResponse::fake_builder()
+                    .data(json!({ "me": { "id": "1", "reviews": [{ "id": "1" }] } }))
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let mut response = plugin()
+            .await
+            .supergraph_service(mock_service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("{ me { id reviews { id } } }")
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.next_response().await.unwrap().data.is_some());
+        assert_eq!(
+            response.context.get(ESTIMATED_COST_CONTEXT_KEY).unwrap(),
+            Some(11.0)
+        );
+        assert_eq!(
+            response.context.get(ACTUAL_COST_CONTEXT_KEY).unwrap(),
+            Some(2.0)
+        );
+    }
+}
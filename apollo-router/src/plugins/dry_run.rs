@@ -0,0 +1,242 @@
+//! Dry runs of operations.
+//!
+//! An operation sent with the `Apollo-Dry-Run: true` header, or with the `apolloDryRun: true`
+//! request extension, is parsed, validated and planned against the current supergraph like any
+//! other, but its plan is not executed: no subgraph is fetched, and the response carries the plan,
+//! its fetch counts and its estimated cost in the `apolloDryRun` extension. Validation and planning
+//! errors are returned as usual, so CI pipelines can check that their operations still plan after
+//! a schema change, and clients can check the cost of an operation before sending it.
+
+use std::ops::ControlFlow;
+
+use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json_bytes::json;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::json_ext::Object;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::demand_control::ESTIMATED_COST_CONTEXT_KEY;
+use crate::register_plugin;
+use crate::services::execution;
+use crate::services::supergraph;
+use crate::ExecutionRequest;
+use crate::ExecutionResponse;
+use crate::SupergraphRequest;
+
+const DRY_RUN_HEADER_NAME: &str = "Apollo-Dry-Run";
+const DRY_RUN_CONTEXT_KEY: &str = "experimental::dry_run.enabled";
+const DRY_RUN_EXTENSION: &str = "apolloDryRun";
+
+/// Configuration for the dry runs of operations
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Plan the operations of the requests with the `Apollo-Dry-Run: true` header or the
+    /// `apolloDryRun: true` extension without executing them (default: false)
+    #[serde(default)]
+    enabled: bool,
+}
+
+#[derive(Debug)]
+struct DryRun {
+    config: Config,
+}
+
+#[async_trait::async_trait]
+impl Plugin for DryRun {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(DryRun {
+            config: init.config,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if !self.config.enabled {
+            return service;
+        }
+        service
+            .map_request(|req: SupergraphRequest| {
+                let header = req.originating_request.headers().get(DRY_RUN_HEADER_NAME);
+                let extension = req
+                    .originating_request
+                    .body()
+                    .extensions
+                    .get(DRY_RUN_EXTENSION);
+                if header == Some(&HeaderValue::from_static("true"))
+                    || extension == Some(&Value::Bool(true))
+                {
+                    req.context.insert(DRY_RUN_CONTEXT_KEY, true).unwrap();
+                }
+                req
+            })
+            .boxed()
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        if !self.config.enabled {
+            return service;
+        }
+        ServiceBuilder::new()
+            .checkpoint(|req: ExecutionRequest| {
+                if req
+                    .context
+                    .get::<_, bool>(DRY_RUN_CONTEXT_KEY)
+                    .ok()
+                    .flatten()
+                    .is_none()
+                {
+                    return Ok(ControlFlow::Continue(req));
+                }
+                let query_plan = &req.query_plan;
+                let mut dry_run = json!({
+                    "fetchCount": query_plan.fetch_count(),
+                    "subgraphFetches": query_plan.subgraph_fetches(),
+                    "sequentialDepth": query_plan.sequential_depth(),
+                    "queryPlan": {
+                        "object": { "kind": "QueryPlan", "node": query_plan.root },
+                        "text": query_plan.formatted_query_plan
+                    }
+                });
+                // Estimated by the demand control plugin, when it is enabled
+                if let Ok(Some(cost)) = req.context.get::<_, f64>(ESTIMATED_COST_CONTEXT_KEY) {
+                    if let Some(dry_run) = dry_run.as_object_mut() {
+                        dry_run.insert("estimatedCost", json!(cost));
+                    }
+                }
+                let mut extensions = Object::new();
+                extensions.insert(DRY_RUN_EXTENSION, dry_run);
+                let res = ExecutionResponse::builder()
+                    .extensions(extensions)
+                    .context(req.context)
+                    .build();
+                Ok(ControlFlow::Break(res))
+            })
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "dry_run", DryRun);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json as sjson;
+
+    use super::*;
+    use crate::plugin::test::MockExecutionService;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+    use crate::query_planner::QueryPlan;
+    use crate::Context;
+    use crate::SupergraphResponse;
+
+    async fn plugin() -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.dry_run")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&sjson!({ "enabled": true }))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dry_runs_are_requested_with_a_header_or_an_extension() {
+        let requests = [
+            (
+                SupergraphRequest::fake_builder()
+                    .header(DRY_RUN_HEADER_NAME, "true")
+                    .build()
+                    .unwrap(),
+                true,
+            ),
+            (
+                SupergraphRequest::fake_builder()
+                    .header(DRY_RUN_HEADER_NAME, "false")
+                    .build()
+                    .unwrap(),
+                false,
+            ),
+            (
+                SupergraphRequest::fake_builder()
+                    .extension(DRY_RUN_EXTENSION, true)
+                    .build()
+                    .unwrap(),
+                true,
+            ),
+        ];
+        for (request, dry_run) in requests {
+            let mut mock_service = MockSupergraphService::new();
+            mock_service
+                .expect_call()
+                .times(1)
+                .returning(move |req: SupergraphRequest| {
+                    assert_eq!(
+                        req.context
+                            .get::<_, bool>(DRY_RUN_CONTEXT_KEY)
+                            .unwrap()
+                            .is_some(),
+                        dry_run
+                    );
+                    SupergraphResponse::fake_builder()
+                        .context(req.context)
+                        .build()
+                });
+            plugin()
+                .await
+                .supergraph_service(mock_service.boxed())
+                .oneshot(request)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_runs_are_not_executed() {
+        let context = Context::new();
+        context.insert(DRY_RUN_CONTEXT_KEY, true).unwrap();
+        context.insert(ESTIMATED_COST_CONTEXT_KEY, 12.0).unwrap();
+        let mut response = plugin()
+            .await
+            .execution_service(MockExecutionService::new().boxed())
+            .oneshot(
+                ExecutionRequest::fake_builder()
+                    .query_plan(
+                        QueryPlan::fake_builder()
+                            .root(
+                                serde_json::from_str(include_str!(
+                                    "../query_planner/testdata/query_plan.json"
+                                ))
+                                .unwrap(),
+                            )
+                            .build(),
+                    )
+                    .context(context)
+                    .build(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.next_response().await.unwrap();
+        let dry_run = body
+            .extensions
+            .get(DRY_RUN_EXTENSION)
+            .and_then(|dry_run| dry_run.as_object())
+            .unwrap();
+        assert_eq!(dry_run.get("fetchCount"), Some(&Value::from(5)));
+        assert_eq!(
+            dry_run.get("subgraphFetches"),
+            Some(&json!({ "books": 2, "product": 3 }))
+        );
+        assert_eq!(dry_run.get("sequentialDepth"), Some(&Value::from(3)));
+        assert_eq!(dry_run.get("estimatedCost"), Some(&Value::from(12.0)));
+    }
+}
@@ -0,0 +1,764 @@
+//! Entity caching for subgraph fetches.
+//!
+//! `_entities` fetches are split per representation: each entity is cached in Redis under a key
+//! derived from the subgraph name, the selection set, the representation and the headers the
+//! response can vary on, and only the entities missing from the cache are requested from the
+//! subgraph. The Redis storage is only compiled with the `redis` feature: without it, the plugin
+//! fails to start.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use http::header::HeaderName;
+use http::header::CACHE_CONTROL;
+use http::StatusCode;
+#[cfg(feature = "redis")]
+use redis::aio::ConnectionManager;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::graphql;
+use crate::json_ext::Object;
+use crate::json_ext::PathElement;
+use crate::json_ext::Value;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::query_planner::OperationKind;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::services::transport;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+const REPRESENTATIONS: &str = "representations";
+const ENTITIES: &str = "_entities";
+const SURROGATE_KEY: &str = "surrogate-key";
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+/// Entities fetched for a user are never shared with other users
+const AUTHORIZATION: &str = "authorization";
+
+/// Configuration for entity caching
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Redis connection
+    redis: RedisConfig,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time to live of cached entities, unless the subgraph response sets `Cache-Control: max-age` (default: 60s)
+    ttl: Option<Duration>,
+    /// Headers of the subgraph requests that are part of the cache key, in addition to
+    /// `authorization`
+    #[serde(default)]
+    vary_headers: Vec<String>,
+    /// Per subgraph configuration
+    #[serde(default)]
+    subgraphs: HashMap<String, SubgraphConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RedisConfig {
+    /// Redis URL, for example `redis://127.0.0.1:6379`
+    url: url::Url,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SubgraphConfig {
+    /// Cache entities fetched from this subgraph (default: true)
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time to live of entities fetched from this subgraph
+    ttl: Option<Duration>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Where cached entities are stored.
+#[async_trait::async_trait]
+trait EntityStorage: Send + Sync {
+    /// Returns the cached entities, in the same order as the keys.
+    async fn get(&self, keys: &[String]) -> Result<Vec<Option<Value>>, BoxError>;
+
+    /// Stores entities, tagging them with the surrogate keys sent by the subgraph.
+    async fn insert(
+        &self,
+        entities: Vec<(String, Value)>,
+        ttl: Duration,
+        surrogate_keys: &[String],
+    ) -> Result<(), BoxError>;
+
+    /// Removes every entity tagged with one of the surrogate keys, and returns how many were removed.
+    async fn invalidate(&self, surrogate_keys: &[String]) -> Result<usize, BoxError>;
+}
+
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+struct RedisStorage {
+    connection: ConnectionManager,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStorage {
+    async fn new(config: &RedisConfig) -> Result<Self, BoxError> {
+        let client = redis::Client::open(config.url.as_str())?;
+        Ok(Self {
+            connection: ConnectionManager::new(client).await?,
+        })
+    }
+}
+
+#[cfg(feature = "redis")]
+async fn redis_storage(config: &RedisConfig) -> Result<Arc<dyn EntityStorage>, BoxError> {
+    Ok(Arc::new(RedisStorage::new(config).await?))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn redis_storage(config: &RedisConfig) -> Result<Arc<dyn EntityStorage>, BoxError> {
+    Err(format!(
+        "cannot cache entities in Redis at {}: the router was built without the `redis` feature",
+        config.url
+    )
+    .into())
+}
+
+#[cfg(feature = "redis")]
+fn surrogate_set(surrogate_key: &str) -> String {
+    format!("entity-surrogate:{}", surrogate_key)
+}
+
+#[cfg(feature = "redis")]
+#[async_trait::async_trait]
+impl EntityStorage for RedisStorage {
+    async fn get(&self, keys: &[String]) -> Result<Vec<Option<Value>>, BoxError> {
+        let mut connection = self.connection.clone();
+        let values: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut connection)
+            .await?;
+        Ok(values
+            .into_iter()
+            .map(|value| value.and_then(|value| serde_json::from_str(&value).ok()))
+            .collect())
+    }
+
+    async fn insert(
+        &self,
+        entities: Vec<(String, Value)>,
+        ttl: Duration,
+        surrogate_keys: &[String],
+    ) -> Result<(), BoxError> {
+        let ttl = ttl.as_secs().max(1);
+        let mut pipeline = redis::pipe();
+        for (key, value) in &entities {
+            pipeline
+                .cmd("SET")
+                .arg(key)
+                .arg(serde_json::to_string(value)?)
+                .arg("EX")
+                .arg(ttl)
+                .ignore();
+        }
+        for surrogate_key in surrogate_keys {
+            let set = surrogate_set(surrogate_key);
+            pipeline.cmd("SADD").arg(&set);
+            for (key, _) in &entities {
+                pipeline.arg(key);
+            }
+            pipeline.ignore().cmd("EXPIRE").arg(&set).arg(ttl).ignore();
+        }
+        let mut connection = self.connection.clone();
+        pipeline.query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn invalidate(&self, surrogate_keys: &[String]) -> Result<usize, BoxError> {
+        let mut connection = self.connection.clone();
+        let mut removed = 0;
+        for surrogate_key in surrogate_keys {
+            let set = surrogate_set(surrogate_key);
+            let keys: Vec<String> = redis::cmd("SMEMBERS")
+                .arg(&set)
+                .query_async(&mut connection)
+                .await?;
+            if !keys.is_empty() {
+                removed += redis::cmd("DEL")
+                    .arg(&keys)
+                    .query_async::<_, usize>(&mut connection)
+                    .await?;
+            }
+            redis::cmd("DEL")
+                .arg(&set)
+                .query_async::<_, ()>(&mut connection)
+                .await?;
+        }
+        Ok(removed)
+    }
+}
+
+struct EntityCache {
+    storage: Arc<dyn EntityStorage>,
+    ttl: Duration,
+    vary_headers: Arc<Vec<HeaderName>>,
+    subgraphs: HashMap<String, SubgraphConfig>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for EntityCache {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let storage = redis_storage(&init.config.redis).await?;
+        Self::with_storage(init.config, storage)
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let subgraph_config = self.subgraphs.get(name);
+        if !subgraph_config.map(|c| c.enabled).unwrap_or(true) {
+            return service;
+        }
+        let ttl = subgraph_config.and_then(|c| c.ttl).unwrap_or(self.ttl);
+        let storage = self.storage.clone();
+        let vary_headers = self.vary_headers.clone();
+        let name = name.to_string();
+        let inner = ServiceBuilder::new().buffered().service(service);
+
+        tower::service_fn(move |request: SubgraphRequest| {
+            cached_fetch(
+                inner.clone(),
+                storage.clone(),
+                name.clone(),
+                ttl,
+                vary_headers.clone(),
+                request,
+            )
+        })
+        .boxed()
+    }
+
+    fn admin_endpoint(&self) -> Option<transport::BoxService> {
+        let storage = self.storage.clone();
+        Some(
+            tower::service_fn(move |request: transport::Request| {
+                invalidation_endpoint(storage.clone(), request)
+            })
+            .boxed(),
+        )
+    }
+}
+
+impl EntityCache {
+    fn with_storage(config: Config, storage: Arc<dyn EntityStorage>) -> Result<Self, BoxError> {
+        let mut vary_headers = vec![HeaderName::from_static(AUTHORIZATION)];
+        for header in &config.vary_headers {
+            let header = HeaderName::try_from(header.as_str())?;
+            if !vary_headers.contains(&header) {
+                vary_headers.push(header);
+            }
+        }
+        Ok(Self {
+            storage,
+            ttl: config.ttl.unwrap_or(DEFAULT_TTL),
+            vary_headers: Arc::new(vary_headers),
+            subgraphs: config.subgraphs,
+        })
+    }
+}
+
+/// Handles `POST /plugins/experimental.entity_cache/invalidate` on the admin listener, with a
+/// body like `{"surrogate_keys": ["product:1"]}`.
+fn invalidation_endpoint(
+    storage: Arc<dyn EntityStorage>,
+    request: transport::Request,
+) -> BoxFuture<'static, Result<transport::Response, BoxError>> {
+    #[derive(Deserialize)]
+    struct Invalidation {
+        surrogate_keys: Vec<String>,
+    }
+
+    Box::pin(async move {
+        if request.method() != http::Method::POST || !request.uri().path().ends_with("/invalidate")
+        {
+            return Ok(http::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(hyper::Body::empty())?);
+        }
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let invalidation: Invalidation = match serde_json::from_slice(&body) {
+            Ok(invalidation) => invalidation,
+            Err(error) => {
+                return Ok(http::Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(error.to_string().into())?)
+            }
+        };
+        let invalidated = storage.invalidate(&invalidation.surrogate_keys).await?;
+        Ok(http::Response::builder()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(
+                serde_json::json!({ "invalidated": invalidated })
+                    .to_string()
+                    .into(),
+            )?)
+    })
+}
+
+async fn cached_fetch<S>(
+    inner: S,
+    storage: Arc<dyn EntityStorage>,
+    name: String,
+    ttl: Duration,
+    vary_headers: Arc<Vec<HeaderName>>,
+    mut request: SubgraphRequest,
+) -> Result<SubgraphResponse, BoxError>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>,
+{
+    let body = request.subgraph_request.body();
+    let headers = request.subgraph_request.headers();
+    let representations = match (body.query.as_deref(), body.variables.get(REPRESENTATIONS)) {
+        (Some(query), Some(Value::Array(representations)))
+            if query.contains(ENTITIES) && request.operation_kind == OperationKind::Query =>
+        {
+            representations.clone()
+        }
+        _ => return inner.oneshot(request).await,
+    };
+
+    let keys: Vec<String> = representations
+        .iter()
+        .map(|representation| cache_key(&name, body, headers, &vary_headers, representation))
+        .collect();
+    let mut entities = match storage.get(&keys).await {
+        Ok(entities) => entities,
+        Err(error) => {
+            tracing::error!(subgraph = %name, "cannot read from the entity cache: {}", error);
+            return inner.oneshot(request).await;
+        }
+    };
+    let missing: Vec<usize> = entities
+        .iter()
+        .enumerate()
+        .filter_map(|(index, entity)| entity.is_none().then(|| index))
+        .collect();
+
+    if missing.is_empty() {
+        tracing::trace!(subgraph = %name, "entity cache hit");
+        let mut data = Object::new();
+        data.insert(
+            ENTITIES,
+            Value::Array(entities.into_iter().flatten().collect()),
+        );
+        return Ok(SubgraphResponse::builder()
+            .data(Value::Object(data))
+            .extensions(Object::new())
+            .context(request.context)
+            .build());
+    }
+
+    request.subgraph_request.body_mut().variables.insert(
+        REPRESENTATIONS,
+        Value::Array(
+            missing
+                .iter()
+                .map(|index| representations[*index].clone())
+                .collect(),
+        ),
+    );
+    let mut response = inner.oneshot(request).await?;
+
+    let ttl = match cache_control_ttl(response.response.headers()) {
+        CacheControl::NoStore => None,
+        CacheControl::MaxAge(max_age) => Some(max_age),
+        CacheControl::Default => Some(ttl),
+    };
+    let surrogate_keys: Vec<String> = response
+        .response
+        .headers()
+        .get_all(SURROGATE_KEY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split_whitespace().map(str::to_string))
+        .collect();
+
+    let body = response.response.body_mut();
+    // errors are reported on the position of the entity in the request we sent,
+    // they have to point to its position in the original request
+    let mut failed = Vec::new();
+    for error in body.errors.iter_mut() {
+        if let Some(path) = error.path.as_mut() {
+            if let [PathElement::Key(key), PathElement::Index(index), ..] = path.0.as_mut_slice() {
+                if key.as_str() == ENTITIES {
+                    if let Some(original) = missing.get(*index) {
+                        failed.push(*original);
+                        *index = *original;
+                    }
+                }
+            }
+        }
+    }
+
+    let fetched = match body
+        .data
+        .as_mut()
+        .and_then(|data| data.as_object_mut())
+        .and_then(|data| data.get_mut(ENTITIES))
+    {
+        Some(Value::Array(fetched)) => std::mem::take(fetched),
+        // the subgraph did not return entities, there is nothing to merge or cache
+        _ => return Ok(response),
+    };
+
+    let mut to_cache = Vec::new();
+    for (position, entity) in fetched.into_iter().enumerate() {
+        if let Some(index) = missing.get(position) {
+            if !entity.is_null() && !failed.contains(index) {
+                to_cache.push((keys[*index].clone(), entity.clone()));
+            }
+            entities[*index] = Some(entity);
+        }
+    }
+    if let Some(Value::Object(data)) = body.data.as_mut() {
+        data.insert(
+            ENTITIES,
+            Value::Array(
+                entities
+                    .into_iter()
+                    .map(|entity| entity.unwrap_or_default())
+                    .collect(),
+            ),
+        );
+    }
+
+    if let Some(ttl) = ttl {
+        if !to_cache.is_empty() {
+            if let Err(error) = storage.insert(to_cache, ttl, &surrogate_keys).await {
+                tracing::error!(subgraph = %name, "cannot write to the entity cache: {}", error);
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+/// The cache key covers the subgraph, the selection set and the other variables, so that the same
+/// entity fetched with different selections is cached separately, and the vary headers, so that
+/// entities fetched on behalf of a user are not served to others.
+fn cache_key(
+    name: &str,
+    body: &graphql::Request,
+    headers: &http::HeaderMap,
+    vary_headers: &[HeaderName],
+    representation: &Value,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update(body.query.as_deref().unwrap_or_default().as_bytes());
+    for (key, value) in body.variables.iter() {
+        if key.as_str() != REPRESENTATIONS {
+            hasher.update(key.as_str().as_bytes());
+            hasher.update(value.to_string().as_bytes());
+        }
+    }
+    for header in vary_headers {
+        for value in headers.get_all(header) {
+            hasher.update(header.as_str().as_bytes());
+            hasher.update(value.as_bytes());
+        }
+    }
+    hasher.update(representation.to_string().as_bytes());
+    format!("entity:{}:{}", name, hex::encode(hasher.finalize()))
+}
+
+#[derive(Debug, PartialEq)]
+enum CacheControl {
+    Default,
+    NoStore,
+    MaxAge(Duration),
+}
+
+fn cache_control_ttl(headers: &http::HeaderMap) -> CacheControl {
+    let mut result = CacheControl::Default;
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+    {
+        match directive {
+            "no-store" | "no-cache" | "private" => return CacheControl::NoStore,
+            _ => {
+                if let Some(max_age) = directive
+                    .strip_prefix("max-age=")
+                    .and_then(|max_age| max_age.parse::<u64>().ok())
+                {
+                    if max_age == 0 {
+                        return CacheControl::NoStore;
+                    }
+                    result = CacheControl::MaxAge(Duration::from_secs(max_age));
+                }
+            }
+        }
+    }
+    result
+}
+
+register_plugin!("experimental", "entity_cache", EntityCache);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        entries: Mutex<HashMap<String, Value>>,
+        surrogates: Mutex<HashMap<String, Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EntityStorage for MemoryStorage {
+        async fn get(&self, keys: &[String]) -> Result<Vec<Option<Value>>, BoxError> {
+            let entries = self.entries.lock().unwrap();
+            Ok(keys.iter().map(|key| entries.get(key).cloned()).collect())
+        }
+
+        async fn insert(
+            &self,
+            entities: Vec<(String, Value)>,
+            _ttl: Duration,
+            surrogate_keys: &[String],
+        ) -> Result<(), BoxError> {
+            let mut surrogates = self.surrogates.lock().unwrap();
+            for surrogate_key in surrogate_keys {
+                surrogates
+                    .entry(surrogate_key.clone())
+                    .or_default()
+                    .extend(entities.iter().map(|(key, _)| key.clone()));
+            }
+            self.entries.lock().unwrap().extend(entities);
+            Ok(())
+        }
+
+        async fn invalidate(&self, surrogate_keys: &[String]) -> Result<usize, BoxError> {
+            let mut entries = self.entries.lock().unwrap();
+            let mut surrogates = self.surrogates.lock().unwrap();
+            Ok(surrogate_keys
+                .iter()
+                .flat_map(|surrogate_key| surrogates.remove(surrogate_key).unwrap_or_default())
+                .filter(|key| entries.remove(key).is_some())
+                .count())
+        }
+    }
+
+    const QUERY: &str = "query($representations:[_Any!]!){_entities(representations:$representations){...on Product{name}}}";
+
+    fn entities_request(upcs: &[&str]) -> SubgraphRequest {
+        let representations: Vec<Value> = upcs
+            .iter()
+            .map(|upc| json!({ "__typename": "Product", "upc": upc }))
+            .collect();
+        SubgraphRequest::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .body(
+                        graphql::Request::builder()
+                            .query(QUERY)
+                            .variable(REPRESENTATIONS, Value::Array(representations))
+                            .build(),
+                    )
+                    .unwrap(),
+            )
+            .build()
+    }
+
+    fn entities_response(req: &SubgraphRequest) -> SubgraphResponse {
+        let entities: Vec<Value> = req.subgraph_request.body().variables[REPRESENTATIONS]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|representation| {
+                json!({ "name": format!("product {}", representation["upc"].as_str().unwrap()) })
+            })
+            .collect();
+        SubgraphResponse::fake_builder()
+            .data(json!({ "_entities": entities }))
+            .build()
+    }
+
+    fn upcs(req: &SubgraphRequest) -> Vec<String> {
+        req.subgraph_request.body().variables[REPRESENTATIONS]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|representation| representation["upc"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn plugin(storage: Arc<MemoryStorage>) -> EntityCache {
+        let config = serde_json::from_value(serde_json::json!({
+            "redis": { "url": "redis://127.0.0.1:6379" }
+        }))
+        .unwrap();
+        EntityCache::with_storage(config, storage).unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_missing_entities_are_fetched() {
+        let storage = Arc::new(MemoryStorage::default());
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .withf(|req| upcs(req) == ["1", "2"])
+            .returning(|req| Ok(entities_response(&req)));
+        let response = plugin(storage.clone())
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(entities_request(&["1", "2"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(json!({ "_entities": [{ "name": "product 1" }, { "name": "product 2" }] }))
+        );
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .withf(|req| upcs(req) == ["3"])
+            .returning(|req| Ok(entities_response(&req)));
+        let response = plugin(storage.clone())
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(entities_request(&["2", "3", "1"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(json!({ "_entities": [
+                { "name": "product 2" },
+                { "name": "product 3" },
+                { "name": "product 1" }
+            ] }))
+        );
+
+        // everything is cached now, the subgraph is not called
+        let response = plugin(storage)
+            .subgraph_service("products", MockSubgraphService::new().boxed())
+            .oneshot(entities_request(&["3"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(json!({ "_entities": [{ "name": "product 3" }] }))
+        );
+    }
+
+    #[tokio::test]
+    async fn entities_are_cached_per_authorization() {
+        let storage = Arc::new(MemoryStorage::default());
+        let request = |authorization: &str| {
+            let mut request = entities_request(&["1"]);
+            request
+                .subgraph_request
+                .headers_mut()
+                .insert(AUTHORIZATION, authorization.parse().unwrap());
+            request
+        };
+
+        let fetching_service = || {
+            let mut mock_service = MockSubgraphService::new();
+            mock_service
+                .expect_call()
+                .times(1)
+                .returning(|req| Ok(entities_response(&req)));
+            mock_service.boxed()
+        };
+        plugin(storage.clone())
+            .subgraph_service("products", fetching_service())
+            .oneshot(request("Bearer alice"))
+            .await
+            .unwrap();
+        // the same user gets the cached entity
+        plugin(storage.clone())
+            .subgraph_service("products", MockSubgraphService::new().boxed())
+            .oneshot(request("Bearer alice"))
+            .await
+            .unwrap();
+        // another user doesn't
+        plugin(storage.clone())
+            .subgraph_service("products", fetching_service())
+            .oneshot(request("Bearer bob"))
+            .await
+            .unwrap();
+        assert_eq!(storage.entries.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn surrogate_keys_invalidate_entities() {
+        let storage = Arc::new(MemoryStorage::default());
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service.expect_call().times(1).returning(|req| {
+            let mut response = entities_response(&req);
+            response
+                .response
+                .headers_mut()
+                .insert(SURROGATE_KEY, "products product:1".parse().unwrap());
+            Ok(response)
+        });
+        plugin(storage.clone())
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(entities_request(&["1"]))
+            .await
+            .unwrap();
+        assert_eq!(storage.entries.lock().unwrap().len(), 1);
+
+        let response = plugin(storage.clone())
+            .admin_endpoint()
+            .unwrap()
+            .oneshot(
+                http::Request::post(
+                    "http://localhost/plugins/experimental.entity_cache/invalidate",
+                )
+                .body(r#"{"surrogate_keys": ["product:1"]}"#.into())
+                .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(storage.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cache_control() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(cache_control_ttl(&headers), CacheControl::Default);
+        headers.insert(CACHE_CONTROL, "public, max-age=120".parse().unwrap());
+        assert_eq!(
+            cache_control_ttl(&headers),
+            CacheControl::MaxAge(Duration::from_secs(120))
+        );
+        headers.insert(CACHE_CONTROL, "private, max-age=120".parse().unwrap());
+        assert_eq!(cache_control_ttl(&headers), CacheControl::NoStore);
+    }
+}
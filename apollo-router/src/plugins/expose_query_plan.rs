@@ -2,6 +2,8 @@ use futures::future::ready;
 use futures::stream::once;
 use futures::StreamExt;
 use http::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
 use serde_json_bytes::json;
 use tower::BoxError;
 use tower::ServiceExt as TowerServiceExt;
@@ -19,19 +21,50 @@ const QUERY_PLAN_CONTEXT_KEY: &str = "experimental::expose_query_plan.plan";
 const FORMATTED_QUERY_PLAN_CONTEXT_KEY: &str = "experimental::expose_query_plan.formatted_plan";
 const ENABLED_CONTEXT_KEY: &str = "experimental::expose_query_plan.enabled";
 
+/// Exposure of the executed query plans in the extensions of responses
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum Config {
+    /// `true` to expose the plans of the requests with the `Apollo-Expose-Query-Plan: true` header
+    Enabled(bool),
+    /// When plans are exposed
+    Mode(Mode),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    /// The plans of the requests with the `Apollo-Expose-Query-Plan: true` header
+    OnHeader,
+    /// The plans of all the requests, for debugging a router without changing its clients
+    Always,
+    /// No plans
+    Never,
+}
+
 #[derive(Debug, Clone)]
 struct ExposeQueryPlan {
-    enabled: bool,
+    mode: Mode,
 }
 
 #[async_trait::async_trait]
 impl Plugin for ExposeQueryPlan {
-    type Config = bool;
+    type Config = Config;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let mode = match init.config {
+            Config::Enabled(true) => Mode::OnHeader,
+            Config::Enabled(false) => Mode::Never,
+            Config::Mode(mode) => mode,
+        };
         Ok(ExposeQueryPlan {
-            enabled: init.config
-                || std::env::var(ENABLE_EXPOSE_QUERY_PLAN_ENV).as_deref() == Ok("true"),
+            mode: if mode == Mode::Never
+                && std::env::var(ENABLE_EXPOSE_QUERY_PLAN_ENV).as_deref() == Ok("true")
+            {
+                Mode::OnHeader
+            } else {
+                mode
+            },
         })
     }
 
@@ -62,10 +95,14 @@ impl Plugin for ExposeQueryPlan {
     }
 
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
-        let conf_enabled = self.enabled;
+        let mode = self.mode;
         service
             .map_future_with_request_data(move |req: &supergraph::Request| {
-                let is_enabled = conf_enabled && req.originating_request.headers().get(EXPOSE_QUERY_PLAN_HEADER_NAME) == Some(&HeaderValue::from_static("true"));
+                let is_enabled = match mode {
+                    Mode::OnHeader => req.originating_request.headers().get(EXPOSE_QUERY_PLAN_HEADER_NAME) == Some(&HeaderValue::from_static("true")),
+                    Mode::Always => true,
+                    Mode::Never => false,
+                };
                 if is_enabled {
                     req.context.insert(ENABLED_CONTEXT_KEY, true).unwrap();
                 }
@@ -203,11 +240,12 @@ mod tests {
         query: &str,
         body: &Response,
         mut supergraph_service: supergraph::BoxCloneService,
+        header_value: &'static str,
     ) {
         let request = supergraph::Request::fake_builder()
             .query(query.to_string())
             .variable("first", 2usize)
-            .header(EXPOSE_QUERY_PLAN_HEADER_NAME, "true")
+            .header(EXPOSE_QUERY_PLAN_HEADER_NAME, header_value)
             .build()
             .expect("expecting valid request");
 
@@ -233,10 +271,38 @@ mod tests {
             VALID_QUERY,
             &*EXPECTED_RESPONSE_WITH_QUERY_PLAN,
             supergraph.clone(),
+            "true",
         )
         .await;
         // let's try that again
-        execute_supergraph_test(VALID_QUERY, &*EXPECTED_RESPONSE_WITH_QUERY_PLAN, supergraph).await;
+        execute_supergraph_test(
+            VALID_QUERY,
+            &*EXPECTED_RESPONSE_WITH_QUERY_PLAN,
+            supergraph.clone(),
+            "true",
+        )
+        .await;
+        // the plan is only exposed when asked for
+        execute_supergraph_test(
+            VALID_QUERY,
+            &*EXPECTED_RESPONSE_WITHOUT_QUERY_PLAN,
+            supergraph,
+            "false",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn it_always_expose_query_plan() {
+        let plugin = get_plugin(&serde_json::json!("always")).await;
+        let supergraph = build_mock_supergraph(plugin).await;
+        execute_supergraph_test(
+            VALID_QUERY,
+            &*EXPECTED_RESPONSE_WITH_QUERY_PLAN,
+            supergraph,
+            "false",
+        )
+        .await;
     }
 
     #[tokio::test]
@@ -247,6 +313,7 @@ mod tests {
             VALID_QUERY,
             &*EXPECTED_RESPONSE_WITHOUT_QUERY_PLAN,
             supergraph,
+            "true",
         )
         .await;
     }
@@ -0,0 +1,896 @@
+//! External coprocessor support.
+//!
+//! Sends request and response data to an external HTTP service at the router, supergraph and
+//! subgraph stages, then applies the headers, body and context changes it sends back. The router
+//! stage is the outermost one: it gets the raw HTTP request, before its body is parsed, and the
+//! HTTP response sent to the client.
+
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::future::ready;
+use futures::stream::once;
+use futures::StreamExt;
+use http::header::HeaderName;
+use http::header::CONTENT_LENGTH;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::StatusCode;
+use http::Uri;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json_bytes::ByteString;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::graphql;
+use crate::json_ext::Object;
+use crate::json_ext::Value;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::router;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const EXTERNAL_PROTOCOL_VERSION: u8 = 1;
+
+/// What is sent to the coprocessor
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+struct PartsConf {
+    /// Send the headers
+    headers: bool,
+    /// Send the body
+    body: bool,
+    /// Send the context
+    context: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+struct StageConf {
+    /// Call the coprocessor with the request
+    request: Option<PartsConf>,
+    /// Call the coprocessor with the response
+    response: Option<PartsConf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, default)]
+struct Stages {
+    /// Router stage, with the raw HTTP request and response. Sending the body reads it whole,
+    /// including the deferred responses of a multipart response
+    router: StageConf,
+    /// Supergraph stage
+    supergraph: StageConf,
+    /// Subgraph stage, applied to every subgraph
+    subgraph: StageConf,
+}
+
+/// Configuration for the external coprocessor
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Conf {
+    /// The url of the coprocessor
+    url: url::Url,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Timeout for each call to the coprocessor (default: 1s)
+    timeout: Option<Duration>,
+    /// The stages where the coprocessor is called
+    #[serde(default)]
+    stages: Stages,
+}
+
+/// The point of the pipeline a payload comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Stage {
+    RouterRequest,
+    RouterResponse,
+    SupergraphRequest,
+    SupergraphResponse,
+    SubgraphRequest,
+    SubgraphResponse,
+}
+
+/// Tells the router what to do after the coprocessor has handled a request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Control {
+    /// Keep processing the request
+    Continue,
+    /// Stop processing and answer with the given status code
+    Break(u16),
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Control::Continue
+    }
+}
+
+/// The payload exchanged with the coprocessor.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Externalizable {
+    version: u8,
+    stage: Stage,
+    #[serde(default)]
+    control: Control,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    service_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    headers: Option<HashMap<String, Vec<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context: Option<Object>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+    /// Method of the HTTP request, at the router stage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    /// URI of the HTTP request at the router stage, which the coprocessor can rewrite
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+}
+
+impl Externalizable {
+    fn new(stage: Stage, service_name: Option<String>) -> Self {
+        Self {
+            version: EXTERNAL_PROTOCOL_VERSION,
+            stage,
+            control: Control::Continue,
+            service_name,
+            headers: None,
+            body: None,
+            context: None,
+            status_code: None,
+            method: None,
+            uri: None,
+        }
+    }
+
+    fn with_parts<T: Serialize>(
+        mut self,
+        conf: &PartsConf,
+        headers: &HeaderMap,
+        body: &T,
+        context: &Context,
+    ) -> Result<Self, BoxError> {
+        if conf.headers {
+            self.headers = Some(externalize_headers(headers));
+        }
+        if conf.body {
+            self.body = Some(serde_json_bytes::to_value(body)?);
+        }
+        if conf.context {
+            self.context = Some(externalize_context(context));
+        }
+        Ok(self)
+    }
+
+    /// Applies the headers, body and context returned by the coprocessor.
+    fn apply<T: for<'de> Deserialize<'de>>(
+        &mut self,
+        headers: &mut HeaderMap,
+        body: &mut T,
+        context: &Context,
+    ) -> Result<(), BoxError> {
+        if let Some(returned) = self.headers.take() {
+            *headers = internalize_headers(returned)?;
+        }
+        if let Some(returned) = self.body.take() {
+            *body = serde_json_bytes::from_value(returned)?;
+        }
+        if let Some(returned) = self.context.take() {
+            // The returned context replaces the router's: the keys it doesn't have are removed
+            context.retain(|key, _| returned.contains_key(key.as_str()));
+            for (key, value) in returned {
+                context.insert_json_value(key.as_str(), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn externalize_headers(headers: &HeaderMap) -> HashMap<String, Vec<String>> {
+    let mut externalized: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in headers {
+        // Headers which are not valid UTF-8 cannot be represented in JSON
+        if let Ok(value) = value.to_str() {
+            externalized
+                .entry(name.as_str().to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+    }
+    externalized
+}
+
+fn internalize_headers(headers: HashMap<String, Vec<String>>) -> Result<HeaderMap, BoxError> {
+    let mut internalized = HeaderMap::new();
+    for (name, values) in headers {
+        let name = HeaderName::from_str(&name)?;
+        for value in values {
+            internalized.append(name.clone(), HeaderValue::from_str(&value)?);
+        }
+    }
+    Ok(internalized)
+}
+
+/// Raw bodies are sent as strings. The coprocessor can send back a string, or any JSON value,
+/// which is serialized.
+fn externalize_body(body: &[u8]) -> Result<Value, BoxError> {
+    Ok(Value::String(std::str::from_utf8(body)?.into()))
+}
+
+fn internalize_body(body: Value, headers: &mut HeaderMap) -> Result<hyper::Body, BoxError> {
+    let body = match body {
+        Value::String(body) => body.as_str().as_bytes().to_vec(),
+        body => serde_json::to_vec(&body)?,
+    };
+    if headers.contains_key(CONTENT_LENGTH) {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    Ok(body.into())
+}
+
+fn externalize_context(context: &Context) -> Object {
+    context
+        .iter()
+        .map(|entry| {
+            (
+                ByteString::from(entry.key().as_str()),
+                entry.value().clone(),
+            )
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+struct Coprocessor {
+    client: reqwest::Client,
+    url: url::Url,
+    timeout: Duration,
+}
+
+impl Coprocessor {
+    async fn call(&self, payload: Externalizable) -> Result<Externalizable, BoxError> {
+        tracing::debug!(stage = ?payload.stage, "calling the external coprocessor");
+        let returned: Externalizable = self
+            .client
+            .post(self.url.clone())
+            .timeout(self.timeout)
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if returned.version != EXTERNAL_PROTOCOL_VERSION {
+            return Err(format!(
+                "unsupported external protocol version {}, expected {}",
+                returned.version, EXTERNAL_PROTOCOL_VERSION
+            )
+            .into());
+        }
+        Ok(returned)
+    }
+
+    async fn router_request(
+        &self,
+        conf: &PartsConf,
+        request: router::Request,
+    ) -> Result<ControlFlow<router::Response, router::Request>, BoxError> {
+        let router::Request {
+            router_request,
+            context,
+        } = request;
+        let (mut parts, body) = router_request.into_parts();
+        // the body is only read if it is sent
+        let (mut body, unread) = if conf.body {
+            let bytes = hyper::body::to_bytes(body).await?;
+            (externalize_body(&bytes)?, None)
+        } else {
+            (Value::Null, Some(body))
+        };
+        let mut payload = Externalizable::new(Stage::RouterRequest, None).with_parts(
+            conf,
+            &parts.headers,
+            &body,
+            &context,
+        )?;
+        payload.method = Some(parts.method.to_string());
+        payload.uri = Some(parts.uri.to_string());
+        let mut returned = self.call(payload).await?;
+
+        if let Control::Break(code) = returned.control {
+            let mut headers = HeaderMap::new();
+            let mut body = Value::Null;
+            returned.apply(&mut headers, &mut body, &context)?;
+            let body = match body {
+                Value::Null => hyper::Body::empty(),
+                body => internalize_body(body, &mut headers)?,
+            };
+            let mut response = http::Response::builder()
+                .status(StatusCode::from_u16(code)?)
+                .body(body)?;
+            *response.headers_mut() = headers;
+            return Ok(ControlFlow::Break(router::Response { response, context }));
+        }
+
+        if let Some(uri) = returned.uri.take() {
+            parts.uri = Uri::from_str(&uri)?;
+        }
+        returned.apply(&mut parts.headers, &mut body, &context)?;
+        let body = match unread {
+            Some(body) => body,
+            None => internalize_body(body, &mut parts.headers)?,
+        };
+        Ok(ControlFlow::Continue(router::Request {
+            router_request: http::Request::from_parts(parts, body),
+            context,
+        }))
+    }
+
+    async fn router_response(
+        &self,
+        conf: &PartsConf,
+        response: router::Response,
+    ) -> Result<router::Response, BoxError> {
+        let router::Response { response, context } = response;
+        let (mut parts, body) = response.into_parts();
+        let (mut body, unread) = if conf.body {
+            let bytes = hyper::body::to_bytes(body).await?;
+            (externalize_body(&bytes)?, None)
+        } else {
+            (Value::Null, Some(body))
+        };
+
+        let mut payload = Externalizable::new(Stage::RouterResponse, None).with_parts(
+            conf,
+            &parts.headers,
+            &body,
+            &context,
+        )?;
+        payload.status_code = Some(parts.status.as_u16());
+        let mut returned = self.call(payload).await?;
+        if let Some(code) = returned.status_code {
+            parts.status = StatusCode::from_u16(code)?;
+        }
+        returned.apply(&mut parts.headers, &mut body, &context)?;
+        let body = match unread {
+            Some(body) => body,
+            None => internalize_body(body, &mut parts.headers)?,
+        };
+
+        Ok(router::Response {
+            response: http::Response::from_parts(parts, body),
+            context,
+        })
+    }
+
+    async fn supergraph_request(
+        &self,
+        conf: &PartsConf,
+        mut request: supergraph::Request,
+    ) -> Result<ControlFlow<supergraph::Response, supergraph::Request>, BoxError> {
+        let payload = Externalizable::new(Stage::SupergraphRequest, None).with_parts(
+            conf,
+            request.originating_request.headers(),
+            request.originating_request.body(),
+            &request.context,
+        )?;
+        let mut returned = self.call(payload).await?;
+
+        if let Control::Break(code) = returned.control {
+            let mut headers = HeaderMap::new();
+            let mut body = graphql::Response::default();
+            returned.apply(&mut headers, &mut body, &request.context)?;
+            let mut response = http::Response::builder()
+                .status(StatusCode::from_u16(code)?)
+                .body(once(ready(body)).boxed())?;
+            *response.headers_mut() = headers;
+            return Ok(ControlFlow::Break(supergraph::Response::new_from_response(
+                response,
+                request.context,
+            )));
+        }
+
+        let (mut parts, mut body) = request.originating_request.into_parts();
+        returned.apply(&mut parts.headers, &mut body, &request.context)?;
+        request.originating_request = http::Request::from_parts(parts, body);
+        Ok(ControlFlow::Continue(request))
+    }
+
+    /// Only the primary response is sent to the coprocessor, deferred responses are
+    /// passed through untouched.
+    async fn supergraph_response(
+        &self,
+        conf: &PartsConf,
+        response: supergraph::Response,
+    ) -> Result<supergraph::Response, BoxError> {
+        let supergraph::Response { response, context } = response;
+        let (mut parts, mut stream) = response.into_parts();
+        let mut first = match stream.next().await {
+            Some(first) => first,
+            None => {
+                return Ok(supergraph::Response::new_from_response(
+                    http::Response::from_parts(parts, stream),
+                    context,
+                ))
+            }
+        };
+
+        let mut payload = Externalizable::new(Stage::SupergraphResponse, None).with_parts(
+            conf,
+            &parts.headers,
+            &first,
+            &context,
+        )?;
+        payload.status_code = Some(parts.status.as_u16());
+        let mut returned = self.call(payload).await?;
+        if let Some(code) = returned.status_code {
+            parts.status = StatusCode::from_u16(code)?;
+        }
+        returned.apply(&mut parts.headers, &mut first, &context)?;
+
+        Ok(supergraph::Response::new_from_response(
+            http::Response::from_parts(parts, once(ready(first)).chain(stream).boxed()),
+            context,
+        ))
+    }
+
+    async fn subgraph_request(
+        &self,
+        conf: &PartsConf,
+        service_name: String,
+        mut request: subgraph::Request,
+    ) -> Result<ControlFlow<subgraph::Response, subgraph::Request>, BoxError> {
+        let payload = Externalizable::new(Stage::SubgraphRequest, Some(service_name)).with_parts(
+            conf,
+            request.subgraph_request.headers(),
+            request.subgraph_request.body(),
+            &request.context,
+        )?;
+        let mut returned = self.call(payload).await?;
+
+        if let Control::Break(code) = returned.control {
+            let mut headers = HeaderMap::new();
+            let mut body = graphql::Response::default();
+            returned.apply(&mut headers, &mut body, &request.context)?;
+            let mut response = http::Response::builder()
+                .status(StatusCode::from_u16(code)?)
+                .body(body)?;
+            *response.headers_mut() = headers;
+            return Ok(ControlFlow::Break(subgraph::Response::new_from_response(
+                response,
+                request.context,
+            )));
+        }
+
+        let (mut parts, mut body) = request.subgraph_request.into_parts();
+        returned.apply(&mut parts.headers, &mut body, &request.context)?;
+        request.subgraph_request = http::Request::from_parts(parts, body);
+        Ok(ControlFlow::Continue(request))
+    }
+
+    async fn subgraph_response(
+        &self,
+        conf: &PartsConf,
+        service_name: String,
+        response: subgraph::Response,
+    ) -> Result<subgraph::Response, BoxError> {
+        let subgraph::Response { response, context } = response;
+        let (mut parts, mut body) = response.into_parts();
+
+        let mut payload = Externalizable::new(Stage::SubgraphResponse, Some(service_name))
+            .with_parts(conf, &parts.headers, &body, &context)?;
+        payload.status_code = Some(parts.status.as_u16());
+        let mut returned = self.call(payload).await?;
+        if let Some(code) = returned.status_code {
+            parts.status = StatusCode::from_u16(code)?;
+        }
+        returned.apply(&mut parts.headers, &mut body, &context)?;
+
+        Ok(subgraph::Response::new_from_response(
+            http::Response::from_parts(parts, body),
+            context,
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct External {
+    coprocessor: Coprocessor,
+    stages: Stages,
+}
+
+#[async_trait::async_trait]
+impl Plugin for External {
+    type Config = Conf;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(External {
+            coprocessor: Coprocessor {
+                client: reqwest::Client::new(),
+                url: init.config.url,
+                timeout: init.config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            },
+            stages: init.config.stages,
+        })
+    }
+
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        let StageConf { request, response } = self.stages.router.clone();
+        if request.is_none() && response.is_none() {
+            return service;
+        }
+
+        let request_coprocessor = self.coprocessor.clone();
+        let response_coprocessor = self.coprocessor.clone();
+        ServiceBuilder::new()
+            .checkpoint_async(move |req: router::Request| {
+                let coprocessor = request_coprocessor.clone();
+                let conf = request.clone();
+                async move {
+                    match conf {
+                        Some(conf) => coprocessor.router_request(&conf, req).await,
+                        None => Ok(ControlFlow::Continue(req)),
+                    }
+                }
+            })
+            .buffered()
+            .service(service)
+            .and_then(move |res: router::Response| {
+                let coprocessor = response_coprocessor.clone();
+                let conf = response.clone();
+                async move {
+                    match conf {
+                        Some(conf) => coprocessor.router_response(&conf, res).await,
+                        None => Ok(res),
+                    }
+                }
+            })
+            .boxed()
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let StageConf { request, response } = self.stages.supergraph.clone();
+        if request.is_none() && response.is_none() {
+            return service;
+        }
+
+        let request_coprocessor = self.coprocessor.clone();
+        let response_coprocessor = self.coprocessor.clone();
+        ServiceBuilder::new()
+            .checkpoint_async(move |req: supergraph::Request| {
+                let coprocessor = request_coprocessor.clone();
+                let conf = request.clone();
+                async move {
+                    match conf {
+                        Some(conf) => coprocessor.supergraph_request(&conf, req).await,
+                        None => Ok(ControlFlow::Continue(req)),
+                    }
+                }
+            })
+            .buffered()
+            .service(service)
+            .and_then(move |res: supergraph::Response| {
+                let coprocessor = response_coprocessor.clone();
+                let conf = response.clone();
+                async move {
+                    match conf {
+                        Some(conf) => coprocessor.supergraph_response(&conf, res).await,
+                        None => Ok(res),
+                    }
+                }
+            })
+            .boxed()
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let StageConf { request, response } = self.stages.subgraph.clone();
+        if request.is_none() && response.is_none() {
+            return service;
+        }
+
+        let request_coprocessor = self.coprocessor.clone();
+        let response_coprocessor = self.coprocessor.clone();
+        let request_name = name.to_string();
+        let response_name = name.to_string();
+        ServiceBuilder::new()
+            .checkpoint_async(move |req: subgraph::Request| {
+                let coprocessor = request_coprocessor.clone();
+                let conf = request.clone();
+                let name = request_name.clone();
+                async move {
+                    match conf {
+                        Some(conf) => coprocessor.subgraph_request(&conf, name, req).await,
+                        None => Ok(ControlFlow::Continue(req)),
+                    }
+                }
+            })
+            .buffered()
+            .service(service)
+            .and_then(move |res: subgraph::Response| {
+                let coprocessor = response_coprocessor.clone();
+                let conf = response.clone();
+                let name = response_name.clone();
+                async move {
+                    match conf {
+                        Some(conf) => coprocessor.subgraph_response(&conf, name, res).await,
+                        None => Ok(res),
+                    }
+                }
+            })
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "external", External);
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::make_service_fn;
+    use hyper::service::service_fn;
+    use hyper::Body;
+    use hyper::Server;
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+    use crate::SubgraphRequest;
+    use crate::SubgraphResponse;
+    use crate::SupergraphRequest;
+    use crate::SupergraphResponse;
+
+    /// Starts a coprocessor which hands every payload to `handler`.
+    async fn coprocessor(handler: fn(serde_json::Value) -> serde_json::Value) -> SocketAddr {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |req: http::Request<Body>| async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let payload = serde_json::from_slice(&body).unwrap();
+                Ok::<_, Infallible>(http::Response::new(Body::from(
+                    serde_json::to_vec(&handler(payload)).unwrap(),
+                )))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    async fn plugin(config: serde_json::Value) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.external")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn plugin_registered() {
+        plugin(json!({ "url": "http://127.0.0.1:8081" })).await;
+    }
+
+    #[tokio::test]
+    async fn router_request_is_modified() {
+        let address = coprocessor(|mut payload| {
+            assert_eq!(payload["stage"], "RouterRequest");
+            assert_eq!(payload["method"], "POST");
+            assert_eq!(payload["body"], r#"{"query":"{ me }"}"#);
+            payload["uri"] = json!("http://localhost/tenant/graphql");
+            payload["body"] = json!({ "query": "{ me { name } }" });
+            payload
+        })
+        .await;
+        let plugin = plugin(json!({
+            "url": format!("http://{}", address),
+            "stages": {
+                "router": {
+                    "request": { "body": true }
+                }
+            }
+        }))
+        .await;
+
+        let service = tower::service_fn(|req: router::Request| async move {
+            assert_eq!(req.router_request.uri(), "http://localhost/tenant/graphql");
+            let body = hyper::body::to_bytes(req.router_request.into_body()).await?;
+            assert_eq!(&body[..], br#"{"query":"{ me { name } }"}"#);
+            Ok::<_, BoxError>(router::Response {
+                response: http::Response::default(),
+                context: req.context,
+            })
+        });
+        let request = http::Request::post("http://localhost/graphql")
+            .body(Body::from(r#"{"query":"{ me }"}"#))
+            .unwrap();
+        plugin
+            .router_service(service.boxed())
+            .oneshot(
+                router::Request::fake_builder()
+                    .router_request(request)
+                    .build(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn router_request_short_circuits() {
+        let address = coprocessor(|mut payload| {
+            payload["control"] = json!({ "break": 403 });
+            payload["body"] = json!("forbidden");
+            payload
+        })
+        .await;
+        let plugin = plugin(json!({
+            "url": format!("http://{}", address),
+            "stages": {
+                "router": {
+                    "request": { "headers": true }
+                }
+            }
+        }))
+        .await;
+
+        let service = tower::service_fn(|_: router::Request| async {
+            Err::<router::Response, BoxError>("the handler is not called".into())
+        });
+        let response = plugin
+            .router_service(service.boxed())
+            .oneshot(router::Request::fake_builder().build())
+            .await
+            .unwrap();
+
+        assert_eq!(response.response.status(), StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(response.response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"forbidden");
+    }
+
+    #[tokio::test]
+    async fn supergraph_request_is_modified() {
+        let address = coprocessor(|mut payload| {
+            assert_eq!(payload["stage"], "SupergraphRequest");
+            payload["headers"]["x-coprocessor"] = json!(["yes"]);
+            payload["context"]["coprocessor"] = json!(true);
+            payload["context"]
+                .as_object_mut()
+                .unwrap()
+                .remove("removed");
+            payload
+        })
+        .await;
+        let plugin = plugin(json!({
+            "url": format!("http://{}", address),
+            "stages": {
+                "supergraph": {
+                    "request": { "headers": true, "context": true }
+                }
+            }
+        }))
+        .await;
+
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                assert_eq!(
+                    req.originating_request.headers().get("x-coprocessor"),
+                    Some(&HeaderValue::from_static("yes"))
+                );
+                assert_eq!(
+                    req.context.get::<_, bool>("coprocessor").unwrap(),
+                    Some(true)
+                );
+                assert!(req.context.get_json_value("removed").is_none());
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let context = Context::new();
+        context.insert("removed", true).unwrap();
+        plugin
+            .supergraph_service(mock_service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .context(context)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn supergraph_request_short_circuits() {
+        let address = coprocessor(|mut payload| {
+            payload["control"] = json!({ "break": 401 });
+            payload["body"] =
+                json!({ "errors": [{ "message": "not authenticated", "locations": [] }] });
+            payload
+        })
+        .await;
+        let plugin = plugin(json!({
+            "url": format!("http://{}", address),
+            "stages": {
+                "supergraph": {
+                    "request": { "headers": true }
+                }
+            }
+        }))
+        .await;
+
+        let mut response = plugin
+            .supergraph_service(MockSupergraphService::new().boxed())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.response.status(), StatusCode::UNAUTHORIZED);
+        let body = response.next_response().await.unwrap();
+        assert_eq!(body.errors[0].message, "not authenticated");
+    }
+
+    #[tokio::test]
+    async fn subgraph_response_is_modified() {
+        let address = coprocessor(|mut payload| {
+            assert_eq!(payload["stage"], "SubgraphResponse");
+            assert_eq!(payload["serviceName"], "products");
+            payload["body"]["data"]["name"] = json!("changed");
+            payload
+        })
+        .await;
+        let plugin = plugin(json!({
+            "url": format!("http://{}", address),
+            "stages": {
+                "subgraph": {
+                    "response": { "body": true }
+                }
+            }
+        }))
+        .await;
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service.expect_call().times(1).returning(|req| {
+            Ok(SubgraphResponse::fake_builder()
+                .data(json!({ "name": "original" }))
+                .context(req.context)
+                .build())
+        });
+
+        let response = plugin
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.response.body().data,
+            Some(serde_json_bytes::json!({ "name": "changed" }))
+        );
+    }
+}
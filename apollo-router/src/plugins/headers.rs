@@ -18,6 +18,7 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use schemars::JsonSchema;
 use serde::Deserialize;
+use serde_json_bytes::Value;
 use tower::BoxError;
 use tower::Layer;
 use tower::ServiceBuilder;
@@ -59,13 +60,59 @@ enum Remove {
 
 #[derive(Clone, JsonSchema, Deserialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
-struct Insert {
-    #[schemars(schema_with = "string_schema")]
-    #[serde(deserialize_with = "deserialize_header_name")]
-    name: HeaderName,
-    #[schemars(schema_with = "string_schema")]
-    #[serde(deserialize_with = "deserialize_header_value")]
-    value: HeaderValue,
+#[serde(untagged)]
+enum Insert {
+    Static {
+        #[schemars(schema_with = "string_schema")]
+        #[serde(deserialize_with = "deserialize_header_name")]
+        name: HeaderName,
+        #[schemars(schema_with = "string_schema")]
+        #[serde(deserialize_with = "deserialize_header_value")]
+        value: HeaderValue,
+    },
+    /// The value of an environment variable, read when the plugin is created.
+    FromEnv {
+        #[schemars(schema_with = "string_schema")]
+        #[serde(deserialize_with = "deserialize_header_name")]
+        name: HeaderName,
+        from_env: String,
+    },
+    /// The value of a context entry, read for each request. Nothing is inserted if the entry
+    /// is missing or is not a string, a number or a boolean.
+    FromContext {
+        #[schemars(schema_with = "string_schema")]
+        #[serde(deserialize_with = "deserialize_header_name")]
+        name: HeaderName,
+        from_context: String,
+    },
+}
+
+impl Operation {
+    /// Replaces the values read from environment variables with static values.
+    fn resolve(self) -> Result<Self, BoxError> {
+        match self {
+            Operation::Insert(Insert::FromEnv { name, from_env }) => {
+                let value = std::env::var(&from_env).map_err(|e| {
+                    format!(
+                        "could not read environment variable {} for header {}: {}",
+                        from_env, name, e
+                    )
+                })?;
+                let value = HeaderValue::try_from(value).map_err(|e| {
+                    format!(
+                        "invalid value in environment variable {} for header {}: {}",
+                        from_env, name, e
+                    )
+                })?;
+                Ok(Operation::Insert(Insert::Static { name, value }))
+            }
+            operation => Ok(operation),
+        }
+    }
+}
+
+fn resolve_all(operations: Vec<Operation>) -> Result<Vec<Operation>, BoxError> {
+    operations.into_iter().map(Operation::resolve).collect()
 }
 
 #[derive(Clone, JsonSchema, Deserialize)]
@@ -116,8 +163,16 @@ impl Plugin for Headers {
     type Config = Config;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let config = init.config;
         Ok(Headers {
-            config: init.config,
+            config: Config {
+                all: resolve_all(config.all)?,
+                subgraphs: config
+                    .subgraphs
+                    .into_iter()
+                    .map(|(name, operations)| Ok((name, resolve_all(operations)?)))
+                    .collect::<Result<_, BoxError>>()?,
+            },
         })
     }
     fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
@@ -195,11 +250,29 @@ where
     fn call(&mut self, mut req: SubgraphRequest) -> Self::Future {
         for operation in &self.operations {
             match operation {
-                Operation::Insert(config) => {
+                Operation::Insert(Insert::Static { name, value }) => {
                     req.subgraph_request
                         .headers_mut()
-                        .insert(&config.name, config.value.clone());
+                        .insert(name, value.clone());
+                }
+                Operation::Insert(Insert::FromContext { name, from_context }) => {
+                    let value =
+                        req.context
+                            .get_json_value(from_context.as_str())
+                            .and_then(|value| match value {
+                                Value::String(value) => HeaderValue::from_str(value.as_str()).ok(),
+                                Value::Number(value) => {
+                                    HeaderValue::try_from(value.to_string()).ok()
+                                }
+                                Value::Bool(value) => HeaderValue::try_from(value.to_string()).ok(),
+                                _ => None,
+                            });
+                    if let Some(value) = value {
+                        req.subgraph_request.headers_mut().insert(name, value);
+                    }
                 }
+                // Resolved to static values when the plugin is created
+                Operation::Insert(Insert::FromEnv { .. }) => {}
                 Operation::Remove(Remove::Named(name)) => {
                     req.subgraph_request.headers_mut().remove(name);
                 }
@@ -274,6 +347,30 @@ mod test {
         "#,
         )
         .unwrap();
+
+        serde_yaml::from_str::<Config>(
+            r#"
+        all:
+            - insert:
+                name: "test"
+                from_env: "TEST"
+            - insert:
+                name: "test"
+                from_context: "test"
+        "#,
+        )
+        .unwrap();
+
+        assert!(serde_yaml::from_str::<Config>(
+            r#"
+        all:
+            - insert:
+                name: "test"
+                value: "test"
+                from_context: "test"
+        "#,
+        )
+        .is_err());
     }
 
     #[test]
@@ -376,7 +473,7 @@ mod test {
             })
             .returning(example_response);
 
-        let mut service = HeadersLayer::new(vec![Operation::Insert(Insert {
+        let mut service = HeadersLayer::new(vec![Operation::Insert(Insert::Static {
             name: "c".try_into()?,
             value: "d".try_into()?,
         })])
@@ -386,6 +483,65 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_insert_from_context() -> Result<(), BoxError> {
+        let mut mock = MockSubgraphService::new();
+        mock.expect_call()
+            .times(1)
+            .withf(|request| {
+                request.assert_headers(vec![
+                    ("aa", "vaa"),
+                    ("ab", "vab"),
+                    ("ac", "vac"),
+                    ("c", "d"),
+                    ("e", "42"),
+                ])
+            })
+            .returning(example_response);
+
+        let mut service = HeadersLayer::new(vec![
+            Operation::Insert(Insert::FromContext {
+                name: "c".try_into()?,
+                from_context: "c".to_string(),
+            }),
+            Operation::Insert(Insert::FromContext {
+                name: "e".try_into()?,
+                from_context: "e".to_string(),
+            }),
+            Operation::Insert(Insert::FromContext {
+                name: "f".try_into()?,
+                from_context: "missing".to_string(),
+            }),
+        ])
+        .layer(mock);
+
+        let request = example_request();
+        request.context.insert("c", "d".to_string())?;
+        request.context.insert("e", 42)?;
+        service.ready().await?.call(request).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_from_env() -> Result<(), BoxError> {
+        std::env::set_var("TEST_HEADERS_INSERT_FROM_ENV", "d");
+        let operation = Operation::Insert(Insert::FromEnv {
+            name: "c".try_into()?,
+            from_env: "TEST_HEADERS_INSERT_FROM_ENV".to_string(),
+        });
+        assert!(matches!(
+            operation.resolve()?,
+            Operation::Insert(Insert::Static { name, value }) if name == "c" && value == "d"
+        ));
+
+        let operation = Operation::Insert(Insert::FromEnv {
+            name: "c".try_into()?,
+            from_env: "TEST_HEADERS_INSERT_FROM_ENV_MISSING".to_string(),
+        });
+        assert!(operation.resolve().is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_remove_exact() -> Result<(), BoxError> {
         let mut mock = MockSubgraphService::new();
@@ -1,12 +1,23 @@
+//! Inclusion of subgraph errors in the responses sent to clients.
+//!
+//! Errors of the subgraphs that are not included are replaced with a single redacted error.
+//! Included errors go through the error policy: their messages are rewritten with regular
+//! expressions, their codes are mapped to the codes clients know, and the name of their subgraph
+//! can be attached to them.
+
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use once_cell::sync::Lazy;
+use regex::Regex;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use tower::BoxError;
 use tower::ServiceExt;
 
 use crate::error::Error as SubgraphError;
+use crate::json_ext::Value;
+use crate::plugin::serde::deserialize_regex;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
 use crate::register_plugin;
@@ -28,17 +39,84 @@ register_plugin!(
     IncludeSubgraphErrors
 );
 
+/// Configuration for the inclusion of subgraph errors in responses
 #[derive(Clone, Debug, JsonSchema, Deserialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 struct Config {
+    /// Include the errors of all subgraphs (default: false)
     #[serde(default)]
     all: bool,
+    /// Include the errors of a subgraph or not, over `all`
     #[serde(default)]
     subgraphs: HashMap<String, bool>,
+    /// Rewrites of the messages of included errors, applied in order
+    #[serde(default)]
+    rewrite_messages: Vec<MessageRewrite>,
+    /// Codes of included errors, by the `code` extension set by the subgraph
+    #[serde(default)]
+    error_codes: HashMap<String, String>,
+    /// Attach the name of the subgraph to errors, in the `service` extension (default: false)
+    #[serde(default)]
+    include_service_name: bool,
+}
+
+#[derive(Clone, Debug, JsonSchema, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessageRewrite {
+    /// Regular expression matched against the messages
+    #[schemars(with = "String")]
+    #[serde(deserialize_with = "deserialize_regex")]
+    pattern: Regex,
+    /// Replacement of the matches, where `$1` is the first group of the pattern
+    replacement: String,
+}
+
+/// What happens to the errors included in responses.
+#[derive(Debug)]
+struct ErrorPolicy {
+    rewrite_messages: Vec<MessageRewrite>,
+    error_codes: HashMap<String, String>,
+    include_service_name: bool,
+}
+
+impl ErrorPolicy {
+    fn is_empty(&self) -> bool {
+        self.rewrite_messages.is_empty()
+            && self.error_codes.is_empty()
+            && !self.include_service_name
+    }
+
+    fn apply(&self, subgraph: &str, mut error: SubgraphError) -> SubgraphError {
+        for rewrite in &self.rewrite_messages {
+            if let std::borrow::Cow::Owned(message) = rewrite
+                .pattern
+                .replace_all(&error.message, rewrite.replacement.as_str())
+            {
+                error.message = message;
+            }
+        }
+        let code = error
+            .extensions
+            .get("code")
+            .and_then(|code| code.as_str())
+            .and_then(|code| self.error_codes.get(code));
+        if let Some(code) = code {
+            error
+                .extensions
+                .insert("code", Value::String(code.clone().into()));
+        }
+        if self.include_service_name {
+            error
+                .extensions
+                .insert("service", Value::String(subgraph.to_string().into()));
+        }
+        error
+    }
 }
 
 struct IncludeSubgraphErrors {
     config: Config,
+    policy: Arc<ErrorPolicy>,
 }
 
 #[async_trait::async_trait]
@@ -46,8 +124,14 @@ impl Plugin for IncludeSubgraphErrors {
     type Config = Config;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let policy = ErrorPolicy {
+            rewrite_messages: init.config.rewrite_messages.clone(),
+            error_codes: init.config.error_codes.clone(),
+            include_service_name: init.config.include_service_name,
+        };
         Ok(IncludeSubgraphErrors {
             config: init.config,
+            policy: Arc::new(policy),
         })
     }
 
@@ -57,11 +141,21 @@ impl Plugin for IncludeSubgraphErrors {
         if !*self.config.subgraphs.get(name).unwrap_or(&self.config.all) {
             let sub_name_response = name.to_string();
             let sub_name_error = name.to_string();
+            let include_service_name = self.config.include_service_name;
             return service
                 .map_response(move |mut response: SubgraphResponse| {
                     if !response.response.body().errors.is_empty() {
                         tracing::info!("redacted subgraph({sub_name_response}) errors");
-                        response.response.body_mut().errors = REDACTED_ERROR_MESSAGE.clone();
+                        let mut errors = REDACTED_ERROR_MESSAGE.clone();
+                        if include_service_name {
+                            for error in &mut errors {
+                                error.extensions.insert(
+                                    "service",
+                                    Value::String(sub_name_response.clone().into()),
+                                );
+                            }
+                        }
+                        response.response.body_mut().errors = errors;
                     }
                     response
                 })
@@ -72,12 +166,22 @@ impl Plugin for IncludeSubgraphErrors {
                     _error = Box::new(crate::error::FetchError::SubrequestHttpError {
                         service: "redacted".to_string(),
                         reason: "redacted".to_string(),
+                        status: None,
                     });
                     _error
                 })
                 .boxed();
         }
+        if self.policy.is_empty() {
+            return service;
+        }
+        let policy = self.policy.clone();
+        let name = name.to_string();
         service
+            .map_response(move |response: SubgraphResponse| {
+                response.map_errors(|error| Some(policy.apply(&name, error)))
+            })
+            .boxed()
     }
 }
 
@@ -305,4 +409,36 @@ mod test {
         let router = build_mock_router(plugin).await;
         execute_router_test(ERROR_ACCOUNT_QUERY, &*REDACTED_ACCOUNT_RESPONSE, router).await;
     }
+
+    #[tokio::test]
+    async fn it_applies_the_error_policy_to_included_errors() {
+        let plugin = get_redacting_plugin(&serde_json::json!({
+            "all": true,
+            "rewrite_messages": [{ "pattern": "couldn't find (\\w+)", "replacement": "missing $1" }],
+            "include_service_name": true
+        }))
+        .await;
+        let router = build_mock_router(plugin).await;
+        let expected: Response = serde_json::from_str(r#"{"data": {"topProducts":null}, "errors":[{"message": "missing mock for query", "locations": [], "path": null, "extensions": { "test": "value", "service": "products" }}]}"#).unwrap();
+        execute_router_test(ERROR_PRODUCT_QUERY, &expected, router).await;
+    }
+
+    #[test]
+    fn it_maps_error_codes() {
+        let policy = ErrorPolicy {
+            rewrite_messages: Vec::new(),
+            error_codes: [("UNAUTHENTICATED".to_string(), "AUTH_REQUIRED".to_string())]
+                .into_iter()
+                .collect(),
+            include_service_name: false,
+        };
+        let error = SubgraphError::builder()
+            .message("not logged in")
+            .extension("code", "UNAUTHENTICATED")
+            .build();
+        assert_eq!(
+            policy.apply("accounts", error).extensions.get("code"),
+            Some(&Value::from("AUTH_REQUIRED"))
+        );
+    }
 }
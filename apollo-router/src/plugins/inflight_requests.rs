@@ -0,0 +1,467 @@
+//! Inspection of in-flight requests.
+//!
+//! Requests are tracked from the supergraph service until their first response is produced. The
+//! plugin endpoint of the admin listener lists them with their age, operation, current stage and
+//! outstanding subgraph fetches, and can cancel a stuck request if cancellation is enabled.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::sync::oneshot;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::graphql;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::execution;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::services::transport;
+use crate::Context;
+use crate::ExecutionRequest;
+use crate::SubgraphRequest;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+const REQUEST_ID_CONTEXT_KEY: &str = "inflight_requests::id";
+
+/// Configuration for the inspection of in-flight requests
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Allow cancelling in-flight requests through the plugin endpoint (default: false)
+    #[serde(default)]
+    allow_cancellation: bool,
+}
+
+struct InflightRequest {
+    started: Instant,
+    operation_name: Option<String>,
+    operation_hash: Option<String>,
+    stage: &'static str,
+    subgraph_fetches: HashMap<String, usize>,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+/// What the endpoint returns for each in-flight request.
+#[derive(Debug, Deserialize, Serialize)]
+struct RequestSnapshot {
+    id: u64,
+    age_ms: u64,
+    operation_name: Option<String>,
+    operation_hash: Option<String>,
+    stage: String,
+    /// Number of outstanding fetches per subgraph
+    subgraph_fetches: BTreeMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<u64, InflightRequest>>,
+}
+
+impl Registry {
+    fn register(
+        self: &Arc<Self>,
+        req: &SupergraphRequest,
+    ) -> (Registration, oneshot::Receiver<()>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (cancel, cancelled) = oneshot::channel();
+        let body = req.originating_request.body();
+        self.requests.lock().expect("lock poisoned").insert(
+            id,
+            InflightRequest {
+                started: Instant::now(),
+                operation_name: body.operation_name.clone(),
+                operation_hash: body
+                    .query
+                    .as_ref()
+                    .map(|query| hex::encode(Sha256::digest(query.as_bytes()))),
+                stage: "supergraph",
+                subgraph_fetches: HashMap::new(),
+                cancel: Some(cancel),
+            },
+        );
+        let _ = req.context.insert(REQUEST_ID_CONTEXT_KEY, id);
+
+        (
+            Registration {
+                registry: self.clone(),
+                id,
+            },
+            cancelled,
+        )
+    }
+
+    fn update(&self, context: &Context, f: impl FnOnce(&mut InflightRequest)) {
+        let id: Option<u64> = context.get(REQUEST_ID_CONTEXT_KEY).ok().flatten();
+        if let Some(id) = id {
+            if let Some(request) = self.requests.lock().expect("lock poisoned").get_mut(&id) {
+                f(request);
+            }
+        }
+    }
+
+    /// Returns the in-flight requests, oldest first.
+    fn snapshot(&self) -> Vec<RequestSnapshot> {
+        let requests = self.requests.lock().expect("lock poisoned");
+        let mut snapshot: Vec<RequestSnapshot> = requests
+            .iter()
+            .map(|(id, request)| RequestSnapshot {
+                id: *id,
+                age_ms: request.started.elapsed().as_millis() as u64,
+                operation_name: request.operation_name.clone(),
+                operation_hash: request.operation_hash.clone(),
+                stage: request.stage.to_string(),
+                subgraph_fetches: request
+                    .subgraph_fetches
+                    .iter()
+                    .filter(|(_, count)| **count > 0)
+                    .map(|(name, count)| (name.clone(), *count))
+                    .collect(),
+            })
+            .collect();
+        snapshot.sort_by_key(|request| request.id);
+        snapshot
+    }
+
+    /// Cancels a request, returning false if it is not in flight.
+    fn cancel(&self, id: u64) -> bool {
+        self.requests
+            .lock()
+            .expect("lock poisoned")
+            .get_mut(&id)
+            .and_then(|request| request.cancel.take())
+            .map(|cancel| cancel.send(()).is_ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Removes a request from the registry when it completes, or when it is dropped.
+struct Registration {
+    registry: Arc<Registry>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry
+            .requests
+            .lock()
+            .expect("lock poisoned")
+            .remove(&self.id);
+    }
+}
+
+/// Decrements the outstanding fetches of a subgraph when the fetch completes, or is dropped.
+struct SubgraphFetch {
+    registry: Arc<Registry>,
+    context: Context,
+    name: String,
+}
+
+impl Drop for SubgraphFetch {
+    fn drop(&mut self) {
+        let name = &self.name;
+        self.registry.update(&self.context, |request| {
+            if let Some(count) = request.subgraph_fetches.get_mut(name) {
+                *count = count.saturating_sub(1);
+            }
+        });
+    }
+}
+
+struct InflightRequests {
+    config: Config,
+    registry: Arc<Registry>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for InflightRequests {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(InflightRequests {
+            config: init.config,
+            registry: Default::default(),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let registry = self.registry.clone();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SupergraphRequest| {
+                    let (registration, cancelled) = registry.register(req);
+                    (registration, cancelled, req.context.clone())
+                },
+                move |(registration, cancelled, context): (
+                    Registration,
+                    oneshot::Receiver<()>,
+                    Context,
+                ),
+                      f| async move {
+                    let result: supergraph::ServiceResult = tokio::select! {
+                        result = f => result,
+                        Ok(()) = cancelled => {
+                            tracing::info!("request {} was cancelled", registration.id);
+                            SupergraphResponse::builder()
+                                .error(
+                                    graphql::Error::builder()
+                                        .message("the request was cancelled")
+                                        .extension("code", "REQUEST_CANCELLED")
+                                        .build(),
+                                )
+                                .status_code(StatusCode::SERVICE_UNAVAILABLE)
+                                .context(context)
+                                .build()
+                        }
+                    };
+                    drop(registration);
+                    result
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        let registry = self.registry.clone();
+        service
+            .map_request(move |req: ExecutionRequest| {
+                registry.update(&req.context, |request| request.stage = "execution");
+                req
+            })
+            .boxed()
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let registry = self.registry.clone();
+        let name = name.to_string();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SubgraphRequest| {
+                    registry.update(&req.context, |request| {
+                        *request.subgraph_fetches.entry(name.clone()).or_default() += 1;
+                    });
+                    SubgraphFetch {
+                        registry: registry.clone(),
+                        context: req.context.clone(),
+                        name: name.clone(),
+                    }
+                },
+                |fetch: SubgraphFetch, f| async move {
+                    let result: subgraph::ServiceResult = f.await;
+                    drop(fetch);
+                    result
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+
+    fn admin_endpoint(&self) -> Option<transport::BoxService> {
+        let registry = self.registry.clone();
+        let allow_cancellation = self.config.allow_cancellation;
+        Some(
+            tower::service_fn(move |request: transport::Request| {
+                endpoint(registry.clone(), allow_cancellation, request)
+            })
+            .boxed(),
+        )
+    }
+}
+
+/// Handles `GET /plugins/experimental.inflight_requests/requests` and
+/// `POST /plugins/experimental.inflight_requests/requests/<id>/cancel` on the admin listener.
+fn endpoint(
+    registry: Arc<Registry>,
+    allow_cancellation: bool,
+    request: transport::Request,
+) -> BoxFuture<'static, Result<transport::Response, BoxError>> {
+    Box::pin(async move {
+        let method = request.method().clone();
+        let path = request.uri().path().trim_end_matches('/');
+        let segments: Vec<&str> = path.rsplit('/').collect();
+        let body = match segments.as_slice() {
+            ["requests", ..] if method == http::Method::GET => {
+                serde_json::to_string(&registry.snapshot())?
+            }
+            ["cancel", id, "requests", ..] if method == http::Method::POST => {
+                if !allow_cancellation {
+                    return Ok(http::Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body("cancellation is not enabled".into())?);
+                }
+                let cancelled = id.parse().map(|id| registry.cancel(id)).unwrap_or_default();
+                if !cancelled {
+                    return Ok(http::Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(format!("request {} is not in flight", id).into())?);
+                }
+                serde_json::json!({ "cancelled": true }).to_string()
+            }
+            _ => {
+                return Ok(http::Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(hyper::Body::empty())?)
+            }
+        };
+        Ok(http::Response::builder()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body.into())?)
+    })
+}
+
+register_plugin!("experimental", "inflight_requests", InflightRequests);
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::DynPlugin;
+
+    async fn plugin(allow_cancellation: bool) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.inflight_requests")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({ "allow_cancellation": allow_cancellation }))
+            .await
+            .unwrap()
+    }
+
+    async fn call_endpoint(
+        plugin: &dyn DynPlugin,
+        method: http::Method,
+        path: &str,
+    ) -> (StatusCode, Vec<u8>) {
+        let response = plugin
+            .admin_endpoint()
+            .unwrap()
+            .oneshot(
+                http::Request::builder()
+                    .method(method)
+                    .uri(format!(
+                        "http://router/plugins/experimental.inflight_requests{}",
+                        path
+                    ))
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    async fn inflight(plugin: &dyn DynPlugin) -> Vec<RequestSnapshot> {
+        let (status, body) = call_endpoint(plugin, http::Method::GET, "/requests").await;
+        assert_eq!(status, StatusCode::OK);
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// A supergraph service that never responds.
+    fn stuck_service() -> supergraph::BoxService {
+        tower::service_fn(|_req: SupergraphRequest| {
+            futures::future::pending::<supergraph::ServiceResult>()
+        })
+        .boxed()
+    }
+
+    async fn wait_for_inflight(plugin: &dyn DynPlugin) -> Vec<RequestSnapshot> {
+        for _ in 0..100 {
+            let requests = inflight(plugin).await;
+            if !requests.is_empty() {
+                return requests;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the request was not registered");
+    }
+
+    #[tokio::test]
+    async fn inflight_requests_can_be_cancelled() {
+        let plugin = plugin(true).await;
+        let service = plugin.supergraph_service(stuck_service());
+        let request = tokio::spawn(
+            service.oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("query Me { me { id } }")
+                    .operation_name("Me")
+                    .build()
+                    .unwrap(),
+            ),
+        );
+
+        let requests = wait_for_inflight(&*plugin).await;
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].operation_name.as_deref(), Some("Me"));
+        assert_eq!(requests[0].stage, "supergraph");
+
+        let (status, _) = call_endpoint(
+            &*plugin,
+            http::Method::POST,
+            &format!("/requests/{}/cancel", requests[0].id),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut response = request.await.unwrap().unwrap();
+        assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.next_response().await.unwrap();
+        assert_eq!(
+            body.errors[0].extensions.get("code"),
+            Some(&"REQUEST_CANCELLED".into())
+        );
+        assert!(inflight(&*plugin).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancellation_is_disabled_by_default() {
+        let plugin = plugin(false).await;
+        let service = plugin.supergraph_service(stuck_service());
+        let request = tokio::spawn(
+            service.oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("{ me { id } }")
+                    .build()
+                    .unwrap(),
+            ),
+        );
+
+        let requests = wait_for_inflight(&*plugin).await;
+        let (status, _) = call_endpoint(
+            &*plugin,
+            http::Method::POST,
+            &format!("/requests/{}/cancel", requests[0].id),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Dropping the request removes it from the in-flight requests
+        request.abort();
+        let _ = request.await;
+        assert!(inflight(&*plugin).await.is_empty());
+    }
+}
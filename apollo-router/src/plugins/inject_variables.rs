@@ -0,0 +1,415 @@
+//! Injection of request headers and context entries into operations.
+//!
+//! Values are read from a header of the client request, an entry of the context or a default,
+//! then set as operation variables when the operation declares them, or sent to subgraphs in
+//! the request extensions. A typical use is to set a `$locale` variable from `Accept-Language`.
+
+use std::collections::HashMap;
+
+use apollo_parser::ast;
+use http::header::HeaderName;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json_bytes::ByteString;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::json_ext::Object;
+use crate::plugin::serde::deserialize_option_header_name;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+use crate::SubgraphRequest;
+use crate::SupergraphRequest;
+
+/// Context entry holding the extensions to send to subgraphs.
+const EXTENSIONS_CONTEXT_KEY: &str = "apollo_inject_variables::extensions";
+
+/// Configuration for the injection of headers and context entries into operations
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Injections applied to all operations
+    #[serde(default)]
+    all: Vec<Injection>,
+    /// Injections applied to the operations with a given name, after the ones of `all`
+    #[serde(default)]
+    operations: HashMap<String, Vec<Injection>>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Injection {
+    /// Read the value from this header of the client request
+    #[schemars(with = "Option<String>", default)]
+    #[serde(deserialize_with = "deserialize_option_header_name", default)]
+    header: Option<HeaderName>,
+    /// Only keep the preferred language of a header formatted like `Accept-Language`
+    #[serde(default)]
+    preferred_language: bool,
+    /// Read the value from this context entry if the header is missing
+    #[serde(default)]
+    context: Option<String>,
+    /// Value to use if neither the header nor the context entry is present
+    #[schemars(with = "Option<serde_json::Value>", default)]
+    #[serde(default)]
+    default: Option<Value>,
+    /// Set this variable, if the operation declares it and the client did not set it
+    #[serde(default)]
+    variable: Option<String>,
+    /// Send the value to subgraphs in this request extension
+    #[serde(default)]
+    extension: Option<String>,
+}
+
+impl Injection {
+    fn value(&self, req: &SupergraphRequest) -> Option<Value> {
+        let header = self.header.as_ref().and_then(|name| {
+            let value = req.originating_request.headers().get(name)?.to_str().ok()?;
+            if self.preferred_language {
+                preferred_language(value).map(Value::from)
+            } else {
+                Some(Value::from(value))
+            }
+        });
+        header
+            .or_else(|| {
+                self.context
+                    .as_ref()
+                    .and_then(|key| req.context.get_json_value(key.as_str()))
+            })
+            .or_else(|| self.default.clone())
+    }
+}
+
+#[derive(Debug)]
+struct InjectVariables {
+    config: Config,
+}
+
+#[async_trait::async_trait]
+impl Plugin for InjectVariables {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let config = init.config;
+        for injection in config
+            .all
+            .iter()
+            .chain(config.operations.values().flatten())
+        {
+            if injection.header.is_none()
+                && injection.context.is_none()
+                && injection.default.is_none()
+            {
+                return Err("an injection needs a header, a context entry or a default".into());
+            }
+            if injection.variable.is_none() && injection.extension.is_none() {
+                return Err("an injection needs a variable or an extension".into());
+            }
+        }
+        Ok(InjectVariables { config })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if self.config.all.is_empty() && self.config.operations.is_empty() {
+            return service;
+        }
+        let config = self.config.clone();
+        service
+            .map_request(move |req: SupergraphRequest| inject(&config, req))
+            .boxed()
+    }
+
+    fn subgraph_service(&self, _name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        if self.config.all.is_empty() && self.config.operations.is_empty() {
+            return service;
+        }
+        service
+            .map_request(|mut req: SubgraphRequest| {
+                if let Some(extensions) = extensions(&req.context) {
+                    req.subgraph_request
+                        .body_mut()
+                        .extensions
+                        .extend(extensions);
+                }
+                req
+            })
+            .boxed()
+    }
+}
+
+/// Sets the variables of the request and stores the extensions to send to subgraphs.
+fn inject(config: &Config, mut req: SupergraphRequest) -> SupergraphRequest {
+    let body = req.originating_request.body();
+    let (operation_name, declared) = match body
+        .query
+        .as_deref()
+        .and_then(|query| operation(query, body.operation_name.as_deref()))
+    {
+        Some(operation) => operation,
+        None => return req,
+    };
+    let injections = config.all.iter().chain(
+        operation_name
+            .and_then(|name| config.operations.get(&name))
+            .into_iter()
+            .flatten(),
+    );
+
+    let mut variables = Object::new();
+    let mut extensions = Object::new();
+    for injection in injections {
+        let value = match injection.value(&req) {
+            Some(value) => value,
+            None => continue,
+        };
+        if let Some(variable) = &injection.variable {
+            if declared.contains(variable) && !body.variables.contains_key(variable.as_str()) {
+                variables.insert(ByteString::from(variable.as_str()), value.clone());
+            }
+        }
+        if let Some(extension) = &injection.extension {
+            extensions.insert(ByteString::from(extension.as_str()), value);
+        }
+    }
+
+    if !extensions.is_empty() {
+        let _ = req.context.insert(EXTENSIONS_CONTEXT_KEY, extensions);
+    }
+    req.originating_request
+        .body_mut()
+        .variables
+        .extend(variables);
+    req
+}
+
+fn extensions(context: &Context) -> Option<Object> {
+    context.get(EXTENSIONS_CONTEXT_KEY).ok().flatten()
+}
+
+/// Returns the name and the declared variables of the operation. Queries that can't be parsed
+/// are left untouched.
+fn operation(query: &str, operation_name: Option<&str>) -> Option<(Option<String>, Vec<String>)> {
+    let tree = apollo_parser::Parser::new(query).parse();
+    if tree.errors().next().is_some() {
+        return None;
+    }
+    let mut operations = tree
+        .document()
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(operation),
+            _ => None,
+        });
+    let operation = match operation_name {
+        Some(operation_name) => operations.find(|operation| {
+            operation
+                .name()
+                .map(|name| name.text() == operation_name)
+                .unwrap_or_default()
+        }),
+        None => operations.next(),
+    }?;
+    let variables = operation
+        .variable_definitions()
+        .iter()
+        .flat_map(|x| x.variable_definitions())
+        .filter_map(|definition| Some(definition.variable()?.name()?.text().to_string()))
+        .collect();
+    Some((
+        operation.name().map(|name| name.text().to_string()),
+        variables,
+    ))
+}
+
+/// Returns the language with the highest quality value of an `Accept-Language` header.
+fn preferred_language(header: &str) -> Option<&str> {
+    let mut preferred: Option<(&str, f32)> = None;
+    for language in header.split(',') {
+        let mut parts = language.split(';').map(str::trim);
+        let tag = match parts.next() {
+            Some(tag) if !tag.is_empty() && tag != "*" => tag,
+            _ => continue,
+        };
+        let quality = parts
+            .find_map(|part| part.strip_prefix("q="))
+            .map(|q| q.parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        if quality > 0.0 && preferred.map(|(_, q)| quality > q).unwrap_or(true) {
+            preferred = Some((tag, quality));
+        }
+    }
+    preferred.map(|(tag, _)| tag)
+}
+
+register_plugin!("experimental", "inject_variables", InjectVariables);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+    use crate::SubgraphResponse;
+    use crate::SupergraphResponse;
+
+    async fn plugin() -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.inject_variables")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({
+                "all": [{
+                    "header": "accept-language",
+                    "preferred_language": true,
+                    "context": "locale",
+                    "default": "en",
+                    "variable": "locale",
+                    "extension": "locale"
+                }],
+                "operations": {
+                    "Prices": [{ "header": "x-currency", "variable": "currency" }]
+                }
+            }))
+            .await
+            .unwrap()
+    }
+
+    async fn variables(request: SupergraphRequest) -> Object {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                let variables = req.originating_request.body().variables.clone();
+                Ok(SupergraphResponse::fake_builder()
+                    .data(Value::Object(variables))
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+        let mut response = plugin()
+            .await
+            .supergraph_service(mock_service.boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+        match response.next_response().await.unwrap().data {
+            Some(Value::Object(variables)) => variables,
+            _ => panic!("expected the variables"),
+        }
+    }
+
+    #[tokio::test]
+    async fn declared_variables_are_injected() {
+        let variables = variables(
+            SupergraphRequest::fake_builder()
+                .query("query Prices($locale: String, $currency: String) { me { name } }")
+                .header("accept-language", "fr-CH, fr;q=0.9, en;q=0.8")
+                .header("x-currency", "CHF")
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(
+            Value::Object(variables),
+            serde_json_bytes::json!({ "locale": "fr-CH", "currency": "CHF" })
+        );
+    }
+
+    #[tokio::test]
+    async fn client_variables_are_kept() {
+        let variables = variables(
+            SupergraphRequest::fake_builder()
+                .query("query Other($locale: String, $currency: String) { me { name } }")
+                .header("x-currency", "CHF")
+                .variable("locale", "de")
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(
+            Value::Object(variables),
+            serde_json_bytes::json!({ "locale": "de" })
+        );
+
+        let variables = variables(
+            SupergraphRequest::fake_builder()
+                .query("query Other { me { name } }")
+                .header("accept-language", "fr")
+                .build()
+                .unwrap(),
+        )
+        .await;
+        assert!(variables.is_empty());
+    }
+
+    #[tokio::test]
+    async fn extensions_are_sent_to_subgraphs() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                assert_eq!(
+                    extensions(&req.context),
+                    Some(
+                        serde_json_bytes::json!({ "locale": "it" })
+                            .as_object()
+                            .unwrap()
+                            .clone()
+                    )
+                );
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+        let context = Context::new();
+        context.insert("locale", "it").unwrap();
+        plugin()
+            .await
+            .supergraph_service(mock_service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("{ me { name } }")
+                    .context(context.clone())
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SubgraphRequest| {
+                assert_eq!(
+                    req.subgraph_request.body().extensions.get("locale"),
+                    Some(&Value::from("it"))
+                );
+                Ok(SubgraphResponse::fake_builder().build())
+            });
+        plugin()
+            .await
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(SubgraphRequest::fake_builder().context(context).build())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn the_preferred_language_has_the_highest_quality() {
+        assert_eq!(preferred_language("fr-CH, fr;q=0.9"), Some("fr-CH"));
+        assert_eq!(preferred_language("en;q=0.5, de;q=0.7, *"), Some("de"));
+        assert_eq!(preferred_language("*, en;q=0"), None);
+        assert_eq!(preferred_language(""), None);
+    }
+}
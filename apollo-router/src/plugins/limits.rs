@@ -0,0 +1,786 @@
+//! Operation limits.
+//!
+//! Rejects operations that are too large or too deeply nested before they are planned. The limits
+//! are measured on the operation that will be executed, with fragments expanded.
+//!
+//! Plans with too many fetches, or too long chains of fetches waiting for each other, are rejected
+//! before they are executed. A small operation can still have a plan fanning out to many subgraph
+//! requests.
+//!
+//! Responses over the size limit are replaced with an error pointing at the largest lists, so that
+//! clients know which fields to paginate.
+
+use std::collections::HashMap;
+use std::io;
+use std::ops::ControlFlow;
+
+use apollo_parser::ast;
+use apollo_parser::Lexer;
+use apollo_parser::TokenKind;
+use futures::StreamExt;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json_bytes::json;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::error::Error;
+use crate::graphql::Response;
+use crate::json_ext::Object;
+use crate::json_ext::Path;
+use crate::json_ext::PathElement;
+use crate::json_ext::Value;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::query_planner::QueryPlan;
+use crate::register_plugin;
+use crate::services::execution;
+use crate::services::supergraph;
+use crate::ExecutionRequest;
+use crate::ExecutionResponse;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+/// Operation limits. Operations over a limit are rejected before they are planned, or before
+/// they are executed for the limits on query plans
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Maximum nesting of fields in an operation, the root fields having a depth of 1
+    max_depth: Option<u32>,
+    /// Maximum number of fields selected by an operation
+    max_height: Option<u32>,
+    /// Maximum number of aliased fields in an operation
+    max_aliases: Option<u32>,
+    /// Maximum number of root fields in an operation
+    max_root_fields: Option<u32>,
+    /// Maximum number of tokens in a query string, checked before the query is parsed
+    max_tokens: Option<u32>,
+    /// Maximum size in bytes of a serialized response, checked on each response of a deferred
+    /// operation. Larger responses are replaced with an error listing the largest lists
+    max_response_size: Option<usize>,
+    /// Maximum number of subgraph fetches in a query plan
+    max_plan_fetches: Option<u32>,
+    /// Maximum number of subgraph fetches of a query plan executed one after the other, each
+    /// one waiting for the previous one
+    max_plan_depth: Option<u32>,
+}
+
+/// Context key set when a response was over the size limit.
+pub(crate) const RESPONSE_SIZE_EXCEEDED_CONTEXT_KEY: &str = "apollo_limits::response_size_exceeded";
+
+/// Number of lists reported when a response is over the size limit.
+const REPORTED_LISTS: usize = 5;
+
+/// What is measured on an operation. Fields selected through fragments are counted each time the
+/// fragment is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Measures {
+    depth: u32,
+    height: u64,
+    aliases: u64,
+    root_fields: u64,
+}
+
+impl Measures {
+    /// Combines the measures of the selections of a selection set.
+    fn add(&mut self, other: Measures) {
+        self.depth = self.depth.max(other.depth);
+        self.height = self.height.saturating_add(other.height);
+        self.aliases = self.aliases.saturating_add(other.aliases);
+        self.root_fields = self.root_fields.saturating_add(other.root_fields);
+    }
+}
+
+/// Measures selection sets, memoizing the measures of fragments, so that operations reusing
+/// fragments many times are measured in linear time.
+struct Measurer {
+    fragments: HashMap<String, ast::FragmentDefinition>,
+    measured: HashMap<String, Measures>,
+    /// Fragments being measured, to ignore cycles. Those operations fail validation later
+    in_progress: Vec<String>,
+}
+
+impl Measurer {
+    fn new(document: &ast::Document) -> Self {
+        let fragments = document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::FragmentDefinition(fragment) => {
+                    let name = fragment.fragment_name()?.name()?.text().to_string();
+                    Some((name, fragment))
+                }
+                _ => None,
+            })
+            .collect();
+        Measurer {
+            fragments,
+            measured: HashMap::new(),
+            in_progress: Vec::new(),
+        }
+    }
+
+    fn selection_set(&mut self, selection_set: Option<ast::SelectionSet>) -> Measures {
+        let mut measures = Measures::default();
+        for selection in selection_set.iter().flat_map(|set| set.selections()) {
+            let selection_measures = match selection {
+                ast::Selection::Field(field) => {
+                    let nested = self.selection_set(field.selection_set());
+                    Measures {
+                        depth: nested.depth + 1,
+                        height: nested.height.saturating_add(1),
+                        aliases: nested
+                            .aliases
+                            .saturating_add(u64::from(field.alias().is_some())),
+                        root_fields: 1,
+                    }
+                }
+                ast::Selection::InlineFragment(fragment) => {
+                    self.selection_set(fragment.selection_set())
+                }
+                ast::Selection::FragmentSpread(spread) => {
+                    match spread.fragment_name().and_then(|name| name.name()) {
+                        Some(name) => self.fragment(name.text().to_string()),
+                        None => Measures::default(),
+                    }
+                }
+            };
+            measures.add(selection_measures);
+        }
+        measures
+    }
+
+    fn fragment(&mut self, name: String) -> Measures {
+        if let Some(measures) = self.measured.get(&name) {
+            return *measures;
+        }
+        if self.in_progress.contains(&name) {
+            return Measures::default();
+        }
+        let selection_set = match self.fragments.get(&name) {
+            Some(fragment) => fragment.selection_set(),
+            None => return Measures::default(),
+        };
+
+        self.in_progress.push(name);
+        let measures = self.selection_set(selection_set);
+        let name = self.in_progress.pop().expect("pushed above; qed");
+        self.measured.insert(name, measures);
+        measures
+    }
+}
+
+/// Counts the tokens of a query string, ignoring whitespace, commas and comments.
+fn count_tokens(query: &str) -> usize {
+    Lexer::new(query)
+        .tokens()
+        .iter()
+        .filter(|token| {
+            !matches!(
+                token.kind(),
+                TokenKind::Whitespace | TokenKind::Comma | TokenKind::Comment | TokenKind::Eof
+            )
+        })
+        .count()
+}
+
+/// Measures the operation that will be executed. Returns `None` if the query can't be parsed or
+/// the operation is not found: the query planner reports those errors.
+fn measure(query: &str, operation_name: Option<&str>) -> Option<Measures> {
+    let tree = apollo_parser::Parser::new(query).parse();
+    if tree.errors().next().is_some() {
+        return None;
+    }
+    let document = tree.document();
+
+    let mut operations = document
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(operation),
+            _ => None,
+        });
+    let operation = match operation_name {
+        Some(operation_name) => operations.find(|operation| {
+            operation
+                .name()
+                .map(|name| name.text() == operation_name)
+                .unwrap_or_default()
+        })?,
+        None => operations.next()?,
+    };
+
+    Some(Measurer::new(&document).selection_set(operation.selection_set()))
+}
+
+/// Returns an error for the first limit the query is over.
+fn check(config: &Config, query: &str, operation_name: Option<&str>) -> Option<Error> {
+    let over_limit = |code: &str, message: String, limit: u32| {
+        Some(
+            Error::builder()
+                .message(message)
+                .extension("code", code)
+                .extension("limit", serde_json_bytes::Value::Number(limit.into()))
+                .build(),
+        )
+    };
+
+    if let Some(max_tokens) = config.max_tokens {
+        if count_tokens(query) > max_tokens as usize {
+            return over_limit(
+                "MAX_TOKENS_LIMIT",
+                format!("the query has more than {} tokens", max_tokens),
+                max_tokens,
+            );
+        }
+    }
+
+    if config.max_depth.is_none()
+        && config.max_height.is_none()
+        && config.max_aliases.is_none()
+        && config.max_root_fields.is_none()
+    {
+        return None;
+    }
+    let measures = measure(query, operation_name)?;
+
+    if let Some(max_depth) = config.max_depth {
+        if measures.depth > max_depth {
+            return over_limit(
+                "MAX_DEPTH_LIMIT",
+                format!("the operation is more than {} fields deep", max_depth),
+                max_depth,
+            );
+        }
+    }
+    if let Some(max_height) = config.max_height {
+        if measures.height > u64::from(max_height) {
+            return over_limit(
+                "MAX_HEIGHT_LIMIT",
+                format!("the operation selects more than {} fields", max_height),
+                max_height,
+            );
+        }
+    }
+    if let Some(max_aliases) = config.max_aliases {
+        if measures.aliases > u64::from(max_aliases) {
+            return over_limit(
+                "MAX_ALIASES_LIMIT",
+                format!("the operation has more than {} aliases", max_aliases),
+                max_aliases,
+            );
+        }
+    }
+    if let Some(max_root_fields) = config.max_root_fields {
+        if measures.root_fields > u64::from(max_root_fields) {
+            return over_limit(
+                "MAX_ROOT_FIELDS_LIMIT",
+                format!(
+                    "the operation has more than {} root fields",
+                    max_root_fields
+                ),
+                max_root_fields,
+            );
+        }
+    }
+    None
+}
+
+/// Returns an error for the first limit the query plan is over.
+fn check_plan(config: &Config, query_plan: &QueryPlan) -> Option<Error> {
+    let over_limit = |code: &str, message: String, limit: u32| {
+        Some(
+            Error::builder()
+                .message(message)
+                .extension("code", code)
+                .extension("limit", serde_json_bytes::Value::Number(limit.into()))
+                .build(),
+        )
+    };
+
+    if let Some(max_plan_fetches) = config.max_plan_fetches {
+        let fetches = query_plan.fetch_count();
+        if fetches > max_plan_fetches as usize {
+            return over_limit(
+                "MAX_PLAN_FETCHES_LIMIT",
+                format!(
+                    "the query plan has {} subgraph fetches, more than the limit of {}",
+                    fetches, max_plan_fetches
+                ),
+                max_plan_fetches,
+            );
+        }
+    }
+    if let Some(max_plan_depth) = config.max_plan_depth {
+        let depth = query_plan.sequential_depth();
+        if depth > max_plan_depth as usize {
+            return over_limit(
+                "MAX_PLAN_DEPTH_LIMIT",
+                format!(
+                    "the query plan has a chain of {} sequential subgraph fetches, more than the limit of {}",
+                    depth, max_plan_depth
+                ),
+                max_plan_depth,
+            );
+        }
+    }
+    None
+}
+
+/// Counts the bytes written to it, to measure serialized sizes without allocating.
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn serialized_size(value: &impl Serialize) -> usize {
+    let mut counter = ByteCounter::default();
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+/// Size of the lists found at a path, list items being flattened in the path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ListSize {
+    bytes: usize,
+    items: usize,
+}
+
+fn list_sizes(value: &Value, path: &mut Path, sizes: &mut HashMap<Path, ListSize>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                path.push(PathElement::Key(key.as_str().to_string()));
+                list_sizes(value, path, sizes);
+                path.pop();
+            }
+        }
+        Value::Array(items) => {
+            let size = sizes.entry(path.clone()).or_default();
+            size.bytes += serialized_size(value);
+            size.items += items.len();
+            path.push(PathElement::Flatten);
+            for item in items {
+                list_sizes(item, path, sizes);
+            }
+            path.pop();
+        }
+        _ => {}
+    }
+}
+
+/// Returns an error listing the largest lists of the response if it is over the size limit.
+fn check_response_size(max_response_size: usize, response: &Response) -> Option<Error> {
+    let size = serialized_size(response);
+    if size <= max_response_size {
+        return None;
+    }
+
+    let mut sizes = HashMap::new();
+    if let Some(data) = &response.data {
+        list_sizes(
+            data,
+            &mut response.path.clone().unwrap_or_default(),
+            &mut sizes,
+        );
+    }
+    for incremental in &response.incremental {
+        if let Some(data) = &incremental.data {
+            list_sizes(
+                data,
+                &mut incremental.path.clone().unwrap_or_default(),
+                &mut sizes,
+            );
+        }
+    }
+    let mut sizes: Vec<_> = sizes.into_iter().collect();
+    sizes.sort_by(|(path1, size1), (path2, size2)| {
+        size2
+            .bytes
+            .cmp(&size1.bytes)
+            .then_with(|| path1.to_string().cmp(&path2.to_string()))
+    });
+    let largest_lists: Vec<_> = sizes
+        .into_iter()
+        .take(REPORTED_LISTS)
+        .map(|(path, size)| {
+            json!({
+                "path": path.to_string(),
+                "bytes": size.bytes,
+                "items": size.items,
+            })
+        })
+        .collect();
+
+    Some(
+        Error::builder()
+            .message(format!(
+                "the response is larger than {} bytes, paginate the largest lists to reduce its size",
+                max_response_size
+            ))
+            .extension("code", "MAX_RESPONSE_SIZE_LIMIT")
+            .extension("limit", json!(max_response_size))
+            .extension("size", json!(size))
+            .extension("largest_lists", Value::Array(largest_lists))
+            .build(),
+    )
+}
+
+#[derive(Debug)]
+struct Limits {
+    config: Config,
+}
+
+#[async_trait::async_trait]
+impl Plugin for Limits {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(Limits {
+            config: init.config,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let config = self.config.clone();
+        let max_response_size = self.config.max_response_size;
+        ServiceBuilder::new()
+            .checkpoint(move |req: SupergraphRequest| {
+                let body = req.originating_request.body();
+                let error = body
+                    .query
+                    .as_deref()
+                    .and_then(|query| check(&config, query, body.operation_name.as_deref()));
+                match error {
+                    None => Ok(ControlFlow::Continue(req)),
+                    Some(error) => {
+                        let res = SupergraphResponse::builder()
+                            .error(error)
+                            .status_code(StatusCode::BAD_REQUEST)
+                            .context(req.context)
+                            .build()?;
+                        Ok(ControlFlow::Break(res))
+                    }
+                }
+            })
+            .map_response(move |mut res: SupergraphResponse| {
+                if let Some(max_response_size) = max_response_size {
+                    let context = res.context.clone();
+                    res.response = res.response.map(|stream| {
+                        stream
+                            .map(move |response| {
+                                match check_response_size(max_response_size, &response) {
+                                    None => response,
+                                    Some(error) => {
+                                        let _ = context
+                                            .insert(RESPONSE_SIZE_EXCEEDED_CONTEXT_KEY, true);
+                                        Response::builder()
+                                            .errors(vec![error])
+                                            .and_has_next(response.has_next)
+                                            .build()
+                                    }
+                                }
+                            })
+                            .boxed()
+                    });
+                }
+                res
+            })
+            .service(service)
+            .boxed()
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        if self.config.max_plan_fetches.is_none() && self.config.max_plan_depth.is_none() {
+            return service;
+        }
+        let config = self.config.clone();
+        ServiceBuilder::new()
+            .checkpoint(
+                move |req: ExecutionRequest| match check_plan(&config, &req.query_plan) {
+                    None => Ok(ControlFlow::Continue(req)),
+                    Some(error) => {
+                        let res = ExecutionResponse::builder()
+                            .error(error)
+                            .extensions(Object::new())
+                            .status_code(StatusCode::BAD_REQUEST)
+                            .context(req.context)
+                            .build();
+                        Ok(ControlFlow::Break(res))
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("apollo", "limits", Limits);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockExecutionService;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+
+    const QUERY: &str = r#"
+        query TopProducts {
+            topProducts {
+                upc
+                name
+                ...Reviews
+                reviews2: reviews { id }
+            }
+            me { id }
+        }
+
+        fragment Reviews on Product {
+            reviews {
+                id
+                author { id name }
+            }
+        }
+    "#;
+
+    #[test]
+    fn operations_are_measured() {
+        assert_eq!(
+            measure(QUERY, None),
+            Some(Measures {
+                depth: 4,
+                height: 12,
+                aliases: 1,
+                root_fields: 2,
+            })
+        );
+        assert_eq!(
+            measure("{ me { id } } query Other { a b c }", Some("Other"))
+                .unwrap()
+                .root_fields,
+            3
+        );
+        assert_eq!(measure("{ me { id }", None), None);
+    }
+
+    #[test]
+    fn fragments_are_measured_once() {
+        let mut query = "query { ...F0 }".to_string();
+        for i in 0..64 {
+            query.push_str(&format!(
+                " fragment F{} on Query {{ a: f{} {{ ...F{} }} b: f{} {{ ...F{} }} }}",
+                i,
+                i,
+                i + 1,
+                i,
+                i + 1
+            ));
+        }
+        query.push_str(" fragment F64 on Query { id }");
+
+        let measures = measure(&query, None).unwrap();
+        assert_eq!(measures.depth, 65);
+        assert_eq!(measures.height, u64::MAX);
+    }
+
+    #[test]
+    fn largest_lists_are_reported() {
+        let response = Response::builder()
+            .data(serde_json_bytes::json!({
+                "me": { "id": "1" },
+                "topProducts": [
+                    { "upc": "1", "reviews": [{ "id": "1" }, { "id": "2" }] },
+                    { "upc": "2", "reviews": [{ "id": "3" }] },
+                ]
+            }))
+            .build();
+        let size = serialized_size(&response);
+        assert_eq!(size, serde_json::to_vec(&response).unwrap().len());
+        assert!(check_response_size(size, &response).is_none());
+
+        let error = check_response_size(size - 1, &response).unwrap();
+        assert_eq!(
+            error.extensions.get("code"),
+            Some(&"MAX_RESPONSE_SIZE_LIMIT".into())
+        );
+        assert_eq!(
+            error.extensions.get("largest_lists"),
+            Some(&serde_json_bytes::json!([
+                { "path": "/topProducts", "bytes": 82, "items": 2 },
+                { "path": "/topProducts/@/reviews", "bytes": 35, "items": 3 },
+            ]))
+        );
+    }
+
+    #[test]
+    fn tokens_are_counted() {
+        assert_eq!(count_tokens("query Me { me { id, name } } # comment"), 9);
+    }
+
+    async fn plugin(config: serde_json::Value) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("apollo.limits")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn operations_within_limits_are_executed() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let response = plugin(json!({
+            "max_depth": 4,
+            "max_height": 12,
+            "max_aliases": 1,
+            "max_root_fields": 2,
+            "max_tokens": 100
+        }))
+        .await
+        .supergraph_service(mock_service.boxed())
+        .oneshot(
+            SupergraphRequest::fake_builder()
+                .query(QUERY)
+                .build()
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn operations_over_limits_are_rejected() {
+        for (config, code) in [
+            (json!({ "max_depth": 3 }), "MAX_DEPTH_LIMIT"),
+            (json!({ "max_height": 11 }), "MAX_HEIGHT_LIMIT"),
+            (json!({ "max_aliases": 0 }), "MAX_ALIASES_LIMIT"),
+            (json!({ "max_root_fields": 1 }), "MAX_ROOT_FIELDS_LIMIT"),
+            (json!({ "max_tokens": 10 }), "MAX_TOKENS_LIMIT"),
+        ] {
+            let mut response = plugin(config)
+                .await
+                .supergraph_service(MockSupergraphService::new().boxed())
+                .oneshot(
+                    SupergraphRequest::fake_builder()
+                        .query(QUERY)
+                        .build()
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.response.status(), StatusCode::BAD_REQUEST);
+            let body = response.next_response().await.unwrap();
+            assert_eq!(body.errors[0].extensions.get("code"), Some(&code.into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_over_the_size_limit_are_replaced() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                Ok(SupergraphResponse::fake_builder()
+                    .data(serde_json_bytes::json!({ "topProducts": [{ "upc": "1" }, { "upc": "2" }] }))
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let mut response = plugin(json!({ "max_response_size": 20 }))
+            .await
+            .supergraph_service(mock_service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query(QUERY)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.next_response().await.unwrap();
+        assert!(body.data.is_none());
+        assert_eq!(
+            body.errors[0].extensions.get("code"),
+            Some(&"MAX_RESPONSE_SIZE_LIMIT".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn plans_over_limits_are_rejected() {
+        let query_plan = || {
+            QueryPlan::fake_builder()
+                .root(
+                    serde_json::from_str(include_str!("../query_planner/testdata/query_plan.json"))
+                        .unwrap(),
+                )
+                .build()
+        };
+
+        let mut mock_service = MockExecutionService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: ExecutionRequest| {
+                Ok(ExecutionResponse::fake_builder()
+                    .context(req.context)
+                    .build())
+            });
+        let response = plugin(json!({ "max_plan_fetches": 5, "max_plan_depth": 3 }))
+            .await
+            .execution_service(mock_service.boxed())
+            .oneshot(
+                ExecutionRequest::fake_builder()
+                    .query_plan(query_plan())
+                    .build(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+
+        for (config, code) in [
+            (json!({ "max_plan_fetches": 4 }), "MAX_PLAN_FETCHES_LIMIT"),
+            (json!({ "max_plan_depth": 2 }), "MAX_PLAN_DEPTH_LIMIT"),
+        ] {
+            let mut response = plugin(config)
+                .await
+                .execution_service(MockExecutionService::new().boxed())
+                .oneshot(
+                    ExecutionRequest::fake_builder()
+                        .query_plan(query_plan())
+                        .build(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.response.status(), StatusCode::BAD_REQUEST);
+            let body = response.next_response().await.unwrap();
+            assert_eq!(body.errors[0].extensions.get("code"), Some(&code.into()));
+        }
+    }
+}
@@ -2,12 +2,36 @@
 //!
 //! These plugins are compiled into the router and configured via YAML configuration.
 
+mod access_log;
+mod context_propagation;
+#[cfg(feature = "http_server")]
 pub(crate) mod csrf;
+pub(crate) mod demand_control;
+mod dry_run;
+mod entity_cache;
 mod expose_query_plan;
+#[cfg(feature = "coprocessor")]
+mod external;
 mod forbid_mutations;
 mod headers;
 mod include_subgraph_errors;
+mod inflight_requests;
+mod inject_variables;
+pub(crate) mod limits;
+pub(crate) mod operation_tags;
+pub(crate) mod override_comparison;
 pub(crate) mod override_url;
+mod persisted_queries;
+mod quarantine;
+pub(crate) mod renames;
+mod request_journal;
+mod response_errors;
+mod response_sampling;
+mod response_scanning;
+mod response_transforms;
+#[cfg(feature = "rhai")]
 pub(crate) mod rhai;
+pub(crate) mod schema_drift;
+mod status_codes;
 pub(crate) mod telemetry;
 pub(crate) mod traffic_shaping;
@@ -0,0 +1,310 @@
+//! Operation tagging.
+//!
+//! Clients can tag their operations with a `routing` block in the request extensions, or with a
+//! header. Tags are validated against an allowlist, then made available in the context, where
+//! telemetry reads them as span attributes and traffic shaping applies rate limits per tag value,
+//! and optionally forwarded to subgraphs as headers.
+
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+
+use http::header::HeaderName;
+use http::HeaderValue;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::error::Error;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::serde::deserialize_option_header_name;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+use crate::SubgraphRequest;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+/// Prefix of the context entries holding the tags of an operation.
+const OPERATION_TAGS_CONTEXT_PREFIX: &str = "operation_tags::";
+const ROUTING_EXTENSION: &str = "routing";
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// The tags clients are allowed to send, with their accepted values.
+    /// An empty list of values accepts any value
+    #[serde(default)]
+    allowed: HashMap<String, Vec<String>>,
+    /// Also read tags from this header, formatted as `name=value` pairs separated by commas.
+    /// Tags from the `routing` extension take precedence
+    #[schemars(with = "Option<String>", default)]
+    #[serde(deserialize_with = "deserialize_option_header_name", default)]
+    header: Option<HeaderName>,
+    /// Send each tag to subgraphs in a header named with this prefix followed by the tag name
+    #[serde(default)]
+    subgraph_header_prefix: Option<String>,
+}
+
+#[derive(Debug)]
+struct OperationTags {
+    config: Config,
+}
+
+#[async_trait::async_trait]
+impl Plugin for OperationTags {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        if let Some(prefix) = &init.config.subgraph_header_prefix {
+            for name in init.config.allowed.keys() {
+                HeaderName::try_from(format!("{}{}", prefix, name))?;
+            }
+        }
+        Ok(OperationTags {
+            config: init.config,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let config = self.config.clone();
+        ServiceBuilder::new()
+            .checkpoint(move |req: SupergraphRequest| {
+                let tags = match extract_tags(&config, &req) {
+                    Ok(tags) => tags,
+                    Err(message) => {
+                        let error = Error::builder()
+                            .message(message)
+                            .extension("code", "INVALID_OPERATION_TAG")
+                            .build();
+                        let res = SupergraphResponse::builder()
+                            .error(error)
+                            .status_code(StatusCode::BAD_REQUEST)
+                            .context(req.context)
+                            .build()?;
+                        return Ok(ControlFlow::Break(res));
+                    }
+                };
+                for (name, value) in tags {
+                    req.context.insert(context_key(&name), value)?;
+                }
+                Ok(ControlFlow::Continue(req))
+            })
+            .service(service)
+            .boxed()
+    }
+
+    fn subgraph_service(&self, _name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let prefix = match &self.config.subgraph_header_prefix {
+            Some(prefix) => prefix.clone(),
+            None => return service,
+        };
+        let names: Vec<String> = self.config.allowed.keys().cloned().collect();
+        service
+            .map_request(move |mut req: SubgraphRequest| {
+                for name in &names {
+                    if let Some(value) = tag(&req.context, name) {
+                        if let (Ok(header_name), Ok(header_value)) = (
+                            HeaderName::try_from(format!("{}{}", prefix, name)),
+                            HeaderValue::try_from(value),
+                        ) {
+                            req.subgraph_request
+                                .headers_mut()
+                                .insert(header_name, header_value);
+                        }
+                    }
+                }
+                req
+            })
+            .boxed()
+    }
+}
+
+/// Returns the value of an operation tag, if the operation was tagged with it.
+pub(crate) fn tag(context: &Context, name: &str) -> Option<String> {
+    context.get(context_key(name)).ok().flatten()
+}
+
+/// The context entry holding the value of an operation tag.
+pub(crate) fn context_key(name: &str) -> String {
+    format!("{}{}", OPERATION_TAGS_CONTEXT_PREFIX, name)
+}
+
+fn extract_tags(
+    config: &Config,
+    req: &SupergraphRequest,
+) -> Result<HashMap<String, String>, String> {
+    let mut tags = HashMap::new();
+
+    if let Some(header_name) = &config.header {
+        for header in req.originating_request.headers().get_all(header_name) {
+            let header = header
+                .to_str()
+                .map_err(|_| format!("the {} header is not valid UTF-8", header_name))?;
+            for pair in header.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                let (name, value) = pair.split_once('=').ok_or_else(|| {
+                    format!("invalid operation tag '{}', expected name=value", pair)
+                })?;
+                tags.insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+    }
+
+    match req
+        .originating_request
+        .body()
+        .extensions
+        .get(ROUTING_EXTENSION)
+    {
+        None => {}
+        Some(Value::Object(routing)) => {
+            for (name, value) in routing {
+                let value = value.as_str().ok_or_else(|| {
+                    format!(
+                        "the value of operation tag '{}' must be a string",
+                        name.as_str()
+                    )
+                })?;
+                tags.insert(name.as_str().to_string(), value.to_string());
+            }
+        }
+        Some(_) => return Err("the routing extension must be an object".to_string()),
+    }
+
+    for (name, value) in &tags {
+        match config.allowed.get(name) {
+            None => return Err(format!("operation tag '{}' is not allowed", name)),
+            Some(values) if !values.is_empty() && !values.contains(value) => {
+                return Err(format!(
+                    "value '{}' is not allowed for operation tag '{}'",
+                    value, name
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok(tags)
+}
+
+register_plugin!("experimental", "operation_tags", OperationTags);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+    use crate::SubgraphResponse;
+
+    async fn plugin() -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.operation_tags")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({
+                "allowed": {
+                    "priority": ["low", "high"],
+                    "team": []
+                },
+                "header": "x-operation-tags",
+                "subgraph_header_prefix": "x-tag-"
+            }))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tags_are_added_to_context() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                assert_eq!(tag(&req.context, "priority"), Some("high".to_string()));
+                assert_eq!(tag(&req.context, "team"), Some("checkout".to_string()));
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let request = SupergraphRequest::fake_builder()
+            .header("x-operation-tags", "priority=low, team=checkout")
+            .extension("routing", serde_json_bytes::json!({ "priority": "high" }))
+            .build()
+            .unwrap();
+        plugin()
+            .await
+            .supergraph_service(mock_service.boxed())
+            .oneshot(request)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_tags_are_rejected() {
+        for request in [
+            SupergraphRequest::fake_builder()
+                .extension("routing", serde_json_bytes::json!({ "priority": "urgent" }))
+                .build()
+                .unwrap(),
+            SupergraphRequest::fake_builder()
+                .extension(
+                    "routing",
+                    serde_json_bytes::json!({ "feature": "new-checkout" }),
+                )
+                .build()
+                .unwrap(),
+            SupergraphRequest::fake_builder()
+                .header("x-operation-tags", "priority")
+                .build()
+                .unwrap(),
+        ] {
+            let mut response = plugin()
+                .await
+                .supergraph_service(MockSupergraphService::new().boxed())
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(response.response.status(), StatusCode::BAD_REQUEST);
+            let body = response.next_response().await.unwrap();
+            assert_eq!(
+                body.errors[0].extensions.get("code"),
+                Some(&"INVALID_OPERATION_TAG".into())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn tags_are_sent_to_subgraphs() {
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SubgraphRequest| {
+                assert_eq!(
+                    req.subgraph_request.headers().get("x-tag-priority"),
+                    Some(&HeaderValue::from_static("high"))
+                );
+                assert!(req.subgraph_request.headers().get("x-tag-team").is_none());
+                Ok(SubgraphResponse::fake_builder().build())
+            });
+
+        let context = Context::new();
+        context.insert(context_key("priority"), "high").unwrap();
+        plugin()
+            .await
+            .subgraph_service("products", mock_service.boxed())
+            .oneshot(SubgraphRequest::fake_builder().context(context).build())
+            .await
+            .unwrap();
+    }
+}
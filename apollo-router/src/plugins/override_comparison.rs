@@ -0,0 +1,889 @@
+//! Comparison of overridden fields during `@override` migrations.
+//!
+//! When a field moves from a subgraph to another with `@override(from:)`, the supergraph schema
+//! records the subgraph the field was overridden from. For a sample of the fetches selecting an
+//! overridden field, the router sends the same request to that subgraph, and compares the values
+//! of the overridden fields in both responses. Clients always get the response of the subgraph
+//! owning the field. Outcomes are counted in the `override_comparison_total` metric, and
+//! divergences are logged as warnings.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use apollo_parser::ast;
+use futures::future::join;
+use futures::future::join_all;
+use http::Uri;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::json_ext::Path;
+use crate::json_ext::PathElement;
+use crate::json_ext::Value;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::schema_drift::Sampler;
+use crate::query_planner::OperationKind;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::services::SubgraphService;
+use crate::spec::FieldType;
+use crate::Context;
+use crate::SubgraphRequest;
+
+/// Context key holding the outcomes of the comparisons of each overridden field.
+pub(crate) const OVERRIDE_COMPARISON_CONTEXT_KEY: &str = "apollo_override_comparison::outcomes";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Configuration for the comparison of overridden fields
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Fraction of the queries to subgraphs owning overridden fields that are compared, between 0
+    /// and 1 (default: 0.01)
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time to wait for the subgraph a field was overridden from (default: 1s). Sampled fetches
+    /// wait for both subgraphs
+    timeout: Option<Duration>,
+}
+
+fn default_sample_rate() -> f64 {
+    0.01
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    /// Both subgraphs returned the same values
+    Match,
+    /// The subgraphs returned different values
+    Divergence,
+    /// The subgraph the field was overridden from did not return a response in time, or returned
+    /// errors
+    Error,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Match => "match",
+            Outcome::Divergence => "divergence",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+/// What the supergraph schema declares about overridden fields.
+#[derive(Debug, Default)]
+struct Overrides {
+    root_types: HashMap<OperationKind, String>,
+    /// Named type of the fields of object types and interfaces
+    field_types: HashMap<String, HashMap<String, String>>,
+    /// Overridden fields by coordinate (`Type.field`), with the subgraph they were overridden
+    /// from, for each subgraph owning overridden fields
+    overridden: HashMap<String, HashMap<String, String>>,
+    urls: HashMap<String, Uri>,
+}
+
+impl Overrides {
+    fn new(schema: &str) -> Result<Self, BoxError> {
+        let tree = apollo_parser::Parser::new(schema).parse();
+        if let Some(error) = tree.errors().next() {
+            return Err(format!("could not parse the schema: {}", error.message()).into());
+        }
+
+        let mut overrides = Overrides::default();
+        // Values of the join__Graph enum, with the name of their subgraph
+        let mut graphs = HashMap::new();
+        // Coordinate, join__Graph value owning the field, and subgraph it was overridden from
+        let mut overridden = Vec::new();
+        for definition in tree.document().definitions() {
+            let (type_name, fields) = match definition {
+                ast::Definition::SchemaDefinition(schema) => {
+                    for operation in schema.root_operation_type_definitions() {
+                        if let (Some(kind), Some(name)) = (
+                            operation.operation_type(),
+                            operation.named_type().and_then(|n| n.name()),
+                        ) {
+                            overrides
+                                .root_types
+                                .insert(kind.into(), name.text().to_string());
+                        }
+                    }
+                    continue;
+                }
+                ast::Definition::EnumTypeDefinition(d)
+                    if d.name().map(|n| n.text() == "join__Graph") == Some(true) =>
+                {
+                    for value in d
+                        .enum_values_definition()
+                        .iter()
+                        .flat_map(|values| values.enum_value_definitions())
+                    {
+                        let graph = match value.enum_value().and_then(|v| v.name()) {
+                            Some(graph) => graph.text().to_string(),
+                            None => continue,
+                        };
+                        let name = string_argument(value.directives(), "join__graph", "name");
+                        let url = string_argument(value.directives(), "join__graph", "url");
+                        if let (Some(name), Some(url)) = (name, url) {
+                            let url = Uri::from_str(&url)
+                                .map_err(|e| format!("invalid URL for subgraph {}: {}", name, e))?;
+                            overrides.urls.insert(name.clone(), url);
+                            graphs.insert(graph, name);
+                        }
+                    }
+                    continue;
+                }
+                ast::Definition::ObjectTypeDefinition(d) => (d.name(), d.fields_definition()),
+                ast::Definition::ObjectTypeExtension(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeDefinition(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeExtension(d) => (d.name(), d.fields_definition()),
+                _ => continue,
+            };
+            let type_name = match type_name {
+                Some(name) => name.text().to_string(),
+                None => continue,
+            };
+
+            let field_types = overrides.field_types.entry(type_name.clone()).or_default();
+            for field in fields.iter().flat_map(|fields| fields.field_definitions()) {
+                let (name, ty) = match (field.name(), field.ty()) {
+                    (Some(name), Some(ty)) => (name.text().to_string(), FieldType::from(ty)),
+                    _ => continue,
+                };
+                if let Some(named_type) = named_type(&ty) {
+                    field_types.insert(name.clone(), named_type.to_string());
+                }
+                for directive in field
+                    .directives()
+                    .iter()
+                    .flat_map(|directives| directives.directives())
+                    .filter(|d| d.name().map(|n| n.text() == "join__field") == Some(true))
+                {
+                    let mut graph = None;
+                    let mut from = None;
+                    for argument in directive
+                        .arguments()
+                        .iter()
+                        .flat_map(|arguments| arguments.arguments())
+                    {
+                        match (
+                            argument.name().map(|n| n.text().to_string()),
+                            argument.value(),
+                        ) {
+                            (Some(name), Some(ast::Value::EnumValue(value))) if name == "graph" => {
+                                graph = value.name().map(|n| n.text().to_string());
+                            }
+                            (Some(name), Some(ast::Value::StringValue(value)))
+                                if name == "override" =>
+                            {
+                                from = Some(String::from(value));
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let (Some(graph), Some(from)) = (graph, from) {
+                        overridden.push((format!("{}.{}", type_name, name), graph, from));
+                    }
+                }
+            }
+        }
+
+        for (coordinate, graph, from) in overridden {
+            // Fields overridden from a subgraph that is not in the supergraph anymore can't be
+            // compared
+            match graphs.get(&graph) {
+                Some(subgraph) if overrides.urls.contains_key(&from) => {
+                    overrides
+                        .overridden
+                        .entry(subgraph.clone())
+                        .or_default()
+                        .insert(coordinate, from);
+                }
+                _ => {}
+            }
+        }
+        Ok(overrides)
+    }
+
+    fn root_type(&self, kind: OperationKind) -> String {
+        self.root_types
+            .get(&kind)
+            .cloned()
+            .unwrap_or_else(|| kind.to_string())
+    }
+
+    fn field_type(&self, type_name: &str, field_name: &str) -> Option<&str> {
+        if field_name == "_entities" {
+            // Federation's entry point to the entities of a subgraph is not in the supergraph
+            return Some("_Entity");
+        }
+        self.field_types
+            .get(type_name)?
+            .get(field_name)
+            .map(|t| t.as_str())
+    }
+}
+
+/// Returns the value of a string argument of a directive.
+fn string_argument(
+    directives: Option<ast::Directives>,
+    directive: &str,
+    argument: &str,
+) -> Option<String> {
+    directives
+        .iter()
+        .flat_map(|directives| directives.directives())
+        .filter(|d| d.name().map(|n| n.text() == directive).unwrap_or_default())
+        .flat_map(|d| d.arguments())
+        .flat_map(|arguments| arguments.arguments())
+        .filter(|a| a.name().map(|n| n.text() == argument).unwrap_or_default())
+        .find_map(|a| match a.value() {
+            Some(ast::Value::StringValue(value)) => Some(String::from(value)),
+            _ => None,
+        })
+}
+
+fn named_type(field_type: &FieldType) -> Option<&str> {
+    match field_type {
+        FieldType::Named(name) => Some(name.as_str()),
+        FieldType::List(inner) | FieldType::NonNull(inner) => named_type(inner),
+        _ => None,
+    }
+}
+
+/// An overridden field selected by a fetch.
+#[derive(Clone, Debug, PartialEq)]
+struct SelectedField {
+    coordinate: String,
+    type_name: String,
+    /// Subgraph the field was overridden from
+    from: String,
+    /// Response keys leading to the field, ending with the key of the field
+    keys: Vec<String>,
+}
+
+/// Walks the selections of a fetch, looking for overridden fields.
+struct Selector<'a> {
+    overrides: &'a Overrides,
+    overridden: &'a HashMap<String, String>,
+    fragments: HashMap<String, ast::FragmentDefinition>,
+    keys: Vec<String>,
+    selected: Vec<SelectedField>,
+}
+
+/// Returns the overridden fields selected by a fetch to a subgraph. Queries that can't be parsed
+/// don't select any field.
+fn selected_fields(
+    overrides: &Overrides,
+    subgraph: &str,
+    query: &str,
+    operation_name: Option<&str>,
+    kind: OperationKind,
+) -> Vec<SelectedField> {
+    let overridden = match overrides.overridden.get(subgraph) {
+        Some(overridden) => overridden,
+        None => return Vec::new(),
+    };
+    let tree = apollo_parser::Parser::new(query).parse();
+    if tree.errors().next().is_some() {
+        return Vec::new();
+    }
+    let document = tree.document();
+
+    let mut operations = document
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(operation),
+            _ => None,
+        });
+    let operation = match operation_name {
+        Some(operation_name) => operations.find(|operation| {
+            operation
+                .name()
+                .map(|name| name.text() == operation_name)
+                .unwrap_or_default()
+        }),
+        None => operations.next(),
+    };
+    let selection_set = match operation.and_then(|o| o.selection_set()) {
+        Some(selection_set) => selection_set,
+        None => return Vec::new(),
+    };
+
+    let mut selector = Selector {
+        overrides,
+        overridden,
+        fragments: document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::FragmentDefinition(fragment) => {
+                    let name = fragment.fragment_name()?.name()?.text().to_string();
+                    Some((name, fragment))
+                }
+                _ => None,
+            })
+            .collect(),
+        keys: Vec::new(),
+        selected: Vec::new(),
+    };
+    selector.selection_set(&overrides.root_type(kind), selection_set);
+    selector.selected
+}
+
+impl<'a> Selector<'a> {
+    fn selection_set(&mut self, type_name: &str, selection_set: ast::SelectionSet) {
+        for selection in selection_set.selections() {
+            let (type_condition, selection_set) = match selection {
+                ast::Selection::Field(field) => {
+                    let name = match field.name() {
+                        Some(name) => name.text().to_string(),
+                        None => continue,
+                    };
+                    let key = field
+                        .alias()
+                        .and_then(|alias| alias.name())
+                        .map(|alias| alias.text().to_string())
+                        .unwrap_or_else(|| name.clone());
+                    let coordinate = format!("{}.{}", type_name, name);
+
+                    let overrides = self.overrides;
+                    self.keys.push(key);
+                    if let Some(from) = self.overridden.get(&coordinate) {
+                        self.selected.push(SelectedField {
+                            coordinate,
+                            type_name: type_name.to_string(),
+                            from: from.clone(),
+                            keys: self.keys.clone(),
+                        });
+                    } else if let (Some(field_type), Some(selection_set)) = (
+                        overrides.field_type(type_name, &name),
+                        field.selection_set(),
+                    ) {
+                        self.selection_set(field_type, selection_set);
+                    }
+                    self.keys.pop();
+                    continue;
+                }
+                ast::Selection::InlineFragment(fragment) => {
+                    (fragment.type_condition(), fragment.selection_set())
+                }
+                ast::Selection::FragmentSpread(spread) => {
+                    let fragment = match spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .and_then(|name| self.fragments.get(&name.text().to_string()))
+                    {
+                        Some(fragment) => fragment.clone(),
+                        None => continue,
+                    };
+                    (fragment.type_condition(), fragment.selection_set())
+                }
+            };
+            let type_condition = type_condition
+                .and_then(|t| t.named_type())
+                .and_then(|t| t.name())
+                .map(|name| name.text().to_string())
+                .unwrap_or_else(|| type_name.to_string());
+            if let Some(selection_set) = selection_set {
+                self.selection_set(&type_condition, selection_set);
+            }
+        }
+    }
+}
+
+/// Returns the paths where the values of an overridden field differ between two responses.
+fn divergences(field: &SelectedField, data: &Value, other: &Value) -> Vec<Path> {
+    let mut divergences = Vec::new();
+    compare(
+        &field.keys,
+        &field.type_name,
+        data,
+        other,
+        &mut Path::empty(),
+        &mut divergences,
+    );
+    divergences
+}
+
+fn compare(
+    keys: &[String],
+    type_name: &str,
+    value: &Value,
+    other: &Value,
+    path: &mut Path,
+    divergences: &mut Vec<Path>,
+) {
+    match (keys.split_first(), value, other) {
+        (Some(_), Value::Array(values), Value::Array(others)) if values.len() == others.len() => {
+            for (index, (value, other)) in values.iter().zip(others).enumerate() {
+                path.push(PathElement::Index(index));
+                compare(keys, type_name, value, other, path, divergences);
+                path.pop();
+            }
+        }
+        (Some((key, rest)), Value::Object(object), Value::Object(other_object)) => {
+            if rest.is_empty() {
+                // The field is only selected on objects of its type
+                let typename = object.get("__typename").and_then(|t| t.as_str());
+                if typename.map(|t| t != type_name).unwrap_or_default() {
+                    return;
+                }
+            }
+            path.push(PathElement::Key(key.clone()));
+            compare(
+                rest,
+                type_name,
+                object.get(key.as_str()).unwrap_or(&Value::Null),
+                other_object.get(key.as_str()).unwrap_or(&Value::Null),
+                path,
+                divergences,
+            );
+            path.pop();
+        }
+        (_, value, other) => {
+            if value != other {
+                divergences.push(path.clone());
+            }
+        }
+    }
+}
+
+/// Copies a subgraph request, sending it to another subgraph.
+fn shadow_request(req: &SubgraphRequest, uri: Uri) -> SubgraphRequest {
+    let mut subgraph_request = http::Request::new(req.subgraph_request.body().clone());
+    *subgraph_request.method_mut() = req.subgraph_request.method().clone();
+    *subgraph_request.uri_mut() = uri;
+    *subgraph_request.headers_mut() = req.subgraph_request.headers().clone();
+    SubgraphRequest::builder()
+        .originating_request(req.originating_request.clone())
+        .subgraph_request(subgraph_request)
+        .operation_kind(req.operation_kind)
+        .context(Context::new())
+        .build()
+}
+
+/// Creates the services sending requests to the subgraphs fields were overridden from.
+type Clients = Arc<dyn Fn(&str) -> Option<subgraph::BoxService> + Send + Sync>;
+
+/// The comparison of the overridden fields of a fetch with a subgraph they were overridden from.
+struct Comparison {
+    from: String,
+    fields: Vec<SelectedField>,
+    request: SubgraphRequest,
+    client: subgraph::BoxService,
+}
+
+impl Comparison {
+    /// Returns the outcome of the comparison for each field.
+    async fn run(self, timeout: Duration) -> (String, Vec<SelectedField>, Result<Value, String>) {
+        let response = match tokio::time::timeout(timeout, self.client.oneshot(self.request)).await
+        {
+            Ok(Ok(response)) => {
+                let body = response.response.into_body();
+                match (body.errors.first(), body.data) {
+                    (None, Some(data)) => Ok(data),
+                    (Some(error), _) => Err(error.message.clone()),
+                    (None, None) => Err("the response has no data".to_string()),
+                }
+            }
+            Ok(Err(error)) => Err(error.to_string()),
+            Err(_) => Err(format!("no response after {:?}", timeout)),
+        };
+        (self.from, self.fields, response)
+    }
+}
+
+struct OverrideComparison {
+    overrides: Arc<Overrides>,
+    sampler: Arc<Sampler>,
+    timeout: Duration,
+    clients: Clients,
+}
+
+#[async_trait::async_trait]
+impl Plugin for OverrideComparison {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let overrides = Overrides::new(&init.supergraph_sdl)?;
+        let clients: HashMap<String, SubgraphService> = overrides
+            .overridden
+            .values()
+            .flat_map(|overridden| overridden.values())
+            .map(|from| (from.clone(), SubgraphService::new(from.as_str())))
+            .collect();
+        Ok(OverrideComparison {
+            overrides: Arc::new(overrides),
+            sampler: Arc::new(Sampler::new(init.config.sample_rate)?),
+            timeout: init.config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            clients: Arc::new(move |name: &str| {
+                clients.get(name).map(|client| client.clone().boxed())
+            }),
+        })
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        if !self.overrides.overridden.contains_key(name) {
+            return service;
+        }
+        let overrides = self.overrides.clone();
+        let sampler = self.sampler.clone();
+        let clients = self.clients.clone();
+        let timeout = self.timeout;
+        let name = name.to_string();
+        let subgraph = name.clone();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SubgraphRequest| {
+                    // Mutations are never sent twice
+                    if req.operation_kind != OperationKind::Query || !sampler.sample() {
+                        return Vec::new();
+                    }
+                    let body = req.subgraph_request.body();
+                    let query = match &body.query {
+                        Some(query) => query,
+                        None => return Vec::new(),
+                    };
+                    let mut fields_by_subgraph: HashMap<String, Vec<SelectedField>> =
+                        HashMap::new();
+                    for field in selected_fields(
+                        &overrides,
+                        &subgraph,
+                        query,
+                        body.operation_name.as_deref(),
+                        req.operation_kind,
+                    ) {
+                        fields_by_subgraph
+                            .entry(field.from.clone())
+                            .or_default()
+                            .push(field);
+                    }
+                    fields_by_subgraph
+                        .into_iter()
+                        .filter_map(|(from, fields)| {
+                            Some(Comparison {
+                                request: shadow_request(req, overrides.urls.get(&from)?.clone()),
+                                client: clients(&from)?,
+                                from,
+                                fields,
+                            })
+                        })
+                        .collect()
+                },
+                move |comparisons: Vec<Comparison>, f| {
+                    let name = name.clone();
+                    async move {
+                        let comparisons = comparisons
+                            .into_iter()
+                            .map(|comparison| comparison.run(timeout));
+                        let (res, comparisons) = join(f, join_all(comparisons)).await;
+                        let res: subgraph::ServiceResult = res;
+                        let res = match res {
+                            Ok(res) => res,
+                            Err(err) => return Err(err),
+                        };
+                        // Fetches that fail in the subgraph owning the fields are not compared
+                        if !res.response.body().errors.is_empty() {
+                            return Ok(res);
+                        }
+                        let data = res.response.body().data.clone().unwrap_or_default();
+
+                        let mut outcomes = Vec::new();
+                        for (from, fields, other) in comparisons {
+                            let other = match other {
+                                Ok(other) => other,
+                                Err(error) => {
+                                    tracing::warn!(
+                                        subgraph = %name,
+                                        overridden_subgraph = %from,
+                                        "could not compare overridden fields: {}",
+                                        error
+                                    );
+                                    outcomes.extend(
+                                        fields.into_iter().map(|f| (f.coordinate, Outcome::Error)),
+                                    );
+                                    continue;
+                                }
+                            };
+                            for field in fields {
+                                let divergences = divergences(&field, &data, &other);
+                                for path in &divergences {
+                                    tracing::warn!(
+                                        subgraph = %name,
+                                        overridden_subgraph = %from,
+                                        field = %field.coordinate,
+                                        path = %path,
+                                        "the value of an overridden field differs between subgraphs"
+                                    );
+                                }
+                                let outcome = if divergences.is_empty() {
+                                    Outcome::Match
+                                } else {
+                                    Outcome::Divergence
+                                };
+                                outcomes.push((field.coordinate, outcome));
+                            }
+                        }
+                        if outcomes.is_empty() {
+                            return Ok(res);
+                        }
+
+                        let _ = res.context.upsert(
+                            OVERRIDE_COMPARISON_CONTEXT_KEY,
+                            |mut counts: HashMap<String, HashMap<String, u64>>| {
+                                for (coordinate, outcome) in &outcomes {
+                                    *counts
+                                        .entry(coordinate.clone())
+                                        .or_default()
+                                        .entry(outcome.as_str().to_string())
+                                        .or_default() += 1;
+                                }
+                                counts
+                            },
+                        );
+                        Ok(res)
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "override_comparison", OverrideComparison);
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::SubgraphResponse;
+
+    const SCHEMA: &str = r#"
+        schema { query: Query }
+        directive @join__field(graph: join__Graph!, requires: join__FieldSet, provides: join__FieldSet, type: String, external: Boolean, override: String, usedOverridden: Boolean) repeatable on FIELD_DEFINITION | INPUT_FIELD_DEFINITION
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        scalar join__FieldSet
+        enum join__Graph {
+            INVENTORY @join__graph(name: "inventory", url: "http://inventory/graphql")
+            PRODUCTS @join__graph(name: "products", url: "http://products/graphql")
+        }
+        type Query {
+            topProducts: [Product] @join__field(graph: PRODUCTS)
+        }
+        type Product {
+            upc: String!
+            name: String @join__field(graph: PRODUCTS)
+            inStock: Boolean @join__field(graph: INVENTORY, override: "products")
+            shippingEstimate: Int @join__field(graph: INVENTORY, override: "shipping")
+        }
+        type Book {
+            isbn: String!
+            inStock: Boolean @join__field(graph: INVENTORY)
+        }
+    "#;
+
+    const ENTITIES_QUERY: &str = "query($representations:[_Any!]!){_entities(representations:$representations){...on Product{upc stock:inStock} ...on Book{inStock}}}";
+
+    fn overrides() -> Overrides {
+        Overrides::new(SCHEMA).unwrap()
+    }
+
+    #[test]
+    fn overridden_fields_are_read_from_the_schema() {
+        let overrides = overrides();
+        // shipping is not in the supergraph anymore
+        assert_eq!(
+            overrides.overridden,
+            [(
+                "inventory".to_string(),
+                [("Product.inStock".to_string(), "products".to_string())]
+                    .into_iter()
+                    .collect()
+            )]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!(
+            overrides.urls["products"],
+            Uri::from_static("http://products/graphql")
+        );
+    }
+
+    #[test]
+    fn overridden_fields_are_found_in_fetches() {
+        let overrides = overrides();
+        let selected = selected_fields(
+            &overrides,
+            "inventory",
+            ENTITIES_QUERY,
+            None,
+            OperationKind::Query,
+        );
+        assert_eq!(
+            selected,
+            vec![SelectedField {
+                coordinate: "Product.inStock".to_string(),
+                type_name: "Product".to_string(),
+                from: "products".to_string(),
+                keys: vec!["_entities".to_string(), "stock".to_string()],
+            }]
+        );
+
+        assert_eq!(
+            selected_fields(
+                &overrides,
+                "inventory",
+                "fragment F on Product { inStock } { topProducts { ...F } }",
+                None,
+                OperationKind::Query,
+            )[0]
+            .keys,
+            vec!["topProducts".to_string(), "inStock".to_string()]
+        );
+        assert!(selected_fields(
+            &overrides,
+            "products",
+            "{ topProducts { name } }",
+            None,
+            OperationKind::Query,
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn divergences_are_found_in_lists() {
+        let field = SelectedField {
+            coordinate: "Product.inStock".to_string(),
+            type_name: "Product".to_string(),
+            from: "products".to_string(),
+            keys: vec!["_entities".to_string(), "inStock".to_string()],
+        };
+        let data = json!({ "_entities": [
+            { "__typename": "Product", "inStock": true },
+            { "__typename": "Product", "inStock": false },
+            { "__typename": "Book", "inStock": true },
+        ]});
+        assert!(divergences(&field, &data, &data).is_empty());
+
+        let other = json!({ "_entities": [
+            { "__typename": "Product", "inStock": true },
+            { "__typename": "Product", "inStock": true },
+            { "__typename": "Book", "inStock": false },
+        ]});
+        assert_eq!(
+            divergences(&field, &data, &other),
+            vec![Path::from("_entities/1/inStock")]
+        );
+        assert_eq!(
+            divergences(&field, &data, &json!({ "_entities": null })),
+            vec![Path::from("_entities")]
+        );
+    }
+
+    async fn outcomes(primary: Value, overridden: Option<Value>) -> HashMap<String, u64> {
+        let mut plugin = OverrideComparison::new(PluginInit::new(
+            serde_json::from_value(serde_json::json!({ "sample_rate": 1.0 })).unwrap(),
+            Arc::new(SCHEMA.to_string()),
+        ))
+        .await
+        .unwrap();
+        plugin.clients = Arc::new(move |name: &str| {
+            assert_eq!(name, "products");
+            let overridden = overridden.clone();
+            Some(
+                tower::service_fn(move |req: SubgraphRequest| {
+                    assert_eq!(
+                        req.subgraph_request.uri(),
+                        &Uri::from_static("http://products/graphql")
+                    );
+                    let response = match overridden.clone() {
+                        Some(data) => SubgraphResponse::fake_builder()
+                            .data(data)
+                            .context(req.context)
+                            .build(),
+                        None => SubgraphResponse::fake_builder()
+                            .error(
+                                crate::error::Error::builder()
+                                    .message("unknown field")
+                                    .build(),
+                            )
+                            .context(req.context)
+                            .build(),
+                    };
+                    async move { Ok::<_, BoxError>(response) }
+                })
+                .boxed(),
+            )
+        });
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(move |req: SubgraphRequest| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(primary.clone())
+                    .context(req.context)
+                    .build())
+            });
+
+        let response = plugin
+            .subgraph_service("inventory", mock_service.boxed())
+            .oneshot(
+                SubgraphRequest::fake_builder()
+                    .subgraph_request(
+                        http::Request::builder()
+                            .body(
+                                crate::graphql::Request::builder()
+                                    .query(ENTITIES_QUERY)
+                                    .build(),
+                            )
+                            .unwrap(),
+                    )
+                    .build(),
+            )
+            .await
+            .unwrap();
+
+        let mut counts: HashMap<String, HashMap<String, u64>> = response
+            .context
+            .get(OVERRIDE_COMPARISON_CONTEXT_KEY)
+            .unwrap()
+            .unwrap();
+        counts.remove("Product.inStock").unwrap()
+    }
+
+    #[tokio::test]
+    async fn overridden_fields_are_compared() {
+        let data = json!({ "_entities": [{ "upc": "1", "stock": true }] });
+        assert_eq!(
+            outcomes(data.clone(), Some(data.clone())).await,
+            [("match".to_string(), 1)].into_iter().collect()
+        );
+        assert_eq!(
+            outcomes(
+                data.clone(),
+                Some(json!({ "_entities": [{ "upc": "1", "stock": false }] }))
+            )
+            .await,
+            [("divergence".to_string(), 1)].into_iter().collect()
+        );
+        assert_eq!(
+            outcomes(data, None).await,
+            [("error".to_string(), 1)].into_iter().collect()
+        );
+    }
+}
@@ -2,8 +2,11 @@
 
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use http::Uri;
+use schemars::JsonSchema;
+use serde::Deserialize;
 use tower::BoxError;
 use tower::ServiceExt;
 
@@ -13,22 +16,124 @@ use crate::register_plugin;
 use crate::services::subgraph;
 use crate::SubgraphRequest;
 
+/// URL of a subgraph, replacing the routing URL of the supergraph
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum SubgraphUrl {
+    /// URL of all the requests to the subgraph
+    Static(url::Url),
+    /// URL picked for each request from a header or a context entry
+    Dynamic(Route),
+}
+
+/// URLs of a subgraph, by value of a header or a context entry
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Route {
+    /// Header of the client request holding the route
+    #[serde(default)]
+    header: Option<String>,
+    /// Context entry holding the route, like one set by a Rhai script or a custom plugin
+    #[serde(default)]
+    context: Option<String>,
+    /// URL of each route
+    urls: HashMap<String, url::Url>,
+    /// URL of the requests without a known route (default: the routing URL of the supergraph)
+    #[serde(default)]
+    default: Option<url::Url>,
+}
+
+#[derive(Debug, Clone)]
+enum Routing {
+    Static(Uri),
+    Dynamic {
+        source: RouteSource,
+        uris: HashMap<String, Uri>,
+        default: Option<Uri>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum RouteSource {
+    Header(String),
+    Context(String),
+}
+
+impl Routing {
+    fn new(subgraph_name: &str, url: SubgraphUrl) -> Result<Self, BoxError> {
+        let uri = |url: url::Url| Uri::from_str(url.as_str());
+        Ok(match url {
+            SubgraphUrl::Static(url) => Routing::Static(uri(url)?),
+            SubgraphUrl::Dynamic(route) => Routing::Dynamic {
+                source: match (route.header, route.context) {
+                    (Some(header), None) => RouteSource::Header(header),
+                    (None, Some(context)) => RouteSource::Context(context),
+                    _ => {
+                        return Err(format!(
+                            "the route of subgraph '{}' must be read from either a header or a context entry",
+                            subgraph_name
+                        )
+                        .into())
+                    }
+                },
+                uris: route
+                    .urls
+                    .into_iter()
+                    .map(|(route, url)| Ok((route, uri(url)?)))
+                    .collect::<Result<_, BoxError>>()?,
+                default: route.default.map(uri).transpose()?,
+            },
+        })
+    }
+
+    /// The URI of a request, `None` to keep the routing URL of the supergraph.
+    fn uri(&self, request: &SubgraphRequest) -> Option<Uri> {
+        match self {
+            Routing::Static(uri) => Some(uri.clone()),
+            Routing::Dynamic {
+                source,
+                uris,
+                default,
+            } => {
+                let route = match source {
+                    RouteSource::Header(name) => request
+                        .originating_request
+                        .headers()
+                        .get(name.as_str())
+                        .and_then(|value| value.to_str().ok())
+                        .map(String::from),
+                    RouteSource::Context(key) => {
+                        request.context.get::<_, String>(key).ok().flatten()
+                    }
+                };
+                route
+                    .and_then(|route| uris.get(&route))
+                    .or_else(|| default.as_ref())
+                    .cloned()
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct OverrideSubgraphUrl {
-    urls: HashMap<String, Uri>,
+    routes: HashMap<String, Arc<Routing>>,
 }
 
 #[async_trait::async_trait]
 impl Plugin for OverrideSubgraphUrl {
-    type Config = HashMap<String, url::Url>;
+    type Config = HashMap<String, SubgraphUrl>;
 
     async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
         Ok(OverrideSubgraphUrl {
-            urls: init
+            routes: init
                 .config
                 .into_iter()
-                .map(|(k, v)| (k, Uri::from_str(v.as_str()).unwrap()))
-                .collect(),
+                .map(|(name, url)| {
+                    let routing = Routing::new(&name, url)?;
+                    Ok((name, Arc::new(routing)))
+                })
+                .collect::<Result<_, BoxError>>()?,
         })
     }
 
@@ -37,11 +142,14 @@ impl Plugin for OverrideSubgraphUrl {
         subgraph_name: &str,
         service: subgraph::BoxService,
     ) -> subgraph::BoxService {
-        let new_url = self.urls.get(subgraph_name).cloned();
+        let routing = match self.routes.get(subgraph_name) {
+            Some(routing) => routing.clone(),
+            None => return service,
+        };
         service
             .map_request(move |mut req: SubgraphRequest| {
-                if let Some(new_url) = new_url.clone() {
-                    *req.subgraph_request.uri_mut() = new_url;
+                if let Some(uri) = routing.uri(&req) {
+                    req.route_to(uri);
                 }
 
                 req
@@ -55,6 +163,7 @@ register_plugin!("apollo", "override_subgraph_url", OverrideSubgraphUrl);
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use http::Uri;
     use serde_json::Value;
@@ -112,4 +221,97 @@ mod tests {
             .await
             .unwrap();
     }
+
+    async fn routed_uri(subgraph_name: &str, request: SubgraphRequest) -> Uri {
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(move |req: SubgraphRequest| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(serde_json_bytes::Value::from(
+                        req.subgraph_request.uri().to_string(),
+                    ))
+                    .context(req.context)
+                    .build())
+            });
+
+        let dyn_plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .get("apollo.override_subgraph_url")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&serde_json::json!({
+                "products": {
+                    "header": "x-shard",
+                    "urls": {
+                        "eu": "http://products-eu:4001/graphql",
+                        "us": "http://products-us:4001/graphql"
+                    }
+                },
+                "reviews": {
+                    "context": "tenant",
+                    "urls": { "acme": "http://reviews-acme:4002" },
+                    "default": "http://reviews:4002"
+                }
+            }))
+            .await
+            .unwrap();
+        let response = dyn_plugin
+            .subgraph_service(subgraph_name, BoxService::new(mock_service))
+            .oneshot(request)
+            .await
+            .unwrap();
+        Uri::from_str(
+            response
+                .response
+                .into_body()
+                .data
+                .unwrap()
+                .as_str()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn routes_are_picked_for_each_request() {
+        let request = |shard: &str| {
+            SubgraphRequest::fake_builder()
+                .originating_request(Arc::new(
+                    http::Request::builder()
+                        .header("x-shard", shard)
+                        .body(Default::default())
+                        .unwrap(),
+                ))
+                .subgraph_request(
+                    http::Request::builder()
+                        .uri("http://products:4001/graphql")
+                        .body(Default::default())
+                        .unwrap(),
+                )
+                .build()
+        };
+        assert_eq!(
+            routed_uri("products", request("eu")).await,
+            "http://products-eu:4001/graphql"
+        );
+        assert_eq!(
+            routed_uri("products", request("asia")).await,
+            "http://products:4001/graphql"
+        );
+
+        let context = Context::new();
+        context.insert("tenant", "acme").unwrap();
+        assert_eq!(
+            routed_uri(
+                "reviews",
+                SubgraphRequest::fake_builder().context(context).build()
+            )
+            .await,
+            "http://reviews-acme:4002/"
+        );
+        assert_eq!(
+            routed_uri("reviews", SubgraphRequest::fake_builder().build()).await,
+            "http://reviews:4002/"
+        );
+    }
 }
@@ -0,0 +1,597 @@
+//! Persisted queries safelist.
+//!
+//! Only operations listed in a persisted query manifest are executed. Operations are identified by
+//! the SHA-256 hash of their query string, the same hash clients send with automatic persisted
+//! queries, so clients using APQ keep working as long as their operations are in the manifest.
+//!
+//! In capture mode, every operation is executed, and the distinct operations are recorded in a
+//! manifest file or sent to an HTTP endpoint, to build the safelist from real traffic.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use apollo_parser::ast;
+use futures::FutureExt;
+use http::StatusCode;
+use router_bridge::planner::UsageReporting;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+use url::Url;
+
+use crate::error::Error;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::telemetry::CLIENT_NAME;
+use crate::query_planner::OperationKind;
+use crate::query_planner::USAGE_REPORTING;
+use crate::register_plugin;
+use crate::services::supergraph;
+use crate::Context;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+/// Configuration for the persisted queries safelist
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Persisted query manifest listing the operations clients are allowed to execute, required
+    /// unless operations are captured
+    #[serde(default)]
+    manifest: Option<PathBuf>,
+    /// What to do with operations that are not in the manifest (default: enforce)
+    #[serde(default)]
+    mode: Mode,
+    /// Where the executed operations are recorded, in capture mode
+    #[serde(default)]
+    capture: Option<CaptureConfig>,
+}
+
+/// Destinations of the captured operations
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CaptureConfig {
+    /// File the captured operations are written to, as a persisted query manifest
+    #[serde(default)]
+    path: Option<PathBuf>,
+    /// URL each new operation, or operation used by a new client, is posted to as JSON
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    endpoint: Option<Url>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Mode {
+    /// Reject operations that are not in the manifest
+    Enforce,
+    /// Execute operations that are not in the manifest, and log them
+    LogOnly,
+    /// Execute every operation, and record the distinct operations
+    Capture,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Enforce
+    }
+}
+
+/// A persisted query manifest, as generated by `@apollo/generate-persisted-query-manifest`.
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    format: String,
+    version: u32,
+    operations: Vec<ManifestOperation>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestOperation {
+    body: String,
+}
+
+impl Manifest {
+    const FORMAT: &'static str = "apollo-persisted-query-manifest";
+    const VERSION: u32 = 1;
+
+    /// Returns the hashes of the operations of the manifest.
+    fn parse(manifest: &str) -> Result<HashSet<String>, BoxError> {
+        let manifest: Manifest = serde_json::from_str(manifest)?;
+        if manifest.format != Self::FORMAT || manifest.version != Self::VERSION {
+            return Err(format!(
+                "unsupported manifest format {} version {}, expected {} version {}",
+                manifest.format,
+                manifest.version,
+                Self::FORMAT,
+                Self::VERSION
+            )
+            .into());
+        }
+        Ok(manifest
+            .operations
+            .iter()
+            .map(|operation| hash(&operation.body))
+            .collect())
+    }
+}
+
+fn hash(query: &str) -> String {
+    hex::encode(Sha256::digest(query.as_bytes()))
+}
+
+/// An executed operation, in the format of the operations of a persisted query manifest, with
+/// the operation signature reported to Apollo Studio and the names of the clients using it.
+#[derive(Clone, Debug, Serialize)]
+struct CapturedOperation {
+    id: String,
+    name: Option<String>,
+    #[serde(rename = "type")]
+    kind: String,
+    body: String,
+    signature: String,
+    clients: BTreeSet<String>,
+}
+
+#[derive(Serialize)]
+struct CapturedManifest<'a> {
+    format: &'static str,
+    version: u32,
+    operations: Vec<&'a CapturedOperation>,
+}
+
+/// Records the distinct operations, and the clients using them.
+#[derive(Debug)]
+struct Capture {
+    clients: Mutex<HashMap<String, BTreeSet<String>>>,
+    sender: mpsc::UnboundedSender<CapturedOperation>,
+}
+
+impl Capture {
+    fn record(&self, query: &str, operation_name: Option<&str>, context: &Context) {
+        let usage_reporting = match context
+            .get::<_, UsageReporting>(USAGE_REPORTING)
+            .unwrap_or_default()
+        {
+            Some(usage_reporting) => usage_reporting,
+            None => return,
+        };
+        // Operations that could not be planned have a key starting with `## `
+        let signature = match usage_reporting.stats_report_key.strip_prefix("# ") {
+            Some(key) => key.split_once('\n').map(|(_, signature)| signature),
+            None => None,
+        };
+        let signature = match signature {
+            Some(signature) => signature.to_string(),
+            None => return,
+        };
+        let id = hash(query);
+        let client = context
+            .get::<_, String>(CLIENT_NAME)
+            .unwrap_or_default()
+            .unwrap_or_default();
+
+        let clients = {
+            let mut captured = self.clients.lock().expect("lock poisoned");
+            let clients = captured.entry(id.clone()).or_default();
+            if !clients.insert(client) {
+                return;
+            }
+            clients.clone()
+        };
+        tracing::debug!(operation_hash = %id, "captured an operation");
+        let _ = self.sender.send(CapturedOperation {
+            id,
+            name: operation_name.map(str::to_string),
+            kind: operation_kind(query, operation_name)
+                .to_string()
+                .to_lowercase(),
+            body: query.to_string(),
+            signature,
+            clients: clients
+                .into_iter()
+                .filter(|client| !client.is_empty())
+                .collect(),
+        });
+    }
+}
+
+/// The kind of the executed operation of a query that was planned.
+fn operation_kind(query: &str, operation_name: Option<&str>) -> OperationKind {
+    let tree = apollo_parser::Parser::new(query).parse();
+    let document = tree.document();
+    let mut operations = document
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(operation),
+            _ => None,
+        });
+    let operation = match operation_name {
+        Some(operation_name) => operations.find(|operation| {
+            operation
+                .name()
+                .map(|name| name.text() == operation_name)
+                .unwrap_or_default()
+        }),
+        None => operations.next(),
+    };
+    operation
+        .and_then(|operation| operation.operation_type())
+        .map(Into::into)
+        .unwrap_or_default()
+}
+
+/// Sends the captured operations to their destinations, from a task.
+fn spawn_capture_writer(config: CaptureConfig) -> mpsc::UnboundedSender<CapturedOperation> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<CapturedOperation>();
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut operations = BTreeMap::new();
+        while let Some(operation) = receiver.recv().await {
+            if let Some(endpoint) = &config.endpoint {
+                if let Err(e) = client
+                    .post(endpoint.clone())
+                    .json(&operation)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                {
+                    tracing::warn!("could not send a captured operation to {}: {}", endpoint, e);
+                }
+            }
+            operations.insert(operation.id.clone(), operation);
+            if let Some(path) = &config.path {
+                if let Err(e) = write_manifest(path, operations.values().collect()).await {
+                    tracing::warn!(
+                        "could not write the captured operations to {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    });
+    sender
+}
+
+/// Replaces the manifest file, so that it is never read partially written.
+async fn write_manifest(path: &Path, operations: Vec<&CapturedOperation>) -> Result<(), BoxError> {
+    let manifest = serde_json::to_vec_pretty(&CapturedManifest {
+        format: Manifest::FORMAT,
+        version: Manifest::VERSION,
+        operations,
+    })?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, manifest).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct PersistedQueries {
+    mode: Mode,
+    safelist: Arc<HashSet<String>>,
+    capture: Option<Arc<Capture>>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for PersistedQueries {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let config = init.config;
+        let capture = match (config.mode, config.capture) {
+            (Mode::Capture, Some(capture))
+                if capture.path.is_some() || capture.endpoint.is_some() =>
+            {
+                Some(Arc::new(Capture {
+                    clients: Default::default(),
+                    sender: spawn_capture_writer(capture),
+                }))
+            }
+            (Mode::Capture, _) => {
+                return Err("the capture mode needs a capture path or endpoint".into())
+            }
+            (_, Some(_)) => return Err("operations are only captured in the capture mode".into()),
+            (_, None) => None,
+        };
+
+        let safelist = match &config.manifest {
+            Some(path) => {
+                let manifest = tokio::fs::read_to_string(path).await.map_err(|e| {
+                    format!(
+                        "could not read the persisted query manifest {}: {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+                Manifest::parse(&manifest).map_err(|e| {
+                    format!("invalid persisted query manifest {}: {}", path.display(), e)
+                })?
+            }
+            None if config.mode == Mode::Capture => HashSet::new(),
+            None => return Err("a persisted query manifest is required".into()),
+        };
+        tracing::debug!("loaded {} persisted queries", safelist.len());
+
+        Ok(PersistedQueries {
+            mode: config.mode,
+            safelist: Arc::new(safelist),
+            capture,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if let Some(capture) = &self.capture {
+            let capture = capture.clone();
+            return ServiceBuilder::new()
+                .map_future_with_request_data(
+                    |req: &SupergraphRequest| {
+                        let body = req.originating_request.body();
+                        (
+                            body.query.clone(),
+                            body.operation_name.clone(),
+                            req.context.clone(),
+                        )
+                    },
+                    move |(query, operation_name, context): (
+                        Option<String>,
+                        Option<String>,
+                        Context,
+                    ),
+                          fut| {
+                        let capture = capture.clone();
+                        fut.map(move |result| {
+                            // The query was planned once the service responds
+                            if let Some(query) = &query {
+                                capture.record(query, operation_name.as_deref(), &context);
+                            }
+                            result
+                        })
+                    },
+                )
+                .service(service)
+                .boxed();
+        }
+
+        let mode = self.mode;
+        let safelist = self.safelist.clone();
+        ServiceBuilder::new()
+            .checkpoint(move |req: SupergraphRequest| {
+                let body = req.originating_request.body();
+                // Requests without a query are rejected later in the pipeline
+                let query_hash = match &body.query {
+                    Some(query) => hash(query),
+                    None => return Ok(ControlFlow::Continue(req)),
+                };
+                if safelist.contains(&query_hash) {
+                    return Ok(ControlFlow::Continue(req));
+                }
+
+                if mode == Mode::LogOnly {
+                    tracing::warn!(
+                        operation_name = ?body.operation_name,
+                        operation_hash = %query_hash,
+                        "executing an operation that is not in the persisted query manifest"
+                    );
+                    return Ok(ControlFlow::Continue(req));
+                }
+
+                let error = Error::builder()
+                    .message("the operation is not in the list of persisted queries")
+                    .extension("code", "QUERY_NOT_IN_SAFELIST")
+                    .build();
+                let res = SupergraphResponse::builder()
+                    .error(error)
+                    .status_code(StatusCode::BAD_REQUEST)
+                    .context(req.context)
+                    .build()?;
+                Ok(ControlFlow::Break(res))
+            })
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "persisted_queries", PersistedQueries);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+
+    const MANIFEST: &str = r#"{
+        "format": "apollo-persisted-query-manifest",
+        "version": 1,
+        "operations": [
+            {
+                "id": "2e3e49f19828396c35f7f9415f5558239d37f5d85b674851f58bf43337f5aaab",
+                "name": "Me",
+                "type": "query",
+                "body": "query Me { me { id } }"
+            }
+        ]
+    }"#;
+
+    async fn plugin(mode: &str) -> (tempfile::TempDir, Box<dyn DynPlugin>) {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("manifest.json");
+        std::fs::write(&manifest, MANIFEST).unwrap();
+        let plugin = crate::plugin::plugins()
+            .get("experimental.persisted_queries")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({ "manifest": manifest, "mode": mode }))
+            .await
+            .unwrap();
+        (dir, plugin)
+    }
+
+    fn executed_service(times: usize) -> supergraph::BoxService {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(times)
+            .returning(|req: SupergraphRequest| {
+                Ok(SupergraphResponse::fake_builder()
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+        mock_service.boxed()
+    }
+
+    fn request(query: &str) -> SupergraphRequest {
+        SupergraphRequest::fake_builder()
+            .query(query)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_persisted_queries_are_executed() {
+        let (_dir, plugin) = plugin("enforce").await;
+
+        let response = plugin
+            .supergraph_service(executed_service(1))
+            .oneshot(request("query Me { me { id } }"))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+
+        let mut response = plugin
+            .supergraph_service(executed_service(0))
+            .oneshot(request("query Me { me { id name } }"))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::BAD_REQUEST);
+        let body = response.next_response().await.unwrap();
+        assert_eq!(
+            body.errors[0].extensions.get("code"),
+            Some(&"QUERY_NOT_IN_SAFELIST".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_queries_are_executed_in_log_only_mode() {
+        let (_dir, plugin) = plugin("log_only").await;
+
+        let response = plugin
+            .supergraph_service(executed_service(1))
+            .oneshot(request("query Me { me { id name } }"))
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn executed_operations_are_captured() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("captured.json");
+        let plugin = crate::plugin::plugins()
+            .get("experimental.persisted_queries")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({
+                "mode": "capture",
+                "capture": { "path": path }
+            }))
+            .await
+            .unwrap();
+
+        for client in ["web", "ios", "web"] {
+            let mut mock_service = MockSupergraphService::new();
+            mock_service
+                .expect_call()
+                .times(1)
+                .returning(|req: SupergraphRequest| {
+                    req.context
+                        .insert(
+                            USAGE_REPORTING,
+                            UsageReporting {
+                                stats_report_key: "# Me\nquery Me{me{id}}".to_string(),
+                                referenced_fields_by_type: Default::default(),
+                            },
+                        )
+                        .unwrap();
+                    Ok(SupergraphResponse::fake_builder()
+                        .context(req.context)
+                        .build()
+                        .unwrap())
+                });
+            let request = SupergraphRequest::fake_builder()
+                .query("query Me { me { id name } }")
+                .operation_name("Me")
+                .build()
+                .unwrap();
+            request
+                .context
+                .insert(CLIENT_NAME, client.to_string())
+                .unwrap();
+            plugin
+                .supergraph_service(mock_service.boxed())
+                .oneshot(request)
+                .await
+                .unwrap();
+        }
+
+        let expected = json!({
+            "format": "apollo-persisted-query-manifest",
+            "version": 1,
+            "operations": [{
+                "id": hash("query Me { me { id name } }"),
+                "name": "Me",
+                "type": "query",
+                "body": "query Me { me { id name } }",
+                "signature": "query Me{me{id}}",
+                "clients": ["ios", "web"]
+            }]
+        });
+        for _ in 0..100 {
+            if let Ok(manifest) = std::fs::read_to_string(&path) {
+                if serde_json::from_str::<serde_json::Value>(&manifest).unwrap() == expected {
+                    // The captured manifest can be used as a safelist
+                    assert_eq!(Manifest::parse(&manifest).unwrap().len(), 1);
+                    return;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("the operation was not captured");
+    }
+
+    #[tokio::test]
+    async fn capture_needs_a_destination() {
+        let result = crate::plugin::plugins()
+            .get("experimental.persisted_queries")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({ "mode": "capture" }))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn manifest_format_is_checked() {
+        assert_eq!(Manifest::parse(MANIFEST).unwrap().len(), 1);
+        assert!(Manifest::parse(r#"{"format":"other","version":1,"operations":[]}"#).is_err());
+        assert!(Manifest::parse(
+            r#"{"format":"apollo-persisted-query-manifest","version":2,"operations":[]}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,424 @@
+//! Quarantine of operations that repeatedly degrade the router.
+//!
+//! Operations are identified by the hash of their query and their operation name. An operation
+//! fails when it times out, panics, or produces a response over the size limit of the `limits`
+//! plugin. An operation failing too many times within a window is quarantined: it is rejected
+//! right away until the end of the cool-down period, and an error event is logged.
+
+use std::collections::VecDeque;
+use std::ops::ControlFlow;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::FutureExt;
+use futures::StreamExt;
+use http::header::RETRY_AFTER;
+use http::StatusCode;
+use lru::LruCache;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json_bytes::json;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::error::Error;
+use crate::graphql;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::limits::RESPONSE_SIZE_EXCEEDED_CONTEXT_KEY;
+use crate::plugins::traffic_shaping::Elapsed;
+use crate::register_plugin;
+use crate::services::supergraph;
+use crate::Context;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+const OPERATION_CONTEXT_KEY: &str = "apollo_quarantine::operation";
+
+const DEFAULT_MAX_FAILURES: u32 = 3;
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(300);
+/// Number of operations whose failures are tracked, the least recently failed being forgotten.
+const TRACKED_OPERATIONS: usize = 10_000;
+
+/// Configuration for the quarantine of operations
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Number of failures within the window that quarantine an operation (default: 3)
+    max_failures: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Window in which the failures of an operation are counted (default: 1m)
+    window: Option<Duration>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long a quarantined operation is rejected (default: 5m)
+    cooldown: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    /// The router or a subgraph timed out, or the request was cancelled before its response
+    Timeout,
+    Panic,
+    /// The response was over the size limit
+    Oversized,
+}
+
+impl Failure {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Failure::Timeout => "timeout",
+            Failure::Panic => "panic",
+            Failure::Oversized => "oversized",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Record {
+    failures: VecDeque<Instant>,
+    quarantined_until: Option<Instant>,
+}
+
+struct Tracker {
+    max_failures: usize,
+    window: Duration,
+    cooldown: Duration,
+    operations: Mutex<LruCache<String, Record>>,
+}
+
+impl Tracker {
+    /// Remaining time of the quarantine of an operation, if it is quarantined.
+    fn quarantined(&self, operation: &str) -> Option<Duration> {
+        let operation = operation.to_string();
+        let mut operations = self.operations.lock().unwrap();
+        let until = operations.peek(&operation)?.quarantined_until?;
+        match until.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => Some(remaining),
+            _ => {
+                if let Some(record) = operations.peek_mut(&operation) {
+                    record.quarantined_until = None;
+                }
+                None
+            }
+        }
+    }
+
+    fn record(&self, operation: &str, operation_name: Option<&str>, failure: Failure) {
+        let now = Instant::now();
+        let key = operation.to_string();
+        let mut operations = self.operations.lock().unwrap();
+        if !operations.contains(&key) {
+            operations.put(key.clone(), Record::default());
+        }
+        let record = operations.get_mut(&key).expect("inserted above; qed");
+        if record.quarantined_until.is_some() {
+            return;
+        }
+        while let Some(first) = record.failures.front() {
+            if now.duration_since(*first) < self.window {
+                break;
+            }
+            record.failures.pop_front();
+        }
+        record.failures.push_back(now);
+        tracing::debug!(
+            operation,
+            operation_name,
+            failure = failure.as_str(),
+            "operation failed"
+        );
+
+        if record.failures.len() >= self.max_failures {
+            record.failures.clear();
+            record.quarantined_until = Some(now + self.cooldown);
+            tracing::error!(
+                operation,
+                operation_name,
+                failure = failure.as_str(),
+                failures = self.max_failures,
+                cooldown_secs = self.cooldown.as_secs(),
+                "quarantining an operation after repeated failures"
+            );
+        }
+    }
+}
+
+/// Records the failure of an operation when dropped, once its response is fully sent or the
+/// request is cancelled.
+struct Outcome {
+    tracker: Arc<Tracker>,
+    operation: String,
+    operation_name: Option<String>,
+    context: Context,
+    failure: Option<Failure>,
+}
+
+impl Drop for Outcome {
+    fn drop(&mut self) {
+        let oversized = matches!(
+            self.context.get(RESPONSE_SIZE_EXCEEDED_CONTEXT_KEY),
+            Ok(Some(true))
+        );
+        let failure = self
+            .failure
+            .or_else(|| oversized.then(|| Failure::Oversized));
+        if let Some(failure) = failure {
+            self.tracker
+                .record(&self.operation, self.operation_name.as_deref(), failure);
+        }
+    }
+}
+
+fn is_timeout(error: &BoxError) -> bool {
+    error.is::<Elapsed>()
+        || error
+            .source()
+            .map(|source| source.is::<Elapsed>())
+            .unwrap_or_default()
+}
+
+fn has_subgraph_timeout(response: &graphql::Response) -> bool {
+    response.errors.iter().any(|error| {
+        error.extensions.get("type").and_then(|t| t.as_str()) == Some("SubrequestTimeout")
+    })
+}
+
+struct Quarantine {
+    tracker: Arc<Tracker>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for Quarantine {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let max_failures = init.config.max_failures.unwrap_or(DEFAULT_MAX_FAILURES);
+        if max_failures == 0 {
+            return Err("max_failures must be at least 1".into());
+        }
+        Ok(Quarantine {
+            tracker: Arc::new(Tracker {
+                max_failures: max_failures as usize,
+                window: init.config.window.unwrap_or(DEFAULT_WINDOW),
+                cooldown: init.config.cooldown.unwrap_or(DEFAULT_COOLDOWN),
+                operations: Mutex::new(LruCache::new(TRACKED_OPERATIONS)),
+            }),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let check_tracker = self.tracker.clone();
+        let outcome_tracker = self.tracker.clone();
+        ServiceBuilder::new()
+            .checkpoint(move |req: SupergraphRequest| {
+                let body = req.originating_request.body();
+                let operation = match &body.query {
+                    Some(query) => format!(
+                        "{}:{}",
+                        hex::encode(Sha256::digest(query.as_bytes())),
+                        body.operation_name.as_deref().unwrap_or_default()
+                    ),
+                    None => return Ok(ControlFlow::Continue(req)),
+                };
+                let remaining = match check_tracker.quarantined(&operation) {
+                    Some(remaining) => remaining,
+                    None => {
+                        let _ = req.context.insert(OPERATION_CONTEXT_KEY, operation);
+                        return Ok(ControlFlow::Continue(req));
+                    }
+                };
+
+                let retry_after = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                let error = Error::builder()
+                    .message(format!(
+                        "the operation is quarantined after repeated failures, retry after {} seconds",
+                        retry_after
+                    ))
+                    .extension("code", "OPERATION_QUARANTINED")
+                    .extension("retry_after", json!(retry_after))
+                    .build();
+                let res = SupergraphResponse::builder()
+                    .error(error)
+                    .status_code(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(RETRY_AFTER, retry_after.to_string())
+                    .context(req.context)
+                    .build()?;
+                Ok(ControlFlow::Break(res))
+            })
+            .map_future_with_request_data(
+                move |req: &SupergraphRequest| {
+                    let operation: Option<String> =
+                        req.context.get(OPERATION_CONTEXT_KEY).ok().flatten();
+                    operation.map(|operation| Outcome {
+                        tracker: outcome_tracker.clone(),
+                        operation,
+                        operation_name: req.originating_request.body().operation_name.clone(),
+                        context: req.context.clone(),
+                        // Until the response is produced, dropping the request is a timeout
+                        failure: Some(Failure::Timeout),
+                    })
+                },
+                |outcome: Option<Outcome>, f| async move {
+                    let mut outcome = match outcome {
+                        Some(outcome) => outcome,
+                        None => return f.await,
+                    };
+                    let res: supergraph::ServiceResult =
+                        match AssertUnwindSafe(f).catch_unwind().await {
+                            Ok(res) => res,
+                            Err(panic) => {
+                                outcome.failure = Some(Failure::Panic);
+                                drop(outcome);
+                                std::panic::resume_unwind(panic)
+                            }
+                        };
+                    let mut res = match res {
+                        Ok(res) => res,
+                        Err(err) => {
+                            outcome.failure = is_timeout(&err).then(|| Failure::Timeout);
+                            return Err(err);
+                        }
+                    };
+
+                    // The outcome is recorded when the response stream is dropped
+                    outcome.failure = None;
+                    res.response = res.response.map(|stream| {
+                        stream
+                            .map(move |response| {
+                                if has_subgraph_timeout(&response) {
+                                    outcome.failure = Some(Failure::Timeout);
+                                }
+                                response
+                            })
+                            .boxed()
+                    });
+                    Ok(res)
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "quarantine", Quarantine);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+
+    const QUERY: &str = "{ me { id } }";
+
+    async fn plugin(config: serde_json::Value) -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.quarantine")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn operations_are_quarantined_after_repeated_failures() {
+        let tracker = Tracker {
+            max_failures: 2,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(60),
+            operations: Mutex::new(LruCache::new(10)),
+        };
+        tracker.record("a", None, Failure::Timeout);
+        assert!(tracker.quarantined("a").is_none());
+        tracker.record("b", None, Failure::Panic);
+        assert!(tracker.quarantined("b").is_none());
+        tracker.record("a", None, Failure::Oversized);
+        let remaining = tracker.quarantined("a").unwrap();
+        assert!(remaining > Duration::from_secs(59));
+        assert!(tracker.quarantined("b").is_none());
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let tracker = Tracker {
+            max_failures: 2,
+            window: Duration::from_millis(10),
+            cooldown: Duration::from_secs(60),
+            operations: Mutex::new(LruCache::new(10)),
+        };
+        tracker.record("a", None, Failure::Timeout);
+        std::thread::sleep(Duration::from_millis(20));
+        tracker.record("a", None, Failure::Timeout);
+        assert!(tracker.quarantined("a").is_none());
+    }
+
+    #[tokio::test]
+    async fn operations_timing_out_are_quarantined() {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(2)
+            .returning(|req: SupergraphRequest| {
+                Ok(SupergraphResponse::fake_builder()
+                    .error(
+                        Error::builder()
+                            .message("request to 'reviews' timed out")
+                            .extension("type", "SubrequestTimeout")
+                            .build(),
+                    )
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+        let mut service = plugin(json!({ "max_failures": 2 }))
+            .await
+            .supergraph_service(mock_service.boxed());
+
+        for _ in 0..2 {
+            let mut response = service
+                .ready()
+                .await
+                .unwrap()
+                .call(
+                    SupergraphRequest::fake_builder()
+                        .query(QUERY)
+                        .build()
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert!(response.next_response().await.is_some());
+        }
+
+        let mut response = service
+            .ready()
+            .await
+            .unwrap()
+            .call(
+                SupergraphRequest::fake_builder()
+                    .query(QUERY)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.response.headers().contains_key(RETRY_AFTER));
+        let body = response.next_response().await.unwrap();
+        assert_eq!(
+            body.errors[0].extensions.get("code"),
+            Some(&"OPERATION_QUARANTINED".into())
+        );
+    }
+}
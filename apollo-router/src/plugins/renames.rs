@@ -0,0 +1,392 @@
+//! Renames of schema members in the operations of clients.
+//!
+//! When a type or a field of the schema is renamed, clients still sending operations with the old
+//! name would fail validation. The operations are translated before they are validated and
+//! planned: renamed types are replaced with their new name, and renamed fields are replaced with
+//! their new name, aliased with the old one so that responses keep their shape. Each use of a
+//! rename is counted in the `renames_total` metric, to follow the migration of the clients.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
+
+use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::query_planner::OperationKind;
+use crate::register_plugin;
+use crate::services::supergraph;
+use crate::spec::FieldType;
+use crate::SupergraphRequest;
+
+/// Context key holding the number of uses of each rename by the operation, by old name.
+pub(crate) const RENAMES_CONTEXT_KEY: &str = "apollo_renames::uses";
+
+/// Configuration for the renames of schema members in the operations of clients
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// New names of the renamed types, by old name
+    #[serde(default)]
+    types: HashMap<String, String>,
+    /// New names of the renamed fields, by old coordinate like `User.fullName`, whose type has
+    /// its new name
+    #[serde(default)]
+    fields: HashMap<String, String>,
+}
+
+/// The renames, and what the schema declares about the types they apply to.
+#[derive(Debug, Default)]
+struct Renames {
+    types: HashMap<String, String>,
+    fields: HashMap<String, String>,
+    root_types: HashMap<OperationKind, String>,
+    /// Types defined by the schema
+    defined_types: HashSet<String>,
+    /// Fields of the object types and interfaces
+    field_types: HashMap<String, HashMap<String, FieldType>>,
+}
+
+impl Renames {
+    fn new(schema: &str, config: Config) -> Result<Self, BoxError> {
+        let tree = apollo_parser::Parser::new(schema).parse();
+        if let Some(error) = tree.errors().next() {
+            return Err(format!("could not parse the schema: {}", error.message()).into());
+        }
+
+        let mut renames = Renames {
+            types: config.types,
+            fields: config.fields,
+            ..Default::default()
+        };
+        for definition in tree.document().definitions() {
+            let (name, fields) = match definition {
+                ast::Definition::SchemaDefinition(schema) => {
+                    for operation in schema.root_operation_type_definitions() {
+                        if let (Some(kind), Some(name)) = (
+                            operation.operation_type(),
+                            operation.named_type().and_then(|n| n.name()),
+                        ) {
+                            renames
+                                .root_types
+                                .insert(kind.into(), name.text().to_string());
+                        }
+                    }
+                    continue;
+                }
+                ast::Definition::ObjectTypeDefinition(d) => (d.name(), d.fields_definition()),
+                ast::Definition::ObjectTypeExtension(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeDefinition(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeExtension(d) => (d.name(), d.fields_definition()),
+                ast::Definition::UnionTypeDefinition(d) => (d.name(), None),
+                ast::Definition::EnumTypeDefinition(d) => (d.name(), None),
+                ast::Definition::InputObjectTypeDefinition(d) => (d.name(), None),
+                ast::Definition::ScalarTypeDefinition(d) => (d.name(), None),
+                _ => continue,
+            };
+            let name = match name {
+                Some(name) => name.text().to_string(),
+                None => continue,
+            };
+            let type_fields = renames.field_types.entry(name.clone()).or_default();
+            for field in fields.iter().flat_map(|fields| fields.field_definitions()) {
+                if let (Some(field_name), Some(ty)) = (field.name(), field.ty()) {
+                    type_fields.insert(field_name.text().to_string(), ty.into());
+                }
+            }
+            renames.defined_types.insert(name);
+        }
+
+        for (old, new) in &renames.types {
+            if renames.defined_types.contains(old) {
+                return Err(format!("the renamed type {} is still in the schema", old).into());
+            }
+            if !renames.defined_types.contains(new) {
+                return Err(
+                    format!("the type {} renaming {} is not in the schema", new, old).into(),
+                );
+            }
+        }
+        for (old, new) in &renames.fields {
+            let (type_name, field_name) = old
+                .split_once('.')
+                .ok_or_else(|| format!("{} is not a field coordinate like Type.field", old))?;
+            if renames.field_type(type_name, field_name).is_some() {
+                return Err(format!("the renamed field {} is still in the schema", old).into());
+            }
+            if renames.field_type(type_name, new).is_none() {
+                return Err(format!(
+                    "the field {}.{} renaming {} is not in the schema",
+                    type_name, new, old
+                )
+                .into());
+            }
+        }
+        Ok(renames)
+    }
+
+    fn root_type(&self, kind: OperationKind) -> String {
+        self.root_types
+            .get(&kind)
+            .cloned()
+            .unwrap_or_else(|| kind.to_string())
+    }
+
+    fn field_type(&self, type_name: &str, field_name: &str) -> Option<&FieldType> {
+        self.field_types.get(type_name)?.get(field_name)
+    }
+
+    /// The query with the renamed members replaced, and the number of uses of each rename, or
+    /// `None` if the query uses no rename. Queries that can't be parsed are not translated.
+    fn translate(&self, query: &str) -> Option<(String, HashMap<String, u64>)> {
+        let tree = apollo_parser::Parser::new(query).parse();
+        if tree.errors().next().is_some() {
+            return None;
+        }
+
+        let mut translator = Translator {
+            renames: self,
+            edits: Vec::new(),
+            uses: HashMap::new(),
+        };
+        for definition in tree.document().definitions() {
+            match definition {
+                ast::Definition::OperationDefinition(operation) => {
+                    let kind = operation
+                        .operation_type()
+                        .map(OperationKind::from)
+                        .unwrap_or(OperationKind::Query);
+                    for variable in operation
+                        .variable_definitions()
+                        .iter()
+                        .flat_map(|variables| variables.variable_definitions())
+                    {
+                        if let Some(name) = variable.ty().and_then(type_name) {
+                            translator.rename_type(&name);
+                        }
+                    }
+                    if let Some(selection_set) = operation.selection_set() {
+                        translator.selection_set(Some(self.root_type(kind)), selection_set);
+                    }
+                }
+                ast::Definition::FragmentDefinition(fragment) => {
+                    let type_condition = fragment
+                        .type_condition()
+                        .and_then(|condition| condition.named_type())
+                        .and_then(|named| named.name())
+                        .map(|name| translator.rename_type(&name));
+                    if let Some(selection_set) = fragment.selection_set() {
+                        translator.selection_set(type_condition, selection_set);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if translator.edits.is_empty() {
+            return None;
+        }
+
+        let mut edits = translator.edits;
+        edits.sort_by_key(|(range, _)| range.start);
+        let mut translated = query.to_string();
+        for (range, replacement) in edits.into_iter().rev() {
+            translated.replace_range(range, &replacement);
+        }
+        Some((translated, translator.uses))
+    }
+}
+
+/// The named type of a type reference.
+fn type_name(ty: ast::Type) -> Option<ast::Name> {
+    match ty {
+        ast::Type::NamedType(named) => named.name(),
+        ast::Type::ListType(list) => type_name(list.ty()?),
+        ast::Type::NonNullType(non_null) => match non_null.named_type() {
+            Some(named) => named.name(),
+            None => type_name(non_null.list_type()?.ty()?),
+        },
+    }
+}
+
+/// Records the replacements of the renamed members of a query.
+struct Translator<'a> {
+    renames: &'a Renames,
+    edits: Vec<(Range<usize>, String)>,
+    uses: HashMap<String, u64>,
+}
+
+impl<'a> Translator<'a> {
+    /// Replaces a type name if it was renamed, and returns the new name.
+    fn rename_type(&mut self, name: &ast::Name) -> String {
+        let renames = self.renames;
+        let old = name.text().to_string();
+        match renames.types.get(&old) {
+            Some(new) => {
+                self.edit(name, new.clone());
+                *self.uses.entry(old).or_default() += 1;
+                new.clone()
+            }
+            None => old,
+        }
+    }
+
+    fn selection_set(&mut self, type_name: Option<String>, selection_set: ast::SelectionSet) {
+        let renames = self.renames;
+        for selection in selection_set.selections() {
+            match selection {
+                ast::Selection::Field(field) => {
+                    let name = match field.name() {
+                        Some(name) => name,
+                        None => continue,
+                    };
+                    let mut field_name = name.text().to_string();
+                    if let Some(type_name) = &type_name {
+                        let coordinate = format!("{}.{}", type_name, field_name);
+                        if let Some(new) = renames.fields.get(&coordinate) {
+                            // The old name stays the response key
+                            let replacement = match field.alias() {
+                                Some(_) => new.clone(),
+                                None => format!("{}: {}", field_name, new),
+                            };
+                            self.edit(&name, replacement);
+                            *self.uses.entry(coordinate).or_default() += 1;
+                            field_name = new.clone();
+                        }
+                    }
+                    if let Some(selection_set) = field.selection_set() {
+                        let field_type = type_name
+                            .as_deref()
+                            .and_then(|type_name| renames.field_type(type_name, &field_name))
+                            .and_then(|field_type| field_type.inner_type_name())
+                            .map(str::to_string);
+                        self.selection_set(field_type, selection_set);
+                    }
+                }
+                ast::Selection::InlineFragment(fragment) => {
+                    let type_condition = fragment
+                        .type_condition()
+                        .and_then(|condition| condition.named_type())
+                        .and_then(|named| named.name())
+                        .map(|name| self.rename_type(&name));
+                    if let Some(selection_set) = fragment.selection_set() {
+                        self.selection_set(
+                            type_condition.or_else(|| type_name.clone()),
+                            selection_set,
+                        );
+                    }
+                }
+                // Fragment definitions are translated on their own
+                ast::Selection::FragmentSpread(_) => {}
+            }
+        }
+    }
+
+    fn edit(&mut self, name: &ast::Name, replacement: String) {
+        let range = name.syntax().text_range();
+        self.edits.push((
+            usize::from(range.start())..usize::from(range.end()),
+            replacement,
+        ));
+    }
+}
+
+#[derive(Debug)]
+struct RenamesPlugin {
+    renames: Arc<Renames>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for RenamesPlugin {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(RenamesPlugin {
+            renames: Arc::new(Renames::new(&init.supergraph_sdl, init.config)?),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if self.renames.types.is_empty() && self.renames.fields.is_empty() {
+            return service;
+        }
+        let renames = self.renames.clone();
+        service
+            .map_request(move |mut req: SupergraphRequest| {
+                let translated = req
+                    .originating_request
+                    .body()
+                    .query
+                    .as_deref()
+                    .and_then(|query| renames.translate(query));
+                if let Some((query, uses)) = translated {
+                    req.originating_request.body_mut().query = Some(query);
+                    let _ = req.context.insert(RENAMES_CONTEXT_KEY, uses);
+                }
+                req
+            })
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "renames", RenamesPlugin);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        schema { query: Query }
+        type Query { me: User, user(id: ID!): User }
+        type User { id: ID!, name: String, account: Account }
+        type Account { id: ID!, plan: String }
+        input UserFilter { name: String }
+    "#;
+
+    fn renames() -> Renames {
+        let config: Config = serde_json::from_value(json!({
+            "types": { "Person": "User", "PersonFilter": "UserFilter" },
+            "fields": { "User.fullName": "name", "Account.tier": "plan" }
+        }))
+        .unwrap();
+        Renames::new(SCHEMA, config).unwrap()
+    }
+
+    #[test]
+    fn renamed_members_are_translated() {
+        let renames = renames();
+        let (query, uses) = renames
+            .translate(
+                "query($filter: [PersonFilter!]) { me { fullName alias: fullName account { tier } ... on Person { fullName } } ...Me } fragment Me on Query { me { ...on User { id } } }",
+            )
+            .unwrap();
+        assert_eq!(
+            query,
+            "query($filter: [UserFilter!]) { me { fullName: name alias: name account { tier: plan } ... on User { fullName: name } } ...Me } fragment Me on Query { me { ...on User { id } } }"
+        );
+        assert_eq!(uses.get("User.fullName"), Some(&3));
+        assert_eq!(uses.get("Account.tier"), Some(&1));
+        assert_eq!(uses.get("Person"), Some(&1));
+        assert_eq!(uses.get("PersonFilter"), Some(&1));
+
+        assert!(renames.translate("{ me { name } }").is_none());
+    }
+
+    #[test]
+    fn renames_are_checked_against_the_schema() {
+        let still_there: Config =
+            serde_json::from_value(json!({ "fields": { "User.name": "id" } })).unwrap();
+        assert!(Renames::new(SCHEMA, still_there).is_err());
+        let unknown: Config =
+            serde_json::from_value(json!({ "types": { "Person": "Human" } })).unwrap();
+        assert!(Renames::new(SCHEMA, unknown).is_err());
+    }
+}
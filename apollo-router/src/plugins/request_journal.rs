@@ -0,0 +1,406 @@
+//! Journal of recent requests, for crash forensics.
+//!
+//! The journal keeps a summary of the most recent requests in memory, in a ring buffer. When the
+//! router panics, it is written to disk before the process exits, so a post-mortem analysis has
+//! context about what the router was doing.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::Weak;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::telemetry::CLIENT_NAME;
+use crate::register_plugin;
+use crate::services::execution;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+use crate::ExecutionRequest;
+use crate::SubgraphRequest;
+use crate::SupergraphRequest;
+
+const JOURNAL_ID_CONTEXT_KEY: &str = "request_journal::id";
+
+/// The journal written to disk by the panic hook.
+static CURRENT_JOURNAL: Lazy<Mutex<Weak<Journal>>> = Lazy::new(Default::default);
+static INSTALL_PANIC_HOOK: Once = Once::new();
+/// Set by the first panic, the journal is written once: later panics, like the ones of other
+/// threads while the process exits, would overwrite it with less relevant requests.
+static JOURNAL_WRITTEN: AtomicBool = AtomicBool::new(false);
+
+/// Configuration for the request journal
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// File the journal is written to when the router panics, as JSON lines
+    path: PathBuf,
+    /// Number of recent requests kept in the journal (default: 1000)
+    #[serde(default = "default_capacity")]
+    capacity: usize,
+}
+
+fn default_capacity() -> usize {
+    1000
+}
+
+/// Summary of a request.
+#[derive(Clone, Debug)]
+struct Entry {
+    id: u64,
+    started_at: SystemTime,
+    start: Instant,
+    operation_name: Option<String>,
+    operation_hash: Option<String>,
+    client_name: Option<String>,
+    stage: String,
+    duration_ms: Option<u64>,
+}
+
+/// How an entry is written to disk.
+#[derive(Debug, Deserialize, Serialize)]
+struct JournalLine {
+    id: u64,
+    /// Milliseconds since the UNIX epoch
+    started_at: u64,
+    operation_name: Option<String>,
+    operation_hash: Option<String>,
+    client_name: Option<String>,
+    /// The last stage the request reached
+    stage: String,
+    /// Time spent on the request, or time elapsed so far if it was in flight
+    duration_ms: u64,
+    in_flight: bool,
+}
+
+#[derive(Debug)]
+struct Journal {
+    path: PathBuf,
+    capacity: usize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+impl Journal {
+    fn new(path: PathBuf, capacity: usize) -> Self {
+        Self {
+            path,
+            capacity,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Adds a request to the journal, evicting the oldest one if it is full, and returns its id.
+    fn start(&self, req: &SupergraphRequest) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let body = req.originating_request.body();
+        let entry = Entry {
+            id,
+            started_at: SystemTime::now(),
+            start: Instant::now(),
+            operation_name: body.operation_name.clone(),
+            operation_hash: body
+                .query
+                .as_ref()
+                .map(|query| hex::encode(Sha256::digest(query.as_bytes()))),
+            client_name: req
+                .context
+                .get::<_, String>(CLIENT_NAME)
+                .ok()
+                .flatten()
+                .filter(|name| !name.is_empty()),
+            stage: "supergraph".to_string(),
+            duration_ms: None,
+        };
+
+        let mut entries = self.entries.lock().expect("lock poisoned");
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        if self.capacity > 0 {
+            entries.push_back(entry);
+        }
+        id
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut Entry)) {
+        let mut entries = self.entries.lock().expect("lock poisoned");
+        // ids are increasing, and evicted entries are the oldest ones
+        if let Ok(index) = entries.binary_search_by_key(&id, |entry| entry.id) {
+            f(&mut entries[index]);
+        }
+    }
+
+    fn set_stage(&self, id: u64, stage: String) {
+        self.update(id, |entry| entry.stage = stage);
+    }
+
+    fn finish(&self, id: u64, success: bool) {
+        self.update(id, |entry| {
+            entry.stage = if success { "completed" } else { "failed" }.to_string();
+            entry.duration_ms = Some(entry.start.elapsed().as_millis() as u64);
+        });
+    }
+
+    fn lines(entries: &VecDeque<Entry>) -> Vec<JournalLine> {
+        entries
+            .iter()
+            .map(|entry| JournalLine {
+                id: entry.id,
+                started_at: entry
+                    .started_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+                operation_name: entry.operation_name.clone(),
+                operation_hash: entry.operation_hash.clone(),
+                client_name: entry.client_name.clone(),
+                stage: entry.stage.clone(),
+                duration_ms: entry
+                    .duration_ms
+                    .unwrap_or_else(|| entry.start.elapsed().as_millis() as u64),
+                in_flight: entry.duration_ms.is_none(),
+            })
+            .collect()
+    }
+
+    /// Writes the journal to disk, oldest request first.
+    fn write(&self) -> std::io::Result<()> {
+        // The panic may have happened while the lock was held: never wait for it
+        let lines = match self.entries.try_lock() {
+            Ok(entries) => Self::lines(&entries),
+            Err(std::sync::TryLockError::Poisoned(entries)) => Self::lines(&entries.into_inner()),
+            Err(std::sync::TryLockError::WouldBlock) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WouldBlock,
+                    "the journal is locked",
+                ))
+            }
+        };
+
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        for line in lines {
+            serde_json::to_writer(&mut file, &line)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()
+    }
+}
+
+fn journal_id(context: &Context) -> Option<u64> {
+    context.get(JOURNAL_ID_CONTEXT_KEY).ok().flatten()
+}
+
+/// Writes the current journal on the first panic of the router, then calls the previous panic
+/// hook.
+fn install_panic_hook() {
+    INSTALL_PANIC_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !JOURNAL_WRITTEN.swap(true, Ordering::SeqCst) {
+                write_current_journal();
+            }
+            previous(info)
+        }));
+    });
+}
+
+fn write_current_journal() {
+    let journal = CURRENT_JOURNAL
+        .try_lock()
+        .ok()
+        .and_then(|journal| journal.upgrade());
+    if let Some(journal) = journal {
+        match journal.write() {
+            Ok(()) => tracing::info!("request journal written to {}", journal.path.display()),
+            Err(e) => tracing::error!(
+                "could not write the request journal to {}: {}",
+                journal.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RequestJournal {
+    journal: Arc<Journal>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for RequestJournal {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let journal = Arc::new(Journal::new(init.config.path, init.config.capacity));
+        *CURRENT_JOURNAL.lock().expect("lock poisoned") = Arc::downgrade(&journal);
+        install_panic_hook();
+        Ok(RequestJournal { journal })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let start_journal = self.journal.clone();
+        let finish_journal = self.journal.clone();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SupergraphRequest| {
+                    let id = start_journal.start(req);
+                    let _ = req.context.insert(JOURNAL_ID_CONTEXT_KEY, id);
+                    id
+                },
+                move |id: u64, f| {
+                    let journal = finish_journal.clone();
+                    async move {
+                        let result: supergraph::ServiceResult = f.await;
+                        journal.finish(id, result.is_ok());
+                        result
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        let journal = self.journal.clone();
+        service
+            .map_request(move |req: ExecutionRequest| {
+                if let Some(id) = journal_id(&req.context) {
+                    journal.set_stage(id, "execution".to_string());
+                }
+                req
+            })
+            .boxed()
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let journal = self.journal.clone();
+        let stage = format!("subgraph:{}", name);
+        service
+            .map_request(move |req: SubgraphRequest| {
+                if let Some(id) = journal_id(&req.context) {
+                    journal.set_stage(id, stage.clone());
+                }
+                req
+            })
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "request_journal", RequestJournal);
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::SupergraphResponse;
+
+    fn read_journal(journal: &Journal) -> Vec<JournalLine> {
+        journal.write().unwrap();
+        std::fs::read_to_string(&journal.path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn journal_keeps_recent_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::new(dir.path().join("journal.jsonl"), 2);
+
+        for name in ["first", "second", "third"] {
+            journal.start(
+                &SupergraphRequest::fake_builder()
+                    .query("{ me { id } }")
+                    .operation_name(name)
+                    .build()
+                    .unwrap(),
+            );
+        }
+        journal.set_stage(1, "subgraph:accounts".to_string());
+        journal.finish(2, true);
+        // Evicted requests are ignored
+        journal.finish(0, false);
+
+        let lines = read_journal(&journal);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].id, 1);
+        assert_eq!(lines[0].operation_name.as_deref(), Some("second"));
+        assert_eq!(lines[0].stage, "subgraph:accounts");
+        assert!(lines[0].in_flight);
+        assert_eq!(lines[1].id, 2);
+        assert_eq!(lines[1].stage, "completed");
+        assert!(!lines[1].in_flight);
+        assert_eq!(
+            lines[1].operation_hash.as_deref(),
+            Some(hex::encode(Sha256::digest(b"{ me { id } }")).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = RequestJournal {
+            journal: Arc::new(Journal::new(dir.path().join("journal.jsonl"), 10)),
+        };
+
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SupergraphRequest| {
+                assert_eq!(journal_id(&req.context), Some(0));
+                Ok(SupergraphResponse::fake_builder()
+                    .data(json!({ "me": { "id": "1" } }))
+                    .context(req.context)
+                    .build()
+                    .unwrap())
+            });
+
+        let context = Context::new();
+        context.insert(CLIENT_NAME, "web".to_string()).unwrap();
+        plugin
+            .supergraph_service(mock_service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("{ me { id } }")
+                    .context(context)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let lines = read_journal(&plugin.journal);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].client_name.as_deref(), Some("web"));
+        assert_eq!(lines[0].stage, "completed");
+    }
+}
@@ -0,0 +1,196 @@
+//! Deduplication and ordering of the errors of responses.
+//!
+//! A subgraph failing on a fetch with many entities produces the same error for each of them, and
+//! the errors of parallel fetches come in the order the fetches complete. Errors identical to a
+//! previous error of the response are removed, and the remaining errors can be sorted by path,
+//! then by code, so that clients receive the same errors in the same order for the same failure.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::error::Error;
+use crate::json_ext::Path;
+use crate::json_ext::PathElement;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::supergraph;
+use crate::SupergraphResponse;
+
+/// Configuration for the deduplication and ordering of the errors of responses
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Which errors are removed as duplicates of a previous error of the response
+    #[serde(default)]
+    deduplicate: Deduplication,
+    /// Sort the errors of each response by path, then by code (default: false). Errors without
+    /// a path come first
+    #[serde(default)]
+    sort: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Deduplication {
+    /// Keep all the errors
+    None,
+    /// Remove the errors with the same message, code and path as a previous error
+    Identical,
+    /// Remove the errors with the same message and code as a previous error, whatever their path
+    IgnorePath,
+}
+
+impl Default for Deduplication {
+    fn default() -> Self {
+        Deduplication::None
+    }
+}
+
+fn code(error: &Error) -> Option<&str> {
+    error.extensions.get("code").and_then(|code| code.as_str())
+}
+
+impl Config {
+    fn apply(&self, errors: &mut Vec<Error>) {
+        if self.deduplicate != Deduplication::None {
+            let ignore_path = self.deduplicate == Deduplication::IgnorePath;
+            let mut seen = HashSet::new();
+            errors.retain(|error| {
+                let path = if ignore_path {
+                    None
+                } else {
+                    error.path.clone()
+                };
+                seen.insert((error.message.clone(), code(error).map(str::to_string), path))
+            });
+        }
+        if self.sort {
+            // The sort is stable: errors with the same path and code keep their order
+            errors.sort_by(|a, b| {
+                compare_paths(a.path.as_ref(), b.path.as_ref()).then_with(|| code(a).cmp(&code(b)))
+            });
+        }
+    }
+}
+
+/// Orders paths element by element, indexes numerically.
+fn compare_paths(a: Option<&Path>, b: Option<&Path>) -> Ordering {
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        (a, b) => return a.is_some().cmp(&b.is_some()),
+    };
+    for (a, b) in a.iter().zip(b.iter()) {
+        let ordering = match (a, b) {
+            (PathElement::Index(a), PathElement::Index(b)) => a.cmp(b),
+            (PathElement::Key(a), PathElement::Key(b)) => a.cmp(b),
+            (a, b) => rank(a).cmp(&rank(b)),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn rank(element: &PathElement) -> u8 {
+    match element {
+        PathElement::Flatten => 0,
+        PathElement::Index(_) => 1,
+        PathElement::Key(_) => 2,
+    }
+}
+
+struct ResponseErrors {
+    config: Arc<Config>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for ResponseErrors {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(ResponseErrors {
+            config: Arc::new(init.config),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if self.config.deduplicate == Deduplication::None && !self.config.sort {
+            return service;
+        }
+        let config = self.config.clone();
+        service
+            .map_response(move |response: SupergraphResponse| {
+                response.map_stream(move |mut response| {
+                    config.apply(&mut response.errors);
+                    for incremental in &mut response.incremental {
+                        config.apply(&mut incremental.errors);
+                    }
+                    response
+                })
+            })
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "response_errors", ResponseErrors);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn error(message: &str, code: &str, path: Option<&str>) -> Error {
+        Error::builder()
+            .message(message)
+            .extension("code", code)
+            .and_path(path.map(Path::from))
+            .build()
+    }
+
+    #[test]
+    fn errors_are_deduplicated_and_sorted() {
+        let errors = vec![
+            error("down", "UNAVAILABLE", Some("topProducts/10/reviews")),
+            error("down", "UNAVAILABLE", Some("topProducts/2/reviews")),
+            error("down", "UNAVAILABLE", Some("topProducts/10/reviews")),
+            error("denied", "FORBIDDEN", Some("topProducts/2/reviews")),
+            error("slow", "TIMEOUT", None),
+        ];
+
+        let config: Config =
+            serde_json::from_value(json!({ "deduplicate": "identical", "sort": true })).unwrap();
+        let mut identical = errors.clone();
+        config.apply(&mut identical);
+        assert_eq!(
+            identical,
+            vec![
+                error("slow", "TIMEOUT", None),
+                error("denied", "FORBIDDEN", Some("topProducts/2/reviews")),
+                error("down", "UNAVAILABLE", Some("topProducts/2/reviews")),
+                error("down", "UNAVAILABLE", Some("topProducts/10/reviews")),
+            ]
+        );
+
+        let config: Config =
+            serde_json::from_value(json!({ "deduplicate": "ignore_path" })).unwrap();
+        let mut ignoring_path = errors;
+        config.apply(&mut ignoring_path);
+        assert_eq!(
+            ignoring_path,
+            vec![
+                error("down", "UNAVAILABLE", Some("topProducts/10/reviews")),
+                error("denied", "FORBIDDEN", Some("topProducts/2/reviews")),
+                error("slow", "TIMEOUT", None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,558 @@
+//! Sampling of requests and their responses to object storage, for offline analysis.
+//!
+//! A fraction of the requests is recorded with their responses, as sent to clients after the
+//! redaction of subgraph errors, and with the configured patterns masked. Samples are batched in
+//! JSON lines objects, uploaded with `PUT` requests to an S3 or GCS bucket under keys partitioned
+//! by date and hour, so they can be queried in place or used to build regression corpora.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use hmac::Hmac;
+use hmac::Mac;
+use http::header::HeaderName;
+use http::header::CONTENT_TYPE;
+use http::HeaderMap;
+use http::HeaderValue;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+use url::Url;
+
+use crate::graphql;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::plugins::schema_drift::Sampler;
+use crate::plugins::telemetry::CLIENT_NAME;
+use crate::plugins::telemetry::CLIENT_VERSION;
+use crate::register_plugin;
+use crate::response_scanning::redact;
+use crate::services::supergraph;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Samples waiting for their upload, beyond which new samples are dropped.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Headers signed by the uploads with AWS Signature Version 4.
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Configuration for the sampling of requests and responses to object storage
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Fraction of the requests sampled, between 0 and 1 (default: 0.01)
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+    /// Bucket the samples are uploaded to
+    storage: Storage,
+    /// Request headers recorded in the samples (default: none)
+    #[serde(default)]
+    headers: Vec<String>,
+    /// Regular expressions masked in the samples, inside the strings of their JSON
+    #[serde(default)]
+    redact: Vec<String>,
+    /// Number of samples per object (default: 1000)
+    #[serde(default = "default_batch_size")]
+    batch_size: usize,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time after which the samples are uploaded, even if there are less than `batch_size`
+    /// (default: 60s)
+    flush_interval: Option<Duration>,
+}
+
+fn default_sample_rate() -> f64 {
+    0.01
+}
+
+fn default_batch_size() -> usize {
+    DEFAULT_BATCH_SIZE
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Storage {
+    /// URL of the bucket, like `https://my-bucket.s3.eu-west-1.amazonaws.com` or
+    /// `https://storage.googleapis.com/my-bucket`
+    #[schemars(with = "String")]
+    url: Url,
+    /// Prefix of the keys of the objects, like `samples/` (default: none)
+    #[serde(default)]
+    prefix: String,
+    /// HMAC credentials signing the uploads with AWS Signature Version 4, also accepted by GCS
+    credentials: Option<Credentials>,
+    /// Headers of the uploads, like an `Authorization` header
+    #[serde(default)]
+    headers: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Credentials {
+    /// Region of the bucket, `auto` for GCS
+    region: String,
+    /// Access key ID
+    access_key_id: String,
+    /// Secret access key
+    secret_access_key: String,
+}
+
+impl Credentials {
+    /// Headers signing an upload with AWS Signature Version 4.
+    fn sign(&self, url: &Url, body: &[u8], time: SystemTime) -> Vec<(&'static str, String)> {
+        let timestamp = humantime::format_rfc3339_seconds(time)
+            .to_string()
+            .replace('-', "")
+            .replace(':', "");
+        let date = &timestamp[..8];
+        let payload_hash = hex::encode(Sha256::digest(body));
+        let host = url.host_str().unwrap_or_default();
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let canonical_request = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            url.path(),
+            host,
+            payload_hash,
+            timestamp,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = [date, self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_access_key).into_bytes(),
+                |key, part| hmac_sha256(&key, part.as_bytes()),
+            );
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+        vec![
+            ("x-amz-date", timestamp.clone()),
+            ("x-amz-content-sha256", payload_hash),
+            (
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key_id, scope, SIGNED_HEADERS, signature
+                ),
+            ),
+        ]
+    }
+}
+
+/// HMAC-SHA256 of some data.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes a key, except its `/` separators, as AWS Signature Version 4 expects.
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// A request, with its responses as sent to the client.
+#[derive(Debug, Serialize)]
+struct Sample {
+    timestamp: String,
+    client_name: Option<String>,
+    client_version: Option<String>,
+    request: SampledRequest,
+    response: SampledResponse,
+}
+
+#[derive(Debug, Serialize)]
+struct SampledRequest {
+    /// The recorded headers
+    headers: BTreeMap<String, String>,
+    body: graphql::Request,
+}
+
+#[derive(Debug, Serialize)]
+struct SampledResponse {
+    status: u16,
+    /// The response, or the primary and incremental responses of a deferred query
+    body: Vec<graphql::Response>,
+}
+
+impl Sample {
+    fn new(request: &SupergraphRequest, headers: &[HeaderName]) -> Self {
+        let recorded = headers
+            .iter()
+            .filter_map(|name| {
+                let value = request.originating_request.headers().get(name)?;
+                Some((
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                ))
+            })
+            .collect();
+        Sample {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            client_name: request.context.get(CLIENT_NAME).ok().flatten(),
+            client_version: request.context.get(CLIENT_VERSION).ok().flatten(),
+            request: SampledRequest {
+                headers: recorded,
+                body: request.originating_request.body().clone(),
+            },
+            response: SampledResponse {
+                status: 0,
+                body: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Uploads batches of samples to the bucket.
+struct Uploader {
+    client: reqwest::Client,
+    storage: Storage,
+    headers: HeaderMap,
+    redact: Vec<Regex>,
+    /// Number of objects uploaded, making their keys unique
+    count: AtomicU64,
+    /// Hash of the host name and process ID, making the keys unique across routers
+    origin: String,
+}
+
+impl Uploader {
+    fn new(storage: Storage, redact: Vec<Regex>) -> Result<Self, BoxError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &storage.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        let host = sys_info::hostname().unwrap_or_default();
+        let origin = hex::encode(Sha256::digest(
+            format!("{}:{}", host, std::process::id()).as_bytes(),
+        ));
+        Ok(Uploader {
+            client: reqwest::Client::new(),
+            storage,
+            headers,
+            redact,
+            count: AtomicU64::new(0),
+            origin: origin[..16].to_string(),
+        })
+    }
+
+    /// Serializes samples as JSON lines, with the patterns masked.
+    fn serialize(&self, samples: &[Sample]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for sample in samples {
+            let mut line = serde_json::to_vec(sample).expect("samples should be serializable; qed");
+            if let Ok(json) = std::str::from_utf8(&line) {
+                let spans: Vec<_> = self
+                    .redact
+                    .iter()
+                    .flat_map(|regex| regex.find_iter(json).map(|found| found.range()))
+                    .collect();
+                if !spans.is_empty() {
+                    redact(&mut line, &spans);
+                }
+            }
+            body.extend_from_slice(&line);
+            body.push(b'\n');
+        }
+        body
+    }
+
+    /// Key of an object uploaded at a time, partitioned by date and hour.
+    fn key(&self, time: SystemTime) -> String {
+        let timestamp = humantime::format_rfc3339_seconds(time).to_string();
+        format!(
+            "{}date={}/hour={}/{}-{}-{}.jsonl",
+            self.storage.prefix,
+            &timestamp[..10],
+            &timestamp[11..13],
+            timestamp.replace('-', "").replace(':', ""),
+            self.origin,
+            self.count.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    async fn upload(&self, samples: &[Sample]) -> Result<(), BoxError> {
+        let time = SystemTime::now();
+        let body = self.serialize(samples);
+        let mut url = self.storage.url.clone();
+        let path = format!(
+            "{}/{}",
+            url.path().trim_end_matches('/'),
+            encode_key(&self.key(time))
+        );
+        url.set_path(&path);
+
+        let mut request = self
+            .client
+            .put(url.clone())
+            .headers(self.headers.clone())
+            .header(CONTENT_TYPE, "application/x-ndjson");
+        if let Some(credentials) = &self.storage.credentials {
+            for (name, value) in credentials.sign(&url, &body, time) {
+                request = request.header(name, value);
+            }
+        }
+        let response = request.body(body).send().await?;
+        if !response.status().is_success() {
+            return Err(
+                format!("the bucket answered with the status {}", response.status()).into(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn flush(&self, batch: &mut Vec<Sample>) {
+        if batch.is_empty() {
+            return;
+        }
+        if let Err(err) = self.upload(batch).await {
+            tracing::error!("could not upload {} response samples: {}", batch.len(), err);
+        }
+        batch.clear();
+    }
+}
+
+/// Uploads the samples in batches, until the plugin is dropped.
+async fn upload_samples(
+    mut receiver: mpsc::Receiver<Sample>,
+    uploader: Uploader,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut interval = tokio::time::interval(flush_interval);
+    loop {
+        tokio::select! {
+            sample = receiver.recv() => match sample {
+                Some(sample) => {
+                    batch.push(sample);
+                    if batch.len() < batch_size {
+                        continue;
+                    }
+                }
+                None => {
+                    uploader.flush(&mut batch).await;
+                    return;
+                }
+            },
+            _ = interval.tick() => {}
+        }
+        uploader.flush(&mut batch).await;
+    }
+}
+
+#[derive(Debug)]
+struct ResponseSampling {
+    sampler: Arc<Sampler>,
+    headers: Arc<Vec<HeaderName>>,
+    sender: mpsc::Sender<Sample>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for ResponseSampling {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let config = init.config;
+        if config.batch_size == 0 {
+            return Err("batch_size must be greater than 0".into());
+        }
+        let headers = config
+            .headers
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let redact = config
+            .redact
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(upload_samples(
+            receiver,
+            Uploader::new(config.storage, redact)?,
+            config.batch_size,
+            config.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL),
+        ));
+        Ok(ResponseSampling {
+            sampler: Arc::new(Sampler::new(config.sample_rate)?),
+            headers: Arc::new(headers),
+            sender,
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let sampler = self.sampler.clone();
+        let headers = self.headers.clone();
+        let sender = self.sender.clone();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SupergraphRequest| sampler.sample().then(|| Sample::new(req, &headers)),
+                move |sample: Option<Sample>, f| {
+                    let sender = sender.clone();
+                    async move {
+                        let response: SupergraphResponse = f.await?;
+                        let mut sample = match sample {
+                            Some(sample) => sample,
+                            None => return Ok(response),
+                        };
+                        sample.response.status = response.response.status().as_u16();
+                        let mut sample = Some(sample);
+                        Ok(response.map_stream(move |body| {
+                            if let Some(sample) = &mut sample {
+                                sample.response.body.push(body.clone());
+                            }
+                            // The sample is complete with the last response
+                            if body.has_next != Some(true) {
+                                if let Some(sample) = sample.take() {
+                                    if sender.try_send(sample).is_err() {
+                                        tracing::debug!(
+                                            "response sample dropped, the queue is full"
+                                        );
+                                    }
+                                }
+                            }
+                            body
+                        }))
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "response_sampling", ResponseSampling);
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+    use axum::extract::OriginalUri;
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn samples_are_uploaded_to_the_bucket() {
+        let (uploads, mut uploaded) = mpsc::unbounded_channel();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = axum::Router::new().route(
+            "/bucket/*key",
+            axum::routing::put(move |uri: OriginalUri, body: Bytes| {
+                let _ = uploads.send((uri.0.path().to_string(), body));
+                async { "" }
+            }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .get("experimental.response_sampling")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({
+                "sample_rate": 1.0,
+                "storage": {
+                    "url": format!("http://{}/bucket", address),
+                    "prefix": "samples/",
+                    "credentials": {
+                        "region": "auto",
+                        "access_key_id": "key",
+                        "secret_access_key": "secret"
+                    }
+                },
+                "headers": ["x-client"],
+                "redact": ["\\d{4}-\\d{4}"],
+                "batch_size": 1
+            }))
+            .await
+            .unwrap();
+
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_call().times(1).returning(|req| {
+            Ok(SupergraphResponse::fake_builder()
+                .data(serde_json_bytes::json!({ "me": { "card": "1234-5678" } }))
+                .context(req.context)
+                .build()
+                .unwrap())
+        });
+        let mut response = plugin
+            .supergraph_service(mock_service.boxed())
+            .oneshot(
+                SupergraphRequest::fake_builder()
+                    .query("{ me { card } }")
+                    .header("x-client", "web")
+                    .header("authorization", "secret")
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        // The client gets the response unmasked, once it is read the sample is complete
+        let body = response.next_response().await.unwrap();
+        assert_eq!(
+            body.data,
+            Some(serde_json_bytes::json!({ "me": { "card": "1234-5678" } }))
+        );
+
+        let (path, body) = uploaded.recv().await.unwrap();
+        assert!(path.starts_with("/bucket/samples/date%3D"));
+        assert!(path.contains("/hour%3D"));
+        let sample: serde_json::Value =
+            serde_json::from_slice(body.strip_suffix(b"\n").unwrap()).unwrap();
+        assert_eq!(sample["request"]["headers"], json!({ "x-client": "web" }));
+        assert_eq!(sample["request"]["body"]["query"], json!("{ me { card } }"));
+        assert_eq!(sample["response"]["status"], json!(200));
+        assert_eq!(
+            sample["response"]["body"][0]["data"],
+            json!({ "me": { "card": "*********" } })
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Declarative transformations of subgraph responses.
+//!
+//! Rules rename fields, map enum values, coerce scalar types or set constants in the data of a
+//! subgraph response, before the router merges it with the responses of other subgraphs. They
+//! adapt the responses of a legacy subgraph to the supergraph schema without changing its code.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json_bytes::ByteString;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::json_ext::Object;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::SubgraphResponse;
+
+/// Configuration for the transformation of subgraph responses
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Transformations applied to the responses of all subgraphs
+    #[serde(default)]
+    all: Vec<Transform>,
+    /// Transformations applied to the responses of a subgraph, after the ones of `all`
+    #[serde(default)]
+    subgraphs: HashMap<String, Vec<Transform>>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Transform {
+    /// Renames a field
+    Rename(Rename),
+    /// Replaces the values of a field, like enum values
+    MapValues(MapValues),
+    /// Converts the value of a field to another scalar type
+    Coerce(Coerce),
+    /// Sets a field to a constant
+    Set(Set),
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Rename {
+    /// Name of the field in the subgraph response
+    from: String,
+    /// New name of the field
+    to: String,
+    /// Only transform the objects with this `__typename`
+    #[serde(rename = "type", default)]
+    type_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct MapValues {
+    /// Name of the field
+    field: String,
+    /// New values, by value of the subgraph. Other values are kept
+    #[schemars(with = "HashMap<String, serde_json::Value>")]
+    values: HashMap<String, Value>,
+    /// Only transform the objects with this `__typename`
+    #[serde(rename = "type", default)]
+    type_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Coerce {
+    /// Name of the field
+    field: String,
+    /// Type the value is converted to. Values that can't be converted are kept
+    to: Scalar,
+    /// Only transform the objects with this `__typename`
+    #[serde(rename = "type", default)]
+    type_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Set {
+    /// Name of the field
+    field: String,
+    /// Value of the field, replacing the one of the subgraph
+    #[schemars(with = "serde_json::Value")]
+    value: Value,
+    /// Only transform the objects with this `__typename`
+    #[serde(rename = "type", default)]
+    type_name: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Scalar {
+    String,
+    Int,
+    Float,
+    Boolean,
+}
+
+impl Transform {
+    fn type_name(&self) -> Option<&str> {
+        match self {
+            Transform::Rename(rename) => rename.type_name.as_deref(),
+            Transform::MapValues(map_values) => map_values.type_name.as_deref(),
+            Transform::Coerce(coerce) => coerce.type_name.as_deref(),
+            Transform::Set(set) => set.type_name.as_deref(),
+        }
+    }
+
+    fn apply(&self, object: &mut Object) {
+        if let Some(type_name) = self.type_name() {
+            if object.get("__typename").and_then(|value| value.as_str()) != Some(type_name) {
+                return;
+            }
+        }
+        match self {
+            Transform::Rename(rename) => {
+                if let Some(value) = object.remove(rename.from.as_str()) {
+                    object.insert(ByteString::from(rename.to.as_str()), value);
+                }
+            }
+            Transform::MapValues(map_values) => {
+                if let Some(value) = object.get_mut(map_values.field.as_str()) {
+                    if let Some(new_value) = value
+                        .as_str()
+                        .and_then(|current| map_values.values.get(current))
+                    {
+                        *value = new_value.clone();
+                    }
+                }
+            }
+            Transform::Coerce(coerce) => {
+                if let Some(value) = object.get_mut(coerce.field.as_str()) {
+                    if let Some(new_value) = coerce.to.convert(value) {
+                        *value = new_value;
+                    }
+                }
+            }
+            Transform::Set(set) => {
+                object.insert(ByteString::from(set.field.as_str()), set.value.clone());
+            }
+        }
+    }
+}
+
+impl Scalar {
+    /// Converts a value, `None` if it is null, a list, an object, or can't be converted.
+    fn convert(self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (Scalar::String, Value::Number(number)) => Some(Value::from(number.to_string())),
+            (Scalar::String, Value::Bool(boolean)) => Some(Value::from(boolean.to_string())),
+            (Scalar::Int, Value::Number(number)) if number.is_f64() => number
+                .as_f64()
+                .filter(|float| float.fract() == 0.0)
+                .map(|float| Value::from(float as i64)),
+            (Scalar::Int, Value::String(string)) => {
+                string.as_str().trim().parse::<i64>().ok().map(Value::from)
+            }
+            (Scalar::Float, Value::Number(number)) if !number.is_f64() => number
+                .as_f64()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number),
+            (Scalar::Float, Value::String(string)) => string
+                .as_str()
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number),
+            (Scalar::Boolean, Value::String(string)) => match string.as_str() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Applies the transformations to every object of a value, the parents before their fields.
+fn transform(transforms: &[Transform], value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for rule in transforms {
+                rule.apply(object);
+            }
+            for value in object.values_mut() {
+                transform(transforms, value);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                transform(transforms, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[derive(Debug)]
+struct ResponseTransforms {
+    config: Config,
+}
+
+#[async_trait::async_trait]
+impl Plugin for ResponseTransforms {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(ResponseTransforms {
+            config: init.config,
+        })
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let transforms: Arc<Vec<Transform>> = Arc::new(
+            self.config
+                .all
+                .iter()
+                .chain(self.config.subgraphs.get(name).into_iter().flatten())
+                .cloned()
+                .collect(),
+        );
+        if transforms.is_empty() {
+            return service;
+        }
+        service
+            .map_response(move |mut response: SubgraphResponse| {
+                if let Some(data) = &mut response.response.body_mut().data {
+                    transform(&transforms, data);
+                }
+                response
+            })
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "response_transforms", ResponseTransforms);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use serde_json_bytes::json as bjson;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::DynPlugin;
+    use crate::SubgraphRequest;
+
+    async fn plugin() -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.response_transforms")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({
+                "all": [
+                    { "coerce": { "field": "id", "to": "string" } }
+                ],
+                "subgraphs": {
+                    "products": [
+                        { "rename": { "from": "title", "to": "name", "type": "Product" } },
+                        { "map_values": { "field": "status", "values": { "A": "AVAILABLE", "S": "SOLD_OUT" } } },
+                        { "coerce": { "field": "price", "to": "float" } },
+                        { "coerce": { "field": "stock", "to": "int" } },
+                        { "set": { "field": "currency", "value": "EUR", "type": "Product" } }
+                    ]
+                }
+            }))
+            .await
+            .unwrap()
+    }
+
+    async fn response_data(subgraph: &str, data: Value) -> Value {
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(move |_| Ok(SubgraphResponse::fake_builder().data(data.clone()).build()));
+        plugin()
+            .await
+            .subgraph_service(subgraph, mock_service.boxed())
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap()
+            .response
+            .into_body()
+            .data
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn subgraph_responses_are_transformed() {
+        let data = bjson!({
+            "topProducts": [
+                { "__typename": "Product", "id": 1, "title": "Table", "status": "A", "price": 10, "stock": "3" },
+                { "__typename": "Product", "id": 2, "title": "Chair", "status": "X", "price": 5.5, "stock": 2.0 },
+                { "__typename": "Review", "id": 3, "title": "Great" }
+            ]
+        });
+
+        assert_eq!(
+            response_data("products", data.clone()).await,
+            bjson!({
+                "topProducts": [
+                    { "__typename": "Product", "id": "1", "status": "AVAILABLE", "price": 10.0, "stock": 3, "name": "Table", "currency": "EUR" },
+                    { "__typename": "Product", "id": "2", "status": "X", "price": 5.5, "stock": 2, "name": "Chair", "currency": "EUR" },
+                    { "__typename": "Review", "id": "3", "title": "Great" }
+                ]
+            })
+        );
+        assert_eq!(
+            response_data("reviews", data).await,
+            bjson!({
+                "topProducts": [
+                    { "__typename": "Product", "id": "1", "title": "Table", "status": "A", "price": 10, "stock": "3" },
+                    { "__typename": "Product", "id": "2", "title": "Chair", "status": "X", "price": 5.5, "stock": 2.0 },
+                    { "__typename": "Review", "id": "3", "title": "Great" }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn values_that_cant_be_converted_are_kept() {
+        assert_eq!(Scalar::Int.convert(&bjson!("12a")), None);
+        assert_eq!(Scalar::Int.convert(&bjson!(1.5)), None);
+        assert_eq!(Scalar::Boolean.convert(&bjson!("yes")), None);
+        assert_eq!(Scalar::Float.convert(&Value::Null), None);
+        assert_eq!(Scalar::String.convert(&bjson!([1])), None);
+        assert_eq!(Scalar::Boolean.convert(&bjson!("true")), Some(bjson!(true)));
+    }
+}
@@ -1,10 +1,12 @@
 //! Customization via Rhai.
 
+use std::num::NonZeroU64;
 use std::ops::ControlFlow;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use futures::future::ready;
 use futures::stream::once;
@@ -16,8 +18,10 @@ use http::uri::Authority;
 use http::uri::Parts;
 use http::uri::PathAndQuery;
 use http::HeaderMap;
+use http::Method;
 use http::StatusCode;
 use http::Uri;
+use once_cell::sync::Lazy;
 use opentelemetry::trace::SpanKind;
 use rhai::module_resolvers::FileModuleResolver;
 use rhai::plugin::*;
@@ -49,6 +53,8 @@ use crate::json_ext::Value;
 use crate::layers::ServiceBuilderExt;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
+use crate::plugins::traffic_shaping::Rate;
+use crate::plugins::traffic_shaping::TokenBucket;
 use crate::register_plugin;
 use crate::Context;
 use crate::ExecutionRequest;
@@ -105,6 +111,10 @@ mod subgraph {
     pub(crate) use crate::services::subgraph::*;
 }
 
+mod router {
+    pub(crate) use crate::services::router::*;
+}
+
 #[export_module]
 mod router_plugin_mod {
     // It would be nice to generate get_originating_headers and
@@ -366,6 +376,131 @@ pub(crate) struct Rhai {
 pub(crate) struct Conf {
     scripts: Option<PathBuf>,
     main: Option<String>,
+    /// Requests sent by the `http_fetch` function of the scripts
+    #[serde(default)]
+    experimental_http_fetch: HttpFetchConf,
+}
+
+/// Configuration of the `http_fetch` function
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct HttpFetchConf {
+    /// Timeout of each request (default: 1s)
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "Option<String>", default)]
+    timeout: Option<Duration>,
+    /// Number of requests allowed per second, for all the scripts. Requests over the limit fail
+    /// right away (default: 100)
+    #[serde(default)]
+    max_per_second: Option<NonZeroU64>,
+}
+
+const HTTP_FETCH_DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const HTTP_FETCH_DEFAULT_MAX_PER_SECOND: u64 = 100;
+
+/// Runtime of the requests sent by `http_fetch`.
+///
+/// Rhai functions are synchronous: the callback waits for the request, which runs on this
+/// runtime, so that it works whatever the runtime the callback runs on.
+static HTTP_FETCH_RUNTIME: Lazy<tokio::runtime::Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("rhai-http-fetch")
+        .enable_all()
+        .build()
+        .expect("http_fetch runtime should build; qed")
+});
+
+/// The `http_fetch` function of the scripts, sending HTTP requests to external services, like an
+/// authorization or a feature flag service.
+#[derive(Clone)]
+struct HttpFetch {
+    client: reqwest::Client,
+    rate: Rate,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl HttpFetch {
+    fn new(conf: &HttpFetchConf) -> Result<Self, BoxError> {
+        let client = reqwest::Client::builder()
+            .timeout(conf.timeout.unwrap_or(HTTP_FETCH_DEFAULT_TIMEOUT))
+            .build()?;
+        let max_per_second = conf.max_per_second.unwrap_or_else(|| {
+            NonZeroU64::new(HTTP_FETCH_DEFAULT_MAX_PER_SECOND).expect("default is not zero; qed")
+        });
+        let rate = Rate::new(max_per_second, Duration::from_secs(1));
+        Ok(HttpFetch {
+            client,
+            rate,
+            bucket: Arc::new(Mutex::new(TokenBucket::new(rate))),
+        })
+    }
+
+    fn register(self, engine: &mut Engine) {
+        let fetch = self.clone();
+        engine
+            .register_result_fn("http_fetch", move |url: &str| fetch.fetch(url, Map::new()))
+            .register_result_fn("http_fetch", move |url: &str, options: Map| {
+                self.fetch(url, options)
+            });
+    }
+
+    /// Sends a request and waits for its response, returned as an object map with its `status`
+    /// and its `body`, parsed if it is JSON.
+    fn fetch(&self, url: &str, options: Map) -> Result<Map, Box<EvalAltResult>> {
+        if let Err(retry_after) = self
+            .bucket
+            .lock()
+            .expect("lock poisoned")
+            .acquire(self.rate, std::time::Instant::now())
+        {
+            return Err(format!(
+                "http_fetch rate limit exceeded, retry after {:?}",
+                retry_after
+            )
+            .into());
+        }
+
+        let method = match options.get("method") {
+            Some(method) => Method::from_str(&method.to_string().to_uppercase())
+                .map_err(|err| format!("invalid http_fetch method: {}", err))?,
+            None => Method::GET,
+        };
+        let mut request = self.client.request(method, url);
+        if let Some(headers) = options.get("headers") {
+            let headers = headers
+                .clone()
+                .try_cast::<Map>()
+                .ok_or("http_fetch headers must be an object map")?;
+            for (name, value) in headers {
+                request = request.header(name.as_str(), value.to_string());
+            }
+        }
+        if let Some(body) = options.get("body") {
+            request = if body.is::<String>() {
+                request.body(body.to_string())
+            } else {
+                request.json(&from_dynamic::<serde_json::Value>(body)?)
+            };
+        }
+
+        let (status, body) = futures::executor::block_on(HTTP_FETCH_RUNTIME.spawn(async move {
+            let response = request.send().await?;
+            let status = response.status();
+            Ok::<_, reqwest::Error>((status, response.bytes().await?))
+        }))
+        .map_err(|err| err.to_string())?
+        .map_err(|err| format!("http_fetch failed: {}", err))?;
+
+        let body = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(json) => to_dynamic(json)?,
+            Err(_) => String::from_utf8_lossy(&body).into_owned().into(),
+        };
+        let mut response = Map::new();
+        response.insert("status".into(), (status.as_u16() as i64).into());
+        response.insert("body".into(), body);
+        Ok(response)
+    }
 }
 
 #[async_trait::async_trait]
@@ -384,11 +519,30 @@ impl Plugin for Rhai {
         };
 
         let main = scripts_path.join(&main_file);
-        let engine = Arc::new(Rhai::new_rhai_engine(Some(scripts_path)));
+        let mut engine = Rhai::new_rhai_engine(Some(scripts_path));
+        HttpFetch::new(&init.config.experimental_http_fetch)?.register(&mut engine);
+        let engine = Arc::new(engine);
         let ast = engine.compile_file(main)?;
         Ok(Self { ast, engine })
     }
 
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        const FUNCTION_NAME_SERVICE: &str = "router_service";
+        if !self.ast_has_function(FUNCTION_NAME_SERVICE) {
+            return service;
+        }
+        tracing::debug!("router_service function found");
+        let shared_service = Arc::new(Mutex::new(Some(service)));
+        if let Err(error) = self.run_rhai_service(
+            FUNCTION_NAME_SERVICE,
+            None,
+            ServiceStep::Router(shared_service.clone()),
+        ) {
+            tracing::error!("service callback failed: {error}");
+        }
+        shared_service.take_unwrap()
+    }
+
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
         const FUNCTION_NAME_SERVICE: &str = "supergraph_service";
         if !self.ast_has_function(FUNCTION_NAME_SERVICE) {
@@ -443,6 +597,7 @@ impl Plugin for Rhai {
 
 #[derive(Clone, Debug)]
 pub(crate) enum ServiceStep {
+    Router(SharedMut<router::BoxService>),
     Supergraph(SharedMut<supergraph::BoxService>),
     Execution(SharedMut<execution::BoxService>),
     Subgraph(SharedMut<subgraph::BoxService>),
@@ -714,11 +869,13 @@ macro_rules! gen_map_deferred_response {
                         response_opt.unwrap();
                     let (parts, body) = http::Response::from(response).into_parts();
 
-                    let ctx = context.clone();
+                    // deferred responses are processed with the context as it is now, whatever
+                    // happens to it while the other responses are processed
+                    let snapshot = context.snapshot();
 
                     let mapped_stream = rest.filter_map(move |deferred_response| {
                         let rhai_service = $rhai_service.clone();
-                        let context = context.clone();
+                        let context = snapshot.snapshot();
                         let callback = $callback.clone();
                         async move {
                             let response = $rhai_deferred_response {
@@ -751,7 +908,7 @@ macro_rules! gen_map_deferred_response {
                     )
                     .into();
                     Ok($response {
-                        context: ctx,
+                        context,
                         response,
                     })
                 },
@@ -894,6 +1051,7 @@ macro_rules! register_rhai_interface {
 impl ServiceStep {
     fn map_request(&mut self, rhai_service: RhaiService, callback: FnPtr) {
         match self {
+            ServiceStep::Router(service) => map_router_request(service, rhai_service, callback),
             ServiceStep::Supergraph(service) => {
                 gen_map_deferred_request!(
                     SupergraphRequest,
@@ -920,6 +1078,7 @@ impl ServiceStep {
 
     fn map_response(&mut self, rhai_service: RhaiService, callback: FnPtr) {
         match self {
+            ServiceStep::Router(service) => map_router_response(service, rhai_service, callback),
             ServiceStep::Supergraph(service) => {
                 gen_map_deferred_response!(
                     SupergraphResponse,
@@ -947,23 +1106,140 @@ impl ServiceStep {
     }
 }
 
-fn execute(
+// The bodies of the router stage are raw HTTP bodies, they are not exposed to the callbacks
+fn map_router_request(
+    service: &SharedMut<router::BoxService>,
+    rhai_service: RhaiService,
+    callback: FnPtr,
+) {
+    service.replace(|service| {
+        ServiceBuilder::new()
+            .instrument(|_request: &router::Request| {
+                tracing::info_span!(
+                    "rhai plugin",
+                    "rhai service" = "router::Request",
+                    "otel.kind" = %SpanKind::Internal
+                )
+            })
+            .checkpoint(move |request: router::Request| {
+                let shared_request = Shared::new(Mutex::new(Some(request)));
+                let result = call(&rhai_service, &callback, (shared_request.clone(),));
+                let mut guard = shared_request.lock().unwrap();
+                let request = guard.take().unwrap();
+                let error = match result {
+                    Ok(_) => return Ok(ControlFlow::Continue(request)),
+                    Err(error) => error,
+                };
+                let (status, message) = rejection(&error).unwrap_or_else(|| {
+                    tracing::error!("map_request callback failed: {error}");
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("rhai execution error: '{}'", error),
+                    )
+                });
+                Ok(ControlFlow::Break(router_failure(
+                    status,
+                    message,
+                    request.context,
+                )))
+            })
+            .service(service)
+            .boxed()
+    })
+}
+
+fn map_router_response(
+    service: &SharedMut<router::BoxService>,
+    rhai_service: RhaiService,
+    callback: FnPtr,
+) {
+    service.replace(|service| {
+        service
+            .map_response(move |response: router::Response| {
+                let shared_response = Shared::new(Mutex::new(Some(response)));
+                let result = execute(&rhai_service, &callback, (shared_response.clone(),));
+                let mut guard = shared_response.lock().unwrap();
+                let response = guard.take().unwrap();
+                match result {
+                    Ok(_) => response,
+                    Err(error) => {
+                        tracing::error!("map_response callback failed: {error}");
+                        router_failure(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            format!("rhai execution error: '{}'", error),
+                            response.context,
+                        )
+                    }
+                }
+            })
+            .boxed()
+    })
+}
+
+/// A GraphQL response holding a single error, sent by the router stage.
+fn router_failure(status: StatusCode, message: String, context: Context) -> router::Response {
+    let body = Response::builder()
+        .errors(vec![Error {
+            message,
+            ..Default::default()
+        }])
+        .build();
+    let mut response = http::Response::new(hyper::Body::from(
+        serde_json::to_vec(&body).expect("can't fail to serialize our error message"),
+    ));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    router::Response { response, context }
+}
+
+/// The status and message of a deliberate rejection, thrown by a callback as a map with a
+/// `status` and a `message`, like `throw #{ status: 403, message: "forbidden" }`.
+fn rejection(mut error: &EvalAltResult) -> Option<(StatusCode, String)> {
+    // Errors thrown in functions are wrapped in the errors of their calls
+    while let EvalAltResult::ErrorInFunctionCall(_, _, inner, _) = error {
+        error = inner;
+    }
+    let value = match error {
+        EvalAltResult::ErrorRuntime(value, _) => value.clone().try_cast::<Map>()?,
+        _ => return None,
+    };
+    let status = value
+        .get("status")
+        .and_then(|status| status.as_int().ok())
+        .and_then(|status| u16::try_from(status).ok())
+        .and_then(|status| StatusCode::from_u16(status).ok())?;
+    let message = match value.get("message") {
+        Some(message) => message.to_string(),
+        None => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+    Some((status, message))
+}
+
+fn call(
     rhai_service: &RhaiService,
     callback: &FnPtr,
     args: impl FuncArgs,
-) -> Result<Dynamic, String> {
+) -> Result<Dynamic, Box<EvalAltResult>> {
     if callback.is_curried() {
-        callback
-            .call(&rhai_service.engine, &rhai_service.ast, args)
-            .map_err(|err| err.to_string())
+        callback.call(&rhai_service.engine, &rhai_service.ast, args)
     } else {
         let mut scope = rhai_service.scope.clone();
         rhai_service
             .engine
             .call_fn(&mut scope, &rhai_service.ast, callback.fn_name(), args)
-            .map_err(|err| err.to_string())
     }
 }
+
+fn execute(
+    rhai_service: &RhaiService,
+    callback: &FnPtr,
+    args: impl FuncArgs,
+) -> Result<Dynamic, String> {
+    call(rhai_service, callback, args).map_err(|err| err.to_string())
+}
 #[derive(Clone, Debug)]
 pub(crate) struct RhaiService {
     scope: Scope<'static>,
@@ -1138,6 +1414,9 @@ impl Rhai {
             .register_set("operation_name", |x: &mut Request, value: &str| {
                 x.operation_name = Some(value.to_string());
             })
+            .register_set("operation_name", |x: &mut Request, _value: ()| {
+                x.operation_name = None;
+            })
             // Request.variables
             .register_get_result("variables", |x: &mut Request| {
                 to_dynamic(x.variables.clone())
@@ -1312,6 +1591,73 @@ impl Rhai {
 
         register_rhai_interface!(engine, supergraph, execution, subgraph);
 
+        // The router stage only exposes the HTTP parts of requests and responses
+        engine
+            .register_get_result("context", |obj: &mut SharedMut<router::Request>| {
+                Ok(obj.with_mut(|request| request.context.clone()))
+            })
+            .register_set_result(
+                "context",
+                |obj: &mut SharedMut<router::Request>, context: Context| {
+                    obj.with_mut(|request| request.context = context);
+                    Ok(())
+                },
+            )
+            .register_get_result("method", |obj: &mut SharedMut<router::Request>| {
+                Ok(obj.with_mut(|request| request.router_request.method().to_string()))
+            })
+            .register_get_result("uri", |obj: &mut SharedMut<router::Request>| {
+                Ok(obj.with_mut(|request| request.router_request.uri().clone()))
+            })
+            .register_set_result("uri", |obj: &mut SharedMut<router::Request>, uri: Uri| {
+                obj.with_mut(|request| *request.router_request.uri_mut() = uri);
+                Ok(())
+            })
+            .register_get_result("headers", |obj: &mut SharedMut<router::Request>| {
+                Ok(obj.with_mut(|request| request.router_request.headers().clone()))
+            })
+            .register_set_result(
+                "headers",
+                |obj: &mut SharedMut<router::Request>, headers: HeaderMap| {
+                    obj.with_mut(|request| *request.router_request.headers_mut() = headers);
+                    Ok(())
+                },
+            )
+            .register_get_result("context", |obj: &mut SharedMut<router::Response>| {
+                Ok(obj.with_mut(|response| response.context.clone()))
+            })
+            .register_set_result(
+                "context",
+                |obj: &mut SharedMut<router::Response>, context: Context| {
+                    obj.with_mut(|response| response.context = context);
+                    Ok(())
+                },
+            )
+            .register_get_result("status", |obj: &mut SharedMut<router::Response>| {
+                Ok(obj.with_mut(|response| response.response.status().as_u16() as i64))
+            })
+            .register_set_result(
+                "status",
+                |obj: &mut SharedMut<router::Response>, status: i64| {
+                    let status = u16::try_from(status)
+                        .ok()
+                        .and_then(|status| StatusCode::from_u16(status).ok())
+                        .ok_or("invalid status code")?;
+                    obj.with_mut(|response| *response.response.status_mut() = status);
+                    Ok(())
+                },
+            )
+            .register_get_result("headers", |obj: &mut SharedMut<router::Response>| {
+                Ok(obj.with_mut(|response| response.response.headers().clone()))
+            })
+            .register_set_result(
+                "headers",
+                |obj: &mut SharedMut<router::Response>, headers: HeaderMap| {
+                    obj.with_mut(|response| *response.response.headers_mut() = headers);
+                    Ok(())
+                },
+            );
+
         engine
             .register_get_result(
                 "context",
@@ -1364,9 +1710,12 @@ mod tests {
     use super::*;
     use crate::http_ext;
     use crate::plugin::test::MockExecutionService;
+    use crate::plugin::test::MockSubgraphService;
     use crate::plugin::test::MockSupergraphService;
     use crate::plugin::DynPlugin;
     use crate::Context;
+    use crate::SubgraphRequest;
+    use crate::SubgraphResponse;
     use crate::SupergraphRequest;
     use crate::SupergraphResponse;
 
@@ -1425,6 +1774,64 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn rhai_plugin_router_stage() -> Result<(), BoxError> {
+        let handler = tower::service_fn(|request: router::Request| async move {
+            assert_eq!(request.router_request.uri().path(), "/graphql");
+            assert_eq!(request.router_request.headers()["x-method"], "POST");
+            assert_eq!(
+                request
+                    .context
+                    .get::<_, String>("tenant")
+                    .unwrap()
+                    .as_deref(),
+                Some("acme")
+            );
+            Ok::<_, BoxError>(router::Response {
+                response: http::Response::new(hyper::Body::from("{}")),
+                context: request.context,
+            })
+        });
+
+        let dyn_plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .get("apollo.rhai")
+            .expect("Plugin not found")
+            .create_instance(
+                &Value::from_str(r#"{"scripts":"tests/fixtures", "main":"router_service.rhai"}"#)
+                    .unwrap(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let mut router_service = dyn_plugin.router_service(handler.boxed());
+
+        let router_req = router::Request::fake_builder()
+            .router_request(
+                http::Request::post("http://localhost/tenants/acme/graphql")
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+            )
+            .build();
+        let router_resp = router_service.ready().await?.call(router_req).await?;
+        assert_eq!(router_resp.response.status(), 203);
+        assert_eq!(router_resp.response.headers()["x-tenant"], "acme");
+
+        // Requests rejected by the script don't reach the handler
+        let router_req = router::Request::fake_builder()
+            .router_request(
+                http::Request::post("http://localhost/graphql")
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+            )
+            .build();
+        let router_resp = router_service.ready().await?.call(router_req).await?;
+        assert_eq!(router_resp.response.status(), 404);
+        let body = hyper::body::to_bytes(router_resp.response.into_body()).await?;
+        let resp: Response = serde_json::from_slice(&body)?;
+        assert_eq!(resp.errors[0].message, "unknown tenant");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn rhai_plugin_execution_service_error() -> Result<(), BoxError> {
         let mut mock_service = MockExecutionService::new();
@@ -1488,6 +1895,97 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn rhai_plugin_subgraph_service_body() -> Result<(), BoxError> {
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(move |req: SubgraphRequest| {
+                let body = req.subgraph_request.body();
+                assert_eq!(
+                    body.query.as_deref(),
+                    Some("query TenantQuery($locale: String) { topProducts(locale: $locale) { name } }")
+                );
+                assert_eq!(body.operation_name.as_deref(), Some("TenantQuery"));
+                assert_eq!(body.variables.get("first"), Some(&2.into()));
+                assert_eq!(body.variables.get("locale"), Some(&"fr".into()));
+                assert_eq!(body.extensions.get("tenant"), Some(&"fr".into()));
+                Ok(SubgraphResponse::fake_builder().context(req.context).build())
+            });
+
+        let dyn_plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .get("apollo.rhai")
+            .expect("Plugin not found")
+            .create_instance(
+                &Value::from_str(r#"{"scripts":"tests/fixtures", "main":"subgraph_body.rhai"}"#)
+                    .unwrap(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let mut subgraph_service =
+            dyn_plugin.subgraph_service("products", BoxService::new(mock_service));
+        let context = Context::new();
+        context.insert("tenant", "fr").unwrap();
+        let mut variables = Object::new();
+        variables.insert("first", 2.into());
+        let subgraph_req = SubgraphRequest::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .body(
+                        Request::builder()
+                            .query("{ topProducts { name } }")
+                            .variables(variables)
+                            .build(),
+                    )
+                    .unwrap(),
+            )
+            .context(context)
+            .build();
+
+        let subgraph_resp = subgraph_service.ready().await?.call(subgraph_req).await?;
+        assert_eq!(subgraph_resp.response.status(), 200);
+        assert!(subgraph_resp.response.body().errors.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn it_fetches_json_over_http() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = axum::Router::new().route(
+            "/flags",
+            axum::routing::post(|axum::Json(body): axum::Json<Value>| async move {
+                axum::Json(serde_json::json!({ "beta": true, "user": body["user"] }))
+            }),
+        );
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let mut engine = Rhai::new_rhai_engine(None);
+        HttpFetch::new(&HttpFetchConf {
+            timeout: None,
+            max_per_second: NonZeroU64::new(1),
+        })
+        .unwrap()
+        .register(&mut engine);
+        let script = format!(
+            r#"http_fetch("http://{address}/flags", #{{ method: "post", body: #{{ user: "alice" }} }})"#
+        );
+        let response = engine.eval::<Map>(&script).unwrap();
+        assert_eq!(response["status"].as_int().unwrap(), 200);
+        let body: Value = from_dynamic(&response["body"]).unwrap();
+        assert_eq!(body, serde_json::json!({ "beta": true, "user": "alice" }));
+
+        // A second request within the same second is over the limit
+        let error = engine.eval::<Map>(&script).unwrap_err();
+        assert!(error.to_string().contains("rate limit exceeded"));
+    }
+
     // Some of these tests rely extensively on internal implementation details of the tracing_test crate.
     // These are unstable, so these test may break if the tracing_test crate is updated.
     //
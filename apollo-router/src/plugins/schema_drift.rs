@@ -0,0 +1,706 @@
+//! Subgraph response schema drift detection.
+//!
+//! Checks a sample of subgraph responses against the supergraph schema. A subgraph deployed ahead
+//! of composition may return fields the router did not ask for, or values of another type than
+//! the one declared in the schema. Those drifts are logged as warnings and counted in the
+//! `subgraph_schema_drift_total` metric, before they turn into errors for clients.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use apollo_parser::ast;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+
+use crate::json_ext::Object;
+use crate::json_ext::Path;
+use crate::json_ext::PathElement;
+use crate::json_ext::Value;
+use crate::json_ext::ValueExt;
+use crate::layers::ServiceBuilderExt;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::query_planner::OperationKind;
+use crate::register_plugin;
+use crate::services::subgraph;
+use crate::spec::FieldType;
+use crate::SubgraphRequest;
+
+/// Context key holding the number of drifts found in the responses of each subgraph, by kind.
+pub(crate) const SCHEMA_DRIFT_CONTEXT_KEY: &str = "apollo_schema_drift::drifts";
+
+/// The entities of a subgraph, see the federation specification.
+const ENTITY_UNION: &str = "_Entity";
+
+/// Configuration for subgraph response schema drift detection
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Fraction of the subgraph responses that are checked, between 0 and 1 (default: 0.01)
+    #[serde(default = "default_sample_rate")]
+    sample_rate: f64,
+}
+
+fn default_sample_rate() -> f64 {
+    0.01
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DriftKind {
+    /// The response contains a field that was not requested, or that is not in the schema
+    UndeclaredField,
+    /// The type of a value is not the type declared in the schema
+    WrongType,
+}
+
+impl DriftKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DriftKind::UndeclaredField => "undeclared_field",
+            DriftKind::WrongType => "wrong_type",
+        }
+    }
+}
+
+/// A difference between a subgraph response and the schema.
+#[derive(Clone, Debug, PartialEq)]
+struct Drift {
+    kind: DriftKind,
+    path: Path,
+    message: String,
+}
+
+/// What the schema declares about the types returned by subgraphs.
+#[derive(Debug, Default)]
+struct SchemaTypes {
+    root_types: HashMap<OperationKind, String>,
+    object_types: HashSet<String>,
+    /// Object types, interfaces and unions
+    composite_types: HashSet<String>,
+    /// Fields of the object types and interfaces
+    fields: HashMap<String, HashMap<String, FieldType>>,
+    enums: HashMap<String, HashSet<String>>,
+}
+
+impl SchemaTypes {
+    fn new(schema: &str) -> Result<Self, BoxError> {
+        let tree = apollo_parser::Parser::new(schema).parse();
+        if let Some(error) = tree.errors().next() {
+            return Err(format!("could not parse the schema: {}", error.message()).into());
+        }
+
+        let mut types = SchemaTypes::default();
+        for definition in tree.document().definitions() {
+            let (type_name, fields) = match definition {
+                ast::Definition::SchemaDefinition(schema) => {
+                    for operation in schema.root_operation_type_definitions() {
+                        if let (Some(kind), Some(name)) = (
+                            operation.operation_type(),
+                            operation.named_type().and_then(|n| n.name()),
+                        ) {
+                            types
+                                .root_types
+                                .insert(kind.into(), name.text().to_string());
+                        }
+                    }
+                    continue;
+                }
+                ast::Definition::ObjectTypeDefinition(d) => {
+                    if let Some(name) = d.name() {
+                        types.object_types.insert(name.text().to_string());
+                    }
+                    (d.name(), d.fields_definition())
+                }
+                ast::Definition::ObjectTypeExtension(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeDefinition(d) => (d.name(), d.fields_definition()),
+                ast::Definition::InterfaceTypeExtension(d) => (d.name(), d.fields_definition()),
+                ast::Definition::UnionTypeDefinition(d) => (d.name(), None),
+                ast::Definition::EnumTypeDefinition(d) => {
+                    if let Some(name) = d.name() {
+                        let values = d
+                            .enum_values_definition()
+                            .iter()
+                            .flat_map(|values| values.enum_value_definitions())
+                            .filter_map(|value| {
+                                Some(value.enum_value()?.name()?.text().to_string())
+                            })
+                            .collect();
+                        types.enums.insert(name.text().to_string(), values);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            let type_name = match type_name {
+                Some(name) => name.text().to_string(),
+                None => continue,
+            };
+
+            let type_fields = types.fields.entry(type_name.clone()).or_default();
+            for field in fields.iter().flat_map(|fields| fields.field_definitions()) {
+                if let (Some(name), Some(ty)) = (field.name(), field.ty()) {
+                    type_fields.insert(name.text().to_string(), ty.into());
+                }
+            }
+            types.composite_types.insert(type_name);
+        }
+        types.composite_types.insert(ENTITY_UNION.to_string());
+        Ok(types)
+    }
+
+    fn root_type(&self, kind: OperationKind) -> String {
+        self.root_types
+            .get(&kind)
+            .cloned()
+            .unwrap_or_else(|| kind.to_string())
+    }
+
+    fn field(&self, type_name: &str, field_name: &str) -> Option<FieldType> {
+        if field_name == "_entities" {
+            // Federation's entry point to the entities of a subgraph is not in the supergraph
+            return Some(FieldType::NonNull(Box::new(FieldType::List(Box::new(
+                FieldType::Named(ENTITY_UNION.to_string()),
+            )))));
+        }
+        self.fields.get(type_name)?.get(field_name).cloned()
+    }
+}
+
+/// Checks a subgraph response against the selections of its query.
+struct Checker<'a> {
+    types: &'a SchemaTypes,
+    fragments: HashMap<String, ast::FragmentDefinition>,
+    path: Path,
+    drifts: Vec<Drift>,
+}
+
+/// The selections of a response key.
+struct Selected {
+    field_type: Option<FieldType>,
+    coordinate: String,
+    selection_sets: Vec<ast::SelectionSet>,
+}
+
+/// Returns the drifts between the data of a subgraph response and the schema. Queries that can't
+/// be parsed are not checked.
+fn check(
+    types: &SchemaTypes,
+    query: &str,
+    operation_name: Option<&str>,
+    kind: OperationKind,
+    data: &Value,
+) -> Vec<Drift> {
+    let tree = apollo_parser::Parser::new(query).parse();
+    if tree.errors().next().is_some() {
+        return Vec::new();
+    }
+    let document = tree.document();
+
+    let mut operations = document
+        .definitions()
+        .filter_map(|definition| match definition {
+            ast::Definition::OperationDefinition(operation) => Some(operation),
+            _ => None,
+        });
+    let operation = match operation_name {
+        Some(operation_name) => operations.find(|operation| {
+            operation
+                .name()
+                .map(|name| name.text() == operation_name)
+                .unwrap_or_default()
+        }),
+        None => operations.next(),
+    };
+    let (selection_set, data) = match (operation.and_then(|o| o.selection_set()), data) {
+        (Some(selection_set), Value::Object(data)) => (selection_set, data),
+        _ => return Vec::new(),
+    };
+
+    let mut checker = Checker {
+        types,
+        fragments: document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::FragmentDefinition(fragment) => {
+                    let name = fragment.fragment_name()?.name()?.text().to_string();
+                    Some((name, fragment))
+                }
+                _ => None,
+            })
+            .collect(),
+        path: Path::empty(),
+        drifts: Vec::new(),
+    };
+    checker.object(&types.root_type(kind), &[selection_set], data);
+    checker.drifts
+}
+
+impl<'a> Checker<'a> {
+    fn drift(&mut self, kind: DriftKind, message: String) {
+        self.drifts.push(Drift {
+            kind,
+            path: self.path.clone(),
+            message,
+        });
+    }
+
+    fn object(&mut self, parent_type: &str, selection_sets: &[ast::SelectionSet], object: &Object) {
+        let typename = object.get("__typename").and_then(|t| t.as_str());
+        let object_type = match typename {
+            Some(typename) if self.types.object_types.contains(typename) => typename,
+            _ => parent_type,
+        };
+
+        let mut selected = HashMap::new();
+        for selection_set in selection_sets {
+            self.collect(object_type, typename, selection_set.clone(), &mut selected);
+        }
+
+        for (key, value) in object.iter() {
+            let key = key.as_str();
+            if key == "__typename" {
+                continue;
+            }
+            self.path.push(PathElement::Key(key.to_string()));
+            match selected.get(key) {
+                None => self.drift(
+                    DriftKind::UndeclaredField,
+                    format!("the field `{}` was not requested", key),
+                ),
+                Some(Selected {
+                    field_type: None,
+                    coordinate,
+                    ..
+                }) => self.drift(
+                    DriftKind::UndeclaredField,
+                    format!("the field `{}` is not in the schema", coordinate),
+                ),
+                Some(Selected {
+                    field_type: Some(field_type),
+                    selection_sets,
+                    ..
+                }) => self.value(field_type, selection_sets, value),
+            }
+            self.path.pop();
+        }
+    }
+
+    /// Groups the fields selected on an object by response key. Without `__typename`, we can't
+    /// tell which fragments apply: all of them are collected.
+    fn collect(
+        &self,
+        type_name: &str,
+        typename: Option<&str>,
+        selection_set: ast::SelectionSet,
+        selected: &mut HashMap<String, Selected>,
+    ) {
+        let applies = |type_condition: &str| match typename {
+            Some(typename) => {
+                typename == type_condition || !self.types.object_types.contains(type_condition)
+            }
+            None => true,
+        };
+
+        for selection in selection_set.selections() {
+            let (type_condition, selection_set) = match selection {
+                ast::Selection::Field(field) => {
+                    let (name, key) = match (field.name(), field.alias().and_then(|a| a.name())) {
+                        (Some(name), Some(alias)) => {
+                            (name.text().to_string(), alias.text().to_string())
+                        }
+                        (Some(name), None) => (name.text().to_string(), name.text().to_string()),
+                        _ => continue,
+                    };
+                    let entry = selected.entry(key).or_insert_with(|| Selected {
+                        field_type: self.types.field(type_name, &name),
+                        coordinate: format!("{}.{}", type_name, name),
+                        selection_sets: Vec::new(),
+                    });
+                    entry.selection_sets.extend(field.selection_set());
+                    continue;
+                }
+                ast::Selection::InlineFragment(fragment) => (
+                    fragment
+                        .type_condition()
+                        .and_then(|t| t.named_type())
+                        .and_then(|t| t.name())
+                        .map(|name| name.text().to_string()),
+                    fragment.selection_set(),
+                ),
+                ast::Selection::FragmentSpread(spread) => {
+                    let fragment = match spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .and_then(|name| self.fragments.get(&name.text().to_string()))
+                    {
+                        Some(fragment) => fragment,
+                        None => continue,
+                    };
+                    (
+                        fragment
+                            .type_condition()
+                            .and_then(|t| t.named_type())
+                            .and_then(|t| t.name())
+                            .map(|name| name.text().to_string()),
+                        fragment.selection_set(),
+                    )
+                }
+            };
+            let type_condition = type_condition.unwrap_or_else(|| type_name.to_string());
+            if let Some(selection_set) = selection_set {
+                if applies(&type_condition) {
+                    self.collect(&type_condition, typename, selection_set, selected);
+                }
+            }
+        }
+    }
+
+    fn value(
+        &mut self,
+        field_type: &FieldType,
+        selection_sets: &[ast::SelectionSet],
+        value: &Value,
+    ) {
+        let valid = match (field_type, value) {
+            (FieldType::NonNull(_), Value::Null) => false,
+            (FieldType::NonNull(inner), value) => {
+                return self.value(inner, selection_sets, value);
+            }
+            (_, Value::Null) => true,
+            (FieldType::List(inner), Value::Array(items)) => {
+                for (index, item) in items.iter().enumerate() {
+                    self.path.push(PathElement::Index(index));
+                    self.value(inner, selection_sets, item);
+                    self.path.pop();
+                }
+                return;
+            }
+            (FieldType::List(_), _) => false,
+            (FieldType::Int, value) => value.is_valid_int_input(),
+            (FieldType::Float, value) => value.is_number(),
+            (FieldType::String, value) => value.is_string(),
+            (FieldType::Id, value) => value.is_string() || value.is_i64() || value.is_u64(),
+            (FieldType::Boolean, value) => value.is_boolean(),
+            (FieldType::Introspection(_), _) => true,
+            (FieldType::Named(name), value) => {
+                if let Some(values) = self.types.enums.get(name) {
+                    value
+                        .as_str()
+                        .map(|value| values.contains(value))
+                        .unwrap_or_default()
+                } else if self.types.composite_types.contains(name) {
+                    match value {
+                        Value::Object(object) => {
+                            self.object(name, selection_sets, object);
+                            return;
+                        }
+                        _ => false,
+                    }
+                } else {
+                    // Custom scalars can be serialized in any way
+                    true
+                }
+            }
+        };
+        if !valid {
+            self.drift(
+                DriftKind::WrongType,
+                format!(
+                    "expected a value of type {}, got {}",
+                    type_name(field_type),
+                    value_kind(value)
+                ),
+            );
+        }
+    }
+}
+
+/// Formats a type like in a schema.
+fn type_name(field_type: &FieldType) -> String {
+    match field_type {
+        FieldType::Named(name) | FieldType::Introspection(name) => name.clone(),
+        FieldType::List(inner) => format!("[{}]", type_name(inner)),
+        FieldType::NonNull(inner) => format!("{}!", type_name(inner)),
+        FieldType::String => "String".to_string(),
+        FieldType::Int => "Int".to_string(),
+        FieldType::Float => "Float".to_string(),
+        FieldType::Id => "ID".to_string(),
+        FieldType::Boolean => "Boolean".to_string(),
+    }
+}
+
+fn value_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Samples a fraction of the responses, evenly.
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    rate: f64,
+    count: AtomicU64,
+}
+
+impl Sampler {
+    /// Creates a sampler for a rate between 0 and 1.
+    pub(crate) fn new(rate: f64) -> Result<Self, BoxError> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!("sample_rate must be between 0 and 1, got {}", rate).into());
+        }
+        Ok(Sampler {
+            rate,
+            count: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn sample(&self) -> bool {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) as f64;
+        ((count + 1.0) * self.rate).floor() > (count * self.rate).floor()
+    }
+}
+
+#[derive(Debug)]
+struct SchemaDrift {
+    types: Arc<SchemaTypes>,
+    sampler: Arc<Sampler>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for SchemaDrift {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        Ok(SchemaDrift {
+            types: Arc::new(SchemaTypes::new(&init.supergraph_sdl)?),
+            sampler: Arc::new(Sampler::new(init.config.sample_rate)?),
+        })
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        let sampler = self.sampler.clone();
+        let types = self.types.clone();
+        let name = name.to_string();
+        ServiceBuilder::new()
+            .map_future_with_request_data(
+                move |req: &SubgraphRequest| {
+                    let body = req.subgraph_request.body();
+                    match &body.query {
+                        Some(query) if sampler.sample() => Some((
+                            query.clone(),
+                            body.operation_name.clone(),
+                            req.operation_kind,
+                        )),
+                        _ => None,
+                    }
+                },
+                move |sampled: Option<(String, Option<String>, OperationKind)>, f| {
+                    let types = types.clone();
+                    let name = name.clone();
+                    async move {
+                        let res: subgraph::ServiceResult = f.await;
+                        let res = match res {
+                            Ok(res) => res,
+                            Err(err) => return Err(err),
+                        };
+                        let (query, operation_name, kind) = match sampled {
+                            Some(sampled) => sampled,
+                            None => return Ok(res),
+                        };
+                        let drifts = match &res.response.body().data {
+                            Some(data) => {
+                                check(&types, &query, operation_name.as_deref(), kind, data)
+                            }
+                            None => Vec::new(),
+                        };
+                        if drifts.is_empty() {
+                            return Ok(res);
+                        }
+
+                        for drift in &drifts {
+                            tracing::warn!(
+                                subgraph = %name,
+                                path = %drift.path,
+                                kind = drift.kind.as_str(),
+                                "subgraph response does not match the schema: {}",
+                                drift.message
+                            );
+                        }
+                        let _ = res.context.upsert(
+                            SCHEMA_DRIFT_CONTEXT_KEY,
+                            |mut counts: HashMap<String, HashMap<String, u64>>| {
+                                let subgraph_counts = counts.entry(name.clone()).or_default();
+                                for drift in &drifts {
+                                    *subgraph_counts
+                                        .entry(drift.kind.as_str().to_string())
+                                        .or_default() += 1;
+                                }
+                                counts
+                            },
+                        );
+                        Ok(res)
+                    }
+                },
+            )
+            .service(service)
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "schema_drift", SchemaDrift);
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+    use crate::plugin::DynPlugin;
+    use crate::SubgraphResponse;
+
+    const SCHEMA: &str = r#"
+        schema { query: Query }
+        type Query { me: User topProducts: [Product!]! }
+        interface Node { id: ID! }
+        type User implements Node { id: ID! name: String status: Status }
+        type Product implements Node { upc: String! price: Int reviews: [Review] }
+        type Review { id: ID! body: String }
+        enum Status { ACTIVE INACTIVE }
+    "#;
+
+    fn types() -> SchemaTypes {
+        SchemaTypes::new(SCHEMA).unwrap()
+    }
+
+    fn drifts(query: &str, data: Value) -> Vec<(DriftKind, String)> {
+        check(&types(), query, None, OperationKind::Query, &data)
+            .into_iter()
+            .map(|drift| (drift.kind, drift.path.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn matching_responses_have_no_drift() {
+        assert!(drifts(
+            "{ me { id name status } topProducts { upc p: price reviews { id } } }",
+            json!({
+                "me": { "id": "1", "name": null, "status": "ACTIVE" },
+                "topProducts": [{ "upc": "1", "p": 10, "reviews": [{ "id": "1" }] }]
+            })
+        )
+        .is_empty());
+        assert!(drifts(
+            "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name}}}",
+            json!({ "_entities": [{ "__typename": "User", "name": "Ada" }] })
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn undeclared_fields_are_detected() {
+        assert_eq!(
+            drifts(
+                "{ me { id } }",
+                json!({ "me": { "id": "1", "email": "ada@example.com" } })
+            ),
+            vec![(DriftKind::UndeclaredField, "/me/email".to_string())]
+        );
+        assert_eq!(
+            drifts(
+                "{ me { id email } }",
+                json!({ "me": { "id": "1", "email": "ada@example.com" } })
+            ),
+            vec![(DriftKind::UndeclaredField, "/me/email".to_string())]
+        );
+    }
+
+    #[test]
+    fn wrong_types_are_detected() {
+        let mut found = drifts(
+            "{ me { status } topProducts { upc price } }",
+            json!({
+                "me": { "status": "DELETED" },
+                "topProducts": [{ "upc": null, "price": "10" }, { "upc": "2", "price": 1.5 }]
+            }),
+        );
+        found.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            found,
+            vec![
+                (DriftKind::WrongType, "/me/status".to_string()),
+                (DriftKind::WrongType, "/topProducts/0/price".to_string()),
+                (DriftKind::WrongType, "/topProducts/0/upc".to_string()),
+                (DriftKind::WrongType, "/topProducts/1/price".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn responses_are_sampled() {
+        let sampler = Sampler {
+            rate: 0.25,
+            count: AtomicU64::new(0),
+        };
+        assert_eq!((0..100).filter(|_| sampler.sample()).count(), 25);
+    }
+
+    #[tokio::test]
+    async fn drifts_are_counted_in_the_context() {
+        let plugin: Box<dyn DynPlugin> = crate::plugin::plugins()
+            .get("experimental.schema_drift")
+            .expect("Plugin not found")
+            .create_instance(
+                &serde_json::json!({ "sample_rate": 1.0 }),
+                Arc::new(SCHEMA.to_string()),
+            )
+            .await
+            .unwrap();
+
+        let mut mock_service = MockSubgraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(|req: SubgraphRequest| {
+                Ok(SubgraphResponse::fake_builder()
+                    .data(json!({ "me": { "id": 1, "name": 2 } }))
+                    .context(req.context)
+                    .build())
+            });
+
+        let response = plugin
+            .subgraph_service("accounts", mock_service.boxed())
+            .oneshot(
+                SubgraphRequest::fake_builder()
+                    .subgraph_request(
+                        http::Request::builder()
+                            .body(
+                                crate::graphql::Request::builder()
+                                    .query("{ me { id name } }")
+                                    .build(),
+                            )
+                            .unwrap(),
+                    )
+                    .build(),
+            )
+            .await
+            .unwrap();
+
+        let counts: HashMap<String, HashMap<String, u64>> = response
+            .context
+            .get(SCHEMA_DRIFT_CONTEXT_KEY)
+            .unwrap()
+            .unwrap();
+        assert_eq!(counts["accounts"]["wrong_type"], 1);
+    }
+}
@@ -0,0 +1,193 @@
+//! HTTP status codes of the responses with errors.
+//!
+//! Clients and CDNs often key their behavior off the HTTP status code rather than the GraphQL
+//! errors of a response. The `code` extensions of the errors are mapped to status codes, like
+//! authentication failures to 401 or rate limiting to 429, and the first error with a mapped code
+//! sets the status of the response.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::future::ready;
+use futures::stream::once;
+use futures::StreamExt;
+use http::StatusCode;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use crate::graphql;
+use crate::plugin::Plugin;
+use crate::plugin::PluginInit;
+use crate::register_plugin;
+use crate::services::supergraph;
+
+/// Configuration for the HTTP status codes of the responses with errors
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// HTTP status codes, by the `code` extension of the errors
+    #[serde(default)]
+    codes: HashMap<String, u16>,
+    /// Also set the status of the responses with data, for errors on some of their fields
+    /// (default: false)
+    #[serde(default)]
+    partial_responses: bool,
+}
+
+#[derive(Debug)]
+struct StatusCodes {
+    codes: HashMap<String, StatusCode>,
+    partial_responses: bool,
+}
+
+impl StatusCodes {
+    /// The status code of the first error with a mapped code.
+    fn status(&self, response: &graphql::Response) -> Option<StatusCode> {
+        if !self.partial_responses && matches!(&response.data, Some(data) if !data.is_null()) {
+            return None;
+        }
+        response.errors.iter().find_map(|error| {
+            let code = error.extensions.get("code")?.as_str()?;
+            self.codes.get(code).copied()
+        })
+    }
+}
+
+struct StatusCodesPlugin {
+    status_codes: Arc<StatusCodes>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for StatusCodesPlugin {
+    type Config = Config;
+
+    async fn new(init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+        let codes = init
+            .config
+            .codes
+            .into_iter()
+            .map(|(code, status)| match StatusCode::from_u16(status) {
+                Ok(status) if status.is_client_error() || status.is_server_error() => {
+                    Ok((code, status))
+                }
+                _ => Err(format!(
+                    "the status code of {} must be between 400 and 599, got {}",
+                    code, status
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(StatusCodesPlugin {
+            status_codes: Arc::new(StatusCodes {
+                codes,
+                partial_responses: init.config.partial_responses,
+            }),
+        })
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        if self.status_codes.codes.is_empty() {
+            return service;
+        }
+        let status_codes = self.status_codes.clone();
+        service
+            .and_then(move |mut res: supergraph::Response| {
+                let status_codes = status_codes.clone();
+                async move {
+                    // The status is sent with the primary response, deferred responses can't
+                    // change it
+                    let (mut parts, stream) = res.response.into_parts();
+                    let (first, rest) = stream.into_future().await;
+                    let first = first.unwrap_or_default();
+                    if let Some(status) = status_codes.status(&first) {
+                        parts.status = status;
+                    }
+                    res.response =
+                        http::Response::from_parts(parts, once(ready(first)).chain(rest).boxed());
+                    Ok(res)
+                }
+            })
+            .boxed()
+    }
+}
+
+register_plugin!("experimental", "status_codes", StatusCodesPlugin);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use serde_json_bytes::json as bjson;
+
+    use super::*;
+    use crate::plugin::test::MockSupergraphService;
+    use crate::plugin::DynPlugin;
+    use crate::SupergraphRequest;
+    use crate::SupergraphResponse;
+
+    async fn plugin() -> Box<dyn DynPlugin> {
+        crate::plugin::plugins()
+            .get("experimental.status_codes")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({
+                "codes": { "UNAUTHENTICATED": 401, "RATE_LIMITED": 429 }
+            }))
+            .await
+            .unwrap()
+    }
+
+    async fn status(data: Option<serde_json_bytes::Value>, code: &'static str) -> StatusCode {
+        let mut mock_service = MockSupergraphService::new();
+        mock_service
+            .expect_call()
+            .times(1)
+            .returning(move |req: SupergraphRequest| {
+                SupergraphResponse::fake_builder()
+                    .and_data(data.clone())
+                    .error(
+                        graphql::Error::builder()
+                            .message("failed")
+                            .extension("code", code)
+                            .build(),
+                    )
+                    .context(req.context)
+                    .build()
+            });
+        plugin()
+            .await
+            .supergraph_service(mock_service.boxed())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap()
+            .response
+            .status()
+    }
+
+    #[tokio::test]
+    async fn error_codes_set_the_status_of_responses() {
+        assert_eq!(
+            status(None, "UNAUTHENTICATED").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(None, "RATE_LIMITED").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(status(None, "OTHER").await, StatusCode::OK);
+        // Partial responses keep their status
+        assert_eq!(
+            status(Some(bjson!({ "me": null })), "UNAUTHENTICATED").await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn status_codes_must_be_errors() {
+        assert!(crate::plugin::plugins()
+            .get("experimental.status_codes")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&json!({ "codes": { "UNAUTHENTICATED": 200 } }))
+            .await
+            .is_err());
+    }
+}
@@ -1,6 +1,7 @@
 //! Configuration for apollo telemetry.
 // This entire file is license key functionality
 use http::header::HeaderName;
+use http::HeaderMap;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use url::Url;
@@ -35,12 +36,87 @@ pub(crate) struct Config {
     )]
     pub(crate) client_version_header: HeaderName,
 
+    /// Validation of the client name and version headers
+    #[serde(default)]
+    pub(crate) client_awareness: ClientAwareness,
+
+    /// Share of the requests for which field-level statistics are reported, from 0 to 1
+    #[serde(default = "field_level_instrumentation_sampler_default")]
+    pub(crate) field_level_instrumentation_sampler: f64,
+
     // This'll get overridden if a user tries to set it.
     // The purpose is to allow is to pass this in to the plugin.
     #[schemars(skip)]
     pub(crate) schema_id: String,
 }
 
+/// Client awareness configuration
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClientAwareness {
+    /// What to do with requests without a valid client name or version (default: accept)
+    #[serde(default)]
+    pub(crate) missing: MissingClient,
+    /// Add the client name and version to the attributes of the router metrics
+    #[serde(default)]
+    pub(crate) metrics_attributes: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MissingClient {
+    /// Accept the request, with an empty client name or version
+    Accept,
+    /// Accept the request, with the client name or version `unknown`
+    Tag,
+    /// Reject the request with a 400 status
+    Reject,
+}
+
+impl Default for MissingClient {
+    fn default() -> Self {
+        MissingClient::Accept
+    }
+}
+
+/// Client name or version of the requests tagged as coming from an unknown client.
+pub(crate) const UNKNOWN_CLIENT: &str = "unknown";
+
+impl Config {
+    /// The client name and version of a request.
+    pub(crate) fn client(&self, headers: &HeaderMap) -> (String, String) {
+        let default = match self.client_awareness.missing {
+            MissingClient::Tag => UNKNOWN_CLIENT,
+            MissingClient::Accept | MissingClient::Reject => "",
+        };
+        (
+            client_header(headers, &self.client_name_header)
+                .unwrap_or(default)
+                .to_string(),
+            client_header(headers, &self.client_version_header)
+                .unwrap_or(default)
+                .to_string(),
+        )
+    }
+
+    /// The client headers missing from a request, or without a valid value.
+    pub(crate) fn missing_client_headers(&self, headers: &HeaderMap) -> Vec<&HeaderName> {
+        [&self.client_name_header, &self.client_version_header]
+            .into_iter()
+            .filter(|name| client_header(headers, name).is_none())
+            .collect()
+    }
+}
+
+/// The value of a client header, if it is set to a non empty string.
+fn client_header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
 fn apollo_key() -> Option<String> {
     std::env::var("APOLLO_KEY").ok()
 }
@@ -65,6 +141,10 @@ fn client_version_header_default() -> HeaderName {
     HeaderName::from_static(client_version_header_default_str())
 }
 
+fn field_level_instrumentation_sampler_default() -> f64 {
+    0.01
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -73,7 +153,40 @@ impl Default for Config {
             apollo_graph_ref: None,
             client_name_header: client_name_header_default(),
             client_version_header: client_version_header_default(),
+            client_awareness: Default::default(),
+            field_level_instrumentation_sampler: field_level_instrumentation_sampler_default(),
             schema_id: "<no_schema_id>".to_string(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn missing_clients_are_tagged() {
+        let config: Config = serde_json::from_value(json!({
+            "client_name_header": "x-client",
+            "client_awareness": { "missing": "tag" }
+        }))
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-client", HeaderValue::from_static("web"));
+        headers.insert(
+            "apollographql-client-version",
+            HeaderValue::from_static(" "),
+        );
+        assert_eq!(
+            config.client(&headers),
+            ("web".to_string(), UNKNOWN_CLIENT.to_string())
+        );
+        assert_eq!(
+            config.missing_client_headers(&headers),
+            vec![&HeaderName::from_static("apollographql-client-version")]
+        );
+    }
+}
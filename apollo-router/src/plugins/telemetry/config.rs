@@ -9,7 +9,9 @@ use opentelemetry::Value;
 use schemars::JsonSchema;
 use serde::Deserialize;
 
+use super::logging::Logging;
 use super::metrics::MetricsAttributesConf;
+use super::span_attributes::SpanAttributesConf;
 use super::*;
 use crate::plugins::telemetry::metrics;
 
@@ -43,6 +45,8 @@ pub struct Conf {
     #[allow(dead_code)]
     pub(crate) metrics: Option<Metrics>,
     pub(crate) tracing: Option<Tracing>,
+    /// Logging configuration
+    pub(crate) logging: Option<Logging>,
     pub(crate) apollo: Option<apollo::Config>,
 }
 
@@ -69,6 +73,8 @@ pub(crate) struct MetricsCommon {
 #[serde(deny_unknown_fields, rename_all = "snake_case")]
 pub(crate) struct Tracing {
     pub(crate) propagation: Option<Propagation>,
+    /// Attributes added to the supergraph and subgraph spans, from request data
+    pub(crate) span_attributes: Option<SpanAttributesConf>,
     pub(crate) trace_config: Option<Trace>,
     pub(crate) otlp: Option<otlp::Config>,
     pub(crate) jaeger: Option<tracing::jaeger::Config>,
@@ -0,0 +1,167 @@
+//! Configuration of the router's own logs.
+//!
+//! The logs are set up with the global subscriber, when the telemetry plugin is first created:
+//! changes to this configuration need a restart of the router.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Logging configuration
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub(crate) struct Logging {
+    /// Format of the logs (default: text if the standard output is a terminal, json otherwise)
+    pub(crate) format: Option<LoggingFormat>,
+    /// Where the logs are written (default: stdout)
+    #[serde(default)]
+    pub(crate) output: LoggingOutput,
+    /// File the logs are appended to, with the `file` output
+    pub(crate) path: Option<PathBuf>,
+    /// Log levels per target, in addition to the log level of the command line, for example
+    /// `apollo_router::plugins::telemetry: debug`
+    #[serde(default)]
+    pub(crate) levels: BTreeMap<String, String>,
+    /// Display the target of the events (default: true)
+    pub(crate) display_target: Option<bool>,
+    /// Display the current span and its parents in JSON logs (default: true)
+    pub(crate) display_span_list: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LoggingFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LoggingOutput {
+    /// The standard output
+    Stdout,
+    /// The standard error
+    Stderr,
+    /// The file set in `path`
+    File,
+}
+
+impl Default for LoggingOutput {
+    fn default() -> Self {
+        LoggingOutput::Stdout
+    }
+}
+
+impl Logging {
+    pub(crate) fn validate(&self) -> Result<(), BoxError> {
+        if (self.output == LoggingOutput::File) != self.path.is_some() {
+            return Err(
+                "the logging path must be set with the file output, and only with it".into(),
+            );
+        }
+        self.env_filter("info").map(|_| ())
+    }
+
+    /// The directives of the log filter, from the log level of the command line and the levels
+    /// per target.
+    fn directives(&self, log_level: &str) -> String {
+        std::iter::once(log_level.to_string())
+            .chain(
+                self.levels
+                    .iter()
+                    .map(|(target, level)| format!("{}={}", target, level)),
+            )
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub(crate) fn env_filter(&self, log_level: &str) -> Result<EnvFilter, BoxError> {
+        let directives = self.directives(log_level);
+        EnvFilter::try_new(&directives)
+            .map_err(|e| format!("could not parse the log levels '{}': {}", directives, e).into())
+    }
+
+    /// The format of the logs, and whether text logs are colored.
+    pub(crate) fn format(&self) -> (LoggingFormat, bool) {
+        let terminal = self.output == LoggingOutput::Stdout
+            && log_writer().is_none()
+            && atty::is(atty::Stream::Stdout);
+        match self.format {
+            Some(format) => (format, terminal),
+            // Logs written to the Windows event log are not colored
+            None if terminal || log_writer().is_some() => (LoggingFormat::Text, terminal),
+            None => (LoggingFormat::Json, false),
+        }
+    }
+
+    pub(crate) fn writer(&self) -> Result<BoxMakeWriter, BoxError> {
+        Ok(match (self.output, &self.path) {
+            (LoggingOutput::Stderr, _) => BoxMakeWriter::new(std::io::stderr),
+            (LoggingOutput::File, Some(path)) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        format!("could not open the log file {}: {}", path.display(), e)
+                    })?;
+                BoxMakeWriter::new(Mutex::new(file))
+            }
+            _ => log_writer().unwrap_or_else(|| BoxMakeWriter::new(std::io::stdout)),
+        })
+    }
+}
+
+/// Where logs are written when there is no console, like when running as a Windows service.
+pub(crate) fn log_writer() -> Option<BoxMakeWriter> {
+    #[cfg(all(windows, feature = "http_server"))]
+    {
+        crate::windows::event_log().map(BoxMakeWriter::new)
+    }
+    #[cfg(not(all(windows, feature = "http_server")))]
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn levels_are_added_to_the_command_line_level() {
+        let logging: Logging = serde_json::from_value(json!({
+            "levels": { "apollo_router::plugins::telemetry": "debug", "hyper": "off" }
+        }))
+        .unwrap();
+        logging.validate().unwrap();
+        assert_eq!(
+            logging.directives("warn"),
+            "warn,apollo_router::plugins::telemetry=debug,hyper=off"
+        );
+
+        let logging: Logging =
+            serde_json::from_value(json!({ "levels": { "hyper": "loud" } })).unwrap();
+        assert!(logging.validate().is_err());
+    }
+
+    #[test]
+    fn the_file_output_needs_a_path() {
+        let logging: Logging = serde_json::from_value(json!({ "output": "file" })).unwrap();
+        assert!(logging.validate().is_err());
+        let logging: Logging =
+            serde_json::from_value(json!({ "path": "/var/log/router.log" })).unwrap();
+        assert!(logging.validate().is_err());
+    }
+}
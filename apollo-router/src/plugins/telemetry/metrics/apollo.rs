@@ -268,6 +268,8 @@ mod test {
             apollo_graph_ref: None,
             client_name_header: HeaderName::from_static("name_header"),
             client_version_header: HeaderName::from_static("version_header"),
+            client_awareness: Default::default(),
+            field_level_instrumentation_sampler: 0.01,
             schema_id: "schema_sha".to_string(),
         })
         .await?;
@@ -396,6 +398,8 @@ mod test {
             apollo_graph_ref: Some("ref".to_string()),
             client_name_header: HeaderName::from_static("name_header"),
             client_version_header: HeaderName::from_static("version_header"),
+            client_awareness: Default::default(),
+            field_level_instrumentation_sampler: 0.01,
             schema_id: "schema_sha".to_string(),
         })
     }
@@ -407,6 +411,7 @@ mod test {
             config::Conf {
                 metrics: None,
                 tracing: None,
+                logging: None,
                 apollo: Some(apollo_config),
             },
             Default::default(),
@@ -89,7 +89,9 @@ pub(crate) struct SingleFieldStat {
     pub(crate) errors_count: u64,
     pub(crate) estimated_execution_count: f64,
     pub(crate) requests_with_errors_count: u64,
-    pub(crate) latency: Duration,
+    pub(crate) observed_execution_count: u64,
+    /// Latency of the field, if it was observed
+    pub(crate) latency: Option<Duration>,
 }
 
 #[derive(Default, Serialize)]
@@ -217,12 +219,14 @@ pub(crate) struct FieldStat {
     errors_count: u64,
     estimated_execution_count: f64,
     requests_with_errors_count: u64,
+    observed_execution_count: u64,
     latency: DurationHistogram,
 }
 
 impl AddAssign<SingleFieldStat> for FieldStat {
     fn add_assign(&mut self, stat: SingleFieldStat) {
-        self.latency.increment_duration(Some(stat.latency), 1);
+        self.latency.increment_duration(stat.latency, 1);
+        self.observed_execution_count += stat.observed_execution_count;
         self.requests_with_errors_count += stat.requests_with_errors_count;
         self.estimated_execution_count += stat.estimated_execution_count;
         self.errors_count += stat.errors_count;
@@ -305,7 +309,7 @@ impl From<FieldStat> for apollo_spaceport::FieldStat {
         Self {
             return_type: stat.return_type,
             errors_count: stat.errors_count,
-            observed_execution_count: stat.latency.entries,
+            observed_execution_count: stat.observed_execution_count,
             estimated_execution_count: stat.estimated_execution_count as u64,
             requests_with_errors_count: stat.requests_with_errors_count,
             latency_count: stat.latency.buckets,
@@ -463,7 +467,8 @@ mod test {
             errors_count: count.inc_u64(),
             estimated_execution_count: count.inc_f64(),
             requests_with_errors_count: count.inc_u64(),
-            latency: Duration::from_secs(1),
+            observed_execution_count: 1,
+            latency: Some(Duration::from_secs(1)),
         }
     }
 
@@ -13,6 +13,8 @@ use opentelemetry::metrics::Counter;
 use opentelemetry::metrics::Meter;
 use opentelemetry::metrics::MeterProvider;
 use opentelemetry::metrics::Number;
+#[cfg(feature = "allocation_metrics")]
+use opentelemetry::metrics::SumObserver;
 use opentelemetry::metrics::ValueRecorder;
 use opentelemetry::KeyValue;
 use regex::Regex;
@@ -21,6 +23,8 @@ use serde::Serialize;
 use serde_json::Value;
 use tower::BoxError;
 
+#[cfg(feature = "allocation_metrics")]
+use crate::allocator;
 use crate::error::FetchError;
 use crate::graphql::Request;
 use crate::plugin::serde::deserialize_header_name;
@@ -518,6 +522,15 @@ pub(crate) struct BasicMetrics {
     pub(crate) http_requests_total: AggregateCounter<u64>,
     pub(crate) http_requests_error_total: AggregateCounter<u64>,
     pub(crate) http_requests_duration: AggregateValueRecorder<f64>,
+    pub(crate) estimated_cost: AggregateValueRecorder<f64>,
+    pub(crate) actual_cost: AggregateValueRecorder<f64>,
+    pub(crate) schema_drift_total: AggregateCounter<u64>,
+    pub(crate) override_comparison_total: AggregateCounter<u64>,
+    pub(crate) renames_total: AggregateCounter<u64>,
+    pub(crate) subgraph_hedges_total: AggregateCounter<u64>,
+    pub(crate) query_planning_timeouts_total: AggregateCounter<u64>,
+    pub(crate) plugin_duration: AggregateValueRecorder<f64>,
+    pub(crate) plugin_errors_total: AggregateCounter<u64>,
 }
 
 impl BasicMetrics {
@@ -539,6 +552,94 @@ impl BasicMetrics {
                     .with_description("Total number of HTTP requests made.")
                     .init()
             }),
+            estimated_cost: meter.build_value_recorder(|m| {
+                m.f64_value_recorder("demand_control_estimated_cost")
+                    .with_description("Estimated cost of the operations, before execution.")
+                    .init()
+            }),
+            actual_cost: meter.build_value_recorder(|m| {
+                m.f64_value_recorder("demand_control_actual_cost")
+                    .with_description("Actual cost of the operations, measured on their response.")
+                    .init()
+            }),
+            schema_drift_total: meter.build_counter(|m| {
+                m.u64_counter("subgraph_schema_drift_total")
+                    .with_description(
+                        "Total number of differences between subgraph responses and the schema.",
+                    )
+                    .init()
+            }),
+            override_comparison_total: meter.build_counter(|m| {
+                m.u64_counter("override_comparison_total")
+                    .with_description(
+                        "Total number of comparisons of overridden fields with the subgraph they were overridden from.",
+                    )
+                    .init()
+            }),
+            renames_total: meter.build_counter(|m| {
+                m.u64_counter("renames_total")
+                    .with_description(
+                        "Total number of uses of renamed types and fields by client operations.",
+                    )
+                    .init()
+            }),
+            subgraph_hedges_total: meter.build_counter(|m| {
+                m.u64_counter("subgraph_hedges_total")
+                    .with_description("Total number of hedged subgraph queries, by outcome.")
+                    .init()
+            }),
+            query_planning_timeouts_total: meter.build_counter(|m| {
+                m.u64_counter("query_planning_timeouts_total")
+                    .with_description("Total number of operations whose planning timed out.")
+                    .init()
+            }),
+            plugin_duration: meter.build_value_recorder(|m| {
+                m.f64_value_recorder("plugin_duration_seconds")
+                    .with_description(
+                        "Time spent in each plugin, per stage, without the services it wraps.",
+                    )
+                    .init()
+            }),
+            plugin_errors_total: meter.build_counter(|m| {
+                m.u64_counter("plugin_errors_total")
+                    .with_description("Total number of errors returned by each plugin, per stage.")
+                    .init()
+            }),
+        }
+    }
+}
+
+/// Allocations made by each stage of the request pipeline, see [`crate::allocator`].
+#[cfg(feature = "allocation_metrics")]
+pub(crate) struct AllocationMetrics {
+    // The observers are called when metrics are collected, as long as they are alive
+    _allocated_bytes: Vec<SumObserver<u64>>,
+    _allocations: Vec<SumObserver<u64>>,
+}
+
+#[cfg(feature = "allocation_metrics")]
+impl AllocationMetrics {
+    pub(crate) fn new(meter_provider: &AggregateMeterProvider) -> AllocationMetrics {
+        let meter = meter_provider.meter("apollo/router", None);
+        AllocationMetrics {
+            _allocated_bytes: meter.build_sum_observer(|m| {
+                m.u64_sum_observer("router_allocated_bytes_total", |result| {
+                    for (stage, bytes, _) in allocator::allocations() {
+                        result.observe(bytes, &[KeyValue::new("stage", stage.as_str())]);
+                    }
+                })
+                .with_description("Total number of bytes allocated, per request pipeline stage.")
+                .init()
+            }),
+            _allocations: meter.build_sum_observer(|m| {
+                m.u64_sum_observer("router_allocations_total", |result| {
+                    for (stage, _, count) in allocator::allocations() {
+                        result.observe(count, &[KeyValue::new("stage", stage.as_str())]);
+                    }
+                })
+                .with_description("Total number of allocations, per request pipeline stage.")
+                .init()
+            }),
         }
     }
 }
@@ -582,6 +683,14 @@ impl AggregateMeter {
     ) -> AggregateValueRecorder<T> {
         AggregateValueRecorder(self.0.iter().map(|m| build(m)).collect())
     }
+
+    #[cfg(feature = "allocation_metrics")]
+    pub(crate) fn build_sum_observer<T: Into<Number> + Copy>(
+        &self,
+        build: fn(&Meter) -> SumObserver<T>,
+    ) -> Vec<SumObserver<T>> {
+        self.0.iter().map(|m| build(m)).collect()
+    }
 }
 
 #[derive(Clone)]
@@ -1,25 +1,39 @@
+#[cfg(feature = "telemetry_otlp")]
 use std::time::Duration;
 
+#[cfg(feature = "telemetry_otlp")]
 use futures::Stream;
+#[cfg(feature = "telemetry_otlp")]
 use futures::StreamExt;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry::sdk::metrics::selectors;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry::util::tokio_interval_stream;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry::KeyValue;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry_otlp::HttpExporterBuilder;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry_otlp::TonicExporterBuilder;
 use tower::BoxError;
 
+#[cfg(feature = "telemetry_otlp")]
+use crate::plugins::telemetry::config::GenericWith;
 use crate::plugins::telemetry::config::MetricsCommon;
 use crate::plugins::telemetry::metrics::MetricsBuilder;
 use crate::plugins::telemetry::metrics::MetricsConfigurator;
+#[cfg(not(feature = "telemetry_otlp"))]
+use crate::plugins::telemetry::not_compiled;
 
 // TODO Remove MetricExporterBuilder once upstream issue is fixed
 // This has to exist because Http is not currently supported for metrics export
 // https://github.com/open-telemetry/opentelemetry-rust/issues/772
+#[cfg(feature = "telemetry_otlp")]
 struct MetricExporterBuilder {
     exporter: Option<TonicExporterBuilder>,
 }
 
+#[cfg(feature = "telemetry_otlp")]
 impl From<TonicExporterBuilder> for MetricExporterBuilder {
     fn from(exporter: TonicExporterBuilder) -> Self {
         Self {
@@ -28,12 +42,14 @@ impl From<TonicExporterBuilder> for MetricExporterBuilder {
     }
 }
 
+#[cfg(feature = "telemetry_otlp")]
 impl From<HttpExporterBuilder> for MetricExporterBuilder {
     fn from(_exporter: HttpExporterBuilder) -> Self {
         Self { exporter: None }
     }
 }
 
+#[cfg(feature = "telemetry_otlp")]
 impl MetricsConfigurator for super::super::otlp::Config {
     fn apply(
         &self,
@@ -47,6 +63,13 @@ impl MetricsConfigurator for super::super::otlp::Config {
                     .metrics(tokio::spawn, delayed_interval)
                     .with_exporter(exporter)
                     .with_aggregator_selector(selectors::simple::Selector::Exact)
+                    .with(
+                        &self
+                            .batch_processor
+                            .as_ref()
+                            .and_then(|batch| batch.scheduled_delay),
+                        |b, d| b.with_period(*d),
+                    )
                     .with_resource(
                         metrics_config
                             .resources
@@ -64,6 +87,18 @@ impl MetricsConfigurator for super::super::otlp::Config {
     }
 }
 
+#[cfg(feature = "telemetry_otlp")]
 fn delayed_interval(duration: Duration) -> impl Stream<Item = tokio::time::Instant> {
     tokio_interval_stream(duration).skip(1)
 }
+
+#[cfg(not(feature = "telemetry_otlp"))]
+impl MetricsConfigurator for super::super::otlp::Config {
+    fn apply(
+        &self,
+        _builder: MetricsBuilder,
+        _metrics_config: &MetricsCommon,
+    ) -> Result<MetricsBuilder, BoxError> {
+        Err(not_compiled("OTLP exporter", "telemetry_otlp").into())
+    }
+}
@@ -1,22 +1,36 @@
+#[cfg(feature = "telemetry_prometheus")]
 use std::task::Context;
+#[cfg(feature = "telemetry_prometheus")]
 use std::task::Poll;
 
+#[cfg(feature = "telemetry_prometheus")]
 use futures::future::BoxFuture;
+#[cfg(feature = "telemetry_prometheus")]
 use http::StatusCode;
+#[cfg(feature = "telemetry_prometheus")]
 use opentelemetry::sdk::Resource;
+#[cfg(feature = "telemetry_prometheus")]
 use opentelemetry::KeyValue;
+#[cfg(feature = "telemetry_prometheus")]
 use prometheus::Encoder;
+#[cfg(feature = "telemetry_prometheus")]
 use prometheus::Registry;
+#[cfg(feature = "telemetry_prometheus")]
 use prometheus::TextEncoder;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use tower::BoxError;
+#[cfg(feature = "telemetry_prometheus")]
 use tower::ServiceExt;
+#[cfg(feature = "telemetry_prometheus")]
 use tower_service::Service;
 
 use crate::plugins::telemetry::config::MetricsCommon;
 use crate::plugins::telemetry::metrics::MetricsBuilder;
 use crate::plugins::telemetry::metrics::MetricsConfigurator;
+#[cfg(not(feature = "telemetry_prometheus"))]
+use crate::plugins::telemetry::not_compiled;
+#[cfg(feature = "telemetry_prometheus")]
 use crate::services::transport;
 
 #[derive(Debug, Clone, Deserialize, JsonSchema)]
@@ -25,6 +39,7 @@ pub(crate) struct Config {
     enabled: bool,
 }
 
+#[cfg(feature = "telemetry_prometheus")]
 impl MetricsConfigurator for Config {
     fn apply(
         &self,
@@ -58,11 +73,27 @@ impl MetricsConfigurator for Config {
     }
 }
 
+#[cfg(not(feature = "telemetry_prometheus"))]
+impl MetricsConfigurator for Config {
+    fn apply(
+        &self,
+        builder: MetricsBuilder,
+        _metrics_config: &MetricsCommon,
+    ) -> Result<MetricsBuilder, BoxError> {
+        if self.enabled {
+            return Err(not_compiled("Prometheus exporter", "telemetry_prometheus").into());
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(feature = "telemetry_prometheus")]
 #[derive(Clone)]
 pub(crate) struct PrometheusService {
     registry: Registry,
 }
 
+#[cfg(feature = "telemetry_prometheus")]
 impl Service<transport::Request> for PrometheusService {
     type Response = transport::Response;
     type Error = BoxError;
@@ -3,12 +3,16 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicU8;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 
+use ::tracing::dispatcher::Dispatch;
 use ::tracing::info_span;
 use ::tracing::subscriber::set_global_default;
 use ::tracing::Span;
@@ -18,9 +22,9 @@ use apollo_spaceport::StatsContext;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use futures::StreamExt;
-use http::HeaderValue;
 use http::StatusCode;
 use metrics::apollo::Sender;
+use once_cell::sync::Lazy;
 use once_cell::sync::OnceCell;
 use opentelemetry::global;
 use opentelemetry::propagation::TextMapPropagator;
@@ -39,36 +43,53 @@ use tower::ServiceBuilder;
 use tower::ServiceExt;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::registry::LookupSpan;
-use tracing_subscriber::EnvFilter;
 use tracing_subscriber::Registry;
 use url::Url;
 
+use self::apollo::MissingClient;
 use self::config::Conf;
+use self::logging::LoggingFormat;
 use self::metrics::AttributesForwardConf;
 use self::metrics::MetricsAttributesConf;
-use crate::executable::GLOBAL_ENV_FILTER;
+use self::span_attributes::RequestData;
+use self::span_attributes::ResponseData;
+use crate::configuration::ACTIVE_SCHEDULES_CONTEXT_KEY;
 use crate::layers::ServiceBuilderExt;
 use crate::plugin::Handler;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
+use crate::plugins::demand_control::ACTUAL_COST_CONTEXT_KEY;
+use crate::plugins::demand_control::ESTIMATED_COST_CONTEXT_KEY;
+use crate::plugins::override_comparison::OVERRIDE_COMPARISON_CONTEXT_KEY;
+use crate::plugins::renames::RENAMES_CONTEXT_KEY;
+use crate::plugins::schema_drift::SCHEMA_DRIFT_CONTEXT_KEY;
 use crate::plugins::telemetry::config::MetricsCommon;
 use crate::plugins::telemetry::config::Trace;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleContextualizedStats;
+use crate::plugins::telemetry::metrics::apollo::studio::SingleFieldStat;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleQueryLatencyStats;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleReport;
 use crate::plugins::telemetry::metrics::apollo::studio::SingleTracesAndStats;
+use crate::plugins::telemetry::metrics::apollo::studio::SingleTypeStat;
 use crate::plugins::telemetry::metrics::AggregateMeterProvider;
 use crate::plugins::telemetry::metrics::BasicMetrics;
 use crate::plugins::telemetry::metrics::MetricsBuilder;
 use crate::plugins::telemetry::metrics::MetricsConfigurator;
 use crate::plugins::telemetry::metrics::MetricsExporterHandle;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
+use crate::plugins::traffic_shaping::HEDGING_CONTEXT_KEY;
+use crate::query_planner::PLANNING_TIMEOUT_CONTEXT_KEY;
 use crate::query_planner::USAGE_REPORTING;
 use crate::register_plugin;
+use crate::services::canary::CANARY_CONTEXT_KEY;
 use crate::services::execution;
+use crate::services::layers::plugin_timing::PluginTiming;
+use crate::services::layers::plugin_timing::PLUGIN_TIMING_CONTEXT_KEY;
 use crate::services::subgraph;
 use crate::services::supergraph;
 use crate::services::transport;
+use crate::spec::FieldCounts;
+use crate::ApolloRouterError;
 use crate::Context;
 use crate::ExecutionRequest;
 use crate::SubgraphRequest;
@@ -78,19 +99,32 @@ use crate::SupergraphResponse;
 
 pub(crate) mod apollo;
 pub(crate) mod config;
+pub(crate) mod logging;
 mod metrics;
 mod otlp;
+mod span_attributes;
 mod tracing;
 
 static SUPERGRAPH_SPAN_NAME: &str = "supergraph";
-static CLIENT_NAME: &str = "apollo_telemetry::client_name";
-static CLIENT_VERSION: &str = "apollo_telemetry::client_version";
+pub(crate) static CLIENT_NAME: &str = "apollo_telemetry::client_name";
+pub(crate) static CLIENT_VERSION: &str = "apollo_telemetry::client_version";
+/// Sampling rate of the field-level statistics, set if they are collected for the request
+pub(crate) static FIELD_LEVEL_INSTRUMENTATION: &str =
+    "apollo_telemetry::field_level_instrumentation";
+pub(crate) static FIELD_COUNTS: &str = "apollo_telemetry::field_counts";
 const ATTRIBUTES: &str = "apollo_telemetry::metrics_attributes";
 const SUBGRAPH_ATTRIBUTES: &str = "apollo_telemetry::subgraph_metrics_attributes";
 pub(crate) static STUDIO_EXCLUDE: &str = "apollo_telemetry::studio::exclude";
 const DEFAULT_SERVICE_NAME: &str = "apollo-router";
 
 static TELEMETRY_LOADED: OnceCell<bool> = OnceCell::new();
+/// The log level of the command line, set by the `router` executable.
+pub(crate) static GLOBAL_ENV_FILTER: OnceCell<String> = OnceCell::new();
+
+/// Builds the global subscriber from a subscriber installed with [`set_tracing_subscriber`] and
+/// the router's tracer.
+type SubscriberFactory = Box<dyn FnOnce(opentelemetry::sdk::trace::Tracer) -> Dispatch + Send>;
+static CUSTOM_SUBSCRIBER: Lazy<Mutex<Option<SubscriberFactory>>> = Lazy::new(Default::default);
 static TELEMETRY_REFCOUNT: AtomicU8 = AtomicU8::new(0);
 
 #[doc(hidden)] // Only public for integration tests
@@ -104,6 +138,8 @@ pub struct Telemetry {
     custom_endpoints: HashMap<String, Handler>,
     spaceport_shutdown: Option<futures::channel::oneshot::Sender<()>>,
     apollo_metrics_sender: metrics::apollo::Sender,
+    #[cfg(feature = "allocation_metrics")]
+    _allocation_metrics: metrics::AllocationMetrics,
 }
 
 #[derive(Debug)]
@@ -139,6 +175,20 @@ fn setup_metrics_exporter<T: MetricsConfigurator>(
     Ok(builder)
 }
 
+/// The error of an exporter configured in a router built without its cargo feature.
+#[cfg(not(all(
+    feature = "telemetry_datadog",
+    feature = "telemetry_jaeger",
+    feature = "telemetry_otlp",
+    feature = "telemetry_prometheus",
+    feature = "telemetry_zipkin"
+)))]
+pub(crate) fn not_compiled(exporter: &str, feature: &str) -> String {
+    format!(
+        "the {exporter} is not compiled into this router: it needs the `{feature}` cargo feature"
+    )
+}
+
 impl Drop for Telemetry {
     fn drop(&mut self) {
         if let Some(sender) = self.spaceport_shutdown.take() {
@@ -168,10 +218,40 @@ impl Plugin for Telemetry {
         let metrics = BasicMetrics::new(&self.meter_provider);
         let config = Arc::new(self.config.clone());
         let config_map_res = config.clone();
+        let span_attributes = Arc::new(
+            config
+                .tracing
+                .as_ref()
+                .and_then(|tracing| tracing.span_attributes.as_ref())
+                .map(|conf| conf.supergraph.clone())
+                .unwrap_or_default(),
+        );
+        let response_span_attributes = span_attributes.clone();
+        let apollo_config = config.apollo.clone().unwrap_or_default();
+        let service = if apollo_config.client_awareness.missing == MissingClient::Reject {
+            Self::reject_missing_client(apollo_config.clone(), service)
+        } else {
+            service
+        };
         ServiceBuilder::new()
             .instrument(Self::supergraph_service_span(
-                config.apollo.clone().unwrap_or_default(),
+                apollo_config,
+                span_attributes,
             ))
+            .map_response(move |response: SupergraphResponse| {
+                span_attributes::set_attributes(
+                    &Span::current(),
+                    span_attributes::response_attributes(
+                        &response_span_attributes,
+                        &ResponseData {
+                            headers: response.response.headers(),
+                            status: response.response.status(),
+                            context: &response.context,
+                        },
+                    ),
+                );
+                response
+            })
             .map_future_with_request_data(
                 move |req: &SupergraphRequest| {
                     Self::populate_context(config.clone(), req);
@@ -353,6 +433,15 @@ impl Plugin for Telemetry {
                 }),
         );
         let subgraph_metrics_conf = subgraph_metrics.clone();
+        let span_attributes = Arc::new(
+            self.config
+                .tracing
+                .as_ref()
+                .and_then(|tracing| tracing.span_attributes.as_ref())
+                .map(|conf| conf.subgraph(&name))
+                .unwrap_or_default(),
+        );
+        let response_span_attributes = span_attributes.clone();
         ServiceBuilder::new()
             .instrument(move |req: &SubgraphRequest| {
                 let query = req
@@ -368,12 +457,45 @@ impl Plugin for Telemetry {
                     .clone()
                     .unwrap_or_default();
 
-                info_span!("subgraph",
+                let span = info_span!("subgraph",
                     name = name.as_str(),
                     graphql.document = query.as_str(),
                     graphql.operation.name = operation_name.as_str(),
                     "otel.kind" = %SpanKind::Internal,
-                )
+                );
+                let client_name: Option<String> = req.context.get(CLIENT_NAME).ok().flatten();
+                let client_version: Option<String> = req.context.get(CLIENT_VERSION).ok().flatten();
+                span_attributes::set_attributes(
+                    &span,
+                    span_attributes::request_attributes(
+                        &span_attributes,
+                        &RequestData {
+                            headers: req.subgraph_request.headers(),
+                            operation_name: req
+                                .originating_request
+                                .body()
+                                .operation_name
+                                .as_deref(),
+                            client_name: client_name.as_deref(),
+                            client_version: client_version.as_deref(),
+                        },
+                    ),
+                );
+                span
+            })
+            .map_response(move |response: SubgraphResponse| {
+                span_attributes::set_attributes(
+                    &Span::current(),
+                    span_attributes::response_attributes(
+                        &response_span_attributes,
+                        &ResponseData {
+                            headers: response.response.headers(),
+                            status: response.response.status(),
+                            context: &response.context,
+                        },
+                    ),
+                );
+                response
             })
             .map_future_with_request_data(
                 move |sub_request: &SubgraphRequest| {
@@ -504,6 +626,36 @@ impl Plugin for Telemetry {
     }
 }
 
+/// Installs the `tracing` subscriber of the router, to compose its telemetry with the
+/// subscriber of an application embedding it.
+///
+/// The router's OpenTelemetry layer is added to the subscriber, which becomes the global default
+/// subscriber when the router starts. The `telemetry.logging` configuration is then ignored: the
+/// subscriber decides how events are logged. This must be called once, before the router starts.
+///
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// apollo_router::set_tracing_subscriber(
+///     tracing_subscriber::registry().with(tracing_subscriber::fmt::layer()),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn set_tracing_subscriber<S>(subscriber: S) -> Result<(), ApolloRouterError>
+where
+    S: Subscriber + Send + Sync + for<'span> LookupSpan<'span> + 'static,
+{
+    let mut custom_subscriber = CUSTOM_SUBSCRIBER.lock().expect("lock poisoned");
+    if TELEMETRY_LOADED.get().is_some() || custom_subscriber.is_some() {
+        return Err(ApolloRouterError::TracingSubscriberAlreadySet);
+    }
+    *custom_subscriber = Some(Box::new(move |tracer| {
+        Dispatch::new(subscriber.with(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }));
+    Ok(())
+}
+
 impl Telemetry {
     /// This method can be used instead of `Plugin::new` to override the subscriber
     pub async fn new_with_subscriber<S>(
@@ -524,6 +676,16 @@ impl Telemetry {
     where
         S: Subscriber + Send + Sync + for<'span> LookupSpan<'span>,
     {
+        if let Some(span_attributes) = config
+            .tracing
+            .as_ref()
+            .and_then(|tracing| tracing.span_attributes.as_ref())
+        {
+            span_attributes.validate()?;
+        }
+        let logging = config.logging.clone().unwrap_or_default();
+        logging.validate()?;
+
         // Apollo config is special because we enable tracing if some env variables are present.
         let apollo = config
             .apollo
@@ -564,7 +726,6 @@ impl Telemetry {
 
         // the global tracer and subscriber initialization step must be performed only once
         TELEMETRY_LOADED.get_or_try_init::<_, BoxError>(|| {
-            use anyhow::Context;
             let tracer_provider = Self::create_tracer_provider(&config)?;
 
             let tracer = tracer_provider.versioned_tracer(
@@ -583,40 +744,51 @@ impl Telemetry {
                 .map(|s| s.as_str())
                 .unwrap_or("info");
 
-            let sub_builder = tracing_subscriber::fmt::fmt().with_env_filter(
-                EnvFilter::try_new(log_level).context("could not parse log configuration")?,
-            );
-
-            if let Some(sub) = subscriber {
-                let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-                let subscriber = sub.with(telemetry);
-                if let Err(e) = set_global_default(subscriber) {
-                    ::tracing::error!("cannot set global subscriber: {:?}", e);
-                }
-            } else if atty::is(atty::Stream::Stdout) {
-                let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-
-                let subscriber = sub_builder.finish().with(telemetry);
-                if let Err(e) = set_global_default(subscriber) {
-                    ::tracing::error!("cannot set global subscriber: {:?}", e);
-                }
+            let custom_subscriber = CUSTOM_SUBSCRIBER.lock().expect("lock poisoned").take();
+            let result = if let Some(sub) = subscriber {
+                set_global_default(sub.with(tracing_opentelemetry::layer().with_tracer(tracer)))
+            } else if let Some(custom_subscriber) = custom_subscriber {
+                ::tracing::dispatcher::set_global_default(custom_subscriber(tracer))
             } else {
-                let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-
-                let subscriber = sub_builder.json().finish().with(telemetry);
-                if let Err(e) = set_global_default(subscriber) {
-                    ::tracing::error!("cannot set global subscriber: {:?}", e);
+                let sub_builder = tracing_subscriber::fmt::fmt()
+                    .with_env_filter(logging.env_filter(log_level)?)
+                    .with_target(logging.display_target.unwrap_or(true))
+                    .with_writer(logging.writer()?);
+                match logging.format() {
+                    (LoggingFormat::Text, ansi) => set_global_default(
+                        sub_builder
+                            .with_ansi(ansi)
+                            .finish()
+                            .with(tracing_opentelemetry::layer().with_tracer(tracer)),
+                    ),
+                    (LoggingFormat::Json, _) => {
+                        let display_span_list = logging.display_span_list.unwrap_or(true);
+                        set_global_default(
+                            sub_builder
+                                .json()
+                                .with_current_span(display_span_list)
+                                .with_span_list(display_span_list)
+                                .finish()
+                                .with(tracing_opentelemetry::layer().with_tracer(tracer)),
+                        )
+                    }
                 }
             };
+            if let Err(e) = result {
+                ::tracing::error!("cannot set global subscriber: {:?}", e);
+            }
 
             Ok(true)
         })?;
 
+        let meter_provider = builder.meter_provider();
         let plugin = Ok(Telemetry {
             spaceport_shutdown: shutdown_tx,
             custom_endpoints: builder.custom_endpoints(),
             _metrics_exporters: builder.exporters(),
-            meter_provider: builder.meter_provider(),
+            #[cfg(feature = "allocation_metrics")]
+            _allocation_metrics: metrics::AllocationMetrics::new(&meter_provider),
+            meter_provider,
             apollo_metrics_sender: builder.apollo_metrics_provider(),
             config,
         });
@@ -659,13 +831,25 @@ impl Telemetry {
             propagators.push(Box::new(TraceContextPropagator::default()));
         }
         if propagation.zipkin.unwrap_or_default() || tracing.zipkin.is_some() {
+            #[cfg(feature = "telemetry_zipkin")]
             propagators.push(Box::new(opentelemetry_zipkin::Propagator::default()));
+            #[cfg(not(feature = "telemetry_zipkin"))]
+            ::tracing::warn!("{}", not_compiled("Zipkin propagator", "telemetry_zipkin"));
         }
         if propagation.jaeger.unwrap_or_default() || tracing.jaeger.is_some() {
+            #[cfg(feature = "telemetry_jaeger")]
             propagators.push(Box::new(opentelemetry_jaeger::Propagator::default()));
+            #[cfg(not(feature = "telemetry_jaeger"))]
+            ::tracing::warn!("{}", not_compiled("Jaeger propagator", "telemetry_jaeger"));
         }
         if propagation.datadog.unwrap_or_default() || tracing.datadog.is_some() {
+            #[cfg(feature = "telemetry_datadog")]
             propagators.push(Box::new(opentelemetry_datadog::DatadogPropagator::default()));
+            #[cfg(not(feature = "telemetry_datadog"))]
+            ::tracing::warn!(
+                "{}",
+                not_compiled("Datadog propagator", "telemetry_datadog")
+            );
         }
 
         TextMapCompositePropagator::new(propagators)
@@ -727,12 +911,45 @@ impl Telemetry {
         )
     }
 
+    /// Rejects the requests without a valid client name or version.
+    fn reject_missing_client(
+        config: apollo::Config,
+        service: supergraph::BoxService,
+    ) -> supergraph::BoxService {
+        ServiceBuilder::new()
+            .checkpoint(move |req: SupergraphRequest| {
+                let missing = config.missing_client_headers(req.originating_request.headers());
+                if missing.is_empty() {
+                    return Ok(ControlFlow::Continue(req));
+                }
+                ::tracing::trace!("request without client awareness headers");
+                let error = crate::error::Error::builder()
+                    .message(format!(
+                        "This operation has been rejected because it does not identify its client: \
+                        the following headers are required: {}",
+                        missing
+                            .iter()
+                            .map(|name| name.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                    .extension("code", "MISSING_CLIENT_AWARENESS")
+                    .build();
+                let res = SupergraphResponse::builder()
+                    .error(error)
+                    .status_code(StatusCode::BAD_REQUEST)
+                    .context(req.context)
+                    .build()?;
+                Ok(ControlFlow::Break(res))
+            })
+            .service(service)
+            .boxed()
+    }
+
     fn supergraph_service_span(
         config: apollo::Config,
+        span_attributes: Arc<Vec<span_attributes::SpanAttribute>>,
     ) -> impl Fn(&SupergraphRequest) -> Span + Clone {
-        let client_name_header = config.client_name_header;
-        let client_version_header = config.client_version_header;
-
         move |request: &SupergraphRequest| {
             let http_request = &request.originating_request;
             let headers = http_request.headers();
@@ -742,23 +959,28 @@ impl Telemetry {
                 .operation_name
                 .clone()
                 .unwrap_or_default();
-            let client_name = headers
-                .get(&client_name_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""));
-            let client_version = headers
-                .get(&client_version_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""));
+            let (client_name, client_version) = config.client(headers);
             let span = info_span!(
                 SUPERGRAPH_SPAN_NAME,
                 graphql.document = query.as_str(),
                 // TODO add graphql.operation.type
                 graphql.operation.name = operation_name.as_str(),
-                client_name = client_name.to_str().unwrap_or_default(),
-                client_version = client_version.to_str().unwrap_or_default(),
+                client_name = client_name.as_str(),
+                client_version = client_version.as_str(),
                 "otel.kind" = %SpanKind::Internal
             );
+            span_attributes::set_attributes(
+                &span,
+                span_attributes::request_attributes(
+                    &span_attributes,
+                    &RequestData {
+                        headers,
+                        operation_name: Some(operation_name.as_str()),
+                        client_name: Some(client_name.as_str()),
+                        client_version: Some(client_version.as_str()),
+                    },
+                ),
+            );
             span
         }
     }
@@ -777,6 +999,9 @@ impl Telemetry {
             let persisted_query_hit = context
                 .get::<_, bool>("persisted_query_hit")
                 .unwrap_or_default();
+            let field_level_instrumentation = context
+                .get::<_, f64>(FIELD_LEVEL_INSTRUMENTATION)
+                .unwrap_or_default();
 
             if context
                 .get(STUDIO_EXCLUDE)
@@ -808,9 +1033,13 @@ impl Telemetry {
                                     latency: duration,
                                     has_errors,
                                     persisted_query_hit,
+                                    without_field_instrumentation: field_level_instrumentation
+                                        .is_none(),
                                     ..Default::default()
                                 },
-                                ..Default::default()
+                                per_type_stat: field_level_instrumentation
+                                    .map(|rate| Self::per_type_stat(context, rate))
+                                    .unwrap_or_default(),
                             },
                             referenced_fields_by_type: usage_reporting
                                 .referenced_fields_by_type
@@ -831,6 +1060,32 @@ impl Telemetry {
         sender.send(metrics);
     }
 
+    /// Field-level statistics of a request, estimating the executions of all requests from the
+    /// sampled ones.
+    fn per_type_stat(context: &Context, rate: f64) -> HashMap<String, SingleTypeStat> {
+        context
+            .get::<_, FieldCounts>(FIELD_COUNTS)
+            .unwrap_or_default()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(parent_type, fields)| {
+                let per_field_stat = fields
+                    .into_iter()
+                    .map(|(field, count)| {
+                        let stat = SingleFieldStat {
+                            return_type: count.return_type,
+                            observed_execution_count: count.count,
+                            estimated_execution_count: count.count as f64 / rate,
+                            ..Default::default()
+                        };
+                        (field, stat)
+                    })
+                    .collect();
+                (parent_type, SingleTypeStat { per_field_stat })
+            })
+            .collect()
+    }
+
     async fn update_metrics(
         config: Arc<Conf>,
         context: Context,
@@ -849,6 +1104,12 @@ impl Telemetry {
                     .collect::<Vec<KeyValue>>()
             })
             .unwrap_or_default();
+        if let Ok(Some(version)) = context.get::<_, String>(CANARY_CONTEXT_KEY) {
+            metric_attrs.push(KeyValue::new("schema", version));
+        }
+        if let Ok(Some(schedules)) = context.get::<_, Vec<String>>(ACTIVE_SCHEDULES_CONTEXT_KEY) {
+            metric_attrs.push(KeyValue::new("schedules", schedules.join(",")));
+        }
         let res = match result {
             Ok(response) => {
                 metric_attrs.push(KeyValue::new(
@@ -887,6 +1148,89 @@ impl Telemetry {
         metrics
             .http_requests_duration
             .record(request_duration.as_secs_f64(), &metric_attrs);
+        if let Ok(Some(cost)) = context.get::<_, f64>(ESTIMATED_COST_CONTEXT_KEY) {
+            metrics.estimated_cost.record(cost, &metric_attrs);
+        }
+        if let Ok(Some(cost)) = context.get::<_, f64>(ACTUAL_COST_CONTEXT_KEY) {
+            metrics.actual_cost.record(cost, &metric_attrs);
+        }
+        if let Ok(Some(drifts)) =
+            context.get::<_, HashMap<String, HashMap<String, u64>>>(SCHEMA_DRIFT_CONTEXT_KEY)
+        {
+            for (subgraph, counts) in drifts {
+                for (kind, count) in counts {
+                    metrics.schema_drift_total.add(
+                        count,
+                        &[
+                            KeyValue::new("subgraph", subgraph.clone()),
+                            KeyValue::new("kind", kind),
+                        ],
+                    );
+                }
+            }
+        }
+        if let Ok(Some(comparisons)) =
+            context.get::<_, HashMap<String, HashMap<String, u64>>>(OVERRIDE_COMPARISON_CONTEXT_KEY)
+        {
+            for (field, counts) in comparisons {
+                for (outcome, count) in counts {
+                    metrics.override_comparison_total.add(
+                        count,
+                        &[
+                            KeyValue::new("field", field.clone()),
+                            KeyValue::new("outcome", outcome),
+                        ],
+                    );
+                }
+            }
+        }
+        if let Ok(Some(renames)) = context.get::<_, HashMap<String, u64>>(RENAMES_CONTEXT_KEY) {
+            for (renamed, count) in renames {
+                metrics
+                    .renames_total
+                    .add(count, &[KeyValue::new("renamed", renamed)]);
+            }
+        }
+        if let Ok(Some(hedges)) =
+            context.get::<_, HashMap<String, HashMap<String, u64>>>(HEDGING_CONTEXT_KEY)
+        {
+            for (subgraph, counts) in hedges {
+                for (outcome, count) in counts {
+                    metrics.subgraph_hedges_total.add(
+                        count,
+                        &[
+                            KeyValue::new("subgraph", subgraph.clone()),
+                            KeyValue::new("outcome", outcome),
+                        ],
+                    );
+                }
+            }
+        }
+        if let Ok(Some(outcome)) = context.get::<_, String>(PLANNING_TIMEOUT_CONTEXT_KEY) {
+            metrics
+                .query_planning_timeouts_total
+                .add(1, &[KeyValue::new("outcome", outcome)]);
+        }
+        if let Ok(Some(timings)) = context
+            .get::<_, HashMap<String, HashMap<String, PluginTiming>>>(PLUGIN_TIMING_CONTEXT_KEY)
+        {
+            for (plugin, stages) in timings {
+                for (stage, timing) in stages {
+                    let attributes = [
+                        KeyValue::new("plugin", plugin.clone()),
+                        KeyValue::new("stage", stage),
+                    ];
+                    metrics
+                        .plugin_duration
+                        .record(timing.duration.max(0.0), &attributes);
+                    if timing.errors > 0 {
+                        metrics
+                            .plugin_errors_total
+                            .add(timing.errors as u64, &attributes);
+                    }
+                }
+            }
+        }
 
         res
     }
@@ -896,28 +1240,16 @@ impl Telemetry {
         let context = &req.context;
         let http_request = &req.originating_request;
         let headers = http_request.headers();
-        let client_name_header = &apollo_config.client_name_header;
-        let client_version_header = &apollo_config.client_version_header;
-        let _ = context.insert(
-            CLIENT_NAME,
-            headers
-                .get(client_name_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""))
-                .to_str()
-                .unwrap_or_default()
-                .to_string(),
-        );
-        let _ = context.insert(
-            CLIENT_VERSION,
-            headers
-                .get(client_version_header)
-                .cloned()
-                .unwrap_or_else(|| HeaderValue::from_static(""))
-                .to_str()
-                .unwrap_or_default()
-                .to_string(),
-        );
+        let (client_name, client_version) = apollo_config.client(headers);
+        let _ = context.insert(CLIENT_NAME, client_name.clone());
+        let _ = context.insert(CLIENT_VERSION, client_version.clone());
+        let rate = apollo_config.field_level_instrumentation_sampler;
+        if apollo_config.apollo_key.is_some()
+            && apollo_config.apollo_graph_ref.is_some()
+            && sample_field_level_instrumentation(rate)
+        {
+            let _ = context.insert(FIELD_LEVEL_INSTRUMENTATION, rate);
+        }
         if let Some(metrics_conf) = &config.metrics {
             // List of custom attributes for metrics
             let mut attributes: HashMap<String, String> = HashMap::new();
@@ -938,11 +1270,27 @@ impl Telemetry {
                 attributes.extend(router_attributes_conf.get_attributes_from_context(context));
             }
 
+            if apollo_config.client_awareness.metrics_attributes {
+                attributes.insert("client_name".to_string(), client_name);
+                attributes.insert("client_version".to_string(), client_version);
+            }
+
             let _ = context.insert(ATTRIBUTES, attributes);
         }
     }
 }
 
+/// Whether field-level statistics are collected for a request, sampling one request out of
+/// `1 / rate`.
+fn sample_field_level_instrumentation(rate: f64) -> bool {
+    static REQUESTS: AtomicU64 = AtomicU64::new(0);
+    if rate <= 0.0 {
+        return false;
+    }
+    let request = REQUESTS.fetch_add(1, Ordering::Relaxed) as f64;
+    (request * rate).floor() != ((request + 1.0) * rate).floor()
+}
+
 // Planner errors return stats report key that start with `## `
 // while successful planning stats report key start with `# `
 fn operation_count(stats_report_key: &str) -> u64 {
@@ -983,9 +1331,13 @@ register_plugin!("apollo", "telemetry", Telemetry);
 //
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::str::FromStr;
 
     use http::StatusCode;
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry::trace::TraceContextExt;
+    use opentelemetry::trace::TraceId;
     use serde_json::Value;
     use serde_json_bytes::json;
     use serde_json_bytes::ByteString;
@@ -1167,6 +1519,7 @@ mod tests {
             .unwrap();
     }
 
+    #[cfg(feature = "telemetry_prometheus")]
     #[tokio::test(flavor = "multi_thread")]
     async fn it_test_prometheus_metrics() {
         let mut mock_service = MockSupergraphService::new();
@@ -1215,6 +1568,7 @@ mod tests {
                 Err(Box::new(FetchError::SubrequestHttpError {
                     service: String::from("my_subgraph_name_error"),
                     reason: String::from("cannot contact the subgraph"),
+                    status: None,
                 }))
             });
 
@@ -1417,4 +1771,51 @@ mod tests {
         assert!(prom_metrics.contains(r#"http_request_duration_seconds_sum{another_test="my_default_value",my_value="2",myname="label_value",renamed_value="my_value_set",service_name="apollo-router",status="200",x_custom="coming_from_header"}"#));
         assert!(prom_metrics.contains(r#"http_request_duration_seconds_bucket{error="INTERNAL_SERVER_ERROR",my_key="my_custom_attribute_from_context",query_from_request="query { test }",service_name="apollo-router",status="200",subgraph="my_subgraph_name",unknown_data="default_value",le="1"}"#));
     }
+
+    #[test]
+    fn field_level_instrumentation_is_sampled() {
+        let sampled = |rate| {
+            (0..100)
+                .filter(|_| super::sample_field_level_instrumentation(rate))
+                .count()
+        };
+        assert_eq!(sampled(0.0), 0);
+        assert_eq!(sampled(0.25), 25);
+        assert_eq!(sampled(1.0), 100);
+    }
+
+    #[cfg(feature = "telemetry_datadog")]
+    #[test]
+    fn datadog_headers_are_propagated() {
+        let config: super::config::Conf = serde_json::from_value(serde_json::json!({
+            "tracing": { "datadog": { "endpoint": "default" } }
+        }))
+        .unwrap();
+        let propagator = super::Telemetry::create_propagator(&config);
+
+        let headers: HashMap<String, String> = [
+            ("x-datadog-trace-id", "1234"),
+            ("x-datadog-parent-id", "5678"),
+            ("x-datadog-sampling-priority", "1"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let context = propagator.extract(&headers);
+        assert_eq!(
+            context.span().span_context().trace_id(),
+            TraceId::from_u128(1234)
+        );
+
+        let mut injected = HashMap::new();
+        propagator.inject_context(&context, &mut injected);
+        assert_eq!(
+            injected.get("x-datadog-trace-id").map(String::as_str),
+            Some("1234")
+        );
+        assert_eq!(
+            injected.get("x-datadog-parent-id").map(String::as_str),
+            Some("5678")
+        );
+    }
 }
@@ -5,8 +5,11 @@ use std::time::Duration;
 
 use indexmap::map::Entry;
 use indexmap::IndexMap;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry_otlp::HttpExporterBuilder;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry_otlp::TonicExporterBuilder;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry_otlp::WithExportConfig;
 use schemars::JsonSchema;
 use serde::Deserialize;
@@ -35,8 +38,10 @@ pub(crate) struct Config {
     pub(crate) timeout: Option<Duration>,
     pub(crate) grpc: Option<GrpcExporter>,
     pub(crate) http: Option<HttpExporter>,
+    pub(crate) batch_processor: Option<BatchProcessorConfig>,
 }
 
+#[cfg(feature = "telemetry_otlp")]
 impl Config {
     pub(crate) fn exporter<T: From<HttpExporterBuilder> + From<TonicExporterBuilder>>(
         &self,
@@ -69,6 +74,9 @@ impl Config {
                     .with(&self.timeout, |b, t| b.with_timeout(*t))
                     .with(&endpoint, |b, e| b.with_endpoint(e.as_str()))
                     .with(&http.headers, |b, h| b.with_headers(h.clone()))
+                    .try_with(&http.tls_config, |b, t| {
+                        Ok(b.with_http_client(reqwest::Client::try_from(t)?))
+                    })?
                     .into();
 
                 Ok(exporter)
@@ -107,6 +115,8 @@ pub(crate) enum EndpointDefault {
 #[derive(Debug, Clone, Deserialize, Serialize, Default, JsonSchema)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct HttpExporter {
+    #[serde(flatten)]
+    pub(crate) tls_config: Option<TlsConfig>,
     pub(crate) headers: Option<HashMap<String, String>>,
 }
 
@@ -158,6 +168,43 @@ impl TryFrom<&TlsConfig> for tonic::transport::channel::ClientTlsConfig {
     }
 }
 
+impl TryFrom<&TlsConfig> for reqwest::Client {
+    type Error = BoxError;
+
+    fn try_from(config: &TlsConfig) -> Result<reqwest::Client, Self::Error> {
+        if config.domain_name.is_some() {
+            return Err("domain_name is only supported by the grpc protocol".into());
+        }
+        Ok(reqwest::Client::builder()
+            .try_with(&config.ca, |b, c| {
+                Ok(b.add_root_certificate(reqwest::Certificate::from_pem(c.read()?.as_bytes())?))
+            })?
+            .try_with(
+                &config.cert.clone().zip(config.key.clone()),
+                |b, (cert, key)| {
+                    let pem = format!("{}\n{}", key.read()?, cert.read()?);
+                    Ok(b.identity(reqwest::Identity::from_pem(pem.as_bytes())?))
+                },
+            )?
+            .build()?)
+    }
+}
+
+/// Batching of the exports
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BatchProcessorConfig {
+    /// Delay between two exports, which is also the export period of metrics (default: 5s for
+    /// traces)
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    pub(crate) scheduled_delay: Option<Duration>,
+    /// Number of spans kept in memory, spans are dropped when the queue is full (default: 2048)
+    pub(crate) max_queue_size: Option<usize>,
+    /// Maximum number of spans per export (default: 512)
+    pub(crate) max_export_batch_size: Option<usize>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields, rename_all = "snake_case")]
 pub(crate) enum Secret {
@@ -294,4 +341,34 @@ mod tests {
             config.endpoint
         );
     }
+
+    #[test]
+    fn batch_and_http_tls_configuration() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+endpoint: https://collector:4318
+protocol: http
+http:
+  domain_name: collector
+  ca:
+    env: OTLP_CA
+batch_processor:
+  scheduled_delay: 1s
+  max_queue_size: 4096
+  max_export_batch_size: 1024
+"#,
+        )
+        .unwrap();
+        let batch = config.batch_processor.unwrap();
+        assert_eq!(batch.scheduled_delay, Some(Duration::from_secs(1)));
+        assert_eq!(batch.max_queue_size, Some(4096));
+        assert_eq!(batch.max_export_batch_size, Some(1024));
+
+        let tls = config.http.unwrap().tls_config.unwrap();
+        assert!(matches!(tls.ca, Some(Secret::Env(ref name)) if name == "OTLP_CA"));
+        assert_eq!(
+            reqwest::Client::try_from(&tls).unwrap_err().to_string(),
+            "domain_name is only supported by the grpc protocol"
+        );
+    }
 }
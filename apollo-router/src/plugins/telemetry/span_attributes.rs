@@ -0,0 +1,311 @@
+//! Custom attributes of the supergraph and subgraph spans.
+//!
+//! Attributes are read from the request when the span is created, and from the response and the
+//! context when the service responds. They are added to the OpenTelemetry span built for the
+//! tracing span, since tracing spans only have the fields declared when they are created.
+
+use std::collections::HashMap;
+
+use http::header::HeaderName;
+use http::HeaderMap;
+use http::StatusCode;
+use opentelemetry::KeyValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+use tracing::Span;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+use crate::plugin::serde::deserialize_option_header_name;
+use crate::plugins::operation_tags;
+use crate::Context;
+
+/// Attributes added to the spans, from request data
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SpanAttributesConf {
+    /// Attributes of the supergraph spans
+    #[serde(default)]
+    pub(crate) supergraph: Vec<SpanAttribute>,
+    /// Attributes of the subgraph spans
+    #[serde(default)]
+    pub(crate) subgraph: SubgraphSpanAttributes,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubgraphSpanAttributes {
+    /// Attributes of the spans of all subgraphs
+    #[serde(default)]
+    pub(crate) all: Vec<SpanAttribute>,
+    /// Attributes of the spans of specific subgraphs, added to the ones of `all`
+    #[serde(default)]
+    pub(crate) subgraphs: HashMap<String, Vec<SpanAttribute>>,
+}
+
+/// A span attribute, read from a single source
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SpanAttribute {
+    /// Name of the attribute
+    name: String,
+    /// Read the value from this request header
+    #[schemars(with = "Option<String>", default)]
+    #[serde(deserialize_with = "deserialize_option_header_name", default)]
+    request_header: Option<HeaderName>,
+    /// Read the value from this response header
+    #[schemars(with = "Option<String>", default)]
+    #[serde(deserialize_with = "deserialize_option_header_name", default)]
+    response_header: Option<HeaderName>,
+    /// Read the value from this context entry, when the service responds
+    #[serde(default)]
+    context: Option<String>,
+    /// Read the value of this tag, set by the `experimental.operation_tags` plugin
+    #[serde(default)]
+    operation_tag: Option<String>,
+    /// Read a value known by the router
+    #[serde(default)]
+    value: Option<StandardValue>,
+    /// Value to use if the source is missing
+    #[serde(default)]
+    default: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StandardValue {
+    /// Name of the operation sent by the client
+    OperationName,
+    /// Name of the client, from the client name header of the `apollo` configuration
+    ClientName,
+    /// Version of the client, from the client version header of the `apollo` configuration
+    ClientVersion,
+    /// HTTP status of the response
+    ResponseStatus,
+}
+
+/// Request data the attributes are read from when the span is created.
+pub(crate) struct RequestData<'a> {
+    pub(crate) headers: &'a HeaderMap,
+    pub(crate) operation_name: Option<&'a str>,
+    pub(crate) client_name: Option<&'a str>,
+    pub(crate) client_version: Option<&'a str>,
+}
+
+/// Response data the attributes are read from when the service responds.
+pub(crate) struct ResponseData<'a> {
+    pub(crate) headers: &'a HeaderMap,
+    pub(crate) status: StatusCode,
+    pub(crate) context: &'a Context,
+}
+
+impl SpanAttributesConf {
+    pub(crate) fn validate(&self) -> Result<(), BoxError> {
+        self.supergraph
+            .iter()
+            .chain(&self.subgraph.all)
+            .chain(self.subgraph.subgraphs.values().flatten())
+            .try_for_each(SpanAttribute::validate)
+    }
+
+    /// The attributes of the spans of a subgraph.
+    pub(crate) fn subgraph(&self, name: &str) -> Vec<SpanAttribute> {
+        self.subgraph
+            .all
+            .iter()
+            .chain(self.subgraph.subgraphs.get(name).into_iter().flatten())
+            .cloned()
+            .collect()
+    }
+}
+
+impl SpanAttribute {
+    fn validate(&self) -> Result<(), BoxError> {
+        let sources = [
+            self.request_header.is_some(),
+            self.response_header.is_some(),
+            self.context.is_some(),
+            self.operation_tag.is_some(),
+            self.value.is_some(),
+        ];
+        if sources.iter().filter(|source| **source).count() != 1 {
+            return Err(format!(
+                "the span attribute '{}' needs exactly one of request_header, response_header, context, operation_tag or value",
+                self.name
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Whether the value is read when the service responds.
+    fn from_response(&self) -> bool {
+        self.response_header.is_some()
+            || self.context.is_some()
+            || self.operation_tag.is_some()
+            || self.value == Some(StandardValue::ResponseStatus)
+    }
+
+    fn request_value(&self, request: &RequestData) -> Option<String> {
+        if let Some(header) = &self.request_header {
+            return Some(request.headers.get(header)?.to_str().ok()?.to_string());
+        }
+        match self.value? {
+            StandardValue::OperationName => request.operation_name,
+            StandardValue::ClientName => request.client_name,
+            StandardValue::ClientVersion => request.client_version,
+            StandardValue::ResponseStatus => None,
+        }
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+    }
+
+    fn response_value(&self, response: &ResponseData) -> Option<String> {
+        if let Some(header) = &self.response_header {
+            return Some(response.headers.get(header)?.to_str().ok()?.to_string());
+        }
+        if let Some(key) = &self.context {
+            return match response.context.get_json_value(key.as_str())? {
+                serde_json_bytes::Value::String(value) => Some(value.as_str().to_string()),
+                value => serde_json::to_string(&value).ok(),
+            };
+        }
+        if let Some(name) = &self.operation_tag {
+            return operation_tags::tag(response.context, name);
+        }
+        (self.value == Some(StandardValue::ResponseStatus))
+            .then(|| response.status.as_u16().to_string())
+    }
+
+    fn key_value(&self, value: Option<String>) -> Option<KeyValue> {
+        value
+            .or_else(|| self.default.clone())
+            .map(|value| KeyValue::new(self.name.clone(), value))
+    }
+}
+
+pub(crate) fn request_attributes(
+    attributes: &[SpanAttribute],
+    request: &RequestData,
+) -> Vec<KeyValue> {
+    attributes
+        .iter()
+        .filter(|attribute| !attribute.from_response())
+        .filter_map(|attribute| attribute.key_value(attribute.request_value(request)))
+        .collect()
+}
+
+pub(crate) fn response_attributes(
+    attributes: &[SpanAttribute],
+    response: &ResponseData,
+) -> Vec<KeyValue> {
+    attributes
+        .iter()
+        .filter(|attribute| attribute.from_response())
+        .filter_map(|attribute| attribute.key_value(attribute.response_value(response)))
+        .collect()
+}
+
+/// Adds attributes to the OpenTelemetry span that will be exported for a tracing span.
+pub(crate) fn set_attributes(span: &Span, attributes: Vec<KeyValue>) {
+    if attributes.is_empty() {
+        return;
+    }
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(span) = dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+        {
+            if let Some(data) = span.extensions_mut().get_mut::<OtelData>() {
+                data.builder
+                    .attributes
+                    .get_or_insert_with(Vec::new)
+                    .extend(attributes);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use serde_json::json;
+
+    use super::*;
+
+    fn attributes() -> Vec<SpanAttribute> {
+        serde_json::from_value(json!([
+            { "name": "tenant", "request_header": "x-tenant", "default": "unknown" },
+            { "name": "operation", "value": "operation_name" },
+            { "name": "client", "value": "client_name" },
+            { "name": "status", "value": "response_status" },
+            { "name": "cache", "response_header": "x-cache" },
+            { "name": "plan", "context": "plan" },
+            { "name": "priority", "operation_tag": "priority" }
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn attributes_are_read_from_the_request_and_the_response() {
+        let attributes = attributes();
+        for attribute in &attributes {
+            attribute.validate().unwrap();
+        }
+
+        let headers = HeaderMap::new();
+        assert_eq!(
+            request_attributes(
+                &attributes,
+                &RequestData {
+                    headers: &headers,
+                    operation_name: Some("TopProducts"),
+                    client_name: Some(""),
+                    client_version: None,
+                }
+            ),
+            vec![
+                KeyValue::new("tenant", "unknown"),
+                KeyValue::new("operation", "TopProducts"),
+            ]
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-cache", HeaderValue::from_static("hit"));
+        let context = Context::new();
+        context.insert("plan", "gold").unwrap();
+        context
+            .insert(operation_tags::context_key("priority"), "low")
+            .unwrap();
+        assert_eq!(
+            response_attributes(
+                &attributes,
+                &ResponseData {
+                    headers: &headers,
+                    status: StatusCode::OK,
+                    context: &context,
+                }
+            ),
+            vec![
+                KeyValue::new("status", "200"),
+                KeyValue::new("cache", "hit"),
+                KeyValue::new("plan", "gold"),
+                KeyValue::new("priority", "low"),
+            ]
+        );
+    }
+
+    #[test]
+    fn attributes_need_a_single_source() {
+        let attributes: Vec<SpanAttribute> = serde_json::from_value(json!([
+            { "name": "none" },
+            { "name": "both", "request_header": "x-tenant", "context": "tenant" }
+        ]))
+        .unwrap();
+        assert!(attributes
+            .iter()
+            .all(|attribute| attribute.validate().is_err()));
+    }
+}
@@ -7,8 +7,11 @@ use tower::BoxError;
 
 use super::deser_endpoint;
 use super::AgentEndpoint;
+#[cfg(feature = "telemetry_datadog")]
 use crate::plugins::telemetry::config::GenericWith;
 use crate::plugins::telemetry::config::Trace;
+#[cfg(not(feature = "telemetry_datadog"))]
+use crate::plugins::telemetry::not_compiled;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
 
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
@@ -22,6 +25,7 @@ const fn default_agent_endpoint() -> &'static str {
     "default"
 }
 
+#[cfg(feature = "telemetry_datadog")]
 impl TracingConfigurator for Config {
     fn apply(&self, builder: Builder, trace_config: &Trace) -> Result<Builder, BoxError> {
         tracing::debug!("configuring Datadog tracing");
@@ -40,6 +44,13 @@ impl TracingConfigurator for Config {
     }
 }
 
+#[cfg(not(feature = "telemetry_datadog"))]
+impl TracingConfigurator for Config {
+    fn apply(&self, _builder: Builder, _trace_config: &Trace) -> Result<Builder, BoxError> {
+        Err(not_compiled("Datadog exporter", "telemetry_datadog").into())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use reqwest::Url;
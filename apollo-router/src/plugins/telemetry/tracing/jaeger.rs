@@ -1,6 +1,7 @@
 //! Configuration for jaeger tracing.
 use std::time::Duration;
 
+#[cfg(feature = "telemetry_jaeger")]
 use opentelemetry::sdk::trace::BatchSpanProcessor;
 use opentelemetry::sdk::trace::Builder;
 use schemars::gen::SchemaGenerator;
@@ -14,8 +15,11 @@ use url::Url;
 
 use super::deser_endpoint;
 use super::AgentEndpoint;
+#[cfg(feature = "telemetry_jaeger")]
 use crate::plugins::telemetry::config::GenericWith;
 use crate::plugins::telemetry::config::Trace;
+#[cfg(not(feature = "telemetry_jaeger"))]
+use crate::plugins::telemetry::not_compiled;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
 
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
@@ -74,6 +78,7 @@ fn default_agent_endpoint() -> &'static str {
     "default"
 }
 
+#[cfg(feature = "telemetry_jaeger")]
 impl TracingConfigurator for Config {
     fn apply(&self, builder: Builder, trace_config: &Trace) -> Result<Builder, BoxError> {
         tracing::debug!("configuring Jaeger tracing");
@@ -114,3 +119,10 @@ impl TracingConfigurator for Config {
         ))
     }
 }
+
+#[cfg(not(feature = "telemetry_jaeger"))]
+impl TracingConfigurator for Config {
+    fn apply(&self, _builder: Builder, _trace_config: &Trace) -> Result<Builder, BoxError> {
+        Err(not_compiled("Jaeger exporter", "telemetry_jaeger").into())
+    }
+}
@@ -1,20 +1,45 @@
 //! Configuration for Otlp tracing.
 use std::result::Result;
 
+#[cfg(feature = "telemetry_otlp")]
+use opentelemetry::sdk::trace::BatchSpanProcessor;
 use opentelemetry::sdk::trace::Builder;
+#[cfg(feature = "telemetry_otlp")]
 use opentelemetry_otlp::SpanExporterBuilder;
 use tower::BoxError;
 
+#[cfg(feature = "telemetry_otlp")]
+use crate::plugins::telemetry::config::GenericWith;
 use crate::plugins::telemetry::config::Trace;
+#[cfg(not(feature = "telemetry_otlp"))]
+use crate::plugins::telemetry::not_compiled;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
 
+#[cfg(feature = "telemetry_otlp")]
 impl TracingConfigurator for super::super::otlp::Config {
     fn apply(&self, builder: Builder, _trace_config: &Trace) -> Result<Builder, BoxError> {
         tracing::debug!("configuring Otlp tracing");
         let exporter: SpanExporterBuilder = self.exporter()?;
-        Ok(builder.with_batch_exporter(
-            exporter.build_span_exporter()?,
-            opentelemetry::runtime::Tokio,
+        let batch = self.batch_processor.clone().unwrap_or_default();
+        Ok(builder.with_span_processor(
+            BatchSpanProcessor::builder(
+                exporter.build_span_exporter()?,
+                opentelemetry::runtime::Tokio,
+            )
+            .with(&batch.scheduled_delay, |b, d| b.with_scheduled_delay(*d))
+            // The queue size bounds the batch size, so it is set first
+            .with(&batch.max_queue_size, |b, s| b.with_max_queue_size(*s))
+            .with(&batch.max_export_batch_size, |b, s| {
+                b.with_max_export_batch_size(*s)
+            })
+            .build(),
         ))
     }
 }
+
+#[cfg(not(feature = "telemetry_otlp"))]
+impl TracingConfigurator for super::super::otlp::Config {
+    fn apply(&self, _builder: Builder, _trace_config: &Trace) -> Result<Builder, BoxError> {
+        Err(not_compiled("OTLP exporter", "telemetry_otlp").into())
+    }
+}
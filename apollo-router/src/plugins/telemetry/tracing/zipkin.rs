@@ -9,8 +9,11 @@ use url::Url;
 
 use super::AgentDefault;
 use super::AgentEndpoint;
+#[cfg(feature = "telemetry_zipkin")]
 use crate::plugins::telemetry::config::GenericWith;
 use crate::plugins::telemetry::config::Trace;
+#[cfg(not(feature = "telemetry_zipkin"))]
+use crate::plugins::telemetry::not_compiled;
 use crate::plugins::telemetry::tracing::TracingConfigurator;
 
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
@@ -45,6 +48,7 @@ where
     Ok(AgentEndpoint::Url(url))
 }
 
+#[cfg(feature = "telemetry_zipkin")]
 impl TracingConfigurator for Config {
     fn apply(&self, builder: Builder, trace_config: &Trace) -> Result<Builder, BoxError> {
         tracing::debug!("configuring Zipkin tracing");
@@ -64,3 +68,10 @@ impl TracingConfigurator for Config {
         Ok(builder.with_batch_exporter(exporter, opentelemetry::runtime::Tokio))
     }
 }
+
+#[cfg(not(feature = "telemetry_zipkin"))]
+impl TracingConfigurator for Config {
+    fn apply(&self, _builder: Builder, _trace_config: &Trace) -> Result<Builder, BoxError> {
+        Err(not_compiled("Zipkin exporter", "telemetry_zipkin").into())
+    }
+}
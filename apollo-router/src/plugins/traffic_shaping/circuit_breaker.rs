@@ -0,0 +1,303 @@
+//! Circuit breaker of the requests to a subgraph. Implemented as a tower Layer.
+//!
+//! The circuit opens after consecutive failures, or right away when the subgraph answers with a
+//! `Retry-After` delay, and stays open for the longest of its open duration and that delay.
+//! Requests fail right away while it is open. When it elapses, the circuit is half open: a single
+//! request probes the subgraph, and closes the circuit if it succeeds or opens it again if it
+//! fails.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tower::BoxError;
+use tower::Layer;
+
+use super::classification::Classifier;
+use super::RateLimited;
+use crate::error::FetchError;
+use crate::services::subgraph::ErrorClass;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe request was sent at this instant.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// State of a circuit, as served by the subgraph statistics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CircuitState {
+    Closed,
+    Open { remaining: Duration },
+    HalfOpen,
+}
+
+/// Shared by all the services of a subgraph.
+#[derive(Clone)]
+pub(crate) struct CircuitBreakerLayer {
+    service_name: String,
+    classifier: Classifier,
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl CircuitBreakerLayer {
+    pub(crate) fn new(
+        service_name: &str,
+        classifier: Classifier,
+        failure_threshold: u32,
+        open_duration: Duration,
+    ) -> Self {
+        Self {
+            service_name: service_name.to_string(),
+            classifier,
+            failure_threshold,
+            open_duration,
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+        }
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        match *self.state.lock().unwrap() {
+            State::Open { until } => match until.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => CircuitState::Open { remaining },
+                // The next request probes the subgraph
+                _ => CircuitState::HalfOpen,
+            },
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+            State::Closed { .. } => CircuitState::Closed,
+        }
+    }
+
+    /// Lets the request through, or returns the number of seconds to wait for.
+    fn acquire(&self) -> Result<(), u64> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } => match until.checked_duration_since(now) {
+                Some(remaining) if !remaining.is_zero() => Err(ceil_secs(remaining)),
+                _ => {
+                    *state = State::HalfOpen { since: now };
+                    Ok(())
+                }
+            },
+            // A probe that was dropped without an answer does not keep the circuit half open
+            State::HalfOpen { since } if now.duration_since(since) >= self.open_duration => {
+                *state = State::HalfOpen { since: now };
+                Ok(())
+            }
+            State::HalfOpen { .. } => Err(1),
+        }
+    }
+
+    fn record(&self, result: Result<&SubgraphResponse, &BoxError>) {
+        let mut state = self.state.lock().unwrap();
+        let retry_after = match result {
+            Err(error) => FetchError::find_retry_after(error).and_then(FetchError::retry_after),
+            Ok(_) => None,
+        };
+        if let Some(retry_after) = retry_after {
+            let delay = self.open_duration.max(Duration::from_secs(retry_after));
+            tracing::debug!(
+                "opening the circuit of subgraph {} for {:?} after a Retry-After delay",
+                self.service_name,
+                delay
+            );
+            *state = State::Open {
+                until: Instant::now() + delay,
+            };
+            return;
+        }
+
+        // Failures classified as `non_retryable` are the fault of the request, not the subgraph
+        let failed = match self.classifier.classify(result) {
+            Some(class) => class != ErrorClass::NonRetryable,
+            None => match result {
+                Ok(response) => response.response.status().is_server_error(),
+                Err(_) => true,
+            },
+        };
+        *state = match (*state, failed) {
+            (_, false) => State::Closed { failures: 0 },
+            (State::Closed { failures }, true) if failures + 1 < self.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (_, true) => {
+                tracing::debug!(
+                    "opening the circuit of subgraph {} for {:?} after consecutive failures",
+                    self.service_name,
+                    self.open_duration
+                );
+                State::Open {
+                    until: Instant::now() + self.open_duration,
+                }
+            }
+        };
+    }
+}
+
+/// Whole seconds, rounded up.
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+impl<S> Layer<S> for CircuitBreakerLayer {
+    type Service = CircuitBreakerService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CircuitBreakerService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct CircuitBreakerService<S> {
+    service: S,
+    layer: CircuitBreakerLayer,
+}
+
+impl<S> tower::Service<SubgraphRequest> for CircuitBreakerService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        if let Err(retry_after) = self.layer.acquire() {
+            let error = FetchError::SubrequestUnavailable {
+                service: self.layer.service_name.clone(),
+                retry_after,
+            };
+            tracing::debug!("not calling the subgraph, its circuit is open: {}", error);
+            return future::ready(Err(error.into())).boxed();
+        }
+
+        let layer = self.layer.clone();
+        self.service
+            .call(request)
+            .map(move |result| {
+                // Rejections of the router's own limits say nothing about the subgraph
+                let rate_limited = result.as_ref().err().map_or(false, |e| {
+                    e.is::<RateLimited>()
+                        || e.source()
+                            .map_or(false, |source| source.is::<RateLimited>())
+                });
+                if !rate_limited {
+                    layer.record(result.as_ref());
+                }
+                result
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn service(
+        layer: &CircuitBreakerLayer,
+        status: u16,
+    ) -> impl tower::Service<
+        SubgraphRequest,
+        Response = SubgraphResponse,
+        Error = BoxError,
+        Future = BoxFuture<'static, Result<SubgraphResponse, BoxError>>,
+    > {
+        layer.layer(tower::service_fn(move |_req: SubgraphRequest| async move {
+            match status {
+                429 => Err(FetchError::SubrequestRateLimited {
+                    service: "test".to_string(),
+                    retry_after: 30,
+                }
+                .into()),
+                status => Ok(SubgraphResponse::fake_builder()
+                    .status_code(http::StatusCode::from_u16(status).unwrap())
+                    .build()),
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() {
+        let layer =
+            CircuitBreakerLayer::new("test", Classifier::default(), 2, Duration::from_millis(50));
+        for status in [500, 200, 500] {
+            let _ = service(&layer, status)
+                .oneshot(SubgraphRequest::fake_builder().build())
+                .await;
+        }
+        assert_eq!(layer.state(), CircuitState::Closed);
+        let _ = service(&layer, 500)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await;
+        assert!(matches!(layer.state(), CircuitState::Open { .. }));
+
+        let error = service(&layer, 200)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FetchError>(),
+            Some(FetchError::SubrequestUnavailable { retry_after: 1, .. })
+        ));
+
+        // the probe closes the circuit
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(layer.state(), CircuitState::HalfOpen);
+        service(&layer, 200)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+        assert_eq!(layer.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn opens_for_the_retry_after_delay() {
+        let layer =
+            CircuitBreakerLayer::new("test", Classifier::default(), 5, Duration::from_secs(10));
+        let _ = service(&layer, 429)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await;
+
+        let error = service(&layer, 200)
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap_err();
+        match error.downcast_ref::<FetchError>() {
+            Some(FetchError::SubrequestUnavailable { retry_after, .. }) => {
+                assert!(*retry_after > 10 && *retry_after <= 30)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
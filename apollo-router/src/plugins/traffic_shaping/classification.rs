@@ -0,0 +1,253 @@
+//! Classification of the failed requests to subgraphs, for retries and holding off subgraphs.
+//!
+//! A failure is classified by the plugin that produced it if any, then by the first matching
+//! rule of the configuration. Errors that are not classified are retried unless the subgraph
+//! asked to be called later, and responses that are not classified are not retried.
+
+use std::error::Error as _;
+use std::sync::Arc;
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tower::BoxError;
+
+use crate::error::ConfigurationError;
+use crate::error::FetchError;
+use crate::services::subgraph::ClassifiedError;
+use crate::services::subgraph::ErrorClass;
+use crate::SubgraphResponse;
+
+/// Rule classifying failed requests. A failure matches the rule if it matches all the criteria
+/// set in the rule
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ClassificationRule {
+    /// HTTP status codes of the subgraph response
+    #[serde(default)]
+    status: Vec<u16>,
+    /// Values of `extensions.code` in the GraphQL errors of the subgraph response
+    #[serde(default)]
+    error_codes: Vec<String>,
+    /// Regular expression matched against the error, or the messages of the GraphQL errors of
+    /// the subgraph response
+    message: Option<String>,
+    /// Class of the matching failures
+    class: ErrorClass,
+}
+
+/// What a failure is matched on.
+struct Failure<'a> {
+    status: Option<u16>,
+    error_codes: Vec<&'a str>,
+    messages: Vec<String>,
+}
+
+/// The classification rules of a subgraph, with their regular expressions compiled.
+#[derive(Clone, Default)]
+pub(crate) struct Classifier {
+    rules: Arc<Vec<(ClassificationRule, Option<Regex>)>>,
+}
+
+impl Classifier {
+    pub(crate) fn new(rules: &[ClassificationRule]) -> Result<Self, ConfigurationError> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                if rule.status.is_empty() && rule.error_codes.is_empty() && rule.message.is_none() {
+                    return Err(invalid(
+                        "a classification rule needs a status, an error code or a message"
+                            .to_string(),
+                    ));
+                }
+                let regex = rule
+                    .message
+                    .as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .map_err(|e| invalid(format!("invalid classification message: {}", e)))?;
+                Ok((rule.clone(), regex))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            rules: Arc::new(rules),
+        })
+    }
+
+    /// The class of a failed request, or `None` for a response without errors or a failure
+    /// that is not classified.
+    pub(crate) fn classify(
+        &self,
+        result: Result<&SubgraphResponse, &BoxError>,
+    ) -> Option<ErrorClass> {
+        let failure = match result {
+            Ok(response) => {
+                if let Some(class) = response.response.extensions().get::<ErrorClass>() {
+                    return Some(*class);
+                }
+                let errors = &response.response.body().errors;
+                let status = response.response.status();
+                if errors.is_empty() && status.is_success() {
+                    return None;
+                }
+                Failure {
+                    status: Some(status.as_u16()),
+                    error_codes: errors
+                        .iter()
+                        .filter_map(|error| error.extensions.get("code")?.as_str())
+                        .collect(),
+                    messages: errors.iter().map(|error| error.message.clone()).collect(),
+                }
+            }
+            Err(error) => {
+                if let Some(classified) = find::<ClassifiedError>(error) {
+                    return Some(classified.class());
+                }
+                Failure {
+                    status: find::<FetchError>(error).and_then(|error| match error {
+                        FetchError::SubrequestHttpError { status, .. } => *status,
+                        _ => None,
+                    }),
+                    error_codes: Vec::new(),
+                    messages: vec![error.to_string()],
+                }
+            }
+        };
+        self.rules
+            .iter()
+            .find(|(rule, regex)| matches(rule, regex.as_ref(), &failure))
+            .map(|(rule, _)| rule.class)
+    }
+}
+
+fn matches(rule: &ClassificationRule, regex: Option<&Regex>, failure: &Failure) -> bool {
+    (rule.status.is_empty()
+        || failure
+            .status
+            .map(|status| rule.status.contains(&status))
+            .unwrap_or_default())
+        && (rule.error_codes.is_empty()
+            || failure
+                .error_codes
+                .iter()
+                .any(|code| rule.error_codes.iter().any(|expected| expected == code)))
+        && regex
+            .map(|regex| {
+                failure
+                    .messages
+                    .iter()
+                    .any(|message| regex.is_match(message))
+            })
+            .unwrap_or(true)
+}
+
+/// Finds an error, which may be wrapped by a buffer layer.
+fn find<T: std::error::Error + 'static>(error: &BoxError) -> Option<&T> {
+    error
+        .downcast_ref::<T>()
+        .or_else(|| error.source().and_then(|source| source.downcast_ref()))
+}
+
+fn invalid(error: String) -> ConfigurationError {
+    ConfigurationError::InvalidConfiguration {
+        message: "bad configuration for traffic_shaping plugin",
+        error,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::graphql;
+
+    fn classifier(rules: serde_json::Value) -> Classifier {
+        Classifier::new(&serde_json::from_value::<Vec<ClassificationRule>>(rules).unwrap()).unwrap()
+    }
+
+    fn http_error(status: u16) -> BoxError {
+        FetchError::SubrequestHttpError {
+            service: "test".to_string(),
+            reason: format!("subgraph HTTP status error '{}'", status),
+            status: Some(status),
+        }
+        .into()
+    }
+
+    #[test]
+    fn failures_are_classified_by_the_first_matching_rule() {
+        let classifier = classifier(json!([
+            { "status": [502, 503], "class": "retryable" },
+            { "status": [500], "message": "overloaded", "class": "failover" },
+            { "error_codes": ["UNAVAILABLE"], "class": "retryable" },
+            { "message": "connection", "class": "non_retryable" }
+        ]));
+
+        assert_eq!(
+            classifier.classify(Err(&http_error(502))),
+            Some(ErrorClass::Retryable)
+        );
+        assert_eq!(classifier.classify(Err(&http_error(500))), None);
+        let overloaded: BoxError = FetchError::SubrequestHttpError {
+            service: "test".to_string(),
+            reason: "subgraph HTTP status error '500': overloaded".to_string(),
+            status: Some(500),
+        }
+        .into();
+        assert_eq!(
+            classifier.classify(Err(&overloaded)),
+            Some(ErrorClass::Failover)
+        );
+        let reset: BoxError = "connection reset".into();
+        assert_eq!(
+            classifier.classify(Err(&reset)),
+            Some(ErrorClass::NonRetryable)
+        );
+
+        let response = SubgraphResponse::fake_builder()
+            .error(
+                graphql::Error::builder()
+                    .message("products are unavailable")
+                    .extension("code", "UNAVAILABLE")
+                    .build(),
+            )
+            .build();
+        assert_eq!(
+            classifier.classify(Ok(&response)),
+            Some(ErrorClass::Retryable)
+        );
+        assert_eq!(
+            classifier.classify(Ok(&SubgraphResponse::fake_builder().build())),
+            None
+        );
+    }
+
+    #[test]
+    fn plugins_classify_failures() {
+        let classifier = classifier(json!([{ "status": [502], "class": "retryable" }]));
+
+        let error: BoxError = ClassifiedError::new(ErrorClass::Failover, http_error(502)).into();
+        assert_eq!(classifier.classify(Err(&error)), Some(ErrorClass::Failover));
+
+        let mut response = SubgraphResponse::fake_builder().build();
+        response
+            .response
+            .extensions_mut()
+            .insert(ErrorClass::NonRetryable);
+        assert_eq!(
+            classifier.classify(Ok(&response)),
+            Some(ErrorClass::NonRetryable)
+        );
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let rules: Vec<ClassificationRule> =
+            serde_json::from_value(json!([{ "class": "retryable" }])).unwrap();
+        assert!(Classifier::new(&rules).is_err());
+        let rules: Vec<ClassificationRule> =
+            serde_json::from_value(json!([{ "message": "(", "class": "retryable" }])).unwrap();
+        assert!(Classifier::new(&rules).is_err());
+    }
+}
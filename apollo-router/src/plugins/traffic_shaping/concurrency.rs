@@ -0,0 +1,232 @@
+//! Adaptive limit of the requests in flight to a subgraph. Implemented as a tower Layer.
+//!
+//! The limit is adjusted with additive increase, multiplicative decrease: it grows by one request
+//! each time a full limit of requests succeeded, and is halved when the subgraph answers with a
+//! `Retry-After` delay or a failure classified as `failover`. Requests over the limit are not
+//! sent, they are rejected like the requests over a rate limit.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tower::BoxError;
+use tower::Layer;
+
+use super::classification::Classifier;
+use super::RateLimited;
+use crate::error::FetchError;
+use crate::services::subgraph::ErrorClass;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+/// How long a rejected request is asked to wait for.
+const REJECTION_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Limit {
+    limit: f64,
+    in_flight: u64,
+}
+
+/// Shared by all the services of a subgraph.
+#[derive(Clone)]
+pub(crate) struct AdaptiveConcurrencyLayer {
+    service_name: String,
+    classifier: Classifier,
+    min_limit: u64,
+    max_limit: u64,
+    limit: Arc<Mutex<Limit>>,
+}
+
+impl AdaptiveConcurrencyLayer {
+    pub(crate) fn new(
+        service_name: &str,
+        classifier: Classifier,
+        initial_limit: u64,
+        min_limit: u64,
+        max_limit: u64,
+    ) -> Self {
+        Self {
+            service_name: service_name.to_string(),
+            classifier,
+            min_limit,
+            max_limit,
+            limit: Arc::new(Mutex::new(Limit {
+                limit: initial_limit.clamp(min_limit, max_limit) as f64,
+                in_flight: 0,
+            })),
+        }
+    }
+
+    /// The current limit of requests in flight.
+    pub(crate) fn limit(&self) -> u64 {
+        self.limit.lock().unwrap().limit as u64
+    }
+
+    fn acquire(&self) -> Option<InFlight> {
+        let mut limit = self.limit.lock().unwrap();
+        if limit.in_flight >= limit.limit as u64 {
+            return None;
+        }
+        limit.in_flight += 1;
+        Some(InFlight(self.limit.clone()))
+    }
+
+    fn record(&self, result: Result<&SubgraphResponse, &BoxError>) {
+        let overloaded = match result {
+            Err(error) if FetchError::find_retry_after(error).is_some() => true,
+            result => self.classifier.classify(result) == Some(ErrorClass::Failover),
+        };
+        let mut limit = self.limit.lock().unwrap();
+        if overloaded {
+            limit.limit = (limit.limit / 2.0).max(self.min_limit as f64);
+            tracing::debug!(
+                "concurrency limit of subgraph {} decreased to {}",
+                self.service_name,
+                limit.limit as u64
+            );
+        } else if result.is_ok() {
+            limit.limit = (limit.limit + 1.0 / limit.limit).min(self.max_limit as f64);
+        }
+    }
+}
+
+/// Releases the slot of a request when it completes, or is dropped.
+struct InFlight(Arc<Mutex<Limit>>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().in_flight -= 1;
+    }
+}
+
+impl<S> Layer<S> for AdaptiveConcurrencyLayer {
+    type Service = AdaptiveConcurrencyService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AdaptiveConcurrencyService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct AdaptiveConcurrencyService<S> {
+    service: S,
+    layer: AdaptiveConcurrencyLayer,
+}
+
+impl<S> tower::Service<SubgraphRequest> for AdaptiveConcurrencyService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        let in_flight = match self.layer.acquire() {
+            Some(in_flight) => in_flight,
+            None => {
+                tracing::debug!(
+                    "not calling subgraph {}, its concurrency limit is reached",
+                    self.layer.service_name
+                );
+                let error = RateLimited::new(REJECTION_RETRY_AFTER);
+                return future::ready(Err(error.into())).boxed();
+            }
+        };
+
+        let layer = self.layer.clone();
+        self.service
+            .call(request)
+            .map(move |result| {
+                drop(in_flight);
+                layer.record(result.as_ref());
+                result
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn limit_adapts_to_retry_after() {
+        let layer = AdaptiveConcurrencyLayer::new("test", Classifier::default(), 8, 2, 10);
+        let service = |retry_after: Option<u64>| {
+            layer.layer(tower::service_fn(move |_req: SubgraphRequest| async move {
+                match retry_after {
+                    Some(retry_after) => Err(BoxError::from(FetchError::SubrequestUnavailable {
+                        service: "test".to_string(),
+                        retry_after,
+                    })),
+                    None => Ok(SubgraphResponse::fake_builder().build()),
+                }
+            }))
+        };
+
+        for _ in 0..2 {
+            let _ = service(Some(5))
+                .oneshot(SubgraphRequest::fake_builder().build())
+                .await;
+        }
+        assert_eq!(layer.limit(), 2);
+        let _ = service(Some(5))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await;
+        assert_eq!(layer.limit(), 2, "the limit does not go under the minimum");
+
+        // a full limit of successes adds one request
+        for _ in 0..3 {
+            service(None)
+                .oneshot(SubgraphRequest::fake_builder().build())
+                .await
+                .unwrap();
+        }
+        assert_eq!(layer.limit(), 3);
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_limit_are_rejected() {
+        let layer = AdaptiveConcurrencyLayer::new("test", Classifier::default(), 1, 1, 10);
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        let receiver = Arc::new(tokio::sync::Mutex::new(Some(receiver)));
+        let slow = layer.layer(tower::service_fn(move |_req: SubgraphRequest| {
+            let receiver = receiver.clone();
+            async move {
+                if let Some(receiver) = receiver.lock().await.take() {
+                    let _ = receiver.await;
+                }
+                Ok::<_, BoxError>(SubgraphResponse::fake_builder().build())
+            }
+        }));
+        let first = tokio::spawn(slow.oneshot(SubgraphRequest::fake_builder().build()));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let error = layer
+            .layer(tower::service_fn(|_req: SubgraphRequest| async {
+                Ok::<_, BoxError>(SubgraphResponse::fake_builder().build())
+            }))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap_err();
+        assert!(error.is::<RateLimited>());
+
+        sender.send(()).unwrap();
+        first.await.unwrap().unwrap();
+    }
+}
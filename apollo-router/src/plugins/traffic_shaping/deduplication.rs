@@ -170,3 +170,96 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use serde_json_bytes::json;
+    use tower::Service;
+
+    use super::*;
+
+    /// A subgraph that takes some time to answer, and counts its requests.
+    fn slow_subgraph(
+        calls: Arc<AtomicUsize>,
+    ) -> impl tower::Service<
+        SubgraphRequest,
+        Response = SubgraphResponse,
+        Error = BoxError,
+        Future = BoxFuture<'static, Result<SubgraphResponse, BoxError>>,
+    > + Clone {
+        tower::service_fn(move |request: SubgraphRequest| {
+            let calls = calls.clone();
+            Box::pin(async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(SubgraphResponse::fake_builder()
+                    .data(json!({ "me": { "id": "1" } }))
+                    .context(request.context)
+                    .build())
+            }) as BoxFuture<'static, Result<SubgraphResponse, BoxError>>
+        })
+    }
+
+    fn request(operation_kind: OperationKind, authorization: &str) -> SubgraphRequest {
+        SubgraphRequest::fake_builder()
+            .subgraph_request(
+                http::Request::builder()
+                    .uri("http://accounts/graphql")
+                    .header("authorization", authorization)
+                    .body(Request::builder().query("{ me { id } }").build())
+                    .unwrap(),
+            )
+            .operation_kind(operation_kind)
+            .build()
+    }
+
+    async fn call_concurrently(requests: Vec<SubgraphRequest>) -> usize {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut service = QueryDeduplicationLayer::default().layer(slow_subgraph(calls.clone()));
+        let responses =
+            futures::future::join_all(requests.into_iter().map(|request| service.call(request)))
+                .await;
+        for response in responses {
+            assert_eq!(
+                response.unwrap().response.body().data,
+                Some(json!({ "me": { "id": "1" } }))
+            );
+        }
+        calls.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn identical_queries_are_deduplicated() {
+        let calls = call_concurrently(vec![
+            request(OperationKind::Query, "a"),
+            request(OperationKind::Query, "a"),
+            request(OperationKind::Query, "a"),
+        ])
+        .await;
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn queries_with_different_headers_are_not_deduplicated() {
+        let calls = call_concurrently(vec![
+            request(OperationKind::Query, "a"),
+            request(OperationKind::Query, "b"),
+        ])
+        .await;
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    async fn mutations_are_not_deduplicated() {
+        let calls = call_concurrently(vec![
+            request(OperationKind::Mutation, "a"),
+            request(OperationKind::Mutation, "a"),
+        ])
+        .await;
+        assert_eq!(calls, 2);
+    }
+}
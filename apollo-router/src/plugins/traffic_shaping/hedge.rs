@@ -0,0 +1,301 @@
+//! Hedge slow subgraph queries. Implemented as a tower Layer.
+//!
+//! When a query to a subgraph is not answered after a delay, a second identical request is sent,
+//! and the first successful response is used while the other request is cancelled. This cuts the
+//! tail latency caused by an occasionally slow replica of the subgraph. Hedge requests are capped
+//! by a budget, see [`tower::retry::budget::Budget`], and by a number of hedge requests in flight.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::future::Either;
+use futures::FutureExt;
+use tower::retry::budget::Budget;
+use tower::BoxError;
+use tower::Layer;
+use tower::ServiceExt;
+
+use crate::http_ext;
+use crate::query_planner::fetch::OperationKind;
+use crate::Context;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+/// Number of hedged requests of each subgraph, by outcome: `won` when the hedge request answered
+/// first, `lost` when the original request did, `capped` when the caps prevented the hedge request.
+pub(crate) const HEDGING_CONTEXT_KEY: &str = "apollo_traffic_shaping::hedging";
+
+/// How long a request is counted in the budget.
+const BUDGET_TTL: Duration = Duration::from_secs(10);
+
+/// Shared by all the services of a subgraph.
+#[derive(Clone)]
+pub(crate) struct HedgeLayer {
+    service_name: String,
+    delay: Duration,
+    operations: Option<Arc<HashSet<String>>>,
+    max_in_flight: u64,
+    in_flight: Arc<AtomicU64>,
+    budget: Arc<Budget>,
+}
+
+impl HedgeLayer {
+    pub(crate) fn new(
+        service_name: &str,
+        delay: Duration,
+        operations: Option<&[String]>,
+        max_in_flight: u64,
+        hedge_percent: f32,
+    ) -> Self {
+        Self {
+            service_name: service_name.to_string(),
+            delay,
+            operations: operations.map(|operations| Arc::new(operations.iter().cloned().collect())),
+            max_in_flight,
+            in_flight: Default::default(),
+            budget: Arc::new(Budget::new(BUDGET_TTL, 0, hedge_percent)),
+        }
+    }
+
+    /// Mutations are never hedged, and queries only for the configured operations.
+    fn hedges(&self, request: &SubgraphRequest) -> bool {
+        if request.operation_kind != OperationKind::Query {
+            return false;
+        }
+        match &self.operations {
+            None => true,
+            Some(operations) => request
+                .originating_request
+                .body()
+                .operation_name
+                .as_ref()
+                .map_or(false, |name| operations.contains(name)),
+        }
+    }
+
+    /// Takes a place among the hedge requests in flight, if the caps allow it.
+    fn acquire(&self) -> Option<InFlight> {
+        let acquired = self
+            .in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_flight| {
+                (in_flight < self.max_in_flight).then(|| in_flight + 1)
+            })
+            .is_ok();
+        if !acquired {
+            return None;
+        }
+        let in_flight = InFlight(self.in_flight.clone());
+        self.budget.withdraw().ok().map(|()| in_flight)
+    }
+}
+
+/// Frees the place of a hedge request when it completes, or is cancelled.
+struct InFlight(Arc<AtomicU64>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S> Layer<S> for HedgeLayer {
+    type Service = HedgeService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        HedgeService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct HedgeService<S> {
+    service: S,
+    layer: HedgeLayer,
+}
+
+fn record(context: &Context, service_name: &str, outcome: &str) {
+    let _ = context.upsert(
+        HEDGING_CONTEXT_KEY,
+        |mut counts: HashMap<String, HashMap<String, u64>>| {
+            *counts
+                .entry(service_name.to_string())
+                .or_default()
+                .entry(outcome.to_string())
+                .or_default() += 1;
+            counts
+        },
+    );
+}
+
+impl<S> tower::Service<SubgraphRequest> for HedgeService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        if !self.layer.hedges(&request) {
+            return self.service.call(request).boxed();
+        }
+
+        let hedge_request = SubgraphRequest {
+            originating_request: request.originating_request.clone(),
+            subgraph_request: http_ext::clone_http_request(&request.subgraph_request),
+            operation_kind: request.operation_kind,
+            context: request.context.clone(),
+        };
+        let layer = self.layer.clone();
+        layer.budget.deposit();
+        // The service is ready for the original request, the clone is for the hedge request
+        let service = self.service.clone();
+        let original = self.service.call(request);
+
+        async move {
+            tokio::pin!(original);
+            let delay = tokio::time::sleep(layer.delay);
+            tokio::pin!(delay);
+            tokio::select! {
+                result = &mut original => return result,
+                _ = &mut delay => {}
+            }
+
+            let in_flight = match layer.acquire() {
+                Some(in_flight) => in_flight,
+                None => {
+                    tracing::debug!("hedging caps reached, not hedging subgraph request");
+                    record(&hedge_request.context, &layer.service_name, "capped");
+                    return original.await;
+                }
+            };
+            tracing::debug!(
+                "subgraph request not answered after {:?}, hedging it",
+                layer.delay
+            );
+            let context = hedge_request.context.clone();
+            let hedge = service.oneshot(hedge_request).map(move |result| {
+                drop(in_flight);
+                result
+            });
+            tokio::pin!(hedge);
+
+            // The first successful response is used, the other request is cancelled when dropped
+            let (result, hedge_won, other) = match futures::future::select(original, hedge).await {
+                Either::Left((result, hedge)) => (result, false, Either::Left(hedge)),
+                Either::Right((result, original)) => (result, true, Either::Right(original)),
+            };
+            let (result, hedge_won) = match result {
+                Ok(response) => (Ok(response), hedge_won),
+                Err(_) => (other.await, !hedge_won),
+            };
+            let outcome = if hedge_won { "won" } else { "lost" };
+            record(&context, &layer.service_name, outcome);
+            result
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    fn service(
+        calls: Arc<AtomicUsize>,
+        latencies: &'static [u64],
+    ) -> impl tower::Service<
+        SubgraphRequest,
+        Response = SubgraphResponse,
+        Error = BoxError,
+        Future = BoxFuture<'static, Result<SubgraphResponse, BoxError>>,
+    > + Clone {
+        tower::service_fn(move |_req: SubgraphRequest| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_millis(latencies[call])).await;
+                Ok(SubgraphResponse::fake_builder().build())
+            }
+            .boxed()
+        })
+    }
+
+    fn outcomes(context: &Context) -> HashMap<String, u64> {
+        context
+            .get::<_, HashMap<String, HashMap<String, u64>>>(HEDGING_CONTEXT_KEY)
+            .unwrap()
+            .unwrap_or_default()
+            .remove("products")
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn slow_requests_are_hedged() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let layer = HedgeLayer::new("products", Duration::from_millis(20), None, 10, 1.0);
+        let request = SubgraphRequest::fake_builder().build();
+        let context = request.context.clone();
+        layer
+            .layer(service(calls.clone(), &[2000, 1]))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(outcomes(&context)["won"], 1);
+        assert_eq!(layer.in_flight.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn hedge_requests_are_capped() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let layer = HedgeLayer::new("products", Duration::from_millis(20), None, 10, 0.0);
+        let request = SubgraphRequest::fake_builder().build();
+        let context = request.context.clone();
+        layer
+            .layer(service(calls.clone(), &[100]))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(outcomes(&context)["capped"], 1);
+    }
+
+    #[tokio::test]
+    async fn only_configured_operations_are_hedged() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let operations = vec!["TopProducts".to_string()];
+        let layer = HedgeLayer::new(
+            "products",
+            Duration::from_millis(1),
+            Some(&operations),
+            10,
+            1.0,
+        );
+        layer
+            .layer(service(calls.clone(), &[50]))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,168 @@
+//! Stop calling a subgraph that asked to be called later. Implemented as a tower Layer.
+//!
+//! When a subgraph rejects a request with a `Retry-After` delay, the following requests fail
+//! right away with the remaining delay, instead of adding to the load of the subgraph. Failures
+//! classified as `failover` hold off the subgraph for a fixed delay.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tower::BoxError;
+use tower::Layer;
+
+use super::classification::Classifier;
+use crate::error::FetchError;
+use crate::services::subgraph::ErrorClass;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+/// How long a subgraph is held off after a failure classified as `failover`.
+const FAILOVER_DELAY: Duration = Duration::from_secs(5);
+
+/// Deadline before which the subgraph must not be called, and the error it was rejected with.
+type HeldOff = Arc<Mutex<Option<(Instant, FetchError)>>>;
+
+/// Shared by all the services of a subgraph.
+#[derive(Clone)]
+pub(crate) struct HoldOffLayer {
+    service_name: String,
+    classifier: Classifier,
+    held_off: HeldOff,
+}
+
+impl HoldOffLayer {
+    pub(crate) fn new(service_name: &str, classifier: Classifier) -> Self {
+        Self {
+            service_name: service_name.to_string(),
+            classifier,
+            held_off: Default::default(),
+        }
+    }
+
+    /// How long the subgraph is still held off for, if it is.
+    pub(crate) fn remaining(&self) -> Option<Duration> {
+        self.held_off
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|(until, _)| until.checked_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+impl<S> Layer<S> for HoldOffLayer {
+    type Service = HoldOffService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        HoldOffService {
+            service,
+            service_name: self.service_name.clone(),
+            classifier: self.classifier.clone(),
+            held_off: self.held_off.clone(),
+        }
+    }
+}
+
+pub(crate) struct HoldOffService<S> {
+    service: S,
+    service_name: String,
+    classifier: Classifier,
+    held_off: HeldOff,
+}
+
+impl<S> HoldOffService<S> {
+    /// The error to return while the subgraph is held off, with the remaining delay.
+    fn rejection(&self) -> Option<FetchError> {
+        let mut held_off = self.held_off.lock().unwrap();
+        let remaining = match held_off.as_ref() {
+            Some((until, _)) => until.checked_duration_since(Instant::now()),
+            None => return None,
+        };
+        match remaining {
+            Some(remaining) if !remaining.is_zero() => {
+                let retry_after = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                held_off
+                    .as_ref()
+                    .map(|(_, error)| with_retry_after(error, retry_after))
+            }
+            _ => {
+                *held_off = None;
+                None
+            }
+        }
+    }
+}
+
+fn with_retry_after(error: &FetchError, retry_after: u64) -> FetchError {
+    match error {
+        FetchError::SubrequestRateLimited { service, .. } => FetchError::SubrequestRateLimited {
+            service: service.clone(),
+            retry_after,
+        },
+        FetchError::SubrequestUnavailable { service, .. } => FetchError::SubrequestUnavailable {
+            service: service.clone(),
+            retry_after,
+        },
+        error => error.clone(),
+    }
+}
+
+impl<S> tower::Service<SubgraphRequest> for HoldOffService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        if let Some(error) = self.rejection() {
+            tracing::debug!("not calling the subgraph: {}", error);
+            return future::ready(Err(error.into())).boxed();
+        }
+
+        let held_off = self.held_off.clone();
+        let service_name = self.service_name.clone();
+        let classifier = self.classifier.clone();
+        self.service
+            .call(request)
+            .map(move |result| {
+                let delay = match &result {
+                    Err(error) => FetchError::find_retry_after(error).map(|error| {
+                        let retry_after = error.retry_after().unwrap_or_default();
+                        (Duration::from_secs(retry_after), error.clone())
+                    }),
+                    Ok(_) => None,
+                };
+                let delay = delay.or_else(|| {
+                    (classifier.classify(result.as_ref()) == Some(ErrorClass::Failover)).then(
+                        || {
+                            (
+                                FAILOVER_DELAY,
+                                FetchError::SubrequestUnavailable {
+                                    service: service_name,
+                                    retry_after: FAILOVER_DELAY.as_secs(),
+                                },
+                            )
+                        },
+                    )
+                });
+                if let Some((delay, error)) = delay {
+                    *held_off.lock().unwrap() = Some((Instant::now() + delay, error));
+                }
+                result
+            })
+            .boxed()
+    }
+}
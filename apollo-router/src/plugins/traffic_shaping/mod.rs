@@ -2,33 +2,67 @@
 //!
 //! Currently includes:
 //! * Query deduplication
+//! * Timeouts
+//! * Rate limiting of client requests and subgraph requests
+//! * Rate limiting of the subgraph requests of tagged operations
+//! * Retries of subgraph queries
+//! * Holding off subgraphs that answered with a `Retry-After` delay
+//! * Circuit breaking and adaptive concurrency limits of subgraphs
+//! * Classification of subgraph failures for retries and holding off
+//! * Live statistics of the requests to subgraphs
+//! * Hedging of slow subgraph queries
 //!
 //! Future functionality:
 //! * APQ (already written, but config needs to be moved here)
 //! * Caching
-//! * Rate limiting
 //!
 
+mod circuit_breaker;
+mod classification;
+mod concurrency;
 mod deduplication;
+mod hedge;
+mod hold_off;
 mod rate;
+mod retry;
+mod stats;
+mod tag_rate;
 mod timeout;
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::num::NonZeroU64;
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 
 use http::header::ACCEPT_ENCODING;
 use http::header::CONTENT_ENCODING;
 use http::HeaderValue;
+use http::StatusCode;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
+use self::circuit_breaker::CircuitBreakerLayer;
+use self::circuit_breaker::CircuitState;
+use self::classification::ClassificationRule;
+use self::classification::Classifier;
+use self::concurrency::AdaptiveConcurrencyLayer;
+use self::hedge::HedgeLayer;
+pub(crate) use self::hedge::HEDGING_CONTEXT_KEY;
+use self::hold_off::HoldOffLayer;
+use self::rate::Quota;
+pub(crate) use self::rate::Rate;
 use self::rate::RateLimitLayer;
 pub(crate) use self::rate::RateLimited;
+pub(crate) use self::rate::TokenBucket;
+use self::retry::RetryPolicy;
+use self::stats::StatsLayer;
+use self::stats::SubgraphStats;
+use self::tag_rate::TagRateLimitLayer;
 pub(crate) use self::timeout::Elapsed;
 use self::timeout::TimeoutLayer;
 use crate::error::ConfigurationError;
@@ -40,10 +74,22 @@ use crate::register_plugin;
 use crate::services::subgraph;
 use crate::services::subgraph_service::Compression;
 use crate::services::supergraph;
+use crate::services::transport;
 use crate::Configuration;
 use crate::SubgraphRequest;
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RETRY_TTL: Duration = Duration::from_secs(10);
+const DEFAULT_RETRY_MIN_PER_SEC: u32 = 10;
+const DEFAULT_RETRY_PERCENT: f32 = 0.2;
+const DEFAULT_STATS_WINDOW: Duration = Duration::from_secs(60);
+const DEFAULT_HEDGE_MAX_IN_FLIGHT: u64 = 10;
+const DEFAULT_HEDGE_PERCENT: f32 = 0.1;
+const DEFAULT_CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_OPEN_DURATION: Duration = Duration::from_secs(10);
+const DEFAULT_CONCURRENCY_INITIAL_LIMIT: u64 = 20;
+const DEFAULT_CONCURRENCY_MIN_LIMIT: u64 = 1;
+const DEFAULT_CONCURRENCY_MAX_LIMIT: u64 = 200;
 trait Merge {
     fn merge(&self, fallback: Option<&Self>) -> Self;
 }
@@ -57,10 +103,36 @@ struct Shaping {
     compression: Option<Compression>,
     /// Enable global rate limiting
     global_rate_limit: Option<RateLimitConf>,
+    /// Rate limits of the requests of the operations tagged by the `experimental.operation_tags`
+    /// plugin, by tag name and value. Each value has its own limit, and requests of operations
+    /// without the tag are not limited by it
+    experimental_tag_rate_limits: Option<HashMap<String, HashMap<String, RateLimitConf>>>,
     #[serde(deserialize_with = "humantime_serde::deserialize", default)]
     #[schemars(with = "String", default)]
-    /// Enable timeout for incoming requests
+    /// Timeout for requests to subgraphs (default: 30s). A fetch that times out is reported as a
+    /// GraphQL error on its path, and the rest of the query keeps executing
     timeout: Option<Duration>,
+    /// Retry failed queries to subgraphs, within a retry budget. Mutations are never retried
+    experimental_retry: Option<RetryConf>,
+    /// Stop sending requests to a subgraph that answered with a 429 or 503 status and a
+    /// `Retry-After` header until the delay elapses. Requests fail right away in the meantime
+    experimental_hold_off: Option<bool>,
+    /// Rules classifying the failures of subgraph requests as `retryable`, `non_retryable` or
+    /// `failover`, the first matching rule applies. Failures classified by a plugin are not
+    /// matched against these rules. A `failover` failure is not retried and holds off the
+    /// subgraph for 5 seconds if `experimental_hold_off` is enabled
+    experimental_error_classification: Option<Vec<ClassificationRule>>,
+    /// Send a second request to the subgraph when a query is not answered after a delay, and use
+    /// the first successful response. Mutations are never hedged
+    experimental_hedging: Option<HedgeConf>,
+    /// Stop sending requests to the subgraph after consecutive failures, or as soon as it answers
+    /// with a `Retry-After` delay. Requests fail right away while the circuit is open, then a
+    /// single request probes the subgraph
+    experimental_circuit_breaker: Option<CircuitBreakerConf>,
+    /// Limit the requests in flight to the subgraph, halving the limit when it answers with a
+    /// `Retry-After` delay or a `failover` failure and raising it back as requests succeed.
+    /// Requests over the limit are rejected
+    experimental_adaptive_concurrency: Option<AdaptiveConcurrencyConf>,
 }
 
 impl Merge for Shaping {
@@ -71,11 +143,44 @@ impl Merge for Shaping {
                 deduplicate_query: self.deduplicate_query.or(fallback.deduplicate_query),
                 compression: self.compression.or(fallback.compression),
                 timeout: self.timeout.or(fallback.timeout),
+                experimental_hold_off: self
+                    .experimental_hold_off
+                    .or(fallback.experimental_hold_off),
+                experimental_retry: self
+                    .experimental_retry
+                    .as_ref()
+                    .or(fallback.experimental_retry.as_ref())
+                    .cloned(),
                 global_rate_limit: self
                     .global_rate_limit
                     .as_ref()
                     .or(fallback.global_rate_limit.as_ref())
                     .cloned(),
+                experimental_tag_rate_limits: self
+                    .experimental_tag_rate_limits
+                    .as_ref()
+                    .or(fallback.experimental_tag_rate_limits.as_ref())
+                    .cloned(),
+                experimental_error_classification: self
+                    .experimental_error_classification
+                    .as_ref()
+                    .or(fallback.experimental_error_classification.as_ref())
+                    .cloned(),
+                experimental_hedging: self
+                    .experimental_hedging
+                    .as_ref()
+                    .or(fallback.experimental_hedging.as_ref())
+                    .cloned(),
+                experimental_circuit_breaker: self
+                    .experimental_circuit_breaker
+                    .as_ref()
+                    .or(fallback.experimental_circuit_breaker.as_ref())
+                    .cloned(),
+                experimental_adaptive_concurrency: self
+                    .experimental_adaptive_concurrency
+                    .as_ref()
+                    .or(fallback.experimental_adaptive_concurrency.as_ref())
+                    .cloned(),
             },
         }
     }
@@ -90,6 +195,10 @@ struct RouterShaping {
     #[schemars(with = "String", default)]
     /// Enable timeout for incoming requests
     timeout: Option<Duration>,
+    /// Send the state of the global rate limit to clients, in the `RateLimit-Limit`,
+    /// `RateLimit-Remaining` and `RateLimit-Reset` headers of the responses and rejections
+    /// (default: false)
+    experimental_rate_limit_headers: Option<bool>,
 }
 
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
@@ -109,16 +218,29 @@ pub(crate) struct Config {
     subgraphs: HashMap<String, Shaping>,
     /// Enable variable deduplication optimization when sending requests to subgraphs (https://github.com/apollographql/router/issues/87)
     deduplicate_variables: Option<bool>,
+    /// Serve live statistics of the requests to each subgraph at
+    /// `/plugins/apollo.traffic_shaping/subgraphs`
+    experimental_subgraph_stats: Option<SubgraphStatsConf>,
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct SubgraphStatsConf {
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// Time window of the latency percentiles and error rates (default: 60s)
+    window: Option<Duration>,
 }
 
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct RateLimitConf {
-    /// Number of requests allowed
+    /// Number of requests allowed per interval. Requests are limited with a token bucket of this
+    /// capacity, so bursts of up to `capacity` requests are accepted
     capacity: NonZeroU64,
     #[serde(deserialize_with = "humantime_serde::deserialize")]
     #[schemars(with = "String")]
-    /// Per interval
+    /// Per interval. The bucket is refilled continuously, at `capacity` tokens per interval
     interval: Duration,
 }
 
@@ -134,12 +256,172 @@ impl Merge for RateLimitConf {
     }
 }
 
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RetryConf {
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long a request is counted in the budget, between 1s and 60s (default: 10s)
+    ttl: Option<Duration>,
+    /// Number of retries allowed per second regardless of the number of requests (default: 10)
+    min_per_sec: Option<u32>,
+    /// Ratio of retries to requests allowed on top of `min_per_sec`, between 0 and 1000
+    /// (default: 0.2, allowing 20% more requests)
+    retry_percent: Option<f32>,
+}
+
+impl RetryConf {
+    fn policy(&self, classifier: Classifier) -> RetryPolicy {
+        RetryPolicy::new(
+            self.ttl.unwrap_or(DEFAULT_RETRY_TTL),
+            self.min_per_sec.unwrap_or(DEFAULT_RETRY_MIN_PER_SEC),
+            self.retry_percent.unwrap_or(DEFAULT_RETRY_PERCENT),
+            classifier,
+        )
+    }
+
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let ttl = self.ttl.unwrap_or(DEFAULT_RETRY_TTL);
+        if ttl < Duration::from_secs(1) || ttl > Duration::from_secs(60) {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "the retry ttl must be between 1s and 60s".to_string(),
+            });
+        }
+        let retry_percent = self.retry_percent.unwrap_or(DEFAULT_RETRY_PERCENT);
+        if !(0.0..=1000.0).contains(&retry_percent) {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "the retry percent must be between 0 and 1000".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct HedgeConf {
+    #[serde(deserialize_with = "humantime_serde::deserialize")]
+    #[schemars(with = "String")]
+    /// How long a query waits for its response before it is hedged
+    delay: Duration,
+    /// Names of the client operations whose queries are hedged (default: all the operations)
+    operations: Option<Vec<String>>,
+    /// Maximum number of hedge requests in flight to the subgraph at once (default: 10)
+    max_in_flight: Option<u64>,
+    /// Ratio of hedge requests to requests allowed, between 0 and 1 (default: 0.1, allowing 10%
+    /// more requests)
+    hedge_percent: Option<f32>,
+}
+
+impl HedgeConf {
+    fn layer(&self, service_name: &str) -> HedgeLayer {
+        HedgeLayer::new(
+            service_name,
+            self.delay,
+            self.operations.as_deref(),
+            self.max_in_flight.unwrap_or(DEFAULT_HEDGE_MAX_IN_FLIGHT),
+            self.hedge_percent.unwrap_or(DEFAULT_HEDGE_PERCENT),
+        )
+    }
+
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let hedge_percent = self.hedge_percent.unwrap_or(DEFAULT_HEDGE_PERCENT);
+        if !(0.0..=1.0).contains(&hedge_percent) {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "the hedge percent must be between 0 and 1".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerConf {
+    /// Number of consecutive failures opening the circuit (default: 5)
+    failure_threshold: Option<u32>,
+    #[serde(deserialize_with = "humantime_serde::deserialize", default)]
+    #[schemars(with = "String", default)]
+    /// How long the circuit stays open, or longer if the subgraph asked to wait longer with a
+    /// `Retry-After` delay (default: 10s)
+    open_duration: Option<Duration>,
+}
+
+impl CircuitBreakerConf {
+    fn layer(&self, service_name: &str, classifier: Classifier) -> CircuitBreakerLayer {
+        CircuitBreakerLayer::new(
+            service_name,
+            classifier,
+            self.failure_threshold
+                .unwrap_or(DEFAULT_CIRCUIT_FAILURE_THRESHOLD),
+            self.open_duration.unwrap_or(DEFAULT_CIRCUIT_OPEN_DURATION),
+        )
+    }
+
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        if self.failure_threshold == Some(0) {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "the circuit breaker failure threshold must be at least 1".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct AdaptiveConcurrencyConf {
+    /// Limit of requests in flight on startup (default: 20)
+    initial_limit: Option<u64>,
+    /// The limit is never decreased under this number of requests (default: 1)
+    min_limit: Option<u64>,
+    /// The limit is never increased over this number of requests (default: 200)
+    max_limit: Option<u64>,
+}
+
+impl AdaptiveConcurrencyConf {
+    fn layer(&self, service_name: &str, classifier: Classifier) -> AdaptiveConcurrencyLayer {
+        AdaptiveConcurrencyLayer::new(
+            service_name,
+            classifier,
+            self.initial_limit
+                .unwrap_or(DEFAULT_CONCURRENCY_INITIAL_LIMIT),
+            self.min_limit.unwrap_or(DEFAULT_CONCURRENCY_MIN_LIMIT),
+            self.max_limit.unwrap_or(DEFAULT_CONCURRENCY_MAX_LIMIT),
+        )
+    }
+
+    fn validate(&self) -> Result<(), ConfigurationError> {
+        let min_limit = self.min_limit.unwrap_or(DEFAULT_CONCURRENCY_MIN_LIMIT);
+        let max_limit = self.max_limit.unwrap_or(DEFAULT_CONCURRENCY_MAX_LIMIT);
+        if min_limit == 0 || min_limit > max_limit {
+            return Err(ConfigurationError::InvalidConfiguration {
+                message: "bad configuration for traffic_shaping plugin",
+                error: "the concurrency limits must satisfy 1 <= min_limit <= max_limit"
+                    .to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
 // FIXME: This struct is pub(crate) because we need its configuration in the query planner service.
 // Remove this once the configuration yml changes.
 pub(crate) struct TrafficShaping {
     config: Config,
     rate_limit_router: Option<RateLimitLayer>,
     rate_limit_subgraphs: Mutex<HashMap<String, RateLimitLayer>>,
+    tag_rate_limit_subgraphs: Mutex<HashMap<String, TagRateLimitLayer>>,
+    retry_subgraphs: Mutex<HashMap<String, RetryPolicy>>,
+    hedge_subgraphs: Mutex<HashMap<String, HedgeLayer>>,
+    hold_off_subgraphs: Arc<Mutex<HashMap<String, HoldOffLayer>>>,
+    circuit_breaker_subgraphs: Arc<Mutex<HashMap<String, CircuitBreakerLayer>>>,
+    concurrency_subgraphs: Arc<Mutex<HashMap<String, AdaptiveConcurrencyLayer>>>,
+    stats_subgraphs: Arc<Mutex<HashMap<String, StatsLayer>>>,
 }
 
 #[async_trait::async_trait]
@@ -165,19 +447,86 @@ impl Plugin for TrafficShaping {
                     Ok(RateLimitLayer::new(
                         router_rate_limit_conf.capacity,
                         router_rate_limit_conf.interval,
+                    )
+                    .with_headers(
+                        init.config
+                            .router
+                            .as_ref()
+                            .and_then(|r| r.experimental_rate_limit_headers)
+                            .unwrap_or_default(),
                     ))
                 }
             })
             .transpose()?;
 
+        for retry in init
+            .config
+            .all
+            .iter()
+            .chain(init.config.subgraphs.values())
+            .filter_map(|shaping| shaping.experimental_retry.as_ref())
+        {
+            retry.validate()?;
+        }
+        for rules in init
+            .config
+            .all
+            .iter()
+            .chain(init.config.subgraphs.values())
+            .filter_map(|shaping| shaping.experimental_error_classification.as_ref())
+        {
+            Classifier::new(rules)?;
+        }
+        for hedging in init
+            .config
+            .all
+            .iter()
+            .chain(init.config.subgraphs.values())
+            .filter_map(|shaping| shaping.experimental_hedging.as_ref())
+        {
+            hedging.validate()?;
+        }
+        for circuit_breaker in init
+            .config
+            .all
+            .iter()
+            .chain(init.config.subgraphs.values())
+            .filter_map(|shaping| shaping.experimental_circuit_breaker.as_ref())
+        {
+            circuit_breaker.validate()?;
+        }
+        for concurrency in init
+            .config
+            .all
+            .iter()
+            .chain(init.config.subgraphs.values())
+            .filter_map(|shaping| shaping.experimental_adaptive_concurrency.as_ref())
+        {
+            concurrency.validate()?;
+        }
+
         Ok(Self {
             config: init.config,
             rate_limit_router,
             rate_limit_subgraphs: Mutex::new(HashMap::new()),
+            tag_rate_limit_subgraphs: Mutex::new(HashMap::new()),
+            retry_subgraphs: Mutex::new(HashMap::new()),
+            hedge_subgraphs: Mutex::new(HashMap::new()),
+            hold_off_subgraphs: Default::default(),
+            circuit_breaker_subgraphs: Default::default(),
+            concurrency_subgraphs: Default::default(),
+            stats_subgraphs: Default::default(),
         })
     }
 
     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        let rate_limit_headers = self.rate_limit_router.clone().filter(|_| {
+            self.config
+                .router
+                .as_ref()
+                .and_then(|r| r.experimental_rate_limit_headers)
+                .unwrap_or_default()
+        });
         ServiceBuilder::new()
             .layer(TimeoutLayer::new(
                 self.config
@@ -186,6 +535,19 @@ impl Plugin for TrafficShaping {
                     .and_then(|r| r.timeout)
                     .unwrap_or(DEFAULT_TIMEOUT),
             ))
+            // The token of the request is taken when the rate limit is ready, before the call
+            .map_future_with_request_data(
+                move |_: &supergraph::Request| {
+                    rate_limit_headers.as_ref().map(RateLimitLayer::quota)
+                },
+                |quota: Option<Quota>, f| async move {
+                    let mut response: supergraph::Response = f.await?;
+                    if let Some(quota) = quota {
+                        quota.insert_headers(response.response.headers_mut());
+                    }
+                    Ok::<_, BoxError>(response)
+                },
+            )
             .option_layer(self.rate_limit_router.clone())
             .service(service)
             .boxed()
@@ -196,8 +558,29 @@ impl Plugin for TrafficShaping {
         let all_config = self.config.all.as_ref();
         let subgraph_config = self.config.subgraphs.get(name);
         let final_config = Self::merge_config(all_config, subgraph_config);
+        let stats = self
+            .config
+            .experimental_subgraph_stats
+            .as_ref()
+            .map(|stats_conf| {
+                self.stats_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| {
+                        StatsLayer::new(stats_conf.window.unwrap_or(DEFAULT_STATS_WINDOW))
+                    })
+                    .clone()
+            });
 
         if let Some(config) = final_config {
+            let classifier = config
+                .experimental_error_classification
+                .as_deref()
+                .map(Classifier::new)
+                .transpose()
+                .expect("classification rules are validated in new(); qed")
+                .unwrap_or_default();
             let rate_limit = config.global_rate_limit.as_ref().map(|rate_limit_conf| {
                 self.rate_limit_subgraphs
                     .lock()
@@ -208,6 +591,65 @@ impl Plugin for TrafficShaping {
                     })
                     .clone()
             });
+            let tag_rate_limit = config.experimental_tag_rate_limits.as_ref().map(|limits| {
+                self.tag_rate_limit_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| TagRateLimitLayer::new(limits))
+                    .clone()
+            });
+            // The budget is shared by all the requests to a subgraph
+            let retry = config.experimental_retry.as_ref().map(|retry_conf| {
+                let policy = self
+                    .retry_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| retry_conf.policy(classifier.clone()))
+                    .clone();
+                // Buffer is required because the retry layer requires a clone service.
+                ServiceBuilder::new().retry(policy).buffered()
+            });
+            // The caps are shared by all the requests to a subgraph
+            let hedge = config.experimental_hedging.as_ref().map(|hedge_conf| {
+                let layer = self
+                    .hedge_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| hedge_conf.layer(name))
+                    .clone();
+                // Buffer is required because the hedge layer requires a clone service.
+                ServiceBuilder::new().layer(layer).buffered()
+            });
+            let hold_off = config.experimental_hold_off.unwrap_or_default().then(|| {
+                self.hold_off_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| HoldOffLayer::new(name, classifier.clone()))
+                    .clone()
+            });
+            let circuit_breaker = config.experimental_circuit_breaker.as_ref().map(|conf| {
+                self.circuit_breaker_subgraphs
+                    .lock()
+                    .unwrap()
+                    .entry(name.to_string())
+                    .or_insert_with(|| conf.layer(name, classifier.clone()))
+                    .clone()
+            });
+            let concurrency = config
+                .experimental_adaptive_concurrency
+                .as_ref()
+                .map(|conf| {
+                    self.concurrency_subgraphs
+                        .lock()
+                        .unwrap()
+                        .entry(name.to_string())
+                        .or_insert_with(|| conf.layer(name, classifier))
+                        .clone()
+                });
             ServiceBuilder::new()
                 .option_layer(config.deduplicate_query.unwrap_or_default().then(|| {
                     // Buffer is required because dedup layer requires a clone service.
@@ -220,7 +662,19 @@ impl Plugin for TrafficShaping {
                     .timeout
                     .unwrap_or(DEFAULT_TIMEOUT),
                 ))
+                .option_layer(hold_off)
+                // The circuit sees the outcome of the retries and hedge requests of a request
+                .option_layer(circuit_breaker)
+                .option_layer(hedge)
+                .option_layer(retry)
+                // Hedge requests and retries take a token too, rejections of the tag rate
+                // limits don't
+                .option_layer(tag_rate_limit)
                 .option_layer(rate_limit)
+                // Hedge requests and retries are in flight too
+                .option_layer(concurrency)
+                // Each request sent to the subgraph is recorded, retries included
+                .option_layer(stats)
                 .service(service)
                 .map_request(move |mut req: SubgraphRequest| {
                     if let Some(compression) = config.compression {
@@ -232,10 +686,91 @@ impl Plugin for TrafficShaping {
                     req
                 })
                 .boxed()
+        } else if let Some(stats) = stats {
+            ServiceBuilder::new().layer(stats).service(service).boxed()
         } else {
             service
         }
     }
+
+    fn admin_endpoint(&self) -> Option<transport::BoxService> {
+        self.config.experimental_subgraph_stats.as_ref()?;
+        let stats_subgraphs = self.stats_subgraphs.clone();
+        let hold_off_subgraphs = self.hold_off_subgraphs.clone();
+        let circuit_breaker_subgraphs = self.circuit_breaker_subgraphs.clone();
+        let concurrency_subgraphs = self.concurrency_subgraphs.clone();
+        Some(
+            tower::service_fn(move |request: transport::Request| {
+                let path = request.uri().path().trim_end_matches('/');
+                let response =
+                    if request.method() == http::Method::GET && path.ends_with("/subgraphs") {
+                        let stats = subgraph_stats(
+                            &stats_subgraphs,
+                            &hold_off_subgraphs,
+                            &circuit_breaker_subgraphs,
+                            &concurrency_subgraphs,
+                        );
+                        serde_json::to_string(&stats)
+                            .map_err(BoxError::from)
+                            .and_then(|body| {
+                                Ok(http::Response::builder()
+                                    .header(http::header::CONTENT_TYPE, "application/json")
+                                    .body(body.into())?)
+                            })
+                    } else {
+                        http::Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(hyper::Body::empty())
+                            .map_err(BoxError::from)
+                    };
+                futures::future::ready(response)
+            })
+            .boxed(),
+        )
+    }
+}
+
+/// Handles `GET /plugins/apollo.traffic_shaping/subgraphs`: the statistics of each subgraph, with
+/// the state of its circuit, open while the subgraph is held off or its circuit breaker is open,
+/// and its concurrency limit.
+fn subgraph_stats(
+    stats_subgraphs: &Mutex<HashMap<String, StatsLayer>>,
+    hold_off_subgraphs: &Mutex<HashMap<String, HoldOffLayer>>,
+    circuit_breaker_subgraphs: &Mutex<HashMap<String, CircuitBreakerLayer>>,
+    concurrency_subgraphs: &Mutex<HashMap<String, AdaptiveConcurrencyLayer>>,
+) -> BTreeMap<String, SubgraphStats> {
+    let hold_off_subgraphs = hold_off_subgraphs.lock().unwrap();
+    let circuit_breaker_subgraphs = circuit_breaker_subgraphs.lock().unwrap();
+    let concurrency_subgraphs = concurrency_subgraphs.lock().unwrap();
+    stats_subgraphs
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, layer)| {
+            let mut stats = layer.stats();
+            let mut remaining = hold_off_subgraphs
+                .get(name)
+                .and_then(HoldOffLayer::remaining);
+            match circuit_breaker_subgraphs
+                .get(name)
+                .map(CircuitBreakerLayer::state)
+            {
+                Some(CircuitState::Open { remaining: open }) => {
+                    remaining = Some(remaining.map_or(open, |remaining| remaining.max(open)))
+                }
+                Some(CircuitState::HalfOpen) => stats.circuit = "half_open",
+                Some(CircuitState::Closed) | None => {}
+            }
+            if let Some(remaining) = remaining {
+                stats.circuit = "open";
+                stats.circuit_remaining_ms = Some(remaining.as_millis() as u64);
+            }
+            stats.concurrency_limit = concurrency_subgraphs
+                .get(name)
+                .map(AdaptiveConcurrencyLayer::limit);
+            (name.clone(), stats)
+        })
+        .collect()
 }
 
 impl TrafficShaping {
@@ -261,8 +796,11 @@ register_plugin!("apollo", "traffic_shaping", TrafficShaping);
 
 #[cfg(test)]
 mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
 
+    use axum::response::IntoResponse;
     use once_cell::sync::Lazy;
     use serde_json_bytes::json;
     use serde_json_bytes::ByteString;
@@ -271,14 +809,17 @@ mod test {
     use tower::Service;
 
     use super::*;
+    use crate::error::FetchError;
     use crate::graphql::Response;
     use crate::json_ext::Object;
     use crate::plugin::test::MockSubgraph;
     use crate::plugin::test::MockSupergraphService;
     use crate::plugin::DynPlugin;
+    use crate::query_planner::fetch::OperationKind;
     use crate::Configuration;
     use crate::PluggableSupergraphServiceBuilder;
     use crate::Schema;
+    use crate::SubgraphResponse;
     use crate::SupergraphRequest;
     use crate::SupergraphResponse;
 
@@ -314,6 +855,13 @@ mod test {
 
     async fn build_mock_router_with_variable_dedup_optimization(
         plugin: Box<dyn DynPlugin>,
+    ) -> BoxCloneService<SupergraphRequest, SupergraphResponse, BoxError> {
+        build_mock_router(plugin, None).await
+    }
+
+    async fn build_mock_router(
+        plugin: Box<dyn DynPlugin>,
+        reviews_delay: Option<Duration>,
     ) -> BoxCloneService<SupergraphRequest, SupergraphResponse, BoxError> {
         let mut extensions = Object::new();
         extensions.insert("test", Value::String(ByteString::from("value")));
@@ -333,6 +881,15 @@ mod test {
             )
             ].into_iter().map(|(query, response)| (serde_json::from_str(query).unwrap(), serde_json::from_str(response).unwrap())).collect();
         let review_service = MockSubgraph::new(review_mocks);
+        let review_service = tower::service_fn(move |req: SubgraphRequest| {
+            let review_service = review_service.clone();
+            async move {
+                if let Some(delay) = reviews_delay {
+                    tokio::time::sleep(delay).await;
+                }
+                review_service.oneshot(req).await
+            }
+        });
 
         let product_mocks = vec![
             (
@@ -398,6 +955,97 @@ mod test {
         execute_router_test(VALID_QUERY, &*EXPECTED_RESPONSE, router).await;
     }
 
+    #[tokio::test]
+    async fn it_returns_an_error_on_the_path_of_a_timed_out_fetch() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        deduplicate_variables: true
+        all:
+            timeout: 5s
+        subgraphs:
+            reviews:
+                timeout: 50ms
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let mut router = build_mock_router(plugin, Some(Duration::from_millis(500))).await;
+
+        let request = SupergraphRequest::fake_builder()
+            .query(VALID_QUERY.to_string())
+            .variable("first", 2usize)
+            .build()
+            .expect("expecting valid request");
+        let response = router
+            .ready()
+            .await
+            .unwrap()
+            .call(request)
+            .await
+            .unwrap()
+            .next_response()
+            .await
+            .unwrap();
+
+        assert_eq!(response.errors.len(), 1);
+        let error = &response.errors[0];
+        assert_eq!(error.message, "request to 'reviews' timed out");
+        assert_eq!(
+            error.extensions.get("type"),
+            Some(&Value::from("SubrequestTimeout"))
+        );
+        assert_eq!(
+            error.path.as_ref().map(|path| path.to_string()),
+            Some("/topProducts/@".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn it_returns_an_error_on_the_path_of_a_rate_limited_fetch() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        deduplicate_variables: true
+        subgraphs:
+            reviews:
+                global_rate_limit:
+                    capacity: 1
+                    interval: 10s
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let router = build_mock_router(plugin, None).await;
+        execute_router_test(VALID_QUERY, &*EXPECTED_RESPONSE, router.clone()).await;
+
+        let request = SupergraphRequest::fake_builder()
+            .query(VALID_QUERY.to_string())
+            .variable("first", 2usize)
+            .build()
+            .expect("expecting valid request");
+        let mut response = router.oneshot(request).await.unwrap();
+        assert_eq!(
+            response.response.headers().get(http::header::RETRY_AFTER),
+            Some(&HeaderValue::from_static("10"))
+        );
+        let response = response.next_response().await.unwrap();
+
+        assert_eq!(response.errors.len(), 1);
+        let error = &response.errors[0];
+        assert_eq!(
+            error.message,
+            "request to 'reviews' was rate limited, retry after 10 seconds"
+        );
+        assert_eq!(
+            error.extensions.get("type"),
+            Some(&Value::from("SubrequestRateLimited"))
+        );
+        assert_eq!(error.extensions.get("retry_after"), Some(&Value::from(10)));
+        assert_eq!(
+            error.path.as_ref().map(|path| path.to_string()),
+            Some("/topProducts/@".to_string())
+        );
+    }
+
     #[tokio::test]
     async fn it_add_correct_headers_for_compression() {
         let config = serde_yaml::from_str::<serde_json::Value>(
@@ -509,6 +1157,425 @@ mod test {
             .unwrap();
     }
 
+    fn failing_once_service(calls: Arc<AtomicUsize>) -> subgraph::BoxService {
+        tower::service_fn(move |_req: SubgraphRequest| {
+            let calls = calls.clone();
+            async move {
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err(BoxError::from(FetchError::SubrequestHttpError {
+                        service: "test".to_string(),
+                        reason: "connection reset".to_string(),
+                        status: None,
+                    }))
+                } else {
+                    Ok(SubgraphResponse::fake_builder().build())
+                }
+            }
+        })
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn it_retries_failed_subgraph_queries() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                experimental_retry:
+                    min_per_sec: 10
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        plugin
+            .subgraph_service("test", failing_once_service(calls.clone()))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect("the query should be retried");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Mutations are never retried
+        let calls = Arc::new(AtomicUsize::new(0));
+        plugin
+            .subgraph_service("test", failing_once_service(calls.clone()))
+            .oneshot(
+                SubgraphRequest::fake_builder()
+                    .operation_kind(OperationKind::Mutation)
+                    .build(),
+            )
+            .await
+            .expect_err("the mutation should not be retried");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_beyond_the_budget() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        all:
+            experimental_retry:
+                min_per_sec: 0
+                retry_percent: 0
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        plugin
+            .subgraph_service("test", failing_once_service(calls.clone()))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the budget does not allow retries");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    fn slow_once_service(calls: Arc<AtomicUsize>) -> subgraph::BoxService {
+        tower::service_fn(move |_req: SubgraphRequest| {
+            let slow = calls.fetch_add(1, Ordering::SeqCst) == 0;
+            async move {
+                if slow {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+                Ok(SubgraphResponse::fake_builder().build())
+            }
+        })
+        .boxed()
+    }
+
+    #[tokio::test]
+    async fn it_hedges_slow_subgraph_queries() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                experimental_hedging:
+                    delay: 10ms
+                    hedge_percent: 1
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            plugin
+                .subgraph_service("test", slow_once_service(calls.clone()))
+                .oneshot(SubgraphRequest::fake_builder().build()),
+        )
+        .await
+        .expect("the hedge request should answer first")
+        .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Mutations are never hedged
+        let calls = Arc::new(AtomicUsize::new(0));
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            plugin
+                .subgraph_service("test", slow_once_service(calls.clone()))
+                .oneshot(
+                    SubgraphRequest::fake_builder()
+                        .operation_kind(OperationKind::Mutation)
+                        .build(),
+                ),
+        )
+        .await
+        .expect_err("the mutation should not be hedged");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_holds_off_subgraphs_asking_to_retry_later() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                experimental_hold_off: true
+                experimental_retry:
+                    min_per_sec: 10
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = |calls: Arc<AtomicUsize>| {
+            tower::service_fn(move |_req: SubgraphRequest| {
+                let calls = calls.clone();
+                async move {
+                    if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                        Err(BoxError::from(FetchError::SubrequestUnavailable {
+                            service: "test".to_string(),
+                            retry_after: 1,
+                        }))
+                    } else {
+                        Ok(SubgraphResponse::fake_builder().build())
+                    }
+                }
+            })
+            .boxed()
+        };
+
+        let error = plugin
+            .subgraph_service("test", service(calls.clone()))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the subgraph is unavailable");
+        assert_eq!(
+            error.to_string(),
+            "service 'test' is unavailable, retry after 1 seconds"
+        );
+        // A subgraph asking to be called later is not retried
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        plugin
+            .subgraph_service("test", service(calls.clone()))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the subgraph is held off");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        plugin
+            .subgraph_service("test", service(calls.clone()))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect("the delay has elapsed");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_serves_the_stats_of_subgraphs() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                experimental_hold_off: true
+                experimental_circuit_breaker:
+                    open_duration: 60s
+                experimental_adaptive_concurrency:
+                    initial_limit: 8
+        experimental_subgraph_stats:
+            window: 10s
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        plugin
+            .subgraph_service(
+                "test",
+                tower::service_fn(|_req: SubgraphRequest| async {
+                    Err(BoxError::from(FetchError::SubrequestUnavailable {
+                        service: "test".to_string(),
+                        retry_after: 5,
+                    }))
+                })
+                .boxed(),
+            )
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the subgraph is unavailable");
+
+        let response = plugin
+            .admin_endpoint()
+            .expect("the stats are enabled")
+            .oneshot(
+                http::Request::builder()
+                    .uri("http://router/plugins/apollo.traffic_shaping/subgraphs")
+                    .body(hyper::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["test"]["in_flight"], 0);
+        assert_eq!(stats["test"]["requests"], 1);
+        assert_eq!(stats["test"]["error_rate"], 1.0);
+        // the circuit breaker opens for longer than the subgraph is held off
+        assert_eq!(stats["test"]["circuit"], "open");
+        assert!(stats["test"]["circuit_remaining_ms"].as_u64().unwrap() > 5_000);
+        assert_eq!(stats["test"]["concurrency_limit"], 4);
+    }
+
+    #[tokio::test]
+    async fn it_retries_and_holds_off_according_to_the_classification() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                experimental_hold_off: true
+                experimental_retry:
+                    min_per_sec: 10
+                experimental_error_classification:
+                    - error_codes: [UNAVAILABLE]
+                      class: retryable
+                    - status: [500]
+                      class: failover
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = |calls: Arc<AtomicUsize>,
+                       first: fn() -> Result<SubgraphResponse, BoxError>| {
+            tower::service_fn(move |_req: SubgraphRequest| {
+                let calls = calls.clone();
+                async move {
+                    if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                        first()
+                    } else {
+                        Ok(SubgraphResponse::fake_builder().build())
+                    }
+                }
+            })
+            .boxed()
+        };
+
+        let response = plugin
+            .subgraph_service(
+                "test",
+                service(calls.clone(), || {
+                    Ok(SubgraphResponse::fake_builder()
+                        .error(
+                            crate::graphql::Error::builder()
+                                .message("products are unavailable")
+                                .extension("code", "UNAVAILABLE")
+                                .build(),
+                        )
+                        .build())
+                }),
+            )
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .unwrap();
+        // The retryable response is retried
+        assert!(response.response.body().errors.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        plugin
+            .subgraph_service(
+                "test",
+                service(calls.clone(), || {
+                    Err(BoxError::from(FetchError::SubrequestHttpError {
+                        service: "test".to_string(),
+                        reason: "subgraph HTTP status error '500'".to_string(),
+                        status: Some(500),
+                    }))
+                }),
+            )
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the failover error is not retried");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let error = plugin
+            .subgraph_service("test", service(calls.clone(), || unreachable!()))
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the subgraph is held off");
+        assert_eq!(
+            error.to_string(),
+            "service 'test' is unavailable, retry after 5 seconds"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_rate_limits_retries() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        subgraphs:
+            test:
+                global_rate_limit:
+                    capacity: 2
+                    interval: 60s
+                experimental_retry:
+                    min_per_sec: 10
+        "#,
+        )
+        .unwrap();
+        let plugin = get_traffic_shaping_plugin(&config).await;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service_calls = calls.clone();
+        let error = plugin
+            .subgraph_service(
+                "test",
+                tower::service_fn(move |_req: SubgraphRequest| {
+                    service_calls.fetch_add(1, Ordering::SeqCst);
+                    async {
+                        Err(BoxError::from(FetchError::SubrequestHttpError {
+                            service: "test".to_string(),
+                            reason: "connection reset".to_string(),
+                            status: None,
+                        }))
+                    }
+                })
+                .boxed(),
+            )
+            .oneshot(SubgraphRequest::fake_builder().build())
+            .await
+            .expect_err("the subgraph fails");
+        // The retry takes the last token, the next one is rejected and not retried
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(error
+            .downcast_ref::<RateLimited>()
+            .or_else(|| error.source().and_then(|source| source.downcast_ref()))
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_retry_budgets() {
+        let config = serde_json::json!({
+            "all": { "experimental_retry": { "ttl": "2m" } }
+        });
+        assert!(crate::plugin::plugins()
+            .get("apollo.traffic_shaping")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_hedge_percents() {
+        let config = serde_json::json!({
+            "all": { "experimental_hedging": { "delay": "100ms", "hedge_percent": 2 } }
+        });
+        assert!(crate::plugin::plugins()
+            .get("apollo.traffic_shaping")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_classification_rules() {
+        let config = serde_json::json!({
+            "subgraphs": {
+                "test": {
+                    "experimental_error_classification": [{ "message": "(", "class": "retryable" }]
+                }
+            }
+        });
+        assert!(crate::plugin::plugins()
+            .get("apollo.traffic_shaping")
+            .expect("Plugin not found")
+            .create_instance_without_schema(&config)
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn it_rate_limit_router_requests() {
         let config = serde_yaml::from_str::<serde_json::Value>(
@@ -545,11 +1612,21 @@ mod test {
             .await
             .unwrap();
 
-        assert!(plugin
+        let error = plugin
             .supergraph_service(mock_service.clone().boxed())
             .oneshot(SupergraphRequest::fake_builder().build().unwrap())
             .await
-            .is_err());
+            .expect_err("should be rate limited");
+        let response = error
+            .downcast_ref::<RateLimited>()
+            .expect("should be a rate limit error")
+            .clone()
+            .into_response();
+        assert_eq!(response.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(http::header::RETRY_AFTER),
+            Some(&HeaderValue::from_static("1"))
+        );
         tokio::time::sleep(Duration::from_millis(300)).await;
         let _response = plugin
             .supergraph_service(mock_service.clone().boxed())
@@ -560,4 +1637,59 @@ mod test {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn it_sends_rate_limit_headers() {
+        let config = serde_yaml::from_str::<serde_json::Value>(
+            r#"
+        router:
+            global_rate_limit:
+                capacity: 2
+                interval: 10s
+            experimental_rate_limit_headers: true
+        "#,
+        )
+        .unwrap();
+
+        let plugin = get_traffic_shaping_plugin(&config).await;
+        let mut mock_service = MockSupergraphService::new();
+        mock_service.expect_clone().returning(|| {
+            let mut mock_service = MockSupergraphService::new();
+            mock_service.expect_call().times(0..2).returning(move |_| {
+                Ok(SupergraphResponse::fake_builder()
+                    .data(json!({ "test": 1234_u32 }))
+                    .build()
+                    .unwrap())
+            });
+            mock_service
+        });
+
+        let response = plugin
+            .supergraph_service(mock_service.clone().boxed())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap();
+        let headers = response.response.headers();
+        assert_eq!(headers.get("ratelimit-limit").unwrap(), "2");
+        assert_eq!(headers.get("ratelimit-remaining").unwrap(), "1");
+        assert_eq!(headers.get("ratelimit-reset").unwrap(), "5");
+
+        let _response = plugin
+            .supergraph_service(mock_service.clone().boxed())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .unwrap();
+        let response = plugin
+            .supergraph_service(mock_service.clone().boxed())
+            .oneshot(SupergraphRequest::fake_builder().build().unwrap())
+            .await
+            .expect_err("should be rate limited")
+            .downcast_ref::<RateLimited>()
+            .expect("should be a rate limit error")
+            .clone()
+            .into_response();
+        assert_eq!(response.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("ratelimit-remaining").unwrap(), "0");
+        assert_eq!(response.headers().get("ratelimit-reset").unwrap(), "10");
+    }
 }
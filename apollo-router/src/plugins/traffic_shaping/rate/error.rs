@@ -2,18 +2,44 @@
 
 use std::error;
 use std::fmt;
+use std::time::Duration;
 
 use axum::response::IntoResponse;
+use http::header::RETRY_AFTER;
 use http::StatusCode;
 
+use super::service::Quota;
+
 /// The rate limit error.
-#[derive(Debug, Default)]
-pub(crate) struct RateLimited;
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimited {
+    retry_after: Duration,
+    quota: Option<Quota>,
+}
 
 impl RateLimited {
     /// Construct a new RateLimited error
-    pub(crate) fn new() -> Self {
-        RateLimited {}
+    pub(crate) fn new(retry_after: Duration) -> Self {
+        RateLimited {
+            retry_after,
+            quota: None,
+        }
+    }
+
+    /// Send the state of the rate limit in the `RateLimit-*` headers of the response
+    pub(crate) fn with_quota(mut self, quota: Quota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Number of seconds to wait before a request can be accepted again, rounded up
+    pub(crate) fn retry_after_secs(&self) -> u64 {
+        let secs = self.retry_after.as_secs();
+        if self.retry_after.subsec_nanos() > 0 || secs == 0 {
+            secs + 1
+        } else {
+            secs
+        }
     }
 }
 
@@ -25,7 +51,16 @@ impl fmt::Display for RateLimited {
 
 impl IntoResponse for RateLimited {
     fn into_response(self) -> axum::response::Response {
-        (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response()
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, self.retry_after_secs().to_string())],
+            self.to_string(),
+        )
+            .into_response();
+        if let Some(quota) = &self.quota {
+            quota.insert_headers(response.headers_mut());
+        }
+        response
     }
 }
 
@@ -7,17 +7,31 @@ use std::task::Poll;
 
 use pin_project_lite::pin_project;
 
+use super::error::RateLimited;
+
 pin_project! {
+    #[project = ResponseFutureProj]
     #[derive(Debug)]
-    pub(crate) struct ResponseFuture<T> {
-        #[pin]
-        response: T,
+    pub(crate) enum ResponseFuture<T> {
+        Called {
+            #[pin]
+            response: T,
+        },
+        Rejected {
+            rate_limited: Option<RateLimited>,
+        },
     }
 }
 
 impl<T> ResponseFuture<T> {
-    pub(crate) fn new(response: T) -> Self {
-        ResponseFuture { response }
+    pub(crate) fn called(response: T) -> Self {
+        ResponseFuture::Called { response }
+    }
+
+    pub(crate) fn rejected(rate_limited: RateLimited) -> Self {
+        ResponseFuture::Rejected {
+            rate_limited: Some(rate_limited),
+        }
     }
 }
 
@@ -29,11 +43,15 @@ where
     type Output = Result<T, tower::BoxError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-
-        match this.response.poll(cx) {
-            Poll::Ready(v) => Poll::Ready(v.map_err(Into::into)),
-            Poll::Pending => Poll::Pending,
+        match self.project() {
+            ResponseFutureProj::Called { response } => match response.poll(cx) {
+                Poll::Ready(v) => Poll::Ready(v.map_err(Into::into)),
+                Poll::Pending => Poll::Pending,
+            },
+            ResponseFutureProj::Rejected { rate_limited } => Poll::Ready(Err(rate_limited
+                .take()
+                .expect("polled after completion")
+                .into())),
         }
     }
 }
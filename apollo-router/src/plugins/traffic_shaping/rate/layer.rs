@@ -1,23 +1,24 @@
 use std::num::NonZeroU64;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
+use std::time::Instant;
 
 use tower::Layer;
 
+use super::service::Quota;
+use super::service::TokenBucket;
 use super::Rate;
 use super::RateLimit;
 /// Enforces a rate limit on the number of requests the underlying
 /// service can handle over a period of time.
+///
+/// The services created by a layer share the same token bucket.
 #[derive(Debug, Clone)]
 pub(crate) struct RateLimitLayer {
     rate: Rate,
-    window_start: Arc<AtomicU64>,
-    previous_nb_requests: Arc<AtomicUsize>,
-    current_nb_requests: Arc<AtomicUsize>,
+    bucket: Arc<Mutex<TokenBucket>>,
+    headers: bool,
 }
 
 impl RateLimitLayer {
@@ -26,16 +27,24 @@ impl RateLimitLayer {
         let rate = Rate::new(num, per);
         RateLimitLayer {
             rate,
-            window_start: Arc::new(AtomicU64::new(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("system time must be after EPOCH")
-                    .as_millis() as u64,
-            )),
-            previous_nb_requests: Arc::default(),
-            current_nb_requests: Arc::new(AtomicUsize::new(1)),
+            bucket: Arc::new(Mutex::new(TokenBucket::new(rate))),
+            headers: false,
         }
     }
+
+    /// Send the `RateLimit-*` headers with the rejections.
+    pub(crate) fn with_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// The current state of the rate limit.
+    pub(crate) fn quota(&self) -> Quota {
+        self.bucket
+            .lock()
+            .expect("lock poisoned")
+            .quota(self.rate, Instant::now())
+    }
 }
 
 impl<S> Layer<S> for RateLimitLayer {
@@ -45,9 +54,9 @@ impl<S> Layer<S> for RateLimitLayer {
         RateLimit {
             inner: service,
             rate: self.rate,
-            window_start: self.window_start.clone(),
-            previous_nb_requests: self.previous_nb_requests.clone(),
-            current_nb_requests: self.current_nb_requests.clone(),
+            bucket: self.bucket.clone(),
+            headers: self.headers,
+            permit: None,
         }
     }
 }
@@ -10,4 +10,6 @@ mod service;
 pub(crate) use self::error::RateLimited;
 pub(crate) use self::layer::RateLimitLayer;
 pub(crate) use self::rate::Rate;
+pub(crate) use self::service::Quota;
 pub(crate) use self::service::RateLimit;
+pub(crate) use self::service::TokenBucket;
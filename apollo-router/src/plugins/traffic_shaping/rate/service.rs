@@ -1,29 +1,105 @@
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::task::Context;
 use std::task::Poll;
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
+use std::time::Duration;
+use std::time::Instant;
 
 use futures::ready;
+use http::HeaderMap;
+use http::HeaderValue;
 use tower::Service;
 
 use super::future::ResponseFuture;
 use super::Rate;
 use crate::plugins::traffic_shaping::rate::error::RateLimited;
 
+/// A token bucket holding up to `rate.num()` tokens, refilled continuously
+/// at `rate.num()` tokens per `rate.per()`.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket, so bursts up to the capacity are accepted right away.
+    pub(crate) fn new(rate: Rate) -> Self {
+        TokenBucket {
+            tokens: rate.num() as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) -> f64 {
+        let tokens_per_sec = rate.num() as f64 / rate.per().as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * tokens_per_sec).min(rate.num() as f64);
+        self.last_refill = now;
+        tokens_per_sec
+    }
+
+    /// Take a token, or return how long to wait until one is available.
+    pub(crate) fn acquire(&mut self, rate: Rate, now: Instant) -> Result<(), Duration> {
+        let tokens_per_sec = self.refill(rate, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / tokens_per_sec,
+            ))
+        }
+    }
+
+    /// The state of the bucket, without taking a token.
+    pub(crate) fn quota(&mut self, rate: Rate, now: Instant) -> Quota {
+        let tokens_per_sec = self.refill(rate, now);
+        Quota {
+            limit: rate.num(),
+            remaining: self.tokens.floor() as u64,
+            reset: Duration::from_secs_f64((rate.num() as f64 - self.tokens) / tokens_per_sec),
+        }
+    }
+}
+
+/// The state of a rate limit, as sent to clients in the `RateLimit-Limit`, `RateLimit-Remaining`
+/// and `RateLimit-Reset` headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Quota {
+    /// Capacity of the bucket
+    pub(crate) limit: u64,
+    /// Number of requests accepted right away
+    pub(crate) remaining: u64,
+    /// Time until the bucket is full again
+    pub(crate) reset: Duration,
+}
+
+impl Quota {
+    pub(crate) fn insert_headers(&self, headers: &mut HeaderMap) {
+        // The reset is a number of seconds, rounded up
+        let reset = self.reset.as_secs() + u64::from(self.reset.subsec_nanos() > 0);
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(reset));
+    }
+}
+
+const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+const RATELIMIT_RESET: &str = "ratelimit-reset";
+
 #[derive(Debug)]
 pub(crate) struct RateLimit<T> {
     pub(crate) inner: T,
     pub(crate) rate: Rate,
-    /// We're using an atomic u64 because it's basically a timestamp in milliseconds for the start of the window
-    /// Instead of using an Instant which is not thread safe we're using an atomic u64
-    /// It's ok to have an u64 because we just care about milliseconds for this use case
-    pub(crate) window_start: Arc<AtomicU64>,
-    pub(crate) previous_nb_requests: Arc<AtomicUsize>,
-    pub(crate) current_nb_requests: Arc<AtomicUsize>,
+    /// Shared between all the services created by the same layer
+    pub(crate) bucket: Arc<Mutex<TokenBucket>>,
+    /// Whether rejections carry the `RateLimit-*` headers
+    pub(crate) headers: bool,
+    /// Whether a token was taken for the next call, or the rejection it gets
+    pub(crate) permit: Option<Result<(), RateLimited>>,
 }
 
 impl<S, Request> Service<Request> for RateLimit<S>
@@ -36,48 +112,104 @@ where
     type Future = ResponseFuture<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let time_unit = self.rate.per().as_millis() as u64;
-
-        let updated =
-            self.window_start
-                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |window_start| {
-                    let duration_now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("system time must be after EPOCH")
-                        .as_millis() as u64;
-                    if duration_now - window_start > self.rate.per().as_millis() as u64 {
-                        Some(duration_now)
-                    } else {
-                        None
-                    }
-                });
-        // If it has been updated
-        if let Ok(_updated_window_start) = updated {
-            self.previous_nb_requests.swap(
-                self.current_nb_requests.load(Ordering::SeqCst),
-                Ordering::SeqCst,
-            );
-            self.current_nb_requests.swap(1, Ordering::SeqCst);
+        // The token is only taken once the inner service is ready, so that requests waiting for
+        // it don't use up the rate limit
+        if let Err(error) = ready!(self.inner.poll_ready(cx)) {
+            return Poll::Ready(Err(error.into()));
+        }
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
         }
 
-        let estimated_cap = (self.previous_nb_requests.load(Ordering::SeqCst)
-            * (time_unit
-                .checked_sub(self.window_start.load(Ordering::SeqCst))
-                .unwrap_or_default()
-                / time_unit) as usize)
-            + self.current_nb_requests.load(Ordering::SeqCst);
+        // Rejections are returned by the call rather than here: a buffer in front of the service
+        // would fail all the following requests after an error of `poll_ready`
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock().expect("lock poisoned");
+        self.permit = Some(bucket.acquire(self.rate, now).map_err(|retry_after| {
+            tracing::trace!("rate limit exceeded; retry after {:?}", retry_after);
+            let rate_limited = RateLimited::new(retry_after);
+            if self.headers {
+                rate_limited.with_quota(bucket.quota(self.rate, now))
+            } else {
+                rate_limited
+            }
+        }));
+        Poll::Ready(Ok(()))
+    }
 
-        if estimated_cap as u64 > self.rate.num() {
-            tracing::trace!("rate limit exceeded; sleeping.");
-            return Poll::Ready(Err(RateLimited::new().into()));
+    fn call(&mut self, request: Request) -> Self::Future {
+        match self.permit.take() {
+            Some(Ok(())) => ResponseFuture::called(self.inner.call(request)),
+            Some(Err(rate_limited)) => ResponseFuture::rejected(rate_limited),
+            None => panic!("service not ready; poll_ready must be called first"),
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use tower::Layer;
+    use tower_test::assert_request_eq;
+    use tower_test::mock;
+
+    use super::*;
+    use crate::plugins::traffic_shaping::rate::RateLimitLayer;
 
-        self.current_nb_requests.fetch_add(1, Ordering::SeqCst);
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let rate = Rate::new(NonZeroU64::new(2).unwrap(), Duration::from_secs(1));
+        let start = Instant::now();
+        let mut bucket = TokenBucket {
+            tokens: 2.0,
+            last_refill: start,
+        };
 
-        Poll::Ready(ready!(self.inner.poll_ready(cx)).map_err(Into::into))
+        assert!(bucket.acquire(rate, start).is_ok());
+        assert!(bucket.acquire(rate, start).is_ok());
+        assert_eq!(bucket.acquire(rate, start), Err(Duration::from_millis(500)));
+        assert!(bucket
+            .acquire(rate, start + Duration::from_millis(500))
+            .is_ok());
+        // The bucket never holds more tokens than its capacity
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.acquire(rate, later).is_ok());
+        assert!(bucket.acquire(rate, later).is_ok());
+        assert!(bucket.acquire(rate, later).is_err());
+        assert_eq!(
+            bucket.quota(rate, later + Duration::from_millis(250)),
+            Quota {
+                limit: 2,
+                remaining: 0,
+                reset: Duration::from_millis(750),
+            }
+        );
     }
 
-    fn call(&mut self, request: Request) -> Self::Future {
-        ResponseFuture::new(self.inner.call(request))
+    #[tokio::test]
+    async fn tokens_are_taken_once_the_inner_service_is_ready() {
+        let layer = RateLimitLayer::new(NonZeroU64::new(1).unwrap(), Duration::from_secs(60));
+        let (mut service, mut handle) =
+            mock::spawn_with(|mock: mock::Mock<(), ()>| layer.layer(mock));
+
+        handle.allow(0);
+        assert!(service.poll_ready().is_pending());
+        assert!(service.poll_ready().is_pending());
+        assert_eq!(layer.quota().remaining, 1);
+
+        handle.allow(1);
+        assert!(service.poll_ready().is_ready());
+        // Polling again doesn't take another token
+        assert!(matches!(service.poll_ready(), Poll::Ready(Ok(()))));
+        let response = service.call(());
+        assert_request_eq!(handle, ()).send_response(());
+        response.await.unwrap();
+
+        // Requests over the limit are rejected without reaching the inner service
+        handle.allow(1);
+        assert!(matches!(service.poll_ready(), Poll::Ready(Ok(()))));
+        let error = service.call(()).await.unwrap_err();
+        assert!(error.is::<RateLimited>());
     }
 }
@@ -0,0 +1,116 @@
+//! Retry failed subgraph queries, within a retry budget.
+//!
+//! See [`tower::retry::budget::Budget`] for how the budget is computed. Failures are retried
+//! according to their classification, see [`super::classification`].
+
+use std::future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tower::retry::budget::Budget;
+use tower::retry::Policy;
+use tower::BoxError;
+
+use super::classification::Classifier;
+use super::RateLimited;
+use crate::error::FetchError;
+use crate::http_ext;
+use crate::query_planner::fetch::OperationKind;
+use crate::services::subgraph::ErrorClass;
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+#[derive(Clone)]
+pub(crate) struct RetryPolicy {
+    budget: Arc<Budget>,
+    classifier: Classifier,
+    retried: bool,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(
+        ttl: Duration,
+        min_per_sec: u32,
+        retry_percent: f32,
+        classifier: Classifier,
+    ) -> Self {
+        Self {
+            budget: Arc::new(Budget::new(ttl, min_per_sec, retry_percent)),
+            classifier,
+            retried: false,
+        }
+    }
+}
+
+impl Policy<SubgraphRequest, SubgraphResponse, BoxError> for RetryPolicy {
+    type Future = future::Ready<Self>;
+
+    fn retry(
+        &self,
+        req: &SubgraphRequest,
+        result: Result<&SubgraphResponse, &BoxError>,
+    ) -> Option<Self::Future> {
+        // Only the first attempt of a request adds to the budget
+        if !self.retried {
+            self.budget.deposit();
+        }
+
+        if req.operation_kind != OperationKind::Query {
+            return None;
+        }
+        // A request rejected by the rate limit was not sent, retrying it would be rejected too
+        if let Err(e) = result {
+            if e.is::<RateLimited>()
+                || e.source()
+                    .map_or(false, |source| source.is::<RateLimited>())
+            {
+                return None;
+            }
+        }
+        let retryable = match self.classifier.classify(result) {
+            Some(class) => class == ErrorClass::Retryable,
+            None => match result {
+                Ok(_) => false,
+                // The subgraph asked to be called later, retrying now would only add to its load
+                Err(e) => FetchError::find_retry_after(e).is_none(),
+            },
+        };
+        if !retryable {
+            return None;
+        }
+
+        match self.budget.withdraw() {
+            Ok(()) => {
+                match result {
+                    Ok(_) => {
+                        tracing::debug!("retrying subgraph request after a retryable response")
+                    }
+                    Err(e) => tracing::debug!("retrying subgraph request after error: {}", e),
+                }
+                Some(future::ready(Self {
+                    budget: self.budget.clone(),
+                    classifier: self.classifier.clone(),
+                    retried: true,
+                }))
+            }
+            Err(_) => {
+                tracing::debug!("retry budget exhausted, not retrying subgraph request");
+                None
+            }
+        }
+    }
+
+    fn clone_request(&self, req: &SubgraphRequest) -> Option<SubgraphRequest> {
+        // Mutations are never retried, so there is no need to keep a copy
+        if req.operation_kind != OperationKind::Query {
+            return None;
+        }
+
+        Some(SubgraphRequest {
+            originating_request: req.originating_request.clone(),
+            subgraph_request: http_ext::clone_http_request(&req.subgraph_request),
+            operation_kind: req.operation_kind,
+            context: req.context.clone(),
+        })
+    }
+}
@@ -0,0 +1,218 @@
+//! Live statistics of the requests to a subgraph. Implemented as a tower Layer.
+//!
+//! The requests actually sent to the subgraph, retries included, are recorded over a sliding
+//! window of time, so that the plugin endpoint serves their in-flight count, latency percentiles
+//! and error rate without a metrics stack.
+
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Serialize;
+use tower::BoxError;
+use tower::Layer;
+
+use crate::SubgraphRequest;
+use crate::SubgraphResponse;
+
+/// Number of requests kept per subgraph, the oldest ones are dropped first.
+const MAX_SAMPLES: usize = 10_000;
+
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    failed: bool,
+}
+
+/// Shared by all the services of a subgraph.
+#[derive(Clone)]
+pub(crate) struct StatsLayer {
+    window: Duration,
+    in_flight: Arc<AtomicU64>,
+    samples: Arc<Mutex<VecDeque<Sample>>>,
+}
+
+/// What the endpoint returns for each subgraph.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct SubgraphStats {
+    /// Requests sent to the subgraph and not answered yet
+    pub(crate) in_flight: u64,
+    /// Requests answered during the window
+    pub(crate) requests: usize,
+    /// Fraction of the requests of the window that failed
+    pub(crate) error_rate: f64,
+    pub(crate) latency_p50_ms: Option<f64>,
+    pub(crate) latency_p99_ms: Option<f64>,
+    /// `open` while the subgraph is held off or its circuit breaker is open, `half_open` while
+    /// the circuit breaker probes the subgraph, `closed` otherwise
+    pub(crate) circuit: &'static str,
+    pub(crate) circuit_remaining_ms: Option<u64>,
+    /// Current limit of the adaptive concurrency, if enabled
+    pub(crate) concurrency_limit: Option<u64>,
+}
+
+impl StatsLayer {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            in_flight: Default::default(),
+            samples: Default::default(),
+        }
+    }
+
+    fn record(&self, latency: Duration, failed: bool) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(Sample {
+            at: Instant::now(),
+            latency,
+            failed,
+        });
+    }
+
+    /// Statistics of the requests of the window, the circuit is closed and there is no
+    /// concurrency limit.
+    pub(crate) fn stats(&self) -> SubgraphStats {
+        let mut samples = self.samples.lock().unwrap();
+        while let Some(sample) = samples.front() {
+            if sample.at.elapsed() <= self.window {
+                break;
+            }
+            samples.pop_front();
+        }
+        let mut latencies: Vec<Duration> = samples.iter().map(|sample| sample.latency).collect();
+        latencies.sort_unstable();
+        let failed = samples.iter().filter(|sample| sample.failed).count();
+        SubgraphStats {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            requests: samples.len(),
+            error_rate: if samples.is_empty() {
+                0.0
+            } else {
+                failed as f64 / samples.len() as f64
+            },
+            latency_p50_ms: percentile(&latencies, 0.5),
+            latency_p99_ms: percentile(&latencies, 0.99),
+            circuit: "closed",
+            circuit_remaining_ms: None,
+            concurrency_limit: None,
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted latencies, in milliseconds.
+fn percentile(latencies: &[Duration], p: f64) -> Option<f64> {
+    if latencies.is_empty() {
+        return None;
+    }
+    let rank = ((p * latencies.len() as f64).ceil() as usize).clamp(1, latencies.len());
+    Some(latencies[rank - 1].as_micros() as f64 / 1000.0)
+}
+
+impl<S> Layer<S> for StatsLayer {
+    type Service = StatsService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        StatsService {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+pub(crate) struct StatsService<S> {
+    service: S,
+    layer: StatsLayer,
+}
+
+/// Decrements the in-flight requests when the request completes, or is dropped.
+struct InFlight(Arc<AtomicU64>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S> tower::Service<SubgraphRequest> for StatsService<S>
+where
+    S: tower::Service<SubgraphRequest, Response = SubgraphResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: SubgraphRequest) -> Self::Future {
+        let layer = self.layer.clone();
+        layer.in_flight.fetch_add(1, Ordering::Relaxed);
+        let in_flight = InFlight(layer.in_flight.clone());
+        let started = Instant::now();
+        self.service
+            .call(request)
+            .map(move |result| {
+                drop(in_flight);
+                let failed = match &result {
+                    Ok(response) => response.response.status().is_server_error(),
+                    Err(_) => true,
+                };
+                layer.record(started.elapsed(), failed);
+                result
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_are_recorded() {
+        let layer = StatsLayer::new(Duration::from_secs(60));
+        for status in [200, 200, 200, 503] {
+            let service = layer.layer(tower::service_fn(move |_req: SubgraphRequest| async move {
+                Ok::<_, BoxError>(
+                    SubgraphResponse::fake_builder()
+                        .status_code(http::StatusCode::from_u16(status).unwrap())
+                        .build(),
+                )
+            }));
+            service
+                .oneshot(SubgraphRequest::fake_builder().build())
+                .await
+                .unwrap();
+        }
+
+        let stats = layer.stats();
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.requests, 4);
+        assert_eq!(stats.error_rate, 0.25);
+        assert!(stats.latency_p50_ms.is_some());
+        assert!(stats.latency_p99_ms >= stats.latency_p50_ms);
+        assert_eq!(stats.circuit, "closed");
+    }
+
+    #[test]
+    fn percentiles_are_nearest_ranks() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 0.5), Some(50.0));
+        assert_eq!(percentile(&latencies, 0.99), Some(99.0));
+        assert_eq!(percentile(&[], 0.5), None);
+    }
+}
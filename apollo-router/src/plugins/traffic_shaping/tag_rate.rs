@@ -0,0 +1,195 @@
+//! Rate limits of the subgraph requests of tagged operations.
+//!
+//! Operations tagged by the `experimental.operation_tags` plugin get a rate limit per tag value,
+//! so that low priority operations, for example, can't use up the capacity of a subgraph. Each
+//! value has its own token bucket, shared by all the requests to the subgraph. Requests of
+//! operations without the tag are not limited.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context as TaskContext;
+use std::task::Poll;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use super::rate::Rate;
+use super::rate::RateLimited;
+use super::rate::TokenBucket;
+use super::RateLimitConf;
+use crate::plugins::operation_tags;
+use crate::services::subgraph;
+use crate::Context;
+
+/// The bucket of a tag value.
+#[derive(Debug)]
+struct TagLimit {
+    tag: String,
+    value: String,
+    rate: Rate,
+    bucket: Mutex<TokenBucket>,
+}
+
+/// Limits the rate of the requests of tagged operations, the services created by a layer share
+/// the same buckets.
+#[derive(Debug, Clone)]
+pub(crate) struct TagRateLimitLayer {
+    limits: Arc<Vec<TagLimit>>,
+}
+
+impl TagRateLimitLayer {
+    pub(super) fn new(conf: &HashMap<String, HashMap<String, RateLimitConf>>) -> Self {
+        let limits = conf
+            .iter()
+            .flat_map(|(tag, values)| {
+                values.iter().map(move |(value, limit)| {
+                    let rate = Rate::new(limit.capacity, limit.interval);
+                    TagLimit {
+                        tag: tag.clone(),
+                        value: value.clone(),
+                        rate,
+                        bucket: Mutex::new(TokenBucket::new(rate)),
+                    }
+                })
+            })
+            .collect();
+        TagRateLimitLayer {
+            limits: Arc::new(limits),
+        }
+    }
+}
+
+impl<S> Layer<S> for TagRateLimitLayer {
+    type Service = TagRateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TagRateLimit {
+            inner,
+            limits: self.limits.clone(),
+        }
+    }
+}
+
+pub(crate) struct TagRateLimit<S> {
+    inner: S,
+    limits: Arc<Vec<TagLimit>>,
+}
+
+impl<S> TagRateLimit<S> {
+    /// Takes a token from the bucket of each tag of the operation.
+    fn acquire(&self, context: &Context) -> Result<(), RateLimited> {
+        let now = Instant::now();
+        for limit in self.limits.iter() {
+            if operation_tags::tag(context, &limit.tag).as_deref() != Some(limit.value.as_str()) {
+                continue;
+            }
+            let mut bucket = limit.bucket.lock().expect("lock poisoned");
+            if let Err(retry_after) = bucket.acquire(limit.rate, now) {
+                tracing::trace!(
+                    "rate limit of operations tagged {}={} exceeded; retry after {:?}",
+                    limit.tag,
+                    limit.value,
+                    retry_after
+                );
+                return Err(RateLimited::new(retry_after));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S> Service<subgraph::Request> for TagRateLimit<S>
+where
+    S: Service<subgraph::Request, Response = subgraph::Response, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = subgraph::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: subgraph::Request) -> Self::Future {
+        match self.acquire(&req.context) {
+            Ok(()) => self.inner.call(req).boxed(),
+            Err(rate_limited) => {
+                futures::future::ready(Err::<_, BoxError>(rate_limited.into())).boxed()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+    use std::time::Duration;
+
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+
+    #[tokio::test]
+    async fn tagged_operations_are_rate_limited() {
+        let layer = TagRateLimitLayer::new(&HashMap::from([(
+            "priority".to_string(),
+            HashMap::from([(
+                "low".to_string(),
+                RateLimitConf {
+                    capacity: NonZeroU64::new(1).unwrap(),
+                    interval: Duration::from_secs(60),
+                },
+            )]),
+        )]));
+        let mut mock = MockSubgraphService::new();
+        mock.expect_call()
+            .times(3)
+            .returning(|_| Ok(subgraph::Response::fake_builder().build()));
+        let mut service = layer.layer(mock);
+
+        let tagged = |value: &str| {
+            let context = Context::new();
+            context
+                .insert(operation_tags::context_key("priority"), value)
+                .unwrap();
+            subgraph::Request::fake_builder().context(context).build()
+        };
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(tagged("low"))
+            .await
+            .unwrap();
+        let error = service
+            .ready()
+            .await
+            .unwrap()
+            .call(tagged("low"))
+            .await
+            .unwrap_err();
+        assert!(error.is::<RateLimited>());
+        // other values and untagged operations are not limited
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(tagged("high"))
+            .await
+            .unwrap();
+        service
+            .ready()
+            .await
+            .unwrap()
+            .call(subgraph::Request::fake_builder().build())
+            .await
+            .unwrap();
+    }
+}
@@ -14,9 +14,12 @@ use tower::BoxError;
 use tower::Service;
 use tracing::Instrument;
 
+use super::subgraph_acl::check_subgraph_acl;
 use super::PlanNode;
 use super::QueryKey;
 use super::QueryPlanOptions;
+use crate::allocator;
+use crate::allocator::Stage;
 use crate::error::QueryPlannerError;
 use crate::introspection::Introspection;
 use crate::plugins::traffic_shaping::TrafficShaping;
@@ -68,9 +71,12 @@ impl BridgeQueryPlanner {
     async fn parse_selections(&self, query: String) -> Result<Query, QueryPlannerError> {
         let schema = self.schema.clone();
         let configuration = self.configuration.clone();
-        let query_parsing_future =
-            tokio::task::spawn_blocking(move || Query::parse(query, &schema, &configuration))
-                .instrument(tracing::info_span!("parse_query", "otel.kind" = %SpanKind::Internal));
+        let query_parsing_future = tokio::task::spawn_blocking(move || {
+            allocator::in_stage(Stage::Parse, || {
+                Query::parse(query, &schema, &configuration)
+            })
+        })
+        .instrument(tracing::info_span!("parse_query", "otel.kind" = %SpanKind::Internal));
         match query_parsing_future.await {
             Ok(res) => res.map_err(QueryPlannerError::from),
             Err(err) => {
@@ -102,13 +108,12 @@ impl BridgeQueryPlanner {
         operation: Option<String>,
         mut selections: Query,
     ) -> Result<QueryPlannerContent, QueryPlannerError> {
-        let planner_result = self
-            .planner
-            .plan(query, operation)
-            .await
-            .map_err(QueryPlannerError::RouterBridgeError)?
-            .into_result()
-            .map_err(QueryPlannerError::from)?;
+        let planner_result =
+            allocator::with_stage(Stage::Plan, self.planner.plan(query, operation))
+                .await
+                .map_err(QueryPlannerError::RouterBridgeError)?
+                .into_result()
+                .map_err(QueryPlannerError::from)?;
 
         match planner_result {
             PlanSuccess {
@@ -119,6 +124,11 @@ impl BridgeQueryPlanner {
                     },
                 usage_reporting,
             } => {
+                check_subgraph_acl(
+                    &node,
+                    &self.schema,
+                    &self.configuration.experimental_subgraph_acl,
+                )?;
                 let subselections = node.parse_subselections(&*self.schema);
                 selections.subselections = subselections;
                 Ok(QueryPlannerContent::Plan {
@@ -1,22 +1,32 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::task;
+use std::time::Duration;
 
 use futures::future::BoxFuture;
+use lru::LruCache;
 use router_bridge::planner::UsageReporting;
 use serde::Serialize;
 use serde_json_bytes::value::Serializer;
 use tower::BoxError;
 use tower::ServiceExt;
+use tracing::Instrument;
 
 use super::QueryKey;
 use super::USAGE_REPORTING;
 use crate::cache::DeduplicatingCache;
+use crate::configuration::PlanningTimeoutPolicy;
+use crate::configuration::QueryPlanning;
 use crate::error::CacheResolverError;
 use crate::error::QueryPlannerError;
 use crate::services::QueryPlannerContent;
 use crate::*;
 
+/// Outcome of a query planning timeout, `rejected` or `stale_plan`, counted by the telemetry
+/// plugin.
+pub(crate) const PLANNING_TIMEOUT_CONTEXT_KEY: &str = "apollo_query_planner::timeout";
+
 /// A query planner wrapper that caches results.
 ///
 /// The query planner performs LRU caching.
@@ -24,6 +34,34 @@ use crate::*;
 pub(crate) struct CachingQueryPlanner<T: Clone> {
     cache: Arc<DeduplicatingCache<QueryKey, Result<QueryPlannerContent, Arc<BoxError>>>>,
     delegate: T,
+    timeout: Option<Duration>,
+    stale_plans: Option<StalePlans>,
+}
+
+/// The last plan computed for each operation hash, executed when the planning of an operation
+/// times out. They are kept across schema reloads.
+#[derive(Clone)]
+pub(crate) struct StalePlans(Arc<Mutex<LruCache<String, QueryPlannerContent>>>);
+
+impl StalePlans {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+
+    fn get(&self, operation_hash: &str) -> Option<QueryPlannerContent> {
+        self.0
+            .lock()
+            .expect("lock poisoned")
+            .get(operation_hash)
+            .cloned()
+    }
+
+    fn insert(&self, operation_hash: String, content: QueryPlannerContent) {
+        self.0
+            .lock()
+            .expect("lock poisoned")
+            .put(operation_hash, content);
+    }
 }
 
 impl<T: Clone + 'static> CachingQueryPlanner<T>
@@ -33,7 +71,21 @@ where
     /// Creates a new query planner that caches the results of another [`QueryPlanner`].
     pub(crate) async fn new(delegate: T, plan_cache_limit: usize) -> CachingQueryPlanner<T> {
         let cache = Arc::new(DeduplicatingCache::with_capacity(plan_cache_limit).await);
-        Self { cache, delegate }
+        Self {
+            cache,
+            delegate,
+            timeout: None,
+            stale_plans: None,
+        }
+    }
+
+    /// Bounds the time spent waiting for plans, falling back to the stale plans if configured.
+    pub(crate) fn with_timeout(mut self, config: &QueryPlanning, stale_plans: StalePlans) -> Self {
+        self.timeout = config.timeout;
+        if config.on_timeout == PlanningTimeoutPolicy::StalePlan {
+            self.stale_plans = Some(stale_plans);
+        }
+        self
     }
 }
 
@@ -51,128 +103,123 @@ where
     }
 
     fn call(&mut self, request: QueryPlannerRequest) -> Self::Future {
-        let mut qp = self.clone();
+        let qp = self.clone();
         Box::pin(async move {
             let key = (request.query.clone(), request.operation_name.to_owned());
             let context = request.context.clone();
             let entry = qp.cache.get(&key).await;
-            if entry.is_first() {
-                let res = qp.delegate.ready().await?.call(request).await;
-                match res {
-                    Ok(QueryPlannerResponse { content, context }) => {
-                        entry.insert(Ok(content.clone())).await;
-
-                        if let QueryPlannerContent::Plan { plan, .. } = &content {
-                            match (&plan.usage_reporting).serialize(Serializer) {
-                                Ok(v) => {
-                                    context.insert_json_value(USAGE_REPORTING, v);
-                                }
-                                Err(e) => {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        e
-                                    );
-                                }
-                            }
+            let res = if entry.is_first() {
+                let delegate = qp.delegate.clone();
+                let planning = async move {
+                    let res = delegate
+                        .oneshot(request)
+                        .await
+                        .map(|response| response.content)
+                        .map_err(Arc::new);
+                    entry.insert(res.clone()).await;
+                    res
+                };
+                match qp.timeout {
+                    // The planning task goes on after a timeout, to cache the plan
+                    Some(timeout) => {
+                        let planning = tokio::spawn(planning.in_current_span());
+                        match tokio::time::timeout(timeout, planning).await {
+                            Ok(res) => Some(res.map_err(QueryPlannerError::from)?),
+                            Err(_) => None,
                         }
-                        Ok(QueryPlannerResponse { content, context })
                     }
-                    Err(error) => {
-                        match error.downcast_ref::<QueryPlannerError>() {
-                            Some(QueryPlannerError::PlanningErrors(pe)) => {
-                                if let Err(inner_e) =
-                                    context.insert(USAGE_REPORTING, pe.usage_reporting.clone())
-                                {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
-                            }
-                            Some(QueryPlannerError::SpecError(e)) => {
-                                let error_key = match e {
-                                    SpecError::ParsingError(_) => "## GraphQLParseFailure\n",
-                                    _ => "## GraphQLValidationFailure\n",
-                                };
-                                if let Err(inner_e) = context.insert(
-                                    USAGE_REPORTING,
-                                    UsageReporting {
-                                        stats_report_key: error_key.to_string(),
-                                        referenced_fields_by_type: HashMap::new(),
-                                    },
-                                ) {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
-                            }
-                            _ => {}
-                        }
+                    None => Some(planning.await),
+                }
+            } else {
+                let waiting = entry.get();
+                let res = match qp.timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, waiting).await.ok(),
+                    None => Some(waiting.await),
+                };
+                match res {
+                    Some(res) => Some(res.map_err(|_| QueryPlannerError::UnhandledPlannerResult)?),
+                    None => None,
+                }
+            };
 
-                        let e = Arc::new(error);
-                        entry.insert(Err(e.clone())).await;
-                        Err(CacheResolverError::RetrievalError(e).into())
+            let res = match res {
+                Some(res) => res,
+                None => {
+                    let timeout = qp.timeout.unwrap_or_default();
+                    let stale_plan = qp.stale_plans.as_ref().and_then(|stale_plans| {
+                        stale_plans.get(&Query::hash(&key.0, key.1.as_deref()))
+                    });
+                    match stale_plan {
+                        Some(content) => {
+                            tracing::warn!(
+                                "query planning timed out after {:?}, executing a stale plan",
+                                timeout
+                            );
+                            let _ = context.insert(PLANNING_TIMEOUT_CONTEXT_KEY, "stale_plan");
+                            Ok(content)
+                        }
+                        None => {
+                            let _ = context.insert(PLANNING_TIMEOUT_CONTEXT_KEY, "rejected");
+                            return Err(QueryPlannerError::Timeout(timeout).into());
+                        }
                     }
                 }
-            } else {
-                let res = entry
-                    .get()
-                    .await
-                    .map_err(|_| QueryPlannerError::UnhandledPlannerResult)?;
+            };
 
-                match res {
-                    Ok(content) => {
-                        if let QueryPlannerContent::Plan { plan, .. } = &content {
-                            match (&plan.usage_reporting).serialize(Serializer) {
-                                Ok(v) => {
-                                    context.insert_json_value(USAGE_REPORTING, v);
-                                }
-                                Err(e) => {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        e
-                                    );
-                                }
+            match res {
+                Ok(content) => {
+                    if let QueryPlannerContent::Plan { plan, .. } = &content {
+                        match (&plan.usage_reporting).serialize(Serializer) {
+                            Ok(v) => {
+                                context.insert_json_value(USAGE_REPORTING, v);
+                            }
+                            Err(e) => {
+                                tracing::error!(
+                                    "usage reporting was not serializable to context, {}",
+                                    e
+                                );
                             }
                         }
-
-                        Ok(QueryPlannerResponse { content, context })
+                        if let Some(stale_plans) = &qp.stale_plans {
+                            stale_plans
+                                .insert(Query::hash(&key.0, key.1.as_deref()), content.clone());
+                        }
                     }
-                    Err(error) => {
-                        if let Some(error) = error.downcast_ref::<QueryPlannerError>() {
-                            if let QueryPlannerError::PlanningErrors(pe) = &error {
-                                if let Err(inner_e) = request
-                                    .context
-                                    .insert(USAGE_REPORTING, pe.usage_reporting.clone())
-                                {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
-                            } else if let QueryPlannerError::SpecError(e) = &error {
-                                let error_key = match e {
-                                    SpecError::ParsingError(_) => "## GraphQLParseFailure\n",
-                                    _ => "## GraphQLValidationFailure\n",
-                                };
-                                if let Err(inner_e) = request.context.insert(
-                                    USAGE_REPORTING,
-                                    UsageReporting {
-                                        stats_report_key: error_key.to_string(),
-                                        referenced_fields_by_type: HashMap::new(),
-                                    },
-                                ) {
-                                    tracing::error!(
-                                        "usage reporting was not serializable to context, {}",
-                                        inner_e
-                                    );
-                                }
+
+                    Ok(QueryPlannerResponse { content, context })
+                }
+                Err(error) => {
+                    if let Some(error) = error.downcast_ref::<QueryPlannerError>() {
+                        if let QueryPlannerError::PlanningErrors(pe) = &error {
+                            if let Err(inner_e) =
+                                context.insert(USAGE_REPORTING, pe.usage_reporting.clone())
+                            {
+                                tracing::error!(
+                                    "usage reporting was not serializable to context, {}",
+                                    inner_e
+                                );
+                            }
+                        } else if let QueryPlannerError::SpecError(e) = &error {
+                            let error_key = match e {
+                                SpecError::ParsingError(_) => "## GraphQLParseFailure\n",
+                                _ => "## GraphQLValidationFailure\n",
+                            };
+                            if let Err(inner_e) = context.insert(
+                                USAGE_REPORTING,
+                                UsageReporting {
+                                    stats_report_key: error_key.to_string(),
+                                    referenced_fields_by_type: HashMap::new(),
+                                },
+                            ) {
+                                tracing::error!(
+                                    "usage reporting was not serializable to context, {}",
+                                    inner_e
+                                );
                             }
                         }
-
-                        Err(CacheResolverError::RetrievalError(error).into())
                     }
+
+                    Err(CacheResolverError::RetrievalError(error).into())
                 }
             }
         })
@@ -318,4 +365,99 @@ mod tests {
                 .is_some());
         }
     }
+
+    /// A planner taking `delay` to plan any query.
+    #[derive(Clone)]
+    struct SlowPlanner {
+        delay: Duration,
+    }
+
+    impl Service<QueryPlannerRequest> for SlowPlanner {
+        type Response = QueryPlannerResponse;
+
+        type Error = BoxError;
+
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(
+            &mut self,
+            _cx: &mut task::Context<'_>,
+        ) -> task::Poll<Result<(), Self::Error>> {
+            task::Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: QueryPlannerRequest) -> Self::Future {
+            let delay = self.delay;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                let query_plan = QueryPlan {
+                    formatted_query_plan: String::new(),
+                    root: serde_json::from_str(test_query_plan!()).unwrap(),
+                    options: QueryPlanOptions::default(),
+                    usage_reporting: UsageReporting {
+                        stats_report_key: "this is a test report key".to_string(),
+                        referenced_fields_by_type: Default::default(),
+                    },
+                };
+                Ok(QueryPlannerResponse::builder()
+                    .content(QueryPlannerContent::Plan {
+                        query: Arc::new(Query::default()),
+                        plan: Arc::new(query_plan),
+                    })
+                    .context(req.context)
+                    .build())
+            })
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_planning_timeout() {
+        let config = QueryPlanning {
+            timeout: Some(Duration::from_millis(50)),
+            on_timeout: PlanningTimeoutPolicy::StalePlan,
+        };
+        let stale_plans = StalePlans::new(10);
+        let request =
+            |query: &str| QueryPlannerRequest::new(query.into(), Some("Me".into()), Context::new());
+
+        let mut planner = CachingQueryPlanner::new(
+            SlowPlanner {
+                delay: Duration::ZERO,
+            },
+            10,
+        )
+        .await
+        .with_timeout(&config, stale_plans.clone());
+        planner.call(request("{ me }")).await.unwrap();
+
+        // A new schema, with a planner too slow for the timeout
+        let mut planner = CachingQueryPlanner::new(
+            SlowPlanner {
+                delay: Duration::from_secs(10),
+            },
+            10,
+        )
+        .await
+        .with_timeout(&config, stale_plans);
+
+        // Formatting doesn't change the operation hash
+        let response = planner.call(request("{\n  me\n}")).await.unwrap();
+        assert!(matches!(response.content, QueryPlannerContent::Plan { .. }));
+        assert_eq!(
+            response
+                .context
+                .get::<_, String>(PLANNING_TIMEOUT_CONTEXT_KEY)
+                .unwrap()
+                .as_deref(),
+            Some("stale_plan")
+        );
+
+        match planner.call(request("{ you }")).await {
+            Err(error) => assert!(matches!(
+                error.downcast_ref::<QueryPlannerError>(),
+                Some(QueryPlannerError::Timeout(_))
+            )),
+            Ok(_) => panic!("planning should time out"),
+        }
+    }
 }
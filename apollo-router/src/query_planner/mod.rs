@@ -2,6 +2,7 @@
 
 #![allow(missing_docs)] // FIXME
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt::Write;
 use std::sync::Arc;
@@ -31,6 +32,7 @@ use crate::*;
 mod bridge_query_planner;
 mod caching_query_planner;
 mod selection;
+mod subgraph_acl;
 
 /// Query planning options.
 #[derive(Clone, Eq, Hash, PartialEq, Debug, Default)]
@@ -262,6 +264,19 @@ impl PlanNode {
 }
 
 impl QueryPlan {
+    /// A plan built by the router rather than the query planner, without usage reporting.
+    pub(crate) fn from_root(root: PlanNode) -> Self {
+        Self {
+            usage_reporting: UsageReporting {
+                stats_report_key: String::new(),
+                referenced_fields_by_type: Default::default(),
+            },
+            root,
+            formatted_query_plan: String::new(),
+            options: QueryPlanOptions::default(),
+        }
+    }
+
     /// Execute the plan and return a [`Response`].
     pub(crate) async fn execute<'a, SF>(
         &self,
@@ -305,6 +320,25 @@ impl QueryPlan {
     pub fn contains_mutations(&self) -> bool {
         self.root.contains_mutations()
     }
+
+    /// Number of fetches in the plan, counting both branches of conditions.
+    pub(crate) fn fetch_count(&self) -> usize {
+        self.root.service_usage().count()
+    }
+
+    /// Length of the longest chain of fetches executed one after the other.
+    pub(crate) fn sequential_depth(&self) -> usize {
+        self.root.sequential_depth()
+    }
+
+    /// Number of fetches in the plan for each subgraph, counting both branches of conditions.
+    pub(crate) fn subgraph_fetches(&self) -> BTreeMap<&str, usize> {
+        let mut fetches = BTreeMap::new();
+        for service in self.root.service_usage() {
+            *fetches.entry(service).or_default() += 1;
+        }
+        fetches
+    }
 }
 
 // holds the query plan executon arguments that do not change between calls
@@ -702,6 +736,44 @@ impl PlanNode {
             },
         }
     }
+
+    /// Length of the longest chain of fetches waiting for each other. Deferred nodes wait for
+    /// the primary node.
+    fn sequential_depth(&self) -> usize {
+        match self {
+            Self::Sequence { nodes } => nodes.iter().map(|node| node.sequential_depth()).sum(),
+            Self::Parallel { nodes } => nodes
+                .iter()
+                .map(|node| node.sequential_depth())
+                .max()
+                .unwrap_or_default(),
+            Self::Fetch(_) => 1,
+            Self::Flatten(flatten) => flatten.node.sequential_depth(),
+            Self::Defer { primary, deferred } => {
+                primary
+                    .node
+                    .as_ref()
+                    .map(|node| node.sequential_depth())
+                    .unwrap_or_default()
+                    + deferred
+                        .iter()
+                        .filter_map(|deferred| deferred.node.as_ref())
+                        .map(|node| node.sequential_depth())
+                        .max()
+                        .unwrap_or_default()
+            }
+            Self::Condition {
+                if_clause,
+                else_clause,
+                ..
+            } => if_clause
+                .iter()
+                .chain(else_clause.iter())
+                .map(|node| node.sequential_depth())
+                .max()
+                .unwrap_or_default(),
+        }
+    }
 }
 
 fn reconstruct_full_query(path: &Path, subselection: &str) -> String {
@@ -746,9 +818,15 @@ pub(crate) mod fetch {
     use crate::json_ext::Path;
     use crate::json_ext::Value;
     use crate::json_ext::ValueExt;
+    use crate::plugins::traffic_shaping::Elapsed;
+    use crate::plugins::traffic_shaping::RateLimited;
     use crate::services::subgraph_service::SubgraphServiceFactory;
     use crate::*;
 
+    /// Context key of the longest delay, in seconds, that a subgraph asked to wait before sending
+    /// it requests again. It is forwarded to the client in the `Retry-After` header.
+    pub(crate) const RETRY_AFTER_CONTEXT_KEY: &str = "apollo_retry_after::seconds";
+
     /// GraphQL operation type.
     #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
     #[serde(rename_all = "camelCase")]
@@ -988,9 +1066,42 @@ pub(crate) mod fetch {
                 // when errors have been redacted in the include_subgraph_errors module.
                 // Unfortunately, not easy to fix here, because at this point we don't
                 // know if we should be redacting errors for this subgraph...
-                .map_err(|e| FetchError::SubrequestHttpError {
-                    service: service_name.to_string(),
-                    reason: e.to_string(),
+                .map_err(|e| {
+                    // the timeout error may be wrapped by a buffer layer
+                    if e.is::<Elapsed>()
+                        || e.source()
+                            .map(|source| source.is::<Elapsed>())
+                            .unwrap_or_default()
+                    {
+                        FetchError::SubrequestTimeout {
+                            service: service_name.to_string(),
+                        }
+                    } else if let Some(rate_limited) = e
+                        .downcast_ref::<RateLimited>()
+                        .or_else(|| e.source().and_then(|source| source.downcast_ref()))
+                    {
+                        FetchError::SubrequestRateLimited {
+                            service: service_name.to_string(),
+                            retry_after: rate_limited.retry_after_secs(),
+                        }
+                    } else if let Some(error) = FetchError::find_retry_after(&e) {
+                        error.clone()
+                    } else {
+                        FetchError::SubrequestHttpError {
+                            service: service_name.to_string(),
+                            reason: e.to_string(),
+                            status: None,
+                        }
+                    }
+                })
+                .map_err(|error| {
+                    // The client is asked to wait for the slowest subgraph to accept requests again
+                    if let Some(retry_after) = error.retry_after() {
+                        let _ = parameters
+                            .context
+                            .upsert(RETRY_AFTER_CONTEXT_KEY, |delay: u64| delay.max(retry_after));
+                    }
+                    error
                 })?
                 .response
                 .into_parts();
@@ -1231,6 +1342,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn plan_complexity() {
+        let query_plan = QueryPlan::fake_builder()
+            .root(serde_json::from_str::<PlanNode>(test_query_plan!()).unwrap())
+            .build();
+        assert_eq!(query_plan.fetch_count(), 5);
+        assert_eq!(query_plan.sequential_depth(), 3);
+        assert_eq!(
+            query_plan
+                .subgraph_fetches()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![("books", 2), ("product", 3)]
+        );
+    }
+
     /// This test panics in the product subgraph. HOWEVER, this does not result in a panic in the
     /// test, since the buffer() functionality in the tower stack "loses" the panic and we end up
     /// with a closed service.
@@ -0,0 +1,225 @@
+//! Checks of the operations sent to subgraphs against the subgraph ACLs.
+//!
+//! Each fetch of a query plan is parsed, and the fields it selects are checked against the
+//! rules of its subgraph, so that a plan requesting a field the router may not request from a
+//! subgraph fails before any request is sent.
+
+use std::collections::HashMap;
+
+use apollo_parser::ast;
+
+use super::PlanNode;
+use crate::configuration::SubgraphAcl;
+use crate::configuration::SubgraphAclRules;
+use crate::error::QueryPlannerError;
+use crate::*;
+
+/// Field resolving entities in the operations of entity fetches. The fields selected in the
+/// entities are checked, not the field itself.
+const ENTITIES: &str = "_entities";
+const TYPENAME: &str = "__typename";
+
+/// Checks the fetches of a plan, or returns the first field not allowed by the ACL of its
+/// subgraph.
+pub(crate) fn check_subgraph_acl(
+    node: &PlanNode,
+    schema: &Schema,
+    acl: &SubgraphAcl,
+) -> Result<(), QueryPlannerError> {
+    if acl.subgraphs.is_empty() {
+        return Ok(());
+    }
+    match node {
+        PlanNode::Sequence { nodes } | PlanNode::Parallel { nodes } => nodes
+            .iter()
+            .try_for_each(|node| check_subgraph_acl(node, schema, acl)),
+        PlanNode::Fetch(fetch) => match acl.subgraphs.get(&fetch.service_name) {
+            Some(rules) => {
+                let root_type = schema.root_operation_name(*fetch.operation_kind());
+                Checker::new(schema, rules, &fetch.operation)
+                    .check(root_type)
+                    .map_err(|field| {
+                        QueryPlannerError::SubgraphAcl(fetch.service_name.clone(), field)
+                    })
+            }
+            None => Ok(()),
+        },
+        PlanNode::Flatten(flatten) => check_subgraph_acl(&flatten.node, schema, acl),
+        PlanNode::Defer { primary, deferred } => primary
+            .node
+            .iter()
+            .chain(
+                deferred
+                    .iter()
+                    .filter_map(|deferred| deferred.node.as_ref()),
+            )
+            .try_for_each(|node| check_subgraph_acl(node, schema, acl)),
+        PlanNode::Condition {
+            if_clause,
+            else_clause,
+            ..
+        } => if_clause
+            .iter()
+            .chain(else_clause.iter())
+            .try_for_each(|node| check_subgraph_acl(node, schema, acl)),
+    }
+}
+
+/// Checks the selections of a subgraph operation.
+struct Checker<'a> {
+    schema: &'a Schema,
+    rules: &'a SubgraphAclRules,
+    document: ast::Document,
+    fragments: HashMap<String, (String, ast::SelectionSet)>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(schema: &'a Schema, rules: &'a SubgraphAclRules, operation: &str) -> Self {
+        let document = apollo_parser::Parser::new(operation).parse().document();
+        let fragments = document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::FragmentDefinition(fragment) => Some((
+                    fragment.fragment_name()?.name()?.text().to_string(),
+                    (
+                        named_type(fragment.type_condition()?)?,
+                        fragment.selection_set()?,
+                    ),
+                )),
+                _ => None,
+            })
+            .collect();
+        Checker {
+            schema,
+            rules,
+            document,
+            fragments,
+        }
+    }
+
+    /// Returns the first field not allowed by the rules, as `Type.field`.
+    fn check(&self, root_type: &str) -> Result<(), String> {
+        self.document
+            .definitions()
+            .filter_map(|definition| match definition {
+                ast::Definition::OperationDefinition(operation) => operation.selection_set(),
+                _ => None,
+            })
+            .try_for_each(|selection_set| self.check_selection_set(Some(root_type), selection_set))
+    }
+
+    /// Checks a selection set of a type, which is unknown in the selection set of `_entities`
+    /// until a type condition.
+    fn check_selection_set(
+        &self,
+        type_name: Option<&str>,
+        selection_set: ast::SelectionSet,
+    ) -> Result<(), String> {
+        for selection in selection_set.selections() {
+            match selection {
+                ast::Selection::Field(field) => {
+                    let name = match field.name() {
+                        Some(name) => name.text().to_string(),
+                        None => continue,
+                    };
+                    if name == TYPENAME {
+                        continue;
+                    }
+                    let field_type = match type_name {
+                        Some(_) if name == ENTITIES => None,
+                        Some(type_name) => {
+                            if !self.rules.allows(type_name, &name) {
+                                return Err(format!("{}.{}", type_name, name));
+                            }
+                            self.field_type(type_name, &name)
+                        }
+                        None => None,
+                    };
+                    if let Some(selection_set) = field.selection_set() {
+                        self.check_selection_set(field_type.as_deref(), selection_set)?;
+                    }
+                }
+                ast::Selection::InlineFragment(fragment) => {
+                    let type_condition = fragment.type_condition().and_then(named_type);
+                    if let Some(selection_set) = fragment.selection_set() {
+                        self.check_selection_set(
+                            type_condition.as_deref().or(type_name),
+                            selection_set,
+                        )?;
+                    }
+                }
+                ast::Selection::FragmentSpread(spread) => {
+                    let fragment = spread
+                        .fragment_name()
+                        .and_then(|name| name.name())
+                        .and_then(|name| self.fragments.get(name.text().as_str()));
+                    if let Some((type_condition, selection_set)) = fragment {
+                        self.check_selection_set(Some(type_condition), selection_set.clone())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The name of the type of a field, without its list and non null wrappers.
+    fn field_type(&self, type_name: &str, field_name: &str) -> Option<String> {
+        self.schema
+            .object_types
+            .get(type_name)
+            .and_then(|object| object.field(field_name))
+            .or_else(|| {
+                self.schema
+                    .interfaces
+                    .get(type_name)
+                    .and_then(|interface| interface.field(field_name))
+            })
+            .and_then(|field_type| field_type.inner_type_name())
+            .map(str::to_string)
+    }
+}
+
+fn named_type(type_condition: ast::TypeCondition) -> Option<String> {
+    Some(type_condition.named_type()?.name()?.text().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        let schema = include_str!("testdata/schema.graphql");
+        Schema::parse(schema, &Default::default()).unwrap()
+    }
+
+    fn rules(allow: Option<&[&str]>, deny: &[&str]) -> SubgraphAclRules {
+        SubgraphAclRules {
+            allow: allow.map(|allow| allow.iter().map(|rule| rule.to_string()).collect()),
+            deny: deny.iter().map(|rule| rule.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn fields_are_checked_against_the_rules() {
+        let schema = schema();
+        let operation = "query($representations:[_Any!]!){_entities(representations:$representations){...on User{__typename username ...Reviews}}} fragment Reviews on User{reviews{body}}";
+
+        let allowed = rules(Some(&["User.username", "User.reviews", "Review.*"]), &[]);
+        assert_eq!(
+            Checker::new(&schema, &allowed, operation).check("Query"),
+            Ok(())
+        );
+
+        let denied = rules(None, &["Review.body"]);
+        assert_eq!(
+            Checker::new(&schema, &denied, operation).check("Query"),
+            Err("Review.body".to_string())
+        );
+
+        let not_allowed = rules(Some(&["User"]), &[]);
+        assert_eq!(
+            Checker::new(&schema, &not_allowed, operation).check("Query"),
+            Err("Review.body".to_string())
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Supergraph schema polled from a URL, for self-hosted registries.
+//!
+//! The schema is fetched with a GET request, then polled. The `ETag` of the last response is sent
+//! in an `If-None-Match` header, and a `304 Not Modified` response or a schema identical to the
+//! last one is not reported, so the router is not reloaded for nothing.
+
+use std::time::Duration;
+
+use futures::prelude::*;
+use http::header::ETAG;
+use http::header::IF_NONE_MATCH;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::StatusCode;
+use tower::BoxError;
+use url::Url;
+
+/// Timeout of each request sent to the schema URL.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Poller {
+    client: reqwest::Client,
+    url: Url,
+    headers: HeaderMap,
+    interval: tokio::time::Interval,
+    etag: Option<HeaderValue>,
+    last_schema: Option<String>,
+}
+
+/// Polls the supergraph schema at `url`, the first request being sent right away.
+///
+/// Each poll yields the new schema, `None` if it did not change, or the reason it could not be
+/// fetched.
+pub(crate) fn stream_supergraph(
+    url: Url,
+    headers: HeaderMap,
+    poll_interval: Duration,
+) -> impl Stream<Item = Result<Option<String>, BoxError>> {
+    let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build();
+    let poller = client.map(|client| Poller {
+        client,
+        url,
+        headers,
+        interval: tokio::time::interval(poll_interval),
+        etag: None,
+        last_schema: None,
+    });
+    stream::unfold(Some(poller), |poller| async move {
+        let mut poller = match poller? {
+            Ok(poller) => poller,
+            // Without a client, the error is reported once and the stream ends
+            Err(err) => return Some((Err(err.into()), None)),
+        };
+        poller.interval.tick().await;
+        let result = poller.fetch().await;
+        Some((result, Some(Ok(poller))))
+    })
+}
+
+impl Poller {
+    async fn fetch(&mut self) -> Result<Option<String>, BoxError> {
+        let mut request = self
+            .client
+            .get(self.url.clone())
+            .headers(self.headers.clone());
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            tracing::trace!("schema did not change");
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(
+                format!("the schema URL answered with status {}", response.status()).into(),
+            );
+        }
+
+        let etag = response.headers().get(ETAG).cloned();
+        let schema = response.text().await?;
+        self.etag = etag;
+        if self.last_schema.as_ref() == Some(&schema) {
+            tracing::trace!("schema did not change");
+            return Ok(None);
+        }
+        self.last_schema = Some(schema.clone());
+        Ok(Some(schema))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use hyper::service::make_service_fn;
+    use hyper::service::service_fn;
+    use hyper::Body;
+    use hyper::Request;
+    use hyper::Response;
+    use hyper::Server;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn schema_is_polled_until_it_changes() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let server_polls = polls.clone();
+        let make_service = make_service_fn(move |_| {
+            let polls = server_polls.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let poll = polls.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let mut response = Response::builder();
+                        if request.headers().get("authorization")
+                            != Some(&HeaderValue::from_static("Bearer secret"))
+                        {
+                            response = response.status(StatusCode::UNAUTHORIZED);
+                        } else if poll == 1 {
+                            assert_eq!(request.headers().get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+                            response = response.status(StatusCode::NOT_MODIFIED);
+                        } else if poll < 3 {
+                            response = response.header(ETAG, "\"v1\"");
+                        }
+                        let schema = if poll < 3 { "schema v1" } else { "schema v2" };
+                        Ok::<_, Infallible>(response.body(Body::from(schema)).unwrap())
+                    }
+                }))
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        let mut stream = stream_supergraph(
+            Url::parse(&format!("http://{address}/supergraph.graphql")).unwrap(),
+            headers,
+            Duration::from_millis(10),
+        )
+        .boxed();
+
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Some("schema v1".to_string())
+        );
+        // Not modified
+        assert_eq!(stream.next().await.unwrap().unwrap(), None);
+        // The server ignored the ETag, but the schema is the same
+        assert_eq!(stream.next().await.unwrap().unwrap(), None);
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Some("schema v2".to_string())
+        );
+    }
+}
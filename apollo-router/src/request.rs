@@ -5,10 +5,12 @@ use derivative::Derivative;
 use serde::de::Error;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json_bytes::json;
 use serde_json_bytes::ByteString;
 use serde_json_bytes::Map as JsonMap;
 use serde_json_bytes::Value;
 
+use crate::configuration::GetParameters;
 use crate::json_ext::Object;
 
 /// A graphql request.
@@ -83,6 +85,14 @@ impl Request {
     }
 
     pub fn from_urlencoded_query(url_encoded_query: String) -> Result<Request, serde_json::Error> {
+        Self::from_urlencoded_query_with(url_encoded_query, &GetParameters::default())
+    }
+
+    /// Like [`Request::from_urlencoded_query`], with the parameter names of the configuration.
+    pub(crate) fn from_urlencoded_query_with(
+        url_encoded_query: String,
+        parameters: &GetParameters,
+    ) -> Result<Request, serde_json::Error> {
         // As explained in the form content types specification https://www.w3.org/TR/html4/interact/forms.html#h-17.13.4.1
         // `Forms submitted with this content type must be encoded as follows:`
         //
@@ -99,21 +109,35 @@ impl Request {
             serde_urlencoded::from_bytes(&decoded_string).map_err(serde_json::Error::custom)?;
 
         let operation_name = if let Some(serde_json::Value::String(operation_name)) =
-            urldecoded.get("operationName")
+            urldecoded.get(&parameters.operation_name)
         {
             Some(operation_name.clone())
         } else {
             None
         };
 
-        let query = if let Some(serde_json::Value::String(query)) = urldecoded.get("query") {
-            Some(query.as_str())
-        } else {
-            None
-        };
-        let variables: Object = get_from_urldecoded(&urldecoded, "variables")?.unwrap_or_default();
-        let extensions: Object =
-            get_from_urldecoded(&urldecoded, "extensions")?.unwrap_or_default();
+        let query =
+            if let Some(serde_json::Value::String(query)) = urldecoded.get(&parameters.query) {
+                Some(query.as_str())
+            } else {
+                None
+            };
+        let variables: Object =
+            get_from_urldecoded(&urldecoded, &parameters.variables)?.unwrap_or_default();
+        let mut extensions: Object =
+            get_from_urldecoded(&urldecoded, &parameters.extensions)?.unwrap_or_default();
+        if let Some(serde_json::Value::String(hash)) = parameters
+            .persisted_query_hash
+            .as_ref()
+            .and_then(|name| urldecoded.get(name))
+        {
+            if !extensions.contains_key("persistedQuery") {
+                extensions.insert(
+                    ByteString::from("persistedQuery"),
+                    json!({ "version": 1, "sha256Hash": hash }),
+                );
+            }
+        }
 
         let request_builder = Self::builder()
             .variables(variables)
@@ -270,4 +294,35 @@ mod tests {
 
         assert_eq!(expected_result, req);
     }
+
+    #[test]
+    fn from_urlencoded_query_with_custom_parameters() {
+        let parameters = GetParameters {
+            query: "q".to_string(),
+            operation_name: "op".to_string(),
+            persisted_query_hash: Some("hash".to_string()),
+            ..Default::default()
+        };
+        let query_string = "q=%7B+me+%7B+name+%7D+%7D&op=Me&query=ignored&hash=20a101de18d4a9331bfc4ccdfef33cc735876a689490433570f17bdd4c0bad3f".to_string();
+
+        let expected_result = serde_json::from_str::<Request>(
+            json!(
+            {
+              "query": "{ me { name } }",
+              "operationName": "Me",
+              "extensions": {
+                  "persistedQuery": {
+                      "version": 1,
+                      "sha256Hash": "20a101de18d4a9331bfc4ccdfef33cc735876a689490433570f17bdd4c0bad3f"
+                  }
+                }
+            })
+            .to_string()
+            .as_str(),
+        ).unwrap();
+
+        let req = Request::from_urlencoded_query_with(query_string, &parameters).unwrap();
+
+        assert_eq!(expected_result, req);
+    }
 }
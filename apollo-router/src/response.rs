@@ -85,6 +85,21 @@ impl Response {
         self.errors.append(errors)
     }
 
+    /// Applies a function to each error of the response, including the errors of its incremental
+    /// responses. Errors for which the function returns `None` are removed.
+    pub fn map_errors(&mut self, mut f: impl FnMut(Error) -> Option<Error>) {
+        self.errors = std::mem::take(&mut self.errors)
+            .into_iter()
+            .filter_map(&mut f)
+            .collect();
+        for incremental in &mut self.incremental {
+            incremental.errors = std::mem::take(&mut incremental.errors)
+                .into_iter()
+                .filter_map(&mut f)
+                .collect();
+        }
+    }
+
     /// Create a [`Response`] from the supplied [`Bytes`].
     ///
     /// This will return an error (identifying the faulty service) if the input is invalid.
@@ -94,6 +109,11 @@ impl Response {
                 service: service_name.to_string(),
                 reason: error.to_string(),
             })?;
+        Response::from_value(service_name, value)
+    }
+
+    /// Create a [`Response`] from the parsed JSON body of a subgraph response.
+    pub(crate) fn from_value(service_name: &str, value: Value) -> Result<Response, FetchError> {
         let mut object =
             ensure_object!(value).map_err(|error| FetchError::SubrequestMalformedResponse {
                 service: service_name.to_string(),
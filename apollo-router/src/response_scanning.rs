@@ -0,0 +1,143 @@
+//! Scanning of the serialized responses, right before they are sent.
+//!
+//! The verdicts of the scanners provided by plugins are applied to the JSON of each response:
+//! redacted spans are masked inside the strings of the response, and a blocked response is
+//! replaced with an error. Deferred responses are scanned one by one as they stream, and a
+//! blocked one ends the stream.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::configuration::JsonStrings;
+use crate::graphql;
+use crate::json_ext;
+use crate::plugin::ResponseScanner;
+use crate::plugin::ScanVerdict;
+
+const REDACTED: u8 = b'*';
+
+/// The scanners of the responses, in the order of their plugins.
+#[derive(Clone, Default)]
+pub(crate) struct ResponseScanning {
+    scanners: Vec<Arc<dyn ResponseScanner>>,
+}
+
+impl ResponseScanning {
+    pub(crate) fn new(scanners: Vec<Arc<dyn ResponseScanner>>) -> Self {
+        ResponseScanning { scanners }
+    }
+
+    /// Applies the verdicts of the scanners to the JSON of a response, or returns `None` if one
+    /// of them blocks it.
+    pub(crate) fn scan(&self, mut json: Vec<u8>) -> Option<Vec<u8>> {
+        for scanner in &self.scanners {
+            match scanner.scan(&json) {
+                ScanVerdict::Pass => {}
+                ScanVerdict::Redact(spans) => redact(&mut json, &spans),
+                ScanVerdict::Block(reason) => {
+                    tracing::warn!("response blocked: {}", reason);
+                    return None;
+                }
+            }
+        }
+        Some(json)
+    }
+}
+
+/// The JSON of the error replacing a blocked response. A deferred response ends the stream.
+pub(crate) fn blocked_json(has_next: Option<bool>, strings: &JsonStrings) -> Vec<u8> {
+    let response = graphql::Response::builder()
+        .error(
+            graphql::Error::builder()
+                .message("the response was blocked")
+                .extension("code", "RESPONSE_BLOCKED")
+                .build(),
+        )
+        .and_has_next(has_next)
+        .build();
+    let mut json = Vec::new();
+    json_ext::to_writer(&mut json, &response, strings).expect("body should be serializable; qed");
+    json
+}
+
+/// Masks the characters starting in the spans, inside the strings of a JSON document.
+///
+/// Quotes and escape sequences are left untouched, and each byte of a masked character is
+/// replaced, so that the document stays valid and the other spans keep their offsets.
+pub(crate) fn redact(json: &mut [u8], spans: &[Range<usize>]) {
+    let mut in_string = false;
+    let mut index = 0;
+    while index < json.len() {
+        let byte = json[index];
+        if !in_string {
+            in_string = byte == b'"';
+            index += 1;
+            continue;
+        }
+        let len = match byte {
+            b'"' => {
+                in_string = false;
+                index += 1;
+                continue;
+            }
+            b'\\' if json.get(index + 1) == Some(&b'u') => {
+                index += 6;
+                continue;
+            }
+            b'\\' => {
+                index += 2;
+                continue;
+            }
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+        let end = (index + len).min(json.len());
+        if spans.iter().any(|span| span.contains(&index)) {
+            json[index..end].fill(REDACTED);
+        }
+        index = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Secrets;
+
+    impl ResponseScanner for Secrets {
+        fn scan(&self, json: &[u8]) -> ScanVerdict {
+            let json = String::from_utf8_lossy(json);
+            if json.contains("forbidden") {
+                return ScanVerdict::Block("forbidden data".to_string());
+            }
+            match json.find("4111") {
+                // the whole card number, and the quote after it
+                Some(start) => ScanVerdict::Redact(vec![start..start + 17]),
+                None => ScanVerdict::Pass,
+            }
+        }
+    }
+
+    #[test]
+    fn redacted_responses_stay_valid() {
+        let scanning = ResponseScanning::new(vec![Arc::new(Secrets)]);
+        let json = r#"{"data":{"card":"4111111111111111","name":"Jérôme"}}"#;
+        let scanned = scanning.scan(json.as_bytes().to_vec()).unwrap();
+        assert_eq!(
+            String::from_utf8(scanned).unwrap(),
+            r#"{"data":{"card":"****************","name":"Jérôme"}}"#
+        );
+
+        let mut json = r#"{"name":"Jérôme"}"#.as_bytes().to_vec();
+        let len = json.len();
+        redact(&mut json, &[0..len]);
+        assert_eq!(String::from_utf8(json).unwrap(), r#"{"****":"********"}"#);
+
+        assert!(scanning
+            .scan(br#"{"data":{"forbidden":true}}"#.to_vec())
+            .is_none());
+    }
+}
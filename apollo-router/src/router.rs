@@ -12,13 +12,12 @@ use std::time::Duration;
 use derivative::Derivative;
 use derive_more::Display;
 use derive_more::From;
-use displaydoc::Display as DisplayDoc;
 use futures::channel::oneshot;
 use futures::prelude::*;
 use futures::FutureExt;
+use http::HeaderMap;
 use http_body::Body as _;
 use hyper::Body;
-use thiserror::Error;
 use tokio::sync::RwLock;
 use tokio::task::spawn;
 use tower::BoxError;
@@ -33,9 +32,12 @@ use Event::UpdateSchema;
 
 use crate::axum_http_server_factory::make_axum_router;
 use crate::axum_http_server_factory::AxumHttpServerFactory;
+use crate::configuration::apply_schedules;
 use crate::configuration::validate_configuration;
 use crate::configuration::Configuration;
 use crate::configuration::ListenAddr;
+use crate::error::ApolloRouterError;
+use crate::health::Health;
 use crate::plugin::DynPlugin;
 use crate::router_factory::SupergraphServiceConfigurator;
 use crate::router_factory::YamlSupergraphServiceFactory;
@@ -60,48 +62,29 @@ async fn make_transport_service<RF>(
         .create(configuration.clone(), schema, None, Some(extra_plugins))
         .await?;
     let extra = Default::default();
-    Ok(make_axum_router(service_factory, &configuration, extra)?
-        .map_response(|response| {
-            response.map(|body| {
-                // Axum makes this `body` have type:
-                // https://docs.rs/http-body/0.4.5/http_body/combinators/struct.UnsyncBoxBody.html
-                let mut body = Box::pin(body);
-                // We make a stream based on its `poll_data` method
-                // in order to create a `hyper::Body`.
-                Body::wrap_stream(stream::poll_fn(move |ctx| body.as_mut().poll_data(ctx)))
-                // … but we ignore the `poll_trailers` method:
-                // https://docs.rs/http-body/0.4.5/http_body/trait.Body.html#tymethod.poll_trailers
-                // Apparently HTTP/2 trailers are like headers, except after the response body.
-                // I (Simon) believe nothing in the Apollo Router uses trailers as of this writing,
-                // so ignoring `poll_trailers` is fine.
-                // If we want to use trailers, we may need remove this convertion to `hyper::Body`
-                // and return `UnsyncBoxBody` (a.k.a. `axum::BoxBody`) as-is.
+    let health = Default::default();
+    Ok(
+        make_axum_router(service_factory, &configuration, extra, health)?
+            .map_response(|response| {
+                response.map(|body| {
+                    // Axum makes this `body` have type:
+                    // https://docs.rs/http-body/0.4.5/http_body/combinators/struct.UnsyncBoxBody.html
+                    let mut body = Box::pin(body);
+                    // We make a stream based on its `poll_data` method
+                    // in order to create a `hyper::Body`.
+                    Body::wrap_stream(stream::poll_fn(move |ctx| body.as_mut().poll_data(ctx)))
+                    // … but we ignore the `poll_trailers` method:
+                    // https://docs.rs/http-body/0.4.5/http_body/trait.Body.html#tymethod.poll_trailers
+                    // Apparently HTTP/2 trailers are like headers, except after the response body.
+                    // I (Simon) believe nothing in the Apollo Router uses trailers as of this writing,
+                    // so ignoring `poll_trailers` is fine.
+                    // If we want to use trailers, we may need remove this convertion to `hyper::Body`
+                    // and return `UnsyncBoxBody` (a.k.a. `axum::BoxBody`) as-is.
+                })
             })
-        })
-        .map_err(|error| match error {})
-        .boxed_clone())
-}
-
-/// Error types for FederatedServer.
-#[derive(Error, Debug, DisplayDoc)]
-pub enum ApolloRouterError {
-    /// failed to start server
-    StartupError,
-
-    /// failed to stop HTTP Server
-    HttpServerLifecycleError,
-
-    /// no valid configuration was supplied
-    NoConfiguration,
-
-    /// no valid schema was supplied
-    NoSchema,
-
-    /// could not create the HTTP pipeline: {0}
-    ServiceCreationError(BoxError),
-
-    /// could not create the HTTP server: {0}
-    ServerCreationError(std::io::Error),
+            .map_err(|error| match error {})
+            .boxed_clone(),
+    )
 }
 
 /// The user supplied schema. Either a static string or a stream for hot reloading.
@@ -145,6 +128,20 @@ pub enum SchemaSource {
         /// The duration between polling
         poll_interval: Duration,
     },
+
+    /// A schema polled from a URL, like a self-hosted registry.
+    #[display(fmt = "Url")]
+    Url {
+        /// The URL of the schema.
+        url: Url,
+
+        /// The headers sent with each request, like an `authorization` header.
+        #[derivative(Debug = "ignore")]
+        headers: HeaderMap,
+
+        /// The duration between polling
+        poll_interval: Duration,
+    },
 }
 
 impl From<&'_ str> for SchemaSource {
@@ -157,7 +154,8 @@ impl From<&'_ str> for SchemaSource {
 
 impl SchemaSource {
     /// Convert this schema into a stream regardless of if is static or not. Allows for unified handling later.
-    fn into_stream(self) -> impl Stream<Item = Event> {
+    /// The result of Uplink polls is reported to `health`.
+    fn into_stream(self, health: Arc<Health>) -> impl Stream<Item = Event> {
         match self {
             SchemaSource::Static { schema_sdl: schema } => {
                 stream::once(future::ready(UpdateSchema(schema))).boxed()
@@ -202,20 +200,39 @@ impl SchemaSource {
                 // With regards to ELv2 licensing, the code inside this block
                 // is license key functionality
                 apollo_uplink::stream_supergraph(apollo_key, apollo_graph_ref, urls, poll_interval)
-                    .filter_map(|res| {
+                    .filter_map(move |res| {
                         future::ready(match res {
-                            Ok(schema_result) => Some(UpdateSchema(schema_result.schema)),
+                            Ok(schema_result) => {
+                                health.uplink_succeeded();
+                                schema_result.map(|schema| UpdateSchema(schema.schema))
+                            }
                             Err(e) => {
                                 tracing::error!(
                                     "error downloading the schema from Uplink: {:?}",
                                     e
                                 );
+                                health.uplink_failed(format!("{:?}", e));
                                 None
                             }
                         })
                     })
                     .boxed()
             }
+            SchemaSource::Url {
+                url,
+                headers,
+                poll_interval,
+            } => crate::remote_schema::stream_supergraph(url, headers, poll_interval)
+                .filter_map(|res| {
+                    future::ready(match res {
+                        Ok(schema) => schema.map(UpdateSchema),
+                        Err(e) => {
+                            tracing::error!("error downloading the schema from its URL: {}", e);
+                            None
+                        }
+                    })
+                })
+                .boxed(),
         }
         .chain(stream::iter(vec![NoMoreSchema]))
     }
@@ -264,13 +281,9 @@ impl Default for ConfigurationSource {
 impl ConfigurationSource {
     /// Convert this config into a stream regardless of if is static or not. Allows for unified handling later.
     fn into_stream(self) -> impl Stream<Item = Event> {
-        match self {
-            ConfigurationSource::Static(instance) => {
-                stream::iter(vec![UpdateConfiguration(instance)]).boxed()
-            }
-            ConfigurationSource::Stream(stream) => {
-                stream.map(|x| UpdateConfiguration(Box::new(x))).boxed()
-            }
+        let configurations = match self {
+            ConfigurationSource::Static(instance) => stream::iter(vec![*instance]).boxed(),
+            ConfigurationSource::Stream(stream) => stream.boxed(),
             ConfigurationSource::File { path, watch, delay } => {
                 // Sanity check, does the config file exists, if it doesn't then bail.
                 if !path.exists() {
@@ -295,13 +308,9 @@ impl ConfigurationSource {
                                             },
                                         )
                                     })
-                                    .map(|x| UpdateConfiguration(Box::new(x)))
                                     .boxed()
                             } else {
-                                stream::once(future::ready(UpdateConfiguration(Box::new(
-                                    configuration,
-                                ))))
-                                .boxed()
+                                stream::once(future::ready(configuration)).boxed()
                             }
                         }
                         Err(err) => {
@@ -311,12 +320,15 @@ impl ConfigurationSource {
                     }
                 }
             }
-        }
-        .chain(stream::iter(vec![NoMoreConfiguration]))
-        .boxed()
+        };
+        // The schedules of each configuration are applied until the next one
+        apply_schedules(configurations)
+            .map(|x| UpdateConfiguration(Box::new(x)))
+            .chain(stream::iter(vec![NoMoreConfiguration]))
+            .boxed()
     }
 
-    fn read_config(path: &Path) -> Result<Configuration, ReadConfigError> {
+    pub(crate) fn read_config(path: &Path) -> Result<Configuration, ReadConfigError> {
         let config = fs::read_to_string(path)?;
         let config = validate_configuration(&config)?;
 
@@ -325,7 +337,7 @@ impl ConfigurationSource {
 }
 
 #[derive(From, Display)]
-enum ReadConfigError {
+pub(crate) enum ReadConfigError {
     /// could not read configuration: {0}
     Io(std::io::Error),
     /// {0}
@@ -474,13 +486,15 @@ impl RouterHttpServer {
         shutdown: Option<ShutdownSource>,
     ) -> RouterHttpServer {
         let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+        let health = Arc::new(Health::default());
         let event_stream = generate_event_stream(
             shutdown.unwrap_or(ShutdownSource::CtrlC),
             configuration.unwrap_or_default(),
             schema,
             shutdown_receiver,
+            health.clone(),
         );
-        let server_factory = AxumHttpServerFactory::new();
+        let server_factory = AxumHttpServerFactory::with_health(health);
         let router_factory = YamlSupergraphServiceFactory::default();
         let state_machine = StateMachine::new(server_factory, router_factory);
         let listen_address = state_machine.listen_address.clone();
@@ -572,12 +586,13 @@ fn generate_event_stream(
     configuration: ConfigurationSource,
     schema: SchemaSource,
     shutdown_receiver: oneshot::Receiver<()>,
+    health: Arc<Health>,
 ) -> impl Stream<Item = Event> {
     // Chain is required so that the final shutdown message is sent.
     let messages = stream::select_all(vec![
         shutdown.into_stream().boxed(),
         configuration.into_stream().boxed(),
-        schema.into_stream().boxed(),
+        schema.into_stream(health).boxed(),
         shutdown_receiver.into_stream().map(|_| Shutdown).boxed(),
     ])
     .take_while(|msg| future::ready(!matches!(msg, Shutdown)))
@@ -732,7 +747,7 @@ mod tests {
             watch: true,
             delay: Some(Duration::from_millis(10)),
         }
-        .into_stream()
+        .into_stream(Default::default())
         .boxed();
 
         // First update is guaranteed
@@ -750,7 +765,7 @@ mod tests {
             watch: true,
             delay: None,
         }
-        .into_stream();
+        .into_stream(Default::default());
 
         // First update fails because the file is invalid.
         assert!(matches!(stream.next().await.unwrap(), NoMoreSchema));
@@ -767,7 +782,7 @@ mod tests {
             watch: false,
             delay: None,
         }
-        .into_stream();
+        .into_stream(Default::default());
         assert!(matches!(stream.next().await.unwrap(), UpdateSchema(_)));
         assert!(matches!(stream.next().await.unwrap(), NoMoreSchema));
     }
@@ -2,18 +2,36 @@ use std::collections::HashMap;
 // With regards to ELv2 licensing, this entire file is license key functionality
 use std::sync::Arc;
 
+use futures::future;
+use futures::stream;
 use futures::stream::BoxStream;
+use futures::StreamExt;
+use http::header::RETRY_AFTER;
+use http::StatusCode;
 use serde_json::Map;
 use serde_json::Value;
+use tower::util::BoxService;
+use tower::util::MapRequest;
 use tower::BoxError;
+use tower::ServiceExt;
 use tower_service::Service;
 
 use crate::configuration::Configuration;
 use crate::configuration::ConfigurationError;
 use crate::graphql;
+use crate::introspection::IntrospectionListener;
 use crate::plugin::DynPlugin;
 use crate::plugin::Handler;
+use crate::plugin::ResponseScanner;
+use crate::services::canary;
+use crate::services::canary::CanarySplit;
+use crate::services::layers::plugin_isolation::Isolated;
 use crate::services::new_service::NewService;
+use crate::services::router;
+use crate::services::spiffe::SpiffeSource;
+use crate::services::subgraph_service::tls_client_config;
+use crate::services::ComputedService;
+use crate::services::ConnectorService;
 use crate::services::RouterCreator;
 use crate::services::SubgraphService;
 use crate::PluggableSupergraphServiceBuilder;
@@ -39,6 +57,28 @@ pub(crate) trait SupergraphServiceFactory:
     type Future: Send;
 
     fn custom_endpoints(&self) -> HashMap<String, Handler>;
+
+    /// Wraps the service answering the HTTP requests to the GraphQL endpoint with the router
+    /// stage of the plugins.
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        service
+    }
+
+    /// Endpoints of the plugins served on the admin listener, by plugin name.
+    fn admin_endpoints(&self) -> HashMap<String, Handler> {
+        HashMap::new()
+    }
+
+    /// Scanners of the responses, in the order of the plugins providing them.
+    fn response_scanners(&self) -> Vec<Arc<dyn ResponseScanner>> {
+        Vec::new()
+    }
+
+    /// Whether the services execute operations against a schema, which the readiness endpoint
+    /// reports.
+    fn schema_loaded(&self) -> bool {
+        true
+    }
 }
 
 /// Factory for creating a SupergraphServiceFactory
@@ -58,6 +98,115 @@ pub(crate) trait SupergraphServiceConfigurator: Send + Sync + 'static {
     ) -> Result<Self::SupergraphServiceFactory, BoxError>;
 }
 
+/// Seconds clients are asked to wait before retrying, while the router is in safe mode
+const SAFE_MODE_RETRY_AFTER: &str = "5";
+
+/// SupergraphService factory used in safe mode, while the router has no usable schema.
+///
+/// Every GraphQL request is answered with a 503 status code, asking the client to retry later.
+#[derive(Clone, Default)]
+pub(crate) struct SafeModeSupergraphServiceFactory;
+
+impl NewService<http::Request<graphql::Request>> for SafeModeSupergraphServiceFactory {
+    type Service = BoxService<
+        http::Request<graphql::Request>,
+        http::Response<BoxStream<'static, graphql::Response>>,
+        BoxError,
+    >;
+
+    fn new_service(&self) -> Self::Service {
+        tower::service_fn(|_request: http::Request<graphql::Request>| async {
+            let response = graphql::Response::builder()
+                .error(
+                    graphql::Error::builder()
+                        .message("the router is starting and no schema is available yet")
+                        .extension("code", "SCHEMA_UNAVAILABLE")
+                        .build(),
+                )
+                .build();
+            Ok(http::Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(RETRY_AFTER, SAFE_MODE_RETRY_AFTER)
+                .body(stream::once(future::ready(response)).boxed())
+                .expect("the response is valid; qed"))
+        })
+        .boxed()
+    }
+}
+
+impl SupergraphServiceFactory for SafeModeSupergraphServiceFactory {
+    type SupergraphService = BoxService<
+        http::Request<graphql::Request>,
+        http::Response<BoxStream<'static, graphql::Response>>,
+        BoxError,
+    >;
+
+    type Future = <<SafeModeSupergraphServiceFactory as NewService<
+        http::Request<graphql::Request>,
+    >>::Service as Service<http::Request<graphql::Request>>>::Future;
+
+    fn custom_endpoints(&self) -> HashMap<String, Handler> {
+        HashMap::new()
+    }
+
+    fn schema_loaded(&self) -> bool {
+        false
+    }
+}
+
+/// SupergraphService factory wrapping the services of another one, for the dedicated
+/// introspection listener.
+///
+/// The requests are marked as received on that listener, which allows them to introspect the
+/// schema.
+#[derive(Clone)]
+pub(crate) struct IntrospectionListenerFactory<RF>(pub(crate) RF);
+
+type MarkRequest = fn(http::Request<graphql::Request>) -> http::Request<graphql::Request>;
+
+fn mark_introspection_listener(
+    mut request: http::Request<graphql::Request>,
+) -> http::Request<graphql::Request> {
+    request.extensions_mut().insert(IntrospectionListener);
+    request
+}
+
+impl<RF> NewService<http::Request<graphql::Request>> for IntrospectionListenerFactory<RF>
+where
+    RF: SupergraphServiceFactory,
+{
+    type Service = MapRequest<RF::SupergraphService, MarkRequest>;
+
+    fn new_service(&self) -> Self::Service {
+        MapRequest::new(self.0.new_service(), mark_introspection_listener)
+    }
+}
+
+impl<RF> SupergraphServiceFactory for IntrospectionListenerFactory<RF>
+where
+    RF: SupergraphServiceFactory,
+{
+    type SupergraphService = MapRequest<RF::SupergraphService, MarkRequest>;
+
+    type Future = RF::Future;
+
+    fn custom_endpoints(&self) -> HashMap<String, Handler> {
+        HashMap::new()
+    }
+
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        self.0.router_service(service)
+    }
+
+    fn response_scanners(&self) -> Vec<Arc<dyn ResponseScanner>> {
+        self.0.response_scanners()
+    }
+
+    fn schema_loaded(&self) -> bool {
+        self.0.schema_loaded()
+    }
+}
+
 /// Main implementation of the SupergraphService factory, supporting the extensions system
 #[derive(Default)]
 pub(crate) struct YamlSupergraphServiceFactory;
@@ -70,28 +219,143 @@ impl SupergraphServiceConfigurator for YamlSupergraphServiceFactory {
         &'a mut self,
         configuration: Arc<Configuration>,
         schema: Arc<Schema>,
-        _previous_router: Option<&'a Self::SupergraphServiceFactory>,
+        previous_router: Option<&'a Self::SupergraphServiceFactory>,
         extra_plugins: Option<Vec<(String, Box<dyn DynPlugin>)>>,
     ) -> Result<Self::SupergraphServiceFactory, BoxError> {
         // Process the plugins.
         let plugins = create_plugins(&configuration, &schema, extra_plugins).await?;
 
         let mut builder = PluggableSupergraphServiceBuilder::new(schema.clone());
-        builder = builder.with_configuration(configuration);
-
-        for (name, _) in schema.subgraphs() {
-            builder = builder.with_subgraph_service(name, SubgraphService::new(name));
-        }
-
         for (plugin_name, plugin) in plugins {
             builder = builder.with_dyn_plugin(plugin_name, plugin);
         }
+        let router = build_router(builder, &configuration, &schema, previous_router).await?;
+
+        // The canary schema shares the plugins of the main one
+        match &configuration.supergraph.experimental_canary {
+            Some(canary) => {
+                let split = CanarySplit::new(canary)?;
+                let canary_schema = Arc::new(canary::load_schema(canary, &configuration)?);
+                let builder = PluggableSupergraphServiceBuilder::new(canary_schema.clone())
+                    .with_plugins(router.plugins());
+                let canary_router = build_router(
+                    builder,
+                    &configuration,
+                    &canary_schema,
+                    previous_router.and_then(RouterCreator::canary),
+                )
+                .await?;
+                Ok(router.with_canary(canary_router, split))
+            }
+            None => Ok(router),
+        }
+    }
+}
 
-        // We're good to go with the new service.
-        let pluggable_router_service = builder.build().await?;
+/// Builds the router of a schema, once its plugins are set.
+async fn build_router(
+    mut builder: PluggableSupergraphServiceBuilder,
+    configuration: &Arc<Configuration>,
+    schema: &Schema,
+    previous_router: Option<&RouterCreator>,
+) -> Result<RouterCreator, BoxError> {
+    builder = builder.with_configuration(configuration.clone());
+    if let Some(previous_router) = previous_router {
+        builder = builder.with_stale_plans(previous_router.stale_plans());
+        if let Some(introspection) = previous_router.introspection() {
+            builder = builder.with_introspection(introspection);
+        }
+    }
 
-        Ok(pluggable_router_service)
+    let tls_config = match &configuration.tls.subgraph.spiffe {
+        Some(spiffe) => Some(SpiffeSource::new(spiffe).await?.client_config()?),
+        None => None,
+    };
+
+    for (name, _) in schema.subgraphs() {
+        if let Some(computed) = configuration
+            .experimental_computed_fields
+            .subgraphs
+            .get(name)
+        {
+            builder = builder.with_subgraph_service(name, ComputedService::new(name, computed)?);
+            continue;
+        }
+        let subgraph_tls_config = match configuration.tls.subgraph.for_subgraph(name) {
+            Some(tls_client) => Some(tls_client_config(&tls_client).map_err(|e| {
+                format!("invalid TLS configuration for subgraph '{}': {}", name, e)
+            })?),
+            None => tls_config.clone(),
+        };
+        let http_client = configuration.http_client.for_subgraph(name);
+        builder = match configuration
+            .experimental_rest_connectors
+            .subgraphs
+            .get(name)
+        {
+            Some(connector) => builder.with_subgraph_service(
+                name,
+                ConnectorService::new(name, connector, subgraph_tls_config, &http_client)?,
+            ),
+            None => builder.with_subgraph_service(
+                name,
+                SubgraphService::with_options(
+                    name,
+                    subgraph_tls_config,
+                    &http_client,
+                    configuration.experimental_json_numbers,
+                    configuration.experimental_json_strings,
+                    configuration
+                        .experimental_subgraph_tolerances
+                        .for_subgraph(name),
+                ),
+            ),
+        };
+    }
+    for name in configuration.experimental_rest_connectors.subgraphs.keys() {
+        if !schema.subgraphs().any(|(subgraph, _)| subgraph == name) {
+            tracing::warn!("REST connector configured for unknown subgraph '{}'", name);
+        }
+    }
+    for name in configuration.experimental_computed_fields.subgraphs.keys() {
+        if !schema.subgraphs().any(|(subgraph, _)| subgraph == name) {
+            tracing::warn!("computed fields configured for unknown subgraph '{}'", name);
+        }
     }
+    if configuration.experimental_upstream.url.is_some() {
+        let name = configuration.experimental_upstream.name.as_str();
+        if schema.subgraphs().any(|(subgraph, _)| subgraph == name) {
+            return Err(format!(
+                "the upstream server is named '{}' like a subgraph of the supergraph",
+                name
+            )
+            .into());
+        }
+        let upstream_tls_config = match configuration.tls.subgraph.for_subgraph(name) {
+            Some(tls_client) => Some(tls_client_config(&tls_client).map_err(|e| {
+                format!("invalid TLS configuration for upstream '{}': {}", name, e)
+            })?),
+            None => tls_config.clone(),
+        };
+        builder = builder.with_subgraph_service(
+            name,
+            SubgraphService::with_options(
+                name,
+                upstream_tls_config,
+                &configuration.http_client.for_subgraph(name),
+                configuration.experimental_json_numbers,
+                configuration.experimental_json_strings,
+                configuration
+                    .experimental_subgraph_tolerances
+                    .for_subgraph(name),
+            ),
+        );
+    }
+
+    // We're good to go with the new service.
+    let pluggable_router_service = builder.build().await?;
+
+    Ok(pluggable_router_service)
 }
 
 /// test only helper method to create a router factory in integration tests
@@ -125,6 +389,8 @@ async fn create_plugins(
     // List of mandatory plugins. Ordering is important!!
     let mandatory_plugins = vec![
         "experimental.include_subgraph_errors",
+        // Only requests received over HTTP need CSRF protection
+        #[cfg(feature = "http_server")]
         "apollo.csrf",
         "apollo.telemetry",
     ];
@@ -133,6 +399,7 @@ async fn create_plugins(
     let plugin_registry = crate::plugin::plugins();
     let mut plugin_instances = Vec::new();
     let extra = extra_plugins.unwrap_or_default();
+    let isolation = &configuration.experimental_plugin_isolation;
 
     for (name, mut configuration) in configuration.plugins().into_iter() {
         if extra.iter().any(|(n, _)| *n == name) {
@@ -156,6 +423,10 @@ async fn create_plugins(
                     .await
                 {
                     Ok(plugin) => {
+                        let plugin: Box<dyn DynPlugin> = match isolation.plugins.get(&name) {
+                            Some(isolation) => Box::new(Isolated::new(&name, plugin, isolation)),
+                            None => plugin,
+                        };
                         plugin_instances.push((name, plugin));
                     }
                     Err(err) => errors.push(ConfigurationError::PluginConfiguration {
@@ -219,6 +490,13 @@ async fn create_plugins(
         }
     }
 
+    // Only the plugins of the configuration can be isolated
+    for name in isolation.plugins.keys() {
+        if !plugin_instances.iter().any(|(n, _)| n == name) {
+            errors.push(ConfigurationError::PluginUnknown(name.clone()));
+        }
+    }
+
     let plugin_details = plugin_instances
         .iter()
         .map(|(name, plugin)| (name, plugin.name()))
@@ -0,0 +1,93 @@
+//! The stable API for plugins.
+//!
+//! Custom router binaries should only use the items of this module. They follow semantic
+//! versioning: they are not removed or changed in an incompatible way before the next major
+//! release of the router. Minor releases can add items to this module, and add fields, variants
+//! and methods to the types marked `#[non_exhaustive]`.
+//!
+//! The rest of the public API, like the configuration of the built-in plugins, the services used
+//! inside the router and everything named `experimental`, can change in any release.
+//!
+//! ```
+//! use apollo_router::sdk::register_plugin;
+//! use apollo_router::sdk::supergraph;
+//! use apollo_router::sdk::Plugin;
+//! use apollo_router::sdk::PluginInit;
+//! use apollo_router::sdk::ServiceBuilderExt;
+//! use tower::BoxError;
+//! use tower::ServiceBuilder;
+//! use tower::ServiceExt;
+//!
+//! struct Hello;
+//!
+//! #[async_trait::async_trait]
+//! impl Plugin for Hello {
+//!     type Config = ();
+//!
+//!     async fn new(_init: PluginInit<Self::Config>) -> Result<Self, BoxError> {
+//!         Ok(Hello)
+//!     }
+//!
+//!     fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+//!         ServiceBuilder::new()
+//!             .map_request(|request: supergraph::Request| {
+//!                 request.context.insert("hello", "world").unwrap();
+//!                 request
+//!             })
+//!             .buffered()
+//!             .service(service)
+//!             .boxed()
+//!     }
+//! }
+//!
+//! register_plugin!("example", "hello", Hello);
+//! # fn main() {}
+//! ```
+
+pub use crate::context::Context;
+pub use crate::layers::async_checkpoint::AsyncCheckpointLayer;
+pub use crate::layers::sync_checkpoint::CheckpointLayer;
+pub use crate::layers::ServiceBuilderExt;
+pub use crate::layers::ServiceExt;
+pub use crate::plugin::Plugin;
+pub use crate::plugin::PluginInit;
+pub use crate::register_plugin;
+pub use crate::test_harness::TestHarness;
+
+/// GraphQL requests, responses and errors.
+pub mod graphql {
+    pub use crate::graphql::Error;
+    pub use crate::graphql::IncrementalResponse;
+    pub use crate::graphql::JsonPath;
+    pub use crate::graphql::JsonPathElement;
+    pub use crate::graphql::Location;
+    pub use crate::graphql::Request;
+    pub use crate::graphql::Response;
+}
+
+/// The supergraph service, hooked with
+/// [`Plugin::supergraph_service`](super::Plugin::supergraph_service).
+pub mod supergraph {
+    pub use crate::services::supergraph::BoxService;
+    pub use crate::services::supergraph::Request;
+    pub use crate::services::supergraph::Response;
+    pub use crate::services::supergraph::ServiceResult;
+}
+
+/// The execution service, hooked with
+/// [`Plugin::execution_service`](super::Plugin::execution_service).
+pub mod execution {
+    pub use crate::services::execution::BoxService;
+    pub use crate::services::execution::Request;
+    pub use crate::services::execution::Response;
+    pub use crate::services::execution::ServiceResult;
+}
+
+/// The subgraph service, hooked with
+/// [`Plugin::subgraph_service`](super::Plugin::subgraph_service).
+pub mod subgraph {
+    pub use crate::services::subgraph::BoxService;
+    pub use crate::services::subgraph::Request;
+    pub use crate::services::subgraph::Response;
+    pub use crate::services::subgraph::ServiceResult;
+}
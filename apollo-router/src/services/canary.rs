@@ -0,0 +1,130 @@
+//! Canary rollouts of supergraph schemas.
+//!
+//! A canary schema is loaded along the main one, with its own query planner and subgraph
+//! services but the same plugins, and a share of the requests is executed against it: the
+//! requests selected with a header, and a percentage of the others. Requests are marked in their
+//! context with the version of the schema they were executed against, which the telemetry plugin
+//! adds to the attributes of the router metrics.
+
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use http::HeaderName;
+use tower::BoxError;
+
+use crate::configuration::Canary;
+use crate::configuration::Configuration;
+use crate::Schema;
+
+/// Context entry holding the version of the schema a request is executed against.
+pub(crate) const CANARY_CONTEXT_KEY: &str = "apollo_canary::schema";
+
+pub(crate) const STABLE_VERSION: &str = "stable";
+pub(crate) const CANARY_VERSION: &str = "canary";
+
+/// Reads and parses the canary schema.
+pub(crate) fn load_schema(
+    canary: &Canary,
+    configuration: &Configuration,
+) -> Result<Schema, BoxError> {
+    let sdl = std::fs::read_to_string(&canary.path).map_err(|e| {
+        format!(
+            "could not read the canary schema {}: {}",
+            canary.path.display(),
+            e
+        )
+    })?;
+    Ok(Schema::parse(&sdl, configuration)?)
+}
+
+/// Selection of the requests executed against the canary schema.
+#[derive(Debug)]
+pub(crate) struct CanarySplit {
+    rate: f64,
+    header: Option<HeaderName>,
+    requests: AtomicU64,
+}
+
+impl CanarySplit {
+    pub(crate) fn new(canary: &Canary) -> Result<Self, BoxError> {
+        if !(0.0..=100.0).contains(&canary.percentage) {
+            return Err(format!(
+                "the canary percentage must be between 0 and 100, got {}",
+                canary.percentage
+            )
+            .into());
+        }
+        let header = canary
+            .header
+            .as_deref()
+            .map(HeaderName::from_str)
+            .transpose()
+            .map_err(|e| format!("invalid canary header: {}", e))?;
+        Ok(CanarySplit {
+            rate: canary.percentage / 100.0,
+            header,
+            requests: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether a request is executed against the canary schema.
+    ///
+    /// The header forces the version with a `canary` or `stable` value. Other requests are
+    /// spread evenly, like the sampling of field level instrumentation, rather than randomly.
+    pub(crate) fn is_canary<T>(&self, request: &http::Request<T>) -> bool {
+        if let Some(value) = self
+            .header
+            .as_ref()
+            .and_then(|header| request.headers().get(header))
+        {
+            match value.as_bytes() {
+                b"canary" => return true,
+                b"stable" => return false,
+                _ => {}
+            }
+        }
+        if self.rate <= 0.0 {
+            return false;
+        }
+        let request = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        (request * self.rate).floor() != ((request + 1.0) * self.rate).floor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn requests_are_split_by_header_then_percentage() {
+        let split = CanarySplit::new(&Canary {
+            path: PathBuf::from("canary.graphql"),
+            percentage: 25.0,
+            header: Some("x-schema-version".to_string()),
+        })
+        .unwrap();
+
+        let request = |version: Option<&str>| {
+            let mut builder = http::Request::builder();
+            if let Some(version) = version {
+                builder = builder.header("x-schema-version", version);
+            }
+            builder.body(()).unwrap()
+        };
+        assert!(split.is_canary(&request(Some("canary"))));
+        assert!(!split.is_canary(&request(Some("stable"))));
+
+        let canaries = (0..100).filter(|_| split.is_canary(&request(None))).count();
+        assert_eq!(canaries, 25);
+
+        assert!(CanarySplit::new(&Canary {
+            path: PathBuf::from("canary.graphql"),
+            percentage: 150.0,
+            header: None,
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Capabilities of a subgraph, learned from its responses.
+//!
+//! A subgraph listing the encodings it accepts in the `Accept-Encoding` header of its responses
+//! gets compressed requests, even if no compression is configured for it, and a subgraph
+//! rejecting a compressed request with a 415 status gets uncompressed requests. Queries are sent
+//! as automatic persisted queries until the subgraph shows it doesn't support them, and
+//! deferring operations only offer to receive multipart responses while the subgraph is not known
+//! to answer them with a single response. What was learned is discarded after a delay, or when
+//! the subgraph cannot be reached, and learned again from the next responses.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use http::header::ACCEPT_ENCODING;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::StatusCode;
+
+use super::subgraph_service::Compression;
+
+pub(crate) const DEFAULT_CAPABILITIES_TTL: Duration = Duration::from_secs(300);
+
+/// Capabilities of a subgraph, shared by the clones of its service.
+#[derive(Clone)]
+pub(crate) struct Capabilities {
+    ttl: Duration,
+    learned: Arc<Mutex<Option<Learned>>>,
+}
+
+struct Learned {
+    at: Instant,
+    /// Encodings accepted by the subgraph, by order of preference
+    accepted: Vec<Compression>,
+    rejects_compression: bool,
+    persisted_queries: Option<bool>,
+    incremental_delivery: Option<bool>,
+}
+
+impl Learned {
+    fn new() -> Self {
+        Self {
+            at: Instant::now(),
+            accepted: Vec::new(),
+            rejects_compression: false,
+            persisted_queries: None,
+            incremental_delivery: None,
+        }
+    }
+}
+
+impl Capabilities {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            learned: Default::default(),
+        }
+    }
+
+    /// The learned capabilities, unless they expired.
+    fn current(&self) -> MutexGuard<'_, Option<Learned>> {
+        let mut learned = self.learned.lock().expect("lock poisoned");
+        if matches!(&*learned, Some(capabilities) if capabilities.at.elapsed() > self.ttl) {
+            *learned = None;
+        }
+        learned
+    }
+
+    /// Updates the learned capabilities, starting over if they expired.
+    fn learn(&self, update: impl FnOnce(&mut Learned)) {
+        let mut learned = self.current();
+        update(learned.get_or_insert_with(Learned::new));
+    }
+
+    /// The `Content-Encoding` of a request, from the configured one and the learned
+    /// capabilities.
+    pub(crate) fn request_encoding(&self, configured: Option<&HeaderValue>) -> Option<HeaderValue> {
+        match &*self.current() {
+            None => configured.cloned(),
+            Some(capabilities) if capabilities.rejects_compression => None,
+            Some(capabilities) => configured.cloned().or_else(|| {
+                capabilities.accepted.first().map(|compression| {
+                    HeaderValue::from_str(&compression.to_string())
+                        .expect("compression names are valid header values; qed")
+                })
+            }),
+        }
+    }
+
+    /// Learns from the response to a request, compressed or not.
+    pub(crate) fn observe(&self, status: StatusCode, headers: &HeaderMap, compressed: bool) {
+        let rejects_compression = compressed && status == StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        let accepted = accepted_encodings(headers);
+        if !rejects_compression && accepted.is_empty() {
+            return;
+        }
+        self.learn(|capabilities| {
+            if rejects_compression {
+                capabilities.rejects_compression = true;
+                capabilities.accepted.clear();
+            } else if !capabilities.rejects_compression {
+                capabilities.accepted = accepted;
+            }
+        });
+    }
+
+    /// Whether the subgraph supports automatic persisted queries, `None` until it is known.
+    pub(crate) fn persisted_queries(&self) -> Option<bool> {
+        self.current()
+            .as_ref()
+            .and_then(|capabilities| capabilities.persisted_queries)
+    }
+
+    /// Learns from the response to a query sent as an automatic persisted query.
+    pub(crate) fn observe_persisted_queries(&self, supported: bool) {
+        self.learn(|capabilities| capabilities.persisted_queries = Some(supported));
+    }
+
+    /// Whether the subgraph answers deferring operations with multipart responses, `None` until it
+    /// is known.
+    pub(crate) fn incremental_delivery(&self) -> Option<bool> {
+        self.current()
+            .as_ref()
+            .and_then(|capabilities| capabilities.incremental_delivery)
+    }
+
+    /// Learns from the response to a deferring operation, multipart or not.
+    pub(crate) fn observe_incremental_delivery(&self, supported: bool) {
+        self.learn(|capabilities| capabilities.incremental_delivery = Some(supported));
+    }
+
+    /// Discards the learned capabilities, after the subgraph could not be reached.
+    pub(crate) fn forget(&self) {
+        *self.learned.lock().expect("lock poisoned") = None;
+    }
+}
+
+/// The supported encodings listed in the `Accept-Encoding` headers, skipping the ones with a
+/// zero quality value.
+fn accepted_encodings(headers: &HeaderMap) -> Vec<Compression> {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|encoding| {
+            let mut parameters = encoding.split(';').map(str::trim);
+            let name = parameters.next()?;
+            let refused = parameters.any(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            if refused {
+                return None;
+            }
+            match name.to_ascii_lowercase().as_str() {
+                "gzip" => Some(Compression::Gzip),
+                "deflate" => Some(Compression::Deflate),
+                "br" => Some(Compression::Br),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepting(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn compression_is_learned_from_the_responses() {
+        let capabilities = Capabilities::new(DEFAULT_CAPABILITIES_TTL);
+        let gzip = HeaderValue::from_static("gzip");
+        assert_eq!(capabilities.request_encoding(None), None);
+        assert_eq!(
+            capabilities.request_encoding(Some(&gzip)),
+            Some(gzip.clone())
+        );
+
+        capabilities.observe(
+            StatusCode::OK,
+            &accepting("identity, br;q=0, deflate;q=0.5, gzip"),
+            false,
+        );
+        assert_eq!(
+            capabilities.request_encoding(None),
+            Some(HeaderValue::from_static("deflate"))
+        );
+
+        capabilities.observe(StatusCode::UNSUPPORTED_MEDIA_TYPE, &HeaderMap::new(), true);
+        assert_eq!(capabilities.request_encoding(None), None);
+        assert_eq!(capabilities.request_encoding(Some(&gzip)), None);
+        capabilities.observe(StatusCode::OK, &accepting("gzip"), false);
+        assert_eq!(capabilities.request_encoding(None), None);
+
+        capabilities.forget();
+        assert_eq!(capabilities.request_encoding(Some(&gzip)), Some(gzip));
+    }
+
+    #[test]
+    fn persisted_queries_and_incremental_delivery_are_learned() {
+        let capabilities = Capabilities::new(DEFAULT_CAPABILITIES_TTL);
+        assert_eq!(capabilities.persisted_queries(), None);
+        assert_eq!(capabilities.incremental_delivery(), None);
+
+        capabilities.observe_persisted_queries(false);
+        capabilities.observe_incremental_delivery(true);
+        capabilities.observe(StatusCode::OK, &accepting("gzip"), false);
+        assert_eq!(capabilities.persisted_queries(), Some(false));
+        assert_eq!(capabilities.incremental_delivery(), Some(true));
+        assert_eq!(
+            capabilities.request_encoding(None),
+            Some(HeaderValue::from_static("gzip"))
+        );
+
+        capabilities.forget();
+        assert_eq!(capabilities.persisted_queries(), None);
+        assert_eq!(capabilities.incremental_delivery(), None);
+    }
+
+    #[test]
+    fn capabilities_expire() {
+        let capabilities = Capabilities::new(Duration::ZERO);
+        capabilities.observe(StatusCode::UNSUPPORTED_MEDIA_TYPE, &HeaderMap::new(), true);
+        std::thread::sleep(Duration::from_millis(1));
+        let gzip = HeaderValue::from_static("gzip");
+        assert_eq!(capabilities.request_encoding(Some(&gzip)), Some(gzip));
+    }
+}
@@ -0,0 +1,402 @@
+//! Tower service resolving the fields of a subgraph in the router.
+//!
+//! The fields of a computed subgraph are declared in its schema and composed into the supergraph
+//! like the fields of any subgraph, but no request is sent for them. Root fields are answered
+//! from the client request, the context or the clock, and entity fields from the other fields of
+//! the entity, sent in its representation because of `@requires`.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::SystemTime;
+
+use apollo_parser::ast;
+use futures::future;
+use serde_json_bytes::ByteString;
+use serde_json_bytes::Value;
+use tower::BoxError;
+
+use crate::configuration::ComputedField;
+use crate::configuration::ComputedSubgraph;
+use crate::error::Error;
+use crate::json_ext::Object;
+use crate::json_ext::Path;
+use crate::query_planner::fetch::OperationKind;
+use crate::Context;
+
+const REPRESENTATIONS: &str = "representations";
+const ENTITIES: &str = "_entities";
+const TYPENAME: &str = "__typename";
+const QUERY: &str = "Query";
+
+/// Resolver of the fields of a computed subgraph.
+#[derive(Clone)]
+pub(crate) struct ComputedService {
+    service: Arc<String>,
+    /// Computed fields by type name, then by field name
+    types: Arc<HashMap<String, HashMap<String, ComputedField>>>,
+}
+
+/// What a computed field is resolved from.
+struct Source<'a> {
+    request: &'a http::Request<crate::graphql::Request>,
+    context: &'a Context,
+    entity: Option<&'a Object>,
+}
+
+impl ComputedService {
+    /// Creates the resolver of a computed subgraph, checking the coordinates of its fields.
+    pub(crate) fn new(
+        service: impl Into<String>,
+        subgraph: &ComputedSubgraph,
+    ) -> Result<Self, BoxError> {
+        let service = service.into();
+        let mut types: HashMap<String, HashMap<String, ComputedField>> = HashMap::new();
+        for (coordinate, field) in &subgraph.fields {
+            let (type_name, field_name) = coordinate
+                .split_once('.')
+                .filter(|(type_name, field_name)| {
+                    !type_name.is_empty() && !field_name.is_empty() && !field_name.contains('.')
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "invalid computed field '{}' of subgraph '{}': expected a coordinate like 'Query.serverTime'",
+                        coordinate, service
+                    )
+                })?;
+            types
+                .entry(type_name.to_string())
+                .or_default()
+                .insert(field_name.to_string(), field.clone());
+        }
+
+        Ok(Self {
+            service: Arc::new(service),
+            types: Arc::new(types),
+        })
+    }
+
+    /// The root fields of the query, by response key.
+    fn root_fields(&self, query: &str, source: &Source, errors: &mut Vec<Error>) -> Object {
+        let mut data = Object::new();
+        let tree = apollo_parser::Parser::new(query).parse();
+        let selection_set = tree
+            .document()
+            .definitions()
+            .find_map(|definition| match definition {
+                ast::Definition::OperationDefinition(operation) => operation.selection_set(),
+                _ => None,
+            });
+        let fields = self.types.get(QUERY);
+        for (key, name) in selection_set.map(response_keys).unwrap_or_default() {
+            let value = if name == TYPENAME {
+                Value::from(QUERY)
+            } else if let Some(field) = fields.and_then(|fields| fields.get(&name)) {
+                field.value(source)
+            } else {
+                errors.push(
+                    Error::builder()
+                        .message(format!(
+                            "field '{}.{}' of subgraph '{}' is not computed",
+                            QUERY, name, self.service
+                        ))
+                        .path(Path::from(key.as_str()))
+                        .build(),
+                );
+                Value::Null
+            };
+            data.insert(ByteString::from(key), value);
+        }
+        data
+    }
+
+    /// The entity of a representation, with all the computed fields of its type.
+    fn entity(&self, representation: &Value, source: &Source) -> Result<Value, String> {
+        let representation = representation
+            .as_object()
+            .ok_or("the representation is not an object")?;
+        let type_name = representation
+            .get(TYPENAME)
+            .and_then(|type_name| type_name.as_str())
+            .ok_or("the representation has no __typename")?;
+        let fields = self
+            .types
+            .get(type_name)
+            .ok_or_else(|| format!("no computed fields for type '{}'", type_name))?;
+
+        let source = Source {
+            entity: Some(representation),
+            ..*source
+        };
+        let mut entity = representation.clone();
+        for (name, field) in fields.iter() {
+            entity.insert(ByteString::from(name.as_str()), field.value(&source));
+        }
+        Ok(Value::Object(entity))
+    }
+}
+
+impl ComputedField {
+    fn value(&self, source: &Source) -> Value {
+        match self {
+            ComputedField::ServerTime => {
+                Value::from(humantime::format_rfc3339_millis(SystemTime::now()).to_string())
+            }
+            ComputedField::Header(name) => source
+                .request
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(Value::from)
+                .unwrap_or_default(),
+            ComputedField::Context(key) => source
+                .context
+                .get_json_value(key.as_str())
+                .unwrap_or_default(),
+            ComputedField::Value(value) => Value::from(value.clone()),
+            ComputedField::Concat { fields, separator } => match source.entity {
+                Some(entity) => Value::from(
+                    fields
+                        .iter()
+                        .filter_map(|field| match entity.get(field.as_str())? {
+                            Value::String(string) => Some(string.as_str().to_string()),
+                            Value::Number(number) => Some(number.to_string()),
+                            Value::Bool(boolean) => Some(boolean.to_string()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>()
+                        .join(separator),
+                ),
+                None => Value::Null,
+            },
+        }
+    }
+}
+
+/// Response keys and names of the fields of a selection set, including the ones of its inline
+/// fragments.
+fn response_keys(selection_set: ast::SelectionSet) -> Vec<(String, String)> {
+    let mut keys = Vec::new();
+    for selection in selection_set.selections() {
+        match selection {
+            ast::Selection::Field(field) => {
+                let name = match field.name() {
+                    Some(name) => name.text().to_string(),
+                    None => continue,
+                };
+                let key = field
+                    .alias()
+                    .and_then(|alias| alias.name())
+                    .map(|alias| alias.text().to_string())
+                    .unwrap_or_else(|| name.clone());
+                keys.push((key, name));
+            }
+            ast::Selection::InlineFragment(fragment) => keys.extend(
+                fragment
+                    .selection_set()
+                    .map(response_keys)
+                    .unwrap_or_default(),
+            ),
+            ast::Selection::FragmentSpread(_) => {}
+        }
+    }
+    keys
+}
+
+impl tower::Service<crate::SubgraphRequest> for ComputedService {
+    type Response = crate::SubgraphResponse;
+    type Error = BoxError;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: crate::SubgraphRequest) -> Self::Future {
+        let crate::SubgraphRequest {
+            originating_request,
+            subgraph_request,
+            operation_kind,
+            context,
+        } = request;
+
+        if operation_kind != OperationKind::Query {
+            return future::ready(
+                crate::SubgraphResponse::error_builder()
+                    .error(
+                        Error::builder()
+                            .message(format!(
+                                "the computed subgraph '{}' only resolves queries",
+                                self.service
+                            ))
+                            .build(),
+                    )
+                    .context(context)
+                    .build(),
+            );
+        }
+
+        let source = Source {
+            request: &originating_request,
+            context: &context,
+            entity: None,
+        };
+        let body = subgraph_request.body();
+        let mut errors = Vec::new();
+        let data = match body.variables.get(REPRESENTATIONS) {
+            Some(Value::Array(representations)) => {
+                let mut entities = Vec::with_capacity(representations.len());
+                for (index, representation) in representations.iter().enumerate() {
+                    match self.entity(representation, &source) {
+                        Ok(entity) => entities.push(entity),
+                        Err(reason) => {
+                            entities.push(Value::Null);
+                            errors.push(
+                                Error::builder()
+                                    .message(format!(
+                                        "could not compute entity of subgraph '{}': {}",
+                                        self.service, reason
+                                    ))
+                                    .path(Path::from(format!("{}/{}", ENTITIES, index)))
+                                    .build(),
+                            );
+                        }
+                    }
+                }
+                let mut object = Object::new();
+                object.insert(ENTITIES, Value::Array(entities));
+                object
+            }
+            _ => self.root_fields(
+                body.query.as_deref().unwrap_or_default(),
+                &source,
+                &mut errors,
+            ),
+        };
+
+        future::ready(Ok(crate::SubgraphResponse::builder()
+            .data(Value::Object(data))
+            .errors(errors)
+            .extensions(Object::new())
+            .context(context)
+            .build()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::graphql::Request;
+    use crate::SubgraphRequest;
+
+    fn service() -> ComputedService {
+        let subgraph: ComputedSubgraph = serde_json::from_value(serde_json::json!({
+            "fields": {
+                "Query.serverTime": "server_time",
+                "Query.requestId": { "header": "x-request-id" },
+                "Query.locale": { "context": "locale" },
+                "Query.version": { "value": "1.2.3" },
+                "User.fullName": { "concat": { "fields": ["firstName", "lastName"], "separator": " " } }
+            }
+        }))
+        .unwrap();
+        ComputedService::new("router", &subgraph).unwrap()
+    }
+
+    fn request(query: &str, representations: Option<Value>) -> SubgraphRequest {
+        let mut body = Request::builder().query(query).build();
+        if let Some(representations) = representations {
+            body.variables.insert(REPRESENTATIONS, representations);
+        }
+        let context = Context::new();
+        context.insert("locale", "fr").unwrap();
+        SubgraphRequest::fake_builder()
+            .originating_request(Arc::new(
+                http::Request::builder()
+                    .header("x-request-id", "abc")
+                    .body(Request::default())
+                    .unwrap(),
+            ))
+            .subgraph_request(http::Request::new(body))
+            .context(context)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn root_fields_are_computed() {
+        let response = service()
+            .oneshot(request(
+                "{ __typename id: requestId locale ... on Query { version } other }",
+                None,
+            ))
+            .await
+            .unwrap()
+            .response
+            .into_body();
+        assert_eq!(
+            response.data,
+            Some(json!({
+                "__typename": "Query",
+                "id": "abc",
+                "locale": "fr",
+                "version": "1.2.3",
+                "other": null
+            }))
+        );
+        assert_eq!(
+            response.errors[0].message,
+            "field 'Query.other' of subgraph 'router' is not computed"
+        );
+
+        let response = service()
+            .oneshot(request("{ serverTime }", None))
+            .await
+            .unwrap()
+            .response
+            .into_body();
+        let data = response.data.unwrap();
+        let server_time = data.as_object().unwrap().get("serverTime").unwrap();
+        assert!(humantime::parse_rfc3339(server_time.as_str().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn entity_fields_are_computed() {
+        let response = service()
+            .oneshot(request(
+                "query($representations:[_Any!]!){_entities(representations:$representations){...on User{fullName}}}",
+                Some(json!([
+                    { "__typename": "User", "id": "1", "firstName": "Ada", "lastName": "Lovelace" },
+                    { "__typename": "Review", "id": "2" }
+                ])),
+            ))
+            .await
+            .unwrap()
+            .response
+            .into_body();
+        assert_eq!(
+            response.data,
+            Some(json!({
+                "_entities": [
+                    { "__typename": "User", "id": "1", "firstName": "Ada", "lastName": "Lovelace", "fullName": "Ada Lovelace" },
+                    null
+                ]
+            }))
+        );
+        assert_eq!(
+            response.errors[0].message,
+            "could not compute entity of subgraph 'router': no computed fields for type 'Review'"
+        );
+    }
+
+    #[test]
+    fn coordinates_are_checked() {
+        let subgraph: ComputedSubgraph = serde_json::from_value(
+            serde_json::json!({ "fields": { "serverTime": "server_time" } }),
+        )
+        .unwrap();
+        assert!(ComputedService::new("router", &subgraph).is_err());
+    }
+}
@@ -0,0 +1,577 @@
+//! Tower service resolving the entities of a subgraph with REST endpoints.
+//!
+//! Entity fetches are answered by calling an endpoint for each representation, with a URL built
+//! from the key fields of the entity, and by mapping the JSON response of the endpoint to the
+//! fields of the entity.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::Poll;
+
+use futures::future;
+use futures::future::BoxFuture;
+use http::header;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::Method;
+use http::StatusCode;
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use opentelemetry::trace::SpanKind;
+use rustls::ClientConfig;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::ServiceExt;
+use tower_http::decompression::Decompression;
+use tracing::Instrument;
+use url::Url;
+
+use super::http_client;
+use crate::configuration::HttpClientOptions;
+use crate::configuration::RestConnector;
+use crate::error::Error;
+use crate::json_ext::Object;
+use crate::json_ext::Path;
+use crate::query_planner::fetch::OperationKind;
+
+const REPRESENTATIONS: &str = "representations";
+const ENTITIES: &str = "_entities";
+
+/// Headers of the subgraph request that are not sent to the endpoints.
+const SKIPPED_HEADERS: [header::HeaderName; 5] = [
+    header::ACCEPT,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_TYPE,
+    header::HOST,
+];
+
+/// Client for the REST endpoints of a subgraph.
+#[derive(Clone)]
+pub(crate) struct ConnectorService {
+    client: Decompression<hyper::Client<HttpsConnector<HttpConnector>>>,
+    service: Arc<String>,
+    entities: Arc<HashMap<String, Entity>>,
+}
+
+struct Entity {
+    url: UrlTemplate,
+    fields: Vec<(String, JsonPath)>,
+}
+
+impl ConnectorService {
+    /// Creates a client for the REST endpoints of a subgraph, checking the URL templates and JSON
+    /// paths of the connector.
+    pub(crate) fn new(
+        service: impl Into<String>,
+        connector: &RestConnector,
+        tls_config: Option<ClientConfig>,
+        options: &HttpClientOptions,
+    ) -> Result<Self, BoxError> {
+        let service = service.into();
+        let mut entities = HashMap::new();
+        for (type_name, entity) in &connector.entities {
+            let invalid = |e: String| {
+                format!(
+                    "invalid REST connector for type '{}' of subgraph '{}': {}",
+                    type_name, service, e
+                )
+            };
+            let url = UrlTemplate::parse(&entity.url).map_err(invalid)?;
+            let fields = entity
+                .fields
+                .iter()
+                .map(|(field, path)| Ok((field.clone(), JsonPath::parse(path)?)))
+                .collect::<Result<_, String>>()
+                .map_err(invalid)?;
+            entities.insert(type_name.clone(), Entity { url, fields });
+        }
+
+        Ok(Self {
+            client: http_client(tls_config, options),
+            service: Arc::new(service),
+            entities: Arc::new(entities),
+        })
+    }
+}
+
+impl tower::Service<crate::SubgraphRequest> for ConnectorService {
+    type Response = crate::SubgraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.client
+            .poll_ready(cx)
+            .map(|res| res.map_err(|e| Box::new(e) as BoxError))
+    }
+
+    fn call(&mut self, request: crate::SubgraphRequest) -> Self::Future {
+        let crate::SubgraphRequest {
+            subgraph_request,
+            operation_kind,
+            context,
+            ..
+        } = request;
+
+        let client = self.client.clone();
+        let service_name = self.service.clone();
+        let entities = self.entities.clone();
+
+        Box::pin(async move {
+            let (parts, body) = subgraph_request.into_parts();
+            let representations = match body.variables.get(REPRESENTATIONS) {
+                Some(Value::Array(representations)) if operation_kind == OperationKind::Query => {
+                    representations
+                }
+                _ => {
+                    return crate::SubgraphResponse::error_builder()
+                        .error(
+                            Error::builder()
+                                .message(format!(
+                                    "the REST connector of subgraph '{}' only resolves entities",
+                                    service_name
+                                ))
+                                .build(),
+                        )
+                        .context(context)
+                        .build();
+                }
+            };
+            let base = Url::parse(&parts.uri.to_string())?;
+
+            let fetches = representations.iter().map(|representation| {
+                fetch_entity(
+                    client.clone(),
+                    &entities,
+                    &base,
+                    &parts.headers,
+                    representation,
+                )
+            });
+            let mut data = Vec::with_capacity(representations.len());
+            let mut errors = Vec::new();
+            for (index, result) in future::join_all(fetches).await.into_iter().enumerate() {
+                match result {
+                    Ok(entity) => data.push(entity),
+                    Err(reason) => {
+                        data.push(Value::Null);
+                        errors.push(
+                            Error::builder()
+                                .message(format!(
+                                    "could not fetch entity from the REST connector of subgraph '{}': {}",
+                                    service_name, reason
+                                ))
+                                .path(Path::from(format!("{}/{}", ENTITIES, index)))
+                                .build(),
+                        );
+                    }
+                }
+            }
+
+            let mut object = Object::new();
+            object.insert(ENTITIES, Value::Array(data));
+            Ok(crate::SubgraphResponse::builder()
+                .data(Value::Object(object))
+                .errors(errors)
+                .extensions(Object::new())
+                .context(context)
+                .build())
+        })
+    }
+}
+
+/// Fetches an entity from its endpoint. Entities not found by the endpoint are null.
+async fn fetch_entity(
+    client: Decompression<hyper::Client<HttpsConnector<HttpConnector>>>,
+    entities: &HashMap<String, Entity>,
+    base: &Url,
+    headers: &HeaderMap,
+    representation: &Value,
+) -> Result<Value, String> {
+    let type_name = representation
+        .as_object()
+        .and_then(|representation| representation.get("__typename"))
+        .and_then(|type_name| type_name.as_str())
+        .ok_or("the representation has no __typename")?;
+    let entity = entities
+        .get(type_name)
+        .ok_or_else(|| format!("no endpoint for type '{}'", type_name))?;
+    let url = base
+        .join(&entity.url.render(representation)?)
+        .map_err(|e| e.to_string())?;
+
+    let mut request = http::Request::builder()
+        .method(Method::GET)
+        .uri(url.as_str())
+        .body(hyper::Body::empty())
+        .map_err(|e| e.to_string())?;
+    for (name, value) in headers {
+        if !SKIPPED_HEADERS.contains(name) {
+            request.headers_mut().append(name, value.clone());
+        }
+    }
+    request
+        .headers_mut()
+        .insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+
+    let response = client
+        .oneshot(request)
+        .instrument(tracing::info_span!("rest_connector_request",
+            "otel.kind" = %SpanKind::Client,
+            "http.url" = %url,
+        ))
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND {
+        return Ok(Value::Null);
+    }
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!(
+            "HTTP status error '{}': {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    let json: Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+    Ok(entity.map(json, representation))
+}
+
+impl Entity {
+    /// The entity built from the response of its endpoint, with the key fields of its
+    /// representation.
+    fn map(&self, json: Value, representation: &Value) -> Value {
+        let mut object = Object::new();
+        for (field, path) in &self.fields {
+            object.insert(
+                field.as_str(),
+                path.get(&json).cloned().unwrap_or(Value::Null),
+            );
+        }
+        if let Value::Object(response) = json {
+            for (field, value) in response {
+                if !object.contains_key(&field) {
+                    object.insert(field, value);
+                }
+            }
+        }
+        if let Some(representation) = representation.as_object() {
+            for (field, value) in representation {
+                object.insert(field.clone(), value.clone());
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    /// Path of a key field in the representation
+    Key(Vec<String>),
+}
+
+/// URL with key fields between braces.
+#[derive(Debug, PartialEq)]
+struct UrlTemplate(Vec<TemplatePart>);
+
+impl UrlTemplate {
+    fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed brace in URL template '{}'", template))?;
+            let key = &rest[start + 1..start + end];
+            if key.is_empty() || key.split('.').any(str::is_empty) {
+                return Err(format!("invalid key '{}' in URL template", key));
+            }
+            parts.push(TemplatePart::Key(
+                key.split('.').map(String::from).collect(),
+            ));
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unopened brace in URL template '{}'", template));
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        Ok(UrlTemplate(parts))
+    }
+
+    /// The URL of an entity, with the percent-encoded values of its key fields.
+    fn render(&self, representation: &Value) -> Result<String, String> {
+        let mut url = String::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Literal(literal) => url.push_str(literal),
+                TemplatePart::Key(path) => {
+                    let value = path
+                        .iter()
+                        .try_fold(representation, |value, key| {
+                            value.as_object()?.get(key.as_str())
+                        })
+                        .ok_or_else(|| {
+                            format!("the representation has no key field '{}'", path.join("."))
+                        })?;
+                    let value = match value {
+                        Value::String(s) => s.as_str().to_string(),
+                        Value::Number(n) => n.to_string(),
+                        Value::Bool(b) => b.to_string(),
+                        _ => {
+                            return Err(format!(
+                                "the key field '{}' is not a scalar",
+                                path.join(".")
+                            ))
+                        }
+                    };
+                    url.push_str(&urlencoding::encode(&value));
+                }
+            }
+        }
+        Ok(url)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// JSON path made of object keys and array indexes, like `$.items[0].name`.
+#[derive(Debug, PartialEq)]
+struct JsonPath(Vec<PathSegment>);
+
+impl JsonPath {
+    fn parse(path: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid JSON path '{}'", path);
+        let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(tail) = rest.strip_prefix('.') {
+                let end = tail
+                    .find(|c: char| c == '.' || c == '[')
+                    .unwrap_or(tail.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+                segments.push(PathSegment::Key(tail[..end].to_string()));
+                rest = &tail[end..];
+            } else if let Some(tail) = rest.strip_prefix('[') {
+                let end = tail.find(']').ok_or_else(invalid)?;
+                let index = tail[..end].parse().map_err(|_| invalid())?;
+                segments.push(PathSegment::Index(index));
+                rest = &tail[end + 1..];
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(JsonPath(segments))
+    }
+
+    fn get<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.0
+            .iter()
+            .try_fold(value, |value, segment| match (segment, value) {
+                (PathSegment::Key(key), Value::Object(object)) => object.get(key.as_str()),
+                (PathSegment::Index(index), Value::Array(array)) => array.get(*index),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use axum::Server;
+    use http::Uri;
+    use hyper::service::make_service_fn;
+    use hyper::Body;
+    use serde_json_bytes::json;
+    use tower::service_fn;
+
+    use super::*;
+    use crate::configuration::RestEntity;
+    use crate::graphql::Request;
+    use crate::Context;
+    use crate::SubgraphRequest;
+
+    #[test]
+    fn url_templates_are_rendered() {
+        let template = UrlTemplate::parse("/stores/{location.id}/products/{upc}?full").unwrap();
+        let representation = json!({
+            "__typename": "Product",
+            "upc": "a b/c",
+            "location": { "id": 12 }
+        });
+        assert_eq!(
+            template.render(&representation).unwrap(),
+            "/stores/12/products/a%20b%2Fc?full"
+        );
+        assert_eq!(
+            template
+                .render(&json!({ "__typename": "Product", "upc": "1" }))
+                .unwrap_err(),
+            "the representation has no key field 'location.id'"
+        );
+
+        assert!(UrlTemplate::parse("/products/{upc").is_err());
+        assert!(UrlTemplate::parse("/products/upc}").is_err());
+        assert!(UrlTemplate::parse("/products/{}").is_err());
+        assert!(UrlTemplate::parse("/products/{a..b}").is_err());
+    }
+
+    #[test]
+    fn json_paths_are_followed() {
+        let json = json!({ "title": "Table", "prices": [{ "amount": 10 }, { "amount": 12 }] });
+        let path = |path| JsonPath::parse(path).unwrap();
+        assert_eq!(path("$").get(&json), Some(&json));
+        assert_eq!(path("$.title").get(&json), Some(&json!("Table")));
+        assert_eq!(path("$.prices[1].amount").get(&json), Some(&json!(12)));
+        assert_eq!(path("$.prices[2].amount").get(&json), None);
+        assert_eq!(path("$.title.amount").get(&json), None);
+
+        assert!(JsonPath::parse("title").is_err());
+        assert!(JsonPath::parse("$..title").is_err());
+        assert!(JsonPath::parse("$.prices[a]").is_err());
+        assert!(JsonPath::parse("$.prices[0").is_err());
+    }
+
+    // starts a local server emulating a REST API of products
+    async fn emulate_rest_api(socket_addr: SocketAddr) {
+        async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            assert_eq!(request.headers().get("x-custom").unwrap(), "value");
+            let body = match request.uri().path() {
+                "/api/products/1" => r#"{"id":"1","title":"Table","pricing":{"amount":10}}"#,
+                "/api/products/3" => {
+                    return Ok(http::Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body("oops".into())
+                        .unwrap())
+                }
+                _ => {
+                    return Ok(http::Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap())
+                }
+            };
+            Ok(http::Response::builder()
+                .header("Content-Type", "application/json")
+                .body(body.into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_entities_are_fetched_from_endpoints() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:3030").unwrap();
+        tokio::task::spawn(emulate_rest_api(socket_addr));
+        let connector = RestConnector {
+            entities: [(
+                "Product".to_string(),
+                RestEntity {
+                    url: "products/{upc}".to_string(),
+                    fields: [
+                        ("name".to_string(), "$.title".to_string()),
+                        ("price".to_string(), "$.pricing.amount".to_string()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                },
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let service =
+            ConnectorService::new("products", &connector, None, &HttpClientOptions::default())
+                .unwrap();
+
+        let url = Uri::from_str(&format!("http://{}/api/", socket_addr)).unwrap();
+        let response = service
+            .oneshot(
+                SubgraphRequest::fake_builder()
+                    .subgraph_request(
+                        http::Request::builder()
+                            .header("x-custom", "value")
+                            .uri(url)
+                            .body(
+                                Request::builder()
+                                    .query("query($representations:[_Any!]!){_entities(representations:$representations){...on Product{name price}}}")
+                                    .variable(
+                                        "representations",
+                                        json!([
+                                            { "__typename": "Product", "upc": "1" },
+                                            { "__typename": "Product", "upc": "2" },
+                                            { "__typename": "Product", "upc": "3" },
+                                            { "__typename": "User", "id": "1" }
+                                        ]),
+                                    )
+                                    .build(),
+                            )
+                            .unwrap(),
+                    )
+                    .context(Context::new())
+                    .build(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.response.into_body();
+        assert_eq!(
+            body.data,
+            Some(json!({
+                "_entities": [
+                    {
+                        "__typename": "Product",
+                        "upc": "1",
+                        "id": "1",
+                        "name": "Table",
+                        "title": "Table",
+                        "price": 10,
+                        "pricing": { "amount": 10 }
+                    },
+                    null,
+                    null,
+                    null
+                ]
+            }))
+        );
+        let errors: Vec<_> = body
+            .errors
+            .iter()
+            .map(|error| {
+                (
+                    error.path.clone().unwrap().to_string(),
+                    error.message.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ("/_entities/2".to_string(), "could not fetch entity from the REST connector of subgraph 'products': HTTP status error '500 Internal Server Error': oops"),
+                ("/_entities/3".to_string(), "could not fetch entity from the REST connector of subgraph 'products': no endpoint for type 'User'"),
+            ]
+        );
+    }
+}
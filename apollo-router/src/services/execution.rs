@@ -199,6 +199,15 @@ impl Response {
         self.map(move |stream| stream.map(f).boxed())
     }
 
+    /// Transforms each error of the responses, the primary one and the incremental ones. Errors
+    /// for which the function returns `None` are removed.
+    pub fn map_errors(self, mut f: impl FnMut(Error) -> Option<Error> + Send + 'static) -> Self {
+        self.map_stream(move |mut response| {
+            response.map_errors(&mut f);
+            response
+        })
+    }
+
     pub async fn next_response(&mut self) -> Option<graphql::Response> {
         self.response.body_mut().next().await
     }
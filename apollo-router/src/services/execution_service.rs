@@ -15,9 +15,12 @@ use tower_service::Service;
 use tracing::Instrument;
 
 use super::layers::allow_only_http_post_mutations::AllowOnlyHttpPostMutationsLayer;
+use super::layers::plugin_timing;
 use super::new_service::NewService;
 use super::subgraph_service::SubgraphServiceFactory;
 use super::Plugins;
+use crate::allocator;
+use crate::allocator::Stage;
 use crate::graphql::Response;
 use crate::services::execution;
 use crate::ExecutionRequest;
@@ -52,7 +55,7 @@ where
 
     fn call(&mut self, req: ExecutionRequest) -> Self::Future {
         let this = self.clone();
-        let fut = async move {
+        let fut = allocator::with_stage(Stage::Execute, async move {
             let context = req.context;
             let ctx = context.clone();
             let (sender, receiver) = futures::channel::mpsc::channel(10);
@@ -76,7 +79,7 @@ where
                 http::Response::new(stream as BoxStream<'static, Response>),
                 ctx,
             ))
-        }
+        })
         .in_current_span();
         Box::pin(fut)
     }
@@ -117,7 +120,11 @@ where
                         subgraph_creator: self.subgraph_creator.clone(),
                     }
                     .boxed(),
-                    |acc, (_, e)| e.execution_service(acc),
+                    |acc, (name, e)| {
+                        plugin_timing::timed(name, "execution", acc, |service| {
+                            e.execution_service(service)
+                        })
+                    },
                 ),
             )
             .boxed()
@@ -0,0 +1,705 @@
+//! The router as a subgraph of another supergraph.
+//!
+//! In subgraph mode, the router answers the `_service` and `_entities` root fields of the
+//! federation subgraph specification, so that its API schema can be composed into a larger
+//! supergraph served by another router. `_service { sdl }` returns the API schema with the keys
+//! of its entities, and the representations of an `_entities` fetch are sent to the subgraphs
+//! resolving the requested fields, in their own `_entities` fetches.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
+use futures::StreamExt;
+use serde_json_bytes::ByteString;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceExt;
+
+use crate::error::Error;
+use crate::graphql;
+use crate::json_ext::Object;
+use crate::json_ext::PathElement;
+use crate::json_ext::ValueExt;
+use crate::query_planner::fetch::FetchNode;
+use crate::query_planner::fetch::OperationKind;
+use crate::query_planner::PlanNode;
+use crate::query_planner::QueryPlan;
+use crate::Context;
+use crate::ExecutionRequest;
+use crate::ExecutionResponse;
+use crate::Schema;
+use crate::SupergraphResponse;
+
+const FEDERATION_LINK: &str =
+    r#"extend schema @link(url: "https://specs.apollo.dev/federation/v2.0", import: ["@key"])"#;
+const SERVICE: &str = "_service";
+const ENTITIES: &str = "_entities";
+const TYPENAME: &str = "__typename";
+
+/// The supergraph seen as a subgraph.
+pub(crate) struct FederatedSubgraph {
+    /// Schema returned by `_service { sdl }`
+    sdl: String,
+    /// Subgraphs with a resolvable key, by entity type
+    entity_subgraphs: HashMap<String, Vec<String>>,
+    /// Subgraphs resolving each field, by type name then field name
+    field_subgraphs: HashMap<String, HashMap<String, Vec<String>>>,
+}
+
+/// A request to the router as a subgraph.
+pub(crate) enum Operation {
+    /// Answered without fetching anything, like `_service { sdl }`
+    Response(graphql::Response),
+    /// Entities fetched from the subgraphs
+    Entities(EntitiesFetch),
+}
+
+/// Entity fetches sent to the subgraphs resolving the requested fields.
+pub(crate) struct EntitiesFetch {
+    plan: Arc<QueryPlan>,
+    /// Representations sent to each subgraph, as variables of the request
+    variables: Object,
+    /// Response key of `_entities` in the request
+    response_key: String,
+    /// Number of representations in the request
+    count: usize,
+    /// Response key of each subgraph fetch, and the indexes of its representations in the
+    /// representations of the request
+    fetches: Vec<(String, Vec<usize>)>,
+}
+
+impl FederatedSubgraph {
+    pub(crate) fn new(schema: &Schema) -> Self {
+        let supergraph = Supergraph::parse(schema.as_string());
+        let mut entity_subgraphs: HashMap<String, Vec<String>> = HashMap::new();
+        for (type_name, keys) in &supergraph.keys {
+            for (_, subgraph) in keys {
+                let subgraphs = entity_subgraphs.entry(type_name.clone()).or_default();
+                if !subgraphs.contains(subgraph) {
+                    subgraphs.push(subgraph.clone());
+                }
+            }
+        }
+        FederatedSubgraph {
+            sdl: sdl(schema.api_schema().as_string(), &supergraph.keys),
+            entity_subgraphs,
+            field_subgraphs: supergraph.fields,
+        }
+    }
+
+    /// Returns the operation of a request querying `_service` or `_entities`, `None` for the
+    /// other requests.
+    pub(crate) fn operation(
+        &self,
+        request: &graphql::Request,
+    ) -> Option<Result<Operation, String>> {
+        let tree = apollo_parser::Parser::new(request.query.as_deref()?).parse();
+        if tree.errors().next().is_some() {
+            return None;
+        }
+        let mut operations =
+            tree.document()
+                .definitions()
+                .filter_map(|definition| match definition {
+                    ast::Definition::OperationDefinition(operation) => Some(operation),
+                    _ => None,
+                });
+        let operation = match request.operation_name.as_deref() {
+            Some(operation_name) => operations.find(|operation| {
+                operation
+                    .name()
+                    .map(|name| name.text() == operation_name)
+                    .unwrap_or_default()
+            }),
+            None => operations.next(),
+        }?;
+        let fields = operation
+            .selection_set()?
+            .selections()
+            .map(|selection| match selection {
+                ast::Selection::Field(field) => Some(field),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let name = |field: &ast::Field| field.name().map(|name| name.text().to_string());
+        if !fields
+            .iter()
+            .any(|field| matches!(name(field).as_deref(), Some(SERVICE) | Some(ENTITIES)))
+        {
+            return None;
+        }
+
+        let mut data = Object::new();
+        let mut entities = None;
+        for field in &fields {
+            let key = response_key(field).unwrap_or_default();
+            match name(field).as_deref() {
+                Some(SERVICE) => {
+                    data.insert(ByteString::from(key), self.service(field));
+                }
+                Some(TYPENAME) => {
+                    data.insert(ByteString::from(key), Value::from("Query"));
+                }
+                Some(ENTITIES) if entities.is_none() => entities = Some(field),
+                Some(ENTITIES) => return Some(Err("_entities can only be queried once".into())),
+                _ => {
+                    return Some(Err(
+                        "_service and _entities can't be queried with other root fields".into(),
+                    ))
+                }
+            }
+        }
+        Some(match entities {
+            Some(field) => self
+                .entities(field, request)
+                .map(Operation::Entities)
+                .map_err(|reason| format!("could not fetch entities: {}", reason)),
+            None => Ok(Operation::Response(
+                graphql::Response::builder()
+                    .data(Value::Object(data))
+                    .build(),
+            )),
+        })
+    }
+
+    fn service(&self, field: &ast::Field) -> Value {
+        let mut service = Object::new();
+        for selection in field
+            .selection_set()
+            .iter()
+            .flat_map(|selection_set| selection_set.selections())
+        {
+            if let ast::Selection::Field(field) = selection {
+                let value = match field.name() {
+                    Some(name) if name.text() == "sdl" => Value::from(self.sdl.as_str()),
+                    Some(name) if name.text() == TYPENAME => Value::from("_Service"),
+                    _ => continue,
+                };
+                service.insert(
+                    ByteString::from(response_key(&field).unwrap_or_default()),
+                    value,
+                );
+            }
+        }
+        Value::Object(service)
+    }
+
+    /// Splits an `_entities` fetch in a fetch for each subgraph resolving some of the requested
+    /// fields, sent the representations of the types it resolves.
+    fn entities(
+        &self,
+        field: &ast::Field,
+        request: &graphql::Request,
+    ) -> Result<EntitiesFetch, String> {
+        let variable = field
+            .arguments()
+            .iter()
+            .flat_map(|arguments| arguments.arguments())
+            .find(|argument| {
+                argument
+                    .name()
+                    .map(|name| name.text() == "representations")
+                    .unwrap_or_default()
+            })
+            .and_then(|argument| match argument.value() {
+                Some(ast::Value::Variable(variable)) => variable.name(),
+                _ => None,
+            })
+            .ok_or("the representations must be a variable")?
+            .text()
+            .to_string();
+        let representations = match request.variables.get(variable.as_str()) {
+            Some(Value::Array(representations)) => representations,
+            _ => return Err("the representations must be a list".into()),
+        };
+
+        // Selections of each type, by subgraph
+        let mut selections: BTreeMap<String, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+        for selection in field
+            .selection_set()
+            .iter()
+            .flat_map(|selection_set| selection_set.selections())
+        {
+            let fragment = match selection {
+                ast::Selection::InlineFragment(fragment) => fragment,
+                // `__typename` is added to the fetch of every type
+                ast::Selection::Field(field)
+                    if field.name().map(|name| name.text() == TYPENAME) == Some(true) =>
+                {
+                    continue
+                }
+                _ => return Err("only inline fragments on entity types are supported".into()),
+            };
+            let type_name = fragment
+                .type_condition()
+                .and_then(|condition| condition.named_type())
+                .and_then(|named_type| named_type.name())
+                .ok_or("only inline fragments on entity types are supported")?
+                .text()
+                .to_string();
+            let entity_subgraphs = self
+                .entity_subgraphs
+                .get(&type_name)
+                .ok_or_else(|| format!("type '{}' is not an entity", type_name))?;
+            for selection in fragment
+                .selection_set()
+                .iter()
+                .flat_map(|selection_set| selection_set.selections())
+            {
+                let field = match selection {
+                    ast::Selection::Field(field) => field,
+                    _ => return Err("only fields are supported in entity fragments".into()),
+                };
+                let name = field.name().ok_or("invalid field")?.text().to_string();
+                let subgraph = if name == TYPENAME {
+                    &entity_subgraphs[0]
+                } else {
+                    self.field_subgraphs
+                        .get(&type_name)
+                        .and_then(|fields| fields.get(&name))
+                        .and_then(|subgraphs| {
+                            subgraphs
+                                .iter()
+                                .find(|subgraph| entity_subgraphs.contains(subgraph))
+                        })
+                        .ok_or_else(|| {
+                            format!(
+                                "field '{}.{}' is not resolved by a subgraph with a key",
+                                type_name, name
+                            )
+                        })?
+                };
+                selections
+                    .entry(subgraph.clone())
+                    .or_default()
+                    .entry(type_name.clone())
+                    .or_default()
+                    .push(field.syntax().to_string());
+            }
+        }
+
+        let mut nodes = Vec::new();
+        let mut variables = Object::new();
+        let mut fetches = Vec::new();
+        for (index, (subgraph, types)) in selections.into_iter().enumerate() {
+            let key = format!("{}{}", ENTITIES, index);
+            let variable = format!("representations{}", index);
+            let (indexes, subgraph_representations): (Vec<usize>, Vec<Value>) = representations
+                .iter()
+                .enumerate()
+                .filter(|(_, representation)| {
+                    representation
+                        .as_object()
+                        .and_then(|representation| representation.get(TYPENAME))
+                        .and_then(|type_name| type_name.as_str())
+                        .map(|type_name| types.contains_key(type_name))
+                        .unwrap_or_default()
+                })
+                .map(|(index, representation)| (index, representation.clone()))
+                .unzip();
+            if subgraph_representations.is_empty() {
+                continue;
+            }
+            let fragments = types
+                .iter()
+                .map(|(type_name, fields)| {
+                    format!("...on {}{{{} {}}}", type_name, TYPENAME, fields.join(" "))
+                })
+                .collect::<String>();
+            nodes.push(PlanNode::Fetch(FetchNode {
+                service_name: subgraph,
+                requires: Vec::new(),
+                variable_usages: vec![variable.clone()],
+                operation: format!(
+                    "query(${0}:[_Any!]!){{{1}:{2}(representations:${0}){{{3}}}}}",
+                    variable, key, ENTITIES, fragments
+                ),
+                operation_name: None,
+                operation_kind: OperationKind::Query,
+                id: None,
+            }));
+            variables.insert(
+                ByteString::from(variable),
+                Value::Array(subgraph_representations),
+            );
+            fetches.push((key, indexes));
+        }
+
+        Ok(EntitiesFetch {
+            plan: Arc::new(QueryPlan::from_root(PlanNode::Parallel { nodes })),
+            variables,
+            response_key: response_key(field).unwrap_or_default(),
+            count: representations.len(),
+            fetches,
+        })
+    }
+}
+
+impl EntitiesFetch {
+    /// Executes the fetches, then merges the entities they returned.
+    pub(crate) async fn execute<S>(
+        mut self,
+        execution: S,
+        mut originating_request: http::Request<graphql::Request>,
+        context: Context,
+    ) -> Result<SupergraphResponse, BoxError>
+    where
+        S: Service<ExecutionRequest, Response = ExecutionResponse, Error = BoxError>,
+    {
+        originating_request
+            .body_mut()
+            .variables
+            .extend(std::mem::take(&mut self.variables));
+        let ExecutionResponse { response, context } = execution
+            .oneshot(
+                ExecutionRequest::builder()
+                    .originating_request(originating_request)
+                    .query_plan(self.plan.clone())
+                    .context(context)
+                    .build(),
+            )
+            .await?;
+        let mut fetched = response.into_body().next().await.unwrap_or_default();
+
+        let mut entities = vec![Value::Null; self.count];
+        let mut data = match fetched.data.take() {
+            Some(Value::Object(data)) => data,
+            _ => Object::new(),
+        };
+        for (key, indexes) in &self.fetches {
+            if let Some(Value::Array(values)) = data.remove(key.as_str()) {
+                for (value, index) in values.into_iter().zip(indexes) {
+                    entities[*index].deep_merge(value);
+                }
+            }
+        }
+        let errors = fetched
+            .errors
+            .into_iter()
+            .map(|error| self.error_in_request(error))
+            .collect::<Vec<_>>();
+
+        let mut data = Object::new();
+        data.insert(
+            ByteString::from(self.response_key.as_str()),
+            Value::Array(entities),
+        );
+        Ok(SupergraphResponse::new_from_graphql_response(
+            graphql::Response::builder()
+                .data(Value::Object(data))
+                .errors(errors)
+                .build(),
+            context,
+        ))
+    }
+
+    /// Moves the path of an error of a subgraph fetch to the matching entity of the request.
+    fn error_in_request(&self, mut error: Error) -> Error {
+        if let Some(path) = &mut error.path {
+            if let [PathElement::Key(key), PathElement::Index(index), ..] = path.0.as_mut_slice() {
+                if let Some((_, indexes)) = self.fetches.iter().find(|(k, _)| *k == *key) {
+                    if let Some(request_index) = indexes.get(*index) {
+                        *key = self.response_key.clone();
+                        *index = *request_index;
+                    }
+                }
+            }
+        }
+        error
+    }
+}
+
+fn response_key(field: &ast::Field) -> Option<String> {
+    field
+        .alias()
+        .and_then(|alias| alias.name())
+        .or_else(|| field.name())
+        .map(|name| name.text().to_string())
+}
+
+/// The API schema with a `@key` directive for each key of its entities.
+fn sdl(api_schema: &str, keys: &BTreeMap<String, Vec<(String, String)>>) -> String {
+    let tree = apollo_parser::Parser::new(api_schema).parse();
+    let mut insertions = Vec::new();
+    for definition in tree.document().definitions() {
+        let name = match definition {
+            ast::Definition::ObjectTypeDefinition(definition) => definition.name(),
+            _ => continue,
+        };
+        let name = match name {
+            Some(name) => name,
+            None => continue,
+        };
+        if let Some(keys) = keys.get(&name.text().to_string()) {
+            let mut directives = String::new();
+            let mut seen = Vec::new();
+            for (key, _) in keys {
+                if !seen.contains(&key) {
+                    seen.push(key);
+                    directives.push_str(&format!(" @key(fields: {:?})", key));
+                }
+            }
+            insertions.push((usize::from(name.syntax().text_range().end()), directives));
+        }
+    }
+
+    let mut sdl = api_schema.to_string();
+    for (position, directives) in insertions.into_iter().rev() {
+        sdl.insert_str(position, &directives);
+    }
+    format!("{}\n\n{}", FEDERATION_LINK, sdl)
+}
+
+/// Keys and fields of the subgraphs, read from the `@join__*` directives of a supergraph.
+#[derive(Default)]
+struct Supergraph {
+    /// Resolvable keys of each entity type, with their subgraph
+    keys: BTreeMap<String, Vec<(String, String)>>,
+    /// Subgraphs resolving each field, by type name then field name
+    fields: HashMap<String, HashMap<String, Vec<String>>>,
+}
+
+impl Supergraph {
+    fn parse(supergraph: &str) -> Self {
+        let tree = apollo_parser::Parser::new(supergraph).parse();
+        let document = tree.document();
+
+        let mut graphs = HashMap::new();
+        for definition in document.definitions() {
+            if let ast::Definition::EnumTypeDefinition(definition) = definition {
+                if definition.name().map(|n| n.text() == "join__Graph") != Some(true) {
+                    continue;
+                }
+                for value in definition
+                    .enum_values_definition()
+                    .iter()
+                    .flat_map(|values| values.enum_value_definitions())
+                {
+                    let graph = value.enum_value().and_then(|v| v.name());
+                    let name = directives(value.directives(), "join__graph")
+                        .into_iter()
+                        .find_map(|arguments| string(arguments.get("name")?));
+                    if let (Some(graph), Some(name)) = (graph, name) {
+                        graphs.insert(graph.text().to_string(), name);
+                    }
+                }
+            }
+        }
+        let graph = |value: Option<&ast::Value>| match value {
+            Some(ast::Value::EnumValue(value)) => value
+                .name()
+                .and_then(|name| graphs.get(&name.text().to_string()).cloned()),
+            _ => None,
+        };
+
+        let mut supergraph = Supergraph::default();
+        for definition in document.definitions() {
+            let (name, type_directives, fields) = match definition {
+                ast::Definition::ObjectTypeDefinition(d) => {
+                    (d.name(), d.directives(), d.fields_definition())
+                }
+                ast::Definition::ObjectTypeExtension(d) => {
+                    (d.name(), d.directives(), d.fields_definition())
+                }
+                _ => continue,
+            };
+            let type_name = match name {
+                Some(name) => name.text().to_string(),
+                None => continue,
+            };
+
+            let mut type_subgraphs = Vec::new();
+            for arguments in directives(type_directives.clone(), "join__type") {
+                let subgraph = match graph(arguments.get("graph")) {
+                    Some(subgraph) => subgraph,
+                    None => continue,
+                };
+                let resolvable = match arguments.get("resolvable") {
+                    Some(ast::Value::BooleanValue(b)) => b.false_token().is_none(),
+                    _ => true,
+                };
+                if let Some(key) = arguments.get("key").and_then(string) {
+                    if resolvable {
+                        supergraph
+                            .keys
+                            .entry(type_name.clone())
+                            .or_default()
+                            .push((key, subgraph.clone()));
+                    }
+                }
+                type_subgraphs.push(subgraph);
+            }
+            // Federation 1 supergraphs have an owner for the fields without `@join__field`
+            let owner = directives(type_directives, "join__owner")
+                .into_iter()
+                .find_map(|arguments| graph(arguments.get("graph")));
+
+            let type_fields = supergraph.fields.entry(type_name).or_default();
+            for field in fields.iter().flat_map(|fields| fields.field_definitions()) {
+                let field_name = match field.name() {
+                    Some(name) => name.text().to_string(),
+                    None => continue,
+                };
+                let join_fields = directives(field.directives(), "join__field");
+                let subgraphs = if join_fields
+                    .iter()
+                    .any(|arguments| arguments.contains_key("graph"))
+                {
+                    join_fields
+                        .iter()
+                        .filter(|arguments| match arguments.get("external") {
+                            Some(ast::Value::BooleanValue(b)) => b.true_token().is_none(),
+                            _ => true,
+                        })
+                        .filter_map(|arguments| graph(arguments.get("graph")))
+                        .collect()
+                } else {
+                    owner
+                        .clone()
+                        .map(|owner| vec![owner])
+                        .unwrap_or_else(|| type_subgraphs.clone())
+                };
+                type_fields.insert(field_name, subgraphs);
+            }
+        }
+        supergraph
+    }
+}
+
+/// Arguments of each use of a directive.
+fn directives(directives: Option<ast::Directives>, name: &str) -> Vec<HashMap<String, ast::Value>> {
+    directives
+        .iter()
+        .flat_map(|directives| directives.directives())
+        .filter(|directive| directive.name().map(|n| n.text() == name) == Some(true))
+        .map(|directive| {
+            directive
+                .arguments()
+                .iter()
+                .flat_map(|arguments| arguments.arguments())
+                .filter_map(|argument| {
+                    Some((argument.name()?.text().to_string(), argument.value()?))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn string(value: &ast::Value) -> Option<String> {
+    match value {
+        ast::Value::StringValue(value) => Some(String::from(value.clone())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::json_ext::Path;
+
+    const SCHEMA: &str = include_str!("../testdata/supergraph.graphql");
+
+    fn subgraph() -> FederatedSubgraph {
+        FederatedSubgraph::new(&Schema::parse(SCHEMA, &Default::default()).unwrap())
+    }
+
+    fn operation(query: &str, variables: Value) -> Option<Result<Operation, String>> {
+        let mut request = graphql::Request::builder().query(query).build();
+        if let Value::Object(variables) = variables {
+            request.variables = variables;
+        }
+        subgraph().operation(&request)
+    }
+
+    #[test]
+    fn the_sdl_has_the_keys_of_entities() {
+        let response = match operation("{ _service { sdl } }", Value::Null) {
+            Some(Ok(Operation::Response(response))) => response,
+            _ => panic!("expected a response"),
+        };
+        let sdl = response.data.unwrap()["_service"]["sdl"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(sdl.starts_with(FEDERATION_LINK));
+        assert!(sdl.contains(r#"type Product @key(fields: "upc") {"#));
+        assert!(sdl.contains(r#"type User @key(fields: "id") {"#));
+        assert!(!sdl.contains("join__"));
+
+        assert!(operation("{ me { name } }", Value::Null).is_none());
+        assert!(matches!(
+            operation("{ _service { sdl } me { name } }", Value::Null),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn entity_fetches_are_split_by_subgraph() {
+        let fetch = match operation(
+            "query($representations:[_Any!]!){_entities(representations:$representations){...on User{name username} ...on Product{name}}}",
+            json!({
+                "representations": [
+                    { "__typename": "Product", "upc": "1" },
+                    { "__typename": "User", "id": "2" },
+                    { "__typename": "Product", "upc": "3" }
+                ]
+            }),
+        ) {
+            Some(Ok(Operation::Entities(fetch))) => fetch,
+            _ => panic!("expected an entity fetch"),
+        };
+        assert_eq!(fetch.count, 3);
+        assert_eq!(
+            fetch.fetches,
+            vec![
+                ("_entities0".to_string(), vec![1]),
+                ("_entities1".to_string(), vec![0, 2]),
+            ]
+        );
+        let services = match &fetch.plan.root {
+            PlanNode::Parallel { nodes } => nodes
+                .iter()
+                .map(|node| match node {
+                    PlanNode::Fetch(fetch) => {
+                        (fetch.service_name.as_str(), fetch.operation.as_str())
+                    }
+                    _ => panic!("expected fetches"),
+                })
+                .collect::<Vec<_>>(),
+            _ => panic!("expected parallel fetches"),
+        };
+        assert_eq!(
+            services,
+            vec![
+                ("accounts", "query($representations0:[_Any!]!){_entities0:_entities(representations:$representations0){...on User{__typename name username}}}"),
+                ("products", "query($representations1:[_Any!]!){_entities1:_entities(representations:$representations1){...on Product{__typename name}}}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_are_moved_to_the_entities_of_the_request() {
+        let fetch = EntitiesFetch {
+            plan: Arc::new(QueryPlan::from_root(PlanNode::Parallel {
+                nodes: Vec::new(),
+            })),
+            variables: Object::new(),
+            response_key: ENTITIES.to_string(),
+            count: 3,
+            fetches: vec![("_entities0".to_string(), vec![0, 2])],
+        };
+        let error = fetch.error_in_request(
+            Error::builder()
+                .message("oops")
+                .path(Path::from("_entities0/1/name"))
+                .build(),
+        );
+        assert_eq!(error.path, Some(Path::from("_entities/2/name")));
+    }
+}
@@ -0,0 +1,332 @@
+//! File uploads following the GraphQL multipart request specification.
+//!
+//! A `multipart/form-data` request holds the GraphQL request in an `operations` field, then a `map`
+//! field listing the variables each file is for, then the files. The router reads the first two
+//! fields and executes the operation, the variables of the files being `null`. The files are not
+//! read yet: the subgraph request holding these variables is sent as a `multipart/form-data`
+//! request too, and the files are streamed to it from the client request.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use http::HeaderMap;
+use http::HeaderValue;
+use http::StatusCode;
+use hyper::Body;
+use multer::Constraints;
+use multer::Multipart;
+use multer::SizeLimit;
+use tower::BoxError;
+
+use crate::configuration::FileUploads;
+use crate::graphql;
+use crate::json_ext::Object;
+
+const OPERATIONS_FIELD: &str = "operations";
+const MAP_FIELD: &str = "map";
+
+/// The files of a client request, not read yet.
+///
+/// They are stored in the extensions of the originating request.
+#[derive(Clone)]
+pub(crate) struct Uploads {
+    boundary: String,
+    /// Name of the field of each file, and the paths of the variables it is for
+    map: BTreeMap<String, Vec<String>>,
+    /// Taken by the subgraph request the files are streamed to
+    multipart: Arc<Mutex<Option<Multipart<'static>>>>,
+}
+
+/// Whether the request is a `multipart/form-data` request, returning its boundary.
+pub(crate) fn multipart_boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(http::header::CONTENT_TYPE)?.to_str().ok()?;
+    if !content_type.starts_with("multipart/form-data") {
+        return None;
+    }
+    multer::parse_boundary(content_type).ok()
+}
+
+/// Reads the GraphQL request of a `multipart/form-data` request, leaving the files in the body.
+pub(crate) async fn read_operations(
+    body: Body,
+    boundary: String,
+    config: &FileUploads,
+    max_request_body_size: Option<usize>,
+) -> Result<(graphql::Request, Uploads), (StatusCode, String)> {
+    let mut size_limit = SizeLimit::new();
+    if let Some(max_size) = max_request_body_size {
+        size_limit = size_limit
+            .for_field(OPERATIONS_FIELD, max_size as u64)
+            .for_field(MAP_FIELD, max_size as u64);
+    }
+    if let Some(max_file_size) = config.max_file_size {
+        size_limit = size_limit.per_field(max_file_size);
+    }
+    let mut multipart = Multipart::with_constraints(
+        body,
+        boundary.clone(),
+        Constraints::new().size_limit(size_limit),
+    );
+
+    let operations = read_field(&mut multipart, OPERATIONS_FIELD).await?;
+    let request = serde_json::from_slice::<graphql::Request>(&operations).map_err(|err| {
+        bad_request(format!(
+            "invalid GraphQL request in the `{OPERATIONS_FIELD}` field: {err}"
+        ))
+    })?;
+    let map = read_field(&mut multipart, MAP_FIELD).await?;
+    let map = serde_json::from_slice::<BTreeMap<String, Vec<String>>>(&map)
+        .map_err(|err| bad_request(format!("invalid `{MAP_FIELD}` field: {err}")))?;
+
+    if map.len() > config.max_files {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "the request has {} files, the maximum is {}",
+                map.len(),
+                config.max_files
+            ),
+        ));
+    }
+    if let Some(path) = map
+        .values()
+        .flatten()
+        .find(|path| variable_name(path).is_none())
+    {
+        return Err(bad_request(format!(
+            "files can only be sent as variables, not at `{path}`"
+        )));
+    }
+
+    Ok((
+        request,
+        Uploads {
+            boundary,
+            map,
+            multipart: Arc::new(Mutex::new(Some(multipart))),
+        },
+    ))
+}
+
+/// Reads one of the fields preceding the files.
+async fn read_field(
+    multipart: &mut Multipart<'static>,
+    name: &'static str,
+) -> Result<Bytes, (StatusCode, String)> {
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|err| bad_request(format!("invalid multipart request: {err}")))?
+        .filter(|field| field.name() == Some(name))
+        .ok_or_else(|| {
+            bad_request(format!(
+                "the multipart request must start with the `{OPERATIONS_FIELD}` and `{MAP_FIELD}` fields"
+            ))
+        })?;
+    field
+        .bytes()
+        .await
+        .map_err(|err| bad_request(format!("cannot read the `{name}` field: {err}")))
+}
+
+fn bad_request(message: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message)
+}
+
+/// The variable of a path of the `map` field, like `file` for `variables.file` or
+/// `variables.files.0`.
+fn variable_name(path: &str) -> Option<&str> {
+    let mut segments = path.split('.');
+    (segments.next() == Some("variables"))
+        .then(|| segments.next())
+        .flatten()
+        .filter(|name| !name.is_empty())
+}
+
+impl Uploads {
+    /// The files of the variables of a subgraph request, with the paths of these variables.
+    pub(crate) fn for_variables(&self, variables: &Object) -> BTreeMap<String, Vec<String>> {
+        self.map
+            .iter()
+            .filter_map(|(name, paths)| {
+                let paths: Vec<String> = paths
+                    .iter()
+                    .filter(|path| {
+                        variable_name(path).map_or(false, |name| variables.contains_key(name))
+                    })
+                    .cloned()
+                    .collect();
+                (!paths.is_empty()).then(|| (name.clone(), paths))
+            })
+            .collect()
+    }
+
+    /// The `multipart/form-data` body of a subgraph request, streaming the files of the client
+    /// request. The files not listed in `map` are skipped.
+    ///
+    /// The files are streamed once: they can't be sent to a second subgraph request.
+    pub(crate) fn body(
+        &self,
+        operations: String,
+        map: BTreeMap<String, Vec<String>>,
+    ) -> Result<(HeaderValue, Body), BoxError> {
+        let mut multipart = self
+            .multipart
+            .lock()
+            .expect("lock poisoned")
+            .take()
+            .ok_or("the files of the request were already sent to another subgraph")?;
+        let boundary = self.boundary.clone();
+        let content_type =
+            HeaderValue::from_str(&format!("multipart/form-data; boundary={boundary}"))?;
+        let names: Vec<String> = map.keys().cloned().collect();
+        let map = serde_json::to_string(&map)?;
+
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let result: Result<(), BoxError> = async {
+                for (name, value) in [(OPERATIONS_FIELD, operations), (MAP_FIELD, map)] {
+                    sender
+                        .send_data(Bytes::from(format!(
+                            "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+                        )))
+                        .await?;
+                }
+                while let Some(mut field) = multipart.next_field().await? {
+                    let name = field.name().unwrap_or_default().to_string();
+                    if !names.contains(&name) {
+                        while field.chunk().await?.is_some() {}
+                        continue;
+                    }
+                    let file_name = field
+                        .file_name()
+                        .map(|file_name| {
+                            format!("; filename=\"{}\"", file_name.replace('"', "%22"))
+                        })
+                        .unwrap_or_default();
+                    let content_type = field
+                        .content_type()
+                        .map(ToString::to_string)
+                        .unwrap_or_else(|| "application/octet-stream".to_string());
+                    sender
+                        .send_data(Bytes::from(format!(
+                            "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"{file_name}\r\nContent-Type: {content_type}\r\n\r\n"
+                        )))
+                        .await?;
+                    while let Some(chunk) = field.chunk().await? {
+                        sender.send_data(chunk).await?;
+                    }
+                    sender.send_data(Bytes::from_static(b"\r\n")).await?;
+                }
+                sender
+                    .send_data(Bytes::from(format!("--{boundary}--\r\n")))
+                    .await?;
+                Ok(())
+            }
+            .await;
+            if let Err(err) = result {
+                tracing::error!("cannot stream the files of the request to the subgraph: {err}");
+                sender.abort();
+            }
+        });
+        Ok((content_type, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    const BOUNDARY: &str = "XXX";
+
+    fn client_request() -> Body {
+        Body::from(
+            "--XXX\r\n\
+            Content-Disposition: form-data; name=\"operations\"\r\n\r\n\
+            {\"query\":\"mutation($avatar: Upload, $doc: Upload) { a(file: $avatar) b(file: $doc) }\",\"variables\":{\"avatar\":null,\"doc\":null}}\r\n\
+            --XXX\r\n\
+            Content-Disposition: form-data; name=\"map\"\r\n\r\n\
+            {\"0\":[\"variables.avatar\"],\"1\":[\"variables.doc\"]}\r\n\
+            --XXX\r\n\
+            Content-Disposition: form-data; name=\"0\"; filename=\"avatar.png\"\r\n\
+            Content-Type: image/png\r\n\r\n\
+            PNG\r\n\
+            --XXX\r\n\
+            Content-Disposition: form-data; name=\"1\"; filename=\"doc.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            TXT\r\n\
+            --XXX--\r\n",
+        )
+    }
+
+    #[tokio::test]
+    async fn files_are_streamed_to_the_subgraph() {
+        let (request, uploads) = read_operations(
+            client_request(),
+            BOUNDARY.to_string(),
+            &FileUploads::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            request.variables.get("avatar"),
+            Some(&serde_json_bytes::Value::Null)
+        );
+
+        let variables = json!({ "avatar": null }).as_object().unwrap().clone();
+        let map = uploads.for_variables(&variables);
+        assert_eq!(map.len(), 1);
+        let (content_type, body) = uploads
+            .body("{\"query\":\"mutation\"}".to_string(), map.clone())
+            .unwrap();
+        assert_eq!(content_type, "multipart/form-data; boundary=XXX");
+
+        let mut subgraph_request = Multipart::new(body, BOUNDARY);
+        let mut fields = Vec::new();
+        while let Some(field) = subgraph_request.next_field().await.unwrap() {
+            let name = field.name().unwrap().to_string();
+            let file_name = field.file_name().map(ToString::to_string);
+            fields.push((name, file_name, field.text().await.unwrap()));
+        }
+        assert_eq!(
+            fields,
+            vec![
+                (
+                    "operations".to_string(),
+                    None,
+                    "{\"query\":\"mutation\"}".to_string()
+                ),
+                (
+                    "map".to_string(),
+                    None,
+                    "{\"0\":[\"variables.avatar\"]}".to_string()
+                ),
+                (
+                    "0".to_string(),
+                    Some("avatar.png".to_string()),
+                    "PNG".to_string()
+                ),
+            ]
+        );
+        assert!(uploads.body(String::new(), map).is_err());
+    }
+
+    #[tokio::test]
+    async fn too_many_files_are_rejected() {
+        let config = FileUploads {
+            enabled: true,
+            max_files: 1,
+            max_file_size: None,
+        };
+        let error = read_operations(client_request(), BOUNDARY.to_string(), &config, None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,198 @@
+//! Shape the GraphQL errors of a [`SupergraphResponse`] in the configured format.
+//!
+//! See [`Layer`] and [`Service`] for more details.
+//!
+//! Errors are left in the router's format by default, or written like Apollo Server 3 did, with an
+//! `extensions.exception` object. Clients can pick the format of their responses with an
+//! `errorFormat` parameter in their `Accept` header, if negotiation is enabled.
+
+use std::task::Poll;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::StreamExt;
+use http::header::ACCEPT;
+use http::HeaderMap;
+use mediatype::MediaTypeList;
+use mediatype::ReadParams;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::Layer;
+use tower::Service;
+
+use crate::configuration::ErrorFormat;
+use crate::configuration::ErrorStyle;
+use crate::graphql::Error;
+use crate::graphql::Response;
+use crate::json_ext::Object;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+const ERROR_FORMAT_PARAMETER: &str = "errorFormat";
+const DEFAULT_CODE: &str = "INTERNAL_SERVER_ERROR";
+
+#[derive(Clone)]
+pub(crate) struct ErrorFormatLayer {
+    config: ErrorFormat,
+}
+
+impl ErrorFormatLayer {
+    pub(crate) fn new(config: ErrorFormat) -> Self {
+        Self { config }
+    }
+}
+
+impl<S> Layer<S> for ErrorFormatLayer {
+    type Service = ErrorFormatService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ErrorFormatService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+pub(crate) struct ErrorFormatService<S> {
+    inner: S,
+    config: ErrorFormat,
+}
+
+impl<S> Service<SupergraphRequest> for ErrorFormatService<S>
+where
+    S: Service<SupergraphRequest, Response = SupergraphResponse, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = SupergraphResponse;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<SupergraphResponse, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: SupergraphRequest) -> Self::Future {
+        let style = if self.config.negotiate {
+            negotiated_style(req.originating_request.headers())
+        } else {
+            None
+        }
+        .unwrap_or(self.config.default);
+        let future = self.inner.call(req);
+        if style == ErrorStyle::Modern {
+            return future.boxed();
+        }
+        future
+            .map(|result| {
+                result.map(|response| {
+                    response.map(|stream| {
+                        stream
+                            .map(|mut response| {
+                                to_apollo_server3(&mut response);
+                                response
+                            })
+                            .boxed()
+                    })
+                })
+            })
+            .boxed()
+    }
+}
+
+/// The error format asked for in the `Accept` header, if any.
+fn negotiated_style(headers: &HeaderMap) -> Option<ErrorStyle> {
+    let name = mediatype::Name::new(ERROR_FORMAT_PARAMETER).expect("valid name");
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(MediaTypeList::new)
+        .filter_map(Result::ok)
+        .find_map(|mime| match mime.get_param(name)?.as_str() {
+            "modern" => Some(ErrorStyle::Modern),
+            "apollo_server3" => Some(ErrorStyle::ApolloServer3),
+            _ => None,
+        })
+}
+
+fn to_apollo_server3(response: &mut Response) {
+    let incremental_errors = response
+        .incremental
+        .iter_mut()
+        .flat_map(|incremental| incremental.errors.iter_mut());
+    for error in response.errors.iter_mut().chain(incremental_errors) {
+        error_to_apollo_server3(error);
+    }
+}
+
+/// Every error gets a code and an `exception` object, holding the stack trace if there is one.
+fn error_to_apollo_server3(error: &mut Error) {
+    let extensions = &mut error.extensions;
+    if !extensions.contains_key("code") {
+        extensions.insert("code", Value::String(DEFAULT_CODE.into()));
+    }
+    let stacktrace = extensions.remove("stacktrace");
+    let exception = extensions
+        .entry("exception")
+        .or_insert_with(|| Value::Object(Object::new()));
+    if let (Value::Object(exception), Some(stacktrace)) = (exception, stacktrace) {
+        exception.insert("stacktrace", stacktrace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn error(extensions: Value) -> Error {
+        Error {
+            message: "PersistedQueryNotFound".to_string(),
+            extensions: extensions.as_object().cloned().unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn errors_are_written_like_apollo_server3() {
+        let as3 = json!({
+            "code": "PERSISTED_QUERY_NOT_FOUND",
+            "exception": {
+                "stacktrace": ["PersistedQueryNotFoundError: PersistedQueryNotFound"],
+            },
+        });
+
+        let mut legacy = error(as3.clone());
+        error_to_apollo_server3(&mut legacy);
+        assert_eq!(Value::Object(legacy.extensions), as3);
+
+        let mut legacy = error(json!({
+            "code": "PERSISTED_QUERY_NOT_FOUND",
+            "stacktrace": ["PersistedQueryNotFoundError: PersistedQueryNotFound"],
+        }));
+        error_to_apollo_server3(&mut legacy);
+        assert_eq!(Value::Object(legacy.extensions), as3);
+
+        let mut legacy = error(Value::Null);
+        error_to_apollo_server3(&mut legacy);
+        assert_eq!(
+            Value::Object(legacy.extensions),
+            json!({ "code": "INTERNAL_SERVER_ERROR", "exception": {} })
+        );
+    }
+
+    #[test]
+    fn the_format_is_negotiated() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(negotiated_style(&headers), None);
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static(
+                "multipart/mixed;deferSpec=20220824, application/json;errorFormat=apollo_server3",
+            ),
+        );
+        assert_eq!(negotiated_style(&headers), Some(ErrorStyle::ApolloServer3));
+    }
+}
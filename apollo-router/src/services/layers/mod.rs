@@ -2,3 +2,6 @@
 pub(crate) mod allow_only_http_post_mutations;
 pub(crate) mod apq;
 pub(crate) mod ensure_query_presence;
+pub(crate) mod error_format;
+pub(crate) mod plugin_isolation;
+pub(crate) mod plugin_timing;
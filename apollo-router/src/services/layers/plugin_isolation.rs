@@ -0,0 +1,448 @@
+//! Isolation of misbehaving plugins.
+//!
+//! An isolated plugin is wrapped so that the services it returns time out when the plugin
+//! itself, not the services it wraps, takes too long. After repeated failures, its circuit
+//! opens: for a while, requests either bypass the plugin or fail right away, depending on the
+//! configuration, so that a plugin calling an unavailable dependency does not slow down every
+//! request.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context as TaskContext;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use tower::util::BoxService;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceExt;
+
+use crate::configuration::CircuitPolicy;
+use crate::configuration::IsolatedPlugin;
+use crate::plugin::DynPlugin;
+use crate::plugin::ResponseScanner;
+use crate::services::execution;
+use crate::services::router;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::services::transport;
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_OPEN_DURATION: Duration = Duration::from_secs(30);
+
+/// A plugin whose services are isolated from the rest of the pipeline.
+pub(crate) struct Isolated {
+    name: String,
+    plugin: Box<dyn DynPlugin>,
+    timeout: Option<Duration>,
+    on_open: CircuitPolicy,
+    circuit: Arc<Circuit>,
+}
+
+impl Isolated {
+    pub(crate) fn new(name: &str, plugin: Box<dyn DynPlugin>, config: &IsolatedPlugin) -> Self {
+        Isolated {
+            name: name.to_string(),
+            plugin,
+            timeout: config.timeout,
+            on_open: config.on_open,
+            circuit: Arc::new(Circuit::new(
+                name,
+                config
+                    .failure_threshold
+                    .unwrap_or(DEFAULT_FAILURE_THRESHOLD),
+                config.open_duration.unwrap_or(DEFAULT_OPEN_DURATION),
+            )),
+        }
+    }
+
+    fn isolate<Request, Response>(
+        &self,
+        service: BoxService<Request, Response, BoxError>,
+        hook: impl FnOnce(
+            BoxService<Request, Response, BoxError>,
+        ) -> BoxService<Request, Response, BoxError>,
+    ) -> BoxService<Request, Response, BoxError>
+    where
+        Request: Send + 'static,
+        Response: Send + 'static,
+    {
+        let wrapped = Arc::new(tokio::sync::Mutex::new(service));
+        let clock = Arc::new(Mutex::new(Clock::default()));
+        let plugin_service = hook(
+            Wrapped {
+                service: wrapped.clone(),
+                clock: clock.clone(),
+            }
+            .boxed(),
+        );
+        IsolatedService {
+            plugin: self.name.clone(),
+            plugin_service,
+            wrapped,
+            clock,
+            timeout: self.timeout,
+            on_open: self.on_open,
+            circuit: self.circuit.clone(),
+        }
+        .boxed()
+    }
+}
+
+impl DynPlugin for Isolated {
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        self.isolate(service, |service| self.plugin.router_service(service))
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        self.isolate(service, |service| self.plugin.supergraph_service(service))
+    }
+
+    fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+        self.isolate(service, |service| self.plugin.execution_service(service))
+    }
+
+    fn subgraph_service(&self, name: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+        self.isolate(service, |service| {
+            self.plugin.subgraph_service(name, service)
+        })
+    }
+
+    fn custom_endpoint(&self) -> Option<transport::BoxService> {
+        self.plugin.custom_endpoint()
+    }
+
+    fn admin_endpoint(&self) -> Option<transport::BoxService> {
+        self.plugin.admin_endpoint()
+    }
+
+    fn response_scanner(&self) -> Option<Arc<dyn ResponseScanner>> {
+        self.plugin.response_scanner()
+    }
+
+    fn name(&self) -> &'static str {
+        self.plugin.name()
+    }
+}
+
+/// Consecutive failures of a plugin, shared by all its services.
+struct Circuit {
+    plugin: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Circuit {
+    fn new(plugin: &str, failure_threshold: u32, open_duration: Duration) -> Self {
+        Circuit {
+            plugin: plugin.to_string(),
+            failure_threshold,
+            open_duration,
+            state: Default::default(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        let state = self.state.lock().expect("the lock is not poisoned; qed");
+        matches!(state.open_until, Some(open_until) if open_until > Instant::now())
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().expect("the lock is not poisoned; qed");
+        if !failed {
+            if state.open_until.take().is_some() {
+                tracing::info!("plugin {} recovered, its circuit is closed", self.plugin);
+            }
+            state.failures = 0;
+            return;
+        }
+        state.failures += 1;
+        if state.failures >= self.failure_threshold {
+            if state.open_until.is_none() {
+                tracing::warn!(
+                    "plugin {} failed {} times in a row, its circuit is open for {:?}",
+                    self.plugin,
+                    state.failures,
+                    self.open_duration
+                );
+            }
+            state.open_until = Some(Instant::now() + self.open_duration);
+        }
+    }
+}
+
+/// Time spent in the services wrapped by the plugin, during the current call.
+///
+/// Services are called once per request at each stage, so a single clock per service is
+/// enough.
+#[derive(Default)]
+struct Clock {
+    spent: Duration,
+    running_since: Option<Instant>,
+    failed: bool,
+}
+
+impl Clock {
+    /// Time spent in the plugin since the start of the call.
+    fn plugin_time(&self, start: Instant) -> Duration {
+        let wrapped = self.spent
+            + self
+                .running_since
+                .map(|since| since.elapsed())
+                .unwrap_or_default();
+        start.elapsed().saturating_sub(wrapped)
+    }
+}
+
+/// The services wrapped by the plugin, timed and shared with the bypass.
+struct Wrapped<S> {
+    service: Arc<tokio::sync::Mutex<S>>,
+    clock: Arc<Mutex<Clock>>,
+}
+
+impl<S, Request> Service<Request> for Wrapped<S>
+where
+    S: Service<Request, Error = BoxError> + Send + 'static,
+    S::Future: Send + 'static,
+    Request: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<S::Response, BoxError>>;
+
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let service = self.service.clone();
+        let clock = self.clock.clone();
+        Box::pin(async move {
+            let mut service = service.lock_owned().await;
+            clock
+                .lock()
+                .expect("the lock is not poisoned; qed")
+                .running_since = Some(Instant::now());
+            let result = service.ready().await?.call(req).await;
+            let mut clock = clock.lock().expect("the lock is not poisoned; qed");
+            if let Some(since) = clock.running_since.take() {
+                clock.spent += since.elapsed();
+            }
+            clock.failed |= result.is_err();
+            result
+        })
+    }
+}
+
+struct IsolatedService<Request, Response> {
+    plugin: String,
+    plugin_service: BoxService<Request, Response, BoxError>,
+    wrapped: Arc<tokio::sync::Mutex<BoxService<Request, Response, BoxError>>>,
+    clock: Arc<Mutex<Clock>>,
+    timeout: Option<Duration>,
+    on_open: CircuitPolicy,
+    circuit: Arc<Circuit>,
+}
+
+impl<Request, Response> Service<Request> for IsolatedService<Request, Response>
+where
+    Request: Send + 'static,
+    Response: Send + 'static,
+{
+    type Response = Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Response, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.plugin_service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        if self.circuit.is_open() {
+            return match self.on_open {
+                CircuitPolicy::Bypass => {
+                    let wrapped = self.wrapped.clone();
+                    Box::pin(async move {
+                        let mut service = wrapped.lock_owned().await;
+                        service.ready().await?.call(req).await
+                    })
+                }
+                CircuitPolicy::FailClosed => {
+                    let error = format!("plugin {} is unavailable", self.plugin);
+                    Box::pin(async move { Err(BoxError::from(error)) })
+                }
+            };
+        }
+
+        *self.clock.lock().expect("the lock is not poisoned; qed") = Clock::default();
+        let start = Instant::now();
+        let fut = self.plugin_service.call(req);
+        let plugin = self.plugin.clone();
+        let clock = self.clock.clone();
+        let timeout = self.timeout;
+        let circuit = self.circuit.clone();
+        Box::pin(async move {
+            let result = match timeout {
+                Some(timeout) => with_timeout(fut, timeout, start, &clock).await,
+                None => Some(fut.await),
+            };
+            let failed = match &result {
+                None => true,
+                Some(Ok(_)) => false,
+                Some(Err(_)) => !clock.lock().expect("the lock is not poisoned; qed").failed,
+            };
+            circuit.record(failed);
+            result.unwrap_or_else(|| Err(format!("plugin {} timed out", plugin).into()))
+        })
+    }
+}
+
+/// Waits for the response of the plugin, unless the time spent in the plugin itself exceeds
+/// the timeout.
+async fn with_timeout<Response>(
+    fut: BoxFuture<'static, Result<Response, BoxError>>,
+    timeout: Duration,
+    start: Instant,
+    clock: &Mutex<Clock>,
+) -> Option<Result<Response, BoxError>> {
+    tokio::pin!(fut);
+    let mut remaining = timeout;
+    loop {
+        tokio::select! {
+            result = &mut fut => return Some(result),
+            _ = tokio::time::sleep(remaining) => {
+                let spent = clock
+                    .lock()
+                    .expect("the lock is not poisoned; qed")
+                    .plugin_time(start);
+                if spent >= timeout {
+                    return None;
+                }
+                remaining = timeout - spent;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::test::MockSubgraphService;
+
+    struct Slow(Duration);
+
+    impl DynPlugin for Slow {
+        fn router_service(&self, service: router::BoxService) -> router::BoxService {
+            service
+        }
+
+        fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+            service
+        }
+
+        fn execution_service(&self, service: execution::BoxService) -> execution::BoxService {
+            service
+        }
+
+        fn subgraph_service(&self, _: &str, service: subgraph::BoxService) -> subgraph::BoxService {
+            let delay = self.0;
+            service
+                .map_future(move |fut| async move {
+                    tokio::time::sleep(delay).await;
+                    fut.await
+                })
+                .boxed()
+        }
+
+        fn custom_endpoint(&self) -> Option<transport::BoxService> {
+            None
+        }
+
+        fn admin_endpoint(&self) -> Option<transport::BoxService> {
+            None
+        }
+
+        fn response_scanner(&self) -> Option<Arc<dyn ResponseScanner>> {
+            None
+        }
+
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+    }
+
+    fn subgraph_service() -> subgraph::BoxService {
+        let mut mock = MockSubgraphService::new();
+        mock.expect_call().returning(|req| {
+            Ok(subgraph::Response::fake_builder()
+                .context(req.context)
+                .build())
+        });
+        mock.boxed()
+    }
+
+    #[tokio::test]
+    async fn timed_out_plugins_are_bypassed() {
+        let plugin = Isolated::new(
+            "test.slow",
+            Box::new(Slow(Duration::from_secs(1))),
+            &IsolatedPlugin {
+                timeout: Some(Duration::from_millis(50)),
+                failure_threshold: Some(2),
+                ..Default::default()
+            },
+        );
+
+        for _ in 0..2 {
+            let result = plugin
+                .subgraph_service("products", subgraph_service())
+                .oneshot(subgraph::Request::fake_builder().build())
+                .await;
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "plugin test.slow timed out"
+            );
+        }
+
+        let start = Instant::now();
+        plugin
+            .subgraph_service("products", subgraph_service())
+            .oneshot(subgraph::Request::fake_builder().build())
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn time_spent_in_wrapped_services_is_not_counted() {
+        let plugin = Isolated::new(
+            "test.slow",
+            Box::new(Slow(Duration::from_millis(10))),
+            &IsolatedPlugin {
+                timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
+        let slow_subgraph = subgraph_service()
+            .map_future(|fut| async move {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                fut.await
+            })
+            .boxed();
+
+        plugin
+            .subgraph_service("products", slow_subgraph)
+            .oneshot(subgraph::Request::fake_builder().build())
+            .await
+            .unwrap();
+    }
+}
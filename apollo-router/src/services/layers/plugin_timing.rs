@@ -0,0 +1,196 @@
+//! Time spent in each plugin, at each stage of the request pipeline.
+//!
+//! The service a plugin returns for a stage is timed, and so is the service the plugin wraps, so
+//! that the time spent in the plugin itself is the difference between both. Timings accumulate
+//! in the context of the request, across the subgraph requests of an operation, and are emitted
+//! as metrics by the telemetry plugin when the supergraph response is ready.
+
+use std::collections::HashMap;
+use std::task::Context as TaskContext;
+use std::task::Poll;
+use std::time::Instant;
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use serde::Serialize;
+use tower::util::BoxService;
+use tower::BoxError;
+use tower::Service;
+use tower::ServiceExt;
+
+use crate::services::execution;
+use crate::services::subgraph;
+use crate::services::supergraph;
+use crate::Context;
+
+/// Context entry holding the timings of the plugins, by plugin name and stage.
+pub(crate) const PLUGIN_TIMING_CONTEXT_KEY: &str = "apollo_plugin_timing::timings";
+
+/// The telemetry plugin emits the timings before its own supergraph service returns, so it is
+/// not timed.
+const UNTIMED_PLUGIN: &str = "apollo.telemetry";
+
+/// Time spent in a plugin at a stage, and errors the plugin returned instead of the service it
+/// wraps.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub(crate) struct PluginTiming {
+    /// In seconds
+    pub(crate) duration: f64,
+    pub(crate) errors: i64,
+}
+
+/// Requests carrying the context the timings are recorded in.
+pub(crate) trait WithContext {
+    fn context(&self) -> &Context;
+}
+
+impl WithContext for supergraph::Request {
+    fn context(&self) -> &Context {
+        &self.context
+    }
+}
+
+impl WithContext for execution::Request {
+    fn context(&self) -> &Context {
+        &self.context
+    }
+}
+
+impl WithContext for subgraph::Request {
+    fn context(&self) -> &Context {
+        &self.context
+    }
+}
+
+/// Calls a plugin hook on a service, and times the service it returns.
+pub(crate) fn timed<Request, Response>(
+    plugin: &str,
+    stage: &'static str,
+    service: BoxService<Request, Response, BoxError>,
+    hook: impl FnOnce(
+        BoxService<Request, Response, BoxError>,
+    ) -> BoxService<Request, Response, BoxError>,
+) -> BoxService<Request, Response, BoxError>
+where
+    Request: WithContext + Send + 'static,
+    Response: Send + 'static,
+{
+    if plugin == UNTIMED_PLUGIN {
+        return hook(service);
+    }
+    let inner = Timed {
+        service,
+        plugin: plugin.to_string(),
+        stage,
+        inner: true,
+    }
+    .boxed();
+    Timed {
+        service: hook(inner),
+        plugin: plugin.to_string(),
+        stage,
+        inner: false,
+    }
+    .boxed()
+}
+
+/// Records the time spent in a service, added to the timing of the plugin if the service is the
+/// one returned by the plugin, and subtracted if it is the one the plugin wraps.
+struct Timed<S> {
+    service: S,
+    plugin: String,
+    stage: &'static str,
+    inner: bool,
+}
+
+impl<S, Request> Service<Request> for Timed<S>
+where
+    S: Service<Request, Error = BoxError>,
+    S::Future: Send + 'static,
+    Request: WithContext,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<S::Response, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let context = req.context().clone();
+        let plugin = self.plugin.clone();
+        let stage = self.stage;
+        let sign = if self.inner { -1 } else { 1 };
+        // the time between the call and the first poll of the future is spent in the services
+        // around this one
+        let start = Instant::now();
+        let fut = self.service.call(req);
+        let call_duration = start.elapsed();
+        Box::pin(async move {
+            let start = Instant::now();
+            let result = fut.await;
+            let duration = (call_duration + start.elapsed()).as_secs_f64();
+            let errors = if result.is_err() { sign } else { 0 };
+            let _ = context.upsert(
+                PLUGIN_TIMING_CONTEXT_KEY,
+                |mut timings: HashMap<String, HashMap<String, PluginTiming>>| {
+                    let timing = timings
+                        .entry(plugin.clone())
+                        .or_default()
+                        .entry(stage.to_string())
+                        .or_default();
+                    timing.duration += sign as f64 * duration;
+                    timing.errors += errors;
+                    timings
+                },
+            );
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn plugins_are_timed_without_the_services_they_wrap() {
+        let service = tower::service_fn(|req: subgraph::Request| async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(subgraph::Response::fake_builder()
+                .context(req.context)
+                .build())
+        })
+        .boxed();
+        let service = timed("test.plugin", "subgraph", service, |service| {
+            service
+                .map_future(|fut| async move {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    let _ = fut.await;
+                    Err::<subgraph::Response, BoxError>("plugin error".into())
+                })
+                .boxed()
+        });
+
+        let context = Context::new();
+        let result = service
+            .oneshot(
+                subgraph::Request::fake_builder()
+                    .context(context.clone())
+                    .build(),
+            )
+            .await;
+        assert!(result.is_err());
+
+        let timings = context
+            .get::<_, HashMap<String, HashMap<String, PluginTiming>>>(PLUGIN_TIMING_CONTEXT_KEY)
+            .unwrap()
+            .unwrap();
+        let timing = timings["test.plugin"]["subgraph"];
+        assert!(timing.duration >= 0.02 && timing.duration < 0.05);
+        assert_eq!(timing.errors, 1);
+    }
+}
@@ -2,6 +2,8 @@
 
 use std::sync::Arc;
 
+pub(crate) use self::computed_service::ComputedService;
+pub(crate) use self::connector_service::ConnectorService;
 pub(crate) use self::execution_service::*;
 pub(crate) use self::query_planner::*;
 pub(crate) use self::subgraph_service::*;
@@ -19,16 +21,27 @@ pub(crate) use crate::services::subgraph::Response as SubgraphResponse;
 pub(crate) use crate::services::supergraph::Request as SupergraphRequest;
 pub(crate) use crate::services::supergraph::Response as SupergraphResponse;
 
+pub(crate) mod canary;
+pub(crate) mod capabilities;
+pub(crate) mod computed_service;
+pub(crate) mod connector_service;
 pub mod execution;
 mod execution_service;
+pub(crate) mod federated_subgraph;
+pub(crate) mod file_uploads;
 pub(crate) mod layers;
 pub(crate) mod new_service;
 pub(crate) mod query_planner;
+pub mod router;
+pub(crate) mod spiffe;
 pub mod subgraph;
+pub(crate) mod subgraph_batching;
 pub(crate) mod subgraph_service;
 pub mod supergraph;
 mod supergraph_service;
+pub(crate) mod tolerances;
 pub mod transport;
+pub(crate) mod upstream;
 
 impl AsRef<Request> for http_ext::Request<Request> {
     fn as_ref(&self) -> &Request {
@@ -47,3 +60,4 @@ pub(crate) const MULTIPART_DEFER_SPEC_PARAMETER: &str = "deferSpec";
 pub(crate) const MULTIPART_DEFER_SPEC_VALUE: &str = "20220824";
 pub(crate) const MULTIPART_DEFER_CONTENT_TYPE: &str =
     "multipart/mixed;boundary=\"graphql\";deferSpec=20220824";
+pub(crate) const MULTIPART_DEFER_ACCEPT: &str = "multipart/mixed;deferSpec=20220824";
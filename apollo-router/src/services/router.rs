@@ -0,0 +1,87 @@
+#![allow(missing_docs)] // FIXME
+
+use static_assertions::assert_impl_all;
+use tower::BoxError;
+
+use crate::Context;
+
+pub type BoxService = tower::util::BoxService<Request, Response, BoxError>;
+pub type BoxCloneService = tower::util::BoxCloneService<Request, Response, BoxError>;
+pub type ServiceResult = Result<Response, BoxError>;
+
+assert_impl_all!(Request: Send);
+/// Represents the HTTP step of the processing pipeline, before the GraphQL request is parsed.
+///
+/// The request is the one received by the HTTP server, with its raw body. Plugins can rewrite its
+/// URI, which becomes the URI of the supergraph request.
+#[non_exhaustive]
+pub struct Request {
+    /// Original request to the Router.
+    pub router_request: http::Request<hyper::Body>,
+
+    /// Context for extension, also the context of the supergraph request.
+    pub context: Context,
+}
+
+#[buildstructor::buildstructor]
+impl Request {
+    /// This is the constructor (or builder) to use when constructing a real Request.
+    ///
+    /// Required parameters are required in non-testing code to create a Request.
+    #[builder(visibility = "pub")]
+    fn new(router_request: http::Request<hyper::Body>, context: Context) -> Request {
+        Self {
+            router_request,
+            context,
+        }
+    }
+
+    /// This is the constructor (or builder) to use when constructing a "fake" Request.
+    ///
+    /// This does not enforce the provision of the data that is required for a fully functional
+    /// Request. It's usually enough for testing, when a fully constructed Request is
+    /// difficult to construct and not required for the purposes of the test.
+    #[builder(visibility = "pub")]
+    fn fake_new(
+        router_request: Option<http::Request<hyper::Body>>,
+        context: Option<Context>,
+    ) -> Request {
+        Request::new(
+            router_request.unwrap_or_default(),
+            context.unwrap_or_default(),
+        )
+    }
+}
+
+assert_impl_all!(Response: Send);
+/// The HTTP response sent to the client, with its body as it is streamed.
+#[non_exhaustive]
+pub struct Response {
+    pub response: http::Response<hyper::Body>,
+
+    pub context: Context,
+}
+
+#[buildstructor::buildstructor]
+impl Response {
+    /// This is the constructor (or builder) to use when constructing a real Response.
+    ///
+    /// Required parameters are required in non-testing code to create a Response.
+    #[builder(visibility = "pub")]
+    fn new(response: http::Response<hyper::Body>, context: Context) -> Response {
+        Self { response, context }
+    }
+
+    /// This is the constructor (or builder) to use when constructing a "fake" Response.
+    ///
+    /// This does not enforce the provision of the data that is required for a fully functional
+    /// Response. It's usually enough for testing, when a fully constructed Response is
+    /// difficult to construct and not required for the purposes of the test.
+    #[builder(visibility = "pub")]
+    fn fake_new(
+        response: Option<http::Response<hyper::Body>>,
+        context: Option<Context>,
+    ) -> Response {
+        Response::new(response.unwrap_or_default(), context.unwrap_or_default())
+    }
+}
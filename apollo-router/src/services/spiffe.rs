@@ -0,0 +1,618 @@
+//! SPIFFE Workload API client.
+//!
+//! Fetches the X.509 SVID of the router from the Workload API, and uses it as client certificate
+//! for the TLS connections to subgraphs. The Workload API streams a new SVID every time the
+//! current one is rotated, before it expires, so the router never needs static secrets.
+//!
+//! Subgraph certificates issued by the trust bundle are identified by the SPIFFE ID in their URI
+//! subject alternative name rather than by a DNS name, like the SPIFFE X.509 SVID standard says.
+
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use http::uri::PathAndQuery;
+#[cfg(unix)]
+use http::Uri;
+use rustls::client::ResolvesClientCert;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::client::WebPkiVerifier;
+use rustls::sign::CertifiedKey;
+use rustls::Certificate;
+use rustls::ClientConfig;
+use rustls::PrivateKey;
+use rustls::RootCertStore;
+use rustls::ServerName;
+use rustls::SignatureScheme;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::oneshot;
+use tonic::codec::ProstCodec;
+use tonic::codec::Streaming;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::transport::Endpoint;
+use tower::BoxError;
+use webpki::EndEntityCert;
+use webpki::TlsServerTrustAnchors;
+use webpki::TrustAnchor;
+
+use crate::configuration::Spiffe;
+
+const SPIFFE_ENDPOINT_SOCKET: &str = "SPIFFE_ENDPOINT_SOCKET";
+const FETCH_X509_SVID: &str = "/SpiffeWorkloadAPI/FetchX509SVID";
+const WORKLOAD_API_HEADER: &str = "workload.spiffe.io";
+const INITIAL_SVID_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+const SPIFFE_SCHEME: &str = "spiffe://";
+/// OID of the subject alternative name extension, 2.5.29.17
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Signature algorithms of the certificates verified with the trust bundle, the same as rustls.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Messages of the Workload API used by the router, from `workload.proto`.
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(super) struct X509SvidRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(super) struct X509SvidResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub(super) svids: Vec<X509Svid>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(super) struct X509Svid {
+        #[prost(string, tag = "1")]
+        pub(super) spiffe_id: String,
+        /// ASN.1 DER encoded certificate chain, leaf first
+        #[prost(bytes = "vec", tag = "2")]
+        pub(super) x509_svid: Vec<u8>,
+        /// ASN.1 DER encoded PKCS#8 private key
+        #[prost(bytes = "vec", tag = "3")]
+        pub(super) x509_svid_key: Vec<u8>,
+        /// ASN.1 DER encoded certificates of the trust domain's bundle
+        #[prost(bytes = "vec", tag = "4")]
+        pub(super) bundle: Vec<u8>,
+    }
+}
+
+/// The current X.509 SVID of the router, kept up to date from the Workload API.
+pub(crate) struct SpiffeSource {
+    shared: Arc<Shared>,
+    allowed_ids: Vec<String>,
+}
+
+struct Shared {
+    current: RwLock<Svid>,
+    // dropped with the last TLS configuration using this source, which stops the update task
+    _shutdown: oneshot::Sender<()>,
+}
+
+struct Svid {
+    spiffe_id: String,
+    certified_key: Arc<CertifiedKey>,
+    /// DER encoded certificates of the trust domain's bundle
+    bundle: Arc<Vec<Vec<u8>>>,
+}
+
+impl SpiffeSource {
+    /// Connects to the Workload API and waits for the first SVID.
+    pub(crate) async fn new(config: &Spiffe) -> Result<Self, BoxError> {
+        let endpoint = match &config.endpoint_socket {
+            Some(endpoint) => endpoint.clone(),
+            None => std::env::var(SPIFFE_ENDPOINT_SOCKET).map_err(|_| {
+                format!(
+                    "no SPIFFE Workload API address: set tls.subgraph.spiffe.endpoint_socket or the {} environment variable",
+                    SPIFFE_ENDPOINT_SOCKET
+                )
+            })?,
+        };
+
+        let mut updates = fetch_x509_svids(&endpoint).await?;
+        let svid = tokio::time::timeout(
+            INITIAL_SVID_TIMEOUT,
+            next_svid(&mut updates, config.spiffe_id.as_deref()),
+        )
+        .await
+        .map_err(|_| "timed out waiting for an X.509 SVID from the SPIFFE Workload API")??;
+        tracing::info!(spiffe_id = %svid.spiffe_id, "fetched X.509 SVID from the SPIFFE Workload API");
+
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let shared = Arc::new(Shared {
+            current: RwLock::new(svid),
+            _shutdown: shutdown,
+        });
+        tokio::spawn(update_svids(
+            endpoint,
+            config.spiffe_id.clone(),
+            updates,
+            Arc::downgrade(&shared),
+            shutdown_receiver,
+        ));
+
+        Ok(Self {
+            shared,
+            allowed_ids: config.allowed_ids.clone(),
+        })
+    }
+
+    /// TLS configuration presenting the current SVID to subgraphs.
+    ///
+    /// Subgraph certificates are verified with the trust bundle of the SVID, and must then have
+    /// an allowed SPIFFE ID. Certificates which aren't issued by the bundle are verified with the
+    /// native root certificates, and must be valid for the subgraph's DNS name.
+    pub(crate) fn client_config(&self) -> Result<ClientConfig, BoxError> {
+        let mut native_roots = RootCertStore::empty();
+        let certificates: Vec<Vec<u8>> = rustls_native_certs::load_native_certs()?
+            .into_iter()
+            .map(|certificate| certificate.0)
+            .collect();
+        native_roots.add_parsable_certificates(&certificates);
+
+        Ok(ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(SvidVerifier {
+                shared: self.shared.clone(),
+                allowed_ids: self.allowed_ids.clone(),
+                native_roots: WebPkiVerifier::new(native_roots, None),
+            }))
+            .with_client_cert_resolver(Arc::new(SvidResolver(self.shared.clone()))))
+    }
+}
+
+async fn update_svids(
+    endpoint: String,
+    spiffe_id: Option<String>,
+    mut updates: Streaming<proto::X509SvidResponse>,
+    shared: Weak<Shared>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => return,
+            svid = next_svid(&mut updates, spiffe_id.as_deref()) => match svid {
+                Ok(svid) => {
+                    let shared = match shared.upgrade() {
+                        Some(shared) => shared,
+                        None => return,
+                    };
+                    tracing::info!(spiffe_id = %svid.spiffe_id, "rotated X.509 SVID");
+                    *shared.current.write().expect("lock poisoned") = svid;
+                    delay = MIN_RECONNECT_DELAY;
+                }
+                Err(error) => {
+                    // the current SVID keeps being used until we can reconnect
+                    tracing::error!("lost the connection to the SPIFFE Workload API: {}", error);
+                    loop {
+                        tokio::select! {
+                            _ = &mut shutdown => return,
+                            _ = tokio::time::sleep(delay) => {}
+                        }
+                        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                        match fetch_x509_svids(&endpoint).await {
+                            Ok(stream) => {
+                                updates = stream;
+                                break;
+                            }
+                            Err(error) => tracing::error!(
+                                "cannot reconnect to the SPIFFE Workload API: {}",
+                                error
+                            ),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn connect(endpoint: &str) -> Result<Channel, BoxError> {
+    if let Some(address) = endpoint.strip_prefix("tcp://") {
+        return Ok(Endpoint::from_shared(format!("http://{}", address))?
+            .connect()
+            .await?);
+    }
+
+    connect_unix(endpoint).await
+}
+
+#[cfg(unix)]
+async fn connect_unix(endpoint: &str) -> Result<Channel, BoxError> {
+    let path = endpoint
+        .strip_prefix("unix://")
+        .or_else(|| endpoint.strip_prefix("unix:"))
+        .ok_or_else(|| {
+            format!(
+                "invalid SPIFFE Workload API address '{}': expected unix:// or tcp://",
+                endpoint
+            )
+        })?
+        .to_string();
+    // the URI is not used by the connector
+    Ok(Endpoint::from_static("http://[::]:50051")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            UnixStream::connect(path.clone())
+        }))
+        .await?)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(endpoint: &str) -> Result<Channel, BoxError> {
+    Err(format!(
+        "invalid SPIFFE Workload API address '{}': expected tcp://",
+        endpoint
+    )
+    .into())
+}
+
+async fn fetch_x509_svids(endpoint: &str) -> Result<Streaming<proto::X509SvidResponse>, BoxError> {
+    let mut client = tonic::client::Grpc::new(connect(endpoint).await?);
+    client.ready().await?;
+
+    let mut request = tonic::Request::new(proto::X509SvidRequest {});
+    request
+        .metadata_mut()
+        .insert(WORKLOAD_API_HEADER, MetadataValue::from_static("true"));
+    let response = client
+        .server_streaming(
+            request,
+            PathAndQuery::from_static(FETCH_X509_SVID),
+            ProstCodec::default(),
+        )
+        .await?;
+    Ok(response.into_inner())
+}
+
+async fn next_svid(
+    updates: &mut Streaming<proto::X509SvidResponse>,
+    spiffe_id: Option<&str>,
+) -> Result<Svid, BoxError> {
+    let response = updates
+        .message()
+        .await?
+        .ok_or("the SPIFFE Workload API closed the stream")?;
+    let svid = response
+        .svids
+        .into_iter()
+        .find(|svid| spiffe_id.map(|id| id == svid.spiffe_id).unwrap_or(true))
+        .ok_or_else(|| match spiffe_id {
+            Some(id) => format!("the SPIFFE Workload API did not return an SVID for {}", id),
+            None => "the SPIFFE Workload API did not return any SVID".to_string(),
+        })?;
+    Svid::parse(svid)
+}
+
+impl Svid {
+    fn parse(svid: proto::X509Svid) -> Result<Self, BoxError> {
+        let chain = split_der(&svid.x509_svid)?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let key = rustls::sign::any_supported_type(&PrivateKey(svid.x509_svid_key))
+            .map_err(|_| "unsupported X.509 SVID private key")?;
+
+        Ok(Svid {
+            spiffe_id: svid.spiffe_id,
+            certified_key: Arc::new(CertifiedKey::new(chain, key)),
+            bundle: Arc::new(split_der(&svid.bundle)?),
+        })
+    }
+}
+
+struct SvidResolver(Arc<Shared>);
+
+impl ResolvesClientCert for SvidResolver {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.0
+                .current
+                .read()
+                .expect("lock poisoned")
+                .certified_key
+                .clone(),
+        )
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+struct SvidVerifier {
+    shared: Arc<Shared>,
+    /// SPIFFE IDs accepted for subgraphs, any ID of the router's trust domain if empty
+    allowed_ids: Vec<String>,
+    native_roots: WebPkiVerifier,
+}
+
+impl SvidVerifier {
+    fn is_allowed(&self, spiffe_id: &str, own_id: &str) -> bool {
+        if self.allowed_ids.is_empty() {
+            trust_domain(spiffe_id).is_some() && trust_domain(spiffe_id) == trust_domain(own_id)
+        } else {
+            self.allowed_ids.iter().any(|allowed| allowed == spiffe_id)
+        }
+    }
+}
+
+impl ServerCertVerifier for SvidVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let (bundle, own_id) = {
+            let current = self.shared.current.read().expect("lock poisoned");
+            (current.bundle.clone(), current.spiffe_id.clone())
+        };
+        // the name of the subgraph is not checked: SVIDs identify workloads by their SPIFFE ID
+        if issued_by_bundle(&bundle, end_entity, intermediates, now) {
+            let spiffe_ids: Vec<String> = uri_sans(&end_entity.0)
+                .ok_or_else(|| {
+                    rustls::Error::InvalidCertificateData(
+                        "invalid subject alternative names".to_string(),
+                    )
+                })?
+                .into_iter()
+                .filter(|uri| uri.starts_with(SPIFFE_SCHEME))
+                .collect();
+            return match spiffe_ids.as_slice() {
+                [spiffe_id] if self.is_allowed(spiffe_id, &own_id) => {
+                    Ok(ServerCertVerified::assertion())
+                }
+                [spiffe_id] => Err(rustls::Error::InvalidCertificateData(format!(
+                    "the SPIFFE ID {} of the subgraph is not allowed",
+                    spiffe_id
+                ))),
+                // an X.509 SVID has exactly one SPIFFE ID
+                _ => Err(rustls::Error::InvalidCertificateData(
+                    "the certificate issued by the trust bundle is not a valid X.509 SVID"
+                        .to_string(),
+                )),
+            };
+        }
+        self.native_roots.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// Whether the certificate chain is issued by the trust bundle. No name is checked.
+fn issued_by_bundle(
+    bundle: &[Vec<u8>],
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    now: SystemTime,
+) -> bool {
+    let anchors: Vec<TrustAnchor> = bundle
+        .iter()
+        .filter_map(|certificate| TrustAnchor::try_from_cert_der(certificate).ok())
+        .collect();
+    let intermediates: Vec<&[u8]> = intermediates
+        .iter()
+        .map(|certificate| certificate.0.as_slice())
+        .collect();
+    let (certificate, now) = match (
+        EndEntityCert::try_from(end_entity.0.as_slice()),
+        webpki::Time::try_from(now),
+    ) {
+        (Ok(certificate), Ok(now)) => (certificate, now),
+        _ => return false,
+    };
+    certificate
+        .verify_is_valid_tls_server_cert(
+            SUPPORTED_SIG_ALGS,
+            &TlsServerTrustAnchors(&anchors),
+            &intermediates,
+            now,
+        )
+        .is_ok()
+}
+
+/// Trust domain of a SPIFFE ID, like `example.org` for `spiffe://example.org/products`.
+fn trust_domain(spiffe_id: &str) -> Option<&str> {
+    spiffe_id
+        .strip_prefix(SPIFFE_SCHEME)?
+        .split('/')
+        .next()
+        .filter(|trust_domain| !trust_domain.is_empty())
+}
+
+/// URIs in the subject alternative names of a DER encoded certificate.
+fn uri_sans(certificate: &[u8]) -> Option<Vec<String>> {
+    const BOOLEAN: u8 = 0x01;
+    const OBJECT_IDENTIFIER: u8 = 0x06;
+    const EXTENSIONS: u8 = 0xa3;
+    const URI: u8 = 0x86;
+
+    // Certificate, then TBSCertificate
+    let (_, certificate, _) = der_value(certificate)?;
+    let (_, mut fields, _) = der_value(certificate)?;
+    while !fields.is_empty() {
+        let (tag, field, rest) = der_value(fields)?;
+        fields = rest;
+        if tag != EXTENSIONS {
+            continue;
+        }
+        let (_, mut extensions, _) = der_value(field)?;
+        while !extensions.is_empty() {
+            let (_, extension, rest) = der_value(extensions)?;
+            extensions = rest;
+            match der_value(extension)? {
+                (OBJECT_IDENTIFIER, SUBJECT_ALT_NAME, value) => {
+                    // the critical flag is optional
+                    let (tag, mut value, rest) = der_value(value)?;
+                    if tag == BOOLEAN {
+                        value = der_value(rest)?.1;
+                    }
+                    let (_, mut names, _) = der_value(value)?;
+                    let mut uris = Vec::new();
+                    while !names.is_empty() {
+                        let (tag, name, rest) = der_value(names)?;
+                        names = rest;
+                        if tag == URI {
+                            uris.push(String::from_utf8(name.to_vec()).ok()?);
+                        }
+                    }
+                    return Some(uris);
+                }
+                _ => continue,
+            }
+        }
+    }
+    Some(Vec::new())
+}
+
+/// Splits concatenated DER encoded certificates, as sent by the Workload API.
+fn split_der(mut der: &[u8]) -> Result<Vec<Vec<u8>>, BoxError> {
+    let mut certificates = Vec::new();
+    while !der.is_empty() {
+        let length = der_length(der).ok_or("invalid DER encoded certificate")?;
+        let (certificate, rest) = der.split_at(length);
+        certificates.push(certificate.to_vec());
+        der = rest;
+    }
+    Ok(certificates)
+}
+
+/// Length of the DER encoded SEQUENCE at the start of `der`, header included.
+fn der_length(der: &[u8]) -> Option<usize> {
+    match der_value(der)? {
+        (0x30, _, rest) => Some(der.len() - rest.len()),
+        _ => None,
+    }
+}
+
+/// Splits the DER encoded value at the start of `der` into its tag, its content, and the rest.
+fn der_value(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > std::mem::size_of::<usize>() || rest.len() < count {
+            return None;
+        }
+        let (length, rest) = rest.split_at(count);
+        let length = length
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, rest)
+    };
+    (length <= rest.len()).then(|| {
+        let (content, rest) = rest.split_at(length);
+        (tag, content, rest)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_concatenated_der() {
+        let short = [0x30, 0x03, 0x02, 0x01, 0x01];
+        let mut long = vec![0x30, 0x81, 0x80];
+        long.extend(std::iter::repeat(0).take(0x80));
+
+        let mut der = short.to_vec();
+        der.extend(&long);
+        assert_eq!(split_der(&der).unwrap(), vec![short.to_vec(), long]);
+        assert!(split_der(&[]).unwrap().is_empty());
+
+        // truncated
+        assert!(split_der(&der[..der.len() - 1]).is_err());
+        // not a SEQUENCE
+        assert!(split_der(&[0x02, 0x01, 0x01]).is_err());
+    }
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag, content.len() as u8];
+        der.extend(content);
+        der
+    }
+
+    #[test]
+    fn spiffe_ids_are_read_from_uri_sans() {
+        let names = der(
+            0x30,
+            &[
+                der(0x82, b"products.internal"),
+                der(0x86, b"spiffe://example.org/products"),
+            ]
+            .concat(),
+        );
+        let san = der(
+            0x30,
+            &[
+                der(0x06, SUBJECT_ALT_NAME),
+                der(0x01, &[0xff]),
+                der(0x04, &names),
+            ]
+            .concat(),
+        );
+        let tbs = der(
+            0x30,
+            &[
+                der(0xa0, &der(0x02, &[0x02])),
+                der(0x02, &[0x01]),
+                der(0xa3, &der(0x30, &san)),
+            ]
+            .concat(),
+        );
+        let certificate = der(0x30, &tbs);
+        assert_eq!(
+            uri_sans(&certificate).unwrap(),
+            vec!["spiffe://example.org/products".to_string()]
+        );
+        // without extensions
+        assert!(uri_sans(&der(0x30, &der(0x30, &der(0x02, &[0x01]))))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn trust_domains() {
+        assert_eq!(
+            trust_domain("spiffe://example.org/products"),
+            Some("example.org")
+        );
+        assert_eq!(trust_domain("spiffe://example.org"), Some("example.org"));
+        assert_eq!(trust_domain("spiffe:///products"), None);
+        assert_eq!(trust_domain("https://example.org/products"), None);
+    }
+}
@@ -3,6 +3,9 @@
 use std::sync::Arc;
 
 use http::StatusCode;
+use http::Uri;
+use schemars::JsonSchema;
+use serde::Deserialize;
 use serde_json_bytes::ByteString;
 use serde_json_bytes::Map as JsonMap;
 use serde_json_bytes::Value;
@@ -74,6 +77,17 @@ impl Request {
     }
 }
 
+impl Request {
+    /// Sends the request to another URI than the routing URL of the subgraph, like a shard picked
+    /// from a header or a context entry.
+    ///
+    /// Plugins call it from their subgraph service, before the request reaches the HTTP client.
+    pub fn route_to(&mut self, uri: Uri) {
+        tracing::debug!("routing the subgraph request to {}", uri);
+        *self.subgraph_request.uri_mut() = uri;
+    }
+}
+
 assert_impl_all!(Response: Send);
 #[derive(Debug)]
 #[non_exhaustive]
@@ -175,3 +189,98 @@ impl Response {
         ))
     }
 }
+
+impl Response {
+    /// Transforms the GraphQL response of the subgraph, as deserialized by the subgraph service.
+    pub fn map<F>(self, f: F) -> Response
+    where
+        F: FnOnce(graphql::Response) -> graphql::Response,
+    {
+        Response {
+            context: self.context,
+            response: self.response.map(f),
+        }
+    }
+
+    /// Transforms the data of the response, `None` if the subgraph returned no data.
+    pub fn map_data(self, f: impl FnOnce(Option<Value>) -> Option<Value>) -> Response {
+        self.map(|mut response| {
+            response.data = f(response.data.take());
+            response
+        })
+    }
+
+    /// Transforms each error of the response. Errors for which the function returns `None` are
+    /// removed.
+    ///
+    /// ```
+    /// # use apollo_router::services::subgraph;
+    /// # use tower::ServiceExt;
+    /// # fn test(service: subgraph::BoxService) -> subgraph::BoxService {
+    /// service
+    ///     .map_response(|response: subgraph::Response| {
+    ///         response.map_errors(|mut error| {
+    ///             error.extensions.remove("exception");
+    ///             Some(error)
+    ///         })
+    ///     })
+    ///     .boxed()
+    /// # }
+    /// ```
+    pub fn map_errors(self, f: impl FnMut(Error) -> Option<Error>) -> Response {
+        self.map(|mut response| {
+            response.map_errors(f);
+            response
+        })
+    }
+}
+
+/// How the traffic shaping plugin treats a failed subgraph request.
+///
+/// Plugins classify a response by inserting a class in its extensions, with
+/// `response.response.extensions_mut().insert(ErrorClass::Retryable)`, and an error by wrapping
+/// it in a [`ClassifiedError`]. Failures that are not classified by a plugin are classified by
+/// the rules of the traffic shaping configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorClass {
+    /// Retry the request, within the retry budget
+    Retryable,
+    /// Do not retry the request
+    NonRetryable,
+    /// Do not retry the request, and stop calling the subgraph for a while
+    Failover,
+}
+
+/// An error of a subgraph request, with its class.
+#[derive(Debug)]
+pub struct ClassifiedError {
+    class: ErrorClass,
+    error: BoxError,
+}
+
+impl ClassifiedError {
+    pub fn new(class: ErrorClass, error: impl Into<BoxError>) -> Self {
+        Self {
+            class,
+            error: error.into(),
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        self.class
+    }
+}
+
+impl std::fmt::Display for ClassifiedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for ClassifiedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
@@ -0,0 +1,345 @@
+//! Batching of the requests sent to a subgraph by the fetches of an operation.
+//!
+//! The fetches of a query plan step start together, like the branches of a parallel node. With
+//! batching, the first of these requests waits for a few scheduler turns, collecting the requests
+//! sent to the same subgraph by the other fetches of the operation. They are sent as a single HTTP
+//! request whose body is the JSON array of the GraphQL requests, and the subgraph answers with the
+//! JSON array of the responses, in the same order.
+
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use http::response::Parts;
+use http::HeaderMap;
+use http::Method;
+use http::StatusCode;
+use http::Uri;
+use http::Version;
+use tokio::sync::oneshot;
+
+use crate::error::FetchError;
+use crate::graphql;
+use crate::Context;
+
+/// Scheduler turns the first request of a batch waits for the other ones.
+const BATCH_YIELDS: usize = 4;
+
+/// A response received for a whole batch: its parts and body, or the response merged from the
+/// payloads of a multipart response.
+pub(crate) type SubgraphHttpResponse = (Parts, Bytes, Option<graphql::Response>);
+
+/// The response of a request sent by another request of its batch.
+pub(crate) struct BatchedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl BatchedResponse {
+    pub(crate) fn into_parts(self) -> (Parts, Bytes) {
+        let mut response = http::Response::new(());
+        *response.status_mut() = self.status;
+        *response.version_mut() = self.version;
+        *response.headers_mut() = self.headers;
+        (response.into_parts().0, self.body)
+    }
+}
+
+type Sender = oneshot::Sender<Result<BatchedResponse, FetchError>>;
+
+/// Requests of an operation waiting to be sent together. They have the same method, URI and
+/// headers.
+struct PendingBatch {
+    id: u64,
+    context: Context,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    bodies: Vec<String>,
+    senders: Vec<Sender>,
+}
+
+/// The batches of a subgraph being collected.
+#[derive(Default)]
+pub(crate) struct Batcher {
+    pending: Mutex<(u64, Vec<PendingBatch>)>,
+}
+
+/// The role of a request in its batch.
+pub(crate) enum Joined {
+    /// The request sends the batch
+    Leader(Batch),
+    /// The request waits for its response, sent by the leader
+    Follower(oneshot::Receiver<Result<BatchedResponse, FetchError>>),
+}
+
+/// The requests sent together, the leader's request first.
+pub(crate) struct Batch {
+    bodies: Vec<String>,
+    senders: Vec<Sender>,
+}
+
+impl Batcher {
+    /// Adds a request to the batch of its operation, or starts a batch.
+    pub(crate) async fn join(
+        &self,
+        context: &Context,
+        parts: &http::request::Parts,
+        body: String,
+    ) -> Joined {
+        let id = {
+            let mut pending = self.pending.lock().expect("lock poisoned");
+            let (next_id, batches) = &mut *pending;
+            if let Some(batch) = batches.iter_mut().find(|batch| {
+                batch.context.same_as(context)
+                    && batch.method == parts.method
+                    && batch.uri == parts.uri
+                    && batch.headers == parts.headers
+            }) {
+                let (sender, receiver) = oneshot::channel();
+                batch.bodies.push(body);
+                batch.senders.push(sender);
+                return Joined::Follower(receiver);
+            }
+
+            let id = *next_id;
+            *next_id += 1;
+            batches.push(PendingBatch {
+                id,
+                context: context.clone(),
+                method: parts.method.clone(),
+                uri: parts.uri.clone(),
+                headers: parts.headers.clone(),
+                bodies: vec![body],
+                senders: Vec::new(),
+            });
+            id
+        };
+
+        let registration = Registration { batcher: self, id };
+        for _ in 0..BATCH_YIELDS {
+            tokio::task::yield_now().await;
+        }
+
+        let batch = registration
+            .take()
+            .expect("only the leader removes its batch; qed");
+        Joined::Leader(Batch {
+            bodies: batch.bodies,
+            senders: batch.senders,
+        })
+    }
+}
+
+/// The batch started by a leader, removed if the leader is cancelled while collecting requests,
+/// failing the other requests of the batch.
+struct Registration<'a> {
+    batcher: &'a Batcher,
+    id: u64,
+}
+
+impl Registration<'_> {
+    fn take(&self) -> Option<PendingBatch> {
+        let mut pending = self.batcher.pending.lock().expect("lock poisoned");
+        let batches = &mut pending.1;
+        let index = batches.iter().position(|batch| batch.id == self.id)?;
+        Some(batches.swap_remove(index))
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+impl Batch {
+    /// A batch holding a single request, sent as is.
+    pub(crate) fn single(body: String) -> Self {
+        Batch {
+            bodies: vec![body],
+            senders: Vec::new(),
+        }
+    }
+
+    /// The body of the HTTP request: the request itself if it is alone, the array of the requests
+    /// otherwise.
+    pub(crate) fn body(&self) -> String {
+        if self.senders.is_empty() {
+            return self.bodies[0].clone();
+        }
+        format!("[{}]", self.bodies.join(","))
+    }
+
+    /// Sends their responses to the other requests of the batch, and returns the leader's one.
+    pub(crate) fn distribute(
+        self,
+        service_name: &str,
+        response: Result<SubgraphHttpResponse, FetchError>,
+    ) -> Result<SubgraphHttpResponse, FetchError> {
+        if self.senders.is_empty() {
+            return response;
+        }
+        let malformed = |reason: String| FetchError::SubrequestMalformedResponse {
+            service: service_name.to_string(),
+            reason,
+        };
+        let responses = response.and_then(|(parts, body, merged)| {
+            if merged.is_some() {
+                return Err(malformed(
+                    "the response to a batch of requests is a multipart response".to_string(),
+                ));
+            }
+            let bodies = split_json_array(&body).ok_or_else(|| {
+                malformed("the response to a batch of requests is not a JSON array".to_string())
+            })?;
+            if bodies.len() != self.bodies.len() {
+                return Err(malformed(format!(
+                    "the subgraph answered a batch of {} requests with {} responses",
+                    self.bodies.len(),
+                    bodies.len()
+                )));
+            }
+            Ok((parts, bodies))
+        });
+
+        match responses {
+            Err(error) => {
+                for sender in self.senders {
+                    let _ = sender.send(Err(error.clone()));
+                }
+                Err(error)
+            }
+            Ok((parts, mut bodies)) => {
+                for (sender, body) in self.senders.into_iter().zip(bodies.drain(1..)) {
+                    let _ = sender.send(Ok(BatchedResponse {
+                        status: parts.status,
+                        version: parts.version,
+                        headers: parts.headers.clone(),
+                        body,
+                    }));
+                }
+                let body = bodies.pop().expect("the batch has a leader; qed");
+                Ok((parts, body, None))
+            }
+        }
+    }
+}
+
+/// Splits a JSON array into the bytes of its elements, without parsing them. The elements are
+/// parsed like any subgraph response afterwards.
+fn split_json_array(body: &Bytes) -> Option<Vec<Bytes>> {
+    let is_whitespace = |byte: &u8| matches!(byte, b' ' | b'\t' | b'\n' | b'\r');
+    let start = body.iter().position(|byte| !is_whitespace(byte))?;
+    let end = body.iter().rposition(|byte| !is_whitespace(byte))?;
+    if body[start] != b'[' || body[end] != b']' || start == end {
+        return None;
+    }
+
+    let mut elements = Vec::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut element_start = start + 1;
+    for index in start + 1..end {
+        let byte = body[index];
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth = depth.checked_sub(1)?,
+            b',' if depth == 0 => {
+                elements.push(body.slice(element_start..index));
+                element_start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = body.slice(element_start..end);
+    if last.iter().all(is_whitespace) {
+        // An empty array, or a trailing comma
+        return if elements.is_empty() {
+            Some(elements)
+        } else {
+            None
+        };
+    }
+    elements.push(last);
+    Some(elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_arrays_are_split() {
+        let body =
+            Bytes::from_static(br#" [{"data":{"a":"],\"["}}, {"errors":[{"message":"x"}]}] "#);
+        assert_eq!(
+            split_json_array(&body).unwrap(),
+            vec![
+                Bytes::from_static(br#"{"data":{"a":"],\"["}}"#),
+                Bytes::from_static(br#" {"errors":[{"message":"x"}]}"#),
+            ]
+        );
+        assert_eq!(
+            split_json_array(&Bytes::from_static(b"[]")).unwrap().len(),
+            0
+        );
+        assert!(split_json_array(&Bytes::from_static(br#"{"data":{}}"#)).is_none());
+        assert!(split_json_array(&Bytes::from_static(b"[{},]")).is_none());
+        assert!(split_json_array(&Bytes::from_static(b"[{}}]")).is_none());
+    }
+
+    #[tokio::test]
+    async fn requests_of_an_operation_are_batched() {
+        let batcher = Batcher::default();
+        let context = Context::new();
+        let (parts, ()) = http::Request::post("http://products/graphql")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let (leader, follower, other) = tokio::join!(
+            batcher.join(&context, &parts, "{\"query\":\"a\"}".to_string()),
+            batcher.join(&context, &parts, "{\"query\":\"b\"}".to_string()),
+            batcher.join(&Context::new(), &parts, "{\"query\":\"c\"}".to_string()),
+        );
+        let leader = match leader {
+            Joined::Leader(batch) => batch,
+            Joined::Follower(_) => panic!("the first request leads the batch"),
+        };
+        assert_eq!(leader.body(), r#"[{"query":"a"},{"query":"b"}]"#);
+        assert!(matches!(other, Joined::Leader(_)));
+
+        let (parts, ()) = http::Response::new(()).into_parts();
+        let (_, body, _) = leader
+            .distribute(
+                "products",
+                Ok((
+                    parts,
+                    Bytes::from_static(br#"[{"data":1},{"data":2}]"#),
+                    None,
+                )),
+            )
+            .unwrap();
+        assert_eq!(body, Bytes::from_static(br#"{"data":1}"#));
+        match follower {
+            Joined::Follower(receiver) => {
+                let (_, body) = receiver.await.unwrap().unwrap().into_parts();
+                assert_eq!(body, Bytes::from_static(br#"{"data":2}"#));
+            }
+            Joined::Leader(_) => panic!("the second request follows the first one"),
+        }
+    }
+}
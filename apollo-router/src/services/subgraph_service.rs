@@ -4,11 +4,16 @@ use std::collections::HashMap;
 use std::fmt::Display;
 use std::sync::Arc;
 use std::task::Poll;
+use std::time::SystemTime;
 
 use ::serde::Deserialize;
+use apollo_parser::ast;
+use apollo_parser::ast::AstNode;
 use async_compression::tokio::write::BrotliEncoder;
 use async_compression::tokio::write::GzipEncoder;
 use async_compression::tokio::write::ZlibEncoder;
+use bytes::Bytes;
+use bytes::BytesMut;
 use futures::future::BoxFuture;
 use global::get_text_map_propagator;
 use http::header::ACCEPT;
@@ -16,13 +21,22 @@ use http::header::CONTENT_ENCODING;
 use http::header::CONTENT_TYPE;
 use http::header::{self};
 use http::HeaderMap;
+use http::HeaderName;
 use http::HeaderValue;
 use http::StatusCode;
+use hyper::body::HttpBody;
 use hyper::client::HttpConnector;
 use hyper_rustls::HttpsConnector;
+use mediatype::names::BOUNDARY;
+use mediatype::MediaType;
 use opentelemetry::global;
 use opentelemetry::trace::SpanKind;
+use rustls::ClientConfig;
+use rustls::RootCertStore;
 use schemars::JsonSchema;
+use serde_json_bytes::ByteString;
+use sha2::Digest;
+use sha2::Sha256;
 use tokio::io::AsyncWriteExt;
 use tower::util::BoxService;
 use tower::BoxError;
@@ -35,9 +49,34 @@ use tracing::Instrument;
 use tracing::Span;
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+use super::capabilities::Capabilities;
+use super::capabilities::DEFAULT_CAPABILITIES_TTL;
+use super::file_uploads::Uploads;
+use super::layers::plugin_timing;
+use super::subgraph_batching::Batch;
+use super::subgraph_batching::Batcher;
+use super::subgraph_batching::Joined;
+use super::subgraph_batching::SubgraphHttpResponse;
+use super::tolerances;
 use super::Plugins;
+use super::MULTIPART_DEFER_ACCEPT;
+use crate::configuration::Http2;
+use crate::configuration::HttpClientOptions;
+use crate::configuration::JsonNumbers;
+use crate::configuration::JsonStrings;
+use crate::configuration::LoneSurrogates;
+use crate::configuration::TlsClient;
+use crate::configuration::Tolerances;
 use crate::error::FetchError;
 use crate::graphql;
+use crate::http_ext::clone_http_request;
+use crate::json_ext::lossy_number;
+use crate::json_ext::replace_lone_surrogates;
+use crate::json_ext::Value;
+use crate::json_ext::ValueExt;
+use crate::tls::load_certificates;
+use crate::tls::load_key;
+use crate::tls::load_root_store;
 
 #[derive(PartialEq, Debug, Clone, Deserialize, JsonSchema, Copy)]
 #[serde(rename_all = "lowercase")]
@@ -65,26 +104,125 @@ impl Display for Compression {
 pub(crate) struct SubgraphService {
     client: Decompression<hyper::Client<HttpsConnector<HttpConnector>>>,
     service: Arc<String>,
+    json_numbers: JsonNumbers,
+    json_strings: JsonStrings,
+    /// Capabilities learned from the responses, if capability discovery is enabled
+    capabilities: Option<Capabilities>,
+    /// Noncompliant behaviors of the subgraph normalized in its responses
+    tolerances: Arc<Tolerances>,
+    /// Batches of the requests of an operation, if batching is enabled
+    batcher: Option<Arc<Batcher>>,
 }
 
 impl SubgraphService {
     pub(crate) fn new(service: impl Into<String>) -> Self {
-        let connector = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .build();
+        Self::with_options(
+            service,
+            None,
+            &HttpClientOptions::default(),
+            JsonNumbers::default(),
+            JsonStrings::default(),
+            Tolerances::default(),
+        )
+    }
 
+    /// Creates a subgraph client using the HTTP client options of the subgraph, and a custom TLS
+    /// configuration for HTTPS connections if one is set.
+    pub(crate) fn with_options(
+        service: impl Into<String>,
+        tls_config: Option<ClientConfig>,
+        options: &HttpClientOptions,
+        json_numbers: JsonNumbers,
+        json_strings: JsonStrings,
+        tolerances: Tolerances,
+    ) -> Self {
         Self {
-            client: ServiceBuilder::new()
-                .layer(DecompressionLayer::new())
-                .service(hyper::Client::builder().build(connector)),
+            client: http_client(tls_config, options),
             service: Arc::new(service.into()),
+            json_numbers,
+            json_strings,
+            capabilities: options
+                .experimental_capability_discovery
+                .unwrap_or_default()
+                .then(|| {
+                    Capabilities::new(options.capabilities_ttl.unwrap_or(DEFAULT_CAPABILITIES_TTL))
+                }),
+            tolerances: Arc::new(tolerances),
+            batcher: options
+                .experimental_batching
+                .unwrap_or_default()
+                .then(|| Arc::new(Batcher::default())),
         }
     }
 }
 
+/// HTTP client for the connections to a subgraph, decompressing the responses.
+pub(crate) fn http_client(
+    tls_config: Option<ClientConfig>,
+    options: &HttpClientOptions,
+) -> Decompression<hyper::Client<HttpsConnector<HttpConnector>>> {
+    let mut http_connector = HttpConnector::new();
+    http_connector.enforce_http(false);
+    http_connector.set_keepalive(options.tcp_keepalive);
+
+    let builder = match tls_config {
+        Some(tls_config) => hyper_rustls::HttpsConnectorBuilder::new().with_tls_config(tls_config),
+        None => hyper_rustls::HttpsConnectorBuilder::new().with_native_roots(),
+    }
+    .https_or_http();
+    // The protocols enabled here are the ones offered with ALPN during the TLS handshake
+    let http2 = options.http2.unwrap_or(Http2::Enable);
+    let connector = match http2 {
+        Http2::Enable => builder
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http_connector),
+        Http2::Disable => builder.enable_http1().wrap_connector(http_connector),
+        Http2::Http2Only => builder.enable_http2().wrap_connector(http_connector),
+    };
+
+    let mut client = hyper::Client::builder();
+    client.http2_only(http2 == Http2::Http2Only);
+    if let Some(max_idle) = options.pool_max_idle_per_host {
+        client.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = options.pool_idle_timeout {
+        client.pool_idle_timeout(idle_timeout);
+    }
+
+    ServiceBuilder::new()
+        .layer(DecompressionLayer::new())
+        .service(client.build(connector))
+}
+
+/// TLS configuration verifying subgraph certificates with the configured certificate authorities,
+/// or the native root certificates, and presenting the configured client certificate.
+pub(crate) fn tls_client_config(tls_client: &TlsClient) -> Result<ClientConfig, BoxError> {
+    let roots = match &tls_client.certificate_authorities {
+        Some(path) => load_root_store(path)?,
+        None => {
+            let mut roots = RootCertStore::empty();
+            let certificates: Vec<Vec<u8>> = rustls_native_certs::load_native_certs()?
+                .into_iter()
+                .map(|certificate| certificate.0)
+                .collect();
+            roots.add_parsable_certificates(&certificates);
+            roots
+        }
+    };
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    Ok(match &tls_client.client_authentication {
+        Some(auth) => builder.with_single_cert(
+            load_certificates(&auth.certificate_chain)?,
+            load_key(&auth.key)?,
+        )?,
+        None => builder.with_no_client_auth(),
+    })
+}
+
 impl tower::Service<crate::SubgraphRequest> for SubgraphService {
     type Response = crate::SubgraphResponse;
     type Error = BoxError;
@@ -98,19 +236,331 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
 
     fn call(&mut self, request: crate::SubgraphRequest) -> Self::Future {
         let crate::SubgraphRequest {
+            originating_request,
             subgraph_request,
             context,
             ..
         } = request;
 
-        let mut client = self.client.clone();
+        let client = self.client.clone();
         let service_name = (*self.service).to_owned();
+        let parser = ResponseParser {
+            service_name: service_name.clone(),
+            forbid_lossy_numbers: self.json_numbers.forbid_lossy_coercion,
+            lone_surrogates: self.json_strings.lone_surrogates,
+            tolerances: self.tolerances.clone(),
+        };
+        let capabilities = self.capabilities.clone();
+        let batcher = self.batcher.clone();
 
         Box::pin(async move {
-            let (parts, body) = subgraph_request.into_parts();
+            let (mut parts, body) = subgraph_request.into_parts();
+            if let Some(capabilities) = &capabilities {
+                match capabilities.request_encoding(parts.headers.get(CONTENT_ENCODING)) {
+                    Some(encoding) => parts.headers.insert(CONTENT_ENCODING, encoding),
+                    None => parts.headers.remove(CONTENT_ENCODING),
+                };
+            }
+            // Files uploaded by the client are streamed to the request holding their variables
+            let files = originating_request
+                .extensions()
+                .get::<Uploads>()
+                .map(|uploads| (uploads, uploads.for_variables(&body.variables)))
+                .filter(|(_, map)| !map.is_empty());
+            // Subgraphs may answer incrementally only to operations deferring fragments
+            let deferred = body.query.as_deref().map(has_defer).unwrap_or_default();
+            let persisted_queries = capabilities
+                .as_ref()
+                .map(|capabilities| capabilities.persisted_queries() != Some(false))
+                .unwrap_or_default();
+
+            let (parts, graphql) = match files {
+                Some((uploads, map)) => {
+                    let body =
+                        serde_json::to_string(&body).expect("JSON serialization should not fail");
+                    let (content_type, body) =
+                        uploads
+                            .body(body, map)
+                            .map_err(|err| FetchError::SubrequestHttpError {
+                                service: service_name.clone(),
+                                reason: err.to_string(),
+                                status: None,
+                            })?;
+                    send(
+                        client,
+                        &service_name,
+                        parts,
+                        RequestBody::Multipart(content_type, body),
+                        deferred,
+                        &capabilities,
+                        &parser,
+                    )
+                    .await
+                    .and_then(|response| parser.parse_response(response))?
+                }
+                // Batched requests are not sent as persisted queries
+                None if persisted_queries && (batcher.is_none() || deferred) => {
+                    send_persisted(
+                        client,
+                        &service_name,
+                        parts,
+                        &body,
+                        deferred,
+                        &capabilities,
+                        &parser,
+                    )
+                    .await?
+                }
+                None => {
+                    let body =
+                        serde_json::to_string(&body).expect("JSON serialization should not fail");
+                    // The response to a batch can't be a multipart response
+                    let joined = match &batcher {
+                        Some(batcher) if !deferred => batcher.join(&context, &parts, body).await,
+                        _ => Joined::Leader(Batch::single(body)),
+                    };
+                    let response = match joined {
+                        Joined::Leader(batch) => {
+                            let response = send(
+                                client,
+                                &service_name,
+                                parts,
+                                RequestBody::Json(batch.body()),
+                                deferred,
+                                &capabilities,
+                                &parser,
+                            )
+                            .await;
+                            batch.distribute(&service_name, response)?
+                        }
+                        Joined::Follower(response) => {
+                            let (parts, body) = response
+                                .await
+                                .map_err(|_| FetchError::SubrequestHttpError {
+                                    service: service_name.clone(),
+                                    reason: "the batch of requests was cancelled".to_string(),
+                                    status: None,
+                                })??
+                                .into_parts();
+                            (parts, body, None)
+                        }
+                    };
+                    parser.parse_response(response)?
+                }
+            };
 
-            let body = serde_json::to_string(&body).expect("JSON serialization should not fail");
+            let resp = http::Response::from_parts(parts, graphql);
 
+            Ok(crate::SubgraphResponse::new_from_response(resp, context))
+        })
+    }
+}
+
+/// Parses the responses of a subgraph, or the payloads of its multipart responses.
+struct ResponseParser {
+    service_name: String,
+    forbid_lossy_numbers: bool,
+    lone_surrogates: LoneSurrogates,
+    /// Noncompliant behaviors of the subgraph normalized in its responses
+    tolerances: Arc<Tolerances>,
+}
+
+impl ResponseParser {
+    fn parse(&self, body: Bytes) -> Result<graphql::Response, FetchError> {
+        if self.forbid_lossy_numbers {
+            if let Some(number) = lossy_number(&body) {
+                return Err(FetchError::SubrequestMalformedResponse {
+                    service: self.service_name.clone(),
+                    reason: format!(
+                        "the number {} can't be parsed without losing precision",
+                        number
+                    ),
+                });
+            }
+        }
+
+        let body = match self.lone_surrogates {
+            LoneSurrogates::Replace => replace_lone_surrogates(&body).map_or(body, Bytes::from),
+            LoneSurrogates::Error => body,
+        };
+
+        if self.tolerances.normalizes_responses() {
+            parse_tolerantly(&self.service_name, &self.tolerances, body)
+        } else {
+            graphql::Response::from_bytes(&self.service_name, body)
+        }
+        .map_err(|error| FetchError::SubrequestMalformedResponse {
+            service: self.service_name.clone(),
+            reason: error.to_string(),
+        })
+    }
+
+    /// Parses the body of a response, unless it was merged from a multipart response.
+    fn parse_response(
+        &self,
+        (parts, body, merged): SubgraphHttpResponse,
+    ) -> Result<(http::response::Parts, graphql::Response), FetchError> {
+        let graphql = match merged {
+            Some(response) => response,
+            None => {
+                tracing::debug_span!("parse_subgraph_response").in_scope(|| self.parse(body))?
+            }
+        };
+        Ok((parts, graphql))
+    }
+}
+
+/// Whether the operation defers fragments, so that the subgraph may answer incrementally.
+fn has_defer(query: &str) -> bool {
+    // Most operations don't, they are not parsed
+    query.contains("@defer")
+        && apollo_parser::Parser::new(query)
+            .parse()
+            .document()
+            .syntax()
+            .descendants()
+            .filter_map(ast::Directive::cast)
+            .any(|directive| {
+                directive
+                    .name()
+                    .map(|name| name.text() == "defer")
+                    .unwrap_or_default()
+            })
+}
+
+/// Sends a query as an automatic persisted query: the hash of the query only, then the query with
+/// its hash if the subgraph doesn't know the hash yet, or the original request if it turns out
+/// the subgraph doesn't support persisted queries. What the subgraph supports is learned.
+async fn send_persisted(
+    client: Decompression<hyper::Client<HttpsConnector<HttpConnector>>>,
+    service_name: &str,
+    parts: http::request::Parts,
+    body: &graphql::Request,
+    deferred: bool,
+    capabilities: &Option<Capabilities>,
+    parser: &ResponseParser,
+) -> Result<(http::response::Parts, graphql::Response), FetchError> {
+    let hash = hex::encode(Sha256::digest(
+        body.query.as_deref().unwrap_or_default().as_bytes(),
+    ));
+    let mut persisted = body.clone();
+    persisted.extensions.insert(
+        ByteString::from("persistedQuery"),
+        serde_json_bytes::json!({ "version": 1, "sha256Hash": hash }),
+    );
+    let mut hash_only =
+        serde_json::to_value(&persisted).expect("JSON serialization should not fail");
+    if let Some(hash_only) = hash_only.as_object_mut() {
+        hash_only.remove("query");
+    }
+
+    let request = http::Request::from_parts(parts, ());
+    let (parts, ()) = clone_http_request(&request).into_parts();
+    let response = send(
+        client.clone(),
+        service_name,
+        parts,
+        RequestBody::Json(hash_only.to_string()),
+        deferred,
+        capabilities,
+        parser,
+    )
+    .await
+    .and_then(|response| parser.parse_response(response));
+
+    let resend = match &response {
+        Ok((_, response)) if has_error_code(response, PERSISTED_QUERY_NOT_FOUND) => {
+            Some((true, &persisted))
+        }
+        // A subgraph ignoring the extension rejects a request without a query
+        Ok((_, response))
+            if has_error_code(response, PERSISTED_QUERY_NOT_SUPPORTED)
+                || (response.data.is_none() && !response.errors.is_empty()) =>
+        {
+            Some((false, body))
+        }
+        Ok(_) => {
+            if let Some(capabilities) = capabilities {
+                capabilities.observe_persisted_queries(true);
+            }
+            None
+        }
+        Err(FetchError::SubrequestHttpError {
+            status: Some(status),
+            reason,
+            ..
+        }) if (400..500).contains(status) => {
+            if reason.contains(PERSISTED_QUERY_NOT_FOUND) {
+                Some((true, &persisted))
+            } else {
+                Some((false, body))
+            }
+        }
+        Err(_) => None,
+    };
+    match resend {
+        None => response,
+        Some((supported, resent)) => {
+            tracing::debug!(
+                "resending the query to subgraph {}, it supports persisted queries: {}",
+                service_name,
+                supported
+            );
+            if let Some(capabilities) = capabilities {
+                capabilities.observe_persisted_queries(supported);
+            }
+            let (parts, ()) = request.into_parts();
+            let body = serde_json::to_string(resent).expect("JSON serialization should not fail");
+            send(
+                client,
+                service_name,
+                parts,
+                RequestBody::Json(body),
+                deferred,
+                capabilities,
+                parser,
+            )
+            .await
+            .and_then(|response| parser.parse_response(response))
+        }
+    }
+}
+
+const PERSISTED_QUERY_NOT_FOUND: &str = "PERSISTED_QUERY_NOT_FOUND";
+const PERSISTED_QUERY_NOT_SUPPORTED: &str = "PERSISTED_QUERY_NOT_SUPPORTED";
+
+fn has_error_code(response: &graphql::Response, code: &str) -> bool {
+    response.errors.iter().any(|error| {
+        error
+            .extensions
+            .get("code")
+            .and_then(|value| value.as_str())
+            == Some(code)
+    })
+}
+
+/// Body of a request to a subgraph.
+enum RequestBody {
+    /// A GraphQL request, or a batch of them, compressed as configured
+    Json(String),
+    /// A `multipart/form-data` request streaming the files uploaded by the client
+    Multipart(HeaderValue, hyper::Body),
+}
+
+/// Sends a request, or a batch of requests, to a subgraph and checks the HTTP response. A
+/// multipart response to a deferring operation is read and merged by the parser as it arrives.
+async fn send(
+    mut client: Decompression<hyper::Client<HttpsConnector<HttpConnector>>>,
+    service_name: &str,
+    mut parts: http::request::Parts,
+    body: RequestBody,
+    deferred: bool,
+    capabilities: &Option<Capabilities>,
+    parser: &ResponseParser,
+) -> Result<SubgraphHttpResponse, FetchError> {
+    let app_json: HeaderValue = HeaderValue::from_static("application/json");
+    let (content_type, body) = match body {
+        RequestBody::Json(body) => {
             let compressed_body = compress(body, &parts.headers)
                 .instrument(tracing::debug_span!("body_compression"))
                 .await
@@ -118,111 +568,375 @@ impl tower::Service<crate::SubgraphRequest> for SubgraphService {
                     tracing::error!(compress_error = format!("{:?}", err).as_str());
 
                     FetchError::CompressionError {
-                        service: service_name.clone(),
+                        service: service_name.to_string(),
                         reason: err.to_string(),
                     }
                 })?;
+            (app_json.clone(), compressed_body.into())
+        }
+        RequestBody::Multipart(content_type, body) => {
+            parts.headers.remove(CONTENT_ENCODING);
+            // Apollo Server rejects multipart requests without this header, to prevent CSRF
+            parts.headers.insert(
+                HeaderName::from_static("apollo-require-preflight"),
+                HeaderValue::from_static("true"),
+            );
+            (content_type, body)
+        }
+    };
+    let compressed = parts
+        .headers
+        .get(CONTENT_ENCODING)
+        .map(|encoding| encoding != "identity")
+        .unwrap_or_default();
 
-            let mut request = http::request::Request::from_parts(parts, compressed_body.into());
-            let app_json: HeaderValue = HeaderValue::from_static("application/json");
-            let app_graphql_json: HeaderValue =
-                HeaderValue::from_static("application/graphql+json");
-            request.headers_mut().insert(CONTENT_TYPE, app_json.clone());
-            request.headers_mut().insert(ACCEPT, app_json);
-            request.headers_mut().append(ACCEPT, app_graphql_json);
-
-            get_text_map_propagator(|propagator| {
-                propagator.inject_context(
-                    &Span::current().context(),
-                    &mut opentelemetry_http::HeaderInjector(request.headers_mut()),
-                )
-            });
-
-            let schema_uri = request.uri();
-            let host = schema_uri.host().map(String::from).unwrap_or_default();
-            let port = schema_uri.port_u16().unwrap_or_else(|| {
-                let scheme = schema_uri.scheme_str();
-                if scheme == Some("https") {
-                    443
-                } else if scheme == Some("http") {
-                    80
-                } else {
-                    0
-                }
-            });
-            let path = schema_uri.path().to_string();
-            let response = client
-                .call(request)
-                .instrument(tracing::info_span!("subgraph_request",
-                    "otel.kind" = %SpanKind::Client,
-                    "net.peer.name" = &display(host),
-                    "net.peer.port" = &display(port),
-                    "http.route" = &display(path),
-                    "net.transport" = "ip_tcp"
-                ))
-                .await
-                .map_err(|err| {
-                    tracing::error!(fetch_error = format!("{:?}", err).as_str());
+    let mut request = http::request::Request::from_parts(parts, body);
+    let app_graphql_json: HeaderValue = HeaderValue::from_static("application/graphql+json");
+    request.headers_mut().insert(CONTENT_TYPE, content_type);
+    request.headers_mut().insert(ACCEPT, app_json);
+    request.headers_mut().append(ACCEPT, app_graphql_json);
+    // Unless the subgraph is known to answer them with a single response
+    let offers_multipart = deferred
+        && capabilities
+            .as_ref()
+            .and_then(Capabilities::incremental_delivery)
+            != Some(false);
+    if offers_multipart {
+        request
+            .headers_mut()
+            .append(ACCEPT, HeaderValue::from_static(MULTIPART_DEFER_ACCEPT));
+    }
 
-                    FetchError::SubrequestHttpError {
-                        service: service_name.clone(),
-                        reason: err.to_string(),
-                    }
-                })?;
+    get_text_map_propagator(|propagator| {
+        propagator.inject_context(
+            &Span::current().context(),
+            &mut opentelemetry_http::HeaderInjector(request.headers_mut()),
+        )
+    });
 
-            // Keep our parts, we'll need them later
-            let (parts, body) = response.into_parts();
-            if let Some(content_type) = parts.headers.get(header::CONTENT_TYPE) {
-                if let Ok(content_type_str) = content_type.to_str() {
-                    // Using .contains because sometimes we could have charset included (example: "application/json; charset=utf-8")
-                    if !content_type_str.contains("application/json")
-                        && !content_type_str.contains("application/graphql+json")
-                    {
-                        return Err(BoxError::from(FetchError::SubrequestHttpError {
-                            service: service_name.clone(),
-                            reason: format!("subgraph didn't return JSON (expected content-type: application/json or content-type: application/graphql+json; found content-type: {content_type:?})"),
-                        }));
-                    }
-                }
+    let schema_uri = request.uri();
+    let host = schema_uri.host().map(String::from).unwrap_or_default();
+    let port = schema_uri.port_u16().unwrap_or_else(|| {
+        let scheme = schema_uri.scheme_str();
+        if scheme == Some("https") {
+            443
+        } else if scheme == Some("http") {
+            80
+        } else {
+            0
+        }
+    });
+    let path = schema_uri.path().to_string();
+    let response = client
+        .call(request)
+        .instrument(tracing::info_span!("subgraph_request",
+            "otel.kind" = %SpanKind::Client,
+            "net.peer.name" = &display(host),
+            "net.peer.port" = &display(port),
+            "http.route" = &display(path),
+            "net.transport" = "ip_tcp"
+        ))
+        .await
+        .map_err(|err| {
+            tracing::error!(fetch_error = format!("{:?}", err).as_str());
+            if let Some(capabilities) = capabilities {
+                capabilities.forget();
+            }
+
+            FetchError::SubrequestHttpError {
+                service: service_name.to_string(),
+                reason: err.to_string(),
+                status: None,
+            }
+        })?;
+
+    // Keep our parts, we'll need them later
+    let (parts, body) = response.into_parts();
+    if let Some(capabilities) = capabilities {
+        capabilities.observe(parts.status, &parts.headers, compressed);
+    }
+    // A subgraph shedding load tells when to come back, whatever the format of its body
+    if let Some(retry_after) = retry_after(parts.status, &parts.headers) {
+        let service = service_name.to_string();
+        return Err(if parts.status == StatusCode::TOO_MANY_REQUESTS {
+            FetchError::SubrequestRateLimited {
+                service,
+                retry_after,
+            }
+        } else {
+            FetchError::SubrequestUnavailable {
+                service,
+                retry_after,
+            }
+        });
+    }
+    let mut multipart_content_type = None;
+    if let Some(content_type) = parts.headers.get(header::CONTENT_TYPE) {
+        if let Ok(content_type_str) = content_type.to_str() {
+            if content_type_str.starts_with("multipart/mixed") {
+                multipart_content_type = Some(content_type_str.to_string());
+            }
+            // Using .contains because sometimes we could have charset included (example: "application/json; charset=utf-8")
+            else if !content_type_str.contains("application/json")
+                && !content_type_str.contains("application/graphql+json")
+                && !parser.tolerances.non_json_content_type.unwrap_or_default()
+            {
+                return Err(FetchError::SubrequestHttpError {
+                    service: service_name.to_string(),
+                    reason: format!("subgraph didn't return JSON (expected content-type: application/json or content-type: application/graphql+json; found content-type: {content_type:?})"),
+                    status: None,
+                });
             }
+        }
+    }
 
-            let body = hyper::body::to_bytes(body)
+    if parts.status == StatusCode::OK {
+        if let (true, Some(capabilities)) = (offers_multipart, capabilities) {
+            capabilities.observe_incremental_delivery(multipart_content_type.is_some());
+        }
+        if let Some(content_type) = &multipart_content_type {
+            let response = read_multipart(service_name, content_type, body, parser)
                 .instrument(tracing::debug_span!("aggregate_response_data"))
-                .await
-                .map_err(|err| {
-                    tracing::error!(fetch_error = format!("{:?}", err).as_str());
+                .await?;
+            return Ok((parts, Bytes::new(), Some(response)));
+        }
+    }
 
-                    FetchError::SubrequestHttpError {
-                        service: service_name.clone(),
-                        reason: err.to_string(),
-                    }
-                })?;
-            if parts.status != StatusCode::OK {
-                return Err(BoxError::from(FetchError::SubrequestHttpError {
-                    service: service_name.clone(),
-                    reason: format!(
-                        "subgraph HTTP status error '{}': {}",
-                        parts.status,
-                        String::from_utf8_lossy(&body)
-                    ),
-                }));
+    let body = hyper::body::to_bytes(body)
+        .instrument(tracing::debug_span!("aggregate_response_data"))
+        .await
+        .map_err(|err| {
+            tracing::error!(fetch_error = format!("{:?}", err).as_str());
+
+            FetchError::SubrequestHttpError {
+                service: service_name.to_string(),
+                reason: err.to_string(),
+                status: None,
             }
+        })?;
+    if parts.status != StatusCode::OK {
+        return Err(FetchError::SubrequestHttpError {
+            service: service_name.to_string(),
+            reason: format!(
+                "subgraph HTTP status error '{}': {}",
+                parts.status,
+                String::from_utf8_lossy(&body)
+            ),
+            status: Some(parts.status.as_u16()),
+        });
+    }
+    Ok((parts, body, None))
+}
 
-            let graphql: graphql::Response = tracing::debug_span!("parse_subgraph_response")
-                .in_scope(|| {
-                    graphql::Response::from_bytes(&service_name, body).map_err(|error| {
-                        FetchError::SubrequestMalformedResponse {
-                            service: service_name.clone(),
-                            reason: error.to_string(),
-                        }
-                    })
-                })?;
+/// Parses the response of a subgraph with tolerances, normalizing its noncompliant behaviors.
+fn parse_tolerantly(
+    service_name: &str,
+    tolerances: &Tolerances,
+    body: Bytes,
+) -> Result<graphql::Response, FetchError> {
+    let mut value =
+        Value::from_bytes(body).map_err(|error| FetchError::SubrequestMalformedResponse {
+            service: service_name.to_string(),
+            reason: error.to_string(),
+        })?;
+    let fixed = tolerances::normalize(tolerances, &mut value);
+    if !fixed.is_empty() {
+        tracing::warn!(
+            "normalized a noncompliant response from subgraph '{}': {}",
+            service_name,
+            fixed.join(", ")
+        );
+    }
+    graphql::Response::from_value(service_name, value)
+}
 
-            let resp = http::Response::from_parts(parts, graphql);
+/// Reads the `multipart/mixed` response of a subgraph delivering `@defer` results
+/// incrementally. The fetch completes with a single response, so each payload is parsed and
+/// merged into the initial response as soon as its part is received, without keeping the body.
+async fn read_multipart<B>(
+    service_name: &str,
+    content_type: &str,
+    body: B,
+    parser: &ResponseParser,
+) -> Result<graphql::Response, FetchError>
+where
+    B: HttpBody<Data = Bytes>,
+    B::Error: Display,
+{
+    let malformed = |reason: &str| FetchError::SubrequestMalformedResponse {
+        service: service_name.to_string(),
+        reason: reason.to_string(),
+    };
+    // The boundary defaults to `-` in the incremental delivery specification
+    let boundary = MediaType::parse(content_type)
+        .ok()
+        .and_then(|media_type| Some(media_type.get_param(BOUNDARY)?.unquoted_str().into_owned()))
+        .unwrap_or_else(|| "-".to_string());
 
-            Ok(crate::SubgraphResponse::new_from_response(resp, context))
-        })
+    let mut parts = MultipartParts::new(&boundary);
+    let mut response: Option<graphql::Response> = None;
+    let mut body = Box::pin(body);
+    while !parts.closed {
+        let chunk = match body.data().await {
+            Some(chunk) => chunk.map_err(|err| FetchError::SubrequestHttpError {
+                service: service_name.to_string(),
+                reason: err.to_string(),
+                status: None,
+            })?,
+            None => {
+                return Err(malformed(
+                    "the multipart response ended before its closing delimiter",
+                ))
+            }
+        };
+        for payload in parts.push(&chunk).map_err(malformed)? {
+            let payload = parser.parse(payload)?;
+            match response.as_mut() {
+                None => response = Some(payload),
+                Some(response) => merge_increment(response, payload),
+            }
+        }
+    }
+
+    let mut response = response.ok_or_else(|| malformed("the multipart response is empty"))?;
+    response.has_next = None;
+    Ok(response)
+}
+
+/// Splits the body of a `multipart/mixed` response into the payloads of its parts, as its chunks
+/// are received. As specified by RFC 2046, a delimiter is a line starting with `--` and the
+/// boundary, and the CRLF preceding it belongs to the delimiter. The closing delimiter ends with
+/// `--`, the epilogue after it is ignored.
+struct MultipartParts {
+    /// CRLF, `--` and the boundary
+    delimiter: Vec<u8>,
+    buffer: BytesMut,
+    /// Length of the start of the buffer already searched for a delimiter
+    scanned: usize,
+    /// Whether the first delimiter was received, the preamble before it is ignored
+    started: bool,
+    closed: bool,
+}
+
+impl MultipartParts {
+    fn new(boundary: &str) -> Self {
+        let mut buffer = BytesMut::new();
+        // The first delimiter can start the body, without a CRLF before it
+        buffer.extend_from_slice(b"\r\n");
+        Self {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            buffer,
+            scanned: 0,
+            started: false,
+            closed: false,
+        }
+    }
+
+    /// Adds a chunk of the body, and returns the payloads of the parts it completes.
+    fn push(&mut self, chunk: &[u8]) -> Result<Vec<Bytes>, &'static str> {
+        let mut payloads = Vec::new();
+        if self.closed {
+            return Ok(payloads);
+        }
+        self.buffer.extend_from_slice(chunk);
+
+        while let Some(position) =
+            find(&self.buffer[self.scanned..], &self.delimiter).map(|index| index + self.scanned)
+        {
+            let after = position + self.delimiter.len();
+            let rest = &self.buffer[after..];
+            // The delimiter is complete with its line, which tells whether it closes the body
+            let closing = rest.starts_with(b"--");
+            let line_end = if closing {
+                Some(2)
+            } else {
+                find(rest, b"\r\n").map(|end| end + 2)
+            };
+            let line_end = match line_end {
+                Some(line_end) => after + line_end,
+                None => {
+                    self.scanned = position;
+                    return Ok(payloads);
+                }
+            };
+
+            let part = self.buffer.split_to(line_end).freeze();
+            self.scanned = 0;
+            if self.started {
+                payloads.extend(part_payload(part.slice(..position))?);
+            }
+            self.started = true;
+            if closing {
+                self.closed = true;
+                self.buffer.clear();
+                return Ok(payloads);
+            }
+        }
+        // A delimiter may start at the end of the buffer and be completed by the next chunk
+        self.scanned = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+        Ok(payloads)
+    }
+}
+
+/// The payload of a part, after its headers, or `None` if it is empty.
+fn part_payload(part: Bytes) -> Result<Option<Bytes>, &'static str> {
+    // A part without headers starts with the empty line ending them
+    let payload = if part.starts_with(b"\r\n") {
+        part.slice(2..)
+    } else {
+        match find(&part, b"\r\n\r\n") {
+            Some(end) => part.slice(end + 4..),
+            None if part.iter().all(u8::is_ascii_whitespace) => return Ok(None),
+            None => return Err("a part of the multipart response has no body"),
+        }
+    };
+    let start = payload.iter().position(|byte| !byte.is_ascii_whitespace());
+    let end = payload.iter().rposition(|byte| !byte.is_ascii_whitespace());
+    Ok(match (start, end) {
+        (Some(start), Some(end)) => Some(payload.slice(start..=end)),
+        _ => None,
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Merges a subsequent payload of an incremental response into the initial response.
+fn merge_increment(response: &mut graphql::Response, mut payload: graphql::Response) {
+    response.errors.append(&mut payload.errors);
+    response.extensions.extend(payload.extensions);
+    let mut increments = vec![(payload.path, payload.data)];
+    for mut incremental in payload.incremental {
+        response.errors.append(&mut incremental.errors);
+        response.extensions.extend(incremental.extensions);
+        increments.push((incremental.path, incremental.data));
+    }
+    for (path, data) in increments {
+        if let (Some(path), Some(data)) = (path, data) {
+            response
+                .data
+                .get_or_insert_with(|| Value::Object(Default::default()))
+                .deep_merge(Value::from_path(&path, data));
+        }
+    }
+}
+
+/// Delay in seconds requested by a subgraph rejecting a request with a 429 or 503 status. The
+/// `Retry-After` header contains either a number of seconds or an HTTP date.
+fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<u64> {
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
     }
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    value.parse().ok().or_else(|| {
+        let date = httpdate::parse_http_date(value).ok()?;
+        // A date in the past means the subgraph can be called again right away
+        let delay = date.duration_since(SystemTime::now()).unwrap_or_default();
+        Some(delay.as_secs() + u64::from(delay.subsec_nanos() > 0))
+    })
 }
 
 pub(crate) async fn compress(body: String, headers: &HeaderMap) -> Result<Vec<u8>, BoxError> {
@@ -326,19 +1040,22 @@ impl SubgraphServiceFactory for SubgraphCreator {
     fn new_service(&self, name: &str) -> Option<Self::SubgraphService> {
         self.services.get(name).map(|service| {
             let service = service.make();
-            self.plugins
-                .iter()
-                .rev()
-                .fold(service, |acc, (_, e)| e.subgraph_service(name, acc))
+            self.plugins.iter().rev().fold(service, |acc, (plugin, e)| {
+                plugin_timing::timed(plugin, "subgraph", acc, |service| {
+                    e.subgraph_service(name, service)
+                })
+            })
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::convert::Infallible;
     use std::net::SocketAddr;
     use std::str::FromStr;
+    use std::sync::Mutex;
 
     use axum::Server;
     use http::header::HOST;
@@ -506,6 +1223,722 @@ mod tests {
         );
     }
 
+    // starts a local server emulating a noncompliant subgraph
+    async fn emulate_noncompliant_subgraph(socket_addr: SocketAddr) {
+        async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            Ok(http::Response::builder()
+                .header("Content-Type", "text/plain")
+                .status(StatusCode::OK)
+                .body(r#"{"data":null,"errors":{"code":"NOT_FOUND"}}"#.into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tolerances() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:3131").unwrap();
+        tokio::task::spawn(emulate_noncompliant_subgraph(socket_addr));
+        let subgraph_service = SubgraphService::with_options(
+            "test",
+            None,
+            &HttpClientOptions::default(),
+            JsonNumbers::default(),
+            JsonStrings::default(),
+            Tolerances {
+                non_json_content_type: Some(true),
+                errors_without_message: Some(true),
+                response_shape: Some(true),
+            },
+        );
+
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let response = subgraph_service
+            .oneshot(SubgraphRequest {
+                originating_request: Arc::new(
+                    http::Request::builder()
+                        .header(HOST, "host")
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Request::builder().query("query").build())
+                        .expect("expecting valid request"),
+                ),
+                subgraph_request: http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+                operation_kind: OperationKind::Query,
+                context: Context::new(),
+            })
+            .await
+            .unwrap();
+        let errors = &response.response.body().errors;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Subgraph error without a message");
+    }
+
+    // starts a local server emulating a subgraph answering batches of requests
+    async fn emulate_batching_subgraph(socket_addr: SocketAddr) {
+        async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+            let requests: Vec<Request> = serde_json::from_slice(&body).unwrap();
+            let responses: Vec<serde_json::Value> = requests
+                .iter()
+                .map(|request| serde_json::json!({ "data": { "query": request.query } }))
+                .collect();
+            Ok(http::Response::builder()
+                .header("Content-Type", "application/json")
+                .status(StatusCode::OK)
+                .body(serde_json::to_string(&responses).unwrap().into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_batching() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:3232").unwrap();
+        tokio::task::spawn(emulate_batching_subgraph(socket_addr));
+        let subgraph_service = SubgraphService::with_options(
+            "test",
+            None,
+            &HttpClientOptions {
+                experimental_batching: Some(true),
+                ..Default::default()
+            },
+            JsonNumbers::default(),
+            JsonStrings::default(),
+            Tolerances::default(),
+        );
+
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let context = Context::new();
+        let request = |query: &str| SubgraphRequest {
+            originating_request: Arc::new(
+                http::Request::builder()
+                    .header(HOST, "host")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+            ),
+            subgraph_request: http::Request::builder()
+                .header(HOST, "rhost")
+                .header(CONTENT_TYPE, "application/json")
+                .uri(url.clone())
+                .body(Request::builder().query(query).build())
+                .expect("expecting valid request"),
+            operation_kind: OperationKind::Query,
+            context: context.clone(),
+        };
+        let (first, second) = tokio::join!(
+            subgraph_service.clone().oneshot(request("first")),
+            subgraph_service.clone().oneshot(request("second")),
+        );
+        assert_eq!(
+            first.unwrap().response.body().data,
+            Some(serde_json_bytes::json!({ "query": "first" }))
+        );
+        assert_eq!(
+            second.unwrap().response.body().data,
+            Some(serde_json_bytes::json!({ "query": "second" }))
+        );
+    }
+
+    // starts a local server emulating a subgraph returning an integer beyond 64 bits
+    async fn emulate_subgraph_large_integer(socket_addr: SocketAddr) {
+        async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            Ok(http::Response::builder()
+                .header("Content-Type", "application/json")
+                .status(StatusCode::OK)
+                .body(r#"{"data":{"id":123456789012345678901234}}"#.into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_forbid_lossy_numbers() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:2424").unwrap();
+        tokio::task::spawn(emulate_subgraph_large_integer(socket_addr));
+        let subgraph_service = SubgraphService::with_options(
+            "test",
+            None,
+            &HttpClientOptions::default(),
+            JsonNumbers {
+                forbid_lossy_coercion: true,
+                ..Default::default()
+            },
+            JsonStrings::default(),
+            Tolerances::default(),
+        );
+
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let err = subgraph_service
+            .oneshot(SubgraphRequest {
+                originating_request: Arc::new(
+                    http::Request::builder()
+                        .header(HOST, "host")
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Request::builder().query("query").build())
+                        .expect("expecting valid request"),
+                ),
+                subgraph_request: http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+                operation_kind: OperationKind::Query,
+                context: Context::new(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "service 'test' response was malformed: the number 123456789012345678901234 can't be parsed without losing precision"
+        );
+    }
+
+    // starts a local server emulating a subgraph returning a lone surrogate
+    async fn emulate_subgraph_lone_surrogate(socket_addr: SocketAddr) {
+        async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            Ok(http::Response::builder()
+                .header("Content-Type", "application/json")
+                .status(StatusCode::OK)
+                .body(r#"{"data":"caf\u00e9 \ud800"}"#.into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replace_lone_surrogates() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:2323").unwrap();
+        tokio::task::spawn(emulate_subgraph_lone_surrogate(socket_addr));
+        let subgraph_service = SubgraphService::with_options(
+            "test",
+            None,
+            &HttpClientOptions::default(),
+            JsonNumbers::default(),
+            JsonStrings {
+                lone_surrogates: LoneSurrogates::Replace,
+                ..Default::default()
+            },
+            Tolerances::default(),
+        );
+
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let resp = subgraph_service
+            .oneshot(SubgraphRequest {
+                originating_request: Arc::new(
+                    http::Request::builder()
+                        .header(HOST, "host")
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Request::builder().query("query").build())
+                        .expect("expecting valid request"),
+                ),
+                subgraph_request: http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+                operation_kind: OperationKind::Query,
+                context: Context::new(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.response.body(),
+            &Response::builder()
+                .data(Value::String(ByteString::from("caf\u{e9} \u{fffd}")))
+                .build()
+        );
+    }
+
+    fn parser() -> ResponseParser {
+        ResponseParser {
+            service_name: "test".to_string(),
+            forbid_lossy_numbers: false,
+            lone_surrogates: LoneSurrogates::default(),
+            tolerances: Default::default(),
+        }
+    }
+
+    const MULTIPART_BODY: &[u8] = b"--graphql\r\ncontent-type: application/json\r\n\r\n\
+        {\"data\":{\"me\":{\"id\":\"1\",\"reviews\":[{\"id\":\"1\"},{\"id\":\"2\"}]}},\"hasNext\":true}\
+        \r\n--graphql\r\ncontent-type: application/json\r\n\r\n\
+        {\"hasNext\":true,\"incremental\":[{\"data\":{\"body\":\"great --graphql\"},\"path\":[\"me\",\"reviews\",1]}]}\
+        \r\n--graphql\r\ncontent-type: application/json\r\n\r\n\
+        {\"hasNext\":false,\"incremental\":[{\"data\":{\"name\":null},\"path\":[\"me\"],\
+        \"errors\":[{\"message\":\"name is unavailable\",\"path\":[\"me\",\"name\"]}]}]}\
+        \r\n--graphql--\r\n";
+
+    #[tokio::test]
+    async fn test_read_multipart() {
+        // the body is received in small chunks, splitting the delimiters
+        let chunks: Vec<Result<Bytes, Infallible>> = MULTIPART_BODY
+            .chunks(7)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let response = read_multipart(
+            "test",
+            "multipart/mixed; boundary=\"graphql\"; deferSpec=20220824",
+            Body::wrap_stream(futures::stream::iter(chunks)),
+            &parser(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.data,
+            Some(serde_json_bytes::json!({
+                "me": {
+                    "id": "1",
+                    "reviews": [{ "id": "1" }, { "id": "2", "body": "great --graphql" }],
+                    "name": null
+                }
+            }))
+        );
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].message, "name is unavailable");
+        assert_eq!(response.has_next, None);
+
+        assert!(
+            read_multipart("test", "multipart/mixed", Body::from("{}"), &parser())
+                .await
+                .is_err()
+        );
+        // the closing delimiter is missing
+        let truncated = &MULTIPART_BODY[..MULTIPART_BODY.len() - 13];
+        assert!(read_multipart(
+            "test",
+            "multipart/mixed; boundary=\"graphql\"",
+            Body::from(truncated),
+            &parser()
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn test_multipart_parts() {
+        let mut parts = MultipartParts::new("-");
+        // the preamble is ignored, and parts without headers start with an empty line
+        let payloads = parts
+            .push(b"preamble\r\n---\r\n\r\n{\"data\":{}}\r\n---\r\ncontent-type: application/json\r\n\r\n")
+            .unwrap();
+        assert_eq!(payloads, vec![Bytes::from_static(b"{\"data\":{}}")]);
+        let payloads = parts
+            .push(b"{\"hasNext\":false}\r\n-----\r\nepilogue")
+            .unwrap();
+        assert_eq!(payloads, vec![Bytes::from_static(b"{\"hasNext\":false}")]);
+        assert!(parts.closed);
+    }
+
+    #[test]
+    fn test_has_defer() {
+        assert!(has_defer(
+            "{ me { id ... @defer(label: \"reviews\") { reviews { id } } } }"
+        ));
+        assert!(!has_defer("{ me { id } }"));
+        assert!(!has_defer("{ me { note(text: \"@defer\") } }"));
+    }
+
+    // starts a local server emulating a subgraph answering deferring operations incrementally
+    async fn emulate_deferring_subgraph(socket_addr: SocketAddr) {
+        async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            let multipart = request
+                .headers()
+                .get_all(ACCEPT)
+                .iter()
+                .any(|value| value.to_str().unwrap().starts_with("multipart/mixed"));
+            Ok(if multipart {
+                http::Response::builder()
+                    .header(
+                        CONTENT_TYPE,
+                        "multipart/mixed;boundary=\"graphql\";deferSpec=20220824",
+                    )
+                    .status(StatusCode::OK)
+                    .body(Body::from(MULTIPART_BODY))
+                    .unwrap()
+            } else {
+                http::Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .status(StatusCode::OK)
+                    .body(r#"{"data":{"me":{"id":"1"}}}"#.into())
+                    .unwrap()
+            })
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_multipart_accepted_for_deferring_operations() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:3333").unwrap();
+        tokio::task::spawn(emulate_deferring_subgraph(socket_addr));
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let request = |query: &str| SubgraphRequest {
+            originating_request: Arc::new(
+                http::Request::builder()
+                    .header(HOST, "host")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Request::builder().query(query).build())
+                    .expect("expecting valid request"),
+            ),
+            subgraph_request: http::Request::builder()
+                .header(HOST, "rhost")
+                .header(CONTENT_TYPE, "application/json")
+                .uri(url.clone())
+                .body(Request::builder().query(query).build())
+                .expect("expecting valid request"),
+            operation_kind: OperationKind::Query,
+            context: Context::new(),
+        };
+
+        let response = SubgraphService::new("test")
+            .oneshot(request("{ me { id } }"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.response.body().data,
+            Some(serde_json_bytes::json!({ "me": { "id": "1" } }))
+        );
+
+        let response = SubgraphService::new("test")
+            .oneshot(request(
+                "{ me { id ... @defer { reviews { id body } name } } }",
+            ))
+            .await
+            .unwrap();
+        let body = response.response.body();
+        assert_eq!(
+            body.data,
+            Some(serde_json_bytes::json!({
+                "me": {
+                    "id": "1",
+                    "reviews": [{ "id": "1" }, { "id": "2", "body": "great --graphql" }],
+                    "name": null
+                }
+            }))
+        );
+        assert_eq!(body.errors.len(), 1);
+    }
+
+    // starts a local server emulating a subgraph supporting automatic persisted queries or not,
+    // recording the requests it receives
+    async fn emulate_persisted_queries_subgraph(
+        socket_addr: SocketAddr,
+        supported: bool,
+        received: Arc<Mutex<Vec<serde_json::Value>>>,
+    ) {
+        let known = Arc::new(Mutex::new(HashSet::new()));
+        let make_svc = make_service_fn(move |_conn| {
+            let received = received.clone();
+            let known = known.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: http::Request<Body>| {
+                    let received = received.clone();
+                    let known = known.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                        received.lock().unwrap().push(body.clone());
+                        let hash = body
+                            .pointer("/extensions/persistedQuery/sha256Hash")
+                            .and_then(|hash| hash.as_str())
+                            .map(str::to_string);
+                        let (status, response) = match (body.get("query"), hash) {
+                            (None, Some(_)) if !supported => (
+                                StatusCode::BAD_REQUEST,
+                                serde_json::json!({ "errors": [{
+                                    "message": "GraphQL operations must contain a non-empty `query`"
+                                }] }),
+                            ),
+                            (None, Some(hash)) if !known.lock().unwrap().contains(&hash) => (
+                                StatusCode::OK,
+                                serde_json::json!({ "errors": [{
+                                    "message": "PersistedQueryNotFound",
+                                    "extensions": { "code": "PERSISTED_QUERY_NOT_FOUND" }
+                                }] }),
+                            ),
+                            (query, hash) => {
+                                if let (Some(_), Some(hash)) = (query, hash) {
+                                    known.lock().unwrap().insert(hash);
+                                }
+                                (
+                                    StatusCode::OK,
+                                    serde_json::json!({ "data": { "me": { "id": "1" } } }),
+                                )
+                            }
+                        };
+                        Ok::<_, Infallible>(
+                            http::Response::builder()
+                                .header(CONTENT_TYPE, "application/json")
+                                .status(status)
+                                .body(Body::from(response.to_string()))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_persisted_queries_discovery() {
+        for (port, supported) in [(3434, true), (3535, false)] {
+            let socket_addr = SocketAddr::from(([127, 0, 0, 1], port));
+            let received = Arc::new(Mutex::new(Vec::new()));
+            tokio::task::spawn(emulate_persisted_queries_subgraph(
+                socket_addr,
+                supported,
+                received.clone(),
+            ));
+            let subgraph_service = SubgraphService::with_options(
+                "test",
+                None,
+                &HttpClientOptions {
+                    experimental_capability_discovery: Some(true),
+                    ..Default::default()
+                },
+                JsonNumbers::default(),
+                JsonStrings::default(),
+                Tolerances::default(),
+            );
+            let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+            for _ in 0..2 {
+                let response = subgraph_service
+                    .clone()
+                    .oneshot(SubgraphRequest {
+                        originating_request: Arc::new(
+                            http::Request::builder()
+                                .header(HOST, "host")
+                                .header(CONTENT_TYPE, "application/json")
+                                .body(Request::builder().query("{ me { id } }").build())
+                                .expect("expecting valid request"),
+                        ),
+                        subgraph_request: http::Request::builder()
+                            .header(HOST, "rhost")
+                            .header(CONTENT_TYPE, "application/json")
+                            .uri(url.clone())
+                            .body(Request::builder().query("{ me { id } }").build())
+                            .expect("expecting valid request"),
+                        operation_kind: OperationKind::Query,
+                        context: Context::new(),
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    response.response.body().data,
+                    Some(serde_json_bytes::json!({ "me": { "id": "1" } }))
+                );
+            }
+
+            let received = received.lock().unwrap();
+            let queries: Vec<bool> = received
+                .iter()
+                .map(|body| body.get("query").is_some())
+                .collect();
+            if supported {
+                // the hash, the query with its hash, then the hash only
+                assert_eq!(queries, [false, true, false]);
+            } else {
+                // the hash, then the original requests
+                assert_eq!(queries, [false, true, true]);
+                assert!(received[1].get("extensions").is_none());
+            }
+        }
+    }
+
+    // starts a local server emulating an overloaded subgraph
+    async fn emulate_subgraph_unavailable(socket_addr: SocketAddr) {
+        async fn handle(_request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            Ok(http::Response::builder()
+                .header("Content-Type", "text/html")
+                .header("Retry-After", "120")
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(r#"TRY LATER"#.into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unavailable_with_retry_after() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:2828").unwrap();
+        tokio::task::spawn(emulate_subgraph_unavailable(socket_addr));
+        let subgraph_service = SubgraphService::new("test");
+
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let err = subgraph_service
+            .oneshot(SubgraphRequest {
+                originating_request: Arc::new(
+                    http::Request::builder()
+                        .header(HOST, "host")
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Request::builder().query("query").build())
+                        .expect("expecting valid request"),
+                ),
+                subgraph_request: http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+                operation_kind: OperationKind::Query,
+                context: Context::new(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "service 'test' is unavailable, retry after 120 seconds"
+        );
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(StatusCode::TOO_MANY_REQUESTS, &headers), None);
+
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("30"));
+        assert_eq!(
+            retry_after(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Some(30)
+        );
+        assert_eq!(
+            retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Some(30)
+        );
+        assert_eq!(retry_after(StatusCode::BAD_GATEWAY, &headers), None);
+
+        let date = SystemTime::now() + std::time::Duration::from_secs(60);
+        headers.insert(
+            header::RETRY_AFTER,
+            HeaderValue::from_str(&httpdate::fmt_http_date(date)).unwrap(),
+        );
+        let delay = retry_after(StatusCode::TOO_MANY_REQUESTS, &headers).unwrap();
+        assert!((59..=61).contains(&delay), "{}", delay);
+
+        headers.insert(
+            header::RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(
+            retry_after(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Some(0)
+        );
+
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(StatusCode::TOO_MANY_REQUESTS, &headers), None);
+    }
+
+    // starts a local server only accepting HTTP/2 on plain text connections
+    async fn emulate_subgraph_http2_only(socket_addr: SocketAddr) {
+        async fn handle(request: http::Request<Body>) -> Result<http::Response<Body>, Infallible> {
+            assert_eq!(request.version(), http::Version::HTTP_2);
+            let body = Response {
+                data: Some(Value::String(ByteString::from("test"))),
+                ..Response::default()
+            };
+            Ok(http::Response::builder()
+                .header("Content-Type", "application/json")
+                .status(StatusCode::OK)
+                .body(serde_json::to_vec(&body).unwrap().into())
+                .unwrap())
+        }
+
+        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = Server::bind(&socket_addr).http2_only(true).serve(make_svc);
+        if let Err(e) = server.await {
+            eprintln!("server error: {}", e);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_http2_prior_knowledge() {
+        let socket_addr = SocketAddr::from_str("127.0.0.1:2929").unwrap();
+        tokio::task::spawn(emulate_subgraph_http2_only(socket_addr));
+        let options = HttpClientOptions {
+            http2: Some(Http2::Http2Only),
+            pool_max_idle_per_host: Some(1),
+            tcp_keepalive: Some(std::time::Duration::from_secs(60)),
+            ..Default::default()
+        };
+        let subgraph_service = SubgraphService::with_options(
+            "test",
+            None,
+            &options,
+            JsonNumbers::default(),
+            JsonStrings::default(),
+            Tolerances::default(),
+        );
+
+        let url = Uri::from_str(&format!("http://{}", socket_addr)).unwrap();
+        let resp = subgraph_service
+            .oneshot(SubgraphRequest {
+                originating_request: Arc::new(
+                    http::Request::builder()
+                        .header(HOST, "host")
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Request::builder().query("query").build())
+                        .expect("expecting valid request"),
+                ),
+                subgraph_request: http::Request::builder()
+                    .header(HOST, "rhost")
+                    .header(CONTENT_TYPE, "application/json")
+                    .uri(url)
+                    .body(Request::builder().query("query").build())
+                    .expect("expecting valid request"),
+                operation_kind: OperationKind::Query,
+                context: Context::new(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.response.body(),
+            &Response::builder()
+                .data(Value::String(ByteString::from("test")))
+                .build()
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_compressed_request_response_body() {
         let socket_addr = SocketAddr::from_str("127.0.0.1:2727").unwrap();
@@ -542,4 +1975,24 @@ mod tests {
 
         assert_eq!(resp.response.body(), &resp_from_subgraph);
     }
+
+    #[test]
+    fn tls_files_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = TlsClient {
+            certificate_authorities: Some(dir.path().join("missing.pem")),
+            client_authentication: None,
+        };
+        let error = tls_client_config(&missing).unwrap_err().to_string();
+        assert!(error.starts_with("could not read"), "{}", error);
+
+        let empty = dir.path().join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let empty = TlsClient {
+            certificate_authorities: Some(empty),
+            client_authentication: None,
+        };
+        let error = tls_client_config(&empty).unwrap_err().to_string();
+        assert!(error.starts_with("no certificate found"), "{}", error);
+    }
 }
@@ -15,6 +15,7 @@ use serde_json_bytes::Map as JsonMap;
 use serde_json_bytes::Value;
 use static_assertions::assert_impl_all;
 use tower::BoxError;
+use tower::ServiceExt;
 
 use crate::error::Error;
 use crate::graphql;
@@ -28,6 +29,9 @@ pub type BoxService = tower::util::BoxService<Request, Response, BoxError>;
 pub type BoxCloneService = tower::util::BoxCloneService<Request, Response, BoxError>;
 pub type ServiceResult = Result<Response, BoxError>;
 
+pub(crate) const SCHEMA_HASH_CONTEXT_KEY: &str = "apollo_supergraph::schema_hash";
+pub(crate) const OPERATION_HASH_CONTEXT_KEY: &str = "apollo_supergraph::operation_hash";
+
 assert_impl_all!(Request: Send);
 /// Represents the router processing step of the processing pipeline.
 ///
@@ -42,10 +46,16 @@ pub struct Request {
 }
 
 impl From<http::Request<graphql::Request>> for Request {
-    fn from(originating_request: http::Request<graphql::Request>) -> Self {
+    /// The context of the router stage is carried in the extensions of HTTP requests, a new one
+    /// is created otherwise.
+    fn from(mut originating_request: http::Request<graphql::Request>) -> Self {
+        let context = originating_request
+            .extensions_mut()
+            .remove::<Context>()
+            .unwrap_or_default();
         Self {
             originating_request,
-            context: Context::new(),
+            context,
         }
     }
 }
@@ -155,6 +165,18 @@ impl Request {
     }
 }
 
+impl Request {
+    /// Hash of the API schema the request is executed against, as reported to Apollo Studio.
+    pub fn schema_hash(&self) -> Option<String> {
+        self.context.get(SCHEMA_HASH_CONTEXT_KEY).ok().flatten()
+    }
+
+    /// Hash of the operation name and query, ignoring whitespace, commas and comments.
+    pub fn operation_hash(&self) -> Option<String> {
+        self.context.get(OPERATION_HASH_CONTEXT_KEY).ok().flatten()
+    }
+}
+
 assert_impl_all!(Response: Send);
 #[non_exhaustive]
 pub struct Response {
@@ -264,6 +286,16 @@ impl Response {
 }
 
 impl Response {
+    /// Hash of the API schema the request was executed against, as reported to Apollo Studio.
+    pub fn schema_hash(&self) -> Option<String> {
+        self.context.get(SCHEMA_HASH_CONTEXT_KEY).ok().flatten()
+    }
+
+    /// Hash of the operation name and query, ignoring whitespace, commas and comments.
+    pub fn operation_hash(&self) -> Option<String> {
+        self.context.get(OPERATION_HASH_CONTEXT_KEY).ok().flatten()
+    }
+
     pub async fn next_response(&mut self) -> Option<graphql::Response> {
         self.response.body_mut().next().await
     }
@@ -291,6 +323,43 @@ impl Response {
     ) -> Self {
         self.map(move |stream| stream.map(f).boxed())
     }
+
+    /// Transforms each error of the responses, the primary one and the incremental ones. Errors
+    /// for which the function returns `None` are removed.
+    pub fn map_errors(self, mut f: impl FnMut(Error) -> Option<Error> + Send + 'static) -> Self {
+        self.map_stream(move |mut response| {
+            response.map_errors(&mut f);
+            response
+        })
+    }
+}
+
+/// Executes many operations with the same supergraph service, like the one built by a
+/// [`TestHarness`](crate::TestHarness), sharing its query planner and caches across the operations.
+///
+/// This runs the router as an execution engine, for example in batch jobs, without going over the
+/// network on the supergraph side. At most `concurrency` operations are executed at a time, and
+/// each one runs to completion: its result holds its response, or its primary response followed by
+/// the incremental ones for deferred queries. The results are in the order of the requests.
+pub async fn execute_batch<S>(
+    service: S,
+    requests: Vec<Request>,
+    concurrency: usize,
+) -> Vec<Result<Vec<graphql::Response>, BoxError>>
+where
+    S: tower::Service<Request, Response = Response, Error = BoxError> + Clone,
+{
+    futures::stream::iter(requests)
+        .map(|request| {
+            let service = service.clone();
+            async move {
+                let response = service.oneshot(request).await?;
+                Ok(response.response.into_body().collect().await)
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
 }
 
 #[cfg(test)]
@@ -394,4 +463,39 @@ mod test {
                 .build()
         );
     }
+
+    #[tokio::test]
+    async fn errors_of_deferred_responses_are_mapped() {
+        let error = |message: &str| Error::builder().message(message).build();
+        let primary = graphql::Response::builder()
+            .errors(vec![error("kept"), error("removed")])
+            .has_next(true)
+            .build();
+        let incremental = graphql::Response::builder()
+            .incremental(vec![graphql::IncrementalResponse::builder()
+                .errors(vec![error("removed"), error("kept")])
+                .build()])
+            .has_next(false)
+            .build();
+        let response = Response::new_from_response(
+            http::Response::new(futures::stream::iter(vec![primary, incremental]).boxed()),
+            Context::new(),
+        );
+
+        let mut response = response.map_errors(|mut error| {
+            if error.message == "removed" {
+                return None;
+            }
+            error.message = "mapped".to_string();
+            Some(error)
+        });
+        assert_eq!(
+            response.next_response().await.unwrap().errors,
+            vec![error("mapped")]
+        );
+        assert_eq!(
+            response.next_response().await.unwrap().incremental[0].errors,
+            vec![error("mapped")]
+        );
+    }
 }
@@ -1,5 +1,6 @@
 //! Implements the router phase of the request lifecycle.
 
+use std::str::FromStr;
 use std::sync::Arc;
 use std::task::Poll;
 
@@ -9,9 +10,13 @@ use futures::stream::once;
 use futures::stream::BoxStream;
 use futures::stream::StreamExt;
 use futures::TryFutureExt;
+use http::header::HeaderName;
 use http::header::ACCEPT;
+use http::header::RETRY_AFTER;
 use http::HeaderMap;
+use http::HeaderValue;
 use http::StatusCode;
+use http::Uri;
 use indexmap::IndexMap;
 use lazy_static::__Deref;
 use mediatype::names::MIXED;
@@ -22,6 +27,7 @@ use opentelemetry::trace::SpanKind;
 use serde_json_bytes::ByteString;
 use serde_json_bytes::Map;
 use serde_json_bytes::Value;
+use tower::service_fn;
 use tower::util::BoxService;
 use tower::BoxError;
 use tower::ServiceBuilder;
@@ -29,29 +35,55 @@ use tower::ServiceExt;
 use tower_service::Service;
 use tracing_futures::Instrument;
 
+use super::canary::CanarySplit;
+use super::canary::CANARY_CONTEXT_KEY;
+use super::canary::CANARY_VERSION;
+use super::canary::STABLE_VERSION;
+use super::federated_subgraph::FederatedSubgraph;
+use super::federated_subgraph::Operation;
 use super::new_service::NewService;
 use super::subgraph_service::MakeSubgraphService;
 use super::subgraph_service::SubgraphCreator;
+use super::supergraph::OPERATION_HASH_CONTEXT_KEY;
+use super::supergraph::SCHEMA_HASH_CONTEXT_KEY;
+use super::upstream::merge_introspection;
+use super::upstream::Route;
+use super::upstream::Upstream;
 use super::ExecutionCreator;
 use super::ExecutionServiceFactory;
 use super::QueryPlannerContent;
 use super::MULTIPART_DEFER_SPEC_PARAMETER;
 use super::MULTIPART_DEFER_SPEC_VALUE;
 use crate::cache::DeduplicatingCache;
+use crate::cache::DEFAULT_CACHE_CAPACITY;
+use crate::configuration::IntrospectionControl;
+use crate::configuration::ACTIVE_SCHEDULES_CONTEXT_KEY;
+use crate::container::CONTAINER_LIMITS;
+use crate::container::DEFAULT_PLAN_CACHE_CAPACITY;
 use crate::error::QueryPlannerError;
 use crate::error::ServiceBuildError;
 use crate::graphql;
 use crate::graphql::Response;
+use crate::introspection;
 use crate::introspection::Introspection;
+use crate::introspection::INTROSPECTION_DENIED_CONTEXT_KEY;
 use crate::json_ext::ValueExt;
 use crate::plugin::DynPlugin;
 use crate::plugin::Handler;
+use crate::plugin::ResponseScanner;
+use crate::plugins::telemetry::FIELD_COUNTS;
+use crate::plugins::telemetry::FIELD_LEVEL_INSTRUMENTATION;
+use crate::query_planner::fetch::RETRY_AFTER_CONTEXT_KEY;
 use crate::query_planner::BridgeQueryPlanner;
 use crate::query_planner::CachingQueryPlanner;
+use crate::query_planner::StalePlans;
 use crate::response::IncrementalResponse;
 use crate::router_factory::SupergraphServiceFactory;
 use crate::services::layers::apq::APQLayer;
 use crate::services::layers::ensure_query_presence::EnsureQueryPresence;
+use crate::services::layers::error_format::ErrorFormatLayer;
+use crate::services::layers::plugin_timing;
+use crate::services::router;
 use crate::spec::Query;
 use crate::Configuration;
 use crate::Context;
@@ -63,6 +95,9 @@ use crate::Schema;
 use crate::SupergraphRequest;
 use crate::SupergraphResponse;
 
+const SCHEMA_HASH_HEADER: &str = "apollo-schema-hash";
+const OPERATION_HASH_HEADER: &str = "apollo-operation-hash";
+
 /// An [`IndexMap`] of available plugins.
 pub(crate) type Plugins = IndexMap<String, Box<dyn DynPlugin>>;
 
@@ -73,6 +108,10 @@ pub(crate) struct SupergraphService<ExecutionFactory> {
     query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
     ready_query_planner_service: Option<CachingQueryPlanner<BridgeQueryPlanner>>,
     schema: Arc<Schema>,
+    accept_without_defer_spec: bool,
+    federated_subgraph: Option<Arc<FederatedSubgraph>>,
+    upstream: Option<Arc<Upstream>>,
+    introspection_control: Option<Arc<IntrospectionControl>>,
 }
 
 #[buildstructor::buildstructor]
@@ -82,12 +121,20 @@ impl<ExecutionFactory> SupergraphService<ExecutionFactory> {
         query_planner_service: CachingQueryPlanner<BridgeQueryPlanner>,
         execution_service_factory: ExecutionFactory,
         schema: Arc<Schema>,
+        accept_without_defer_spec: Option<bool>,
+        federated_subgraph: Option<Arc<FederatedSubgraph>>,
+        upstream: Option<Arc<Upstream>>,
+        introspection_control: Option<Arc<IntrospectionControl>>,
     ) -> Self {
         SupergraphService {
             query_planner_service,
             execution_service_factory,
             ready_query_planner_service: None,
             schema,
+            accept_without_defer_spec: accept_without_defer_spec.unwrap_or_default(),
+            federated_subgraph,
+            upstream,
+            introspection_control,
         }
     }
 }
@@ -115,11 +162,64 @@ where
         let planning = self.ready_query_planner_service.take().unwrap();
         let execution = self.execution_service_factory.new_service();
 
+        // In subgraph mode, the federation fields are answered without planning, as they are not
+        // in the supergraph schema
+        if let Some(operation) = self
+            .federated_subgraph
+            .as_ref()
+            .and_then(|federated_subgraph| {
+                federated_subgraph.operation(req.originating_request.body())
+            })
+        {
+            return Box::pin(federated_subgraph_call(operation, execution, req));
+        }
+
+        // The introspection queries of the requests that are not allowed to introspect the
+        // schema are answered like when introspection is disabled
+        let introspection_allowed = self
+            .introspection_control
+            .as_ref()
+            .map_or(true, |control| introspection::is_allowed(control, &req));
+        if !introspection_allowed {
+            let _ = req.context.insert(INTROSPECTION_DENIED_CONTEXT_KEY, true);
+        }
+
+        // Operations the supergraph can't resolve are sent to the upstream server, and the
+        // introspection queries to both
+        let mut introspection = None;
+        if let Some(upstream) = &self.upstream {
+            match upstream.route(req.originating_request.body()) {
+                Route::Upstream(operation_kind) => {
+                    return Box::pin(upstream.clone().delegate(req, operation_kind));
+                }
+                Route::Introspection if introspection_allowed => {
+                    introspection = Some(upstream.clone().introspect(&req))
+                }
+                Route::Introspection | Route::Supergraph => {}
+            }
+        }
+
         let schema = self.schema.clone();
+        let accept_without_defer_spec = self.accept_without_defer_spec;
 
         let context_cloned = req.context.clone();
-        let fut =
-            service_call(planning, execution, schema, req).or_else(|error: BoxError| async move {
+        let fut = service_call(planning, execution, schema, accept_without_defer_spec, req)
+            .or_else(|error: BoxError| async move {
+                if let Some(QueryPlannerError::Timeout(_)) =
+                    error.downcast_ref::<QueryPlannerError>()
+                {
+                    return Ok(SupergraphResponse::builder()
+                        .error(
+                            crate::error::Error::builder()
+                                .message(error.to_string())
+                                .extension("code", "PLANNING_TIMEOUT")
+                                .build(),
+                        )
+                        .status_code(StatusCode::GATEWAY_TIMEOUT)
+                        .context(context_cloned)
+                        .build()
+                        .expect("building a response like this should not fail"));
+                }
                 let errors = vec![crate::error::Error {
                     message: error.to_string(),
                     ..Default::default()
@@ -145,7 +245,10 @@ where
                     .expect("building a response like this should not fail"))
             });
 
-        Box::pin(fut)
+        match introspection {
+            Some(upstream) => Box::pin(merge_introspection(Box::pin(fut), upstream)),
+            None => Box::pin(fut),
+        }
     }
 }
 
@@ -153,6 +256,7 @@ async fn service_call<ExecutionService>(
     planning: CachingQueryPlanner<BridgeQueryPlanner>,
     execution: ExecutionService,
     schema: Arc<Schema>,
+    accept_without_defer_spec: bool,
     req: SupergraphRequest,
 ) -> Result<SupergraphResponse, BoxError>
 where
@@ -165,10 +269,16 @@ where
     let QueryPlannerResponse { content, context } = plan_query(planning, body, context).await?;
 
     match content {
-        QueryPlannerContent::Introspection { response } => Ok(
-            SupergraphResponse::new_from_graphql_response(*response, context),
-        ),
-        QueryPlannerContent::IntrospectionDisabled => {
+        QueryPlannerContent::Introspection { response }
+            if context
+                .get_json_value(INTROSPECTION_DENIED_CONTEXT_KEY)
+                .is_none() =>
+        {
+            Ok(SupergraphResponse::new_from_graphql_response(
+                *response, context,
+            ))
+        }
+        QueryPlannerContent::Introspection { .. } | QueryPlannerContent::IntrospectionDisabled => {
             let mut response = SupergraphResponse::new_from_graphql_response(
                 graphql::Response::builder()
                     .errors(vec![crate::error::Error::builder()
@@ -183,7 +293,9 @@ where
         QueryPlannerContent::Plan { query, plan } => {
             let can_be_deferred = plan.root.contains_defer();
 
-            if can_be_deferred && !accepts_multipart(req.originating_request.headers()) {
+            if can_be_deferred
+                && !accepts_multipart(req.originating_request.headers(), accept_without_defer_spec)
+            {
                 let mut response = SupergraphResponse::new_from_graphql_response(graphql::Response::builder()
                     .errors(vec![crate::error::Error::builder()
                         .message(String::from("the router received a query with the @defer directive but the client does not accept multipart/mixed HTTP responses. To enable @defer support, add the HTTP header 'Accept: multipart/mixed; deferSpec=20220824'"))
@@ -221,6 +333,40 @@ where
     }
 }
 
+async fn federated_subgraph_call<ExecutionService>(
+    operation: Result<Operation, String>,
+    execution: ExecutionService,
+    req: SupergraphRequest,
+) -> Result<SupergraphResponse, BoxError>
+where
+    ExecutionService:
+        Service<ExecutionRequest, Response = ExecutionResponse, Error = BoxError> + Send,
+{
+    match operation {
+        Ok(Operation::Response(response)) => Ok(SupergraphResponse::new_from_graphql_response(
+            response,
+            req.context,
+        )),
+        Ok(Operation::Entities(fetch)) => {
+            fetch
+                .execute(execution, req.originating_request, req.context)
+                .await
+        }
+        Err(message) => {
+            let mut response = SupergraphResponse::new_from_graphql_response(
+                graphql::Response::builder()
+                    .errors(vec![crate::error::Error::builder()
+                        .message(message)
+                        .build()])
+                    .build(),
+                req.context,
+            );
+            *response.response.status_mut() = StatusCode::BAD_REQUEST;
+            Ok(response)
+        }
+    }
+}
+
 async fn plan_query(
     mut planning: CachingQueryPlanner<BridgeQueryPlanner>,
     body: &graphql::Request,
@@ -246,31 +392,24 @@ async fn plan_query(
         .await
 }
 
-fn accepts_multipart(headers: &HeaderMap) -> bool {
-    headers.get_all(ACCEPT).iter().any(|value| {
-        value
-            .to_str()
-            .map(|accept_str| {
-                let mut list = MediaTypeList::new(accept_str);
-
-                list.any(|mime| {
-                    mime.as_ref()
-                        .map(|mime| {
-                            mime.ty == MULTIPART
-                                && mime.subty == MIXED
-                                && mime.get_param(
-                                    mediatype::Name::new(MULTIPART_DEFER_SPEC_PARAMETER)
-                                        .expect("valid name"),
-                                ) == Some(
-                                    mediatype::Value::new(MULTIPART_DEFER_SPEC_VALUE)
-                                        .expect("valid value"),
-                                )
-                        })
-                        .unwrap_or(false)
-                })
-            })
-            .unwrap_or(false)
-    })
+/// Whether the client accepts deferred results in `multipart/mixed` responses, with the supported
+/// `deferSpec` parameter, or without it if allowed.
+fn accepts_multipart(headers: &HeaderMap, accept_without_defer_spec: bool) -> bool {
+    let defer_spec = mediatype::Name::new(MULTIPART_DEFER_SPEC_PARAMETER).expect("valid name");
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(MediaTypeList::new)
+        .filter_map(Result::ok)
+        .any(|mime| {
+            mime.ty == MULTIPART
+                && mime.subty == MIXED
+                && match mime.get_param(defer_spec) {
+                    Some(value) => value.unquoted_str() == MULTIPART_DEFER_SPEC_VALUE,
+                    None => accept_without_defer_spec,
+                }
+        })
 }
 
 fn process_execution_response(
@@ -283,7 +422,18 @@ fn process_execution_response(
 ) -> Result<SupergraphResponse, BoxError> {
     let ExecutionResponse { response, context } = execution_response;
 
-    let (parts, response_stream) = response.into_parts();
+    let (mut parts, response_stream) = response.into_parts();
+    // Subgraphs shedding load asked to be called later, so the client should wait as well
+    if let Ok(Some(retry_after)) = context.get::<_, u64>(RETRY_AFTER_CONTEXT_KEY) {
+        parts
+            .headers
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    let count_fields = matches!(
+        context.get::<_, f64>(FIELD_LEVEL_INSTRUMENTATION),
+        Ok(Some(_))
+    );
+    let field_counts_context = context.clone();
 
     let stream = response_stream.map(move |mut response: Response| {
         tracing::debug_span!("format_response").in_scope(|| {
@@ -294,6 +444,18 @@ fn process_execution_response(
                 schema.api_schema(),
             )
         });
+        // Fields of the deferred responses are not counted
+        if count_fields && response.path.is_none() {
+            if let Some(data) = &response.data {
+                let counts = query.count_fields(
+                    operation_name.as_deref(),
+                    &variables,
+                    data,
+                    schema.api_schema(),
+                );
+                let _ = field_counts_context.insert(FIELD_COUNTS, counts);
+            }
+        }
 
         match (response.path.as_ref(), response.data.as_ref()) {
             (None, _) | (_, None) => {
@@ -357,6 +519,21 @@ fn process_execution_response(
     })
 }
 
+/// Sets the schema and operation hash headers of a response.
+fn add_hash_headers(res: &mut SupergraphResponse) {
+    let hashes = [
+        (SCHEMA_HASH_HEADER, res.schema_hash()),
+        (OPERATION_HASH_HEADER, res.operation_hash()),
+    ];
+    for (name, hash) in hashes {
+        if let Some(value) = hash.and_then(|hash| HeaderValue::from_str(&hash).ok()) {
+            res.response
+                .headers_mut()
+                .insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
 /// Builder which generates a plugin pipeline.
 ///
 /// This is at the heart of the delegation of responsibility model for the router. A schema,
@@ -365,9 +542,11 @@ fn process_execution_response(
 /// through the entire stack to return a response.
 pub(crate) struct PluggableSupergraphServiceBuilder {
     schema: Arc<Schema>,
-    plugins: Plugins,
+    plugins: Arc<Plugins>,
     subgraph_services: Vec<(String, Arc<dyn MakeSubgraphService>)>,
     configuration: Option<Arc<Configuration>>,
+    stale_plans: Option<StalePlans>,
+    introspection: Option<Arc<Introspection>>,
 }
 
 impl PluggableSupergraphServiceBuilder {
@@ -377,6 +556,8 @@ impl PluggableSupergraphServiceBuilder {
             plugins: Default::default(),
             subgraph_services: Default::default(),
             configuration: None,
+            stale_plans: None,
+            introspection: None,
         }
     }
 
@@ -385,7 +566,18 @@ impl PluggableSupergraphServiceBuilder {
         plugin_name: String,
         plugin: Box<dyn DynPlugin>,
     ) -> PluggableSupergraphServiceBuilder {
-        self.plugins.insert(plugin_name, plugin);
+        Arc::get_mut(&mut self.plugins)
+            .expect("plugins are added before being shared; qed")
+            .insert(plugin_name, plugin);
+        self
+    }
+
+    /// Uses the plugins of another router, like the main router of a canary schema.
+    pub(crate) fn with_plugins(
+        mut self,
+        plugins: Arc<Plugins>,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.plugins = plugins;
         self
     }
 
@@ -410,6 +602,24 @@ impl PluggableSupergraphServiceBuilder {
         self
     }
 
+    /// Keeps the stale plans of the previous router, executed when query planning times out.
+    pub(crate) fn with_stale_plans(
+        mut self,
+        stale_plans: StalePlans,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.stale_plans = Some(stale_plans);
+        self
+    }
+
+    /// Keeps the introspection cache of the previous router, if the schema didn't change.
+    pub(crate) fn with_introspection(
+        mut self,
+        introspection: Arc<Introspection>,
+    ) -> PluggableSupergraphServiceBuilder {
+        self.introspection = Some(introspection);
+        self
+    }
+
     pub(crate) async fn build(self) -> Result<RouterCreator, crate::error::ServiceBuildError> {
         // Note: The plugins are always applied in reverse, so that the
         // fold is applied in the correct sequence. We could reverse
@@ -419,34 +629,75 @@ impl PluggableSupergraphServiceBuilder {
         // the plugins in their original order.
 
         let configuration = self.configuration.unwrap_or_default();
+        let hash_headers = configuration.server.experimental_hash_headers;
+        let accept_without_defer_spec = configuration
+            .server
+            .experimental_defer_multipart
+            .accept_without_defer_spec;
+        let error_format =
+            ErrorFormatLayer::new(configuration.server.experimental_error_format.clone());
+        let query_planning = configuration.experimental_query_planning.clone();
+        let federated_subgraph = configuration
+            .experimental_subgraph_mode
+            .enabled
+            .then(|| Arc::new(FederatedSubgraph::new(&self.schema)));
+        let upstream = configuration.experimental_upstream.clone();
+        let active_schedules = Arc::new(configuration.active_schedules.clone());
 
         let plan_cache_limit = std::env::var("ROUTER_PLAN_CACHE_LIMIT")
             .ok()
             .and_then(|x| x.parse().ok())
-            .unwrap_or(100);
+            .unwrap_or_else(|| CONTAINER_LIMITS.cache_capacity(DEFAULT_PLAN_CACHE_CAPACITY));
 
+        let introspection_control =
+            Arc::new(configuration.server.experimental_introspection.clone());
         let introspection = if configuration.server.introspection {
-            Some(Arc::new(Introspection::new(&configuration).await))
+            match self.introspection {
+                Some(introspection) if introspection.is_reusable(&configuration, &self.schema) => {
+                    Some(introspection)
+                }
+                _ => Some(Arc::new(
+                    Introspection::for_schema(&configuration, &self.schema).await,
+                )),
+            }
         } else {
             None
         };
 
         // QueryPlannerService takes an UnplannedRequest and outputs PlannedRequest
         let bridge_query_planner =
-            BridgeQueryPlanner::new(self.schema.clone(), introspection, configuration)
+            BridgeQueryPlanner::new(self.schema.clone(), introspection.clone(), configuration)
                 .await
                 .map_err(ServiceBuildError::QueryPlannerError)?;
+        let stale_plans = self
+            .stale_plans
+            .unwrap_or_else(|| StalePlans::new(plan_cache_limit));
         let query_planner_service =
-            CachingQueryPlanner::new(bridge_query_planner, plan_cache_limit).await;
+            CachingQueryPlanner::new(bridge_query_planner, plan_cache_limit)
+                .await
+                .with_timeout(&query_planning, stale_plans.clone());
 
-        let plugins = Arc::new(self.plugins);
+        let plugins = self.plugins;
 
         let subgraph_creator = Arc::new(SubgraphCreator::new(
             self.subgraph_services,
             plugins.clone(),
         ));
+        let upstream = upstream.url.map(|url| {
+            Arc::new(Upstream::new(
+                upstream.name,
+                Uri::from_str(url.as_str()).expect("a valid URL is a valid URI; qed"),
+                self.schema.clone(),
+                subgraph_creator.clone(),
+            ))
+        });
 
-        let apq = APQLayer::with_cache(DeduplicatingCache::new().await);
+        let apq = APQLayer::with_cache(
+            DeduplicatingCache::with_capacity(
+                CONTAINER_LIMITS.cache_capacity(DEFAULT_CACHE_CAPACITY),
+            )
+            .await,
+        );
 
         Ok(RouterCreator {
             query_planner_service,
@@ -454,6 +705,16 @@ impl PluggableSupergraphServiceBuilder {
             schema: self.schema,
             plugins,
             apq,
+            hash_headers,
+            accept_without_defer_spec,
+            error_format,
+            stale_plans,
+            federated_subgraph,
+            upstream,
+            introspection,
+            introspection_control,
+            active_schedules,
+            canary: None,
         })
     }
 }
@@ -466,6 +727,22 @@ pub(crate) struct RouterCreator {
     schema: Arc<Schema>,
     plugins: Arc<Plugins>,
     apq: APQLayer,
+    hash_headers: bool,
+    accept_without_defer_spec: bool,
+    error_format: ErrorFormatLayer,
+    stale_plans: StalePlans,
+    federated_subgraph: Option<Arc<FederatedSubgraph>>,
+    upstream: Option<Arc<Upstream>>,
+    introspection: Option<Arc<Introspection>>,
+    introspection_control: Arc<IntrospectionControl>,
+    active_schedules: Arc<Vec<String>>,
+    canary: Option<Arc<CanaryRouter>>,
+}
+
+/// The router of a canary schema, and the requests it executes.
+struct CanaryRouter {
+    router: RouterCreator,
+    split: CanarySplit,
 }
 
 impl NewService<http::Request<graphql::Request>> for RouterCreator {
@@ -475,10 +752,20 @@ impl NewService<http::Request<graphql::Request>> for RouterCreator {
         BoxError,
     >;
     fn new_service(&self) -> Self::Service {
-        self.make()
-            .map_request(|http_request: http::Request<graphql::Request>| http_request.into())
-            .map_response(|response| response.response)
-            .boxed()
+        let canary = match &self.canary {
+            Some(canary) => canary.clone(),
+            None => return self.make_http(None),
+        };
+        let stable = self.clone();
+        service_fn(move |http_request: http::Request<graphql::Request>| {
+            let service = if canary.split.is_canary(&http_request) {
+                canary.router.make_http(Some(CANARY_VERSION))
+            } else {
+                stable.make_http(Some(STABLE_VERSION))
+            };
+            service.oneshot(http_request)
+        })
+        .boxed()
     }
 }
 
@@ -505,9 +792,82 @@ impl SupergraphServiceFactory for RouterCreator {
             })
             .collect()
     }
+
+    fn router_service(&self, service: router::BoxService) -> router::BoxService {
+        self.plugins
+            .values()
+            .rev()
+            .fold(service, |acc, plugin| plugin.router_service(acc))
+    }
+
+    fn admin_endpoints(&self) -> std::collections::HashMap<String, crate::plugin::Handler> {
+        self.plugins
+            .iter()
+            .filter_map(|(plugin_name, plugin)| {
+                (plugin_name.starts_with("apollo.") || plugin_name.starts_with("experimental."))
+                    .then(|| plugin.admin_endpoint().map(Handler::new))
+                    .flatten()
+                    .map(|h| (plugin_name.clone(), h))
+            })
+            .collect()
+    }
+
+    fn response_scanners(&self) -> Vec<Arc<dyn ResponseScanner>> {
+        self.plugins
+            .values()
+            .filter_map(|plugin| plugin.response_scanner())
+            .collect()
+    }
 }
 
 impl RouterCreator {
+    pub(crate) fn stale_plans(&self) -> StalePlans {
+        self.stale_plans.clone()
+    }
+
+    pub(crate) fn introspection(&self) -> Option<Arc<Introspection>> {
+        self.introspection.clone()
+    }
+
+    pub(crate) fn plugins(&self) -> Arc<Plugins> {
+        self.plugins.clone()
+    }
+
+    /// The router of the canary schema, if there is one.
+    pub(crate) fn canary(&self) -> Option<&RouterCreator> {
+        self.canary.as_ref().map(|canary| &canary.router)
+    }
+
+    /// Executes a share of the requests against the router of a canary schema.
+    pub(crate) fn with_canary(mut self, router: RouterCreator, split: CanarySplit) -> Self {
+        self.canary = Some(Arc::new(CanaryRouter { router, split }));
+        self
+    }
+
+    /// The service executing HTTP requests, marking them with the version of the schema when
+    /// there is a canary.
+    fn make_http(
+        &self,
+        version: Option<&'static str>,
+    ) -> BoxService<
+        http::Request<graphql::Request>,
+        http::Response<BoxStream<'static, Response>>,
+        BoxError,
+    > {
+        self.make()
+            .map_request(move |http_request: http::Request<graphql::Request>| {
+                let request: SupergraphRequest = http_request.into();
+                if let Some(version) = version {
+                    let _ = request
+                        .context
+                        .insert(CANARY_CONTEXT_KEY, version.to_string());
+                }
+                request
+            })
+            .map_response(|response| response.response)
+            .boxed()
+    }
+
     pub(crate) fn make(
         &self,
     ) -> impl Service<
@@ -516,24 +876,67 @@ impl RouterCreator {
         Error = BoxError,
         Future = BoxFuture<'static, Result<SupergraphResponse, BoxError>>,
     > + Send {
+        let schema_hash = self.schema.api_schema().schema_id.clone();
+        let active_schedules = self.active_schedules.clone();
+        let hash_headers = self.hash_headers;
         ServiceBuilder::new()
+            .layer(self.error_format.clone())
             .layer(self.apq.clone())
             .layer(EnsureQueryPresence::default())
             .service(
-                self.plugins.iter().rev().fold(
-                    BoxService::new(
-                        SupergraphService::builder()
-                            .query_planner_service(self.query_planner_service.clone())
-                            .execution_service_factory(ExecutionCreator {
-                                schema: self.schema.clone(),
-                                plugins: self.plugins.clone(),
-                                subgraph_creator: self.subgraph_creator.clone(),
-                            })
-                            .schema(self.schema.clone())
-                            .build(),
-                    ),
-                    |acc, (_, e)| e.supergraph_service(acc),
-                ),
+                ServiceBuilder::new()
+                    .map_request(move |req: SupergraphRequest| {
+                        // Plugins can key on the hashes, so they are set before calling them
+                        if let Some(schema_hash) = &schema_hash {
+                            let _ = req
+                                .context
+                                .insert(SCHEMA_HASH_CONTEXT_KEY, schema_hash.clone());
+                        }
+                        if !active_schedules.is_empty() {
+                            let _ = req
+                                .context
+                                .insert(ACTIVE_SCHEDULES_CONTEXT_KEY, active_schedules.to_vec());
+                        }
+                        let body = req.originating_request.body();
+                        if let Some(query) = &body.query {
+                            let operation_hash = Query::hash(query, body.operation_name.as_deref());
+                            let _ = req
+                                .context
+                                .insert(OPERATION_HASH_CONTEXT_KEY, operation_hash);
+                        }
+                        req
+                    })
+                    .map_response(move |mut res: SupergraphResponse| {
+                        if hash_headers {
+                            add_hash_headers(&mut res);
+                        }
+                        res
+                    })
+                    .service(
+                        self.plugins.iter().rev().fold(
+                            BoxService::new(
+                                SupergraphService::builder()
+                                    .query_planner_service(self.query_planner_service.clone())
+                                    .execution_service_factory(ExecutionCreator {
+                                        schema: self.schema.clone(),
+                                        plugins: self.plugins.clone(),
+                                        subgraph_creator: self.subgraph_creator.clone(),
+                                    })
+                                    .schema(self.schema.clone())
+                                    .accept_without_defer_spec(self.accept_without_defer_spec)
+                                    .and_federated_subgraph(self.federated_subgraph.clone())
+                                    .and_upstream(self.upstream.clone())
+                                    .introspection_control(self.introspection_control.clone())
+                                    .build(),
+                            ),
+                            |acc, (name, e)| {
+                                plugin_timing::timed(name, "supergraph", acc, |service| {
+                                    e.supergraph_service(service)
+                                })
+                            },
+                        ),
+                    )
+                    .boxed(),
             )
     }
 
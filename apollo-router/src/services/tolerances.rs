@@ -0,0 +1,196 @@
+//! Normalization of the responses of subgraphs that don't follow the GraphQL specification.
+//!
+//! Legacy subgraphs sometimes send errors without a message, a single error instead of a list,
+//! or `data` that is not an object. With the matching tolerance, these responses are rewritten
+//! into spec-compliant responses before they are parsed, instead of failing the subgraph request.
+
+use serde_json_bytes::Value;
+
+use crate::configuration::Tolerances;
+use crate::json_ext::Object;
+
+/// Message of the errors received without a message.
+const MISSING_MESSAGE: &str = "Subgraph error without a message";
+
+impl Tolerances {
+    /// Whether the responses of the subgraph are normalized before they are parsed.
+    pub(crate) fn normalizes_responses(&self) -> bool {
+        self.errors_without_message.unwrap_or_default() || self.response_shape.unwrap_or_default()
+    }
+}
+
+/// Normalizes the body of a subgraph response, returning the noncompliant behaviors found.
+pub(crate) fn normalize(tolerances: &Tolerances, body: &mut Value) -> Vec<&'static str> {
+    let mut fixed = Vec::new();
+    let response = match body {
+        Value::Object(response) => response,
+        _ => return fixed,
+    };
+
+    if tolerances.response_shape.unwrap_or_default() {
+        match response.get("errors") {
+            Some(Value::Object(_)) | Some(Value::String(_)) => {
+                let error = response.remove("errors").expect("errors is present; qed");
+                response.insert("errors", Value::Array(vec![error]));
+                note(&mut fixed, "errors is not a list");
+            }
+            _ => {}
+        }
+        if !matches!(
+            response.get("data"),
+            None | Some(Value::Object(_)) | Some(Value::Null)
+        ) {
+            response.insert("data", Value::Null);
+            note(&mut fixed, "data is not an object");
+        }
+        if !matches!(
+            response.get("extensions"),
+            None | Some(Value::Object(_)) | Some(Value::Null)
+        ) {
+            response.remove("extensions");
+            note(&mut fixed, "extensions is not an object");
+        }
+    }
+
+    if let Some(Value::Array(errors)) = response.get_mut("errors") {
+        for error in errors.iter_mut() {
+            normalize_error(tolerances, error, &mut fixed);
+        }
+    }
+    fixed
+}
+
+/// Records a noncompliant behavior, once per response.
+fn note(fixed: &mut Vec<&'static str>, behavior: &'static str) {
+    if !fixed.contains(&behavior) {
+        fixed.push(behavior);
+    }
+}
+
+fn is_location(location: &Value) -> bool {
+    match location {
+        Value::Object(location) => ["line", "column"]
+            .iter()
+            .all(|key| location.get(*key).and_then(Value::as_u64).is_some()),
+        _ => false,
+    }
+}
+
+fn normalize_error(tolerances: &Tolerances, error: &mut Value, fixed: &mut Vec<&'static str>) {
+    if tolerances.errors_without_message.unwrap_or_default() {
+        if let Value::String(message) = error {
+            let mut object = Object::new();
+            object.insert("message", Value::String(message.clone()));
+            *error = Value::Object(object);
+            note(fixed, "error is a string");
+        }
+    }
+    let error = match error {
+        Value::Object(error) => error,
+        _ => return,
+    };
+
+    if tolerances.errors_without_message.unwrap_or_default() {
+        match error.get("message") {
+            Some(Value::String(message)) if !message.as_str().is_empty() => {}
+            Some(Value::String(_)) | Some(Value::Null) | None => {
+                error.insert("message", Value::String(MISSING_MESSAGE.into()));
+                note(fixed, "error without a message");
+            }
+            Some(message) => {
+                let message = serde_json::to_string(message).unwrap_or_default();
+                error.insert("message", Value::String(message.into()));
+                note(fixed, "error message is not a string");
+            }
+        }
+    }
+
+    if tolerances.response_shape.unwrap_or_default() {
+        let invalid_locations = match error.get("locations") {
+            None | Some(Value::Null) => false,
+            Some(Value::Array(locations)) => !locations.iter().all(is_location),
+            Some(_) => true,
+        };
+        if invalid_locations {
+            error.remove("locations");
+            note(fixed, "invalid error locations");
+        }
+        if !matches!(
+            error.get("path"),
+            None | Some(Value::Array(_)) | Some(Value::Null)
+        ) {
+            error.remove("path");
+            note(fixed, "invalid error path");
+        }
+        if !matches!(
+            error.get("extensions"),
+            None | Some(Value::Object(_)) | Some(Value::Null)
+        ) {
+            error.remove("extensions");
+            note(fixed, "invalid error extensions");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+    use crate::graphql;
+
+    fn tolerances(errors_without_message: bool, response_shape: bool) -> Tolerances {
+        Tolerances {
+            non_json_content_type: None,
+            errors_without_message: Some(errors_without_message),
+            response_shape: Some(response_shape),
+        }
+    }
+
+    #[test]
+    fn noncompliant_responses_are_normalized() {
+        let mut body = json!({
+            "data": "nothing",
+            "errors": { "code": 42, "locations": [{ "line": "one" }], "path": "a.b" }
+        });
+        let fixed = normalize(&tolerances(true, true), &mut body);
+        assert_eq!(
+            fixed,
+            vec![
+                "errors is not a list",
+                "data is not an object",
+                "error without a message",
+                "invalid error locations",
+                "invalid error path",
+            ]
+        );
+        assert_eq!(
+            body,
+            json!({
+                "data": null,
+                "errors": [{ "code": 42, "message": "Subgraph error without a message" }]
+            })
+        );
+        let response = graphql::Response::from_value("products", body).unwrap();
+        assert_eq!(response.errors[0].message, MISSING_MESSAGE);
+    }
+
+    #[test]
+    fn errors_are_normalized_with_their_tolerance_only() {
+        let original = json!({ "errors": ["not found", { "message": 404 }] });
+
+        let mut body = original.clone();
+        assert!(normalize(&tolerances(false, true), &mut body).is_empty());
+        assert_eq!(body, original);
+
+        let mut body = original;
+        assert_eq!(
+            normalize(&tolerances(true, false), &mut body),
+            vec!["error is a string", "error message is not a string"]
+        );
+        assert_eq!(
+            body,
+            json!({ "errors": [{ "message": "not found" }, { "message": "404" }] })
+        );
+    }
+}
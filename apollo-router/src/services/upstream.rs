@@ -0,0 +1,419 @@
+//! Delegation of operations to an upstream GraphQL server.
+//!
+//! During a migration from a monolithic GraphQL server to federation, the router resolves the
+//! operations of the supergraph and sends the ones with root fields the supergraph doesn't have
+//! yet to the monolith, as they are. Introspection queries are answered by both, and the upstream
+//! schema is merged in the response of the router, so that clients see the fields of both.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use apollo_parser::ast;
+use futures::future::ready;
+use futures::future::BoxFuture;
+use futures::stream::once;
+use futures::StreamExt;
+use http::Uri;
+use serde_json_bytes::Value;
+use tower::BoxError;
+use tower::ServiceExt;
+
+use super::subgraph_service::SubgraphCreator;
+use super::subgraph_service::SubgraphServiceFactory;
+use crate::graphql;
+use crate::query_planner::fetch::OperationKind;
+use crate::Context;
+use crate::Schema;
+use crate::SubgraphRequest;
+use crate::SupergraphRequest;
+use crate::SupergraphResponse;
+
+/// Upstream GraphQL server, called as a subgraph named after it.
+pub(crate) struct Upstream {
+    name: String,
+    uri: Uri,
+    subgraph_creator: Arc<SubgraphCreator>,
+    schema: Arc<Schema>,
+}
+
+/// Where an operation is resolved.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Route {
+    /// By the supergraph
+    Supergraph,
+    /// By the upstream server, as it has root fields the supergraph doesn't have
+    Upstream(OperationKind),
+    /// By both, as an introspection query
+    Introspection,
+}
+
+impl Upstream {
+    pub(crate) fn new(
+        name: String,
+        uri: Uri,
+        schema: Arc<Schema>,
+        subgraph_creator: Arc<SubgraphCreator>,
+    ) -> Self {
+        Upstream {
+            name,
+            uri,
+            subgraph_creator,
+            schema,
+        }
+    }
+
+    pub(crate) fn route(&self, request: &graphql::Request) -> Route {
+        let query = match request.query.as_deref() {
+            Some(query) => query,
+            None => return Route::Supergraph,
+        };
+        let tree = apollo_parser::Parser::new(query).parse();
+        // Invalid operations are reported by the query planner
+        if tree.errors().next().is_some() {
+            return Route::Supergraph;
+        }
+        let document = tree.document();
+        let mut fragments = HashMap::new();
+        let mut operations = Vec::new();
+        for definition in document.definitions() {
+            match definition {
+                ast::Definition::FragmentDefinition(fragment) => {
+                    if let Some(name) = fragment.fragment_name().and_then(|name| name.name()) {
+                        fragments.insert(name.text().to_string(), fragment);
+                    }
+                }
+                ast::Definition::OperationDefinition(operation) => operations.push(operation),
+                _ => {}
+            }
+        }
+        let operation = match request.operation_name.as_deref() {
+            Some(operation_name) => operations.into_iter().find(|operation| {
+                operation
+                    .name()
+                    .map(|name| name.text() == operation_name)
+                    .unwrap_or_default()
+            }),
+            None => operations.into_iter().next(),
+        };
+        let operation = match operation {
+            Some(operation) => operation,
+            None => return Route::Supergraph,
+        };
+        let kind = match operation.operation_type() {
+            Some(operation_type) if operation_type.mutation_token().is_some() => {
+                OperationKind::Mutation
+            }
+            Some(operation_type) if operation_type.subscription_token().is_some() => {
+                OperationKind::Subscription
+            }
+            _ => OperationKind::Query,
+        };
+
+        let mut names = Vec::new();
+        if let Some(selection_set) = operation.selection_set() {
+            root_field_names(selection_set, &fragments, &mut names, &mut HashSet::new());
+        }
+        let api_schema = self.schema.api_schema();
+        let root = api_schema
+            .object_types
+            .get(api_schema.root_operation_name(kind));
+        if names
+            .iter()
+            .any(|name| !name.starts_with("__") && root.and_then(|root| root.field(name)).is_none())
+        {
+            Route::Upstream(kind)
+        } else if names
+            .iter()
+            .any(|name| name == "__schema" || name == "__type")
+        {
+            Route::Introspection
+        } else {
+            Route::Supergraph
+        }
+    }
+
+    /// Sends an operation to the upstream server.
+    pub(crate) async fn delegate(
+        self: Arc<Self>,
+        request: SupergraphRequest,
+        operation_kind: OperationKind,
+    ) -> Result<SupergraphResponse, BoxError> {
+        let response = self
+            .send(
+                Arc::new(request.originating_request),
+                operation_kind,
+                request.context.clone(),
+            )
+            .await?;
+        Ok(SupergraphResponse::new_from_graphql_response(
+            response,
+            request.context,
+        ))
+    }
+
+    /// Sends an introspection query to the upstream server, to merge its response in the one of
+    /// the supergraph with [`merge_introspection`].
+    pub(crate) fn introspect(
+        self: Arc<Self>,
+        request: &SupergraphRequest,
+    ) -> BoxFuture<'static, Result<graphql::Response, BoxError>> {
+        let originating_request = Arc::new(copy(&request.originating_request));
+        let context = request.context.clone();
+        Box::pin(async move {
+            self.send(originating_request, OperationKind::Query, context)
+                .await
+                .map_err(|error| {
+                    BoxError::from(format!(
+                        "could not introspect the upstream server '{}': {}",
+                        self.name, error
+                    ))
+                })
+        })
+    }
+
+    async fn send(
+        &self,
+        originating_request: Arc<http::Request<graphql::Request>>,
+        operation_kind: OperationKind,
+        context: Context,
+    ) -> Result<graphql::Response, BoxError> {
+        let service = self
+            .subgraph_creator
+            .new_service(&self.name)
+            .ok_or_else(|| format!("no service for the upstream server '{}'", self.name))?;
+        let mut subgraph_request = http::Request::new(originating_request.body().clone());
+        *subgraph_request.method_mut() = http::Method::POST;
+        *subgraph_request.uri_mut() = self.uri.clone();
+        let response = service
+            .oneshot(
+                SubgraphRequest::builder()
+                    .originating_request(originating_request)
+                    .subgraph_request(subgraph_request)
+                    .operation_kind(operation_kind)
+                    .context(context)
+                    .build(),
+            )
+            .await?;
+        Ok(response.response.into_body())
+    }
+}
+
+/// Answers an introspection query with the response of the supergraph, merged with the response
+/// of the upstream server. The response of the supergraph is kept as it is if the upstream server
+/// fails.
+pub(crate) async fn merge_introspection(
+    supergraph: BoxFuture<'static, Result<SupergraphResponse, BoxError>>,
+    upstream: BoxFuture<'static, Result<graphql::Response, BoxError>>,
+) -> Result<SupergraphResponse, BoxError> {
+    let (response, upstream) = futures::join!(supergraph, upstream);
+    let mut response = response?;
+    let upstream = match upstream {
+        Ok(upstream) => upstream,
+        Err(error) => {
+            tracing::warn!("{}", error);
+            return Ok(response);
+        }
+    };
+
+    let (parts, mut stream) = response.response.into_parts();
+    let mut first = stream.next().await.unwrap_or_default();
+    match first.data.as_mut() {
+        Some(data) => {
+            if let Some(upstream_data) = upstream.data {
+                merge(data, upstream_data);
+            }
+        }
+        None => first.data = upstream.data,
+    }
+    response.response = http::Response::from_parts(parts, once(ready(first)).boxed());
+    Ok(response)
+}
+
+/// Names of the fields of a selection set, including the ones of its fragments.
+fn root_field_names(
+    selection_set: ast::SelectionSet,
+    fragments: &HashMap<String, ast::FragmentDefinition>,
+    names: &mut Vec<String>,
+    visited: &mut HashSet<String>,
+) {
+    for selection in selection_set.selections() {
+        match selection {
+            ast::Selection::Field(field) => {
+                if let Some(name) = field.name() {
+                    names.push(name.text().to_string());
+                }
+            }
+            ast::Selection::InlineFragment(fragment) => {
+                if let Some(selection_set) = fragment.selection_set() {
+                    root_field_names(selection_set, fragments, names, visited);
+                }
+            }
+            ast::Selection::FragmentSpread(spread) => {
+                let name = match spread.fragment_name().and_then(|name| name.name()) {
+                    Some(name) => name.text().to_string(),
+                    None => continue,
+                };
+                if let Some(selection_set) = fragments
+                    .get(&name)
+                    .filter(|_| visited.insert(name.clone()))
+                    .and_then(|fragment| fragment.selection_set())
+                {
+                    root_field_names(selection_set, fragments, names, visited);
+                }
+            }
+        }
+    }
+}
+
+/// Merges the introspection response of the upstream server in the one of the supergraph.
+///
+/// Lists of named items, like types, fields or enum values, get the items of the upstream server
+/// the supergraph doesn't have, and items with the same name are merged. The supergraph wins for
+/// the other values.
+fn merge(supergraph: &mut Value, upstream: Value) {
+    match (supergraph, upstream) {
+        (Value::Object(supergraph), Value::Object(upstream)) => {
+            for (key, value) in upstream {
+                match supergraph.get_mut(key.as_str()) {
+                    Some(supergraph_value) => merge(supergraph_value, value),
+                    None => {
+                        supergraph.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(supergraph), Value::Array(upstream)) => {
+            for value in upstream {
+                let name = value
+                    .as_object()
+                    .and_then(|object| object.get("name"))
+                    .and_then(|name| name.as_str())
+                    .map(String::from);
+                let same = supergraph.iter_mut().find(|supergraph_value| match &name {
+                    Some(name) => {
+                        supergraph_value
+                            .as_object()
+                            .and_then(|object| object.get("name"))
+                            .and_then(|name| name.as_str())
+                            == Some(name.as_str())
+                    }
+                    None => **supergraph_value == value,
+                });
+                match same {
+                    Some(supergraph_value) => merge(supergraph_value, value),
+                    None => supergraph.push(value),
+                }
+            }
+        }
+        (supergraph @ Value::Null, upstream) => *supergraph = upstream,
+        _ => {}
+    }
+}
+
+fn copy(request: &http::Request<graphql::Request>) -> http::Request<graphql::Request> {
+    let mut copy = http::Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_bytes::json;
+
+    use super::*;
+
+    fn upstream() -> Upstream {
+        let schema = Schema::parse(
+            include_str!("../testdata/supergraph.graphql"),
+            &Default::default(),
+        )
+        .unwrap();
+        Upstream::new(
+            "upstream".to_string(),
+            Uri::from_static("http://monolith:4000/graphql"),
+            Arc::new(schema),
+            Arc::new(SubgraphCreator::new(Vec::new(), Default::default())),
+        )
+    }
+
+    fn route(query: &str, operation_name: Option<&str>) -> Route {
+        upstream().route(
+            &graphql::Request::builder()
+                .query(query)
+                .and_operation_name(operation_name.map(String::from))
+                .build(),
+        )
+    }
+
+    #[test]
+    fn operations_with_unknown_root_fields_are_delegated() {
+        assert_eq!(
+            route("{ me { name } topProducts { upc } }", None),
+            Route::Supergraph
+        );
+        assert_eq!(
+            route("{ me { name } legacyOrders { id } }", None),
+            Route::Upstream(OperationKind::Query)
+        );
+        assert_eq!(
+            route(
+                "query A { me { name } } query B { ...Legacy } fragment Legacy on Query { legacyOrders { id } }",
+                Some("B")
+            ),
+            Route::Upstream(OperationKind::Query)
+        );
+        assert_eq!(
+            route("mutation { cancelOrder(id: 1) }", None),
+            Route::Upstream(OperationKind::Mutation)
+        );
+        assert_eq!(
+            route("{ __schema { types { name } } }", None),
+            Route::Introspection
+        );
+        assert_eq!(route("{ __typename }", None), Route::Supergraph);
+    }
+
+    #[test]
+    fn introspection_responses_are_merged() {
+        let mut supergraph = json!({
+            "__schema": {
+                "queryType": { "name": "Query" },
+                "mutationType": null,
+                "types": [
+                    { "kind": "OBJECT", "name": "Query", "fields": [{ "name": "me" }] },
+                    { "kind": "OBJECT", "name": "User", "fields": [{ "name": "id" }] }
+                ]
+            }
+        });
+        merge(
+            &mut supergraph,
+            json!({
+                "__schema": {
+                    "queryType": { "name": "Query" },
+                    "mutationType": { "name": "Mutation" },
+                    "types": [
+                        { "kind": "OBJECT", "name": "Query", "fields": [{ "name": "me" }, { "name": "legacyOrders" }] },
+                        { "kind": "OBJECT", "name": "Mutation", "fields": [{ "name": "cancelOrder" }] }
+                    ]
+                }
+            }),
+        );
+        assert_eq!(
+            supergraph,
+            json!({
+                "__schema": {
+                    "queryType": { "name": "Query" },
+                    "mutationType": { "name": "Mutation" },
+                    "types": [
+                        { "kind": "OBJECT", "name": "Query", "fields": [{ "name": "me" }, { "name": "legacyOrders" }] },
+                        { "kind": "OBJECT", "name": "User", "fields": [{ "name": "id" }] },
+                        { "kind": "OBJECT", "name": "Mutation", "fields": [{ "name": "cancelOrder" }] }
+                    ]
+                }
+            })
+        );
+    }
+}
@@ -121,6 +121,20 @@ impl FieldType {
         }
     }
 
+    /// The type as written in a schema, for example `[User!]!`
+    pub(crate) fn type_reference(&self) -> String {
+        match self {
+            FieldType::Named(name) | FieldType::Introspection(name) => name.clone(),
+            FieldType::List(inner) => format!("[{}]", inner.type_reference()),
+            FieldType::NonNull(inner) => format!("{}!", inner.type_reference()),
+            FieldType::String => "String".to_string(),
+            FieldType::Int => "Int".to_string(),
+            FieldType::Float => "Float".to_string(),
+            FieldType::Id => "ID".to_string(),
+            FieldType::Boolean => "Boolean".to_string(),
+        }
+    }
+
     pub(crate) fn is_builtin_scalar(&self) -> bool {
         match self {
             FieldType::Named(_)
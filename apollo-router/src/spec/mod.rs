@@ -7,6 +7,8 @@ mod selection;
 use displaydoc::Display;
 pub(crate) use field_type::*;
 pub(crate) use fragments::*;
+pub(crate) use query::FieldCount;
+pub(crate) use query::FieldCounts;
 pub(crate) use query::Query;
 pub(crate) use schema::Schema;
 pub(crate) use selection::*;
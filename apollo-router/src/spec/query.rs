@@ -4,15 +4,23 @@
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::iter::Peekable;
+use std::str::Chars;
 
 use apollo_parser::ast;
 use derivative::Derivative;
+use serde::Deserialize;
+use serde::Serialize;
 use serde_json_bytes::ByteString;
+use sha2::Digest;
+use sha2::Sha256;
 use tracing::level_filters::LevelFilter;
 
+use crate::configuration::JsonNumbers;
 use crate::error::FetchError;
 use crate::graphql::Request;
 use crate::graphql::Response;
+use crate::json_ext::stringify_large_integers;
 use crate::json_ext::Object;
 use crate::json_ext::Path;
 use crate::json_ext::Value;
@@ -21,6 +29,16 @@ use crate::*;
 
 const TYPENAME: &str = "__typename";
 
+/// Number of executions of a field observed in a response, with the type it returns.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FieldCount {
+    pub(crate) return_type: String,
+    pub(crate) count: u64,
+}
+
+/// Field executions observed in a response, by parent type and field name.
+pub(crate) type FieldCounts = HashMap<String, HashMap<String, FieldCount>>;
+
 /// A GraphQL query.
 #[derive(Debug, Derivative, Default)]
 #[derivative(PartialEq, Hash, Eq)]
@@ -32,6 +50,8 @@ pub(crate) struct Query {
     operations: Vec<Operation>,
     #[derivative(PartialEq = "ignore", Hash = "ignore")]
     pub(crate) subselections: HashMap<(Option<Path>, String), Query>,
+    #[derivative(PartialEq = "ignore", Hash = "ignore")]
+    json_numbers: JsonNumbers,
 }
 
 impl Query {
@@ -75,7 +95,7 @@ impl Query {
                                 &mut output,
                                 schema,
                             ) {
-                                Ok(()) => output.into(),
+                                Ok(()) => self.format_numbers(output.into()),
                                 Err(InvalidValue) => Value::Null,
                             },
                         );
@@ -107,7 +127,7 @@ impl Query {
                         &mut output,
                         schema,
                     ) {
-                        Ok(()) => output.into(),
+                        Ok(()) => self.format_numbers(output.into()),
                         Err(InvalidValue) => Value::Null,
                     },
                 );
@@ -123,6 +143,204 @@ impl Query {
         response.data = Some(Value::default());
     }
 
+    /// Counts the executions of the fields of the operation, from the data of its response.
+    ///
+    /// A field is counted once per object of its parent type in the data. Fields are counted on
+    /// the type they are selected on: the parent field's type, or the type condition of a
+    /// fragment.
+    pub(crate) fn count_fields(
+        &self,
+        operation_name: Option<&str>,
+        variables: &Object,
+        data: &Value,
+        schema: &Schema,
+    ) -> FieldCounts {
+        let mut counts = FieldCounts::new();
+        let operation = match operation_name {
+            Some(name) => self
+                .operations
+                .iter()
+                .find(|op| op.name.as_deref() == Some(name)),
+            None => self.operations.get(0),
+        };
+        if let (Some(operation), Value::Object(data)) = (operation, data) {
+            let variables: Object = operation
+                .variables
+                .iter()
+                .filter_map(|(k, (_, opt))| opt.as_ref().map(|v| (k, v)))
+                .chain(variables.iter())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            self.count_selection_set(
+                schema.root_operation_name(operation.kind),
+                &operation.selection_set,
+                &variables,
+                data,
+                schema,
+                &mut counts,
+            );
+        }
+        counts
+    }
+
+    fn count_selection_set(
+        &self,
+        parent_type: &str,
+        selection_set: &[Selection],
+        variables: &Object,
+        data: &Object,
+        schema: &Schema,
+        counts: &mut FieldCounts,
+    ) {
+        // Fragments that don't apply to the object are skipped if its type is known
+        let applies = |type_condition: &str| {
+            data.get(TYPENAME)
+                .and_then(|typename| typename.as_str())
+                .map(|typename| {
+                    typename == type_condition || schema.is_subtype(type_condition, typename)
+                })
+                .unwrap_or(true)
+        };
+        for selection in selection_set {
+            match selection {
+                Selection::Field {
+                    name,
+                    alias,
+                    selection_set,
+                    field_type,
+                    skip,
+                    include,
+                } => {
+                    if skip.should_skip(variables).unwrap_or(false)
+                        || !include.should_include(variables).unwrap_or(true)
+                        || name.as_str() == TYPENAME
+                    {
+                        continue;
+                    }
+                    let value = match data.get(alias.as_ref().unwrap_or(name).as_str()) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    counts
+                        .entry(parent_type.to_string())
+                        .or_default()
+                        .entry(name.as_str().to_string())
+                        .or_insert_with(|| FieldCount {
+                            return_type: field_type.type_reference(),
+                            count: 0,
+                        })
+                        .count += 1;
+                    if let (Some(selection_set), Some(field_type)) =
+                        (selection_set, field_type.inner_type_name())
+                    {
+                        self.count_value(
+                            field_type,
+                            selection_set,
+                            variables,
+                            value,
+                            schema,
+                            counts,
+                        );
+                    }
+                }
+                Selection::InlineFragment {
+                    type_condition,
+                    selection_set,
+                    skip,
+                    include,
+                    ..
+                } => {
+                    if skip.should_skip(variables).unwrap_or(false)
+                        || !include.should_include(variables).unwrap_or(true)
+                        || !applies(type_condition)
+                    {
+                        continue;
+                    }
+                    self.count_selection_set(
+                        type_condition,
+                        selection_set,
+                        variables,
+                        data,
+                        schema,
+                        counts,
+                    );
+                }
+                Selection::FragmentSpread {
+                    name,
+                    skip,
+                    include,
+                    ..
+                } => {
+                    if skip.should_skip(variables).unwrap_or(false)
+                        || !include.should_include(variables).unwrap_or(true)
+                    {
+                        continue;
+                    }
+                    if let Some(fragment) = self.fragments.get(name) {
+                        if fragment.skip.should_skip(variables).unwrap_or(false)
+                            || !fragment.include.should_include(variables).unwrap_or(true)
+                            || !applies(&fragment.type_condition)
+                        {
+                            continue;
+                        }
+                        self.count_selection_set(
+                            &fragment.type_condition,
+                            &fragment.selection_set,
+                            variables,
+                            data,
+                            schema,
+                            counts,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn count_value(
+        &self,
+        parent_type: &str,
+        selection_set: &[Selection],
+        variables: &Object,
+        value: &Value,
+        schema: &Schema,
+        counts: &mut FieldCounts,
+    ) {
+        match value {
+            Value::Object(object) => self.count_selection_set(
+                parent_type,
+                selection_set,
+                variables,
+                object,
+                schema,
+                counts,
+            ),
+            Value::Array(values) => {
+                for value in values {
+                    self.count_value(parent_type, selection_set, variables, value, schema, counts);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn format_numbers(&self, mut data: Value) -> Value {
+        if self.json_numbers.large_integers_as_strings {
+            stringify_large_integers(&mut data);
+        }
+        data
+    }
+
+    /// Hash of an operation, in hexadecimal, identical for documents differing only by ignored
+    /// tokens: whitespace, commas and comments.
+    pub(crate) fn hash(query: &str, operation_name: Option<&str>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(operation_name.unwrap_or_default().as_bytes());
+        hasher.update(b"\n");
+        hasher.update(normalize(query).as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
     pub(crate) fn parse(
         query: impl Into<String>,
         schema: &Schema,
@@ -171,6 +389,7 @@ impl Query {
             fragments,
             operations,
             subselections: HashMap::new(),
+            json_numbers: configuration.experimental_json_numbers,
         })
     }
 
@@ -310,10 +529,13 @@ impl Query {
                 } else {
                     None
                 };
+                // subgraphs returning 64-bit integers can be allowed
+                let wide =
+                    self.json_numbers.preserve_integer_width && (input.is_i64() || input.is_u64());
 
                 // if the value is invalid, we do not insert it in the output object
                 // which is equivalent to inserting null
-                if opt.is_some() {
+                if opt.is_some() || wide {
                     *output = input.clone();
                 } else {
                     *output = Value::Null;
@@ -809,6 +1031,74 @@ impl From<ast::OperationType> for OperationKind {
     }
 }
 
+/// The tokens of a document without ignored tokens, separated by a space only where it is
+/// necessary to tell them apart. Strings are kept as they are.
+fn normalize(query: &str) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut normalized = String::with_capacity(query.len());
+    let mut separated = false;
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                while chars.next_if(|c| *c != '\n' && *c != '\r').is_some() {}
+                separated = true;
+            }
+            ',' => separated = true,
+            c if c.is_whitespace() || c == '\u{feff}' => separated = true,
+            c => {
+                if separated && is_word(c) && normalized.ends_with(is_word) {
+                    normalized.push(' ');
+                }
+                separated = false;
+                normalized.push(c);
+                if c == '"' {
+                    copy_string(&mut chars, &mut normalized);
+                }
+            }
+        }
+    }
+    normalized
+}
+
+/// Copies a string after its opening quote, including block strings.
+fn copy_string(chars: &mut Peekable<Chars<'_>>, normalized: &mut String) {
+    let block = chars.next_if_eq(&'"').is_some();
+    if block {
+        if chars.next_if_eq(&'"').is_none() {
+            // Empty string
+            normalized.push('"');
+            return;
+        }
+        normalized.push_str("\"\"");
+    }
+    let mut quotes = 0;
+    while let Some(c) = chars.next() {
+        normalized.push(c);
+        match c {
+            '\\' => {
+                quotes = 0;
+                if block {
+                    // Escaped triple quote
+                    for _ in 0..3 {
+                        normalized.extend(chars.next_if_eq(&'"'));
+                    }
+                } else {
+                    normalized.extend(chars.next());
+                }
+            }
+            '"' if !block => return,
+            '"' => {
+                quotes += 1;
+                if quotes == 3 {
+                    return;
+                }
+            }
+            _ => quotes = 0,
+        }
+    }
+}
+
 fn parse_default_value(definition: &ast::VariableDefinition) -> Option<Value> {
     definition
         .default_value()
@@ -1200,6 +1490,94 @@ mod tests {
         );
     }
 
+    #[test]
+    fn reformat_response_json_numbers() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+                id: ID
+                count: Int
+                total: Int
+            }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let data = json! {{
+            "id": 9007199254740993u64,
+            "count": 1,
+            "total": 4294967296u64,
+        }};
+
+        let query = Query::parse("{ id count total }", &schema, &Default::default()).unwrap();
+        let mut response = Response::builder().data(data.clone()).build();
+        query.format_response(&mut response, None, Object::new(), schema.api_schema());
+        assert_eq!(
+            response.data.unwrap(),
+            json! {{ "id": 9007199254740993u64, "count": 1, "total": null }}
+        );
+
+        let configuration = Configuration::builder()
+            .experimental_json_numbers(JsonNumbers {
+                preserve_integer_width: true,
+                forbid_lossy_coercion: false,
+                large_integers_as_strings: true,
+            })
+            .build();
+        let query = Query::parse("{ id count total }", &schema, &configuration).unwrap();
+        let mut response = Response::builder().data(data).build();
+        query.format_response(&mut response, None, Object::new(), schema.api_schema());
+        assert_eq!(
+            response.data.unwrap(),
+            json! {{ "id": "9007199254740993", "count": 1, "total": 4294967296u64 }}
+        );
+    }
+
+    #[test]
+    fn fields_are_counted_from_the_response() {
+        let schema = with_supergraph_boilerplate(
+            "type Query {
+                products: [Product!]!
+            }
+            interface Product {
+                upc: ID!
+            }
+            type Book implements Product {
+                upc: ID!
+                title: String
+            }
+            type Movie implements Product {
+                upc: ID!
+                title: String
+            }",
+        );
+        let schema = Schema::parse(&schema, &Default::default()).expect("could not parse schema");
+        let query = Query::parse(
+            "query Products($skip: Boolean = true) {
+                products { upc ...on Book { title } ...on Movie { title @skip(if: $skip) } }
+            }",
+            &schema,
+            &Default::default(),
+        )
+        .unwrap();
+        let data = json! {{
+            "products": [
+                { "__typename": "Book", "upc": "1", "title": "Dune" },
+                { "__typename": "Movie", "upc": "2", "title": "Alien" },
+                { "__typename": "Book", "upc": "3", "title": null },
+            ]
+        }};
+
+        let counts = query.count_fields(Some("Products"), &Object::new(), &data, &schema);
+        let count = |parent: &str, field: &str| {
+            counts
+                .get(parent)
+                .and_then(|fields| fields.get(field))
+                .map(|count| (count.return_type.as_str(), count.count))
+        };
+        assert_eq!(count("Query", "products"), Some(("[Product!]!", 1)));
+        assert_eq!(count("Product", "upc"), Some(("ID!", 3)));
+        assert_eq!(count("Book", "title"), Some(("String", 2)));
+        assert_eq!(count("Movie", "title"), None);
+    }
+
     #[test]
     fn reformat_response_data_best_effort() {
         assert_format_response!(
@@ -5574,4 +5952,34 @@ mod tests {
             }},
         );
     }
+
+    #[test]
+    fn hash_ignores_ignored_tokens() {
+        let hash = Query::hash(
+            "query Q($id: ID!) { user(id: $id) { name, ...on User { id } } }",
+            Some("Q"),
+        );
+        assert_eq!(
+            hash,
+            Query::hash(
+                "# comment\nquery Q($id:ID!){user(id:$id){name ... on User{id}}}",
+                Some("Q")
+            )
+        );
+        assert_ne!(
+            hash,
+            Query::hash(
+                "query Q($id: ID!) { user(id: $id) { name, ...on User { id } } }",
+                None
+            )
+        );
+        assert_ne!(
+            Query::hash("{ a(s: \"x  y\") }", None),
+            Query::hash("{ a(s: \"x y\") }", None)
+        );
+        assert_eq!(
+            normalize("{ a(s: \"\"\"a \\\"\"\" # b\"\"\", t: \"\") b }"),
+            "{a(s:\"\"\"a \\\"\"\" # b\"\"\"t:\"\")b}"
+        );
+    }
 }
@@ -1,32 +1,40 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::fmt::Formatter;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::prelude::*;
 use tokio::sync::OwnedRwLockWriteGuard;
 use tokio::sync::RwLock;
+use tokio::time::Instant;
 use Event::NoMoreConfiguration;
 use Event::NoMoreSchema;
 use Event::Shutdown;
 
 use super::http_server_factory::HttpServerFactory;
 use super::http_server_factory::HttpServerHandle;
-use super::router::ApolloRouterError::NoConfiguration;
-use super::router::ApolloRouterError::NoSchema;
-use super::router::ApolloRouterError::{self};
 use super::router::Event::UpdateConfiguration;
 use super::router::Event::UpdateSchema;
 use super::router::Event::{self};
 use super::state_machine::State::Errored;
 use super::state_machine::State::Running;
+use super::state_machine::State::SafeMode;
 use super::state_machine::State::Startup;
 use super::state_machine::State::Stopped;
 use crate::configuration::Configuration;
 use crate::configuration::ListenAddr;
+use crate::error::ApolloRouterError::NoConfiguration;
+use crate::error::ApolloRouterError::NoSchema;
+use crate::error::ApolloRouterError::{self};
+use crate::router_factory::SafeModeSupergraphServiceFactory;
 use crate::router_factory::SupergraphServiceConfigurator;
 use crate::router_factory::SupergraphServiceFactory;
 use crate::Schema;
 
+/// Time to wait for a schema at startup before entering safe mode, if it is enabled.
+const SAFE_MODE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
 /// This state maintains private information that is not exposed to the user via state listener.
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
@@ -43,6 +51,10 @@ enum State<RS> {
         router_service_factory: RS,
         server_handle: HttpServerHandle,
     },
+    SafeMode {
+        configuration: Arc<Configuration>,
+        server_handle: HttpServerHandle,
+    },
     Stopped,
     Errored(ApolloRouterError),
 }
@@ -52,6 +64,7 @@ impl<T> Display for State<T> {
         match self {
             Startup { .. } => write!(f, "startup"),
             Running { .. } => write!(f, "running"),
+            SafeMode { .. } => write!(f, "safe mode"),
             Stopped => write!(f, "stopped"),
             Errored { .. } => write!(f, "errored"),
         }
@@ -62,6 +75,7 @@ impl<T> Display for State<T> {
 /// The server is in startup state until both configuration and schema are supplied.
 /// If config and schema are not supplied then the machine ends with an error.
 /// Once schema and config are obtained running state is entered.
+/// If safe mode is enabled and no usable schema is available, because it can't be parsed, the schema source ended, or no schema was received during a grace period, safe mode state is entered instead, until a schema is obtained.
 /// Config and schema updates will try to swap in the new values into the running state. In future we may trigger an http server restart if for instance socket address is encountered.
 /// At any point a shutdown event will cause the machine to try to get to stopped state.  
 pub(crate) struct StateMachine<S, FA>
@@ -75,6 +89,7 @@ where
     // The reason we have listen_address and listen_address_guard is that on startup we want ensure that we update the listen address before users can read the value.
     pub(crate) listen_address: Arc<RwLock<Option<ListenAddr>>>,
     listen_address_guard: Option<OwnedRwLockWriteGuard<Option<ListenAddr>>>,
+    safe_mode_grace_period: Duration,
 }
 
 impl<S, FA> StateMachine<S, FA>
@@ -91,6 +106,7 @@ where
             router_configurator: router_factory,
             listen_address: ready,
             listen_address_guard: Some(ready_guard),
+            safe_mode_grace_period: SAFE_MODE_GRACE_PERIOD,
         }
     }
 
@@ -103,7 +119,43 @@ where
             configuration: None,
             schema: None,
         };
-        while let Some(message) = messages.next().await {
+        // Deadline to receive a schema before entering safe mode
+        let mut safe_mode_deadline: Option<Instant> = None;
+        loop {
+            let message = match safe_mode_deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, messages.next()).await {
+                    Ok(message) => message,
+                    Err(_) => {
+                        safe_mode_deadline = None;
+                        state = match state {
+                            Startup {
+                                configuration: Some(configuration),
+                                schema: None,
+                            } => {
+                                tracing::warn!(
+                                    "no schema received after {:?}",
+                                    self.safe_mode_grace_period
+                                );
+                                self.enter_safe_mode(Arc::new(configuration))
+                                    .await
+                                    .into_ok_or_err2()
+                            }
+                            state => state,
+                        };
+                        tracing::trace!("transitioned to {}", &state);
+                        self.maybe_update_listen_address(&mut state).await;
+                        if matches!(&state, Errored(_)) {
+                            break;
+                        }
+                        continue;
+                    }
+                },
+                None => messages.next().await,
+            };
+            let message = match message {
+                Some(message) => message,
+                None => break,
+            };
             let new_state = match (state, message) {
                 // Startup: Handle configuration updates, maybe transition to running.
                 (Startup { configuration, .. }, UpdateSchema(new_schema)) => self
@@ -132,14 +184,67 @@ where
                     NoMoreConfiguration,
                 ) => Errored(NoConfiguration),
 
-                // Startup: Missing schema.
+                // Startup: Missing schema, enter safe mode if enabled.
+                (
+                    Startup {
+                        configuration: Some(configuration),
+                        schema: None,
+                    },
+                    NoMoreSchema,
+                ) if configuration.server.experimental_safe_mode => self
+                    .enter_safe_mode(Arc::new(configuration))
+                    .await
+                    .into_ok_or_err2(),
                 (Startup { schema: None, .. }, NoMoreSchema) => Errored(NoSchema),
 
                 // Startup: Go straight for shutdown.
                 (Startup { .. }, Shutdown) => Stopped,
 
-                // Running: Handle shutdown.
-                (Running { server_handle, .. }, Shutdown) => {
+                // Safe mode: Handle schema updates, maybe transition to running.
+                (
+                    SafeMode {
+                        configuration,
+                        server_handle,
+                    },
+                    UpdateSchema(new_schema),
+                ) => self
+                    .leave_safe_mode(configuration, server_handle, new_schema)
+                    .await
+                    .into_ok_or_err2(),
+
+                // Safe mode: Handle configuration updates
+                (SafeMode { server_handle, .. }, UpdateConfiguration(new_configuration)) => {
+                    tracing::info!("reloading configuration");
+                    let configuration = Arc::new(*new_configuration);
+                    match server_handle
+                        .restart(
+                            &self.http_server_factory,
+                            SafeModeSupergraphServiceFactory,
+                            configuration.clone(),
+                            HashMap::new(),
+                        )
+                        .await
+                    {
+                        Ok(server_handle) => SafeMode {
+                            configuration,
+                            server_handle,
+                        },
+                        Err(err) => {
+                            tracing::error!("cannot start the router: {}", err);
+                            Errored(err)
+                        }
+                    }
+                }
+
+                // Safe mode: Missing schema, keep serving until shutdown.
+                (state @ SafeMode { .. }, NoMoreSchema) => {
+                    tracing::error!("no schema is available, staying in safe mode");
+                    state
+                }
+
+                // Running or safe mode: Handle shutdown.
+                (Running { server_handle, .. }, Shutdown)
+                | (SafeMode { server_handle, .. }, Shutdown) => {
                     tracing::debug!("shutting down");
                     match server_handle.shutdown().await {
                         Ok(_) => Stopped,
@@ -192,7 +297,6 @@ where
                     },
                     UpdateConfiguration(new_configuration),
                 ) => {
-                    tracing::info!("reloading configuration");
                     if let Err(e) = configuration.is_compatible(&new_configuration) {
                         tracing::error!("could not reload configuration: {e}");
 
@@ -203,6 +307,16 @@ where
                             server_handle,
                         }
                     } else {
+                        // Files read by the plugins may have changed even if the options did not
+                        let changes = configuration.changes(&new_configuration);
+                        if changes.is_empty() {
+                            tracing::info!("reloading configuration, no option changed");
+                        } else {
+                            tracing::info!(
+                                "reloading configuration, changed options: {}",
+                                changes.join(", ")
+                            );
+                        }
                         self.reload_server(
                             configuration,
                             schema,
@@ -230,6 +344,16 @@ where
             tracing::trace!("transitioned to {}", &new_state);
             state = new_state;
 
+            // Give the schema source some time before entering safe mode
+            safe_mode_deadline = match &state {
+                Startup {
+                    configuration: Some(configuration),
+                    schema: None,
+                } if configuration.server.experimental_safe_mode => safe_mode_deadline
+                    .or_else(|| Some(Instant::now() + self.safe_mode_grace_period)),
+                _ => None,
+            };
+
             // If we're running then let those waiting proceed.
             self.maybe_update_listen_address(&mut state).await;
 
@@ -256,11 +380,11 @@ where
         &mut self,
         state: &mut State<<FA as SupergraphServiceConfigurator>::SupergraphServiceFactory>,
     ) {
-        let listen_address = if let Running { server_handle, .. } = &state {
-            let listen_address = server_handle.listen_address().clone();
-            Some(listen_address)
-        } else {
-            None
+        let listen_address = match &state {
+            Running { server_handle, .. } | SafeMode { server_handle, .. } => {
+                Some(server_handle.listen_address().clone())
+            }
+            _ => None,
         };
 
         if let Some(listen_address) = listen_address {
@@ -288,6 +412,9 @@ where
                 Ok(schema) => schema,
                 Err(e) => {
                     tracing::error!("could not parse schema: {:?}", e);
+                    if configuration.server.experimental_safe_mode {
+                        return self.enter_safe_mode(Arc::new(configuration)).await;
+                    }
                     return Ok(Startup {
                         configuration: Some(configuration),
                         schema: None,
@@ -298,14 +425,20 @@ where
             let configuration = Arc::new(configuration);
             let schema = Arc::new(schema);
 
-            let router_factory = self
+            let router_factory = match self
                 .router_configurator
                 .create(configuration.clone(), schema.clone(), None, None)
                 .await
-                .map_err(|err| {
+            {
+                Ok(router_factory) => router_factory,
+                Err(err) => {
                     tracing::error!("cannot create the router: {}", err);
-                    Errored(ApolloRouterError::ServiceCreationError(err))
-                })?;
+                    if configuration.server.experimental_safe_mode {
+                        return self.enter_safe_mode(configuration).await;
+                    }
+                    return Err(Errored(ApolloRouterError::ServiceCreationError(err)));
+                }
+            };
             let plugin_handlers = router_factory.custom_endpoints();
 
             let server_handle = self
@@ -333,6 +466,92 @@ where
         }
     }
 
+    async fn enter_safe_mode(
+        &mut self,
+        configuration: Arc<Configuration>,
+    ) -> Result<
+        State<<FA as SupergraphServiceConfigurator>::SupergraphServiceFactory>,
+        State<<FA as SupergraphServiceConfigurator>::SupergraphServiceFactory>,
+    > {
+        tracing::warn!("no usable schema is available, starting in safe mode");
+        let server_handle = self
+            .http_server_factory
+            .create(
+                SafeModeSupergraphServiceFactory,
+                configuration.clone(),
+                None,
+                HashMap::new(),
+            )
+            .await
+            .map_err(|err| {
+                tracing::error!("cannot start the router: {}", err);
+                Errored(err)
+            })?;
+
+        Ok(SafeMode {
+            configuration,
+            server_handle,
+        })
+    }
+
+    async fn leave_safe_mode(
+        &mut self,
+        configuration: Arc<Configuration>,
+        server_handle: HttpServerHandle,
+        schema: String,
+    ) -> Result<
+        State<<FA as SupergraphServiceConfigurator>::SupergraphServiceFactory>,
+        State<<FA as SupergraphServiceConfigurator>::SupergraphServiceFactory>,
+    > {
+        let schema = match Schema::parse(&schema, &configuration) {
+            Ok(schema) => Arc::new(schema),
+            Err(e) => {
+                tracing::error!("could not parse schema, staying in safe mode: {:?}", e);
+                return Err(SafeMode {
+                    configuration,
+                    server_handle,
+                });
+            }
+        };
+
+        let router_factory = match self
+            .router_configurator
+            .create(configuration.clone(), schema.clone(), None, None)
+            .await
+        {
+            Ok(router_factory) => router_factory,
+            Err(err) => {
+                tracing::error!("cannot create the router, staying in safe mode: {}", err);
+                return Err(SafeMode {
+                    configuration,
+                    server_handle,
+                });
+            }
+        };
+        let plugin_handlers = router_factory.custom_endpoints();
+
+        let server_handle = server_handle
+            .restart(
+                &self.http_server_factory,
+                router_factory.clone(),
+                configuration.clone(),
+                plugin_handlers,
+            )
+            .await
+            .map_err(|err| {
+                tracing::error!("cannot start the router: {}", err);
+                Errored(err)
+            })?;
+        tracing::info!("schema loaded, leaving safe mode");
+
+        Ok(Running {
+            configuration,
+            schema,
+            router_service_factory: router_factory,
+            server_handle,
+        })
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn reload_server(
         &mut self,
@@ -547,6 +766,28 @@ mod tests {
         assert_eq!(shutdown_receivers.lock().unwrap().len(), 2);
     }
 
+    #[test(tokio::test)]
+    async fn startup_reload_same_configuration() {
+        let router_factory = create_mock_router_configurator(2);
+        let (server_factory, shutdown_receivers) = create_mock_server_factory(2);
+
+        assert!(matches!(
+            execute(
+                server_factory,
+                router_factory,
+                vec![
+                    UpdateConfiguration(Configuration::builder().build().boxed()),
+                    UpdateSchema(example_schema()),
+                    UpdateConfiguration(Configuration::builder().build().boxed()),
+                    Shutdown
+                ],
+            )
+            .await,
+            Ok(()),
+        ));
+        assert_eq!(shutdown_receivers.lock().unwrap().len(), 2);
+    }
+
     #[test(tokio::test)]
     async fn extract_routing_urls() {
         let router_factory = create_mock_router_configurator(1);
@@ -632,6 +873,106 @@ mod tests {
         assert_eq!(shutdown_receivers.lock().unwrap().len(), 1);
     }
 
+    fn safe_mode_configuration() -> Box<Configuration> {
+        Configuration::builder()
+            .server(
+                crate::configuration::Server::builder()
+                    .safe_mode(true)
+                    .build(),
+            )
+            .build()
+            .boxed()
+    }
+
+    #[test(tokio::test)]
+    async fn safe_mode_startup() {
+        let router_factory = create_mock_router_configurator(1);
+        let (server_factory, shutdown_receivers) = create_mock_server_factory(1);
+
+        // The schema arrives during the grace period, safe mode is not entered
+        assert!(matches!(
+            execute(
+                server_factory,
+                router_factory,
+                vec![
+                    UpdateConfiguration(safe_mode_configuration()),
+                    UpdateSchema(example_schema()),
+                    Shutdown
+                ],
+            )
+            .await,
+            Ok(()),
+        ));
+        assert_eq!(shutdown_receivers.lock().unwrap().len(), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn safe_mode_after_grace_period() {
+        let router_factory = create_mock_router_configurator(1);
+        let (server_factory, shutdown_receivers) = create_mock_server_factory(2);
+
+        let mut state_machine = StateMachine::new(server_factory, router_factory);
+        state_machine.safe_mode_grace_period = Duration::from_millis(10);
+        let events = stream::iter(vec![UpdateConfiguration(safe_mode_configuration())])
+            .chain(stream::once(async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                UpdateSchema(example_schema())
+            }))
+            .chain(stream::iter(vec![Shutdown]));
+        assert!(matches!(
+            state_machine.process_events(events.boxed()).await,
+            Ok(()),
+        ));
+        assert_eq!(shutdown_receivers.lock().unwrap().len(), 2);
+    }
+
+    #[test(tokio::test)]
+    async fn safe_mode_no_schema() {
+        let router_factory = create_mock_router_configurator(0);
+        let (server_factory, shutdown_receivers) = create_mock_server_factory(1);
+
+        assert!(matches!(
+            execute(
+                server_factory,
+                router_factory,
+                vec![
+                    UpdateConfiguration(safe_mode_configuration()),
+                    NoMoreSchema,
+                    Shutdown
+                ],
+            )
+            .await,
+            Ok(()),
+        ));
+        assert_eq!(shutdown_receivers.lock().unwrap().len(), 1);
+    }
+
+    #[test(tokio::test)]
+    async fn safe_mode_router_factory_error() {
+        let mut router_factory = MockMyRouterConfigurator::new();
+        router_factory
+            .expect_create()
+            .times(1)
+            .returning(|_, _, _, _| Err(BoxError::from("Error")));
+
+        let (server_factory, shutdown_receivers) = create_mock_server_factory(1);
+
+        assert!(matches!(
+            execute(
+                server_factory,
+                router_factory,
+                vec![
+                    UpdateConfiguration(safe_mode_configuration()),
+                    UpdateSchema(example_schema()),
+                    Shutdown
+                ],
+            )
+            .await,
+            Ok(()),
+        ));
+        assert_eq!(shutdown_receivers.lock().unwrap().len(), 1);
+    }
+
     mock! {
         #[derive(Debug)]
         MyRouterConfigurator {}
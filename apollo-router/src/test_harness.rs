@@ -4,16 +4,14 @@ use tower::BoxError;
 use tower::ServiceExt;
 
 use crate::configuration::Configuration;
+use crate::engine::supergraph_service;
 use crate::plugin::test::canned;
 use crate::plugin::DynPlugin;
 use crate::plugin::Plugin;
 use crate::plugin::PluginInit;
-use crate::router_factory::SupergraphServiceConfigurator;
-use crate::router_factory::YamlSupergraphServiceFactory;
 use crate::services::execution;
 use crate::services::subgraph;
 use crate::services::supergraph;
-use crate::Schema;
 
 /// Builder for the part of an Apollo Router that handles GraphQL requests, as a [`tower::Service`].
 ///
@@ -112,19 +110,7 @@ impl<'a> TestHarness<'a> {
     /// May be called multiple times.
     /// These extra plugins are added after plugins specified in configuration.
     pub fn extra_plugin<P: Plugin>(mut self, plugin: P) -> Self {
-        let type_id = std::any::TypeId::of::<P>();
-        let name = match crate::plugin::plugins()
-            .iter()
-            .find(|(_name, factory)| factory.type_id == type_id)
-        {
-            Some((name, _factory)) => name.clone(),
-            None => format!(
-                "extra_plugins.{}.{}",
-                self.extra_plugins.len(),
-                std::any::type_name::<P>(),
-            ),
-        };
-
+        let name = extra_plugin_name::<P>(self.extra_plugins.len());
         self.extra_plugins.push((name, Box::new(plugin)));
         self
     }
@@ -193,15 +179,20 @@ impl<'a> TestHarness<'a> {
         let config = builder.configuration.unwrap_or_default();
         let canned_schema = include_str!("../../examples/graphql/local.graphql");
         let schema = builder.schema.unwrap_or(canned_schema);
-        let schema = Arc::new(Schema::parse(schema, &config)?);
-        let router_creator = YamlSupergraphServiceFactory
-            .create(config, schema, None, Some(builder.extra_plugins))
-            .await?;
-        Ok(tower::service_fn(move |request| {
-            let service = router_creator.make();
-            async move { service.oneshot(request).await }
-        })
-        .boxed_clone())
+        supergraph_service(schema, config, builder.extra_plugins).await
+    }
+}
+
+/// The name of an extra plugin: its registered name if it has one, so that it replaces the plugin
+/// of the configuration.
+pub(crate) fn extra_plugin_name<P: Plugin>(index: usize) -> String {
+    let type_id = std::any::TypeId::of::<P>();
+    match crate::plugin::plugins()
+        .iter()
+        .find(|(_name, factory)| factory.type_id == type_id)
+    {
+        Some((name, _factory)) => name.clone(),
+        None => format!("extra_plugins.{}.{}", index, std::any::type_name::<P>()),
     }
 }
 
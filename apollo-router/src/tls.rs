@@ -0,0 +1,128 @@
+//! Loading of TLS certificates and keys from PEM files.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+#[cfg(feature = "http_server")]
+use std::sync::Arc;
+
+#[cfg(feature = "http_server")]
+use futures::prelude::*;
+#[cfg(feature = "tls")]
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::Certificate;
+use rustls::PrivateKey;
+use rustls::RootCertStore;
+#[cfg(feature = "http_server")]
+use rustls::ServerConfig;
+use tower::BoxError;
+
+#[cfg(feature = "http_server")]
+use crate::configuration::ServerTls;
+
+/// Reads the certificates of a PEM file. Files without certificates are rejected.
+pub(crate) fn load_certificates(path: &Path) -> Result<Vec<Certificate>, BoxError> {
+    let mut reader = BufReader::new(
+        File::open(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?,
+    );
+    let certificates = rustls_pemfile::certs(&mut reader)
+        .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate found in {}", path.display()).into());
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first private key of a PEM file, in PKCS#8 or RSA format.
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKey, BoxError> {
+    let mut reader = BufReader::new(
+        File::open(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?,
+    );
+    rustls_pemfile::read_all(&mut reader)
+        .map_err(|e| format!("could not parse {}: {}", path.display(), e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) => {
+                Some(PrivateKey(key))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| format!("no private key found in {}", path.display()).into())
+}
+
+/// Reads the certificate authorities of a PEM file.
+pub(crate) fn load_root_store(path: &Path) -> Result<RootCertStore, BoxError> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(path)? {
+        roots
+            .add(&certificate)
+            .map_err(|e| format!("invalid certificate in {}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+/// TLS configuration of the router's listener, requiring client certificates if certificate
+/// authorities are configured for them.
+#[cfg(feature = "tls")]
+pub(crate) fn server_config(tls: &ServerTls) -> Result<Arc<ServerConfig>, BoxError> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &tls.client_certificate_authorities {
+        Some(path) => {
+            let roots = load_root_store(path)?;
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(
+        load_certificates(&tls.certificate_chain)?,
+        load_key(&tls.key)?,
+    )?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Without the `tls` feature, the router's listener can't be configured with TLS.
+#[cfg(all(feature = "http_server", not(feature = "tls")))]
+pub(crate) fn server_config(_tls: &ServerTls) -> Result<Arc<ServerConfig>, BoxError> {
+    Err("TLS termination is not compiled into this router: it needs the `tls` cargo feature".into())
+}
+
+/// Creates a stream of events whenever one of the files of the TLS configuration changes.
+#[cfg(feature = "http_server")]
+pub(crate) fn watch(tls: &ServerTls) -> impl Stream<Item = ()> {
+    let mut paths = vec![tls.certificate_chain.clone(), tls.key.clone()];
+    paths.extend(tls.client_certificate_authorities.clone());
+    // The first event of each watch only asks to read the file, which was already done
+    stream::select_all(
+        paths
+            .into_iter()
+            .map(|path| crate::files::watch(path, None).skip(1)),
+    )
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_files_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("key.pem");
+        std::fs::write(&key, "").unwrap();
+        let tls = ServerTls {
+            certificate_chain: dir.path().join("missing.pem"),
+            key: key.clone(),
+            client_certificate_authorities: None,
+        };
+        let error = server_config(&tls).unwrap_err().to_string();
+        assert!(error.starts_with("could not read"), "{}", error);
+
+        let tls = ServerTls {
+            certificate_chain: key.clone(),
+            key,
+            client_certificate_authorities: None,
+        };
+        let error = server_config(&tls).unwrap_err().to_string();
+        assert!(error.starts_with("no certificate found"), "{}", error);
+    }
+}
@@ -0,0 +1,303 @@
+//! Running the router as a Windows service.
+//!
+//! The service control manager starts the router with the `--windows-service` option. The router
+//! then reports its status to the service control manager: start pending until it listens, running
+//! until the service is stopped, then stop pending while it shuts down gracefully. It writes its
+//! logs to the Windows event log since it has no console.
+
+use std::ffi::OsString;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use tokio::sync::oneshot;
+use tracing::Level;
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+use windows_service::define_windows_service;
+use windows_service::service::ServiceControl;
+use windows_service::service::ServiceControlAccept;
+use windows_service::service::ServiceExitCode;
+use windows_service::service::ServiceState;
+use windows_service::service::ServiceStatus;
+use windows_service::service::ServiceType;
+use windows_service::service_control_handler;
+use windows_service::service_control_handler::ServiceControlHandlerResult;
+use windows_service::service_control_handler::ServiceStatusHandle;
+use windows_service::service_dispatcher;
+use windows_sys::Win32::System::EventLog::DeregisterEventSource;
+use windows_sys::Win32::System::EventLog::RegisterEventSourceW;
+use windows_sys::Win32::System::EventLog::ReportEventW;
+use windows_sys::Win32::System::EventLog::EVENTLOG_ERROR_TYPE;
+use windows_sys::Win32::System::EventLog::EVENTLOG_INFORMATION_TYPE;
+use windows_sys::Win32::System::EventLog::EVENTLOG_WARNING_TYPE;
+
+use crate::executable::runtime;
+use crate::Executable;
+use crate::ShutdownSource;
+
+/// Ignored by the service control manager for services running in their own process.
+const SERVICE_NAME: &str = "apollo-router";
+/// Name of the event log source, it must be registered for the messages to display properly.
+const EVENT_SOURCE: &str = "Apollo Router";
+/// Time the service control manager waits for the next status update of a pending service. The
+/// router reports its progress twice as often, as loading the schema can take a while.
+const PENDING_WAIT_HINT: Duration = Duration::from_secs(10);
+
+static EVENT_LOG: OnceCell<EventLog> = OnceCell::new();
+static SERVICE: OnceCell<Service> = OnceCell::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hands the main thread over to the service control manager, which runs the router in
+/// `service_main`. Returns once the service is stopped.
+pub(crate) fn run() -> Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("could not connect to the service control manager, --windows-service must only be used when the router is started as a Windows service")
+}
+
+/// The event log, if the router is running as a Windows service.
+pub(crate) fn event_log() -> Option<EventLog> {
+    EVENT_LOG.get().cloned()
+}
+
+/// Reports the service as running, once the router listens. Does nothing if the router isn't
+/// running as a Windows service.
+pub(crate) fn report_running() {
+    if let Some(service) = SERVICE.get() {
+        service.set_state(ServiceState::Running, ServiceExitCode::Win32(0));
+    }
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(error) = run_service() {
+        // without the event log, the failure is only reported by the exit code of the service
+        if let Some(event_log) = event_log() {
+            event_log.report(EVENTLOG_ERROR_TYPE, &format!("{:#}", error));
+        }
+    }
+}
+
+fn run_service() -> Result<()> {
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel::<()>();
+    let shutdown_sender = Mutex::new(Some(shutdown_sender));
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(sender) = shutdown_sender.lock().expect("lock poisoned").take() {
+                    if let Some(service) = SERVICE.get() {
+                        service.set_state(ServiceState::StopPending, ServiceExitCode::Win32(0));
+                    }
+                    let _ = sender.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+    let service = SERVICE.get_or_init(|| Service::new(status_handle));
+    service.set_state(ServiceState::StartPending, ServiceExitCode::Win32(0));
+
+    let result = EventLog::register(EVENT_SOURCE)
+        .context("could not register the event log source")
+        .and_then(|event_log| {
+            let _ = EVENT_LOG.set(event_log);
+            std::thread::spawn(move || service.report_progress());
+            runtime()
+        })
+        .and_then(|runtime| {
+            runtime.block_on(
+                Executable::builder()
+                    .shutdown(ShutdownSource::Custom(Box::pin(async move {
+                        let _ = shutdown_receiver.await;
+                    })))
+                    .start(),
+            )
+        });
+
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    service.set_state(ServiceState::Stopped, exit_code);
+    result
+}
+
+/// The status of the service, reported to the service control manager.
+struct Service {
+    handle: ServiceStatusHandle,
+    /// Current state, and progress of the pending states
+    state: Mutex<(ServiceState, u32)>,
+}
+
+impl Service {
+    fn new(handle: ServiceStatusHandle) -> Self {
+        Self {
+            handle,
+            state: Mutex::new((ServiceState::StartPending, 0)),
+        }
+    }
+
+    fn set_state(&self, current_state: ServiceState, exit_code: ServiceExitCode) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        // the service is stopping, it can't run again
+        if state.0 == ServiceState::StopPending && current_state == ServiceState::Running {
+            return;
+        }
+        *state = (current_state, 0);
+        self.report(current_state, exit_code, 0);
+    }
+
+    /// Tells the service control manager that the router is still starting or stopping.
+    fn report_progress(&self) {
+        loop {
+            std::thread::sleep(PENDING_WAIT_HINT / 2);
+            let mut state = self.state.lock().expect("lock poisoned");
+            match state.0 {
+                ServiceState::StartPending | ServiceState::StopPending => {
+                    state.1 += 1;
+                    self.report(state.0, ServiceExitCode::Win32(0), state.1);
+                }
+                ServiceState::Stopped => return,
+                _ => {}
+            }
+        }
+    }
+
+    fn report(&self, current_state: ServiceState, exit_code: ServiceExitCode, checkpoint: u32) {
+        let pending = matches!(
+            current_state,
+            ServiceState::StartPending | ServiceState::StopPending
+        );
+        let status = ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state,
+            controls_accepted: if current_state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code,
+            checkpoint,
+            wait_hint: if pending {
+                PENDING_WAIT_HINT
+            } else {
+                Duration::default()
+            },
+            process_id: None,
+        };
+        if let Err(error) = self.handle.set_service_status(status) {
+            tracing::error!(
+                "could not report the status of the Windows service: {}",
+                error
+            );
+        }
+    }
+}
+
+/// Writes each log line to the Windows event log, with the event type matching its level.
+#[derive(Clone)]
+pub(crate) struct EventLog {
+    source: Arc<EventSource>,
+}
+
+struct EventSource(isize);
+
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        unsafe {
+            DeregisterEventSource(self.0);
+        }
+    }
+}
+
+impl EventLog {
+    fn register(source: &str) -> io::Result<Self> {
+        let source = to_wide(source);
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            source: Arc::new(EventSource(handle)),
+        })
+    }
+
+    fn report(&self, event_type: u16, message: &str) {
+        let message = to_wide(message.trim_end());
+        let strings = [message.as_ptr() as *mut u16];
+        unsafe {
+            ReportEventW(
+                self.source.0,
+                event_type,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            );
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+    type Writer = EventLogEntry;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventLogEntry {
+            event_log: self.clone(),
+            event_type: EVENTLOG_INFORMATION_TYPE,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let event_type = match *meta.level() {
+            Level::ERROR => EVENTLOG_ERROR_TYPE,
+            Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        EventLogEntry {
+            event_log: self.clone(),
+            event_type,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// A log line, reported to the event log when dropped.
+pub(crate) struct EventLogEntry {
+    event_log: EventLog,
+    event_type: u16,
+    buffer: Vec<u8>,
+}
+
+impl io::Write for EventLogEntry {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventLogEntry {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            self.event_log
+                .report(self.event_type, &String::from_utf8_lossy(&self.buffer));
+        }
+    }
+}
+
+fn to_wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(std::iter::once(0)).collect()
+}
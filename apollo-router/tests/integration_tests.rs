@@ -644,6 +644,94 @@ async fn defer_query_without_accept() {
     insta::assert_json_snapshot!(first);
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn schema_and_operation_hashes() {
+    let seen_by_plugins = Arc::new(Mutex::new(None));
+    let seen = seen_by_plugins.clone();
+    let router = apollo_router::TestHarness::builder()
+        .configuration_json(serde_json::json!({
+            "server": {
+                "experimental_hash_headers": true
+            }
+        }))
+        .unwrap()
+        .supergraph_hook(move |service| {
+            let seen = seen.clone();
+            service
+                .map_request(move |request: supergraph::Request| {
+                    *seen.lock().unwrap() = request.operation_hash();
+                    request
+                })
+                .boxed()
+        })
+        .build()
+        .await
+        .unwrap();
+
+    let response = router
+        .oneshot(supergraph::Request::canned_builder().build().unwrap())
+        .await
+        .unwrap();
+    let header = |name: &str| {
+        response
+            .response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap().to_string())
+    };
+    let operation_hash = response.operation_hash();
+    assert!(operation_hash.is_some());
+    assert_eq!(header("apollo-operation-hash"), operation_hash);
+    assert_eq!(*seen_by_plugins.lock().unwrap(), operation_hash);
+    let schema_hash = response.schema_hash();
+    assert!(schema_hash.is_some());
+    assert_eq!(header("apollo-schema-hash"), schema_hash);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn operations_are_executed_in_batches() {
+    let router = apollo_router::TestHarness::builder().build().await.unwrap();
+    let requests = (0..3)
+        .map(|_| supergraph::Request::canned_builder().build().unwrap())
+        .collect();
+
+    let results = supergraph::execute_batch(router, requests, 2).await;
+    assert_eq!(results.len(), 3);
+    for result in results {
+        let responses = result.unwrap();
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].errors.is_empty());
+        assert!(responses[0].data.is_some());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_engine_executes_operations_without_http_server() {
+    assert!(apollo_router::Engine::builder().build().await.is_err());
+
+    let engine = apollo_router::Engine::builder()
+        .schema(include_str!("fixtures/supergraph.graphql"))
+        .build()
+        .await
+        .unwrap();
+    let request = supergraph::Request::fake_builder()
+        .query("{ __schema { queryType { name } } }")
+        .build()
+        .unwrap();
+    let response = engine
+        .oneshot(request)
+        .await
+        .unwrap()
+        .next_response()
+        .await
+        .unwrap();
+    assert!(response.errors.is_empty());
+    assert_eq!(
+        response.data,
+        Some(json!({ "__schema": { "queryType": { "name": "Query" } } }))
+    );
+}
+
 async fn query_node(request: &supergraph::Request) -> Result<graphql::Response, String> {
     reqwest::Client::new()
         .post("https://federation-demo-gateway.fly.dev/")
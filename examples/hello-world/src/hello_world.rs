@@ -1,9 +1,9 @@
-use apollo_router::plugin::Plugin;
-use apollo_router::plugin::PluginInit;
-use apollo_router::register_plugin;
-use apollo_router::services::execution;
-use apollo_router::services::subgraph;
-use apollo_router::services::supergraph;
+use apollo_router::sdk::execution;
+use apollo_router::sdk::register_plugin;
+use apollo_router::sdk::subgraph;
+use apollo_router::sdk::supergraph;
+use apollo_router::sdk::Plugin;
+use apollo_router::sdk::PluginInit;
 use schemars::JsonSchema;
 use serde::Deserialize;
 use tower::BoxError;
@@ -96,7 +96,7 @@ mod tests {
         // Build a test harness. Usually we'd use this and send requests to
         // it, but in this case it's enough to build the harness to see our
         // output when our service registers.
-        let _test_harness = apollo_router::TestHarness::builder()
+        let _test_harness = apollo_router::sdk::TestHarness::builder()
             .configuration_json(config)
             .unwrap()
             .build()
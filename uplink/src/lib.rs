@@ -48,12 +48,15 @@ pub struct Schema {
 }
 
 /// regularly download a schema from Uplink
+///
+/// `None` is sent when the schema did not change since the previous poll, so that consumers
+/// know Uplink is reachable.
 pub fn stream_supergraph(
     api_key: String,
     graph_ref: String,
     urls: Option<Vec<Url>>,
     interval: Duration,
-) -> impl Stream<Item = Result<Schema, Error>> {
+) -> impl Stream<Item = Result<Option<Schema>, Error>> {
     let (sender, receiver) = channel(2);
     let _ = tokio::task::spawn(async move {
         let mut composition_id = None;
@@ -75,10 +78,10 @@ pub fn stream_supergraph(
                     ) => {
                         composition_id = Some(schema_config.id.clone());
                         if sender
-                            .send(Ok(Schema {
+                            .send(Ok(Some(Schema {
                                 id: schema_config.id,
                                 schema: schema_config.supergraph_sdl,
-                            }))
+                            })))
                             .await
                             .is_err()
                         {
@@ -87,6 +90,9 @@ pub fn stream_supergraph(
                     }
                     supergraph_sdl::SupergraphSdlRouterConfig::Unchanged => {
                         tracing::trace!("schema did not change");
+                        if sender.send(Ok(None)).await.is_err() {
+                            break;
+                        }
                     }
                     supergraph_sdl::SupergraphSdlRouterConfig::FetchError(e) => {
                         if let Some(urls) = &urls {
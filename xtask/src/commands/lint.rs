@@ -38,6 +38,27 @@ impl Lint {
         Self::install_rustfmt()?;
         fmt()?;
         cargo!(["clippy", "--all", "--all-targets", "--", "-D", "warnings"]);
+        // The optional subsystems of the router are not all enabled by default
+        cargo!([
+            "clippy",
+            "--all",
+            "--all-targets",
+            "--all-features",
+            "--",
+            "-D",
+            "warnings"
+        ]);
+        cargo!([
+            "clippy",
+            "--package",
+            "apollo-router",
+            "--no-default-features",
+            "--features",
+            "minimal",
+            "--",
+            "-D",
+            "warnings"
+        ]);
         cargo!(["doc", "--all", "--no-deps"], env = { "RUSTDOCFLAGS" => "-Dwarnings" });
         Ok(())
     }